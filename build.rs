@@ -1,3 +1,62 @@
 fn main() {
     println!("cargo:rerun-if-changed=linker.ld");
+
+    // Disk image to build in as a ramdisk. See src/block/ramdisk.rs
+    println!("cargo:rerun-if-env-changed=RAMDISK_IMAGE");
+    println!("cargo:rustc-check-cfg=cfg(ramdisk_image)");
+    if let Ok(path) = std::env::var("RAMDISK_IMAGE") {
+        println!("cargo:rerun-if-changed={path}");
+        println!("cargo:rustc-cfg=ramdisk_image");
+    }
+
+    // cpio archive to build in as the initramfs. See src/fs/cpio.rs
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    println!("cargo:rustc-check-cfg=cfg(initramfs_image)");
+    if let Ok(path) = std::env::var("INITRAMFS") {
+        println!("cargo:rerun-if-changed={path}");
+        println!("cargo:rustc-cfg=initramfs_image");
+    }
+
+    // What's being built, for oops reports. See src/oops.rs
+    // HEAD only changes on a checkout, so watch the branch it points at for new commits. A
+    // branch that's been packed away lives in packed-refs instead.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    let head = std::fs::read_to_string(".git/HEAD").unwrap_or_default();
+    if let Some(branch) = head.trim().strip_prefix("ref: ") {
+        let branch = format!(".git/{branch}");
+        if std::path::Path::new(&branch).exists() {
+            println!("cargo:rerun-if-changed={branch}");
+        } else {
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+    let build_id = std::process::Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    let build_id = build_id.as_deref().map_or("unknown", str::trim);
+    println!("cargo:rustc-env=KERNEL_BUILD_ID={build_id}");
+
+    // Static user programs to build in, for before there's a filesystem. A space separated
+    // list of paths, each optionally `name=path`. See src/process/programs.rs
+    println!("cargo:rerun-if-env-changed=USER_PROGRAMS");
+    let mut programs = String::from("&[\n");
+    let list = std::env::var("USER_PROGRAMS").unwrap_or_default();
+    for program in list.split_whitespace() {
+        let (name, path) = match program.split_once('=') {
+            Some((name, path)) => (Some(name), path),
+            None => (None, program),
+        };
+        let path = std::fs::canonicalize(path)
+            .unwrap_or_else(|err| panic!("USER_PROGRAMS: {path}: {err}"));
+        let path = path.to_str().expect("USER_PROGRAMS: path isn't UTF-8");
+        let name = name.unwrap_or_else(|| path.rsplit('/').next().unwrap());
+        println!("cargo:rerun-if-changed={path}");
+        programs += &format!("    Program {{ name: {name:?}, elf: include_bytes!({path:?}) }},\n");
+    }
+    programs += "]\n";
+    let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("programs.rs");
+    std::fs::write(out, programs).unwrap();
 }
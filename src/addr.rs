@@ -0,0 +1,275 @@
+//! Physical and virtual addresses, as types of their own so one can't be passed as the other.
+//!
+//! A [`PhysAddr`] is below 2^56, as far as any paging mode reaches. A [`VirtAddr`] is
+//! canonical for Sv57: the bits above bit 56 are copies of it. That's canonical for Sv39 and
+//! Sv48 too as long as it's in their range, which the page tables check for themselves.
+//! Both are checked when they're made, and arithmetic that leaves the range panics like
+//! overflow does. The [`try_new`](PhysAddr::try_new)s are for addresses from outside, like a
+//! process or the shell.
+//!
+//! The direct map gets from one to the other: [`phys_to_virt`] and [`virt_to_phys`].
+//!
+//! [`phys_to_virt`]: crate::pagetable::phys_to_virt
+//! [`virt_to_phys`]: crate::pagetable::virt_to_phys
+
+use core::{
+    fmt::{self, Debug, Display, Formatter, LowerHex, UpperHex},
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
+
+use crate::pagetable::PAGE_SIZE;
+
+/// Bits in a physical address.
+pub const PHYS_BITS: u32 = 56;
+/// Bits in a virtual address before it's sign extended, in the biggest mode.
+pub const VIRT_BITS: u32 = 57;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(u64);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(u64);
+
+impl PhysAddr {
+    /// Panics if `addr` is past [`PHYS_BITS`].
+    pub const fn new(addr: u64) -> Self {
+        match Self::try_new(addr) {
+            Some(addr) => addr,
+            None => panic!("not a physical address"),
+        }
+    }
+
+    pub const fn try_new(addr: u64) -> Option<Self> {
+        if addr >> PHYS_BITS == 0 {
+            Some(PhysAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    /// Physical page number, for page table entries and satp.
+    pub const fn ppn(self) -> u64 {
+        self.0 >> 12
+    }
+
+    /// The start of page number `ppn`.
+    pub const fn from_ppn(ppn: u64) -> Self {
+        Self::new(ppn << 12)
+    }
+}
+
+impl VirtAddr {
+    /// Panics if `addr` isn't canonical.
+    pub const fn new(addr: u64) -> Self {
+        match Self::try_new(addr) {
+            Some(addr) => addr,
+            None => panic!("not a canonical virtual address"),
+        }
+    }
+
+    pub const fn try_new(addr: u64) -> Option<Self> {
+        let top = (addr as i64) >> (VIRT_BITS - 1);
+        if top == 0 || top == -1 {
+            Some(VirtAddr(addr))
+        } else {
+            None
+        }
+    }
+
+    /// Pointers are canonical, or they couldn't be used.
+    pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
+        VirtAddr(ptr as *const u8 as u64)
+    }
+
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Index into the table at `level` (0 is the last) for this address.
+    pub const fn vpn(self, level: usize) -> usize {
+        ((self.0 >> (12 + 9 * level)) & 0x1ff) as usize
+    }
+}
+
+/// What the two have in common.
+macro_rules! address {
+    ($addr:ident) => {
+        impl $addr {
+            pub const fn as_u64(self) -> u64 {
+                self.0
+            }
+
+            /// `align` is a power of two.
+            pub const fn align_down(self, align: u64) -> Self {
+                $addr(self.0 & !(align - 1))
+            }
+
+            /// `None` if there's no such address.
+            pub const fn align_up(self, align: u64) -> Option<Self> {
+                match self.0.checked_add(align - 1) {
+                    Some(addr) => $addr::try_new(addr & !(align - 1)),
+                    None => None,
+                }
+            }
+
+            pub const fn is_aligned(self, align: u64) -> bool {
+                self.0 & (align - 1) == 0
+            }
+
+            pub const fn page_offset(self) -> u64 {
+                self.0 & (PAGE_SIZE - 1)
+            }
+
+            pub const fn is_page_aligned(self) -> bool {
+                self.is_aligned(PAGE_SIZE)
+            }
+
+            pub const fn checked_add(self, bytes: u64) -> Option<Self> {
+                match self.0.checked_add(bytes) {
+                    Some(addr) => $addr::try_new(addr),
+                    None => None,
+                }
+            }
+
+            pub const fn checked_sub(self, bytes: u64) -> Option<Self> {
+                match self.0.checked_sub(bytes) {
+                    Some(addr) => $addr::try_new(addr),
+                    None => None,
+                }
+            }
+        }
+
+        impl Add<u64> for $addr {
+            type Output = $addr;
+
+            fn add(self, bytes: u64) -> $addr {
+                $addr::new(self.0 + bytes)
+            }
+        }
+
+        impl AddAssign<u64> for $addr {
+            fn add_assign(&mut self, bytes: u64) {
+                *self = *self + bytes;
+            }
+        }
+
+        impl Sub<u64> for $addr {
+            type Output = $addr;
+
+            fn sub(self, bytes: u64) -> $addr {
+                $addr::new(self.0 - bytes)
+            }
+        }
+
+        impl SubAssign<u64> for $addr {
+            fn sub_assign(&mut self, bytes: u64) {
+                *self = *self - bytes;
+            }
+        }
+
+        /// Bytes from one to the other.
+        impl Sub for $addr {
+            type Output = u64;
+
+            fn sub(self, other: $addr) -> u64 {
+                self.0 - other.0
+            }
+        }
+
+        impl Debug for $addr {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({:#x})", stringify!($addr), self.0)
+            }
+        }
+
+        impl Display for $addr {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "{:#x}", self.0)
+            }
+        }
+
+        impl LowerHex for $addr {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                LowerHex::fmt(&self.0, f)
+            }
+        }
+
+        impl UpperHex for $addr {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                UpperHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+address!(PhysAddr);
+address!(VirtAddr);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn checked_addresses() {
+        assert!(PhysAddr::try_new((1 << PHYS_BITS) - 1).is_some());
+        assert!(PhysAddr::try_new(1 << PHYS_BITS).is_none());
+        assert!(VirtAddr::try_new(0xffff_ffc0_0000_0000).is_some());
+        assert!(VirtAddr::try_new(0x00ff_ffff_ffff_ffff).is_some());
+        assert!(VirtAddr::try_new(0x0100_0000_0000_0000).is_none());
+        assert!(VirtAddr::try_new(0xfe00_0000_0000_0000).is_some());
+
+        let pa = PhysAddr::new(0x8020_1234);
+        assert_eq!(pa.page_offset(), 0x234);
+        assert_eq!(pa.ppn(), 0x80201);
+        assert_eq!(PhysAddr::from_ppn(pa.ppn()), pa.align_down(PAGE_SIZE));
+        assert_eq!(pa.align_up(PAGE_SIZE), Some(PhysAddr::new(0x8020_2000)));
+        assert!(!pa.is_page_aligned());
+        assert_eq!((pa + 0x10) - pa, 0x10);
+        assert_eq!(PhysAddr::new(u64::MAX >> 8).checked_add(1), None);
+        assert_eq!(VirtAddr::new(u64::MAX).checked_add(1), None);
+
+        let va = VirtAddr::new(0xffff_ffc0_8020_1234);
+        assert_eq!(
+            [va.vpn(0), va.vpn(1), va.vpn(2), va.page_offset() as usize],
+            [0x001, 0x001, 0x102, 0x234]
+        );
+        assert_eq!(
+            format!("{} {:x} {:?}", pa, pa, pa),
+            "0x80201234 80201234 PhysAddr(0x80201234)"
+        );
+    }
+
+    const PA_ALL1S: PhysAddr = PhysAddr::new((1 << PHYS_BITS) - 1);
+    const VA_ALL1S: VirtAddr = VirtAddr::new(u64::MAX);
+
+    #[test_case]
+    fn page_offset_all1s() {
+        assert_eq!(0b111111111111, PA_ALL1S.page_offset());
+        assert_eq!(0b111111111111, VA_ALL1S.page_offset());
+    }
+
+    #[test_case]
+    fn pp0_all1s() {
+        assert_eq!(0b111111111, PA_ALL1S.ppn() & 0x1ff);
+        assert_eq!(0b111111111, VA_ALL1S.vpn(0));
+    }
+
+    #[test_case]
+    fn pp2_all1s() {
+        assert_eq!(0b111111111, (PA_ALL1S.ppn() >> 9) & 0x1ff);
+        assert_eq!(0b111111111, VA_ALL1S.vpn(1));
+    }
+
+    #[test_case]
+    fn pp3_all1s() {
+        assert_eq!(0b11111111111111111111111111, PA_ALL1S.ppn() >> 18);
+        assert_eq!(0b111111111, VA_ALL1S.vpn(2));
+    }
+}
@@ -0,0 +1,166 @@
+//! Address space identifiers.
+//!
+//! Each [`AddressSpace`](crate::process::memory::AddressSpace) gets an ASID the first time
+//! it runs, so switching between processes doesn't flush the TLB. They're handed out in
+//! order. Once they run out a new generation starts: every hart flushes its TLB before its
+//! next switch, and address spaces holding an ASID from an older generation get a new one
+//! when they next run.
+//!
+//! ASID 0 is the kernel's. On harts without ASIDs everything runs on 0 and each switch
+//! flushes the TLB, like before.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{hart_local, log, pagetable, tlb};
+
+/// Where the ASID goes in satp.
+const SATP_ASID_SHIFT: u64 = 44;
+/// ASIDLEN is at most 16 bits with Sv39 and up.
+const SATP_ASID_MASK: u64 = 0xffff << SATP_ASID_SHIFT;
+
+/// How many ASID bits the hardware has, from [`init`].
+static BITS: AtomicU8 = AtomicU8::new(0);
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new(0));
+/// Harts that have to flush their TLB before running another address space, one bit per
+/// hart id.
+static FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// An address space's ASID, and the generation it's from.
+#[derive(Debug, Default)]
+pub struct Asid {
+    /// 0 if it's never had one.
+    generation: u64,
+    asid: u16,
+}
+
+impl Asid {
+    pub const fn new() -> Asid {
+        Asid {
+            generation: 0,
+            asid: 0,
+        }
+    }
+
+    /// The ASID it last ran with. May belong to someone else by now, which only costs
+    /// them some extra flushes.
+    pub fn get(&self) -> u16 {
+        self.asid
+    }
+
+    /// Get a current ASID to run with on this hart, and flush the TLB if it needs it.
+    pub fn activate(&mut self) -> u16 {
+        if bits() == 0 {
+            tlb::flush_local_all(None);
+            return 0;
+        }
+        if ALLOCATOR.lock().allocate(self) {
+            log::debug!("ASIDs used up, starting generation {}", self.generation);
+            FLUSH_PENDING.store(usize::MAX, Ordering::Release);
+        }
+
+        let hart = hart_local::current_hart().0;
+        let flush = if hart < usize::BITS as usize {
+            let bit = 1 << hart;
+            FLUSH_PENDING.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+        } else {
+            // No bit to remember it by, so always flush.
+            true
+        };
+        if flush {
+            tlb::flush_local_all(None);
+        }
+        self.asid
+    }
+}
+
+struct Allocator {
+    generation: u64,
+    next: u16,
+    /// The last usable ASID.
+    max: u16,
+}
+
+impl Allocator {
+    const fn new(max: u16) -> Allocator {
+        Allocator {
+            generation: 1,
+            next: 1,
+            max,
+        }
+    }
+
+    /// Give `asid` an ASID from this generation if it doesn't have one. True if that took
+    /// a new generation.
+    fn allocate(&mut self, asid: &mut Asid) -> bool {
+        if asid.generation == self.generation {
+            return false;
+        }
+        let rollover = self.next > self.max;
+        if rollover {
+            self.generation += 1;
+            self.next = 1;
+        }
+        asid.generation = self.generation;
+        asid.asid = self.next;
+        self.next += 1;
+        rollover
+    }
+}
+
+/// How many ASID bits there are. 0 until [`init`] has run, or if there aren't any.
+pub fn bits() -> u8 {
+    BITS.load(Ordering::Relaxed)
+}
+
+/// Find out how many ASID bits the hardware has, by writing ones to satp's ASID field and
+/// seeing which stick. Harts are assumed to all have the same.
+pub fn init() {
+    let satp = pagetable::kernel_satp();
+    let probed: u64;
+    // The kernel's mappings are global, so changing ASID doesn't unmap anything.
+    unsafe {
+        asm!(
+            "csrw satp, {probe}",
+            "csrr {probed}, satp",
+            "csrw satp, {satp}",
+            probe = in(reg) satp | SATP_ASID_MASK,
+            probed = out(reg) probed,
+            satp = in(reg) satp,
+        );
+    }
+    let bits = ((probed & SATP_ASID_MASK) >> SATP_ASID_SHIFT).count_ones() as u8;
+    BITS.store(bits, Ordering::Relaxed);
+    if bits > 0 {
+        *ALLOCATOR.lock() = Allocator::new(((1u32 << bits) - 1) as u16);
+    }
+    log::info!("{} ASID bits", bits);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn asid_generations() {
+        let mut allocator = Allocator::new(2);
+        let (mut a, mut b, mut c) = (Asid::new(), Asid::new(), Asid::new());
+        assert!(!allocator.allocate(&mut a));
+        assert!(!allocator.allocate(&mut b));
+        assert_eq!((a.get(), b.get()), (1, 2));
+        // Already current.
+        assert!(!allocator.allocate(&mut a));
+        assert_eq!(a.get(), 1);
+
+        assert!(allocator.allocate(&mut c));
+        assert_eq!(c.get(), 1);
+        assert!(!allocator.allocate(&mut a));
+        assert_eq!(a.get(), 2);
+        assert!(allocator.allocate(&mut b));
+        assert_eq!(b.get(), 1);
+    }
+}
@@ -1,22 +1,39 @@
 
 use core::arch::asm;
 
-use crate::{kmain, linker_info::*, trap::trap};
+use crate::{
+    kmain,
+    linker_info::*,
+    pagetable::{EntryFlags, ENTRIES, KERNEL_ROOT, PHYS_OFFSET},
+    process::{Context, TrapFrame},
+    sbi::hart::SuspendContext,
+    trap::{trap, trap_too_deep, user_trap, TrapContext, TrapRegisters, MAX_DEPTH},
+};
 
+/// Where the bootloader jumps to, at the physical address it loaded us at.
+///
+/// Clears `.bss`, maps all of physical memory at [`PHYS_OFFSET`] in
+/// [`KERNEL_ROOT`](crate::pagetable::KERNEL_ROOT) with gigapages, and at its own address
+/// too so turning on paging doesn't pull the floor out from under it. Then it jumps up to
+/// where the kernel is linked and calls [`kmain`] with the device tree's upper half
+/// address, and the time it started, for the [boot times](crate::boot::timed). Only PC
+/// relative addressing works until then, so it's all assembly.
 #[naked]
 #[no_mangle]
 #[link_section = ".text.init"]
 pub unsafe extern "C" fn _start(hart_id: usize, dev_tree: *const u8) -> ! {
     asm!(
-        // Set global pointer.
+        // Before anything else, so boot times include this.
+        "rdtime s3",
+        // Set global pointer. Physical for now, like everything else.
         ".option push",
         ".option norelax",
         "la   gp, {global_pointer}",
         ".option pop",
         // Setup stack
         "la   sp, {stack_top}",
-        // Frame pointer
-        "mv   s0, sp",
+        // No hart-local area until kmain installs one.
+        "mv   tp, zero",
         // Save heart_id and device_tree address. So we can call clear_memory
         "mv   s1, a0",
         "mv   s2, a1",
@@ -28,19 +45,83 @@ pub unsafe extern "C" fn _start(hart_id: usize, dev_tree: *const u8) -> ! {
         "sub  a2, a2, a0",
         "call memset",
 
-        // kmain(hart_id, device_tree)
+        // Gigapage i goes in entry i (identity) and 256 + i (PHYS_OFFSET), for the first
+        // 256 GiB.
+        "la   t0, {root}",
+        "li   t1, {half} * 8",
+        "add  t1, t0, t1",
+        "li   t2, {half}",
+        "li   t3, {flags}",
+        "1:",
+        "sd   t3, 0(t0)",
+        "sd   t3, 0(t1)",
+        "li   t4, 1 << 28", // One gigapage, in the entry's PPN field.
+        "add  t3, t3, t4",
+        "addi t0, t0, 8",
+        "addi t1, t1, 8",
+        "addi t2, t2, -1",
+        "bnez t2, 1b",
+
+        // Sv39 with that table.
+        "la   t0, {root}",
+        "srli t0, t0, 12",
+        "li   t1, 8 << 60",
+        "or   t0, t0, t1",
+        "csrw satp, t0",
+        "sfence.vma",
+
+        // Up to the upper half.
+        "li   t1, {offset}",
+        "la   t0, 2f",
+        "add  t0, t0, t1",
+        "jr   t0",
+        "2:",
+        // la is PC relative, so these are upper half addresses now.
+        ".option push",
+        ".option norelax",
+        "la   gp, {global_pointer}",
+        ".option pop",
+        "la   sp, {stack_top}",
+        // Frame pointer
+        "mv   s0, sp",
+
+        // kmain(hart_id, device_tree, started)
         "mv   a0, s1",             // heart_id: usize
-        "mv   a1, s2",             // device_tree: *const u8
+        "add  a1, s2, t1",         // device_tree: *const u8
+        "mv   a2, s3",             // started: u64
         "tail {kmain}",
         global_pointer = sym __global_pointer,
         stack_top = sym __stack_top,
         bss_start = sym __bss_start,
         stack_limit = sym __stack_limit,
+        root = sym KERNEL_ROOT,
+        half = const ENTRIES / 2,
+        flags = const (EntryFlags::VALID.bits()
+            | EntryFlags::READ.bits()
+            | EntryFlags::WRITE.bits()
+            | EntryFlags::EXECUTE.bits()
+            | EntryFlags::GLOBAL.bits()
+            | EntryFlags::ACCESSED.bits()
+            | EntryFlags::DIRTY.bits()),
+        offset = const PHYS_OFFSET,
         kmain = sym kmain,
         options(noreturn)
     )
 }
 
+/// Where every trap lands.
+///
+/// `sscratch` points at this hart's [`TrapContext`](crate::trap::TrapContext) the whole
+/// time. A trap from U-mode saves the user registers into the process's
+/// [`TrapFrame`] and goes on to [`user_trap`] on its kernel stack, with the kernel's page
+/// table and `tp`.
+///
+/// A trap from the kernel goes onto this hart's trap stack, so it still has somewhere to go
+/// when the stack it came from is full or wrecked, and saves a
+/// [`TrapRegisters`](crate::trap::TrapRegisters) there for [`trap`]. One more trap while
+/// that's running carries on down the trap stack, with `sepc` and `sstatus` saved so the
+/// first one can still return. A trap past [`MAX_DEPTH`](crate::trap::MAX_DEPTH) starts
+/// again at the top of the trap stack in [`trap_too_deep`], which doesn't return.
 #[naked]
 #[no_mangle]
 // Interrupt CSR uses lowest bits for flags so handler must be aligned to 2048 bytes.
@@ -48,75 +129,399 @@ pub unsafe extern "C" fn _start(hart_id: usize, dev_tree: *const u8) -> ! {
 #[cfg(target_pointer_width = "64")]
 pub unsafe extern "C" fn trap_entry() {
     asm!(
-        "addi  sp, sp, -31 * 8", /* Allocate stack space */
-        "sd    ra,  0 * 8(sp)",  /* Push registers */
-        "sd    sp,  1 * 8(sp)", /* fixme: this is saving the updated value of sp. Not it's value *before* the trap was called. */
-        "sd    gp,  2 * 8(sp)",
-        "sd    tp,  3 * 8(sp)",
-        "sd    t0,  4 * 8(sp)",
+        // t0 is the context and t1 is parked in it, until the registers are saved.
+        "csrrw t0, sscratch, t0",
+        "sd    t1, {scratch}(t0)",
+        "csrr  t1, sstatus",
+        "andi  t1, t1, {spp}",
+        "bnez  t1, 1f",
+
+        // From U-mode.
+        "ld    t1, {user_frame}(t0)",
+        "sd    ra,  1 * 8(t1)",
+        "sd    sp,  2 * 8(t1)",
+        "sd    gp,  3 * 8(t1)",
+        "sd    tp,  4 * 8(t1)",
+        "sd    t2,  7 * 8(t1)",
+        "sd    s0,  8 * 8(t1)",
+        "sd    s1,  9 * 8(t1)",
+        "sd    a0, 10 * 8(t1)",
+        "sd    a1, 11 * 8(t1)",
+        "sd    a2, 12 * 8(t1)",
+        "sd    a3, 13 * 8(t1)",
+        "sd    a4, 14 * 8(t1)",
+        "sd    a5, 15 * 8(t1)",
+        "sd    a6, 16 * 8(t1)",
+        "sd    a7, 17 * 8(t1)",
+        "sd    s2, 18 * 8(t1)",
+        "sd    s3, 19 * 8(t1)",
+        "sd    s4, 20 * 8(t1)",
+        "sd    s5, 21 * 8(t1)",
+        "sd    s6, 22 * 8(t1)",
+        "sd    s7, 23 * 8(t1)",
+        "sd    s8, 24 * 8(t1)",
+        "sd    s9, 25 * 8(t1)",
+        "sd   s10, 26 * 8(t1)",
+        "sd   s11, 27 * 8(t1)",
+        "sd    t3, 28 * 8(t1)",
+        "sd    t4, 29 * 8(t1)",
+        "sd    t5, 30 * 8(t1)",
+        "sd    t6, 31 * 8(t1)",
+        "ld    t2, {scratch}(t0)",
+        "sd    t2,  6 * 8(t1)",
+        "csrrw t2, sscratch, t0",
+        "sd    t2,  5 * 8(t1)",
+        "csrr  t2, sepc",
+        "sd    t2, {pc}(t1)",
+        "ld    sp, {kernel_sp}(t1)",
+        "ld    tp, {kernel_tp}(t1)",
+        ".option push",
+        ".option norelax",
+        "la    gp, {global_pointer}",
+        ".option pop",
+        // The kernel doesn't touch the lower half directly, and its own half is global,
+        // so no fence needed.
+        "ld    t2, {kernel_satp}(t1)",
+        "csrw  satp, t2",
+        "mv    a0, t1",
+        "tail  {user_trap}",
+
+        // From the kernel. Count it, and pick a stack.
+        "1:",
+        "ld    t1, {depth}(t0)",
+        "addi  t1, t1, 1",
+        "sd    t1, {depth}(t0)",
+        "addi  t1, t1, -{max_depth}",
+        "bgtz  t1, 4f",
+        "addi  t1, t1, {max_depth} - 1",
+        // Nested: stay on the trap stack.
+        "bnez  t1, 2f",
+        "ld    t1, {trap_sp}(t0)",
+        "bnez  t1, 3f",
+        "2:",
+        "mv    t1, sp",
+        "3:",
+        "addi  t1, t1, -{size}",
+        "sd    sp,  2 * 8(t1)",
+        "mv    sp, t1",
+        "ld    t1, {scratch}(t0)",
+        "sd    t1,  6 * 8(sp)",
+        "csrrw t1, sscratch, t0",
         "sd    t1,  5 * 8(sp)",
-        "sd    t2,  6 * 8(sp)",
-        "sd    s0,  7 * 8(sp)",
-        "sd    s1,  8 * 8(sp)",
-        "sd    a0,  9 * 8(sp)",
-        "sd    a1, 10 * 8(sp)",
-        "sd    a2, 11 * 8(sp)",
-        "sd    a3, 12 * 8(sp)",
-        "sd    a4, 13 * 8(sp)",
-        "sd    a5, 14 * 8(sp)",
-        "sd    a6, 15 * 8(sp)",
-        "sd    a7, 16 * 8(sp)",
-        "sd    s2, 17 * 8(sp)",
-        "sd    s3, 18 * 8(sp)",
-        "sd    s4, 19 * 8(sp)",
-        "sd    s5, 20 * 8(sp)",
-        "sd    s6, 21 * 8(sp)",
-        "sd    s7, 22 * 8(sp)",
-        "sd    s8, 23 * 8(sp)",
-        "sd    s9, 24 * 8(sp)",
-        "sd   s10, 25 * 8(sp)",
-        "sd   s11, 26 * 8(sp)",
-        "sd    t3, 27 * 8(sp)",
-        "sd    t4, 28 * 8(sp)",
-        "sd    t5, 29 * 8(sp)",
-        "sd    t6, 30 * 8(sp)",
+        "csrr  t1, sepc",
+        "sd    t1,  0 * 8(sp)",
+        "csrr  t1, sstatus",
+        "sd    t1, {sstatus}(sp)",
+        "sd    ra,  1 * 8(sp)",
+        "sd    gp,  3 * 8(sp)",
+        "sd    tp,  4 * 8(sp)",
+        "sd    t2,  7 * 8(sp)",
+        "sd    s0,  8 * 8(sp)",
+        "sd    s1,  9 * 8(sp)",
+        "sd    a0, 10 * 8(sp)",
+        "sd    a1, 11 * 8(sp)",
+        "sd    a2, 12 * 8(sp)",
+        "sd    a3, 13 * 8(sp)",
+        "sd    a4, 14 * 8(sp)",
+        "sd    a5, 15 * 8(sp)",
+        "sd    a6, 16 * 8(sp)",
+        "sd    a7, 17 * 8(sp)",
+        "sd    s2, 18 * 8(sp)",
+        "sd    s3, 19 * 8(sp)",
+        "sd    s4, 20 * 8(sp)",
+        "sd    s5, 21 * 8(sp)",
+        "sd    s6, 22 * 8(sp)",
+        "sd    s7, 23 * 8(sp)",
+        "sd    s8, 24 * 8(sp)",
+        "sd    s9, 25 * 8(sp)",
+        "sd   s10, 26 * 8(sp)",
+        "sd   s11, 27 * 8(sp)",
+        "sd    t3, 28 * 8(sp)",
+        "sd    t4, 29 * 8(sp)",
+        "sd    t5, 30 * 8(sp)",
+        "sd    t6, 31 * 8(sp)",
         "mv    a0, sp",
         "call {trap}",
-        /* Pop registers */
-        "ld    ra,  0 * 8(sp)", /* Push registers */
-        "ld    sp,  1 * 8(sp)", /* fixme: this is saving the updated value of sp. Not it's value *before* the trap was called. */
-        "ld    gp,  2 * 8(sp)",
-        "ld    tp,  3 * 8(sp)",
-        "ld    t0,  4 * 8(sp)",
-        "ld    t1,  5 * 8(sp)",
-        "ld    t2,  6 * 8(sp)",
-        "ld    s0,  7 * 8(sp)",
-        "ld    s1,  8 * 8(sp)",
-        "ld    a0,  9 * 8(sp)",
-        "ld    a1, 10 * 8(sp)",
-        "ld    a2, 11 * 8(sp)",
-        "ld    a3, 12 * 8(sp)",
-        "ld    a4, 13 * 8(sp)",
-        "ld    a5, 14 * 8(sp)",
-        "ld    a6, 15 * 8(sp)",
-        "ld    a7, 16 * 8(sp)",
-        "ld    s2, 17 * 8(sp)",
-        "ld    s3, 18 * 8(sp)",
-        "ld    s4, 19 * 8(sp)",
-        "ld    s5, 20 * 8(sp)",
-        "ld    s6, 21 * 8(sp)",
-        "ld    s7, 22 * 8(sp)",
-        "ld    s8, 23 * 8(sp)",
-        "ld    s9, 24 * 8(sp)",
-        "ld   s10, 25 * 8(sp)",
-        "ld   s11, 26 * 8(sp)",
-        "ld    t3, 27 * 8(sp)",
-        "ld    t4, 28 * 8(sp)",
-        "ld    t5, 29 * 8(sp)",
-        "ld    t6, 30 * 8(sp)",
-        "addi  sp, sp, 31 * 8", /* Deallocate stack space */
+        // A nested trap may have changed these.
+        "ld    t0,  0 * 8(sp)",
+        "csrw  sepc, t0",
+        "ld    t0, {sstatus}(sp)",
+        "csrw  sstatus, t0",
+        "csrr  t0, sscratch",
+        "ld    t1, {depth}(t0)",
+        "addi  t1, t1, -1",
+        "sd    t1, {depth}(t0)",
+        "ld    ra,  1 * 8(sp)",
+        "ld    gp,  3 * 8(sp)",
+        "ld    tp,  4 * 8(sp)",
+        "ld    t0,  5 * 8(sp)",
+        "ld    t1,  6 * 8(sp)",
+        "ld    t2,  7 * 8(sp)",
+        "ld    s0,  8 * 8(sp)",
+        "ld    s1,  9 * 8(sp)",
+        "ld    a0, 10 * 8(sp)",
+        "ld    a1, 11 * 8(sp)",
+        "ld    a2, 12 * 8(sp)",
+        "ld    a3, 13 * 8(sp)",
+        "ld    a4, 14 * 8(sp)",
+        "ld    a5, 15 * 8(sp)",
+        "ld    a6, 16 * 8(sp)",
+        "ld    a7, 17 * 8(sp)",
+        "ld    s2, 18 * 8(sp)",
+        "ld    s3, 19 * 8(sp)",
+        "ld    s4, 20 * 8(sp)",
+        "ld    s5, 21 * 8(sp)",
+        "ld    s6, 22 * 8(sp)",
+        "ld    s7, 23 * 8(sp)",
+        "ld    s8, 24 * 8(sp)",
+        "ld    s9, 25 * 8(sp)",
+        "ld   s10, 26 * 8(sp)",
+        "ld   s11, 27 * 8(sp)",
+        "ld    t3, 28 * 8(sp)",
+        "ld    t4, 29 * 8(sp)",
+        "ld    t5, 30 * 8(sp)",
+        "ld    t6, 31 * 8(sp)",
+        "ld    sp,  2 * 8(sp)",
         "sret",
+
+        // Too deep. Whatever was on the trap stack is lost, but it wasn't going back.
+        "4:",
+        "csrw  sscratch, t0",
+        "ld    t1, {trap_sp}(t0)",
+        "beqz  t1, 5f",
+        "mv    sp, t1",
+        "5:",
+        "tail  {too_deep}",
+        scratch = const TrapContext::SCRATCH,
+        trap_sp = const TrapContext::TRAP_SP,
+        depth = const TrapContext::DEPTH,
+        user_frame = const TrapContext::USER_FRAME,
+        max_depth = const MAX_DEPTH,
+        spp = const 1 << 8,
+        size = const TrapRegisters::SIZE,
+        sstatus = const TrapRegisters::SSTATUS,
+        pc = const TrapFrame::PC,
+        kernel_sp = const TrapFrame::KERNEL_SP,
+        kernel_tp = const TrapFrame::KERNEL_TP,
+        kernel_satp = const TrapFrame::KERNEL_SATP,
+        global_pointer = sym __global_pointer,
         trap = sym trap,
+        user_trap = sym user_trap,
+        too_deep = sym trap_too_deep,
         options(noreturn)
     );
 }
+
+/// Load the user registers from `frame`, switch to the process's page table and `sret`.
+///
+/// `sstatus`, the kernel half of `frame` and the hart's
+/// [`TrapContext`](crate::trap::TrapContext) must be setup already. See
+/// [`process::return_to_user`](crate::process::return_to_user).
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn user_return(frame: *mut TrapFrame) -> ! {
+    asm!(
+        "ld    t0, {pc}(a0)",
+        "csrw  sepc, t0",
+        // return_to_user flushed the TLB already if it needed it.
+        "ld    t0, {satp}(a0)",
+        "csrw  satp, t0",
+        "mv    t0, a0",
+        "ld    ra,  1 * 8(t0)",
+        "ld    sp,  2 * 8(t0)",
+        "ld    gp,  3 * 8(t0)",
+        "ld    tp,  4 * 8(t0)",
+        "ld    t1,  6 * 8(t0)",
+        "ld    t2,  7 * 8(t0)",
+        "ld    s0,  8 * 8(t0)",
+        "ld    s1,  9 * 8(t0)",
+        "ld    a0, 10 * 8(t0)",
+        "ld    a1, 11 * 8(t0)",
+        "ld    a2, 12 * 8(t0)",
+        "ld    a3, 13 * 8(t0)",
+        "ld    a4, 14 * 8(t0)",
+        "ld    a5, 15 * 8(t0)",
+        "ld    a6, 16 * 8(t0)",
+        "ld    a7, 17 * 8(t0)",
+        "ld    s2, 18 * 8(t0)",
+        "ld    s3, 19 * 8(t0)",
+        "ld    s4, 20 * 8(t0)",
+        "ld    s5, 21 * 8(t0)",
+        "ld    s6, 22 * 8(t0)",
+        "ld    s7, 23 * 8(t0)",
+        "ld    s8, 24 * 8(t0)",
+        "ld    s9, 25 * 8(t0)",
+        "ld   s10, 26 * 8(t0)",
+        "ld   s11, 27 * 8(t0)",
+        "ld    t3, 28 * 8(t0)",
+        "ld    t4, 29 * 8(t0)",
+        "ld    t5, 30 * 8(t0)",
+        "ld    t6, 31 * 8(t0)",
+        "ld    t0,  5 * 8(t0)",
+        "sret",
+        pc = const TrapFrame::PC,
+        satp = const TrapFrame::SATP,
+        options(noreturn)
+    )
+}
+
+/// Save the callee saved registers to `old` and load them from `new`. Returns into
+/// whatever `new` was switched away from, or to `new.ra` for a fresh context.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn switch_context(old: *mut Context, new: *const Context) {
+    asm!(
+        "sd    ra,  0 * 8(a0)",
+        "sd    sp,  1 * 8(a0)",
+        "sd    s0,  2 * 8(a0)",
+        "sd    s1,  3 * 8(a0)",
+        "sd    s2,  4 * 8(a0)",
+        "sd    s3,  5 * 8(a0)",
+        "sd    s4,  6 * 8(a0)",
+        "sd    s5,  7 * 8(a0)",
+        "sd    s6,  8 * 8(a0)",
+        "sd    s7,  9 * 8(a0)",
+        "sd    s8, 10 * 8(a0)",
+        "sd    s9, 11 * 8(a0)",
+        "sd   s10, 12 * 8(a0)",
+        "sd   s11, 13 * 8(a0)",
+        "ld    ra,  0 * 8(a1)",
+        "ld    sp,  1 * 8(a1)",
+        "ld    s0,  2 * 8(a1)",
+        "ld    s1,  3 * 8(a1)",
+        "ld    s2,  4 * 8(a1)",
+        "ld    s3,  5 * 8(a1)",
+        "ld    s4,  6 * 8(a1)",
+        "ld    s5,  7 * 8(a1)",
+        "ld    s6,  8 * 8(a1)",
+        "ld    s7,  9 * 8(a1)",
+        "ld    s8, 10 * 8(a1)",
+        "ld    s9, 11 * 8(a1)",
+        "ld   s10, 12 * 8(a1)",
+        "ld   s11, 13 * 8(a1)",
+        "ret",
+        options(noreturn)
+    )
+}
+
+/// Save the callee saved registers and the CSRs a non-retentive suspend loses to `ctx`,
+/// then make SBI call `ext`/`func` with `a0 = arg`, `a1` = [`suspend_resume`]'s physical
+/// address and `a2` = `ctx`'s. Returns the call's error if it comes back, or 0 from
+/// [`suspend_resume`] once the hart wakes up.
+///
+/// `ctx` has to be in the kernel image or the direct map, `PHYS_OFFSET` above its
+/// physical address.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn suspend_and_save(
+    ctx: *mut SuspendContext,
+    arg: usize,
+    ext: usize,
+    func: usize,
+) -> isize {
+    asm!(
+        "sd    ra,  0 * 8(a0)",
+        "sd    sp,  1 * 8(a0)",
+        "sd    gp,  2 * 8(a0)",
+        "sd    tp,  3 * 8(a0)",
+        "sd    s0,  4 * 8(a0)",
+        "sd    s1,  5 * 8(a0)",
+        "sd    s2,  6 * 8(a0)",
+        "sd    s3,  7 * 8(a0)",
+        "sd    s4,  8 * 8(a0)",
+        "sd    s5,  9 * 8(a0)",
+        "sd    s6, 10 * 8(a0)",
+        "sd    s7, 11 * 8(a0)",
+        "sd    s8, 12 * 8(a0)",
+        "sd    s9, 13 * 8(a0)",
+        "sd   s10, 14 * 8(a0)",
+        "sd   s11, 15 * 8(a0)",
+        "csrr  t0, satp",
+        "sd    t0, {satp}(a0)",
+        "csrr  t0, stvec",
+        "sd    t0, {stvec}(a0)",
+        "csrr  t0, sie",
+        "sd    t0, {sie}(a0)",
+        "csrr  t0, sscratch",
+        "sd    t0, {sscratch}(a0)",
+        "csrr  t0, sstatus",
+        "sd    t0, {sstatus}(a0)",
+
+        "mv    a7, a2",
+        "mv    a6, a3",
+        "li    t1, {offset}",
+        "sub   a2, a0, t1",
+        "mv    a0, a1",
+        // la is PC relative, so this is the upper half address.
+        "la    a1, {resume}",
+        "sub   a1, a1, t1",
+        "ecall",
+        // Didn't suspend, or it was retentive after all. Nothing was lost.
+        "ret",
+        satp = const SuspendContext::SATP,
+        stvec = const SuspendContext::STVEC,
+        sie = const SuspendContext::SIE,
+        sscratch = const SuspendContext::SSCRATCH,
+        sstatus = const SuspendContext::SSTATUS,
+        offset = const PHYS_OFFSET,
+        resume = sym suspend_resume,
+        options(noreturn)
+    )
+}
+
+/// Where a hart resumes after [`suspend_and_save`], with paging off, `a0` = hart id and
+/// `a1` = the context's physical address.
+///
+/// Turning paging back on pulls the floor out from under us, since only the upper half
+/// is mapped. So `stvec` is pointed at the upper half copy of the next instruction first,
+/// and if the fetch faults the trap lands there.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn suspend_resume() -> ! {
+    asm!(
+        "li    t1, {offset}",
+        "la    t0, 2f",
+        "add   t0, t0, t1",
+        "csrw  stvec, t0",
+        "ld    t2, {satp}(a1)",
+        "csrw  satp, t2",
+        "sfence.vma",
+        "jr    t0",
+        ".align 2",
+        "2:",
+        "add   a1, a1, t1",
+        "ld    ra,  0 * 8(a1)",
+        "ld    sp,  1 * 8(a1)",
+        "ld    gp,  2 * 8(a1)",
+        "ld    tp,  3 * 8(a1)",
+        "ld    s0,  4 * 8(a1)",
+        "ld    s1,  5 * 8(a1)",
+        "ld    s2,  6 * 8(a1)",
+        "ld    s3,  7 * 8(a1)",
+        "ld    s4,  8 * 8(a1)",
+        "ld    s5,  9 * 8(a1)",
+        "ld    s6, 10 * 8(a1)",
+        "ld    s7, 11 * 8(a1)",
+        "ld    s8, 12 * 8(a1)",
+        "ld    s9, 13 * 8(a1)",
+        "ld   s10, 14 * 8(a1)",
+        "ld   s11, 15 * 8(a1)",
+        "ld    t0, {stvec}(a1)",
+        "csrw  stvec, t0",
+        "ld    t0, {sie}(a1)",
+        "csrw  sie, t0",
+        "ld    t0, {sscratch}(a1)",
+        "csrw  sscratch, t0",
+        "ld    t0, {sstatus}(a1)",
+        "csrw  sstatus, t0",
+        "li    a0, 0",
+        "ret",
+        satp = const SuspendContext::SATP,
+        stvec = const SuspendContext::STVEC,
+        sie = const SuspendContext::SIE,
+        sscratch = const SuspendContext::SSCRATCH,
+        sstatus = const SuspendContext::SSTATUS,
+        offset = const PHYS_OFFSET,
+        options(noreturn)
+    )
+}
@@ -0,0 +1,185 @@
+//! Frame pointer backtraces.
+//!
+//! The kernel is built with `force-frame-pointers`, so every function's prologue leaves
+//! `s0` pointing just above its frame, with the return address at `s0 - 8` and the
+//! caller's `s0` at `s0 - 16`. Following that chain gives the return addresses.
+//!
+//! Addresses are symbolized from `.ksyms`, which `make symbols` fills in after linking
+//! with the output of `nm`. If it wasn't run the table is empty and only addresses are
+//! printed. `addr2line -e target/riscv64gc-unknown-none-elf/debug/kernel` will do the rest.
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::linker_info::text;
+
+/// Space for the symbol table. Must match `KSYMS_SIZE` in the Makefile.
+const KSYMS_SIZE: usize = 256 * 1024;
+
+/// Give up after this many frames. Something has probably gone wrong by then.
+const MAX_FRAMES: usize = 64;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS_SPACE: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    // Read through these rather than KSYMS_SPACE, so the compiler can't assume it's
+    // still all zeros.
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// Set while printing a backtrace. If walking the stack faults, the nested panic
+/// shouldn't try again.
+static IN_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+/// Return addresses found by following the frame pointer chain.
+pub struct Frames {
+    fp: usize,
+    depth: usize,
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let fp = self.fp;
+        if fp == 0 || fp % 8 != 0 || self.depth >= MAX_FRAMES {
+            return None;
+        }
+
+        let (ra, prev_fp) = unsafe {
+            let frame = fp as *const usize;
+            (*frame.sub(1), *frame.sub(2))
+        };
+        if !text().contains(&(ra as u64)) {
+            return None;
+        }
+
+        // Stacks grow down, so callers' frames are always at higher addresses.
+        self.fp = if prev_fp > fp { prev_fp } else { 0 };
+        self.depth += 1;
+        Some(ra)
+    }
+}
+
+/// Walk the frames starting at frame pointer `fp`.
+///
+/// # Safety
+/// `fp` must be a frame pointer on a valid kernel stack.
+pub unsafe fn frames(fp: usize) -> Frames {
+    Frames { fp, depth: 0 }
+}
+
+/// Frame pointer of the caller.
+#[inline(always)]
+pub fn current_fp() -> usize {
+    let fp: usize;
+    unsafe {
+        asm!("mv {fp}, s0", fp = out(reg) fp);
+    }
+    fp
+}
+
+fn symbol_table() -> &'static [u8] {
+    unsafe {
+        let start = &__ksyms_start as *const u8;
+        let end = &__ksyms_end as *const u8;
+        let table = core::slice::from_raw_parts(start, end as usize - start as usize);
+        // Padded with zeros.
+        let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+        &table[..len]
+    }
+}
+
+/// Find the function containing `addr`. Returns its name and the offset into it.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    if !text().contains(&(addr as u64)) {
+        return None;
+    }
+
+    let table = core::str::from_utf8(symbol_table()).ok()?;
+    // `nm -n` output: "<address> <type> <name>", sorted by address.
+    let mut found = None;
+    for line in table.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (sym_addr, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(sym_addr), Some(_kind), Some(name)) => (sym_addr, name),
+            _ => continue,
+        };
+        let sym_addr = match usize::from_str_radix(sym_addr, 16) {
+            Ok(sym_addr) => sym_addr,
+            Err(_) => continue,
+        };
+        if sym_addr > addr {
+            break;
+        }
+        found = Some((name, addr - sym_addr));
+    }
+    found
+}
+
+/// The address of the symbol `name`.
+pub fn lookup(name: &str) -> Option<usize> {
+    let table = core::str::from_utf8(symbol_table()).ok()?;
+    table.lines().find_map(|line| {
+        let mut parts = line.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(addr), Some(_kind), Some(sym)) if sym == name => {
+                usize::from_str_radix(addr, 16).ok()
+            }
+            _ => None,
+        }
+    })
+}
+
+fn print_frame(w: &mut dyn Write, index: usize, addr: usize, lookup: usize) -> fmt::Result {
+    match symbolize(lookup) {
+        Some((name, offset)) => writeln!(
+            w,
+            "  {index:>2}: 0x{addr:016x} {name}+0x{:x}",
+            offset + addr - lookup
+        ),
+        None => writeln!(w, "  {index:>2}: 0x{addr:016x}"),
+    }
+}
+
+fn print_frames(w: &mut dyn Write, pc: Option<usize>, fp: usize) -> fmt::Result {
+    writeln!(w, "backtrace:")?;
+    let mut index = 0;
+    if let Some(pc) = pc {
+        print_frame(w, index, pc, pc)?;
+        index += 1;
+    }
+    for ra in unsafe { frames(fp) } {
+        // ra is the instruction after the call. Which may be the start of the next
+        // function if the call was the last thing in this one, so look up the byte before.
+        print_frame(w, index, ra, ra - 1)?;
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Print a backtrace starting from frame pointer `fp`. `pc` is printed first if given,
+/// for traps where the faulting instruction isn't a return address.
+///
+/// # Safety
+/// `fp` must be a frame pointer on a valid kernel stack.
+pub unsafe fn print(w: &mut dyn Write, pc: Option<usize>, fp: usize) -> fmt::Result {
+    if IN_BACKTRACE.swap(true, Ordering::Acquire) {
+        return writeln!(w, "backtrace: faulted while printing a backtrace");
+    }
+    let result = print_frames(w, pc, fp);
+    IN_BACKTRACE.store(false, Ordering::Release);
+    result
+}
+
+/// Print a backtrace of the caller.
+#[inline(always)]
+pub fn print_current(w: &mut dyn Write) -> fmt::Result {
+    unsafe { print(w, None, current_fp()) }
+}
@@ -1,19 +1,211 @@
-use core::sync::atomic::{AtomicBool, Ordering};
-use core::fmt::Write;
-use linked_list_allocator::LockedHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::{self, Display, Formatter, Write};
+use core::ops::Range;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use linked_list_allocator::Heap;
+use spin::Mutex;
 
+use crate::addr::{PhysAddr, VirtAddr};
 use crate::console::sbi_console;
 use crate::hwinfo::{PhysicalAddressRange, PhysicalAddressKind, HwInfo, DtbRef};
+use crate::log;
+use crate::pagetable::{phys_to_virt, regions::subtract, virt_to_phys};
+use crate::poison;
+use crate::slab::{self, Size, Slabs};
+use crate::trace::trace;
 
 const BASIC_POOL_SIZE: usize = 1024 * 1024;
+/// Most of RAM the heap gets before we've read the device tree. Anything the bootloader
+/// put above this (like an initrd) survives until `finish_init` knows where it is.
+const EARLY_HEAP_MAX: usize = 16 * 1024 * 1024;
+/// Most separate pieces of RAM the heap can be made of. The first is the one the kernel
+/// booted in.
+const MAX_BANKS: usize = 8;
+/// Pieces of RAM smaller than this aren't worth a bank.
+const MIN_BANK_SIZE: u64 = 64 * 1024;
+/// Allocations are counted by size, in powers of two from 16 bytes up to 64 KiB, and
+/// one more for anything bigger.
+pub const SIZE_CLASSES: usize = 14;
+const SMALLEST_CLASS: u32 = 4;
 
 // Mutable so it get's linked into the correct section. mut keyword may not actually be necessary.
 
 static mut BASIC_POOL: BasicPoolMemory = BasicPoolMemory::new();
 static HAS_INIT: AtomicBool = AtomicBool::new(false);
+/// End of the first bank's RAM, while the initrd is keeping the heap from growing into it.
+/// 0 otherwise.
+static HELD_FOR_INITRD: AtomicU64 = AtomicU64::new(0);
+/// The initrd, when it's in some other bank and becomes a bank of its own once it's
+/// released.
+static INITRD_BANK: Mutex<Option<Range<PhysAddr>>> = Mutex::new(None);
 
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: Banks = Banks {
+    heaps: Mutex::new([EMPTY; MAX_BANKS]),
+    slabs: Slabs::new(),
+};
+
+const EMPTY: Heap = Heap::empty();
+
+static COUNTERS: Counters = Counters {
+    in_use: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+    allocations: AtomicU64::new(0),
+    frees: AtomicU64::new(0),
+    failures: AtomicU64::new(0),
+    by_class: [const { AtomicU64::new(0) }; SIZE_CLASSES],
+};
+
+/// Kept outside the heap's lock, so they can be a little behind each other.
+struct Counters {
+    /// Bytes asked for and not yet freed.
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicU64,
+    frees: AtomicU64,
+    failures: AtomicU64,
+    by_class: [AtomicU64; SIZE_CLASSES],
+}
+
+impl Counters {
+    fn allocated(&self, layout: Layout) {
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.by_class[size_class(layout.size())].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn freed(&self, layout: Layout) {
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Which of the [`SIZE_CLASSES`] an allocation of `size` bytes counts under.
+fn size_class(size: usize) -> usize {
+    let bits = size.max(1).next_power_of_two().trailing_zeros();
+    (bits.saturating_sub(SMALLEST_CLASS) as usize).min(SIZE_CLASSES - 1)
+}
+
+/// A heap for each piece of RAM. Allocations come from the first with room, except small
+/// and page sized ones, which go through the [`slab`](crate::slab) caches.
+struct Banks {
+    heaps: Mutex<[Heap; MAX_BANKS]>,
+    slabs: Slabs,
+}
+
+impl Banks {
+    fn lock(&self) -> spin::MutexGuard<'_, [Heap; MAX_BANKS]> {
+        self.heaps.lock()
+    }
+
+    fn alloc_from_heap(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.lock()
+            .iter_mut()
+            .find_map(|heap| heap.allocate_first_fit(layout).ok())
+    }
+
+    unsafe fn dealloc_to_heap(&self, ptr: *mut u8, layout: Layout) {
+        let mut heaps = self.lock();
+        let heap = heaps
+            .iter_mut()
+            .find(|heap| heap.bottom() <= ptr && ptr < heap.top())
+            .expect("dealloc of memory outside the heap");
+        heap.deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+unsafe impl GlobalAlloc for Banks {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = Size::of(layout);
+        let ptr = match size {
+            Size::Large => self.alloc_from_heap(layout),
+            size => self.slabs.alloc(size, || {
+                let page = self.alloc_from_heap(slab::page_layout())?;
+                if poison::ENABLED {
+                    poison::fill(page.as_ptr(), slab::page_layout().size());
+                }
+                Some(page)
+            }),
+        };
+        match ptr {
+            Some(ptr) => {
+                if let (true, Some(bytes)) = (poison::ENABLED, size.bytes()) {
+                    poison::check_object(ptr.as_ptr(), bytes);
+                }
+                COUNTERS.allocated(layout);
+                trace!(Alloc, layout.size(), ptr.as_ptr() as usize);
+                ptr.as_ptr()
+            }
+            None => {
+                COUNTERS.failures.fetch_add(1, Ordering::Relaxed);
+                null_mut()
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = Size::of(layout);
+        if poison::ENABLED {
+            poison::fill(ptr, size.bytes().unwrap_or(layout.size()));
+        }
+        match size {
+            Size::Large => self.dealloc_to_heap(ptr, layout),
+            size => {
+                if let Some(page) = self.slabs.dealloc(NonNull::new_unchecked(ptr), size) {
+                    self.dealloc_to_heap(page.as_ptr(), slab::page_layout());
+                }
+            }
+        }
+        COUNTERS.freed(layout);
+        trace!(Free, layout.size(), ptr as usize);
+    }
+}
+
+/// Running out of heap panics with everything [`stats`] knows, rather than just the size.
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    panic!(
+        "out of heap allocating {} bytes, aligned to {}\n{}",
+        layout.size(),
+        layout.align(),
+        stats()
+    )
+}
+
+/// Biggest allocation `heap` could make right now. The allocator doesn't say, so this
+/// tries allocations until it finds it.
+fn largest_free(heap: &mut Heap) -> usize {
+    let (mut fits, mut too_big) = (0, heap.free() + 1);
+    while fits + 1 < too_big {
+        let size = fits + (too_big - fits) / 2;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                unsafe { heap.deallocate(ptr, layout) };
+                fits = size;
+            }
+            Err(()) => too_big = size,
+        }
+    }
+    fits
+}
+
+/// Start another bank with the physical `range`. False if there are too many already.
+///
+/// # Safety
+/// Nothing else may use `range`.
+unsafe fn add_bank(heaps: &mut [Heap; MAX_BANKS], range: Range<PhysAddr>) -> bool {
+    match heaps.iter_mut().find(|heap| heap.size() == 0) {
+        Some(heap) => {
+            heap.init(phys_to_virt(range.start).as_mut_ptr(), (range.end - range.start) as usize);
+            true
+        }
+        None => false,
+    }
+}
 
 #[repr(align(4096))]
 struct BasicPoolMemory {
@@ -34,31 +226,220 @@ impl BasicPoolMemory {
 
 pub(crate) unsafe fn init_from_free_space(start: *mut u8, end: &DtbRef) {
     assert!((start as usize) < (end.start() as usize));
-    let heap_size = (end.start() as usize) - (start as usize);
+    let heap_size = ((end.start() as usize) - (start as usize)).min(EARLY_HEAP_MAX);
     unsafe {
         writeln!(sbi_console(), "HEAP BYTES: {}", heap_size).ok();
     }
-    let mut heap = HEAP.lock();
-    heap.init(start, heap_size);
+    let mut heaps = HEAP.lock();
+    heaps[0].init(start, heap_size);
+}
+
+/// Each bank of the heap, by physical address.
+pub fn heap_ranges() -> Vec<PhysicalAddressRange> {
+    // Collected first so the allocation doesn't happen with the heap locked.
+    let mut ranges = [(null_mut(), null_mut()); MAX_BANKS];
+    for (range, heap) in ranges.iter_mut().zip(HEAP.lock().iter()) {
+        *range = (heap.bottom(), heap.top());
+    }
+    ranges
+        .into_iter()
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| {
+            let phys = |ptr| virt_to_phys(VirtAddr::from_ptr(ptr));
+            let range = phys(start)..phys(end);
+            PhysicalAddressRange::new(range, PhysicalAddressKind::Writable, "heap")
+        })
+        .collect()
+}
+
+/// Bytes of heap in use, and free, over all the banks. What's free in the slab caches
+/// counts as free.
+pub fn heap_usage() -> (usize, usize) {
+    let cached = HEAP.slabs.cached();
+    let (used, free) = HEAP
+        .lock()
+        .iter()
+        .fold((0, 0), |(used, free), heap| (used + heap.used(), free + heap.free()));
+    (used.saturating_sub(cached), free + cached)
+}
+
+/// What the heap has been up to.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes asked for and not freed yet.
+    pub in_use: usize,
+    pub peak: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Allocations there wasn't room for.
+    pub failures: u64,
+    /// Allocations made of each size. See [`SIZE_CLASSES`].
+    pub by_class: [u64; SIZE_CLASSES],
+    /// Free in the heap itself.
+    pub free: usize,
+    /// Free in the slab caches.
+    pub cached: usize,
+    /// Biggest single allocation that would fit.
+    pub largest_free: usize,
+}
+
+pub fn stats() -> HeapStats {
+    let (free, largest_free) = HEAP.lock().iter_mut().fold((0, 0), |(free, largest), heap| {
+        (free + heap.free(), largest.max(largest_free(heap)))
+    });
+    HeapStats {
+        in_use: COUNTERS.in_use.load(Ordering::Relaxed),
+        peak: COUNTERS.peak.load(Ordering::Relaxed),
+        allocations: COUNTERS.allocations.load(Ordering::Relaxed),
+        frees: COUNTERS.frees.load(Ordering::Relaxed),
+        failures: COUNTERS.failures.load(Ordering::Relaxed),
+        by_class: core::array::from_fn(|class| COUNTERS.by_class[class].load(Ordering::Relaxed)),
+        free,
+        cached: HEAP.slabs.cached(),
+        largest_free,
+    }
 }
 
-pub fn heap_range() -> PhysicalAddressRange {
-    let heap = HEAP.lock();
-    let start = heap.bottom() as u64;
-    let end = heap.top() as u64;
-    PhysicalAddressRange::new(start..end, PhysicalAddressKind::Writable, "heap".into())
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} bytes in use, {} at most, {} free and {} more in slab caches",
+            self.in_use, self.peak, self.free, self.cached
+        )?;
+        writeln!(
+            f,
+            "{} bytes in the largest free block",
+            self.largest_free
+        )?;
+        writeln!(
+            f,
+            "{} allocations, {} frees, {} failed",
+            self.allocations, self.frees, self.failures
+        )?;
+        for (class, count) in self.by_class.iter().enumerate() {
+            let size = 1u64 << (class as u32 + SMALLEST_CLASS);
+            if class == SIZE_CLASSES - 1 {
+                write!(f, "  >{:<6} {}", size / 2, count)?;
+            } else {
+                writeln!(f, "  <={:<5} {}", size, count)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub(crate) unsafe fn finish_init(hwinfo: &HwInfo) {
-    let ram = &hwinfo.ram[0];
-    let end_of_ram = ram.end;
-    let mut heap = HEAP.lock();
-    let top = heap.top() as u64;
+    // Everything's worked out before the heap is locked again, since allocating with it
+    // locked would wait on ourselves.
+    let (bottom, top) = {
+        let heaps = HEAP.lock();
+        (heaps[0].bottom(), heaps[0].top())
+    };
+    let bottom = virt_to_phys(VirtAddr::from_ptr(bottom));
+    let top = virt_to_phys(VirtAddr::from_ptr(top));
+    let first = hwinfo
+        .ram
+        .iter()
+        .position(|ram| ram.start <= bottom && bottom < ram.end)
+        .expect("early heap isn't in RAM");
+    let ram = hwinfo.ram[first];
+    let reserved: Vec<Range<PhysAddr>> = hwinfo
+        .reserved_memory
+        .iter()
+        .map(PhysicalAddressRange::as_range)
+        .collect();
+    if reserved.iter().any(|hole| hole.start < top && hole.end > bottom) {
+        writeln!(sbi_console(), "reserved memory overlaps the early heap").ok();
+    }
+    // The first bank ends at the first reservation after it. Firmware may put one in the
+    // middle of RAM. What's past it is added as banks of its own below.
+    let end_of_bank = reserved
+        .iter()
+        .filter(|hole| hole.start >= top)
+        .fold(ram.end, |end, hole| end.min(hole.start));
+
+    // Grow the first bank. Stop short of the initrd if it's in the way. release_initrd
+    // hands over the rest once it's unpacked.
+    let limit = match hwinfo.initrd {
+        Some(initrd) if initrd.start >= top && initrd.end <= end_of_bank => {
+            HELD_FOR_INITRD.store(end_of_bank.as_u64(), Ordering::Release);
+            initrd.start
+        }
+        Some(initrd) if initrd.end > bottom && initrd.start < end_of_bank => {
+            writeln!(sbi_console(), "initrd overlaps the early heap. It may be corrupt.").ok();
+            end_of_bank
+        }
+        _ => end_of_bank,
+    };
+
+    // Everything else, leaving out what's reserved.
+    let mut holes = reserved;
+    if let Some(initrd) = hwinfo.initrd {
+        let in_first = initrd.start < end_of_bank && initrd.end > ram.start;
+        if !in_first {
+            holes.push(initrd.as_range());
+            *INITRD_BANK.lock() = Some(initrd.as_range());
+        }
+    }
+    let mut pieces = Vec::new();
+    for (i, ram) in hwinfo.ram.iter().enumerate() {
+        let start = if i == first { end_of_bank } else { ram.start };
+        pieces.extend(
+            subtract(start..ram.end, &holes)
+                .into_iter()
+                .filter(|piece| piece.end - piece.start >= MIN_BANK_SIZE),
+        );
+    }
+
+    let mut heaps = HEAP.lock();
+    if top < limit {
+        heaps[0].extend((limit - top) as usize);
+    }
+    // Banks only run out, so once one doesn't fit none of the rest do.
+    let added = pieces
+        .iter()
+        .take_while(|piece| add_bank(&mut heaps, (*piece).clone()))
+        .count();
+    drop(heaps);
+
+    for piece in &pieces[added..] {
+        log::warn!(
+            "too many RAM banks, not using {:#x}..{:#x}",
+            piece.start,
+            piece.end
+        );
+    }
+}
+
+/// Give the memory the initrd was in to the heap. If it was in the first bank, that's
+/// everything after it too.
+///
+/// # Safety
+/// Nothing may use the initrd after this.
+pub(crate) unsafe fn release_initrd() {
+    if let Some(initrd) = INITRD_BANK.lock().take() {
+        let start = initrd.start.align_up(8).unwrap_or(initrd.end);
+        if start + MIN_BANK_SIZE <= initrd.end && !add_bank(&mut HEAP.lock(), start..initrd.end) {
+            log::warn!("too many RAM banks, not using the initrd's memory");
+        }
+    }
+
+    let end_of_ram = match HELD_FOR_INITRD.swap(0, Ordering::AcqRel) {
+        0 => return,
+        end_of_ram => PhysAddr::new(end_of_ram),
+    };
+    let mut heaps = HEAP.lock();
+    let top = virt_to_phys(VirtAddr::from_ptr(heaps[0].top()));
     if top < end_of_ram {
-        heap.extend((end_of_ram - top) as usize);
+        heaps[0].extend((end_of_ram - top) as usize);
     }
 }
 
+pub(crate) fn initrd_released() -> bool {
+    HELD_FOR_INITRD.load(Ordering::Acquire) == 0 && INITRD_BANK.lock().is_none()
+}
+
 pub(crate) fn init() {
     if HAS_INIT.swap(true, Ordering::Acquire) {
         return;
@@ -66,7 +447,23 @@ pub(crate) fn init() {
     unsafe {
         let (bottom, size) = BASIC_POOL.range();
 
-        let mut heap = HEAP.lock();
-        heap.init(bottom as *mut u8, size);
+        let mut heaps = HEAP.lock();
+        heaps[0].init(bottom as *mut u8, size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn size_classes() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(16), 0);
+        assert_eq!(size_class(17), 1);
+        assert_eq!(size_class(4096), 8);
+        assert_eq!(size_class(64 * 1024), SIZE_CLASSES - 2);
+        assert_eq!(size_class(64 * 1024 + 1), SIZE_CLASSES - 1);
+        assert_eq!(size_class(usize::MAX / 2), SIZE_CLASSES - 1);
     }
 }
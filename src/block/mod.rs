@@ -0,0 +1,127 @@
+//! Block devices.
+//!
+//! Drivers implement [`BlockDevice`] and [`register`] themselves at boot. Filesystems
+//! look them up by name with [`get`].
+
+pub mod ramdisk;
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+/// Bytes per block. Every device uses 512 byte blocks for now.
+pub const BLOCK_SIZE: usize = 512;
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request goes past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks.
+    BadBufferSize,
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+    Unsupported,
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "block out of range"),
+            BlockError::BadBufferSize => write!(f, "buffer is not a multiple of the block size"),
+            BlockError::ReadOnly => write!(f, "device is read only"),
+            BlockError::Io => write!(f, "i/o error"),
+            BlockError::Unsupported => write!(f, "operation not supported"),
+        }
+    }
+}
+
+impl core::error::Error for BlockError {}
+
+pub trait BlockDevice: Send + Sync {
+    /// Number of [`BLOCK_SIZE`] blocks on the device.
+    fn num_blocks(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Read `buf.len() / BLOCK_SIZE` blocks starting at `lba`.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / BLOCK_SIZE` blocks starting at `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Make sure earlier writes have reached the backing storage.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Check a request fits on a device with `num_blocks` blocks. Returns the block count.
+pub fn check_request(num_blocks: u64, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if len % BLOCK_SIZE != 0 {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (len / BLOCK_SIZE) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= num_blocks => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Make a device available as `name`. Names are numbered by the caller, eg. `vda`, `vdb`.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    crate::log::info!(
+        "{} ({} blocks{})",
+        name,
+        device.num_blocks(),
+        if device.read_only() {
+            ", read only"
+        } else {
+            ""
+        }
+    );
+    DEVICES.lock().push((name.into(), device));
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, device)| device.clone())
+}
+
+/// Every registered device and its name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock().clone()
+}
+
+/// Number of devices registered whose name starts with `prefix`. For picking the next name.
+pub fn count_with_prefix(prefix: &str) -> usize {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|(n, _)| n.starts_with(prefix))
+        .count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn block_request_bounds() {
+        assert_eq!(check_request(8, 0, 4 * BLOCK_SIZE), Ok(4));
+        assert_eq!(check_request(8, 4, 4 * BLOCK_SIZE), Ok(4));
+        assert_eq!(
+            check_request(8, 5, 4 * BLOCK_SIZE),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(check_request(8, 0, 100), Err(BlockError::BadBufferSize));
+    }
+}
@@ -0,0 +1,113 @@
+//! Block devices in RAM.
+//!
+//! Either an empty disk from [`vmalloc`], or a disk image built into the kernel. Set
+//! `RAMDISK_IMAGE` to the path of an image when building and it's registered as `ram0`
+//! at boot. Built in images are read only: they live in `.rodata`.
+
+use spin::Mutex;
+
+use super::{check_request, BlockDevice, BlockError, BLOCK_SIZE};
+use crate::vmalloc::{vmalloc, VmArea};
+
+enum Backing {
+    Heap(Mutex<VmArea>),
+    Static(&'static [u8]),
+}
+
+pub struct RamDisk {
+    backing: Backing,
+    num_blocks: u64,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `num_blocks` blocks.
+    pub fn new(num_blocks: u64) -> Self {
+        let bytes = vmalloc(num_blocks as usize * BLOCK_SIZE, "ramdisk")
+            .expect("no memory for a RAM disk");
+        RamDisk {
+            backing: Backing::Heap(Mutex::new(bytes)),
+            num_blocks,
+        }
+    }
+
+    /// A read only disk over `image`. Any partial block at the end is ignored.
+    pub fn from_static(image: &'static [u8]) -> Self {
+        RamDisk {
+            backing: Backing::Static(image),
+            num_blocks: (image.len() / BLOCK_SIZE) as u64,
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    fn read_only(&self) -> bool {
+        matches!(self.backing, Backing::Static(_))
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.num_blocks, lba, buf.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        let range = start..start + buf.len();
+        match &self.backing {
+            Backing::Heap(bytes) => buf.copy_from_slice(&bytes.lock()[range]),
+            Backing::Static(bytes) => buf.copy_from_slice(&bytes[range]),
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.num_blocks, lba, buf.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        match &self.backing {
+            Backing::Heap(bytes) => {
+                bytes.lock()[start..start + buf.len()].copy_from_slice(buf);
+                Ok(())
+            }
+            Backing::Static(_) => Err(BlockError::ReadOnly),
+        }
+    }
+}
+
+#[cfg(ramdisk_image)]
+static IMAGE: &[u8] = include_bytes!(env!("RAMDISK_IMAGE"));
+
+/// Register the built in image, if there is one.
+pub fn init() {
+    #[cfg(ramdisk_image)]
+    super::register("ram0", alloc::sync::Arc::new(RamDisk::from_static(IMAGE)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ramdisk_round_trip() {
+        let disk = RamDisk::new(4);
+        let data = [0xa5u8; 2 * BLOCK_SIZE];
+        disk.write_blocks(1, &data).unwrap();
+
+        let mut read = [0u8; 3 * BLOCK_SIZE];
+        disk.read_blocks(0, &mut read).unwrap();
+        assert!(read[..BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert!(read[BLOCK_SIZE..].iter().all(|&b| b == 0xa5));
+
+        assert_eq!(disk.write_blocks(3, &data), Err(BlockError::OutOfRange));
+    }
+
+    #[test_case]
+    fn ramdisk_static_is_read_only() {
+        static IMAGE: [u8; 2 * BLOCK_SIZE] = [7; 2 * BLOCK_SIZE];
+        let disk = RamDisk::from_static(&IMAGE);
+        assert_eq!(disk.num_blocks(), 2);
+
+        let mut read = [0u8; BLOCK_SIZE];
+        disk.read_blocks(1, &mut read).unwrap();
+        assert_eq!(read, [7; BLOCK_SIZE]);
+        assert_eq!(disk.write_blocks(0, &read), Err(BlockError::ReadOnly));
+    }
+}
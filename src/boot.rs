@@ -0,0 +1,197 @@
+//! Boot stages, and what went wrong in them.
+//!
+//! `kmain` runs the stages that can fail through [`stage`]. One that fails is logged with
+//! its name and error, and boot goes on without it. Stages can also [`report`] a problem
+//! they worked around, like a device tree node they had to skip. `boot` in the shell
+//! lists everything reported.
+//!
+//! Anything boot can't go on without goes to [`fatal`] instead.
+//!
+//! Stages are also [`timed`], in mtime ticks, since the first ones run before the heap or
+//! the clock are there. `_start` reads the time first thing, so the report starts with how
+//! long the firmware took, if mtime started at reset, and then `_start` itself: clearing
+//! `.bss`, mapping memory and turning paging on. `kmain` logs the report once it's booted,
+//! and `boot times` in the shell prints it again.
+
+use alloc::format;
+use core::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+use spin::Mutex;
+
+use crate::{
+    error::{KernelError, KernelResult},
+    log,
+    prelude::*,
+    time,
+};
+
+static PROBLEMS: Mutex<Vec<Problem>> = Mutex::new(Vec::new());
+
+/// Stages [`timed`] that there's room for. Later ones aren't kept.
+const MAX_TIMINGS: usize = 32;
+
+struct Timings {
+    list: [Timing; MAX_TIMINGS],
+    len: usize,
+}
+
+static TIMINGS: Mutex<Timings> = Mutex::new(Timings {
+    list: [Timing {
+        stage: "",
+        start: 0,
+        end: 0,
+    }; MAX_TIMINGS],
+    len: 0,
+});
+
+/// When a stage started and ended, in mtime ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    pub stage: &'static str,
+    start: u64,
+    end: u64,
+}
+
+impl Timing {
+    /// Since mtime started. Panics before the clock's set up, as do the others.
+    pub fn start(&self) -> Duration {
+        time::convert_mtime_to_duration(self.start)
+    }
+
+    pub fn time(&self) -> Duration {
+        time::convert_mtime_to_duration(self.end.saturating_sub(self.start))
+    }
+}
+
+impl Display for Timing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (start, time) = (self.start().as_micros(), self.time().as_micros());
+        write!(
+            f,
+            "{:>6}.{:03} ms {:>6}.{:03} ms  {}",
+            start / 1000,
+            start % 1000,
+            time / 1000,
+            time % 1000,
+            self.stage
+        )
+    }
+}
+
+fn ticks() -> u64 {
+    riscv::register::time::read() as u64
+}
+
+fn record(stage: &'static str, start: u64, end: u64) {
+    let mut timings = TIMINGS.lock();
+    let len = timings.len;
+    if let Some(slot) = timings.list.get_mut(len) {
+        *slot = Timing { stage, start, end };
+        timings.len += 1;
+    }
+}
+
+/// `_start` read mtime at `started`, and `kmain`'s just been called.
+pub fn entered(started: u64) {
+    let now = ticks();
+    record("firmware", 0, started);
+    record("_start", started, now);
+}
+
+/// Run `f`, timing it as stage `name`.
+pub fn timed<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = ticks();
+    let value = f();
+    record(name, start, ticks());
+    value
+}
+
+/// Every stage timed, in the order they finished.
+pub fn timings() -> Vec<Timing> {
+    let timings = TIMINGS.lock();
+    timings.list[..timings.len].to_vec()
+}
+
+/// Log how long each stage took. Once the clock's set up.
+pub fn log_timings() {
+    let timings = timings();
+    let Some(last) = timings.last() else {
+        return;
+    };
+    log::info!("booted in {:?}:", last.start() + last.time());
+    log::info!("     start       time      stage");
+    for timing in timings {
+        log::info!("{}", timing);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub stage: &'static str,
+    pub message: String,
+}
+
+/// Run the boot stage `name`, [`timed`]. Returns whether it worked.
+pub fn stage(name: &'static str, f: impl FnOnce() -> KernelResult<()>) -> bool {
+    match timed(name, f) {
+        Ok(()) => true,
+        Err(err) => {
+            report(name, &err);
+            false
+        }
+    }
+}
+
+/// Note a problem in stage `name` that boot carried on past.
+pub fn report(name: &'static str, err: &KernelError) {
+    log::error!("{}: {}", name, err);
+    PROBLEMS.lock().push(Problem {
+        stage: name,
+        message: format!("{}", err),
+    });
+}
+
+/// Stop booting. For stages nothing else works without, like reading the device tree.
+pub fn fatal(name: &'static str, err: &KernelError) -> ! {
+    panic!("boot failed: {}: {}", name, err)
+}
+
+/// Everything reported so far, in order.
+pub fn problems() -> Vec<Problem> {
+    PROBLEMS.lock().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::ProbeError;
+
+    #[test_case]
+    fn failed_stages_are_reported() {
+        let before = problems().len();
+        assert!(stage("test ok", || Ok(())));
+        assert!(!stage("test fail", || Err(ProbeError::NoDevice.into())));
+        let problems = problems();
+        assert_eq!(problems.len(), before + 1);
+        let last = problems.last().unwrap();
+        assert_eq!(
+            (last.stage, last.message.as_str()),
+            ("test fail", "no device")
+        );
+    }
+
+    #[test_case]
+    fn stages_are_timed() {
+        assert!(stage("test stage", || Ok(())));
+        assert_eq!(timed("test timed", || 42), 42);
+        let timings = timings();
+        let last = timings.last().unwrap();
+        assert_eq!(last.stage, "test timed");
+        assert!(last.start <= last.end);
+        assert!(last.start() <= crate::time::uptime().unwrap());
+        assert_eq!(timings[timings.len() - 2].stage, "test stage");
+        assert!(format!("{}", last).ends_with(" ms  test timed"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Kernel command line.
+//!
+//! `bootargs` from the device tree's `/chosen`, split on whitespace. Each word is a
+//! `key=value` option, like `init=/bin/sh`, or a bare flag, like `nosmp`. Double quotes
+//! keep spaces in a value: `init="/bin/my init"`. If an option is given twice the last one
+//! wins.
+
+use crate::sync::Once;
+
+use crate::prelude::*;
+
+/// Where init is looked for without `init=`, in order.
+pub const DEFAULT_INITS: &[&str] = &["/sbin/init", "/bin/init"];
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+#[derive(Debug, Clone, Default)]
+pub struct Cmdline {
+    /// Each word, and its value if it had one.
+    words: Vec<(String, Option<String>)>,
+}
+
+impl Cmdline {
+    pub fn parse(args: &str) -> Cmdline {
+        let mut words = Vec::new();
+        let mut chars = args.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let mut word = String::new();
+            let mut quoted = false;
+            while let Some(c) = chars.next_if(|&c| quoted || !c.is_whitespace()) {
+                match c {
+                    '"' => quoted = !quoted,
+                    c => word.push(c),
+                }
+            }
+            match word.split_once('=') {
+                Some((key, value)) => words.push((key.into(), Some(value.into()))),
+                None => words.push((word, None)),
+            }
+        }
+        Cmdline { words }
+    }
+
+    /// The value of `key=value`. `None` if it's missing or a bare flag.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.words
+            .iter()
+            .rev()
+            .find(|(name, _)| name == key)?
+            .1
+            .as_deref()
+    }
+
+    /// Whether `name` is there at all, with or without a value.
+    pub fn flag(&self, name: &str) -> bool {
+        self.words.iter().any(|(word, _)| word == name)
+    }
+}
+
+/// Parse the command line. Once `setup_dtb` has run, before anything asks for an option.
+pub fn init(bootargs: &str) {
+    CMDLINE.call_once(|| Cmdline::parse(bootargs));
+}
+
+fn cmdline() -> Option<&'static Cmdline> {
+    CMDLINE.get()
+}
+
+/// The value of `key=value` on the command line.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()?.get(key)
+}
+
+pub fn flag(name: &str) -> bool {
+    cmdline().map_or(false, |cmdline| cmdline.flag(name))
+}
+
+/// The program to run as `init`, from `init=`. `None` to try [`DEFAULT_INITS`].
+pub fn init_path() -> Option<&'static str> {
+    get("init")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn cmdline_options_and_flags() {
+        let cmdline =
+            Cmdline::parse("  nosmp init=/bin/sh log=info,fs=debug init=\"/bin/my init\" quiet=");
+        assert!(cmdline.flag("nosmp"));
+        assert!(cmdline.flag("init"));
+        assert!(!cmdline.flag("smp"));
+        assert_eq!(cmdline.get("nosmp"), None);
+        assert_eq!(cmdline.get("init"), Some("/bin/my init"));
+        assert_eq!(cmdline.get("log"), Some("info,fs=debug"));
+        assert_eq!(cmdline.get("quiet"), Some(""));
+    }
+}
@@ -0,0 +1,390 @@
+//! A text console on the first framebuffer.
+//!
+//! It's a [`ConsoleSink`], so everything printed shows up on the screen as well as the
+//! UART, panics included. Glyphs are the 8x8 [`font`] drawn twice as tall. It understands
+//! enough ANSI escapes for colour, moving the cursor and clearing: `ESC [ ... m` with the
+//! sixteen colours, `H`, `A` to `D`, `J` and `K`.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use crate::{
+    graphics::{self, font, Color, Framebuffer, Rect},
+    sync::{IrqSafeMutex, Once},
+};
+
+use super::ConsoleSink;
+
+const SCALE: u32 = 2;
+const CELL_WIDTH: u32 = font::WIDTH;
+const CELL_HEIGHT: u32 = font::HEIGHT * SCALE;
+const TAB: usize = 8;
+const MAX_PARAMS: usize = 8;
+
+/// The VGA colours: black, red, green, yellow, blue, magenta, cyan and white, then the
+/// bright versions.
+const PALETTE: [Color; 16] = [
+    Color::rgb(0x00, 0x00, 0x00),
+    Color::rgb(0xaa, 0x00, 0x00),
+    Color::rgb(0x00, 0xaa, 0x00),
+    Color::rgb(0xaa, 0x55, 0x00),
+    Color::rgb(0x00, 0x00, 0xaa),
+    Color::rgb(0xaa, 0x00, 0xaa),
+    Color::rgb(0x00, 0xaa, 0xaa),
+    Color::rgb(0xaa, 0xaa, 0xaa),
+    Color::rgb(0x55, 0x55, 0x55),
+    Color::rgb(0xff, 0x55, 0x55),
+    Color::rgb(0x55, 0xff, 0x55),
+    Color::rgb(0xff, 0xff, 0x55),
+    Color::rgb(0x55, 0x55, 0xff),
+    Color::rgb(0xff, 0x55, 0xff),
+    Color::rgb(0x55, 0xff, 0xff),
+    Color::rgb(0xff, 0xff, 0xff),
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+static CONSOLE: Once<Arc<FbConsole>> = Once::INIT;
+
+/// Put the console on the first framebuffer, if there is one and `console=` doesn't leave
+/// out `fb`.
+pub fn init() {
+    if !super::wanted("fb") {
+        return;
+    }
+    let Some(fb) = graphics::get(0) else {
+        return;
+    };
+    let console = CONSOLE.call_once(|| Arc::new(FbConsole::new(fb)));
+    super::add_sink(console.clone());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    fg: u8,
+    bg: u8,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        c: ' ',
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    /// After `ESC`.
+    Escape,
+    /// After `ESC [`. `params[len - 1]` is the one being read.
+    Csi {
+        params: [u16; MAX_PARAMS],
+        len: usize,
+    },
+}
+
+/// The screen as text. Knows nothing about framebuffers, so it can be tested.
+struct Screen {
+    columns: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+    column: usize,
+    row: usize,
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    state: State,
+    /// Cells changed since [`Screen::take_changes`], by index.
+    changed: Vec<usize>,
+    /// Everything moved up this many rows since [`Screen::take_changes`].
+    scrolled: usize,
+}
+
+impl Screen {
+    fn new(columns: usize, rows: usize) -> Screen {
+        Screen {
+            columns,
+            rows,
+            cells: vec![Cell::BLANK; columns * rows],
+            column: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            state: State::Normal,
+            changed: Vec::new(),
+            scrolled: 0,
+        }
+    }
+
+    fn set(&mut self, row: usize, column: usize, cell: Cell) {
+        let index = row * self.columns + column;
+        if self.cells[index] != cell {
+            self.cells[index] = cell;
+            self.changed.push(index);
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        Cell {
+            bg: self.bg,
+            ..Cell::BLANK
+        }
+    }
+
+    fn clear(&mut self, from: usize, to: usize) {
+        let blank = self.blank();
+        for index in from..to {
+            self.set(index / self.columns, index % self.columns, blank);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        self.cells.copy_within(self.columns.., 0);
+        let last = self.cells.len() - self.columns;
+        // In the default colours, the same as `Framebuffer::scroll_up` leaves it.
+        self.cells[last..].fill(Cell::BLANK);
+        self.scrolled += 1;
+        // Whatever changed has moved up a row too.
+        let columns = self.columns;
+        self.changed
+            .retain_mut(|index| match index.checked_sub(columns) {
+                Some(moved) => {
+                    *index = moved;
+                    true
+                }
+                None => false,
+            });
+    }
+
+    fn put(&mut self, c: char) {
+        let fg = if self.bold && self.fg < 8 {
+            self.fg + 8
+        } else {
+            self.fg
+        };
+        let cell = Cell { c, fg, bg: self.bg };
+        self.set(self.row, self.column, cell);
+        self.column += 1;
+        if self.column == self.columns {
+            self.newline();
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.state = match (self.state, byte) {
+            (State::Normal, 0x1b) => State::Escape,
+            (State::Normal, _) => {
+                self.control_or_print(byte);
+                State::Normal
+            }
+            (State::Escape, b'[') => State::Csi {
+                params: [0; MAX_PARAMS],
+                len: 1,
+            },
+            (State::Escape, _) => State::Normal,
+            (State::Csi { mut params, len }, b'0'..=b'9') => {
+                let param = &mut params[len - 1];
+                *param = param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+                State::Csi { params, len }
+            }
+            (State::Csi { params, len }, b';') => State::Csi {
+                params,
+                len: (len + 1).min(MAX_PARAMS),
+            },
+            (State::Csi { params, len }, 0x40..=0x7e) => {
+                self.csi(byte, &params[..len]);
+                State::Normal
+            }
+            // Intermediate bytes and private markers, like `?`.
+            (State::Csi { .. }, 0x20..=0x3f) => self.state,
+            (State::Csi { .. }, _) => State::Normal,
+        };
+    }
+
+    fn control_or_print(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            8 => self.column = self.column.saturating_sub(1),
+            // Rubs out the character before the cursor, like the UART's "\x08 \x08".
+            0x7f => {
+                self.column = self.column.saturating_sub(1);
+                let blank = self.blank();
+                self.set(self.row, self.column, blank);
+            }
+            b'\t' => self.column = ((self.column / TAB + 1) * TAB).min(self.columns - 1),
+            b' '..=b'~' => self.put(byte as char),
+            // One box for each UTF-8 character, at its first byte.
+            0xc0.. => self.put(char::REPLACEMENT_CHARACTER),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, command: u8, params: &[u16]) {
+        // Counts of 0 mean 1.
+        let count = params[0].max(1) as usize;
+        match command {
+            b'm' => params.iter().for_each(|&param| self.sgr(param)),
+            b'H' | b'f' => {
+                self.row = (count - 1).min(self.rows - 1);
+                let column = params.get(1).map_or(1, |&column| column.max(1));
+                self.column = (column as usize - 1).min(self.columns - 1);
+            }
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = (self.row + count).min(self.rows - 1),
+            b'C' => self.column = (self.column + count).min(self.columns - 1),
+            b'D' => self.column = self.column.saturating_sub(count),
+            b'J' | b'K' => {
+                let cursor = self.row * self.columns + self.column;
+                let (start, end) = match command {
+                    b'J' => (0, self.cells.len()),
+                    _ => (self.row * self.columns, (self.row + 1) * self.columns),
+                };
+                match params[0] {
+                    0 => self.clear(cursor, end),
+                    1 => self.clear(start, cursor + 1),
+                    _ => self.clear(start, end),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition: colours and bold.
+    fn sgr(&mut self, param: u16) {
+        match param {
+            0 => {
+                self.fg = DEFAULT_FG;
+                self.bg = DEFAULT_BG;
+                self.bold = false;
+            }
+            1 => self.bold = true,
+            22 => self.bold = false,
+            30..=37 => self.fg = (param - 30) as u8,
+            39 => self.fg = DEFAULT_FG,
+            40..=47 => self.bg = (param - 40) as u8,
+            49 => self.bg = DEFAULT_BG,
+            90..=97 => self.fg = (param - 90) as u8 + 8,
+            100..=107 => self.bg = (param - 100) as u8 + 8,
+            _ => {}
+        }
+    }
+
+    /// What's changed since last time: how far it scrolled, and which cells to draw.
+    fn take_changes(&mut self) -> (usize, Vec<usize>) {
+        let changed = core::mem::take(&mut self.changed);
+        (core::mem::replace(&mut self.scrolled, 0), changed)
+    }
+}
+
+pub struct FbConsole {
+    fb: Arc<Framebuffer>,
+    screen: IrqSafeMutex<Screen>,
+}
+
+impl FbConsole {
+    fn new(fb: Arc<Framebuffer>) -> FbConsole {
+        let columns = (fb.width() / CELL_WIDTH) as usize;
+        let rows = (fb.height() / CELL_HEIGHT) as usize;
+        fb.fill(fb.bounds(), PALETTE[DEFAULT_BG as usize]);
+        fb.flush(fb.bounds()).ok();
+        FbConsole {
+            fb,
+            screen: IrqSafeMutex::new(Screen::new(columns, rows)),
+        }
+    }
+
+    fn draw(&self, screen: &Screen, index: usize, cursor: bool) {
+        let cell = screen.cells[index];
+        let (mut fg, mut bg) = (PALETTE[cell.fg as usize], PALETTE[cell.bg as usize]);
+        if cursor {
+            core::mem::swap(&mut fg, &mut bg);
+        }
+        let x = (index % screen.columns) as u32 * CELL_WIDTH;
+        let y = (index / screen.columns) as u32 * CELL_HEIGHT;
+        font::draw(&self.fb, x, y, SCALE, cell.c, fg, bg);
+    }
+}
+
+impl ConsoleSink for FbConsole {
+    fn write(&self, bytes: &[u8]) {
+        // Already drawing on this hart means we panicked doing it. Leave it to the UART.
+        let Some(mut screen) = self.screen.try_lock() else {
+            return;
+        };
+        let old_cursor = screen.row * screen.columns + screen.column;
+        for &byte in bytes {
+            screen.write(byte);
+        }
+        let cursor = screen.row * screen.columns + screen.column;
+        let (scrolled, mut changed) = screen.take_changes();
+
+        let bg = PALETTE[DEFAULT_BG as usize];
+        self.fb.scroll_up(scrolled as u32 * CELL_HEIGHT, bg);
+        // Rub out the old cursor, wherever scrolling took it.
+        if let Some(old_cursor) = old_cursor.checked_sub(scrolled * screen.columns) {
+            changed.push(old_cursor);
+        }
+        for &index in &changed {
+            self.draw(&screen, index, index == cursor);
+        }
+        self.draw(&screen, cursor, true);
+
+        let rows = changed
+            .iter()
+            .chain([&cursor])
+            .map(|index| index / screen.columns);
+        let (top, bottom) = match scrolled {
+            0 => (rows.clone().min().unwrap(), rows.max().unwrap()),
+            _ => (0, screen.rows - 1),
+        };
+        let height = (bottom - top + 1) as u32 * CELL_HEIGHT;
+        let dirty = Rect::new(0, top as u32 * CELL_HEIGHT, self.fb.width(), height);
+        self.fb.flush(dirty).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::String;
+
+    fn text(screen: &Screen, row: usize) -> String {
+        let cells = &screen.cells[row * screen.columns..(row + 1) * screen.columns];
+        cells.iter().map(|cell| cell.c).collect::<String>()
+    }
+
+    #[test_case]
+    fn ansi_screen() {
+        let mut screen = Screen::new(8, 4);
+        for &byte in b"hi\tx\n\x1b[31;1mred\x1b[0m\x7f\n" {
+            screen.write(byte);
+        }
+        // The `x` wrapped, so the newline left a blank line.
+        assert_eq!(text(&screen, 0), "hi     x");
+        assert_eq!(text(&screen, 1), "        ");
+        assert_eq!(text(&screen, 2), "re      ");
+        assert_eq!(screen.cells[16].fg, 9);
+        assert_eq!((screen.row, screen.column), (3, 0));
+        assert_eq!(screen.take_changes().0, 0);
+
+        for &byte in b"one\ntwo\x1b[2;2H\x1b[K" {
+            screen.write(byte);
+        }
+        assert_eq!(screen.take_changes().0, 1);
+        assert_eq!(text(&screen, 1), "r       ");
+        assert_eq!(text(&screen, 2), "one     ");
+        assert_eq!(text(&screen, 3), "two     ");
+        assert_eq!((screen.row, screen.column), (1, 1));
+    }
+}
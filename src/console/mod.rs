@@ -1,49 +1,418 @@
-mod uart_ns16550a;
+pub(crate) mod framebuffer;
+pub(crate) mod sbi;
+pub(crate) mod serial;
+pub(crate) mod tty;
+pub(crate) mod uart_ns16550a;
 
+use alloc::{string::String, sync::Arc, vec::Vec};
 use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
 use core::str;
-use spin::{Mutex, MutexGuard, Once};
-
-use crate::console::uart_ns16550a::MmioSerialPort;
+use core::task::{Context, Poll, Waker};
+use crate::sync::Once;
+
+use crate::console::tty::{Tty, TtyPort};
+use crate::console::uart_ns16550a::{
+    InterruptEnable, LineConfig, LineErrorCounts, MmioSerialPort, MmioSerialReceiver,
+    MmioSerialTransmitter, UartError, TX_FIFO_DEPTH,
+};
+use crate::cmdline;
+use crate::fs::devfs::CharDevice;
 use crate::hwinfo::HwInfo;
+use crate::isr::plic::{self, InterruptId};
+use crate::pagetable::memory_map::ioremap;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+use crate::task::console::{ByteQueue, UART_QUEUE};
+use crate::trap::gdbstub;
+use crate::workqueue::Work;
+
+const TX_QUEUE_SIZE: usize = 4096;
+const RX_QUEUE_SIZE: usize = 256;
+/// Consoles besides the UART when there's no `console=`. The SBI console is usually the
+/// same serial port as the UART, so it has to be asked for.
+const DEFAULT_CONSOLES: &str = "hvc,fb";
+
+static NS16550A: Once<IrqSafeMutex<MmioSerialPort>> = Once::INIT;
+/// Which of [`HwInfo::uarts`] that is.
+static CONSOLE_UART: Once<usize> = Once::INIT;
+static RECEIVER: Once<MmioSerialReceiver> = Once::INIT;
+/// The interrupt handler takes this too.
+static TRANSMITTER: Once<IrqSafeMutex<MmioSerialTransmitter>> = Once::INIT;
+/// Output waiting for the UART. Written by whoever holds the [`NS16550A`] lock.
+static TX_QUEUE: ByteQueue<TX_QUEUE_SIZE> = ByteQueue::new();
+/// Input the interrupt handler took from the UART, for [`RX_WORK`] to pass on.
+static RX_QUEUE: ByteQueue<RX_QUEUE_SIZE> = ByteQueue::new();
+static RX_WORK: Work = Work::new("uart rx", receive);
+/// Everywhere else console output goes.
+static SINKS: IrqSafeMutex<Vec<Arc<dyn ConsoleSink>>> = IrqSafeMutex::new(Vec::new());
+/// [`UART_QUEUE`] takes one producer at a time. This is held by whoever's pushing.
+static INPUT: IrqSafeMutex<()> = IrqSafeMutex::new(());
+/// The line discipline processes see the console through.
+static CONSOLE_TTY: Once<Arc<Tty>> = Once::INIT;
+
+/// Another console, like a telnet session or a virtio-console. It gets a copy of everything
+/// printed, and what it feeds to [`push_input`] is read along with what's typed on the UART.
+pub trait ConsoleSink: Send + Sync {
+    /// Output as it was printed: backspace and line endings are left for the sink.
+    /// Called with the console locked and interrupts off, so it mustn't print, and anything
+    /// it waits for has to finish without interrupts. Panics come here too.
+    fn write(&self, bytes: &[u8]);
+}
 
-static NS16550A: Once<Mutex<MmioSerialPort>> = Once::INIT;
+pub fn add_sink(sink: Arc<dyn ConsoleSink>) {
+    SINKS.lock().push(sink);
+}
+
+pub fn remove_sink(sink: &Arc<dyn ConsoleSink>) {
+    SINKS.lock().retain(|other| !Arc::ptr_eq(other, sink));
+}
+
+/// Input from a [`ConsoleSink`]'s side, for whoever reads the console.
+pub fn push_input(bytes: &[u8]) {
+    let _input = INPUT.lock();
+    for &byte in bytes {
+        if !intercept(byte) {
+            UART_QUEUE.push(byte);
+        }
+    }
+    UART_QUEUE.wake();
+}
 
 pub fn init(info: &HwInfo) {
+    let console = *CONSOLE_UART.call_once(|| select_uart(info));
     NS16550A.call_once(|| {
-        let uart = &info.uart;
+        let uart = &info.uarts[console];
         let mut sp = unsafe {
-            MmioSerialPort::new(uart.reg.start as usize, uart.interrupt)
+            let base = ioremap(uart.reg.start, uart.reg.end - uart.reg.start, "UART");
+            MmioSerialPort::new(base.as_u64() as usize, uart.interrupt, uart.clock_freq)
         };
-        sp.init().expect("failed to initialize serial port");
+        let line = cmdline::get("uart");
+        let configured = line.map(|line| line.parse().and_then(|config| sp.init(config)));
+        if !matches!(configured, Some(Ok(()))) {
+            sp.init(LineConfig::default())
+                .expect("failed to initialize serial port");
+        }
         writeln!(sp, "Serial Port initialized!").ok();
+        if let Some(Err(err)) = configured {
+            writeln!(sp, "uart={}: {}", line.unwrap_or_default(), err).ok();
+        }
 
-        Mutex::new(sp)
+        RECEIVER.call_once(|| sp.receiver());
+        TRANSMITTER.call_once(|| IrqSafeMutex::new(sp.transmitter()));
+        RX_WORK.register();
+        plic::register_handler(uart.interrupt, uart_interrupt);
+
+        IrqSafeMutex::new(sp)
     });
+    enable_interrupts();
+    serial::init(info, console);
+    if wanted("sbi") {
+        sbi::init();
+    }
+}
+
+/// The UART for the console: `ttyS<n>` in `console=`, or the one `stdout-path` names.
+fn select_uart(info: &HwInfo) -> usize {
+    cmdline::get("console")
+        .unwrap_or_default()
+        .split(',')
+        .find_map(|name| name.strip_prefix("ttyS")?.parse().ok())
+        .filter(|&index| index < info.uarts.len())
+        .unwrap_or(info.console_uart)
+}
+
+/// Which of [`HwInfo::uarts`] the console is on, once [`init`] has run.
+pub fn uart_index() -> Option<usize> {
+    CONSOLE_UART.get().copied()
+}
+
+/// Whether to use the console backend `name` as well as the UART: `sbi` for the SBI debug
+/// console, `hvc` for virtio-console and `fb` for the framebuffer. They're listed in
+/// `console=`, comma separated, along with `ttyS<n>` to pick the UART.
+pub fn wanted(name: &str) -> bool {
+    cmdline::get("console")
+        .unwrap_or(DEFAULT_CONSOLES)
+        .split(',')
+        .any(|backend| backend == name)
+}
+
+/// The console UART's line settings, if they're known, and the receive errors it's seen.
+pub fn uart_status() -> Option<(Option<LineConfig>, LineErrorCounts)> {
+    let uart = NS16550A.get()?.lock();
+    Some((uart.line_config(), uart.errors()))
+}
+
+/// Change the console UART's baud rate and framing. `uart=` sets them at boot.
+pub fn set_uart_line(config: LineConfig) -> Result<(), UartError> {
+    let mut uart = NS16550A.get().ok_or(UartError::UnknownClock)?.lock();
+    // Keeps the interrupt handler from writing while the divisor latch is in the way.
+    let _tx = TRANSMITTER.get().unwrap().lock();
+    uart.set_line(config)
+}
+
+/// Whether [`init`] has run, so `print!` works.
+pub fn is_initialized() -> bool {
+    NS16550A.get().is_some()
 }
 
 pub(crate) fn enable_interrupts() {
-    // NS16550A.get().unwrap().lock().enable_interrupts();
+    let mut tx = TRANSMITTER.get().unwrap().lock();
+    let enable = tx.interrupts() | InterruptEnable::RDI;
+    tx.set_interrupts(enable);
+}
+
+/// Move as much of [`TX_QUEUE`] into the UART as it'll take. Leaves THRI enabled while
+/// there's more to send, so the interrupt handler picks up the rest.
+fn drain_tx() {
+    let transmitter = match TRANSMITTER.get() {
+        Some(transmitter) => transmitter,
+        None => return,
+    };
+
+    let mut tx = transmitter.lock();
+    if tx.fifo_empty() {
+        for _ in 0..TX_FIFO_DEPTH {
+            match TX_QUEUE.pop() {
+                Some(byte) => tx.write_unchecked(byte),
+                None => break,
+            }
+        }
+    }
+
+    let mut enable = tx.interrupts();
+    enable.set(InterruptEnable::THRI, !TX_QUEUE.is_empty());
+    tx.set_interrupts(enable);
+}
+
+fn queue_byte(byte: u8) {
+    while TX_QUEUE.is_full() {
+        // Interrupts may be off (printing from a trap handler). So push it out ourselves.
+        drain_tx();
+        crate::cpu::relax();
+    }
+    TX_QUEUE.push(byte);
 }
 
-struct PendingBytes {
-    uart: &'static Mutex<MmioSerialPort>,
+/// Queue output for the UART. Caller must hold the [`NS16550A`] lock.
+///
+/// Expands backspace the same way [`MmioSerialPort::send`] does.
+fn write_buffered(bytes: &[u8]) {
+    for &byte in bytes {
+        match byte {
+            8 | 0x7F => {
+                queue_byte(8);
+                queue_byte(b' ');
+                queue_byte(8);
+            }
+            _ => queue_byte(byte),
+        }
+    }
+    drain_tx();
+    write_sinks(bytes);
 }
 
+/// Copy `s` to the [`SINKS`], unless they're busy changing.
+fn write_sinks(bytes: &[u8]) {
+    if let Some(sinks) = SINKS.try_lock() {
+        for sink in sinks.iter() {
+            sink.write(bytes);
+        }
+    }
+}
+
+/// Wait until everything queued has been handed to the UART. Sleeps between FIFO refills
+/// if interrupts are on, since the UART interrupts when it wants more.
+pub fn flush() {
+    if riscv::register::sstatus::read().sie() {
+        return crate::isr::wait_until(|| {
+            drain_tx();
+            TX_QUEUE.is_empty()
+        });
+    }
+    while !TX_QUEUE.is_empty() {
+        drain_tx();
+        crate::cpu::relax();
+    }
+}
+
+/// Send whatever is queued synchronously, without the transmitter lock.
+///
+/// For the panic and exception paths, which need earlier output to come out first and
+/// can't trust that the lock holder will ever let go.
+unsafe fn flush_unlocked(port: &mut MmioSerialPort) {
+    while let Some(byte) = TX_QUEUE.pop() {
+        port.send_raw(byte);
+    }
+}
+
+/// PLIC handler. Moves everything in the receive FIFO into [`RX_QUEUE`], for [`receive`]
+/// later, and refills the transmit FIFO. The debugger's break in is seen straight away.
+fn uart_interrupt(_interrupt: InterruptId) {
+    let receiver = match RECEIVER.get() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+
+    let mut received = false;
+    while let Some(byte) = receiver.try_receive() {
+        if gdbstub::watch(byte) {
+            continue;
+        }
+        RX_QUEUE.push(byte);
+        received = true;
+    }
+
+    if received {
+        RX_WORK.schedule();
+    }
+
+    drain_tx();
+}
+
+/// Deferred from [`uart_interrupt`]: pass what it received on to [`UART_QUEUE`], and wake
+/// whoever's reading.
+fn receive() {
+    let _input = INPUT.lock();
+    let mut received = false;
+    while let Some(byte) = RX_QUEUE.pop() {
+        if !intercept(byte) {
+            UART_QUEUE.push(byte);
+            received = true;
+        }
+    }
+    if received {
+        UART_QUEUE.wake();
+    }
+}
+
+/// Let the console tty act on `^C` before it's queued, so a process that isn't reading
+/// can be interrupted.
+fn intercept(byte: u8) -> bool {
+    CONSOLE_TTY.get().map_or(false, |tty| tty.intercept(byte))
+}
+
+struct PendingBytes;
+
 impl Iterator for PendingBytes {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.uart.lock().try_receive()
+        UART_QUEUE.pop()
     }
 }
 
+/// Bytes received since the last call. Doesn't wait for more.
 pub(crate) fn pending_bytes() -> impl Iterator<Item = u8> {
-    let uart = NS16550A.get().expect("Serial Port initialized");
-    PendingBytes { uart }
+    PendingBytes
+}
+
+/// Future for the next received byte.
+pub struct ReadByte {
+    _private: (),
+}
+
+impl Future for ReadByte {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        if let Some(byte) = UART_QUEUE.pop() {
+            return Poll::Ready(byte);
+        }
+        UART_QUEUE.register_waker(cx.waker());
+        // A byte may have arrived while we were registering.
+        match UART_QUEUE.pop() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub fn read_byte_async() -> ReadByte {
+    ReadByte { _private: () }
+}
+
+/// Wait for the next received byte. Runs spawned tasks meanwhile, and sleeps the hart
+/// when there's nothing else to do.
+pub fn read_byte() -> u8 {
+    crate::task::block_on(read_byte_async())
+}
+
+/// The console's end of [`CONSOLE_TTY`]: input from [`UART_QUEUE`], output like `print!`
+/// but without going through formatting.
+struct ConsolePort;
+
+impl TtyPort for ConsolePort {
+    fn receive(&self) -> Option<u8> {
+        UART_QUEUE.pop()
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        UART_QUEUE.register_waker(waker);
+    }
+
+    fn transmit(&self, bytes: &[u8]) {
+        crate::panic::park_if_panicking();
+        let _uart = NS16550A.get().unwrap().lock();
+        write_buffered(bytes);
+    }
+}
+
+fn console_tty() -> &'static Arc<Tty> {
+    CONSOLE_TTY.call_once(|| Arc::new(Tty::new(Arc::new(ConsolePort))))
+}
+
+/// The console as a device, for `/dev/console` and processes' standard descriptors.
+pub fn device() -> Arc<dyn CharDevice> {
+    console_tty().clone()
+}
+
+/// A byte straight from the UART, if it has one. Bypasses [`UART_QUEUE`].
+pub(crate) fn try_receive() -> Option<u8> {
+    RECEIVER.get()?.try_receive()
+}
+
+/// Wait for the next received byte with interrupts off, for the debugger. Spins on the
+/// UART, and pushes out queued output while it waits.
+pub(crate) fn read_byte_polled() -> u8 {
+    loop {
+        flush();
+        if let Some(byte) = UART_QUEUE.pop().or_else(|| RX_QUEUE.pop()) {
+            return byte;
+        }
+        if let Some(byte) = try_receive() {
+            return byte;
+        }
+        crate::cpu::relax();
+    }
+}
+
+/// Read a line, echoing it back. Handles backspace. The line ending isn't included.
+pub fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match read_byte() {
+            b'\r' | b'\n' => {
+                crate::print!("\n");
+                return line;
+            }
+            // Backspace or delete
+            8 | 0x7F => {
+                if line.pop().is_some() {
+                    // write_buffered turns this into "\x08 \x08"
+                    crate::print!("\x7f");
+                }
+            }
+            byte if byte.is_ascii() && !byte.is_ascii_control() => {
+                line.push(byte as char);
+                crate::print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
 }
 
-struct ForceUnlockedWriter(MutexGuard<'static, MmioSerialPort>);
+struct ForceUnlockedWriter(IrqSafeMutexGuard<'static, MmioSerialPort>);
 
 impl fmt::Write for ForceUnlockedWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
@@ -54,7 +423,8 @@ impl fmt::Write for ForceUnlockedWriter {
 pub unsafe fn force_unlock() -> impl core::fmt::Write {
     if let Some(uart) = NS16550A.get() {
         uart.force_unlock();
-        let lock = uart.lock();
+        let mut lock = uart.lock();
+        flush_unlocked(&mut lock);
         return ForceUnlockedWriter(lock);
     }
 
@@ -65,9 +435,11 @@ pub unsafe fn force_unlock() -> impl core::fmt::Write {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments, file: &str, line: u32, column: u32) {
+    // The panicking hart has the console now.
+    crate::panic::park_if_panicking();
     if let Some(uart) = NS16550A.get() {
-        let mut lock = uart.lock();
-        core::fmt::Write::write_fmt(&mut *lock, args).ok();
+        let mut lock = LockHandle(uart.lock());
+        core::fmt::Write::write_fmt(&mut lock, args).ok();
     } else {
         panic!("Attempted to print before console was initialized. {file}:{line}:{column}\n{args}")
     }
@@ -88,14 +460,18 @@ macro_rules! println {
 }
 
 #[derive(Debug)]
-struct LockHandle(MutexGuard<'static, MmioSerialPort>);
+struct LockHandle(IrqSafeMutexGuard<'static, MmioSerialPort>);
 
 impl fmt::Write for LockHandle {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        self.0.write_str(s)
+        write_buffered(s.as_bytes());
+        Ok(())
     }
 }
 
+/// The console, for writing a lot at once without other harts' output in the middle. Only
+/// once [`init`] has run: before that, or where taking a lock isn't safe, there's
+/// [`sbi_console`].
 pub(crate) fn lock() -> impl fmt::Write {
     let lock = NS16550A.get().unwrap().lock();
     LockHandle(lock)
@@ -103,35 +479,31 @@ pub(crate) fn lock() -> impl fmt::Write {
 
 pub enum LockOrDummy {
     Dummy,
-    Normal(MutexGuard<'static, MmioSerialPort>),
+    Normal(IrqSafeMutexGuard<'static, MmioSerialPort>),
 }
 
 impl fmt::Write for LockOrDummy {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         match self {
             LockOrDummy::Dummy => Ok(()),
-            LockOrDummy::Normal(n) => n.write_str(s),
-        }
-    }
-
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        match self {
-            LockOrDummy::Dummy => Ok(()),
-            LockOrDummy::Normal(n) => n.write_char(c),
+            LockOrDummy::Normal(_) => {
+                write_buffered(s.as_bytes());
+                Ok(())
+            }
         }
     }
 
     fn write_fmt(self: &mut Self, args: core::fmt::Arguments<'_>) -> core::fmt::Result {
         match self {
             LockOrDummy::Dummy => Ok(()),
-            LockOrDummy::Normal(n) => n.write_fmt(args),
+            LockOrDummy::Normal(_) => fmt::write(self, args),
         }
     }
 }
 
 /// Get a writer if it's available. Otherwise get a dummy writer which does
 pub(crate) fn lock_or_dummy() -> impl fmt::Write {
-    match NS16550A.get().unwrap().try_lock() {
+    match NS16550A.get().and_then(|uart| uart.try_lock()) {
         Some(l) => LockOrDummy::Normal(l),
         None => LockOrDummy::Dummy,
     }
@@ -140,42 +512,22 @@ pub(crate) fn lock_or_dummy() -> impl fmt::Write {
 #[derive(Debug)]
 enum PanicWriter {
     Fallback,
-    Normal(MutexGuard<'static, MmioSerialPort>),
-}
-
-impl PanicWriter {
-    fn fallback_write(&self, s: &str) -> core::fmt::Result {
-        for b in s.bytes() {
-            #[allow(deprecated)]
-            crate::sbi::_legacy_putchar(b);
-        }
-        Ok(())
-    }
+    Normal(IrqSafeMutexGuard<'static, MmioSerialPort>),
 }
 
 impl Write for PanicWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         match self {
-            PanicWriter::Normal(w) => w.write_str(s),
-            PanicWriter::Fallback => self.fallback_write(s),
-        }
-    }
-
-    fn write_char(&mut self, c: char) -> core::fmt::Result {
-        match self {
-            PanicWriter::Normal(w) => w.write_char(c),
-            PanicWriter::Fallback => self.fallback_write(&c.encode_utf8(&mut [0; 4])),
-        }
-    }
-
-    fn write_fmt(mut self: &mut Self, args: core::fmt::Arguments<'_>) -> core::fmt::Result {
-        match self {
-            PanicWriter::Fallback => core::fmt::write(&mut self, args),
-            PanicWriter::Normal(w) => w.write_fmt(args),
+            PanicWriter::Normal(w) => {
+                write_sinks(s.as_bytes());
+                w.write_str(s)
+            }
+            PanicWriter::Fallback => SbiWriter.write_str(s),
         }
     }
 }
 
+/// Writes a byte at a time through the firmware's legacy console call.
 pub struct SbiWriter;
 
 impl Write for SbiWriter {
@@ -188,6 +540,9 @@ impl Write for SbiWriter {
     }
 }
 
+/// Straight to the firmware, with no lock and no buffering. For before the UART is up, and
+/// for the allocator and panic handler, which can't wait on [`lock`]. Output can interleave
+/// with the UART's.
 pub(crate) unsafe fn sbi_console() -> impl fmt::Write {
     SbiWriter
 }
@@ -197,25 +552,10 @@ pub(crate) unsafe fn _panic_unlock() -> impl fmt::Write {
     match NS16550A.get() {
         Some(lock) => {
             unsafe { lock.force_unlock() };
-            PanicWriter::Normal(lock.lock())
+            let mut port = lock.lock();
+            flush_unlocked(&mut port);
+            PanicWriter::Normal(port)
         }
         None => PanicWriter::Fallback,
     }
 }
-
-/*
-struct MmioSerialPort {
-    data: AtomicPtr<u8>,
-    int_en: AtomicPtr<u8>,
-    fifo_ctrl: AtomicPtr<u8>,
-    line_ctrl: AtomicPtr<u8>,
-    modem_ctrl: AtomicPtr<u8>,
-    line_sts: AtomicPtr<u8>,
-    info:
-}
-
-impl MmioSerialPort {
-    pub unsafe fn new(info: &'static UartNS16550a) -> Self {
-
-    }
-} */
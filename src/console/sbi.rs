@@ -0,0 +1,94 @@
+//! The SBI debug console, for firmware that has one.
+//!
+//! Output is copied to a buffer the firmware reads by physical address. There's no
+//! interrupt for input, so a task polls for it. On QEMU the firmware's console is the
+//! same serial port as the UART, so this is only on with `console=sbi`.
+
+use alloc::sync::Arc;
+use core::time::Duration;
+
+use crate::{
+    dma::{self, DmaBuffer},
+    log,
+    sbi::dbcn::{self, DebugConsoleExtension},
+    sync::{IrqSafeMutex, HIGH_PRIORITY},
+    task, time,
+};
+
+use super::ConsoleSink;
+
+const BUFFER: usize = 256;
+/// How often to ask the firmware for input.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+struct SbiConsole {
+    extension: &'static DebugConsoleExtension,
+    output: IrqSafeMutex<DmaBuffer>,
+}
+
+impl SbiConsole {
+    /// Hand the first `len` bytes of `buffer` to the firmware.
+    fn send(&self, buffer: &DmaBuffer, len: usize) {
+        buffer.sync_for_device();
+        let mut written = 0;
+        while written < len {
+            let phys = buffer.phys() + written as u64;
+            match unsafe { self.extension.write(phys, len - written) } {
+                Ok(n) => written += n,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl ConsoleSink for SbiConsole {
+    fn write(&self, bytes: &[u8]) {
+        let Some(mut buffer) = self.output.try_lock() else {
+            return;
+        };
+        let mut len = 0;
+        for &byte in bytes {
+            // Backspace the same way as the UART.
+            let expanded: &[u8] = match byte {
+                8 | 0x7f => b"\x08 \x08",
+                _ => core::slice::from_ref(&byte),
+            };
+            if len + expanded.len() > BUFFER {
+                self.send(&buffer, len);
+                len = 0;
+            }
+            unsafe { buffer.as_mut_slice()[len..len + expanded.len()].copy_from_slice(expanded) };
+            len += expanded.len();
+        }
+        self.send(&buffer, len);
+    }
+}
+
+async fn poll_input(extension: &'static DebugConsoleExtension, buffer: DmaBuffer) {
+    loop {
+        match unsafe { extension.read(buffer.phys(), BUFFER) } {
+            Ok(0) | Err(_) => time::sleep_async(POLL_INTERVAL).await,
+            Ok(len) => {
+                buffer.sync_for_cpu();
+                super::push_input(unsafe { &buffer.as_slice()[..len] });
+            }
+        }
+    }
+}
+
+pub(crate) fn init() {
+    let Some(extension) = dbcn::debug_console_extension() else {
+        log::warn!("console: the firmware has no debug console");
+        return;
+    };
+    let (Ok(output), Ok(input)) = (dma::alloc(BUFFER, 1), dma::alloc(BUFFER, 1)) else {
+        log::warn!("console: no memory for the SBI console");
+        return;
+    };
+    super::add_sink(Arc::new(SbiConsole {
+        extension,
+        output: IrqSafeMutex::new(output),
+    }));
+    // Ahead of everything else, so typing keeps up when the harts are busy.
+    task::spawn_with_priority(HIGH_PRIORITY, poll_input(extension, input));
+}
@@ -0,0 +1,108 @@
+//! The UARTs that aren't the console, as `/dev/ttyS<n>`.
+//!
+//! They're numbered by their place in [`HwInfo::uarts`], console included, so the
+//! console's number is left out. Each is a [`Tty`], so reads and writes go through the
+//! line discipline. The interrupt handler queues received bytes for it, and writes go
+//! straight out, spinning until the UART has room.
+
+use core::task::Waker;
+
+use alloc::{format, sync::Arc, vec::Vec};
+
+use crate::{
+    fs::devfs,
+    hwinfo::HwInfo,
+    isr::plic::{self, InterruptId},
+    log,
+    pagetable::memory_map::ioremap,
+    sync::{IrqSafeMutex, Once},
+    task::console::ByteQueue,
+};
+
+use super::tty::{Tty, TtyPort};
+use super::uart_ns16550a::{InterruptEnable, LineConfig, MmioSerialPort, MmioSerialReceiver};
+
+const INPUT_SIZE: usize = 256;
+
+static TTYS: Once<Vec<(Arc<SerialPort>, Arc<Tty>)>> = Once::INIT;
+
+/// A UART as the device end of a [`Tty`].
+pub struct SerialPort {
+    port: IrqSafeMutex<MmioSerialPort>,
+    /// Read by the interrupt handler without the port's lock, like the console's.
+    receiver: MmioSerialReceiver,
+    interrupt: InterruptId,
+    input: ByteQueue<INPUT_SIZE>,
+}
+
+impl TtyPort for SerialPort {
+    fn receive(&self) -> Option<u8> {
+        self.input.pop()
+    }
+
+    fn register_waker(&self, waker: &Waker) {
+        self.input.register_waker(waker);
+    }
+
+    fn transmit(&self, bytes: &[u8]) {
+        let mut port = self.port.lock();
+        for &byte in bytes {
+            port.send(byte);
+        }
+    }
+}
+
+/// PLIC handler. Moves received bytes into their port's queue, unless the tty acts on
+/// them first.
+fn serial_interrupt(interrupt: InterruptId) {
+    let Some(ttys) = TTYS.get() else {
+        return;
+    };
+    for (port, tty) in ttys.iter().filter(|(port, _)| port.interrupt == interrupt) {
+        let mut received = false;
+        while let Some(byte) = port.receiver.try_receive() {
+            if !tty.intercept(byte) {
+                port.input.push(byte);
+            }
+            received = true;
+        }
+        if received {
+            port.input.wake();
+        }
+    }
+}
+
+/// Set up every UART except the `console` one, and add them to `/dev`.
+pub fn init(info: &HwInfo, console: usize) {
+    let ttys = TTYS.call_once(|| {
+        let mut ttys = Vec::new();
+        for (index, uart) in info.uarts.iter().enumerate() {
+            if index == console {
+                continue;
+            }
+            let base = ioremap(uart.reg.start, uart.reg.size(), "UART").as_u64() as usize;
+            let mut port = unsafe { MmioSerialPort::new(base, uart.interrupt, uart.clock_freq) };
+            if let Err(err) = port.init(LineConfig::default()) {
+                log::warn!("ttyS{}: {}", index, err);
+                continue;
+            }
+            let port = Arc::new(SerialPort {
+                receiver: port.receiver(),
+                port: IrqSafeMutex::new(port),
+                interrupt: uart.interrupt,
+                input: ByteQueue::new(),
+            });
+            let tty = Arc::new(Tty::new(port.clone()));
+            devfs::register(&format!("ttyS{}", index), tty.clone());
+            ttys.push((port, tty));
+        }
+        ttys
+    });
+    for (port, _) in ttys {
+        plic::register_handler(port.interrupt, serial_interrupt);
+        port.port
+            .lock()
+            .transmitter()
+            .set_interrupts(InterruptEnable::RDI);
+    }
+}
@@ -0,0 +1,566 @@
+//! Terminal line discipline.
+//!
+//! A [`Tty`] sits between a [`TtyPort`], the console or a serial port, and the processes
+//! reading and writing it. In canonical mode (`ICANON`) input is collected a line at a
+//! time with erase, word erase and kill, and a read gets a line once it's finished. `^D`
+//! ends a line without a newline, so on an empty line it reads as end of file. Without
+//! `ICANON` reads get bytes as they come, waiting as `VMIN` and `VTIME` say. `ISIG` makes
+//! the interrupt and quit characters signal the last process to use the tty.
+//!
+//! The settings are a Linux `struct termios`, got and set with the `TCGETS` family of
+//! ioctls.
+
+use core::{future::poll_fn, task::Poll, task::Waker, time::Duration};
+
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    fs::{
+        self,
+        devfs::{CharDevice, PollFlags},
+        FsError,
+    },
+    process::{self, Pid, SIGINT, SIGQUIT},
+    sync::IrqSafeMutex,
+    task, time,
+};
+
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+/// Set after output drains. Output here never waits, so the same as `TCSETS`.
+pub const TCSETSW: u32 = 0x5403;
+/// Set and throw away input that hasn't been read.
+pub const TCSETSF: u32 = 0x5404;
+pub const TIOCGWINSZ: u32 = 0x5413;
+/// Bytes a read would get now.
+pub const FIONREAD: u32 = 0x541b;
+
+// c_iflag
+pub const INLCR: u32 = 0o100;
+pub const IGNCR: u32 = 0o200;
+pub const ICRNL: u32 = 0o400;
+
+// c_oflag
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+
+// c_cflag
+pub const CS8: u32 = 0o60;
+pub const CREAD: u32 = 0o200;
+
+// c_lflag
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const IEXTEN: u32 = 0o100000;
+
+// Indexes into c_cc.
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VWERASE: usize = 14;
+pub const NCCS: usize = 19;
+
+/// Longest line canonical mode holds. Anything typed past it is dropped.
+const MAX_LINE: usize = 4095;
+/// What `TIOCGWINSZ` says, since there's no asking the other end.
+const WINSIZE: (u16, u16) = (24, 80);
+
+/// Linux's `struct termios`, as the `TCGETS` ioctls see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+impl Termios {
+    pub const SIZE: usize = 4 * 4 + 1 + NCCS;
+
+    /// Raw mode, like `cfmakeraw`: no line editing, echo, signals or translation. Reads
+    /// wait for a byte.
+    pub fn raw() -> Termios {
+        let mut termios = Termios::default();
+        termios.iflag &= !(INLCR | IGNCR | ICRNL);
+        termios.oflag &= !OPOST;
+        termios.lflag &= !(ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHONL | IEXTEN);
+        termios
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        for (i, flag) in [self.iflag, self.oflag, self.cflag, self.lflag]
+            .iter()
+            .enumerate()
+        {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&flag.to_le_bytes());
+        }
+        bytes[16] = self.line;
+        bytes[17..].copy_from_slice(&self.cc);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Termios {
+        let flag = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        Termios {
+            iflag: flag(0),
+            oflag: flag(1),
+            cflag: flag(2),
+            lflag: flag(3),
+            line: bytes[16],
+            cc: bytes[17..].try_into().unwrap(),
+        }
+    }
+}
+
+impl Default for Termios {
+    /// Cooked, the way Linux starts a tty.
+    fn default() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03;
+        cc[VQUIT] = 0x1c;
+        cc[VERASE] = 0x7f;
+        cc[VKILL] = 0x15;
+        cc[VEOF] = 0x04;
+        cc[VMIN] = 1;
+        cc[VWERASE] = 0x17;
+        Termios {
+            iflag: ICRNL,
+            oflag: OPOST | ONLCR,
+            cflag: CS8 | CREAD,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+/// The device end of a tty.
+pub trait TtyPort: Send + Sync {
+    /// The next byte received, if there is one.
+    fn receive(&self) -> Option<u8>;
+
+    /// Wake `waker` when a byte comes in.
+    fn register_waker(&self, waker: &Waker);
+
+    /// Send `bytes` as they are. 8 and 0x7f rub out the last character, as they do
+    /// everywhere on the console.
+    fn transmit(&self, bytes: &[u8]);
+}
+
+struct State {
+    termios: Termios,
+    /// The line being typed, in canonical mode.
+    line: Vec<u8>,
+    /// Finished lines, each a separate read in canonical mode. An empty one is end of
+    /// file. Raw input is appended to the last.
+    input: VecDeque<Vec<u8>>,
+    /// A signal character came in. The next read gives up with `Interrupted`.
+    interrupted: bool,
+    /// Who the signal characters signal: whoever used the tty last.
+    foreground: Option<Pid>,
+}
+
+pub struct Tty {
+    port: Arc<dyn TtyPort>,
+    /// Interrupt handlers take it too, through [`intercept`](Self::intercept).
+    state: IrqSafeMutex<State>,
+}
+
+impl Tty {
+    pub fn new(port: Arc<dyn TtyPort>) -> Tty {
+        Tty {
+            port,
+            state: IrqSafeMutex::new(State {
+                termios: Termios::default(),
+                line: Vec::new(),
+                input: VecDeque::new(),
+                interrupted: false,
+                foreground: None,
+            }),
+        }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.state.lock().termios
+    }
+
+    pub fn set_termios(&self, termios: Termios) {
+        let mut state = self.state.lock();
+        // Going raw hands over the half typed line.
+        if state.termios.lflag & ICANON != 0 && termios.lflag & ICANON == 0 {
+            let line = core::mem::take(&mut state.line);
+            if !line.is_empty() {
+                state.input.push_back(line);
+            }
+        }
+        state.termios = termios;
+    }
+
+    /// Throw away input nobody's read.
+    pub fn flush_input(&self) {
+        let mut state = self.state.lock();
+        state.line.clear();
+        state.input.clear();
+        while self.port.receive().is_some() {}
+    }
+
+    /// For the port's interrupt handler: act on `byte` straight away if it's a signal
+    /// character, so a process that isn't reading can still be interrupted. Returns true
+    /// if it was, and shouldn't be queued.
+    ///
+    /// Only while the process that has the tty is running, so the kernel shell still gets
+    /// its `^C`.
+    pub fn intercept(&self, byte: u8) -> bool {
+        let mut state = self.state.lock();
+        let running = process::current().map(|process| process.pid());
+        running.is_some() && running == state.foreground && signal_char(&mut state, byte)
+    }
+
+    /// The current process is the one to signal now.
+    fn claim(&self) {
+        if let Some(process) = process::current() {
+            self.state.lock().foreground = Some(process.pid());
+        }
+    }
+
+    /// Run what the port has received through the discipline, echoing as it goes.
+    fn process_input(&self, state: &mut State) {
+        let mut echo = Vec::new();
+        while let Some(byte) = self.port.receive() {
+            receive(state, byte, &mut echo);
+        }
+        if !echo.is_empty() {
+            self.transmit(state.termios, &echo);
+        }
+    }
+
+    /// Output processing, then out the port.
+    fn transmit(&self, termios: Termios, bytes: &[u8]) {
+        if termios.oflag & (OPOST | ONLCR) != OPOST | ONLCR || !bytes.contains(&b'\n') {
+            return self.port.transmit(bytes);
+        }
+        let mut out = Vec::with_capacity(bytes.len() + 8);
+        for &byte in bytes {
+            if byte == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(byte);
+        }
+        self.port.transmit(&out);
+    }
+
+    /// Take what a read can have now, or `None` if it has to wait.
+    fn take(&self, buf: &mut [u8]) -> Option<fs::Result<usize>> {
+        let mut state = self.state.lock();
+        self.process_input(&mut state);
+        if state.interrupted {
+            state.interrupted = false;
+            self.transmit(state.termios, b"^C\n");
+            return Some(Err(FsError::Interrupted));
+        }
+        match state.termios.lflag & ICANON {
+            0 => take_raw(&mut state.input, buf),
+            _ => take_line(&mut state.input, buf),
+        }
+        .map(Ok)
+    }
+
+    fn poll_read(&self, buf: &mut [u8], waker: &Waker) -> Poll<fs::Result<usize>> {
+        if let Some(result) = self.take(buf) {
+            return Poll::Ready(result);
+        }
+        self.port.register_waker(waker);
+        // A byte may have come in while registering.
+        match self.take(buf) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+
+    fn ready(&self) -> usize {
+        let mut state = self.state.lock();
+        self.process_input(&mut state);
+        match state.termios.lflag & ICANON {
+            0 => state.input.iter().map(Vec::len).sum(),
+            _ => state.input.front().map_or(0, Vec::len),
+        }
+    }
+}
+
+/// `true` if `byte` is the interrupt or quit character and signals are on.
+fn signal_char(state: &mut State, byte: u8) -> bool {
+    let termios = state.termios;
+    if termios.lflag & ISIG == 0 {
+        return false;
+    }
+    let signal = match byte {
+        0 => return false,
+        byte if byte == termios.cc[VINTR] => SIGINT,
+        byte if byte == termios.cc[VQUIT] => SIGQUIT,
+        _ => return false,
+    };
+    // Only the running process can be waiting on the tty, so only it can be signalled.
+    if let Some(process) = process::current().filter(|p| Some(p.pid()) == state.foreground) {
+        process.signal(signal);
+    }
+    state.line.clear();
+    state.input.clear();
+    state.interrupted = true;
+    true
+}
+
+/// Input processing for one byte. Anything to echo is added to `echo`.
+fn receive(state: &mut State, byte: u8, echo: &mut Vec<u8>) {
+    let termios = state.termios;
+    let byte = match byte {
+        b'\r' if termios.iflag & IGNCR != 0 => return,
+        b'\r' if termios.iflag & ICRNL != 0 => b'\n',
+        b'\n' if termios.iflag & INLCR != 0 => b'\r',
+        byte => byte,
+    };
+    if signal_char(state, byte) {
+        return;
+    }
+    let echoing = termios.lflag & ECHO != 0;
+
+    if termios.lflag & ICANON == 0 {
+        match state.input.back_mut() {
+            Some(last) if !last.is_empty() => last.push(byte),
+            _ => state.input.push_back(vec![byte]),
+        }
+        if echoing {
+            echo.push(byte);
+        }
+        return;
+    }
+
+    let cc = termios.cc;
+    let erase = |count: usize, echo: &mut Vec<u8>| {
+        if echoing && termios.lflag & ECHOE != 0 {
+            echo.resize(echo.len() + count, 8);
+        }
+    };
+    match byte {
+        // Backspace too, whatever VERASE is.
+        byte if byte == 8 || byte == cc[VERASE] => {
+            if state.line.pop().is_some() {
+                erase(1, echo);
+            }
+        }
+        byte if byte == cc[VWERASE] && termios.lflag & IEXTEN != 0 => {
+            let line = &mut state.line;
+            let spaces = line
+                .iter()
+                .rev()
+                .take_while(|b| b.is_ascii_whitespace())
+                .count();
+            let word = line[..line.len() - spaces]
+                .iter()
+                .rev()
+                .take_while(|b| !b.is_ascii_whitespace())
+                .count();
+            line.truncate(line.len() - spaces - word);
+            erase(spaces + word, echo);
+        }
+        byte if byte == cc[VKILL] => {
+            let len = state.line.len();
+            state.line.clear();
+            if termios.lflag & ECHOK != 0 {
+                erase(len, echo);
+            }
+        }
+        byte if byte == cc[VEOF] => {
+            let line = core::mem::take(&mut state.line);
+            state.input.push_back(line);
+        }
+        b'\n' => {
+            let mut line = core::mem::take(&mut state.line);
+            line.push(b'\n');
+            state.input.push_back(line);
+            if echoing || termios.lflag & ECHONL != 0 {
+                echo.push(b'\n');
+            }
+        }
+        _ if state.line.len() >= MAX_LINE => {}
+        byte => {
+            state.line.push(byte);
+            if echoing {
+                echo_char(byte, echo);
+            }
+        }
+    }
+}
+
+/// Control characters echo as `^X`, so they can be seen.
+fn echo_char(byte: u8, echo: &mut Vec<u8>) {
+    if byte < 0x20 && byte != b'\t' {
+        echo.extend([b'^', byte + b'@']);
+    } else {
+        echo.push(byte);
+    }
+}
+
+/// At most one line.
+fn take_line(input: &mut VecDeque<Vec<u8>>, buf: &mut [u8]) -> Option<usize> {
+    let line = input.front_mut()?;
+    let n = line.len().min(buf.len());
+    buf[..n].copy_from_slice(&line[..n]);
+    line.drain(..n);
+    // A line that's all been read goes, and so does an end of file.
+    if line.is_empty() {
+        input.pop_front();
+    }
+    Some(n)
+}
+
+/// As much as there is, across lines. End of file markers are skipped.
+fn take_raw(input: &mut VecDeque<Vec<u8>>, buf: &mut [u8]) -> Option<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        let Some(chunk) = input.front_mut() else {
+            break;
+        };
+        let len = chunk.len().min(buf.len() - n);
+        buf[n..n + len].copy_from_slice(&chunk[..len]);
+        chunk.drain(..len);
+        n += len;
+        if chunk.is_empty() {
+            input.pop_front();
+        }
+    }
+    (n > 0).then_some(n)
+}
+
+impl CharDevice for Tty {
+    fn read(&self, buf: &mut [u8]) -> fs::Result<usize> {
+        self.claim();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let termios = self.termios();
+        let read = poll_fn(|cx| self.poll_read(buf, cx.waker()));
+        if termios.lflag & ICANON != 0 || termios.cc[VMIN] > 0 {
+            return task::block_on(read);
+        }
+        // VMIN 0: wait at most VTIME tenths of a second, which may be none at all.
+        let wait = Duration::from_millis(termios.cc[VTIME] as u64 * 100);
+        task::block_on(time::timeout(wait, read)).unwrap_or(Ok(0))
+    }
+
+    fn write(&self, buf: &[u8]) -> fs::Result<usize> {
+        self.claim();
+        self.transmit(self.termios(), buf);
+        Ok(buf.len())
+    }
+
+    fn poll(&self, interest: PollFlags, waker: &Waker) -> PollFlags {
+        let mut ready = interest & PollFlags::OUT;
+        if interest.contains(PollFlags::IN) {
+            if self.ready() == 0 {
+                self.port.register_waker(waker);
+            }
+            ready.set(PollFlags::IN, self.ready() > 0);
+        }
+        ready
+    }
+
+    fn ioctl(&self, request: u32, arg: u64) -> fs::Result<u64> {
+        self.claim();
+        let process = process::current().ok_or(FsError::Unsupported)?;
+        let mut memory = process.memory();
+        match request {
+            TCGETS => {
+                let termios = self.termios().to_bytes();
+                memory.copy_to_user(arg, &termios)
+            }
+            TCSETS | TCSETSW | TCSETSF => {
+                let mut termios = [0; Termios::SIZE];
+                memory
+                    .copy_from_user(arg, &mut termios)
+                    .map_err(|_| FsError::BadAddress)?;
+                if request == TCSETSF {
+                    self.flush_input();
+                }
+                self.set_termios(Termios::from_bytes(&termios));
+                Ok(())
+            }
+            TIOCGWINSZ => {
+                let (rows, columns) = WINSIZE;
+                let mut winsize = [0; 8];
+                winsize[..2].copy_from_slice(&rows.to_le_bytes());
+                winsize[2..4].copy_from_slice(&columns.to_le_bytes());
+                memory.copy_to_user(arg, &winsize)
+            }
+            FIONREAD => memory.copy_to_user(arg, &(self.ready() as i32).to_le_bytes()),
+            _ => return Err(FsError::Unsupported),
+        }
+        .map_err(|_| FsError::BadAddress)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed(state: &mut State, input: &[u8]) -> Vec<u8> {
+        let mut echo = Vec::new();
+        for &byte in input {
+            receive(state, byte, &mut echo);
+        }
+        echo
+    }
+
+    #[test_case]
+    fn line_discipline() {
+        let mut state = State {
+            termios: Termios::default(),
+            line: Vec::new(),
+            input: VecDeque::new(),
+            interrupted: false,
+            foreground: None,
+        };
+        let mut buf = [0; 16];
+
+        // Canonical: nothing until the line's done, then one line per read.
+        let echo = feed(&mut state, b"lss\x7f -l\rfoo bar\x17baz\n");
+        assert_eq!(echo, b"lss\x08 -l\nfoo bar\x08\x08\x08baz\n");
+        assert_eq!(take_line(&mut state.input, &mut buf), Some(6));
+        assert_eq!(&buf[..6], b"ls -l\n");
+        assert_eq!(take_line(&mut state.input, &mut buf), Some(8));
+        assert_eq!(&buf[..8], b"foo baz\n");
+        assert_eq!(take_line(&mut state.input, &mut buf), None);
+
+        // Kill and end of file.
+        feed(&mut state, b"junk\x15\x04");
+        assert_eq!(take_line(&mut state.input, &mut buf), Some(0));
+        assert_eq!(take_line(&mut state.input, &mut buf), None);
+
+        // ^C throws the line away.
+        feed(&mut state, b"half\x03");
+        assert!(state.interrupted);
+        assert!(state.line.is_empty());
+
+        // Raw: bytes as they come, no echo, no signals.
+        state.termios = Termios::raw();
+        state.interrupted = false;
+        assert!(feed(&mut state, b"w\x03\r").is_empty());
+        assert!(!state.interrupted);
+        assert_eq!(take_raw(&mut state.input, &mut buf), Some(3));
+        assert_eq!(&buf[..3], b"w\x03\r");
+
+        let termios = Termios::raw();
+        assert_eq!(Termios::from_bytes(&termios.to_bytes()), termios);
+    }
+}
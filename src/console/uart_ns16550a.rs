@@ -5,23 +5,262 @@
 /// Copyright (c) 2019 Philipp Oppermann
 /// Copyright (c) 2022 Triss Healy
 ///
+use alloc::sync::Arc;
 use core::{
-    fmt, str,
-    sync::atomic::{AtomicPtr, Ordering},
+    fmt::{self, Display, Formatter},
+    str::{self, FromStr},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use crate::{
     isr::plic::{self, InterruptId},
+    mmio::Reg,
     wait_for,
 };
 
+/// Bytes the transmit FIFO holds once it reports empty.
+pub const TX_FIFO_DEPTH: usize = 16;
+
+/// How far off the requested baud rate the divisor may land, in tenths of a percent.
+const BAUD_TOLERANCE: u64 = 25;
+
 bitflags::bitflags! {
     /// Line status flags
     struct LineStsFlags: u8 {
         const INPUT_FULL = 1;
-        // 1 to 4 unknown
+        const OVERRUN = 1 << 1;
+        const PARITY = 1 << 2;
+        const FRAMING = 1 << 3;
+        const BREAK = 1 << 4;
         const OUTPUT_EMPTY = 1 << 5;
-        // 6 and 7 unknown
+        /// The shift register is empty too, so the last byte is all the way out.
+        const TRANSMITTER_EMPTY = 1 << 6;
+        // 7 unknown
+    }
+}
+
+/// Set in `line_ctrl` to reach the divisor latch.
+const DLAB: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The UART's clock can't be divided down to this rate.
+    Baud(u32),
+    DataBits(u8),
+    /// No `clock-frequency`, so there's no working out a divisor.
+    UnknownClock,
+    /// Not `<baud>[parity[bits[stop]]]`.
+    Parse,
+}
+
+impl Display for UartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UartError::Baud(baud) => write!(f, "can't do {} baud", baud),
+            UartError::DataBits(bits) => write!(f, "can't do {} data bits", bits),
+            UartError::UnknownClock => write!(f, "unknown UART clock"),
+            UartError::Parse => write!(f, "expected <baud>[parity[bits[stop]]], like 115200n8"),
+        }
+    }
+}
+
+impl core::error::Error for UartError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Always 1.
+    Mark,
+    /// Always 0.
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// One and a half with 5 data bits.
+    Two,
+}
+
+/// Baud rate and framing.
+///
+/// Parses and prints the way Linux's `console=` does, `115200n8`, with an optional stop
+/// bit count on the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    /// 115200 8-N-1.
+    fn default() -> Self {
+        LineConfig {
+            baud: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl LineConfig {
+    /// `line_ctrl` for this, with DLAB clear.
+    fn line_ctrl(&self) -> Result<u8, UartError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(UartError::DataBits(self.data_bits));
+        }
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        };
+        Ok((self.data_bits - 5) | stop | parity << 3)
+    }
+}
+
+impl FromStr for LineConfig {
+    type Err = UartError;
+
+    fn from_str(s: &str) -> Result<Self, UartError> {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (baud, rest) = s.split_at(digits);
+        let mut config = LineConfig {
+            baud: baud.parse().map_err(|_| UartError::Parse)?,
+            ..LineConfig::default()
+        };
+        let mut rest = rest.chars();
+        if let Some(parity) = rest.next() {
+            config.parity = match parity.to_ascii_lowercase() {
+                'n' => Parity::None,
+                'o' => Parity::Odd,
+                'e' => Parity::Even,
+                'm' => Parity::Mark,
+                's' => Parity::Space,
+                _ => return Err(UartError::Parse),
+            };
+        }
+        if let Some(bits) = rest.next() {
+            config.data_bits = bits.to_digit(10).ok_or(UartError::Parse)? as u8;
+        }
+        config.stop_bits = match rest.next() {
+            None | Some('1') => StopBits::One,
+            Some('2') => StopBits::Two,
+            Some(_) => return Err(UartError::Parse),
+        };
+        match rest.next() {
+            None => Ok(config),
+            Some(_) => Err(UartError::Parse),
+        }
+    }
+}
+
+impl Display for LineConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'n',
+            Parity::Odd => 'o',
+            Parity::Even => 'e',
+            Parity::Mark => 'm',
+            Parity::Space => 's',
+        };
+        write!(f, "{}{}{}", self.baud, parity, self.data_bits)?;
+        match self.stop_bits {
+            StopBits::One => Ok(()),
+            StopBits::Two => write!(f, "2"),
+        }
+    }
+}
+
+/// The divisor latch value closest to `baud` from a `clock` Hz input clock.
+fn divisor(clock: u32, baud: u32) -> Result<u16, UartError> {
+    if baud == 0 {
+        return Err(UartError::Baud(baud));
+    }
+    let (clock, rate) = (clock as u64, baud as u64);
+    let divisor = (clock + 8 * rate) / (16 * rate);
+    if divisor == 0 || divisor > u16::MAX as u64 {
+        return Err(UartError::Baud(baud));
+    }
+    let actual = clock / (16 * divisor);
+    if actual.abs_diff(rate) * 1000 > rate * BAUD_TOLERANCE {
+        return Err(UartError::Baud(baud));
+    }
+    Ok(divisor as u16)
+}
+
+/// Receive errors the line status register has reported. Reading the register clears
+/// them, so every read counts them, whichever half of the port it's from.
+#[derive(Debug, Default)]
+pub struct LineErrors {
+    overrun: AtomicU64,
+    parity: AtomicU64,
+    framing: AtomicU64,
+    breaks: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineErrorCounts {
+    /// Bytes lost because the receive FIFO was full.
+    pub overrun: u64,
+    pub parity: u64,
+    pub framing: u64,
+    pub breaks: u64,
+}
+
+impl LineErrors {
+    fn record(&self, sts: LineStsFlags) {
+        for (flag, count) in [
+            (LineStsFlags::OVERRUN, &self.overrun),
+            (LineStsFlags::PARITY, &self.parity),
+            (LineStsFlags::FRAMING, &self.framing),
+            (LineStsFlags::BREAK, &self.breaks),
+        ] {
+            if sts.contains(flag) {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn counts(&self) -> LineErrorCounts {
+        LineErrorCounts {
+            overrun: self.overrun.load(Ordering::Relaxed),
+            parity: self.parity.load(Ordering::Relaxed),
+            framing: self.framing.load(Ordering::Relaxed),
+            breaks: self.breaks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Read the line status register, counting any errors in it.
+fn read_line_sts(line_sts: Reg<u8>, errors: &LineErrors) -> LineStsFlags {
+    let sts = LineStsFlags::from_bits_truncate(line_sts.read());
+    errors.record(sts);
+    sts
+}
+
+crate::register_block! {
+    struct Regs {
+        0 => data: u8,
+        1 => int_en: InterruptEnable,
+        /// The divisor latch replaces `data` and `int_en` while DLAB is set.
+        0 => divisor_low: u8,
+        1 => divisor_high: u8,
+        2 => fifo_ctrl: u8,
+        3 => line_ctrl: u8,
+        4 => modem_ctrl: ModemControlRegister,
+        5 => line_sts: u8,
     }
 }
 
@@ -29,12 +268,11 @@ bitflags::bitflags! {
 /// A memory-mapped UART.
 pub struct MmioSerialPort {
     int_id: InterruptId,
-    data: AtomicPtr<u8>,
-    int_en: AtomicPtr<InterruptEnable>,
-    fifo_ctrl: AtomicPtr<u8>,
-    line_ctrl: AtomicPtr<u8>,
-    modem_ctrl: AtomicPtr<ModemControlRegister>,
-    line_sts: AtomicPtr<u8>,
+    regs: Regs,
+    /// Input clock in Hz. 0 if unknown.
+    clock: u32,
+    config: Option<LineConfig>,
+    errors: Arc<LineErrors>,
 }
 
 bitflags::bitflags! {
@@ -61,122 +299,223 @@ impl MmioSerialPort {
     ///
     /// This function is unsafe because the caller must ensure that the given base address
     /// really points to a serial port device.
-
-    pub unsafe fn new(base: usize, int_id: InterruptId) -> Self {
-        let base_pointer = base as *mut u8;
+    ///
+    /// `clock` is the UART's input clock in Hz, from `clock-frequency`, or 0 if it isn't
+    /// known.
+    pub unsafe fn new(base: usize, int_id: InterruptId, clock: u32) -> Self {
         Self {
             int_id,
-            data: AtomicPtr::new(base_pointer),
-            int_en: AtomicPtr::new(base_pointer.add(1) as *mut InterruptEnable),
-            fifo_ctrl: AtomicPtr::new(base_pointer.add(2)),
-            line_ctrl: AtomicPtr::new(base_pointer.add(3)),
-            modem_ctrl: AtomicPtr::new(base_pointer.add(4) as *mut ModemControlRegister),
-            line_sts: AtomicPtr::new(base_pointer.add(5)),
+            regs: Regs::new(base),
+            clock,
+            config: None,
+            errors: Arc::default(),
         }
     }
 
-    /// Initializes the memory-mapped UART.
+    /// Initializes the memory-mapped UART with `config`.
     ///
-    /// The default configuration of [38400/8-N-1](https://en.wikipedia.org/wiki/8-N-1) is used.
-    pub fn init(&mut self) -> anyhow::Result<()> {
-        let self_int_en = self.int_en.load(Ordering::Relaxed);
-        let self_line_ctrl = self.line_ctrl.load(Ordering::Relaxed);
-        let self_data = self.data.load(Ordering::Relaxed);
-        let self_fifo_ctrl = self.fifo_ctrl.load(Ordering::Relaxed);
-        let self_modem_ctrl = self.modem_ctrl.load(Ordering::Relaxed);
-        unsafe {
-            // Disable interrupts
-            self_int_en.write_volatile(InterruptEnable::empty());
-
-            // Enable DLAB
-            self_line_ctrl.write_volatile(0x80);
-
-            // Set maximum speed to 38400 bps by configuring DLL and DLM
-            self_data.write_volatile(0x03);
-            self_int_en.write_volatile(InterruptEnable::empty());
-
-            // Disable DLAB and set data word length to 8 bits
-            self_line_ctrl.write_volatile(0x03);
-
-            // Enable FIFO, clear TX/RX queues and
-            // set interrupt watermark at 14 bytes
-            self_fifo_ctrl.write_volatile(0xC7);
-
-            // Mark data terminal ready, signal request to send
-            // and enable auxilliary output #2 (used as interrupt line for CPU)
-            self_modem_ctrl.write_volatile(
-                ModemControlRegister::REQUEST_TO_SEND
-                    | ModemControlRegister::REQUEST_TO_SEND
-                    | ModemControlRegister::OUT_1,
-            );
-
-            let _res = self_fifo_ctrl.read_volatile();
-
-            plic::enable_interrupt(self.int_id);
-
-            /*
-            // Put into loopback mode to test the chip.
-            self_modem_ctrl.write_volatile(
-                ModemControlRegister::REQUEST_TO_SEND
-                    | ModemControlRegister::OUT_1
-                    | ModemControlRegister::OUT_2
-                    | ModemControlRegister::LOOP,
-            );
-
-            const TEST_DATA: u8 = 0xAE;
-            self_data.write_volatile(TEST_DATA);
-            let read = self_data.read_volatile();
-            if read != TEST_DATA {
-                anyhow::bail!("ERROR Uart Loopback did not return test data.");
-            }
-            */
+    /// If the clock isn't known the divisor the firmware set is kept, and only the framing
+    /// is changed.
+    pub fn init(&mut self, config: LineConfig) -> Result<(), UartError> {
+        let regs = self.regs;
+
+        // Disable interrupts
+        regs.int_en().write(InterruptEnable::empty());
+
+        if self.clock == 0 {
+            regs.line_ctrl().write(config.line_ctrl()?);
+        } else {
+            self.set_line(config)?;
+        }
+
+        // Enable FIFO, clear TX/RX queues and
+        // set interrupt watermark at 14 bytes
+        regs.fifo_ctrl().write(0xC7);
+
+        // Mark data terminal ready, signal request to send
+        // and enable auxilliary output #2 (used as interrupt line for CPU)
+        regs.modem_ctrl().write(
+            ModemControlRegister::DATA_TERMINAL_READY
+                | ModemControlRegister::REQUEST_TO_SEND
+                | ModemControlRegister::OUT_2,
+        );
+
+        let _res = regs.fifo_ctrl().read();
+
+        plic::enable_interrupt(self.int_id);
+
+        /*
+        // Put into loopback mode to test the chip.
+        regs.modem_ctrl().write(
+            ModemControlRegister::REQUEST_TO_SEND
+                | ModemControlRegister::OUT_1
+                | ModemControlRegister::OUT_2
+                | ModemControlRegister::LOOP,
+        );
+
+        const TEST_DATA: u8 = 0xAE;
+        regs.data().write(TEST_DATA);
+        let read = regs.data().read();
+        if read != TEST_DATA {
+            anyhow::bail!("ERROR Uart Loopback did not return test data.");
+        }
+        */
+
+        Ok(())
+    }
+
+    /// The line settings, if they've been set. Not known if the firmware's divisor was kept.
+    pub fn line_config(&self) -> Option<LineConfig> {
+        self.config
+    }
+
+    /// Change baud rate and framing. Waits for what's already in the UART to go out first,
+    /// and turns the UART's interrupts off while the divisor latch hides the data register.
+    pub fn set_line(&mut self, config: LineConfig) -> Result<(), UartError> {
+        if self.clock == 0 {
+            return Err(UartError::UnknownClock);
         }
+        let divisor = divisor(self.clock, config.baud)?;
+        let line_ctrl = config.line_ctrl()?;
+
+        let regs = self.regs;
+        wait_for!(self.line_sts().contains(LineStsFlags::TRANSMITTER_EMPTY));
+        let int_en = regs.int_en().read();
+        regs.int_en().write(InterruptEnable::empty());
+        regs.line_ctrl().write(line_ctrl | DLAB);
+        regs.divisor_low().write(divisor as u8);
+        regs.divisor_high().write((divisor >> 8) as u8);
+        regs.line_ctrl().write(line_ctrl);
+        regs.int_en().write(int_en);
 
+        self.config = Some(config);
         Ok(())
     }
 
+    /// Change only the baud rate.
+    pub fn set_baud(&mut self, baud: u32) -> Result<(), UartError> {
+        let config = self.config.unwrap_or_default();
+        self.set_line(LineConfig { baud, ..config })
+    }
+
+    /// Receive errors seen so far.
+    pub fn errors(&self) -> LineErrorCounts {
+        self.errors.counts()
+    }
+
     fn line_sts(&mut self) -> LineStsFlags {
-        unsafe { LineStsFlags::from_bits_truncate(*self.line_sts.load(Ordering::Relaxed)) }
+        read_line_sts(self.regs.line_sts(), &self.errors)
     }
 
     /// Sends a byte on the serial port.
     pub fn send(&mut self, data: u8) {
-        let self_data = self.data.load(Ordering::Relaxed);
-        unsafe {
-            match data {
-                8 | 0x7F => {
-                    wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
-                    self_data.write(8);
-                    wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
-                    self_data.write(b' ');
-                    wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
-                    self_data.write(8)
-                }
-                _ => {
-                    wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
-                    self_data.write(data);
-                }
+        match data {
+            8 | 0x7F => {
+                self.send_raw(8);
+                self.send_raw(b' ');
+                self.send_raw(8);
             }
+            _ => self.send_raw(data),
         }
     }
 
+    /// Sends a byte with no translation. Spins until the UART has room.
+    pub fn send_raw(&mut self, data: u8) {
+        wait_for!(self.line_sts().contains(LineStsFlags::OUTPUT_EMPTY));
+        self.regs.data().write(data);
+    }
+
     /// Receives a byte on the serial port.
     pub fn receive(&mut self) -> u8 {
-        let self_data = self.data.load(Ordering::Relaxed);
-        unsafe {
-            wait_for!(self.line_sts().contains(LineStsFlags::INPUT_FULL));
-            self_data.read()
+        wait_for!(self.line_sts().contains(LineStsFlags::INPUT_FULL));
+        self.regs.data().read()
+    }
+
+    /// Get the transmit half of the port. See [`MmioSerialTransmitter`].
+    pub fn transmitter(&self) -> MmioSerialTransmitter {
+        MmioSerialTransmitter {
+            data: self.regs.data(),
+            int_en: self.regs.int_en(),
+            line_sts: self.regs.line_sts(),
+            int_en_shadow: InterruptEnable::empty(),
+            errors: self.errors.clone(),
+        }
+    }
+
+    /// Get the receive half of the port. See [`MmioSerialReceiver`].
+    pub fn receiver(&self) -> MmioSerialReceiver {
+        MmioSerialReceiver {
+            data: self.regs.data(),
+            line_sts: self.regs.line_sts(),
+            errors: self.errors.clone(),
         }
     }
 
     pub fn try_receive(&mut self) -> Option<u8> {
-        let self_data = self.data.load(Ordering::Relaxed);
-        unsafe {
-            if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
-                Some(self_data.read_volatile())
-            } else {
-                None
-            }
+        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+            Some(self.regs.data().read())
+        } else {
+            None
+        }
+    }
+}
+
+/// Receive half of the UART.
+///
+/// Only reads the receive buffer and line status registers, neither of which the
+/// transmit path touches. So the interrupt handler can drain received bytes without
+/// taking the console lock, which may be held by the code it interrupted.
+#[derive(Debug)]
+pub struct MmioSerialReceiver {
+    data: Reg<u8>,
+    line_sts: Reg<u8>,
+    errors: Arc<LineErrors>,
+}
+
+impl MmioSerialReceiver {
+    pub fn try_receive(&self) -> Option<u8> {
+        let line_sts = read_line_sts(self.line_sts, &self.errors);
+        if line_sts.contains(LineStsFlags::INPUT_FULL) {
+            Some(self.data.read())
+        } else {
+            None
+        }
+    }
+}
+
+/// Transmit half of the UART, used to drain the transmit queue from the interrupt handler.
+///
+/// Also owns the interrupt enable register, since THRI is toggled on and off depending
+/// on whether there's anything left to send.
+#[derive(Debug)]
+pub struct MmioSerialTransmitter {
+    data: Reg<u8>,
+    int_en: Reg<InterruptEnable>,
+    line_sts: Reg<u8>,
+    int_en_shadow: InterruptEnable,
+    errors: Arc<LineErrors>,
+}
+
+impl MmioSerialTransmitter {
+    /// True when the transmit FIFO is empty and can take [`TX_FIFO_DEPTH`] bytes.
+    pub fn fifo_empty(&self) -> bool {
+        read_line_sts(self.line_sts, &self.errors).contains(LineStsFlags::OUTPUT_EMPTY)
+    }
+
+    /// Write to the transmit register without checking there's room.
+    pub fn write_unchecked(&mut self, byte: u8) {
+        self.data.write(byte);
+    }
+
+    pub fn interrupts(&self) -> InterruptEnable {
+        self.int_en_shadow
+    }
+
+    /// Set which events raise the UART interrupt.
+    pub fn set_interrupts(&mut self, enable: InterruptEnable) {
+        if enable != self.int_en_shadow {
+            self.int_en_shadow = enable;
+            self.int_en.write(enable);
         }
     }
 }
@@ -189,3 +528,41 @@ impl fmt::Write for MmioSerialPort {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn line_config() {
+        // QEMU's clock.
+        assert_eq!(divisor(3_686_400, 115200), Ok(2));
+        assert_eq!(divisor(3_686_400, 9600), Ok(24));
+        assert_eq!(
+            divisor(3_686_400, 1_000_000),
+            Err(UartError::Baud(1_000_000))
+        );
+        assert_eq!(divisor(3_686_400, 0), Err(UartError::Baud(0)));
+
+        assert_eq!(LineConfig::default().line_ctrl(), Ok(0x03));
+        let config: LineConfig = "9600e72".parse().unwrap();
+        assert_eq!(
+            config,
+            LineConfig {
+                baud: 9600,
+                data_bits: 7,
+                parity: Parity::Even,
+                stop_bits: StopBits::Two,
+            }
+        );
+        assert_eq!(config.line_ctrl(), Ok(0x1e));
+        assert_eq!(format!("{}", config), "9600e72");
+        assert_eq!("115200".parse(), Ok(LineConfig::default()));
+        assert_eq!("115200x8".parse::<LineConfig>(), Err(UartError::Parse));
+        assert_eq!(
+            "115200n9".parse::<LineConfig>().unwrap().line_ctrl(),
+            Err(UartError::DataBits(9))
+        );
+    }
+}
@@ -0,0 +1,35 @@
+//! Waiting on the hart itself.
+//!
+//! [`relax`] is for waits of a few microseconds on something that won't interrupt, like
+//! a UART's transmitter emptying. [`halt`] sleeps in `wfi` until an interrupt, for
+//! anything longer that will. [`halt_forever`] is for harts with nothing left to do.
+
+use riscv::register::sstatus;
+
+use crate::isr::{disable_interrupts, restore_interrupts};
+
+/// One turn of a busy wait.
+#[inline]
+pub fn relax() {
+    core::hint::spin_loop();
+}
+
+/// Sleep until an interrupt enabled in `sie` is pending. If interrupts were on, it's
+/// handled before this returns.
+///
+/// Check whatever you're waiting for with interrupts off first, or one that comes in
+/// between is missed until the next. [`isr::wait_until`](crate::isr::wait_until) does
+/// that.
+pub fn halt() {
+    let interrupts = disable_interrupts();
+    unsafe { riscv::asm::wfi() };
+    restore_interrupts(interrupts);
+}
+
+/// Stop this hart for good, in `wfi` with interrupts off.
+pub fn halt_forever() -> ! {
+    unsafe { sstatus::clear_sie() };
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
+}
@@ -0,0 +1,223 @@
+//! Where the harts' time goes.
+//!
+//! Each hart adds up the time it spends in [`idle`](crate::idle::idle), and counts its
+//! context switches: each time [`process::run`] switches to a process or back. Processes
+//! keep their own CPU time and switch counts in [`Usage`]. A [`Snapshot`] takes all of it
+//! at once, and two of them give how busy each hart and process was in between. That's
+//! what `top` in the shell shows.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    hart_local::{online_harts, try_current_hart},
+    prelude::*,
+    process::{self, Pid, Usage},
+    sbi::hart::HartId,
+    smp::MAX_HARTS,
+    time,
+};
+
+/// Nanoseconds each hart has spent idle.
+static IDLE: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+static SWITCHES: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+fn this_hart() -> Option<usize> {
+    try_current_hart()
+        .map(|hart| hart.0)
+        .filter(|&hart| hart < MAX_HARTS)
+}
+
+/// This hart was idle for `duration`.
+pub fn add_idle(duration: Duration) {
+    if let Some(hart) = this_hart() {
+        IDLE[hart].fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// This hart switched to a process, or back from one.
+pub fn count_switch() {
+    if let Some(hart) = this_hart() {
+        SWITCHES[hart].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HartStats {
+    pub hart: HartId,
+    /// Since boot.
+    pub idle: Duration,
+    pub switches: u64,
+}
+
+pub fn hart(hart: HartId) -> HartStats {
+    let (idle, switches) = if hart.0 < MAX_HARTS {
+        (
+            IDLE[hart.0].load(Ordering::Relaxed),
+            SWITCHES[hart.0].load(Ordering::Relaxed),
+        )
+    } else {
+        (0, 0)
+    };
+    HartStats {
+        hart,
+        idle: Duration::from_nanos(idle),
+        switches,
+    }
+}
+
+/// Each online hart's stats.
+pub fn harts() -> Vec<HartStats> {
+    let online = online_harts();
+    (0..MAX_HARTS)
+        .map(HartId)
+        .filter(|&id| online.contains(id))
+        .map(hart)
+        .collect()
+}
+
+/// Everything at one moment.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Uptime when it was taken.
+    pub at: Duration,
+    pub harts: Vec<HartStats>,
+    pub processes: Vec<(Pid, String, Usage)>,
+}
+
+/// How busy a hart was between two [`Snapshot`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HartLoad {
+    pub hart: HartId,
+    /// Of the time between them, how much it wasn't idle, in percent.
+    pub busy: u32,
+    pub switches: u64,
+}
+
+/// How much a process ran between two [`Snapshot`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessLoad {
+    pub pid: Pid,
+    pub name: String,
+    /// Of the time between them, how much it was running, in percent of one hart.
+    pub cpu: u32,
+    pub cpu_time: Duration,
+    pub switches: u64,
+}
+
+impl Snapshot {
+    pub fn take() -> Snapshot {
+        Snapshot {
+            at: time::uptime().unwrap_or_default(),
+            harts: harts(),
+            processes: process::list()
+                .iter()
+                .map(|process| (process.pid(), process.name(), process.usage()))
+                .collect(),
+        }
+    }
+
+    /// Each hart in both, and how busy it was from `earlier` to this.
+    pub fn harts_since(&self, earlier: &Snapshot) -> Vec<HartLoad> {
+        let elapsed = self.at.saturating_sub(earlier.at);
+        self.harts
+            .iter()
+            .filter_map(|now| {
+                let then = earlier.harts.iter().find(|then| then.hart == now.hart)?;
+                let idle = now.idle.saturating_sub(then.idle);
+                Some(HartLoad {
+                    hart: now.hart,
+                    busy: 100 - percent(idle, elapsed),
+                    switches: now.switches - then.switches,
+                })
+            })
+            .collect()
+    }
+
+    /// Each process in this, busiest first, and how much it ran since `earlier`. Ones that
+    /// weren't in `earlier` count from when they started.
+    pub fn processes_since(&self, earlier: &Snapshot) -> Vec<ProcessLoad> {
+        let elapsed = self.at.saturating_sub(earlier.at);
+        let mut loads: Vec<ProcessLoad> = self
+            .processes
+            .iter()
+            .map(|(pid, name, now)| {
+                let then = earlier
+                    .processes
+                    .iter()
+                    .find(|(then, _, _)| then == pid)
+                    .map(|(_, _, usage)| *usage)
+                    .unwrap_or_default();
+                ProcessLoad {
+                    pid: *pid,
+                    name: name.clone(),
+                    cpu: percent(now.cpu_time.saturating_sub(then.cpu_time), elapsed),
+                    cpu_time: now.cpu_time,
+                    switches: now.switches - then.switches,
+                }
+            })
+            .collect();
+        loads.sort_by_key(|load| (core::cmp::Reverse(load.cpu), load.pid));
+        loads
+    }
+}
+
+/// `part` out of `whole`, in percent, at most 100.
+fn percent(part: Duration, whole: Duration) -> u32 {
+    if whole.is_zero() {
+        return 0;
+    }
+    (part.as_nanos() * 100 / whole.as_nanos()).min(100) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn load_between_snapshots() {
+        let stats = |idle, switches| HartStats {
+            hart: HartId(0),
+            idle: Duration::from_millis(idle),
+            switches,
+        };
+        let usage = |ms, switches| {
+            let mut usage = Usage::default();
+            usage.cpu_time = Duration::from_millis(ms);
+            usage.switches = switches;
+            usage
+        };
+        let earlier = Snapshot {
+            at: Duration::from_secs(10),
+            harts: vec![stats(4000, 10)],
+            processes: vec![(Pid(1), "init".into(), usage(100, 2))],
+        };
+        let now = Snapshot {
+            at: Duration::from_secs(12),
+            harts: vec![stats(4500, 16)],
+            processes: vec![
+                (Pid(1), "init".into(), usage(300, 4)),
+                (Pid(2), "sh".into(), usage(1000, 1)),
+            ],
+        };
+
+        let harts = now.harts_since(&earlier);
+        assert_eq!(harts.len(), 1);
+        assert_eq!((harts[0].busy, harts[0].switches), (75, 6));
+        let processes = now.processes_since(&earlier);
+        let loads: Vec<(u32, u32, u64)> = processes
+            .iter()
+            .map(|load| (load.pid.0, load.cpu, load.switches))
+            .collect();
+        assert_eq!(loads, [(2, 50, 1), (1, 10, 2)]);
+
+        // Counted for the hart that calls it.
+        let before = hart(HartId(0)).idle;
+        add_idle(Duration::from_micros(1));
+        if try_current_hart() == Some(HartId(0)) {
+            assert!(hart(HartId(0)).idle > before);
+        }
+    }
+}
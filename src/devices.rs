@@ -0,0 +1,237 @@
+//! Drivers bound to device tree nodes.
+//!
+//! Each entry in [`DRIVERS`] names the `compatible` strings it handles and a probe function.
+//! `walk_dtb` keeps a [`DtNode`] for every node with a `compatible` property, and
+//! [`probe_all`] goes through them once, probing the first driver that matches each one.
+//!
+//! The PLIC, CLINT, RTC and console UART are still set up straight from [`HwInfo`], since
+//! everything here needs them first.
+
+use alloc::sync::Arc;
+use core::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+};
+
+use crate::{
+    addr::PhysAddr,
+    error::KernelError,
+    finisher,
+    hwinfo::HwInfo,
+    log, pci,
+    prelude::*,
+    sync::{Lazy, Rcu},
+    virtio,
+};
+
+/// `#address-cells` when the parent doesn't say.
+pub const DEFAULT_ADDRESS_CELLS: u32 = 2;
+/// `#size-cells` when the parent doesn't say.
+pub const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// A device tree node, copied out of the blob.
+#[derive(Debug, Clone)]
+pub struct DtNode {
+    /// Full path, like `/soc/virtio_mmio@10001000`.
+    pub path: String,
+    pub name: String,
+    /// The parent's `#address-cells`, for reading `reg`.
+    pub address_cells: u32,
+    /// The parent's `#size-cells`.
+    pub size_cells: u32,
+    pub props: Vec<(String, Vec<u8>)>,
+}
+
+impl DtNode {
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(prop, _)| prop == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The strings in a string list property, like `compatible`.
+    pub fn strings<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.prop(name)
+            .unwrap_or_default()
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.strings("compatible").any(|c| c == compatible)
+    }
+
+    /// The `index`th 32 bit cell of a property.
+    pub fn u32(&self, name: &str, index: usize) -> Option<u32> {
+        let bytes = self.prop(name)?.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    /// `cells` cells starting at cell `index`, as one number.
+    pub fn cells(&self, name: &str, index: usize, cells: u32) -> Option<u64> {
+        (0..cells as usize).try_fold(0u64, |value, i| {
+            Some(value << 32 | u64::from(self.u32(name, index + i)?))
+        })
+    }
+
+    /// The `index`th range in `reg`. `None` if it isn't a range of physical addresses.
+    pub fn reg(&self, index: usize) -> Option<Range<PhysAddr>> {
+        let stride = (self.address_cells + self.size_cells) as usize;
+        let base = self.cells("reg", index * stride, self.address_cells)?;
+        let len = self.cells(
+            "reg",
+            index * stride + self.address_cells as usize,
+            self.size_cells,
+        )?;
+        Some(PhysAddr::try_new(base)?..PhysAddr::try_new(base.checked_add(len)?)?)
+    }
+
+    /// The first interrupt, for devices on the PLIC with one cell per interrupt.
+    pub fn interrupt(&self) -> Option<u32> {
+        self.u32("interrupts", 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The node is there but nothing is plugged into it, like an empty virtio slot. Not
+    /// logged.
+    NoDevice,
+    MissingProperty(&'static str),
+    Virtio(virtio::VirtioError),
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::NoDevice => write!(f, "no device"),
+            ProbeError::MissingProperty(prop) => write!(f, "missing `{}` property", prop),
+            ProbeError::Virtio(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for ProbeError {}
+
+impl From<virtio::VirtioError> for ProbeError {
+    fn from(err: virtio::VirtioError) -> Self {
+        ProbeError::Virtio(err)
+    }
+}
+
+/// A probed device. Dropping it should leave the hardware alone.
+pub trait Driver: Send + Sync {
+    /// A line about the device, for `devices` in the shell.
+    fn describe(&self) -> String;
+}
+
+pub struct DriverInfo {
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    pub probe: fn(&DtNode) -> Result<Box<dyn Driver>, KernelError>,
+}
+
+pub const DRIVERS: &[DriverInfo] = &[
+    DriverInfo {
+        name: "virtio-mmio",
+        compatible: &["virtio,mmio"],
+        probe: virtio::probe_mmio,
+    },
+    DriverInfo {
+        name: "pci-ecam",
+        compatible: &["pci-host-ecam-generic"],
+        probe: pci::probe_ecam,
+    },
+    DriverInfo {
+        name: "sifive-test",
+        compatible: &["sifive,test1", "sifive,test0"],
+        probe: finisher::probe,
+    },
+];
+
+pub struct Device {
+    pub path: String,
+    pub driver: &'static DriverInfo,
+    pub instance: Box<dyn Driver>,
+}
+
+static DEVICES: Lazy<Rcu<Vec<Arc<Device>>>> = Lazy::new(|| Rcu::new(Vec::new()));
+
+/// The first driver for `node`. Earlier `compatible` strings are more specific, so they
+/// win over the order of [`DRIVERS`].
+pub fn driver_for(node: &DtNode) -> Option<&'static DriverInfo> {
+    node.strings("compatible").find_map(|compatible| {
+        DRIVERS
+            .iter()
+            .find(|driver| driver.compatible.contains(&compatible))
+    })
+}
+
+/// Probe a driver for each node from the device tree.
+pub fn probe_all(hwinfo: &HwInfo) {
+    for node in &hwinfo.nodes {
+        let driver = match driver_for(node) {
+            Some(driver) => driver,
+            None => continue,
+        };
+        match (driver.probe)(node) {
+            Ok(instance) => {
+                log::info!("{}: {}", node.path, instance.describe());
+                let device = Arc::new(Device {
+                    path: node.path.clone(),
+                    driver,
+                    instance,
+                });
+                DEVICES.update(|devices| {
+                    let mut devices = devices.clone();
+                    devices.push(device);
+                    devices
+                });
+            }
+            Err(KernelError::Probe(ProbeError::NoDevice)) => {}
+            Err(err) => log::warn!("{}: {}: {}", node.path, driver.name, err),
+        }
+    }
+}
+
+/// Do something with each bound device.
+pub fn for_each(f: impl FnMut(&Device)) {
+    DEVICES.read().iter().map(|device| &**device).for_each(f);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn dt_node_props() {
+        let node = DtNode {
+            path: "/soc/virtio_mmio@10001000".into(),
+            name: "virtio_mmio@10001000".into(),
+            address_cells: 2,
+            size_cells: 2,
+            props: vec![
+                ("compatible".into(), b"acme,thing\0virtio,mmio\0".to_vec()),
+                (
+                    "reg".into(),
+                    vec![0, 0, 0, 0, 0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0x10, 0],
+                ),
+                ("interrupts".into(), vec![0, 0, 0, 1]),
+            ],
+        };
+        assert!(node.is_compatible("virtio,mmio"));
+        assert!(!node.is_compatible("virtio"));
+        assert_eq!(
+            node.reg(0),
+            Some(PhysAddr::new(0x1000_1000)..PhysAddr::new(0x1000_2000))
+        );
+        assert_eq!(node.reg(1), None);
+        assert_eq!(node.interrupt(), Some(1));
+        assert_eq!(
+            driver_for(&node).map(|driver| driver.name),
+            Some("virtio-mmio")
+        );
+    }
+}
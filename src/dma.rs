@@ -0,0 +1,146 @@
+//! Memory for devices to read and write.
+//!
+//! A [`DmaBuffer`] is physically contiguous and knows its physical address, which is what
+//! goes to the device. It comes from the heap, which is the direct map of whole banks of
+//! RAM, so any allocation is contiguous and its physical address is [`virt_to_phys`] of
+//! its pointer. Memory elsewhere, a kernel stack say, needn't be. [`phys_addr`] looks it up
+//! in the page tables, and says whether it is.
+//!
+//! Harts on the machines we run on are coherent with devices, so [`DmaBuffer::sync_for_device`]
+//! and [`DmaBuffer::sync_for_cpu`] only order memory against I/O. They're where cache
+//! cleaning and invalidating would go on a machine that isn't.
+
+use core::{
+    alloc::Layout,
+    arch::asm,
+    fmt::{self, Display, Formatter},
+    ptr::NonNull,
+};
+
+use crate::addr::VirtAddr;
+use crate::pagetable::{self, virt_to_phys, PAGE_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The alignment isn't a power of two.
+    BadAlignment(usize),
+    OutOfMemory,
+}
+
+impl Display for DmaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::BadAlignment(align) => write!(f, "bad DMA alignment {}", align),
+            DmaError::OutOfMemory => write!(f, "out of memory for DMA"),
+        }
+    }
+}
+
+impl core::error::Error for DmaError {}
+
+/// `len` zeroed bytes for a device, aligned to `align`.
+pub fn alloc(len: usize, align: usize) -> Result<DmaBuffer, DmaError> {
+    let layout =
+        Layout::from_size_align(len.max(1), align).map_err(|_| DmaError::BadAlignment(align))?;
+    let virt =
+        NonNull::new(unsafe { alloc::alloc::alloc_zeroed(layout) }).ok_or(DmaError::OutOfMemory)?;
+    Ok(DmaBuffer {
+        virt,
+        phys: virt_to_phys(VirtAddr::from_ptr(virt.as_ptr())).as_u64(),
+        len,
+        layout,
+    })
+}
+
+/// Physical address of `len` bytes at `ptr`, from the page tables. `None` if they aren't
+/// all mapped, one after the other in physical memory.
+pub fn phys_addr(ptr: *const u8, len: usize) -> Option<u64> {
+    let start = VirtAddr::from_ptr(ptr);
+    let phys = pagetable::translate_current(start)?.pa;
+    let mut page = start.align_down(PAGE_SIZE);
+    while page + PAGE_SIZE < start + len as u64 {
+        page += PAGE_SIZE;
+        if pagetable::translate_current(page)?.pa != phys + (page - start) {
+            return None;
+        }
+    }
+    Some(phys.as_u64())
+}
+
+pub struct DmaBuffer {
+    virt: NonNull<u8>,
+    phys: u64,
+    len: usize,
+    layout: Layout,
+}
+
+// Owns its memory, like a `Box<[u8]>`.
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Where the device sees the start of the buffer.
+    pub fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt.as_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// # Safety
+    /// The device isn't writing to it.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        core::slice::from_raw_parts(self.as_ptr(), self.len)
+    }
+
+    /// # Safety
+    /// The device isn't using it.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        core::slice::from_raw_parts_mut(self.as_ptr(), self.len)
+    }
+
+    /// Call after writing the buffer and before telling the device about it.
+    pub fn sync_for_device(&self) {
+        unsafe { asm!("fence rw, ow") };
+    }
+
+    /// Call after the device says it's done and before reading what it wrote.
+    pub fn sync_for_cpu(&self) {
+        unsafe { asm!("fence ir, rw") };
+    }
+}
+
+impl Drop for DmaBuffer {
+    /// The device must be done with it.
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.as_ptr(), self.layout) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn dma_buffers() {
+        let mut buffer = alloc(100, 64).unwrap();
+        assert_eq!(buffer.phys() % 64, 0);
+        assert_eq!(
+            buffer.phys(),
+            virt_to_phys(VirtAddr::from_ptr(buffer.as_ptr())).as_u64()
+        );
+        assert!(unsafe { buffer.as_mut_slice() }
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(alloc(8, 3).err(), Some(DmaError::BadAlignment(3)));
+        assert_eq!(
+            phys_addr(buffer.as_ptr(), buffer.len()),
+            Some(buffer.phys())
+        );
+    }
+}
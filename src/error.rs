@@ -0,0 +1,151 @@
+//! [`KernelError`], for code that can fail in more than one subsystem's way.
+//!
+//! Each subsystem keeps its own error enum. `KernelError` wraps them so a probe function
+//! or system call can use `?` on all of them, and syscalls turn it into an errno instead
+//! of panicking.
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{collections::TryReserveError, vec::Vec};
+use fdt_rs::error::DevTreeError;
+
+use crate::{
+    devices::ProbeError, dma::DmaError, fs::FsError, hwinfo::DtError, io, pagetable::MapError,
+    sbi::SbiError, virtio::VirtioError,
+};
+
+pub type KernelResult<T> = Result<T, KernelError>;
+
+#[derive(Debug)]
+pub enum KernelError {
+    /// A heap or DMA allocation failed.
+    OutOfMemory,
+    Sbi(SbiError),
+    Io(io::Error),
+    Dtb(DtError),
+    Fs(FsError),
+    Probe(ProbeError),
+    Map(MapError),
+}
+
+impl Display for KernelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelError::OutOfMemory => write!(f, "out of memory"),
+            KernelError::Sbi(err) => write!(f, "{}", err),
+            KernelError::Io(err) => write!(f, "{}", err),
+            KernelError::Dtb(err) => write!(f, "device tree: {}", err),
+            KernelError::Fs(err) => write!(f, "{}", err),
+            KernelError::Probe(err) => write!(f, "{}", err),
+            KernelError::Map(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for KernelError {}
+
+impl From<TryReserveError> for KernelError {
+    fn from(_: TryReserveError) -> Self {
+        KernelError::OutOfMemory
+    }
+}
+
+impl From<DmaError> for KernelError {
+    fn from(err: DmaError) -> Self {
+        match err {
+            DmaError::OutOfMemory => KernelError::OutOfMemory,
+            DmaError::BadAlignment(_) => KernelError::Io(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl From<SbiError> for KernelError {
+    fn from(err: SbiError) -> Self {
+        KernelError::Sbi(err)
+    }
+}
+
+impl From<io::Error> for KernelError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::OutOfMemory => KernelError::OutOfMemory,
+            _ => KernelError::Io(err),
+        }
+    }
+}
+
+impl From<DevTreeError> for KernelError {
+    fn from(err: DevTreeError) -> Self {
+        match err {
+            DevTreeError::NotEnoughMemory => KernelError::OutOfMemory,
+            err => KernelError::Dtb(err.into()),
+        }
+    }
+}
+
+impl From<DtError> for KernelError {
+    fn from(err: DtError) -> Self {
+        KernelError::Dtb(err)
+    }
+}
+
+impl From<FsError> for KernelError {
+    fn from(err: FsError) -> Self {
+        KernelError::Fs(err)
+    }
+}
+
+impl From<ProbeError> for KernelError {
+    fn from(err: ProbeError) -> Self {
+        KernelError::Probe(err)
+    }
+}
+
+impl From<VirtioError> for KernelError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::OutOfMemory => KernelError::OutOfMemory,
+            err => KernelError::Probe(ProbeError::Virtio(err)),
+        }
+    }
+}
+
+impl From<MapError> for KernelError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfMemory => KernelError::OutOfMemory,
+            err => KernelError::Map(err),
+        }
+    }
+}
+
+/// A zeroed buffer of `len` bytes, or [`KernelError::OutOfMemory`] instead of the
+/// allocation failure handler.
+pub fn try_zeroed(len: usize) -> KernelResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len)?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn kernel_errors() {
+        assert!(matches!(
+            KernelError::from(MapError::OutOfMemory),
+            KernelError::OutOfMemory
+        ));
+        assert!(matches!(
+            KernelError::from(VirtioError::QueueFull),
+            KernelError::Probe(ProbeError::Virtio(VirtioError::QueueFull))
+        ));
+        assert!(matches!(
+            try_zeroed(usize::MAX),
+            Err(KernelError::OutOfMemory)
+        ));
+        assert_eq!(try_zeroed(3).unwrap(), [0, 0, 0]);
+    }
+}
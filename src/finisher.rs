@@ -0,0 +1,80 @@
+//! The SiFive test finisher on QEMU's `virt` machine, which ends QEMU with an exit status.
+//!
+//! SBI's shutdown can't say whether things went well, so [`exit`] is how `test_runner` and
+//! a panicking test tell the host. Writing [`PASS`] exits QEMU with 0, and [`FAIL`] with
+//! the code in the top half.
+
+use alloc::format;
+
+use crate::{
+    addr::PhysAddr,
+    devices::{Driver, DtNode, ProbeError},
+    error::KernelError,
+    pagetable::memory_map::ioremap,
+    prelude::*,
+    sync::Once,
+};
+
+const PASS: u32 = 0x5555;
+const FAIL: u32 = 0x3333;
+
+crate::register_block! {
+    struct Regs {
+        0x00 => finisher: u32,
+    }
+}
+
+static FINISHER: Once<Regs> = Once::INIT;
+
+/// How QEMU should exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    /// Nonzero, and under 0x10000.
+    Failure(u16),
+}
+
+impl ExitCode {
+    fn value(self) -> u32 {
+        match self {
+            ExitCode::Success => PASS,
+            ExitCode::Failure(code) => (code as u32) << 16 | FAIL,
+        }
+    }
+}
+
+struct Finisher {
+    base: PhysAddr,
+}
+
+impl Driver for Finisher {
+    fn describe(&self) -> String {
+        format!("test finisher at 0x{:x}", self.base)
+    }
+}
+
+pub fn probe(node: &DtNode) -> Result<Box<dyn Driver>, KernelError> {
+    let reg = node.reg(0).ok_or(ProbeError::MissingProperty("reg"))?;
+    let base = ioremap(reg.start, reg.end - reg.start, "test finisher");
+    FINISHER.call_once(|| unsafe { Regs::new(base.as_u64() as usize) });
+    Ok(Box::new(Finisher { base: reg.start }))
+}
+
+/// End QEMU with `code`. Returns if there's no finisher, or it didn't work.
+pub fn exit(code: ExitCode) {
+    if let Some(regs) = FINISHER.get() {
+        regs.finisher().write(code.value());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn exit_code_values() {
+        assert_eq!(ExitCode::Success.value(), 0x5555);
+        assert_eq!(ExitCode::Failure(1).value(), 0x1_3333);
+        assert_eq!(ExitCode::Failure(0x42).value(), 0x42_3333);
+    }
+}
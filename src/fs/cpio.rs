@@ -0,0 +1,244 @@
+//! newc cpio archives, for the initramfs.
+//!
+//! The archive comes from the bootloader (`linux,initrd-start`/`end` in `/chosen`, which
+//! QEMU sets for `-initrd`), or is built into the kernel by setting `INITRAMFS` to its
+//! path when building. [`init`] unpacks it into the root filesystem.
+//!
+//! Only regular files and directories are unpacked. Anything else is skipped.
+
+use core::str;
+
+use alloc::format;
+
+use crate::{basic_allocator, error::KernelResult, hwinfo::HwInfo, log, pagetable::phys_to_virt};
+
+use super::{FsError, Result};
+
+const MAGIC: &[u8] = b"070701";
+/// Same layout, with a checksum we don't check.
+const MAGIC_CRC: &[u8] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIR: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+#[cfg(initramfs_image)]
+static IMAGE: Option<&[u8]> = Some(include_bytes!(env!("INITRAMFS")));
+#[cfg(not(initramfs_image))]
+static IMAGE: Option<&[u8]> = None;
+
+pub struct Entry<'a> {
+    /// Without any leading `/` or `./`.
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_DIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE == MODE_FILE
+    }
+}
+
+/// Iterator over the entries in an archive. Stops at the trailer, or with an error if the
+/// archive is malformed.
+pub struct Archive<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Archive {
+            data,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn field(header: &[u8], index: usize) -> Result<u32> {
+        let start = 6 + index * 8;
+        let hex = str::from_utf8(&header[start..start + 8]).map_err(|_| FsError::Corrupt)?;
+        u32::from_str_radix(hex, 16).map_err(|_| FsError::Corrupt)
+    }
+
+    fn parse(&mut self) -> Result<Option<Entry<'a>>> {
+        let data = self.data;
+        let header = data
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(FsError::Corrupt)?;
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+            return Err(FsError::Corrupt);
+        }
+
+        let mode = Self::field(header, 1)?;
+        let file_size = Self::field(header, 6)? as usize;
+        let name_size = Self::field(header, 11)? as usize;
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_size)
+            .ok_or(FsError::Corrupt)?;
+        // Includes the nul.
+        let name = str::from_utf8(name.strip_suffix(b"\0").ok_or(FsError::Corrupt)?)
+            .map_err(|_| FsError::Corrupt)?;
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let file = data
+            .get(data_start..data_start + file_size)
+            .ok_or(FsError::Corrupt)?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        Ok(Some(Entry {
+            name,
+            mode,
+            data: file,
+        }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Unpack `archive` under `dest`. Returns how many files and directories were created.
+pub fn unpack(archive: &[u8], dest: &str) -> Result<usize> {
+    let mut count = 0;
+    for entry in Archive::new(archive) {
+        let entry = entry?;
+        if entry.name.is_empty() || entry.name == "." {
+            continue;
+        }
+        let path = format!("{}/{}", dest.trim_end_matches('/'), entry.name);
+
+        if entry.is_dir() {
+            super::mkdir_all(&path)?;
+        } else if entry.is_file() {
+            if let Some((parent, _)) = path.rsplit_once('/') {
+                if !parent.is_empty() {
+                    super::mkdir_all(parent)?;
+                }
+            }
+            super::write_file(&path, entry.data)?;
+        } else {
+            log::warn!("skipping {} (mode {:o})", entry.name, entry.mode);
+            continue;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Unpack the initramfs into `/`, if there is one. A built in archive is used over the
+/// bootloader's.
+pub fn init(hwinfo: &HwInfo) -> KernelResult<()> {
+    let archive = IMAGE.or_else(|| {
+        hwinfo.initrd.map(|initrd| unsafe {
+            core::slice::from_raw_parts(
+                phys_to_virt(initrd.start).as_ptr(),
+                initrd.size() as usize,
+            )
+        })
+    });
+
+    let result = archive.map_or(Ok(()), |archive| {
+        // No root filesystem to unpack into is `NotFound`.
+        super::lookup("/")?;
+        let count = unpack(archive, "/")?;
+        log::info!("unpacked {} entries from the initramfs", count);
+        Ok(())
+    });
+
+    // Everything has been copied out of it, or never will be.
+    unsafe { basic_allocator::release_initrd() };
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prelude::*;
+
+    fn push_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            0,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        archive.extend_from_slice(MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    #[test_case]
+    fn cpio_entries() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, ".", MODE_DIR | 0o755, &[]);
+        push_entry(&mut archive, "./bin", MODE_DIR | 0o755, &[]);
+        push_entry(&mut archive, "./bin/init", MODE_FILE | 0o755, b"hello");
+        push_entry(&mut archive, TRAILER, 0, &[]);
+
+        let entries: Vec<Entry> = Archive::new(&archive).map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[1].is_dir());
+        assert_eq!(entries[1].name, "bin");
+        assert!(entries[2].is_file());
+        assert_eq!(entries[2].name, "bin/init");
+        assert_eq!(entries[2].data, b"hello");
+    }
+
+    #[test_case]
+    fn cpio_truncated() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, "file", MODE_FILE, b"some data");
+        archive.truncate(archive.len() - 8);
+        assert!(matches!(
+            Archive::new(&archive).next(),
+            Some(Err(FsError::Corrupt))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! Device files, mounted at `/dev`.
+//!
+//! One flat directory of [`CharDevice`]s that drivers [`register`]. Nothing can be created
+//! or removed through the filesystem itself. Opening one gives a file descriptor that
+//! reads and writes the device, with no offset, and takes `ioctl` and `ppoll`.
+
+use core::{any::Any, task::Waker};
+
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind, Result};
+
+static DEVICES: Mutex<BTreeMap<String, Arc<DevNode>>> = Mutex::new(BTreeMap::new());
+
+bitflags::bitflags! {
+    /// Readiness from [`CharDevice::poll`]. The same bits as Linux's `POLLIN` and `POLLOUT`.
+    pub struct PollFlags: u16 {
+        const IN = 0x001;
+        const OUT = 0x004;
+    }
+}
+
+pub trait CharDevice: Send + Sync {
+    /// Read what's there, waiting for at least one byte. 0 is end of file.
+    fn read(&self, buf: &mut [u8]) -> Result<usize>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize>;
+
+    /// Which of `interest` wouldn't have to wait. If none, `waker` is woken when that might
+    /// have changed.
+    fn poll(&self, interest: PollFlags, _waker: &Waker) -> PollFlags {
+        interest
+    }
+
+    /// A device specific request. `arg` is often an address in the calling process.
+    fn ioctl(&self, _request: u32, _arg: u64) -> Result<u64> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// A device's node in `/dev`.
+pub struct DevNode {
+    device: Arc<dyn CharDevice>,
+}
+
+impl Inode for DevNode {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            kind: NodeKind::File,
+            size: 0,
+        })
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.device.read(buf)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize> {
+        self.device.write(buf)
+    }
+
+    /// Opening with `O_TRUNC` is fine.
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The device behind `node`, if it's one of ours.
+pub fn device(node: &Arc<dyn Inode>) -> Option<Arc<dyn CharDevice>> {
+    let node = node.as_any().downcast_ref::<DevNode>()?;
+    Some(node.device.clone())
+}
+
+pub struct DevFs;
+
+struct DevRoot;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevRoot)
+    }
+}
+
+impl Inode for DevRoot {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            kind: NodeKind::Directory,
+            size: DEVICES.lock().len() as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        match DEVICES.lock().get(name) {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Ok(DEVICES
+            .lock()
+            .keys()
+            .map(|name| DirEntry {
+                name: name.clone(),
+                kind: NodeKind::File,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Reads as empty, and takes anything written.
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads as zeros forever.
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads from [`rand`](crate::rand). Writes are stirred into the pool, but don't count as
+/// a seed.
+struct Urandom;
+
+impl CharDevice for Urandom {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        crate::rand::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        crate::rand::add_entropy(buf, false);
+        Ok(buf.len())
+    }
+}
+
+/// Add a device as `/dev/<name>`. Replaces any device already called that.
+pub fn register(name: &str, device: Arc<dyn CharDevice>) {
+    DEVICES
+        .lock()
+        .insert(name.into(), Arc::new(DevNode { device }));
+}
+
+/// Add the devices that aren't drivers and mount at `/dev`. Devices registered before or
+/// after show up either way.
+pub fn init() {
+    register("null", Arc::new(Null));
+    register("zero", Arc::new(Zero));
+    register("urandom", Arc::new(Urandom));
+    register("console", crate::console::device());
+
+    // So it shows up when listing `/`. Mounting works without it.
+    let _ = super::mkdir_all("/dev");
+    if let Err(err) = super::mount("/dev", Arc::new(DevFs)) {
+        crate::log::error!("failed to mount /dev: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn devfs_lookup() {
+        register("test-zero", Arc::new(Zero));
+        let node = DevRoot.lookup("test-zero").unwrap();
+        let mut buf = [1; 4];
+        assert_eq!(node.read_at(100, &mut buf), Ok(4));
+        assert_eq!(buf, [0; 4]);
+        assert!(device(&node).is_some());
+        assert!(DevRoot
+            .read_dir()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.name == "test-zero"));
+
+        assert!(matches!(DevRoot.lookup("missing"), Err(FsError::NotFound)));
+        assert!(matches!(
+            DevRoot.create("new", NodeKind::File),
+            Err(FsError::ReadOnly)
+        ));
+        DEVICES.lock().remove("test-zero");
+    }
+}
@@ -0,0 +1,1119 @@
+//! FAT32.
+//!
+//! Reads and writes files, creates files and directories, and handles long file names.
+//! Accepts either a bare filesystem or an MBR with a FAT32 partition. Sectors must be
+//! 512 bytes.
+//!
+//! Everything that touches the FAT or a directory holds the filesystem's lock, so
+//! operations are simply one at a time. It's held across disk I/O, so it's a sleeping
+//! [`Mutex`] and whoever's waiting doesn't spin. Nodes are cached by the position of their
+//! directory entry, so two lookups of the same file share a size and cluster chain.
+
+use core::{any::Any, char::decode_utf16};
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    block::{self, BlockDevice, BLOCK_SIZE},
+    log,
+    prelude::*,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{check_name, DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind, Result};
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = BLOCK_SIZE / DIR_ENTRY_SIZE;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// Set in the sequence number of the last (first stored) long name entry.
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+/// Windows NT stores "all lower case" for short names in the reserved byte.
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+const CLUSTER_BAD: u32 = 0x0fff_fff7;
+const CLUSTER_EOC: u32 = 0x0fff_ffff;
+const CLUSTER_EOC_MIN: u32 = 0x0fff_fff8;
+
+/// FAT32 partition types in an MBR.
+const MBR_FAT32_CHS: u8 = 0x0b;
+const MBR_FAT32_LBA: u8 = 0x0c;
+
+/// Directory entry position: sector, relative to the start of the filesystem, and byte
+/// offset in it.
+type EntryPos = (u64, usize);
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The fields of a short directory entry we use.
+#[derive(Debug, Clone, Copy)]
+struct RawEntry {
+    name: [u8; 11],
+    attr: u8,
+    nt_flags: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl RawEntry {
+    fn parse(bytes: &[u8]) -> Self {
+        RawEntry {
+            name: bytes[0..11].try_into().unwrap(),
+            attr: bytes[11],
+            nt_flags: bytes[12],
+            cluster: (u16_at(bytes, 20) as u32) << 16 | u16_at(bytes, 26) as u32,
+            size: u32_at(bytes, 28),
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        let (date, time) = timestamp();
+        bytes.fill(0);
+        bytes[0..11].copy_from_slice(&self.name);
+        bytes[11] = self.attr;
+        bytes[12] = self.nt_flags;
+        put_u16(bytes, 14, time);
+        put_u16(bytes, 16, date);
+        put_u16(bytes, 18, date);
+        put_u16(bytes, 20, (self.cluster >> 16) as u16);
+        put_u16(bytes, 22, time);
+        put_u16(bytes, 24, date);
+        put_u16(bytes, 26, self.cluster as u16);
+        put_u32(bytes, 28, self.size);
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// "NAME.EXT", lower cased as the NT flags say.
+    fn display_name(&self) -> String {
+        let mut name = String::new();
+        for &b in self.name[..8].iter().take_while(|&&b| b != b' ') {
+            let b = if self.nt_flags & NT_LOWER_BASE != 0 {
+                b.to_ascii_lowercase()
+            } else {
+                b
+            };
+            name.push(b as char);
+        }
+        let ext = &self.name[8..];
+        if ext[0] != b' ' {
+            name.push('.');
+            for &b in ext.iter().take_while(|&&b| b != b' ') {
+                let b = if self.nt_flags & NT_LOWER_EXT != 0 {
+                    b.to_ascii_lowercase()
+                } else {
+                    b
+                };
+                name.push(b as char);
+            }
+        }
+        name
+    }
+}
+
+/// Checksum of a short name, stored in each of its long name entries.
+fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// FAT date and time for now. 1980-01-01 if the RTC isn't up.
+fn timestamp() -> (u16, u16) {
+    use ::time::OffsetDateTime;
+
+    let now = match crate::time::SystemTime::try_now() {
+        Some(now) => OffsetDateTime::from_system_time(now),
+        None => return ((1 << 5) | 1, 0),
+    };
+    let year = (now.year() - 1980).clamp(0, 127) as u16;
+    let date = year << 9 | (now.month() as u16) << 5 | now.day() as u16;
+    let time = (now.hour() as u16) << 11 | (now.minute() as u16) << 5 | now.second() as u16 / 2;
+    (date, time)
+}
+
+/// A directory entry, with the positions of its long name entries and short entry.
+struct FoundEntry {
+    name: String,
+    raw: RawEntry,
+    /// Long name entries then the short entry. The short entry is last.
+    positions: Vec<EntryPos>,
+}
+
+impl FoundEntry {
+    fn short_pos(&self) -> EntryPos {
+        *self.positions.last().unwrap()
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.raw.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+/// Everything in a directory, plus which slots are free.
+struct DirScan {
+    entries: Vec<FoundEntry>,
+    /// Every slot in the directory, in order, and whether it's free.
+    slots: Vec<(EntryPos, bool)>,
+    last_cluster: u32,
+}
+
+impl DirScan {
+    fn find(&self, name: &str) -> Option<&FoundEntry> {
+        self.entries.iter().find(|entry| entry.matches(name))
+    }
+
+    /// `count` consecutive free slots.
+    fn free_run(&self, count: usize) -> Option<Vec<EntryPos>> {
+        let mut run = Vec::new();
+        for &(pos, free) in &self.slots {
+            if free {
+                run.push(pos);
+                if run.len() == count {
+                    return Some(run);
+                }
+            } else {
+                run.clear();
+            }
+        }
+        None
+    }
+}
+
+struct State {
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    nodes: BTreeMap<EntryPos, Weak<FatNode>>,
+}
+
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    /// First sector of the filesystem on the device.
+    start: u64,
+    sectors_per_cluster: u64,
+    /// First sector of the first FAT. Relative to `start`, like the rest.
+    fat_start: u64,
+    fat_size: u64,
+    num_fats: u64,
+    data_start: u64,
+    /// Highest valid cluster number + 1.
+    cluster_end: u32,
+    root_cluster: u32,
+    state: Mutex<State>,
+    this: Weak<FatFs>,
+}
+
+impl FatFs {
+    /// Open the FAT32 filesystem on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>> {
+        let mut sector = vec![0u8; BLOCK_SIZE];
+        device.read_blocks(0, &mut sector)?;
+        if u16_at(&sector, 510) != 0xaa55 {
+            return Err(FsError::Unsupported);
+        }
+
+        let mut start = 0;
+        if !looks_like_bpb(&sector) {
+            // Maybe an MBR. Use the first FAT32 partition.
+            let partition = (0..4)
+                .map(|i| &sector[446 + 16 * i..446 + 16 * (i + 1)])
+                .find(|entry| matches!(entry[4], MBR_FAT32_CHS | MBR_FAT32_LBA))
+                .ok_or(FsError::Unsupported)?;
+            start = u32_at(partition, 8) as u64;
+            device.read_blocks(start, &mut sector)?;
+            if !looks_like_bpb(&sector) {
+                return Err(FsError::Unsupported);
+            }
+        }
+
+        let bytes_per_sector = u16_at(&sector, 11) as usize;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = u16_at(&sector, 14) as u64;
+        let num_fats = sector[16] as u64;
+        let root_entries = u16_at(&sector, 17);
+        let fat_size_16 = u16_at(&sector, 22);
+        let total_sectors = match u16_at(&sector, 19) {
+            0 => u32_at(&sector, 32) as u64,
+            n => n as u64,
+        };
+        let fat_size = u32_at(&sector, 36) as u64;
+        let root_cluster = u32_at(&sector, 44);
+
+        // FAT12 and FAT16 have a fixed root directory and a 16 bit FAT size.
+        if root_entries != 0 || fat_size_16 != 0 {
+            return Err(FsError::Unsupported);
+        }
+        if bytes_per_sector != BLOCK_SIZE
+            || sectors_per_cluster == 0
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+        {
+            return Err(FsError::Corrupt);
+        }
+
+        let data_start = reserved_sectors + num_fats * fat_size;
+        let cluster_count = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+        // Also limited by how many entries fit in the FAT.
+        let fat_entries = fat_size * (BLOCK_SIZE / 4) as u64;
+        let cluster_end = (cluster_count + 2).min(fat_entries) as u32;
+        if root_cluster < 2 || root_cluster >= cluster_end {
+            return Err(FsError::Corrupt);
+        }
+
+        Ok(Arc::new_cyclic(|this| FatFs {
+            device,
+            start,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_size,
+            num_fats,
+            data_start,
+            cluster_end,
+            root_cluster,
+            state: Mutex::new(State {
+                next_free: 2,
+                nodes: BTreeMap::new(),
+            }),
+            this: this.clone(),
+        }))
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * BLOCK_SIZE
+    }
+
+    /// `buf` may be DMAed into, so it's on the heap: stacks aren't in the direct map.
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(self.device.read_blocks(self.start + sector, buf)?)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<()> {
+        Ok(self.device.write_blocks(self.start + sector, buf)?)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<()> {
+        self.read_sector(self.cluster_sector(cluster), buf)
+    }
+
+    fn write_cluster(&self, cluster: u32, buf: &[u8]) -> Result<()> {
+        self.write_sector(self.cluster_sector(cluster), buf)
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32> {
+        if cluster >= 2 && cluster < self.cluster_end {
+            Ok(cluster)
+        } else {
+            Err(FsError::Corrupt)
+        }
+    }
+
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (
+            self.fat_start + offset / BLOCK_SIZE as u64,
+            (offset % BLOCK_SIZE as u64) as usize,
+        )
+    }
+
+    fn fat_get(&self, cluster: u32) -> Result<u32> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_sector(sector, &mut buf)?;
+        Ok(u32_at(&buf, offset) & CLUSTER_MASK)
+    }
+
+    /// Set a FAT entry in every copy of the FAT.
+    fn fat_set(&self, cluster: u32, value: u32) -> Result<()> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_sector(sector, &mut buf)?;
+        // The top 4 bits are reserved and must be kept.
+        let old = u32_at(&buf, offset);
+        put_u32(
+            &mut buf,
+            offset,
+            (old & !CLUSTER_MASK) | (value & CLUSTER_MASK),
+        );
+        for fat in 0..self.num_fats {
+            self.write_sector(sector + fat * self.fat_size, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Every cluster in the chain starting at `first`. Empty if `first` is 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < CLUSTER_EOC_MIN {
+            if cluster == CLUSTER_BAD || chain.len() >= self.cluster_end as usize {
+                return Err(FsError::Corrupt);
+            }
+            chain.push(self.check_cluster(cluster)?);
+            cluster = self.fat_get(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    /// Allocate a zeroed cluster and link it after `prev`.
+    fn alloc_cluster(&self, state: &mut State, prev: Option<u32>) -> Result<u32> {
+        let total = self.cluster_end - 2;
+        let mut cluster = state.next_free.clamp(2, self.cluster_end - 1);
+        for _ in 0..total {
+            if self.fat_get(cluster)? == 0 {
+                self.fat_set(cluster, CLUSTER_EOC)?;
+                if let Some(prev) = prev {
+                    self.fat_set(prev, cluster)?;
+                }
+                self.write_cluster(cluster, &vec![0; self.cluster_size()])?;
+                state.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+            cluster = if cluster + 1 >= self.cluster_end {
+                2
+            } else {
+                cluster + 1
+            };
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.fat_set(cluster, 0)?;
+            state.next_free = state.next_free.min(cluster);
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, pos: EntryPos) -> Result<[u8; DIR_ENTRY_SIZE]> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_sector(pos.0, &mut buf)?;
+        Ok(buf[pos.1..pos.1 + DIR_ENTRY_SIZE].try_into().unwrap())
+    }
+
+    fn write_entry(&self, pos: EntryPos, entry: &[u8]) -> Result<()> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        self.read_sector(pos.0, &mut buf)?;
+        buf[pos.1..pos.1 + DIR_ENTRY_SIZE].copy_from_slice(entry);
+        self.write_sector(pos.0, &buf)
+    }
+
+    /// Read every entry in the directory starting at `first`.
+    fn scan_dir(&self, first: u32) -> Result<DirScan> {
+        let mut scan = DirScan {
+            entries: Vec::new(),
+            slots: Vec::new(),
+            last_cluster: first,
+        };
+        let mut long_name: Vec<(u8, [u16; LFN_CHARS])> = Vec::new();
+        let mut long_positions = Vec::new();
+        let mut checksum = 0;
+        let mut ended = false;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+
+        for cluster in self.chain(first)? {
+            scan.last_cluster = cluster;
+            for s in 0..self.sectors_per_cluster {
+                let sector = self.cluster_sector(cluster) + s;
+                self.read_sector(sector, &mut buf)?;
+                for i in 0..ENTRIES_PER_SECTOR {
+                    let pos = (sector, i * DIR_ENTRY_SIZE);
+                    let bytes = &buf[pos.1..pos.1 + DIR_ENTRY_SIZE];
+                    // Everything after an end marker is free too.
+                    ended |= bytes[0] == ENTRY_END;
+                    let free = ended || bytes[0] == ENTRY_FREE;
+                    scan.slots.push((pos, free));
+                    if free {
+                        long_name.clear();
+                        long_positions.clear();
+                        continue;
+                    }
+
+                    if bytes[11] & 0x3f == ATTR_LONG_NAME {
+                        if bytes[0] & LFN_LAST != 0 {
+                            long_name.clear();
+                            long_positions.clear();
+                            checksum = bytes[13];
+                        }
+                        let mut chars = [0u16; LFN_CHARS];
+                        for (j, offset) in (1..11)
+                            .step_by(2)
+                            .chain((14..26).step_by(2))
+                            .chain((28..32).step_by(2))
+                            .enumerate()
+                        {
+                            chars[j] = u16_at(bytes, offset);
+                        }
+                        long_name.push((bytes[0] & 0x1f, chars));
+                        long_positions.push(pos);
+                        continue;
+                    }
+
+                    let raw = RawEntry::parse(bytes);
+                    let is_dot = raw.name[0] == b'.';
+                    if raw.attr & ATTR_VOLUME_ID != 0 || is_dot {
+                        long_name.clear();
+                        long_positions.clear();
+                        continue;
+                    }
+
+                    let mut positions = Vec::new();
+                    let name = if !long_name.is_empty() && checksum == lfn_checksum(&raw.name) {
+                        positions.append(&mut long_positions);
+                        decode_long_name(&mut long_name)
+                    } else {
+                        raw.display_name()
+                    };
+                    positions.push(pos);
+                    long_name.clear();
+                    long_positions.clear();
+                    scan.entries.push(FoundEntry {
+                        name,
+                        raw,
+                        positions,
+                    });
+                }
+            }
+        }
+        Ok(scan)
+    }
+
+    /// Get the cached node for an entry, or make one.
+    fn node(&self, state: &mut State, entry: &FoundEntry) -> Arc<FatNode> {
+        let pos = entry.short_pos();
+        if let Some(node) = state.nodes.get(&pos).and_then(Weak::upgrade) {
+            return node;
+        }
+        let node = Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            is_dir: entry.raw.is_dir(),
+            state: Mutex::new(NodeState {
+                first_cluster: entry.raw.cluster,
+                size: entry.raw.size,
+                entry: Some(pos),
+                deleted: false,
+            }),
+        });
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(pos, Arc::downgrade(&node));
+        node
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock()
+    }
+}
+
+fn looks_like_bpb(sector: &[u8]) -> bool {
+    matches!(sector[0], 0xeb | 0xe9) && u16_at(sector, 11) as usize == BLOCK_SIZE
+}
+
+fn decode_long_name(parts: &mut Vec<(u8, [u16; LFN_CHARS])>) -> String {
+    parts.sort_by_key(|(seq, _)| *seq);
+    let units = parts
+        .iter()
+        .flat_map(|(_, chars)| chars.iter().copied())
+        .take_while(|&c| c != 0 && c != 0xffff);
+    decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Characters allowed in a short name, besides letters and digits.
+fn short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// `name` as an 8.3 name and NT case flags, if it is one. Each part must be all upper or
+/// all lower case, or the case can't be kept without a long name.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut nt_flags = 0;
+    for (part, lower_flag) in [(base, NT_LOWER_BASE), (ext, NT_LOWER_EXT)] {
+        if !part.chars().all(short_name_char) {
+            return None;
+        }
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        match (has_lower, has_upper) {
+            (true, true) => return None,
+            (true, false) => nt_flags |= lower_flag,
+            _ => {}
+        }
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some((short, nt_flags))
+}
+
+/// Make up a unique 8.3 name for a long name. `BASENA~1.EXT` style.
+fn generate_short_name(name: &str, scan: &DirScan) -> Result<[u8; 11]> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let clean = |s: &str, max: usize| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                if short_name_char(c) {
+                    c.to_ascii_uppercase() as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(max)
+            .collect()
+    };
+    let base = clean(base, 8);
+    let ext = clean(ext, 3);
+
+    for n in 1..1000000u32 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !scan.entries.iter().any(|entry| entry.raw.name == short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+/// Long name entries for `name`, in the order they're stored.
+fn long_name_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() % LFN_CHARS != 0 {
+        units.push(0);
+    }
+    while units.len() % LFN_CHARS != 0 {
+        units.push(0xffff);
+    }
+
+    let checksum = lfn_checksum(short);
+    let count = units.len() / LFN_CHARS;
+    let mut entries = Vec::with_capacity(count);
+    for seq in (1..=count).rev() {
+        let chars = &units[(seq - 1) * LFN_CHARS..seq * LFN_CHARS];
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = seq as u8 | if seq == count { LFN_LAST } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, &c) in offsets.zip(chars) {
+            put_u16(&mut entry, offset, c);
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+struct NodeState {
+    /// 0 for an empty file.
+    first_cluster: u32,
+    size: u32,
+    /// Position of the short directory entry. `None` for the root.
+    entry: Option<EntryPos>,
+    deleted: bool,
+}
+
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    is_dir: bool,
+    state: Mutex<NodeState>,
+}
+
+impl FatNode {
+    fn first_cluster(&self) -> u32 {
+        self.state.lock().first_cluster
+    }
+
+    /// Write the node's size and first cluster back to its directory entry.
+    fn update_entry(&self, node: &NodeState) -> Result<()> {
+        let pos = match node.entry {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let mut bytes = self.fs.read_entry(pos)?;
+        let mut raw = RawEntry::parse(&bytes);
+        raw.cluster = node.first_cluster;
+        raw.size = if self.is_dir { 0 } else { node.size };
+        raw.write(&mut bytes);
+        self.fs.write_entry(pos, &bytes)
+    }
+
+    /// Make sure the file has at least `clusters` clusters. Returns the chain.
+    fn grow_chain(
+        &self,
+        fs_state: &mut State,
+        node: &mut NodeState,
+        clusters: usize,
+    ) -> Result<Vec<u32>> {
+        let mut chain = self.fs.chain(node.first_cluster)?;
+        while chain.len() < clusters {
+            let cluster = self.fs.alloc_cluster(fs_state, chain.last().copied())?;
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    fn write_locked(
+        &self,
+        fs_state: &mut State,
+        node: &mut NodeState,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize> {
+        let end = offset + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset > node.size as u64 {
+            // Fill the gap so old data in the last cluster doesn't show through.
+            let gap = vec![0; (offset - node.size as u64) as usize];
+            let size = node.size as u64;
+            self.write_locked(fs_state, node, size, &gap)?;
+        }
+
+        let cluster_size = self.fs.cluster_size();
+        let chain = self.grow_chain(fs_state, node, (end as usize).div_ceil(cluster_size))?;
+
+        let mut cluster_buf = vec![0; cluster_size];
+        let mut written = 0;
+        while written < buf.len() {
+            let pos = offset as usize + written;
+            let cluster = chain[pos / cluster_size];
+            let in_cluster = pos % cluster_size;
+            let len = (cluster_size - in_cluster).min(buf.len() - written);
+            if len != cluster_size {
+                self.fs.read_cluster(cluster, &mut cluster_buf)?;
+            }
+            cluster_buf[in_cluster..in_cluster + len].copy_from_slice(&buf[written..written + len]);
+            self.fs.write_cluster(cluster, &cluster_buf)?;
+            written += len;
+        }
+
+        node.size = node.size.max(end as u32);
+        self.update_entry(node)?;
+        Ok(written)
+    }
+
+    fn dir_scan(&self) -> Result<DirScan> {
+        if !self.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+        self.fs.scan_dir(self.first_cluster())
+    }
+
+    /// Write `entries` into free slots, growing the directory if it's full.
+    fn insert_entries(
+        &self,
+        fs_state: &mut State,
+        scan: &mut DirScan,
+        entries: &[[u8; DIR_ENTRY_SIZE]],
+    ) -> Result<Vec<EntryPos>> {
+        let positions = loop {
+            if let Some(run) = scan.free_run(entries.len()) {
+                break run;
+            }
+            let cluster = self.fs.alloc_cluster(fs_state, Some(scan.last_cluster))?;
+            scan.last_cluster = cluster;
+            for s in 0..self.fs.sectors_per_cluster {
+                let sector = self.fs.cluster_sector(cluster) + s;
+                for i in 0..ENTRIES_PER_SECTOR {
+                    scan.slots.push(((sector, i * DIR_ENTRY_SIZE), true));
+                }
+            }
+        };
+        for (pos, entry) in positions.iter().zip(entries) {
+            self.fs.write_entry(*pos, entry)?;
+        }
+        Ok(positions)
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Result<Metadata> {
+        let node = self.state.lock();
+        Ok(Metadata {
+            kind: if self.is_dir {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            },
+            size: node.size as u64,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let _fs = self.fs.lock();
+        let node = self.state.lock();
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+
+        let cluster_size = self.fs.cluster_size();
+        let chain = self.fs.chain(node.first_cluster)?;
+        let mut cluster_buf = vec![0; cluster_size];
+        let mut read = 0;
+        while read < len {
+            let pos = offset as usize + read;
+            let cluster = *chain.get(pos / cluster_size).ok_or(FsError::Corrupt)?;
+            let in_cluster = pos % cluster_size;
+            let n = (cluster_size - in_cluster).min(len - read);
+            self.fs.read_cluster(cluster, &mut cluster_buf)?;
+            buf[read..read + n].copy_from_slice(&cluster_buf[in_cluster..in_cluster + n]);
+            read += n;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let mut fs = self.fs.lock();
+        let mut node = self.state.lock();
+        if node.deleted {
+            return Err(FsError::NotFound);
+        }
+        self.write_locked(&mut fs, &mut node, offset, buf)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        if self.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        if size > u32::MAX as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+        let mut fs = self.fs.lock();
+        let mut node = self.state.lock();
+        if node.deleted {
+            return Err(FsError::NotFound);
+        }
+        if size > node.size as u64 {
+            let gap = vec![0; (size - node.size as u64) as usize];
+            let end = node.size as u64;
+            self.write_locked(&mut fs, &mut node, end, &gap)?;
+            return Ok(());
+        }
+
+        let keep = (size as usize).div_ceil(self.fs.cluster_size());
+        let chain = self.fs.chain(node.first_cluster)?;
+        if keep == 0 {
+            self.fs.free_chain(&mut fs, node.first_cluster)?;
+            node.first_cluster = 0;
+        } else if keep < chain.len() {
+            self.fs.free_chain(&mut fs, chain[keep])?;
+            self.fs.fat_set(chain[keep - 1], CLUSTER_EOC)?;
+        }
+        node.size = size as u32;
+        self.update_entry(&node)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let mut fs = self.fs.lock();
+        let scan = self.dir_scan()?;
+        let entry = scan.find(name).ok_or(FsError::NotFound)?;
+        Ok(self.fs.node(&mut fs, entry))
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        check_name(name)?;
+        if name.chars().any(|c| c < ' ' || "\"*:<>?\\|".contains(c)) {
+            return Err(FsError::InvalidPath);
+        }
+
+        let mut fs = self.fs.lock();
+        let mut scan = self.dir_scan()?;
+        if scan.find(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short, nt_flags, mut entries) = match exact_short_name(name) {
+            Some((short, nt_flags)) => (short, nt_flags, Vec::new()),
+            None => {
+                let short = generate_short_name(name, &scan)?;
+                (short, 0, long_name_entries(name, &short))
+            }
+        };
+
+        let mut raw = RawEntry {
+            name: short,
+            attr: ATTR_ARCHIVE,
+            nt_flags,
+            cluster: 0,
+            size: 0,
+        };
+
+        if kind == NodeKind::Directory {
+            raw.attr = ATTR_DIRECTORY;
+            raw.cluster = self.fs.alloc_cluster(&mut fs, None)?;
+            // ".." of a directory in the root points at cluster 0.
+            let parent = match self.first_cluster() {
+                cluster if cluster == self.fs.root_cluster => 0,
+                cluster => cluster,
+            };
+            let mut dot = [0u8; DIR_ENTRY_SIZE];
+            let mut dotdot = [0u8; DIR_ENTRY_SIZE];
+            RawEntry {
+                name: *b".          ",
+                ..raw
+            }
+            .write(&mut dot);
+            RawEntry {
+                name: *b"..         ",
+                cluster: parent,
+                ..raw
+            }
+            .write(&mut dotdot);
+            let sector = self.fs.cluster_sector(raw.cluster);
+            self.fs.write_entry((sector, 0), &dot)?;
+            self.fs.write_entry((sector, DIR_ENTRY_SIZE), &dotdot)?;
+        }
+
+        let mut short_entry = [0u8; DIR_ENTRY_SIZE];
+        raw.write(&mut short_entry);
+        entries.push(short_entry);
+        let positions = self.insert_entries(&mut fs, &mut scan, &entries)?;
+
+        let found = FoundEntry {
+            name: name.into(),
+            raw,
+            positions,
+        };
+        Ok(self.fs.node(&mut fs, &found))
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut fs = self.fs.lock();
+        let scan = self.dir_scan()?;
+        let entry = scan.find(name).ok_or(FsError::NotFound)?;
+        if entry.raw.is_dir() && !self.fs.scan_dir(entry.raw.cluster)?.entries.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        for &pos in &entry.positions {
+            let mut bytes = self.fs.read_entry(pos)?;
+            bytes[0] = ENTRY_FREE;
+            self.fs.write_entry(pos, &bytes)?;
+        }
+        self.fs.free_chain(&mut fs, entry.raw.cluster)?;
+
+        if let Some(node) = fs
+            .nodes
+            .remove(&entry.short_pos())
+            .and_then(|n| n.upgrade())
+        {
+            let mut node = node.state.lock();
+            node.deleted = true;
+            node.entry = None;
+            node.first_cluster = 0;
+            node.size = 0;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let _fs = self.fs.lock();
+        let scan = self.dir_scan()?;
+        Ok(scan
+            .entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: if entry.raw.is_dir() {
+                    NodeKind::Directory
+                } else {
+                    NodeKind::File
+                },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            is_dir: true,
+            state: Mutex::new(NodeState {
+                first_cluster: self.root_cluster,
+                size: 0,
+                entry: None,
+                deleted: false,
+            }),
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.device.flush()?)
+    }
+}
+
+/// Mount every block device with a FAT32 filesystem on it at `/mnt/<device>`.
+pub fn mount_all() {
+    for (name, device) in block::devices() {
+        match FatFs::new(device) {
+            Ok(fs) => {
+                let path = format!("/mnt/{}", name);
+                // So it shows up when listing /mnt. Mounting works without it.
+                let _ = super::mkdir_all(&path);
+                if let Err(err) = super::mount(&path, fs) {
+                    log::error!("failed to mount {}: {}", name, err);
+                }
+            }
+            Err(FsError::Unsupported) => {}
+            Err(err) => log::warn!("{}: {}", name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::ramdisk::RamDisk;
+
+    /// Minimal FAT32 image: 1 sector clusters, one FAT.
+    fn format(blocks: u64) -> Arc<dyn BlockDevice> {
+        let disk = Arc::new(RamDisk::new(blocks));
+        let mut sector = vec![0u8; BLOCK_SIZE];
+        sector[0] = 0xeb;
+        put_u16(&mut sector, 11, BLOCK_SIZE as u16);
+        sector[13] = 1;
+        put_u16(&mut sector, 14, 8);
+        sector[16] = 1;
+        put_u32(&mut sector, 32, blocks as u32);
+        put_u32(&mut sector, 36, 2);
+        put_u32(&mut sector, 44, 2);
+        put_u16(&mut sector, 510, 0xaa55);
+        disk.write_blocks(0, &sector).unwrap();
+
+        let mut fat = vec![0u8; BLOCK_SIZE];
+        put_u32(&mut fat, 0, 0x0fff_fff8);
+        put_u32(&mut fat, 4, CLUSTER_EOC);
+        put_u32(&mut fat, 8, CLUSTER_EOC);
+        disk.write_blocks(8, &fat).unwrap();
+        disk
+    }
+
+    #[test_case]
+    fn fat_create_write_read() {
+        let fs = FatFs::new(format(128)).unwrap();
+        let root = fs.root();
+
+        let file = root.create("A long file name.txt", NodeKind::File).unwrap();
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(0, &data).unwrap(), data.len());
+
+        let found = root.lookup("a long FILE name.txt").unwrap();
+        assert_eq!(found.metadata().unwrap().size, 2000);
+        let mut read = vec![0u8; 2000];
+        assert_eq!(found.read_at(0, &mut read).unwrap(), 2000);
+        assert_eq!(read, data);
+
+        found.truncate(10).unwrap();
+        assert_eq!(file.metadata().unwrap().size, 10);
+    }
+
+    #[test_case]
+    fn fat_directories() {
+        let fs = FatFs::new(format(128)).unwrap();
+        let root = fs.root();
+
+        let dir = root.create("BIN", NodeKind::Directory).unwrap();
+        dir.create("init", NodeKind::File).unwrap();
+        assert_eq!(
+            root.create("bin", NodeKind::File).err(),
+            Some(FsError::AlreadyExists)
+        );
+
+        let names: Vec<String> = dir
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["init"]);
+
+        assert_eq!(root.unlink("BIN").err(), Some(FsError::NotEmpty));
+        dir.unlink("init").unwrap();
+        root.unlink("BIN").unwrap();
+        assert!(root.read_dir().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,482 @@
+//! Virtual filesystem.
+//!
+//! Filesystems implement [`FileSystem`] and [`Inode`], and are [`mount`]ed at an absolute
+//! path. Paths are resolved by picking the mount with the longest matching prefix, then
+//! walking the rest of the path one [`Inode::lookup`] at a time.
+//!
+//! Paths are normalized lexically before resolving. There are no symlinks, so `..` can
+//! just drop the previous component.
+
+pub mod cpio;
+pub mod devfs;
+pub mod fat;
+pub mod ninep;
+pub mod procfs;
+pub mod tmpfs;
+
+use core::{
+    any::Any,
+    fmt::{self, Display, Formatter},
+};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    block::BlockError,
+    io,
+    sync::{Lazy, Rcu},
+};
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+/// Looked at by every path lookup, and hardly ever changed.
+static MOUNTS: Lazy<Rcu<Vec<Mount>>> = Lazy::new(|| Rcu::new(Vec::new()));
+
+#[derive(Clone)]
+struct Mount {
+    /// Normalized absolute path.
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// Tried to remove a directory that still has entries.
+    NotEmpty,
+    /// Empty name, bad characters, or a relative path where an absolute one is needed.
+    InvalidPath,
+    NameTooLong,
+    ReadOnly,
+    NoSpace,
+    FileTooLarge,
+    /// Rename between two filesystems.
+    CrossDevice,
+    /// Something is mounted there.
+    Busy,
+    /// The on-disk structures don't make sense.
+    Corrupt,
+    Unsupported,
+    /// A wait was cut short by a signal.
+    Interrupted,
+    /// A device was handed an address the process can't use.
+    BadAddress,
+    Io(BlockError),
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NotEmpty => write!(f, "directory not empty"),
+            FsError::InvalidPath => write!(f, "invalid path"),
+            FsError::NameTooLong => write!(f, "file name too long"),
+            FsError::ReadOnly => write!(f, "read only filesystem"),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::FileTooLarge => write!(f, "file too large"),
+            FsError::CrossDevice => write!(f, "cross device link"),
+            FsError::Busy => write!(f, "device or resource busy"),
+            FsError::Corrupt => write!(f, "filesystem corrupt"),
+            FsError::Unsupported => write!(f, "operation not supported"),
+            FsError::Interrupted => write!(f, "interrupted"),
+            FsError::BadAddress => write!(f, "bad address"),
+            FsError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for FsError {}
+
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> Self {
+        let kind = match err {
+            FsError::NotFound => io::ErrorKind::NotFound,
+            FsError::NotADirectory => io::ErrorKind::NotADirectory,
+            FsError::IsADirectory => io::ErrorKind::IsADirectory,
+            FsError::AlreadyExists => io::ErrorKind::AlreadyExists,
+            FsError::NotEmpty => io::ErrorKind::DirectoryNotEmpty,
+            FsError::InvalidPath | FsError::BadAddress => io::ErrorKind::InvalidInput,
+            FsError::NameTooLong => io::ErrorKind::FilenameTooLong,
+            FsError::ReadOnly => io::ErrorKind::ReadOnlyFilesystem,
+            FsError::NoSpace => io::ErrorKind::StorageFull,
+            FsError::FileTooLarge => io::ErrorKind::FileTooLarge,
+            FsError::CrossDevice => io::ErrorKind::CrossesDevices,
+            FsError::Busy => io::ErrorKind::ResourceBusy,
+            FsError::Corrupt => io::ErrorKind::InvalidData,
+            FsError::Unsupported => io::ErrorKind::Unsupported,
+            FsError::Interrupted => io::ErrorKind::Interrupted,
+            FsError::Io(_) => io::ErrorKind::Other,
+        };
+        io::Error::from(kind)
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            err => FsError::Io(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    /// Bytes for files. Filesystem specific for directories.
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Short name for the type of filesystem. eg. `fat32`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Write back anything cached.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file or directory. Methods that don't apply to the kind of node return
+/// [`FsError::IsADirectory`] or [`FsError::NotADirectory`].
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata>;
+
+    /// Read from `offset`. Returns 0 at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Write at `offset`, growing the file if needed.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Set the size of a file. New space reads as zeros.
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Remove a file or an empty directory.
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Move `name` to `new_name` in `new_dir`. `new_dir` is on the same filesystem.
+    fn rename(&self, _name: &str, _new_dir: &Arc<dyn Inode>, _new_name: &str) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Entries in a directory. Doesn't include `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// For filesystems to find their own node type in [`rename`](Self::rename).
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Split `path` into components, resolving `.` and `..`. Must be absolute.
+pub fn normalize(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+fn join(components: &[&str]) -> String {
+    let mut path = String::from("/");
+    for (i, component) in components.iter().enumerate() {
+        if i > 0 {
+            path.push('/');
+        }
+        path.push_str(component);
+    }
+    path
+}
+
+/// Find the filesystem `components` is on. Returns it and how many components the mount
+/// point used up.
+fn find_mount(components: &[&str]) -> Result<(Arc<dyn FileSystem>, usize)> {
+    MOUNTS
+        .read()
+        .iter()
+        .filter_map(|mount| {
+            let mount_components = normalize(&mount.path).ok()?;
+            let prefix_len = mount_components.len();
+            if components.len() >= prefix_len && components[..prefix_len] == mount_components[..] {
+                Some((mount.fs.clone(), prefix_len))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(_, prefix_len)| *prefix_len)
+        .ok_or(FsError::NotFound)
+}
+
+/// Make `fs` visible at `path`. The path doesn't need to exist on the parent filesystem.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let path = join(&normalize(path)?);
+    let name = fs.name();
+    MOUNTS.try_update(|mounts| {
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(FsError::Busy);
+        }
+        let mut mounts = mounts.clone();
+        mounts.push(Mount {
+            path: path.clone(),
+            fs,
+        });
+        Ok(mounts)
+    })?;
+    crate::log::info!("mounted {} at {}", name, path);
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<()> {
+    let path = join(&normalize(path)?);
+    let fs = MOUNTS
+        .read()
+        .iter()
+        .find(|mount| mount.path == path)
+        .map(|mount| mount.fs.clone())
+        .ok_or(FsError::NotFound)?;
+    fs.sync()?;
+    MOUNTS.try_update(|mounts| {
+        let mut mounts = mounts.clone();
+        let index = mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(FsError::NotFound)?;
+        mounts.remove(index);
+        Ok(mounts)
+    })
+}
+
+fn resolve(components: &[&str]) -> Result<Arc<dyn Inode>> {
+    let (fs, skip) = find_mount(components)?;
+    let mut node = fs.root();
+    for name in &components[skip..] {
+        node = node.lookup(name)?;
+    }
+    Ok(node)
+}
+
+/// Find the node at absolute `path`.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>> {
+    resolve(&normalize(path)?)
+}
+
+/// Split `path` into its parent directory and final name.
+fn parent_of(path: &str) -> Result<(Arc<dyn Inode>, String)> {
+    let components = normalize(path)?;
+    let (name, parent) = components.split_last().ok_or(FsError::InvalidPath)?;
+    Ok((resolve(parent)?, String::from(*name)))
+}
+
+pub fn create(path: &str, kind: NodeKind) -> Result<Arc<dyn Inode>> {
+    let (parent, name) = parent_of(path)?;
+    parent.create(&name, kind)
+}
+
+pub fn mkdir(path: &str) -> Result<Arc<dyn Inode>> {
+    create(path, NodeKind::Directory)
+}
+
+/// Create every missing directory in `path`.
+pub fn mkdir_all(path: &str) -> Result<Arc<dyn Inode>> {
+    let components = normalize(path)?;
+    for end in 1..=components.len() {
+        match resolve(&components[..end]) {
+            Ok(_) => {}
+            Err(FsError::NotFound) => {
+                resolve(&components[..end - 1])?
+                    .create(components[end - 1], NodeKind::Directory)?;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    resolve(&components)
+}
+
+pub fn remove(path: &str) -> Result<()> {
+    let (parent, name) = parent_of(path)?;
+    parent.unlink(&name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    let from_components = normalize(from)?;
+    let to_components = normalize(to)?;
+    let (from_fs, _) = find_mount(&from_components)?;
+    let (to_fs, _) = find_mount(&to_components)?;
+    if !Arc::ptr_eq(&from_fs, &to_fs) {
+        return Err(FsError::CrossDevice);
+    }
+
+    let (from_dir, from_name) = parent_of(from)?;
+    let (to_dir, to_name) = parent_of(to)?;
+    from_dir.rename(&from_name, &to_dir, &to_name)
+}
+
+/// An open file and a position in it, for the [`io`] traits. Wrap it in an
+/// [`io::BufReader`] or [`io::BufWriter`] to go a little at a time.
+pub struct File {
+    node: Arc<dyn Inode>,
+    pos: u64,
+}
+
+impl File {
+    pub fn open(path: &str) -> Result<File> {
+        Ok(File {
+            node: lookup(path)?,
+            pos: 0,
+        })
+    }
+
+    /// Open `path` empty, creating it if needed.
+    pub fn create(path: &str) -> Result<File> {
+        let node = match lookup(path) {
+            Ok(node) => node,
+            Err(FsError::NotFound) => create(path, NodeKind::File)?,
+            Err(err) => return Err(err),
+        };
+        node.truncate(0)?;
+        Ok(File { node, pos: 0 })
+    }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        self.node.metadata()
+    }
+}
+
+impl io::Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.node.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.node.write_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Writes go straight to the inode. [`sync`](FileSystem::sync) is what writes back.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Seek for File {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            io::SeekFrom::End(n) => (self.metadata()?.size, n),
+            io::SeekFrom::Current(n) => (self.pos, n),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+/// Read a whole file.
+pub fn read_to_vec(path: &str) -> Result<Vec<u8>> {
+    let node = lookup(path)?;
+    let size = node.metadata()?.size as usize;
+    let mut buf = alloc::vec![0; size];
+    let mut read = 0;
+    while read < size {
+        match node.read_at(read as u64, &mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    buf.truncate(read);
+    Ok(buf)
+}
+
+/// Create or replace a file with `data`. [`FsError::NoSpace`] if the filesystem stops
+/// taking it.
+pub fn write_file(path: &str, data: &[u8]) -> Result<()> {
+    let file = File::create(path)?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.node.write_at(written as u64, &data[written..])? {
+            0 => return Err(FsError::NoSpace),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+/// Check a name is something we can put in a directory.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(FsError::InvalidPath);
+    }
+    if name.len() > 255 {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn normalize_paths() {
+        assert_eq!(normalize("/").unwrap(), Vec::<&str>::new());
+        assert_eq!(normalize("/a//b/./c/").unwrap(), ["a", "b", "c"]);
+        assert_eq!(normalize("/a/b/../c").unwrap(), ["a", "c"]);
+        assert_eq!(normalize("/../a").unwrap(), ["a"]);
+        assert_eq!(normalize("a/b"), Err(FsError::InvalidPath));
+        assert_eq!(join(&["a", "b"]), "/a/b");
+    }
+}
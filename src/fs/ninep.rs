@@ -0,0 +1,615 @@
+//! 9P2000.L client, for directories shared from the host.
+//!
+//! A [`Transport`] carries the messages, which for now is always virtio-9p. Requests go
+//! one at a time, so they all use the same tag. Each node keeps a fid walked to its file
+//! and never opened, so more can be walked from it. Reading or writing opens a second fid
+//! the first time and keeps it; listing a directory opens one just for the listing. A
+//! node's fids are clunked when it's dropped.
+//!
+//! Permissions are up to the server. We create files as root, mode 0644, and directories
+//! 0755.
+
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use crate::block::BlockError;
+
+use super::{check_name, DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind, Result};
+
+const VERSION: &str = "9P2000.L";
+const TAG: u16 = 1;
+/// The tag `Tversion` uses.
+const NOTAG: u16 = 0xffff;
+const NOFID: u32 = !0;
+const ROOT_FID: u32 = 0;
+/// Everything in `Twrite` but the data, which is more than `Rread` has.
+const IO_HEADER: usize = 4 + 1 + 2 + 4 + 8 + 4;
+
+const TLERROR: u8 = 6;
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+/// Set in a qid's type for directories.
+const QTDIR: u8 = 0x80;
+
+/// Linux `open` flags, which `Tlopen` and `Tlcreate` take.
+const O_RDONLY: u32 = 0o0;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_SIZE: u32 = 0x8;
+
+/// Something that takes 9P requests to a server and brings back the response.
+pub trait Transport: Send + Sync {
+    /// The biggest message it carries, either way.
+    fn max_message(&self) -> usize;
+
+    /// Send a whole `request` message and return the whole response.
+    fn rpc(&self, request: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Qid {
+    kind: u8,
+    version: u32,
+    path: u64,
+}
+
+impl Qid {
+    fn node_kind(&self) -> NodeKind {
+        match self.kind & QTDIR {
+            0 => NodeKind::File,
+            _ => NodeKind::Directory,
+        }
+    }
+}
+
+/// A T-message being put together. The size goes in at the end.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(kind: u8) -> Message {
+        let tag = match kind {
+            TVERSION => NOTAG,
+            _ => TAG,
+        };
+        Message(Vec::new()).u32(0).u8(kind).u16(tag)
+    }
+
+    fn u8(mut self, value: u8) -> Message {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Message {
+        self.u16(value.len() as u16).bytes(value.as_bytes())
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Message {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Takes the fields out of an R-message in order.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(FsError::Corrupt);
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| FsError::Corrupt)
+    }
+
+    fn qid(&mut self) -> Result<Qid> {
+        Ok(Qid {
+            kind: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+}
+
+/// What a Linux errno from `Rlerror` means for us.
+fn errno_to_error(errno: u32) -> FsError {
+    match errno {
+        2 => FsError::NotFound,
+        1 | 13 | 30 => FsError::ReadOnly,
+        16 => FsError::Busy,
+        17 => FsError::AlreadyExists,
+        18 => FsError::CrossDevice,
+        20 => FsError::NotADirectory,
+        21 => FsError::IsADirectory,
+        22 => FsError::InvalidPath,
+        27 => FsError::FileTooLarge,
+        28 => FsError::NoSpace,
+        36 => FsError::NameTooLong,
+        39 => FsError::NotEmpty,
+        95 => FsError::Unsupported,
+        _ => FsError::Io(BlockError::Io),
+    }
+}
+
+/// Entries in an `Rreaddir`, and the offset to ask for the rest from.
+fn parse_dir_entries(mut data: Reader<'_>, entries: &mut Vec<DirEntry>) -> Result<u64> {
+    let mut next = 0;
+    while !data.buf.is_empty() {
+        let qid = data.qid()?;
+        next = data.u64()?;
+        let _kind = data.u8()?;
+        let name = data.str()?;
+        if name != "." && name != ".." {
+            entries.push(DirEntry {
+                name: name.into(),
+                kind: qid.node_kind(),
+            });
+        }
+    }
+    Ok(next)
+}
+
+pub struct NinePFs {
+    this: Weak<NinePFs>,
+    transport: Arc<dyn Transport>,
+    /// The biggest message, as agreed with the server.
+    msize: usize,
+    next_fid: AtomicU32,
+    root_qid: Qid,
+}
+
+impl NinePFs {
+    /// Agree on a version and attach to the share `aname`. Most servers only have one, and
+    /// take `""`.
+    pub fn new(transport: Arc<dyn Transport>, aname: &str) -> Result<Arc<NinePFs>> {
+        let max = transport.max_message();
+        let request = Message::new(TVERSION).u32(max as u32).str(VERSION).finish();
+        let response = transport.rpc(&request)?;
+        let mut reply = check_reply(&response, TVERSION + 1)?;
+        let msize = (reply.u32()? as usize).min(max);
+        if reply.str()? != VERSION || msize <= IO_HEADER {
+            return Err(FsError::Unsupported);
+        }
+
+        let request = Message::new(TATTACH)
+            .u32(ROOT_FID)
+            .u32(NOFID)
+            .str("root")
+            .str(aname)
+            .u32(0)
+            .finish();
+        let response = transport.rpc(&request)?;
+        let root_qid = check_reply(&response, TATTACH + 1)?.qid()?;
+
+        Ok(Arc::new_cyclic(|this| NinePFs {
+            this: this.clone(),
+            transport,
+            msize,
+            next_fid: AtomicU32::new(ROOT_FID + 1),
+            root_qid,
+        }))
+    }
+
+    /// Send `request` and check the reply is the R-message that goes with it.
+    fn rpc(&self, request: Message) -> Result<Vec<u8>> {
+        let kind = request.0[4];
+        let response = self.transport.rpc(&request.finish())?;
+        check_reply(&response, kind + 1)?;
+        Ok(response)
+    }
+
+    fn new_fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// A new fid for `name` in directory `fid`. With no name, a copy of `fid`.
+    fn walk(&self, fid: u32, name: Option<&str>) -> Result<(u32, Option<Qid>)> {
+        let new_fid = self.new_fid();
+        let request = Message::new(TWALK).u32(fid).u32(new_fid);
+        let request = match name {
+            Some(name) => request.u16(1).str(name),
+            None => request.u16(0),
+        };
+        let response = self.rpc(request)?;
+        let mut reply = body(&response);
+        let qid = match reply.u16()? {
+            0 if name.is_some() => return Err(FsError::NotFound),
+            0 => None,
+            _ => Some(reply.qid()?),
+        };
+        Ok((new_fid, qid))
+    }
+
+    fn clunk(&self, fid: u32) {
+        // Nothing to do if it fails. The fid's gone either way.
+        let _ = self.rpc(Message::new(TCLUNK).u32(fid));
+    }
+
+    /// Open a copy of `fid`. Returns the new fid and the most to read or write at once.
+    fn open(&self, fid: u32, flags: u32) -> Result<(u32, usize)> {
+        let (open, _) = self.walk(fid, None)?;
+        match self.rpc(Message::new(TLOPEN).u32(open).u32(flags)) {
+            Ok(response) => {
+                let mut reply = body(&response);
+                reply.qid()?;
+                Ok((open, self.io_size(reply.u32()?)))
+            }
+            Err(err) => {
+                self.clunk(open);
+                Err(err)
+            }
+        }
+    }
+
+    /// How much to read or write at once, given the `iounit` the server sent.
+    fn io_size(&self, iounit: u32) -> usize {
+        match iounit as usize {
+            0 => self.msize - IO_HEADER,
+            iounit => iounit.min(self.msize - IO_HEADER),
+        }
+    }
+
+    fn node(&self, fid: u32, qid: Qid) -> Arc<dyn Inode> {
+        Arc::new(NinePNode {
+            fs: self.this.upgrade().unwrap(),
+            fid,
+            qid,
+            io: Mutex::new(None),
+        })
+    }
+}
+
+/// Check `response` is a `kind` message, or turn an `Rlerror` into an error.
+fn check_reply(response: &[u8], kind: u8) -> Result<Reader<'_>> {
+    let mut reader = Reader { buf: response };
+    reader.u32()?;
+    let got = reader.u8()?;
+    reader.u16()?;
+    match got {
+        RLERROR => Err(errno_to_error(reader.u32()?)),
+        _ if got == kind => Ok(reader),
+        _ => Err(FsError::Corrupt),
+    }
+}
+
+/// The fields after size, type and tag.
+fn body(response: &[u8]) -> Reader<'_> {
+    Reader {
+        buf: response.get(7..).unwrap_or_default(),
+    }
+}
+
+impl FileSystem for NinePFs {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        // Nodes clunk their fid, so the root gets a copy of the attached one.
+        match self.walk(ROOT_FID, None) {
+            Ok((fid, _)) => self.node(fid, self.root_qid),
+            // With no fid it'll fail whatever it's asked.
+            Err(_) => self.node(NOFID, self.root_qid),
+        }
+    }
+}
+
+/// A fid opened for reading, and writing if the server allowed it.
+struct OpenFid {
+    fid: u32,
+    writable: bool,
+    io_size: usize,
+}
+
+struct NinePNode {
+    fs: Arc<NinePFs>,
+    fid: u32,
+    qid: Qid,
+    /// Opened the first time it's read or written.
+    io: Mutex<Option<OpenFid>>,
+}
+
+impl NinePNode {
+    fn check_dir(&self) -> Result<()> {
+        match self.qid.node_kind() {
+            NodeKind::Directory => Ok(()),
+            NodeKind::File => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn check_file(&self) -> Result<()> {
+        match self.qid.node_kind() {
+            NodeKind::File => Ok(()),
+            NodeKind::Directory => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Run `f` with the open fid, opening it first if needed.
+    fn with_open<T>(&self, f: impl FnOnce(&OpenFid) -> Result<T>) -> Result<T> {
+        let mut io = self.io.lock();
+        if io.is_none() {
+            let (open, writable) = match self.fs.open(self.fid, O_RDWR) {
+                Ok(open) => (open, true),
+                Err(FsError::ReadOnly) => (self.fs.open(self.fid, O_RDONLY)?, false),
+                Err(err) => return Err(err),
+            };
+            *io = Some(OpenFid {
+                fid: open.0,
+                writable,
+                io_size: open.1,
+            });
+        }
+        f(io.as_ref().unwrap())
+    }
+}
+
+impl Drop for NinePNode {
+    fn drop(&mut self) {
+        if let Some(open) = self.io.get_mut().take() {
+            self.fs.clunk(open.fid);
+        }
+        if self.fid != NOFID {
+            self.fs.clunk(self.fid);
+        }
+    }
+}
+
+impl Inode for NinePNode {
+    fn metadata(&self) -> Result<Metadata> {
+        let request = Message::new(TGETATTR).u32(self.fid).u64(GETATTR_BASIC);
+        let response = self.fs.rpc(request)?;
+        let mut reply = body(&response);
+        let _valid = reply.u64()?;
+        let qid = reply.qid()?;
+        // mode, uid, gid, nlink and rdev come before the size.
+        reply.take(4 + 4 + 4 + 8 + 8)?;
+        Ok(Metadata {
+            kind: qid.node_kind(),
+            size: reply.u64()?,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.check_file()?;
+        self.with_open(|open| {
+            let count = buf.len().min(open.io_size) as u32;
+            let request = Message::new(TREAD).u32(open.fid).u64(offset).u32(count);
+            let response = self.fs.rpc(request)?;
+            let mut reply = body(&response);
+            let len = (reply.u32()? as usize).min(buf.len());
+            buf[..len].copy_from_slice(reply.take(len)?);
+            Ok(len)
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        self.check_file()?;
+        self.with_open(|open| {
+            if !open.writable {
+                return Err(FsError::ReadOnly);
+            }
+            let data = &buf[..buf.len().min(open.io_size)];
+            let request = Message::new(TWRITE)
+                .u32(open.fid)
+                .u64(offset)
+                .u32(data.len() as u32)
+                .bytes(data);
+            let response = self.fs.rpc(request)?;
+            Ok(body(&response).u32()? as usize)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.check_file()?;
+        let request = Message::new(TSETATTR)
+            .u32(self.fid)
+            .u32(SETATTR_SIZE)
+            // mode, uid, gid
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u64(size)
+            // atime and mtime, seconds and nanoseconds.
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0);
+        self.fs.rpc(request)?;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.check_dir()?;
+        check_name(name)?;
+        match self.fs.walk(self.fid, Some(name))? {
+            (fid, Some(qid)) => Ok(self.fs.node(fid, qid)),
+            (_, None) => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        self.check_dir()?;
+        check_name(name)?;
+        match kind {
+            NodeKind::File => {
+                // Tlcreate turns the fid into the new file, opened.
+                let (fid, _) = self.fs.walk(self.fid, None)?;
+                let request = Message::new(TLCREATE)
+                    .u32(fid)
+                    .str(name)
+                    .u32(O_RDWR | O_CREAT | O_EXCL)
+                    .u32(0o644)
+                    .u32(0);
+                let created = self.fs.rpc(request);
+                self.fs.clunk(fid);
+                created?;
+            }
+            NodeKind::Directory => {
+                let request = Message::new(TMKDIR)
+                    .u32(self.fid)
+                    .str(name)
+                    .u32(0o755)
+                    .u32(0);
+                self.fs.rpc(request)?;
+            }
+        }
+        self.lookup(name)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.check_dir()?;
+        let flags = match self.lookup(name)?.metadata()?.kind {
+            NodeKind::File => 0,
+            NodeKind::Directory => AT_REMOVEDIR,
+        };
+        let request = Message::new(TUNLINKAT).u32(self.fid).str(name).u32(flags);
+        self.fs.rpc(request)?;
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<NinePNode>()
+            .ok_or(FsError::CrossDevice)?;
+        let request = Message::new(TRENAMEAT)
+            .u32(self.fid)
+            .str(name)
+            .u32(new_dir.fid)
+            .str(new_name);
+        self.fs.rpc(request)?;
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        self.check_dir()?;
+        let (fid, io_size) = self.fs.open(self.fid, O_RDONLY)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let result = loop {
+            let request = Message::new(TREADDIR)
+                .u32(fid)
+                .u64(offset)
+                .u32(io_size as u32);
+            let response = match self.fs.rpc(request) {
+                Ok(response) => response,
+                Err(err) => break Err(err),
+            };
+            let mut reply = body(&response);
+            let data = match reply.u32().and_then(|count| reply.take(count as usize)) {
+                // Nothing left.
+                Ok([]) => break Ok(()),
+                Ok(data) => data,
+                Err(err) => break Err(err),
+            };
+            match parse_dir_entries(Reader { buf: data }, &mut entries) {
+                Ok(next) => offset = next,
+                Err(err) => break Err(err),
+            }
+        };
+        self.fs.clunk(fid);
+        result.map(|_| entries)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ninep_messages() {
+        let walk = Message::new(TWALK).u32(1).u32(2).u16(1).str("bin").finish();
+        assert_eq!(
+            walk,
+            b"\x16\0\0\0\x6e\x01\0\x01\0\0\0\x02\0\0\0\x01\0\x03\0bin"
+        );
+
+        let error = b"\x0b\0\0\0\x07\x01\0\x02\0\0\0";
+        assert_eq!(check_reply(error, TWALK + 1).err(), Some(FsError::NotFound));
+
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&[QTDIR, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        entry.extend_from_slice(&7u64.to_le_bytes());
+        entry.extend_from_slice(b"\x04\x03\0doc");
+        let mut entries = Vec::new();
+        let next = parse_dir_entries(Reader { buf: &entry }, &mut entries).unwrap();
+        assert_eq!(next, 7);
+        assert_eq!(entries[0].name, "doc");
+        assert_eq!(entries[0].kind, NodeKind::Directory);
+    }
+}
@@ -0,0 +1,302 @@
+//! Processes as files, mounted at `/proc`.
+//!
+//! A directory per process, named by its pid, plus `self` for whoever's looking. Each has
+//! `status` (name, parent, state, nice, hart, CPU time and memory), `maps` (its VMAs) and
+//! `fds` (its open files). They're written out fresh on every read, so reading one in
+//! pieces can see it change in between. Nothing can be written.
+
+use core::{any::Any, fmt::Write};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use super::{DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind, Result};
+use crate::{
+    pagetable::EntryFlags,
+    process::{self, Pid, Process, State},
+};
+
+/// The files in each process's directory.
+const FILES: &[&str] = &["status", "maps", "fds"];
+
+pub struct ProcFs;
+
+struct ProcRoot;
+
+/// A process's directory.
+struct ProcDir {
+    pid: Pid,
+}
+
+/// One of [`FILES`].
+struct ProcFile {
+    pid: Pid,
+    name: &'static str,
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcRoot)
+    }
+}
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            kind: NodeKind::Directory,
+            size: process::list().len() as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let pid = match name {
+            "self" => process::current().ok_or(FsError::NotFound)?.pid(),
+            name => Pid(name.parse().map_err(|_| FsError::NotFound)?),
+        };
+        process::find(pid).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcDir { pid }))
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        let mut entries: Vec<DirEntry> = process::list()
+            .iter()
+            .map(|process| DirEntry {
+                name: format!("{}", process.pid()),
+                kind: NodeKind::Directory,
+            })
+            .collect();
+        if process::current().is_some() {
+            entries.push(DirEntry {
+                name: "self".into(),
+                kind: NodeKind::Directory,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Inode for ProcDir {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            kind: NodeKind::Directory,
+            size: FILES.len() as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let name = FILES
+            .iter()
+            .find(|&&file| file == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcFile {
+            pid: self.pid,
+            name,
+        }))
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        Ok(FILES
+            .iter()
+            .map(|&name| DirEntry {
+                name: name.into(),
+                kind: NodeKind::File,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl ProcFile {
+    /// What's in it now. [`FsError::NotFound`] once the process is gone.
+    fn contents(&self) -> Result<String> {
+        let process = process::find(self.pid).ok_or(FsError::NotFound)?;
+        Ok(match self.name {
+            "status" => status(&process),
+            "maps" => maps(&process),
+            _ => fds(&process),
+        })
+    }
+}
+
+impl Inode for ProcFile {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            kind: NodeKind::File,
+            size: self.contents()?.len() as u64,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let contents = self.contents()?;
+        let data = contents.as_bytes();
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+        let data = &data[offset as usize..];
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn status(process: &Process) -> String {
+    let state = match process.state() {
+        State::Ready => "ready".into(),
+        State::Running => "running".into(),
+        State::Exited(code) => format!("exited {}", code),
+    };
+    let usage = process.usage();
+    let mut out = String::new();
+    let _ = writeln!(out, "Name:\t{}", process.name());
+    let _ = writeln!(out, "Pid:\t{}", process.pid());
+    let parent = process.parent().map_or(0, |parent| parent.pid().0);
+    let _ = writeln!(out, "PPid:\t{}", parent);
+    let _ = writeln!(out, "State:\t{}", state);
+    let _ = writeln!(out, "Nice:\t{}", process.nice());
+    let hart = usage.hart.map_or("-".into(), |hart| format!("{}", hart.0));
+    let _ = writeln!(out, "Hart:\t{}", hart);
+    let _ = writeln!(out, "CpuTime:\t{} us", usage.cpu_time.as_micros());
+    let _ = writeln!(out, "VmRSS:\t{} kB", process.memory().size() / 1024);
+    let _ = writeln!(out, "FDSize:\t{}", process.files().iter().count());
+    out
+}
+
+/// `start-end rwx` for each VMA, like Linux's.
+fn maps(process: &Process) -> String {
+    let memory = process.memory();
+    let mut out = String::new();
+    for vma in memory.vmas() {
+        let flag = |flag, c| if vma.flags.contains(flag) { c } else { '-' };
+        let _ = writeln!(
+            out,
+            "{:08x}-{:08x} {}{}{}",
+            vma.start,
+            vma.end,
+            flag(EntryFlags::READ, 'r'),
+            flag(EntryFlags::WRITE, 'w'),
+            flag(EntryFlags::EXECUTE, 'x'),
+        );
+    }
+    out
+}
+
+/// `fd mode offset path` for each open file.
+fn fds(process: &Process) -> String {
+    let files = process.files();
+    let mut out = String::new();
+    for (fd, file) in files.iter() {
+        let _ = writeln!(
+            out,
+            "{} {} {} {}",
+            fd,
+            file.mode(),
+            file.offset(),
+            file.path()
+        );
+    }
+    out
+}
+
+/// Mount at `/proc`.
+pub fn init() {
+    // So it shows up when listing `/`. Mounting works without it.
+    let _ = super::mkdir_all("/proc");
+    if let Err(err) = super::mount("/proc", Arc::new(ProcFs)) {
+        crate::log::error!("failed to mount /proc: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An executable with one segment, its own headers, at 0x10000.
+    fn tiny_elf() -> Vec<u8> {
+        let mut elf = alloc::vec![0u8; 120];
+        elf[..4].copy_from_slice(b"\x7fELF");
+        elf[4..7].copy_from_slice(&[2, 1, 1]);
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&243u16.to_le_bytes());
+        elf[24..32].copy_from_slice(&0x10000u64.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&1u16.to_le_bytes());
+        // PT_LOAD, r-x
+        elf[64..68].copy_from_slice(&1u32.to_le_bytes());
+        elf[68..72].copy_from_slice(&5u32.to_le_bytes());
+        elf[80..88].copy_from_slice(&0x10000u64.to_le_bytes());
+        elf[96..104].copy_from_slice(&120u64.to_le_bytes());
+        elf[104..112].copy_from_slice(&120u64.to_le_bytes());
+        elf
+    }
+
+    fn read(node: &Arc<dyn Inode>) -> String {
+        let mut buf = [0; 512];
+        let len = node.read_at(0, &mut buf).unwrap();
+        String::from_utf8(buf[..len].into()).unwrap()
+    }
+
+    #[test_case]
+    fn process_files() {
+        let process = Process::from_elf("proctest", &tiny_elf(), &[b"proctest"]).unwrap();
+        let pid = format!("{}", process.pid());
+        assert!(ProcRoot
+            .read_dir()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.name == pid));
+
+        let dir = ProcRoot.lookup(&pid).unwrap();
+        let status = read(&dir.lookup("status").unwrap());
+        assert!(status.starts_with("Name:\tproctest\n"));
+        assert!(status.contains("State:\tready\n"));
+        let maps = read(&dir.lookup("maps").unwrap());
+        assert!(maps.starts_with("00010000-00011000 r-x\n"));
+        let fds = read(&dir.lookup("fds").unwrap());
+        assert!(fds.starts_with("0 rw 0 /dev/console\n"));
+        assert!(matches!(dir.lookup("environ"), Err(FsError::NotFound)));
+
+        let status = dir.lookup("status").unwrap();
+        drop(process);
+        assert!(matches!(ProcRoot.lookup(&pid), Err(FsError::NotFound)));
+        assert!(matches!(status.metadata(), Err(FsError::NotFound)));
+    }
+}
@@ -0,0 +1,336 @@
+//! In-memory filesystem.
+//!
+//! Everything lives on the kernel heap and is gone on reboot. Mounted at `/` at boot, so
+//! there's somewhere to unpack the initramfs and to put scratch files.
+//!
+//! File contents each have their own lock. Anything that changes the tree (create, unlink,
+//! rename) also holds the filesystem's tree lock, so those can lock several directories
+//! without worrying about the order.
+
+use core::any::Any;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+
+use super::{check_name, DirEntry, FileSystem, FsError, Inode, Metadata, NodeKind, Result};
+
+/// Largest file we'll make. Stops a bad offset from trying to allocate everything.
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpNode>>),
+}
+
+pub struct TmpNode {
+    /// Shared by every node on the filesystem.
+    tree: Arc<Mutex<()>>,
+    this: Weak<TmpNode>,
+    /// Empty for the root, and for nodes that have been removed.
+    parent: Mutex<Weak<TmpNode>>,
+    contents: Mutex<Contents>,
+}
+
+impl TmpFs {
+    pub fn new() -> Arc<Self> {
+        let tree = Arc::new(Mutex::new(()));
+        Arc::new(TmpFs {
+            root: TmpNode::new(tree, Weak::new(), NodeKind::Directory),
+        })
+    }
+}
+
+impl TmpNode {
+    fn new(tree: Arc<Mutex<()>>, parent: Weak<TmpNode>, kind: NodeKind) -> Arc<Self> {
+        let contents = match kind {
+            NodeKind::File => Contents::File(Vec::new()),
+            NodeKind::Directory => Contents::Directory(BTreeMap::new()),
+        };
+        Arc::new_cyclic(|this| TmpNode {
+            tree,
+            this: this.clone(),
+            parent: Mutex::new(parent),
+            contents: Mutex::new(contents),
+        })
+    }
+
+    fn kind(&self) -> NodeKind {
+        match *self.contents.lock() {
+            Contents::File(_) => NodeKind::File,
+            Contents::Directory(_) => NodeKind::Directory,
+        }
+    }
+
+    fn is_empty_dir(&self) -> bool {
+        matches!(&*self.contents.lock(), Contents::Directory(entries) if entries.is_empty())
+    }
+
+    /// Is `self` `node`, or somewhere under it?
+    fn is_within(&self, node: &TmpNode) -> bool {
+        let mut current = self.this.upgrade();
+        while let Some(dir) = current {
+            if core::ptr::eq(&*dir, node) {
+                return true;
+            }
+            current = dir.parent.lock().upgrade();
+        }
+        false
+    }
+}
+
+/// Resize `data` to `size`, failing instead of aborting if the heap runs out.
+fn resize(data: &mut Vec<u8>, size: u64) -> Result<()> {
+    if size > MAX_FILE_SIZE {
+        return Err(FsError::FileTooLarge);
+    }
+    let size = size as usize;
+    if size > data.len() {
+        data.try_reserve(size - data.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
+
+impl Inode for TmpNode {
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(match &*self.contents.lock() {
+            Contents::File(data) => Metadata {
+                kind: NodeKind::File,
+                size: data.len() as u64,
+            },
+            Contents::Directory(entries) => Metadata {
+                kind: NodeKind::Directory,
+                size: entries.len() as u64,
+            },
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        match &*self.contents.lock() {
+            Contents::File(data) => {
+                if offset >= data.len() as u64 {
+                    return Ok(0);
+                }
+                let data = &data[offset as usize..];
+                let len = buf.len().min(data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+            Contents::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => {
+                let end = offset
+                    .checked_add(buf.len() as u64)
+                    .ok_or(FsError::FileTooLarge)?;
+                if end > data.len() as u64 {
+                    resize(data, end)?;
+                }
+                data[offset as usize..end as usize].copy_from_slice(buf);
+                Ok(buf.len())
+            }
+            Contents::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        match &mut *self.contents.lock() {
+            Contents::File(data) => {
+                resize(data, size)?;
+                if data.capacity() > 2 * data.len() {
+                    data.shrink_to_fit();
+                }
+                Ok(())
+            }
+            Contents::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => match entries.get(name) {
+                Some(node) => Ok(node.clone()),
+                None => Err(FsError::NotFound),
+            },
+            Contents::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<Arc<dyn Inode>> {
+        check_name(name)?;
+        let _tree = self.tree.lock();
+        match &mut *self.contents.lock() {
+            Contents::Directory(entries) => {
+                if entries.contains_key(name) {
+                    return Err(FsError::AlreadyExists);
+                }
+                let node = TmpNode::new(self.tree.clone(), self.this.clone(), kind);
+                entries.insert(name.into(), node.clone());
+                Ok(node)
+            }
+            Contents::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let _tree = self.tree.lock();
+        match &mut *self.contents.lock() {
+            Contents::Directory(entries) => {
+                let node = entries.get(name).ok_or(FsError::NotFound)?;
+                if node.kind() == NodeKind::Directory && !node.is_empty_dir() {
+                    return Err(FsError::NotEmpty);
+                }
+                // Anyone with it open can keep using it until they drop it.
+                let node = entries.remove(name).unwrap();
+                *node.parent.lock() = Weak::new();
+                Ok(())
+            }
+            Contents::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        check_name(new_name)?;
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<TmpNode>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.tree, &new_dir.tree) {
+            return Err(FsError::CrossDevice);
+        }
+        let _tree = self.tree.lock();
+
+        let node = match &*self.contents.lock() {
+            Contents::Directory(entries) => entries.get(name).ok_or(FsError::NotFound)?.clone(),
+            Contents::File(_) => return Err(FsError::NotADirectory),
+        };
+        let same_dir = core::ptr::eq(self, new_dir);
+        if same_dir && name == new_name {
+            return Ok(());
+        }
+        let kind = node.kind();
+        if kind == NodeKind::Directory && new_dir.is_within(&node) {
+            return Err(FsError::InvalidPath);
+        }
+
+        // Check what we'd be replacing.
+        match &*new_dir.contents.lock() {
+            Contents::Directory(entries) => {
+                if let Some(existing) = entries.get(new_name) {
+                    match (kind, existing.kind()) {
+                        (NodeKind::File, NodeKind::Directory) => return Err(FsError::IsADirectory),
+                        (NodeKind::Directory, NodeKind::File) => {
+                            return Err(FsError::NotADirectory)
+                        }
+                        (NodeKind::Directory, NodeKind::Directory) if !existing.is_empty_dir() => {
+                            return Err(FsError::NotEmpty)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Contents::File(_) => return Err(FsError::NotADirectory),
+        }
+
+        if let Contents::Directory(entries) = &mut *self.contents.lock() {
+            entries.remove(name);
+        }
+        if let Contents::Directory(entries) = &mut *new_dir.contents.lock() {
+            if let Some(replaced) = entries.insert(new_name.into(), node.clone()) {
+                *replaced.parent.lock() = Weak::new();
+            }
+        }
+        *node.parent.lock() = new_dir.this.clone();
+        Ok(())
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>> {
+        match &*self.contents.lock() {
+            Contents::Directory(entries) => Ok(entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    kind: node.kind(),
+                })
+                .collect()),
+            Contents::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mount an empty tmpfs as the root filesystem.
+pub fn init() {
+    if let Err(err) = super::mount("/", TmpFs::new()) {
+        crate::log::error!("failed to mount /: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn tmpfs_files() {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let file = root.create("file", NodeKind::File).unwrap();
+        file.write_at(4, b"data").unwrap();
+
+        let mut buf = [0xffu8; 16];
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 8);
+        assert_eq!(&buf[..8], b"\0\0\0\0data");
+
+        file.truncate(2).unwrap();
+        assert_eq!(root.lookup("file").unwrap().metadata().unwrap().size, 2);
+        assert_eq!(
+            root.create("file", NodeKind::File).err(),
+            Some(FsError::AlreadyExists)
+        );
+    }
+
+    #[test_case]
+    fn tmpfs_rename() {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let dir = root.create("dir", NodeKind::Directory).unwrap();
+        let sub = dir.create("sub", NodeKind::Directory).unwrap();
+        root.create("file", NodeKind::File).unwrap();
+
+        root.rename("file", &sub, "moved").unwrap();
+        assert!(sub.lookup("moved").is_ok());
+        assert_eq!(root.lookup("file").err(), Some(FsError::NotFound));
+
+        assert_eq!(
+            root.rename("dir", &sub, "loop").err(),
+            Some(FsError::InvalidPath)
+        );
+        assert_eq!(root.unlink("dir").err(), Some(FsError::NotEmpty));
+    }
+}
@@ -0,0 +1,266 @@
+//! Framebuffers.
+//!
+//! A display driver makes a [`Framebuffer`] for each screen and [`register`]s it. Drawing
+//! goes straight into its memory, and nothing reaches the screen until it's
+//! [`flush`](Framebuffer::flush)ed, which the driver does through [`Scanout`]. Text is
+//! drawn with [`font`].
+
+pub mod font;
+
+use core::{
+    fmt::{self, Display, Formatter},
+    ptr::NonNull,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+static FRAMEBUFFERS: Mutex<Vec<Arc<Framebuffer>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsError {
+    /// The device reported an error.
+    Io,
+    OutOfMemory,
+    Unsupported,
+}
+
+impl Display for GraphicsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphicsError::Io => write!(f, "display error"),
+            GraphicsError::OutOfMemory => write!(f, "out of memory for the framebuffer"),
+            GraphicsError::Unsupported => write!(f, "operation not supported"),
+        }
+    }
+}
+
+impl core::error::Error for GraphicsError {}
+
+/// How a pixel is laid out in memory. Four bytes each, in this order, the last one unused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgrx8888,
+    Rgbx8888,
+}
+
+impl PixelFormat {
+    pub const fn bytes_per_pixel(self) -> usize {
+        4
+    }
+
+    /// `color` as it's stored, in native byte order.
+    pub fn encode(self, color: Color) -> u32 {
+        let Color { r, g, b } = color;
+        let bytes = match self {
+            PixelFormat::Bgrx8888 => [b, g, r, 0],
+            PixelFormat::Rgbx8888 => [r, g, b, 0],
+        };
+        u32::from_ne_bytes(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+}
+
+/// A rectangle of pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The part of it inside a `width` by `height` screen.
+    pub fn clip(self, width: u32, height: u32) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Rect {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+}
+
+/// What a display driver does for its framebuffers.
+pub trait Scanout: Send + Sync {
+    /// Show what's been drawn in `rect`, which is inside the framebuffer.
+    fn flush(&self, rect: Rect) -> Result<(), GraphicsError>;
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    /// Bytes from the start of one row to the next.
+    stride: usize,
+    format: PixelFormat,
+    pixels: NonNull<u8>,
+    scanout: Arc<dyn Scanout>,
+}
+
+// The memory belongs to the driver, which keeps it for as long as `scanout` is around.
+// Drawing from two places at once just mixes up the pixels.
+unsafe impl Send for Framebuffer {}
+unsafe impl Sync for Framebuffer {}
+
+impl Framebuffer {
+    /// # Safety
+    /// `pixels` is `stride * height` bytes that stay valid as long as `scanout` does, and
+    /// `stride` has room for `width` pixels.
+    pub unsafe fn new(
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+        pixels: NonNull<u8>,
+        scanout: Arc<dyn Scanout>,
+    ) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            stride,
+            format,
+            pixels,
+            scanout,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// The first byte of the top left pixel. There are `stride * height` bytes.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.pixels.as_ptr()
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> *mut u32 {
+        let offset = y as usize * self.stride + x as usize * self.format.bytes_per_pixel();
+        self.as_ptr().wrapping_add(offset) as *mut u32
+    }
+
+    /// Set one pixel. Ones off the screen are ignored.
+    pub fn put_pixel(&self, x: u32, y: u32, color: Color) {
+        if x < self.width && y < self.height {
+            unsafe { self.pixel(x, y).write_volatile(self.format.encode(color)) };
+        }
+    }
+
+    /// Fill the part of `rect` on the screen with `color`.
+    pub fn fill(&self, rect: Rect, color: Color) {
+        let rect = rect.clip(self.width, self.height);
+        let pixel = self.format.encode(color);
+        for y in rect.y..rect.y + rect.height {
+            let row = self.pixel(rect.x, y);
+            for x in 0..rect.width as usize {
+                unsafe { row.add(x).write_volatile(pixel) };
+            }
+        }
+    }
+
+    /// Move everything up `lines` rows of pixels, and fill the rows that leaves at the
+    /// bottom with `color`.
+    pub fn scroll_up(&self, lines: u32, color: Color) {
+        let lines = lines.min(self.height);
+        let keep = (self.height - lines) as usize;
+        unsafe {
+            let from = self.as_ptr().add(lines as usize * self.stride);
+            core::ptr::copy(from, self.as_ptr(), keep * self.stride);
+        }
+        self.fill(Rect::new(0, keep as u32, self.width, lines), color);
+    }
+
+    /// Show what's been drawn in `rect`.
+    pub fn flush(&self, rect: Rect) -> Result<(), GraphicsError> {
+        let rect = rect.clip(self.width, self.height);
+        if rect.is_empty() {
+            return Ok(());
+        }
+        self.scanout.flush(rect)
+    }
+}
+
+pub fn register(framebuffer: Arc<Framebuffer>) {
+    crate::log::info!(
+        "fb{}: {}x{} {:?}",
+        FRAMEBUFFERS.lock().len(),
+        framebuffer.width,
+        framebuffer.height,
+        framebuffer.format
+    );
+    FRAMEBUFFERS.lock().push(framebuffer);
+}
+
+/// Framebuffer `index`, numbered in the order they were registered.
+pub fn get(index: usize) -> Option<Arc<Framebuffer>> {
+    FRAMEBUFFERS.lock().get(index).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn rects_and_pixels() {
+        assert_eq!(
+            Rect::new(10, 20, 100, 100).clip(50, 60),
+            Rect::new(10, 20, 40, 40)
+        );
+        assert!(Rect::new(80, 0, 10, 10).clip(50, 60).is_empty());
+        let red = Color::rgb(0xff, 0, 0);
+        assert_eq!(
+            PixelFormat::Bgrx8888.encode(red).to_ne_bytes(),
+            [0, 0, 0xff, 0]
+        );
+        assert_eq!(
+            PixelFormat::Rgbx8888.encode(red).to_ne_bytes(),
+            [0xff, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Per-hart storage.
+//!
+//! Every hart gets its own copy of the `.tdata`/`.tbss` sections and points `tp` at it.
+//! Statics declared with [`hart_local!`](crate::hart_local) are `#[thread_local]`, so
+//! the compiler addresses them relative to `tp` and each hart sees its own copy.
+//!
+//! RISC-V uses TLS variant I with a zero sized TCB. So `tp` points directly at the
+//! start of the TLS block and no extra header is needed.
+
+use core::{
+    alloc::Layout,
+    arch::asm,
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    linker_info::{tbss, tdata},
+    sbi::hart::{HartId, HartMask},
+};
+
+/// Harts that have been through [`init_hart`], one bit per hart id.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// A value with one instance per hart. Declare these with [`hart_local!`](crate::hart_local).
+pub struct PerHart<T> {
+    value: T,
+}
+
+impl<T> PerHart<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        PerHart { value }
+    }
+
+    /// Get this hart's copy of the value.
+    ///
+    /// Panics if the hart-local area hasn't been setup yet.
+    pub fn get(&self) -> &T {
+        assert!(is_initialized(), "hart local storage used before init_hart");
+        &self.value
+    }
+
+    /// Get this hart's copy. Returns `None` if called before [`init_hart`]. Useful in the
+    /// panic and trap paths where we can't assume boot got that far.
+    pub fn try_get(&self) -> Option<&T> {
+        if is_initialized() {
+            Some(&self.value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Declare statics that have a separate copy per hart.
+///
+/// ```ignore
+/// hart_local! {
+///     static COUNTER: Cell<u64> = Cell::new(0);
+/// }
+///
+/// COUNTER.get().set(COUNTER.get().get() + 1);
+/// ```
+#[macro_export]
+macro_rules! hart_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            #[thread_local]
+            $vis static $name: $crate::hart_local::PerHart<$t> =
+                $crate::hart_local::PerHart::new($init);
+        )+
+    };
+}
+
+crate::hart_local! {
+    static HART_ID: Cell<Option<HartId>> = Cell::new(None);
+}
+
+pub(crate) fn read_tp() -> usize {
+    let tp: usize;
+    unsafe {
+        asm!("mv {tp}, tp", tp = out(reg) tp);
+    }
+    tp
+}
+
+/// `_start` zeros `tp`. So anything else means some hart-local area is installed.
+pub fn is_initialized() -> bool {
+    read_tp() != 0
+}
+
+fn block_layout() -> Layout {
+    // .tbss is laid out straight after .tdata, so the whole template is one range.
+    let start = tdata().start;
+    let end = tbss().end;
+    Layout::from_size_align((end - start) as usize, 4096).expect("invalid tls layout")
+}
+
+/// Allocate and install the hart-local area for the current hart.
+///
+/// The area is copied from the `.tdata`/`.tbss` image in the kernel. Which is never
+/// written to directly, so it stays a clean template for harts started later.
+///
+/// # Safety
+/// Must be called once per hart, after the allocator is available, and before any
+/// hart-local is accessed on this hart.
+pub(crate) unsafe fn init_hart(hart_id: HartId) {
+    let layout = block_layout();
+    let template = tdata().start as *const u8;
+
+    let block = alloc::alloc::alloc(layout);
+    if block.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    ptr::copy_nonoverlapping(template, block, layout.size());
+
+    asm!("mv tp, {block}", block = in(reg) block);
+
+    HART_ID.get().set(Some(hart_id));
+    // Harts past the first 64 don't fit. Nothing that needs them to be counted runs there.
+    if hart_id.0 < usize::BITS as usize {
+        ONLINE.fetch_or(1 << hart_id.0, Ordering::AcqRel);
+    }
+}
+
+/// The harts that are up and running kernel code.
+pub fn online_harts() -> HartMask {
+    HartMask {
+        hart_mask: ONLINE.load(Ordering::Acquire),
+        hart_mask_base: 0,
+    }
+}
+
+/// Id of the hart we're currently running on.
+pub fn current_hart() -> HartId {
+    HART_ID
+        .get()
+        .get()
+        .expect("hart local storage has no hart id")
+}
+
+/// Like [`current_hart`] but usable before the hart-local area is installed.
+pub fn try_current_hart() -> Option<HartId> {
+    HART_ID.try_get().and_then(Cell::get)
+}
@@ -1,41 +1,100 @@
 use core::{
-    fmt::{Debug, Formatter},
+    fmt::{self, Debug, Display, Formatter},
     mem::size_of,
     ops::{Range},
     str,
 };
 
-use alloc::{vec::Vec};
+use alloc::{format, vec::Vec};
 use anyhow::Error;
 use fdt_rs::{base::DevTree, index::DevTreeIndex, prelude::*, spec::Phandle, error::DevTreeError};
-use spin::Once;
+use crate::sync::Once;
 
 use crate::{
-    basic_allocator,
+    addr::{PhysAddr, VirtAddr},
+    basic_allocator, boot,
+    devices::{self, DtNode},
+    error::KernelResult,
+    log,
     isr::plic::InterruptId,
     linker_info::{bss, data, rodata, text},
     prelude::*,
+    pstore,
     sbi::{
         hart::HartId,
         reset::{shutdown, system_reset_extension},
     },
 };
-use crate::pagetable::PAGE_SIZE;
+use crate::pagetable::{virt_to_phys, VirtualMemorySystem, PAGE_SIZE};
 
 static HW_INFO: Once<HwInfo> = Once::INIT;
 
+/// Something wrong with the device tree: which node, and what.
+#[derive(Debug, Clone)]
+pub struct DtError {
+    /// Empty if it's the tree as a whole.
+    pub node: String,
+    pub problem: String,
+}
+
+impl DtError {
+    pub fn new(node: &str, problem: impl Display) -> Self {
+        DtError {
+            node: node.into(),
+            problem: format!("{}", problem),
+        }
+    }
+
+    /// `property` of `node` couldn't be read.
+    pub fn property(node: &str, property: &str, err: impl Display) -> Self {
+        DtError::new(node, format_args!("bad `{}`: {}", property, err))
+    }
+}
+
+impl Display for DtError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.node.is_empty() {
+            write!(f, "{}", self.problem)
+        } else {
+            write!(f, "{}: {}", self.node, self.problem)
+        }
+    }
+}
+
+impl core::error::Error for DtError {}
+
+impl From<DevTreeError> for DtError {
+    fn from(err: DevTreeError) -> Self {
+        DtError::new("", err)
+    }
+}
+
+/// A range from the device tree that isn't one of physical addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotPhysical;
+
+impl Display for NotPhysical {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("not a physical address range")
+    }
+}
+
 pub type PHandle = u32;
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct PhysicalAddressRange {
     pub kind: PhysicalAddressKind,
     pub description: &'static str,
-    pub start: u64,
-    pub end: u64,
+    pub start: PhysAddr,
+    pub end: PhysAddr,
 }
 
 impl PhysicalAddressRange {
-    pub fn new(range: Range<u64>, kind: PhysicalAddressKind, description: &'static str) -> Self {
+    pub fn new(
+        range: Range<PhysAddr>,
+        kind: PhysicalAddressKind,
+        description: &'static str,
+    ) -> Self {
         PhysicalAddressRange {
             kind,
             start: range.start,
@@ -44,21 +103,44 @@ impl PhysicalAddressRange {
         }
     }
 
-    pub fn page_numbers(&self) -> impl Iterator<Item = u64> {
-        let mut current = self.start;
-        let end = self.end;
-        core::iter::from_fn(move || {
-            let next = current;
-            current += 4096;
-            if next < end {
-                Some(next / 4096)
-            } else {
-                None
+    /// From a `reg` property's base and length, unless it goes past what a physical address
+    /// can be.
+    pub fn from_reg(
+        base: u64,
+        len: u64,
+        kind: PhysicalAddressKind,
+        description: &'static str,
+    ) -> Result<Self, NotPhysical> {
+        let end = base.checked_add(len).ok_or(NotPhysical)?;
+        Self::from_bounds(base, end, kind, description)
+    }
+
+    /// From `start` to `end`, unless that's backwards or past what a physical address can
+    /// be.
+    pub fn from_bounds(
+        start: u64,
+        end: u64,
+        kind: PhysicalAddressKind,
+        description: &'static str,
+    ) -> Result<Self, NotPhysical> {
+        match (PhysAddr::try_new(start), PhysAddr::try_new(end)) {
+            (Some(start), Some(end)) if start <= end => {
+                Ok(Self::new(start..end, kind, description))
             }
-        })
+            _ => Err(NotPhysical),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
     }
 
-    pub fn as_range(&self) -> Range<u64> {
+    pub fn page_numbers(&self) -> impl Iterator<Item = u64> {
+        let (start, end) = (self.start.as_u64(), self.end.as_u64());
+        (start..end).step_by(4096).map(|addr| addr / 4096)
+    }
+
+    pub fn as_range(&self) -> Range<PhysAddr> {
         self.start..self.end
     }
 }
@@ -97,7 +179,7 @@ pub enum PhysicalAddressKind {
 pub struct HwInfo {
     pub timebase_freq: u64,
 
-    /// Memory. Currently assuming a single block of RAM.
+    /// RAM banks, from every `memory` node. Not necessarily contiguous.
     #[builder(default, setter(each(name = "add_memory")))]
     pub ram: Vec<PhysicalAddressRange>,
     // Memory reserved by SBI.
@@ -105,11 +187,43 @@ pub struct HwInfo {
     pub reserved_memory: Vec<PhysicalAddressRange>,
     #[builder(setter(each(name = "add_hart")))]
     pub harts: Vec<Hart>,
-    pub uart: UartNS16550a,
+    /// Every ns16550a, by address. `ttyS<n>` is the `n`th.
+    pub uarts: Vec<UartNS16550a>,
+    /// Which of [`uarts`](Self::uarts) `stdout-path` points at, or the first.
+    #[builder(default)]
+    pub console_uart: usize,
     pub plic: Plic,
-    pub clint: Clint,
+    #[builder(default, setter(strip_option))]
+    pub clint: Option<Clint>,
+
+    /// Without one the wall clock starts at the epoch.
+    #[builder(default, setter(strip_option))]
+    pub rtc: Option<Rtc>,
+
+    /// Kernel command line. From `/chosen`.
+    #[builder(default)]
+    pub bootargs: String,
 
-    pub rtc: Rtc,
+    /// Device tree path of the console, from `stdout-path` in `/chosen` with any
+    /// `:options` taken off and aliases resolved.
+    #[builder(default, setter(strip_option))]
+    pub stdout_path: Option<String>,
+
+    /// Random bytes from the bootloader, for [`rand`](crate::rand). `rng-seed` in `/chosen`.
+    #[builder(default)]
+    pub rng_seed: Vec<u8>,
+
+    /// Initial ramdisk loaded by the bootloader. From `/chosen`.
+    #[builder(default, setter(strip_option))]
+    pub initrd: Option<PhysicalAddressRange>,
+
+    /// Where a panic report is kept across reboots. See [`pstore`](crate::pstore).
+    #[builder(default, setter(strip_option))]
+    pub pstore: Option<PhysicalAddressRange>,
+
+    /// Every node with a `compatible` property, for [`devices::probe_all`].
+    #[builder(default, setter(each(name = "add_node")))]
+    pub nodes: Vec<DtNode>,
 }
 
 #[derive(Debug, Clone, derive_builder::Builder)]
@@ -119,6 +233,36 @@ pub struct Hart {
     pub phandle: PHandle,
     pub hart_id: HartId,
     pub interrupt_handle: PHandle,
+    /// Biggest paging mode the hart has, from `mmu-type`. `None` if it can't page.
+    #[builder(default)]
+    pub mmu_type: Option<VirtualMemorySystem>,
+    /// `riscv,isa`, like `rv64imafdc_zicsr_svpbmt`.
+    #[builder(default)]
+    pub isa: String,
+    /// `riscv,isa-extensions`, which newer trees have as well or instead.
+    #[builder(default)]
+    pub isa_extensions: Vec<String>,
+}
+
+impl Hart {
+    /// Whether the hart has ISA extension `name`, like `c` or `svpbmt`.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.isa_extensions
+            .iter()
+            .any(|ext| ext.eq_ignore_ascii_case(name))
+            || isa_has_extension(&self.isa, name)
+    }
+}
+
+/// Look for `name` in an ISA string. Single letter extensions come straight after `rv64`,
+/// and the longer ones after that, separated by underscores.
+fn isa_has_extension(isa: &str, name: &str) -> bool {
+    let mut parts = isa.split('_');
+    let letters = parts.next().and_then(|base| base.get(4..)).unwrap_or("");
+    match name.as_bytes() {
+        [letter] => letters.bytes().any(|b| b.eq_ignore_ascii_case(letter)),
+        _ => parts.any(|ext| ext.eq_ignore_ascii_case(name)),
+    }
 }
 
 #[derive(Debug, Clone, derive_builder::Builder)]
@@ -251,13 +395,13 @@ pub fn dump_dtb(dtb: *const u8) {
 
     for node in index.nodes() {
         let name = node.name().unwrap();
-        println!("{} {{", name);
+        log::info!("{} {{", name);
         for prop in node.props() {
             let name = prop.name().unwrap();
             let value = prop.raw();
-            println!("  {} = {:?}", name, value);
+            log::info!("  {} = {:?}", name, value);
         }
-        println!("}}");
+        log::info!("}}");
     }
 
     shutdown();
@@ -290,33 +434,141 @@ impl DtbRef {
     }
 }
 
+/// A copy of the device tree blob, kept after the allocator takes over the memory the
+/// bootloader put it in.
+pub struct Dtb {
+    /// `u64`s to keep it aligned.
+    words: Box<[u64]>,
+    len: usize,
+}
+
+static DTB: Once<Dtb> = Once::INIT;
+
+impl Dtb {
+    fn copy(tree: &DevTree) -> Dtb {
+        let bytes = tree.buf();
+        let mut words = vec![0u64; bytes.len().div_ceil(8)].into_boxed_slice();
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(8)) {
+            let mut buf = [0; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            *word = u64::from_ne_bytes(buf);
+        }
+        Dtb {
+            words,
+            len: bytes.len(),
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+
+    pub fn tree(&self) -> DevTree<'_> {
+        // Checked when it was copied.
+        unsafe { DevTree::new(self.bytes()) }.unwrap()
+    }
+
+    /// Do something with an index of the tree. The index is built fresh each time.
+    pub fn with_index<R>(&self, f: impl FnOnce(&DevTreeIndex) -> R) -> Result<R, DevTreeError> {
+        let tree = self.tree();
+        let layout = DevTreeIndex::get_layout(&tree)?;
+        let mut buffer = vec![0u8; layout.size()];
+        let index = DevTreeIndex::new(tree, &mut buffer)?;
+        Ok(f(&index))
+    }
+
+    /// Every node in the tree, in order.
+    pub fn nodes(&self) -> Vec<DtNode> {
+        self.with_index(|index| index.nodes().map(|node| dt_node(&node)).collect())
+            .unwrap_or_default()
+    }
+
+    /// The node at `path`, like `/soc/rtc@101000`.
+    pub fn find(&self, path: &str) -> Option<DtNode> {
+        self.with_index(|index| {
+            index
+                .nodes()
+                .find(|node| path_matches(&node_path(node), path))
+                .map(|node| dt_node(&node))
+        })
+        .ok()?
+    }
+
+    /// Nodes with `compatible` in their `compatible` list.
+    pub fn compatible(&self, compatible: &str) -> Vec<DtNode> {
+        self.with_index(|index| {
+            index
+                .compatible_nodes(compatible)
+                .map(|node| dt_node(&node))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+}
+
+/// What the device tree says. Once `setup_dtb` has run.
+pub fn get() -> Option<&'static HwInfo> {
+    HW_INFO.get()
+}
+
+/// The device tree the kernel booted with. Once `setup_dtb` has run.
+pub fn dtb() -> Option<&'static Dtb> {
+    DTB.get()
+}
+
 pub fn setup_dtb(dtb: DtbRef) -> &'static HwInfo {
     HW_INFO.call_once(|| {
+        let dt_start = dtb.start();
         let dt = match dtb.dev_tree() {
             Ok(dt) => dt,
-            Err(err) => {
-                panic!("Error parsing Device Tree: {}", err);
-            }
+            Err(err) => boot::fatal("device tree", &err.into()),
         };
+        // The original goes when the allocator gets the rest of RAM.
+        let dtb = DTB.call_once(|| Dtb::copy(&dt));
 
-        let hwinfo = match walk_dtb(dt) {
+        let mut hwinfo = match walk_dtb(dtb.tree()) {
             Ok(hwinfo) => hwinfo,
-            Err(err) => {
-                panic!("Error parsing Device Tree: {}", err);
-            }
+            Err(err) => boot::fatal("device tree", &err),
         };
+        if hwinfo.pstore.is_none() {
+            // Out of the way of the kernel and everything the bootloader put in RAM.
+            let original = virt_to_phys(VirtAddr::new(dt_start));
+            let mut avoid: Vec<_> = hwinfo
+                .reserved_memory
+                .iter()
+                .map(PhysicalAddressRange::as_range)
+                .collect();
+            avoid.push(phys(text().start..bss().end));
+            avoid.push(original..original + dtb.bytes().len() as u64);
+            avoid.extend(hwinfo.initrd.map(|initrd| initrd.as_range()));
+            avoid.extend(basic_allocator::heap_ranges().iter().map(PhysicalAddressRange::as_range));
+            if let Some(pstore) = pstore::place(&hwinfo.ram, &avoid) {
+                hwinfo.reserved_memory.push(pstore);
+                hwinfo.pstore = Some(pstore);
+            }
+        }
+        let ram: u64 = hwinfo.ram.iter().map(|ram| ram.end - ram.start).sum();
+        log::info!(
+            "device tree: {} harts, {} MiB RAM, {} device nodes, {} bytes",
+            hwinfo.harts.len(),
+            ram / (1024 * 1024),
+            hwinfo.nodes.len(),
+            dtb.bytes().len()
+        );
 
         hwinfo
     })
 }
 
-fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
-    let index_layout = DevTreeIndex::get_layout(&tree).map_err(Error::msg)?;
+/// Devices that can't be made sense of are reported and left out. Anything boot needs
+/// that's missing is an error.
+fn walk_dtb<'a>(tree: DevTree<'a>) -> KernelResult<HwInfo> {
+    let index_layout = DevTreeIndex::get_layout(&tree)?;
 
     let mut index_buffer = alloc::vec![0u8; index_layout.size()];
     let slice = index_buffer.as_mut_slice();
 
-    let index = DevTreeIndex::new(tree, slice).map_err(Error::msg)?;
+    let index = DevTreeIndex::new(tree, slice)?;
 
     let mut hwinfo = HwInfoBuilder::default();
 
@@ -344,6 +596,25 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
                     hart.hart_id(value.into());
                 }
             }
+            if prop.name() == Ok("mmu-type") {
+                if let Ok(mmu_type) = prop.str() {
+                    hart.mmu_type(VirtualMemorySystem::from_mmu_type(mmu_type));
+                }
+            }
+            if prop.name() == Ok("riscv,isa") {
+                if let Ok(isa) = prop.str() {
+                    hart.isa(isa.into());
+                }
+            }
+            if prop.name() == Ok("riscv,isa-extensions") {
+                let extensions = prop
+                    .raw()
+                    .split(|&b| b == 0)
+                    .filter(|ext| !ext.is_empty())
+                    .map(|ext| String::from_utf8_lossy(ext).into_owned())
+                    .collect();
+                hart.isa_extensions(extensions);
+            }
         }
 
         for child in node.children() {
@@ -375,6 +646,12 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
         }
     }
 
+    let stdout_path = stdout_path(&index);
+    if let Some(path) = &stdout_path {
+        hwinfo.stdout_path(path.clone());
+    }
+
+    let mut uarts = Vec::new();
     for node in index.compatible_nodes("ns16550a") {
         let mut uart = UartNS16550aBuilder::default();
 
@@ -398,11 +675,13 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
                 }
                 Ok("reg") => {
                     if let (Ok(base), Ok(len)) = (prop.u64(0), prop.u64(1)) {
-                        uart.reg(PhysicalAddressRange::new(
-                            base..base + len,
-                            PhysicalAddressKind::Mmio,
-                            "uart",
-                        ));
+                        let kind = PhysicalAddressKind::Mmio;
+                        match PhysicalAddressRange::from_reg(base, len, kind, "uart") {
+                            Ok(reg) => {
+                                uart.reg(reg);
+                            }
+                            Err(err) => report(DtError::property(&node_path(&node), "reg", err)),
+                        }
                     }
                 }
                 Ok("clock-frequency") => {
@@ -415,10 +694,19 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
         }
 
         if let Ok(uart) = uart.build() {
-            hwinfo.uart(uart);
-            break;
+            uarts.push((node_path(&node), uart));
         }
     }
+    uarts.sort_by_key(|(_, uart)| uart.reg.start);
+    // The console is the UART `stdout-path` points at, or the first one if it doesn't.
+    let console = stdout_path
+        .as_deref()
+        .and_then(|wanted| uarts.iter().position(|(path, _)| path_matches(path, wanted)))
+        .unwrap_or(0);
+    hwinfo.console_uart(console);
+    if !uarts.is_empty() {
+        hwinfo.uarts(uarts.into_iter().map(|(_, uart)| uart).collect());
+    }
 
     for node in index.compatible_nodes("sifive,plic-1.0.0") {
         let mut plic = PlicBuilder::default();
@@ -436,104 +724,119 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
                     }
                 }
                 Ok("riscv,ndev") => {
-                    plic.number_of_sources(prop.u32(0).unwrap());
+                    if let Ok(sources) = prop.u32(0) {
+                        plic.number_of_sources(sources);
+                    }
                 }
                 Ok("reg") => {
                     if let (Ok(base), Ok(len)) = (prop.u64(0), prop.u64(1)) {
-                        let reg = PhysicalAddressRange::new(
-                            base..(base + len),
-                            PhysicalAddressKind::Mmio,
-                            "plic",
-                        );
-                        plic.reg(reg);
+                        let kind = PhysicalAddressKind::Mmio;
+                        match PhysicalAddressRange::from_reg(base, len, kind, "plic") {
+                            Ok(reg) => {
+                                plic.reg(reg);
+                            }
+                            Err(err) => report(DtError::property(&node_path(&node), "reg", err)),
+                        }
                     }
                 }
                 Ok("interrupts-extended") => {
-                    plic.contexts(parse_interrupt_extended(prop, &hwinfo));
+                    match parse_interrupt_extended(prop, &hwinfo) {
+                        Ok(contexts) => {
+                            plic.contexts(contexts);
+                        }
+                        Err(err) => {
+                            let node = node_path(&node);
+                            report(DtError::property(&node, "interrupts-extended", err))
+                        }
+                    }
                 }
 
                 _ => {}
             }
         }
 
-        if let Ok(plic) = plic.build() {
-            hwinfo.plic(plic);
+        match plic.build() {
+            Ok(plic) => {
+                hwinfo.plic(plic);
+            }
+            Err(err) => report(DtError::new(&node_path(&node), err)),
         }
     }
 
     for node in index.compatible_nodes("sifive,clint0") {
-        let mut clint = ClintBuilder::default();
-        let name = node.name().expect("clint node does not have name");
-        clint.name(name.into());
-
-        for prop in node.props() {
-            match prop.name().expect("clint node failed get prop name") {
-                "reg" => {
-                    // OpenSBI protects clint0.
-                    let kind = PhysicalAddressKind::Reserved;
-                    let base = prop
-                        .u64(0)
-                        .unwrap_or_else(|err| panic!("failed to read {name}/reg[0] as u64: {err}"));
-                    let len = prop
-                        .u64(1)
-                        .unwrap_or_else(|err| panic!("failed to read {name}/reg[1] as u64: {err}"));
-                    clint.reg(PhysicalAddressRange::new(base..(base + len), kind, "clint"));
-                }
-                "interrupts-extended" => {
-                    clint.contexts(parse_interrupt_extended(prop, &hwinfo));
-                }
-
-                _ => {}
+        match parse_clint(&node, &hwinfo) {
+            Ok(clint) => {
+                hwinfo.clint(clint);
             }
+            Err(err) => report(err),
         }
-        hwinfo.clint(clint.build().expect("failed to build clint"));
     }
 
     for node in index.compatible_nodes("google,goldfish-rtc") {
-        let mut rtc = RtcBuilder::default();
-
-        rtc.name(node.name().expect("rtc: node has no name").into());
+        match parse_rtc(&node) {
+            Ok(rtc) => {
+                hwinfo.rtc(rtc);
+            }
+            Err(err) => report(err),
+        }
+    }
 
-        for prop in node.props() {
-            match prop.name().expect("rtc: prop has no name") {
-                "interrupts" => {
-                    let int = InterruptId::new(prop.u32(0).expect("interrupts has no data"))
-                        .expect("rtc: interrupt numbers cannot be zero");
-                    rtc.interrupt(int);
-                }
-                "interrupt-parent" => {
-                    let val = prop
-                        .phandle(0)
-                        .expect("rtc: interrupt-parent requires parent");
+    let mut initrd_start = None;
+    let mut initrd_end = None;
+    for node in index.nodes() {
+        if node.props().any(|prop| prop.name() == Ok("compatible")) {
+            hwinfo.add_node(dt_node(&node));
+        }
 
-                    rtc.interrupt_parent(val);
-                }
-                "reg" => {
-                    let reg_base = prop.u64(0).expect("rtc: error getting reg[0]");
-                    let reg_len = prop.u64(1).expect("rtc: error getting reg[1]");
-                    rtc.reg(PhysicalAddressRange::new(
-                        reg_base..(reg_base + reg_len),
-                        PhysicalAddressKind::Mmio,
-                        "rtc",
-                    ));
+        if node.name() == Ok("chosen") {
+            for prop in node.props() {
+                let value = match prop.length() {
+                    4 => prop.u32(0).map(u64::from),
+                    _ => prop.u64(0),
+                };
+                match prop.name() {
+                    Ok("linux,initrd-start") => initrd_start = value.ok(),
+                    Ok("linux,initrd-end") => initrd_end = value.ok(),
+                    Ok("bootargs") => {
+                        if let Ok(args) = prop.str() {
+                            hwinfo.bootargs(args.into());
+                        }
+                    }
+                    Ok("rng-seed") => {
+                        hwinfo.rng_seed(prop.raw().to_vec());
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
+            continue;
         }
-        hwinfo.rtc(rtc.build().unwrap());
-    }
 
-    for node in index.nodes() {
         if node.name() == Ok("reserved-memory") {
             for range in node.children() {
+                let ramoops = range
+                    .props()
+                    .any(|p| p.name() == Ok("compatible") && p.str() == Ok("ramoops"));
                 if let Some(reg) = range.props().find(|p| p.name() == Ok("reg")) {
-                    let base = reg.u64(0).unwrap();
-                    let len = reg.u64(1).unwrap();
-                    hwinfo.add_reserved_memory(PhysicalAddressRange::new(
-                        base..(base + len),
-                        PhysicalAddressKind::Reserved,
-                        "reserved-memory",
-                    ));
+                    match (reg.u64(0), reg.u64(1)) {
+                        (Ok(base), Ok(len)) => {
+                            let description = if ramoops { "pstore" } else { "reserved-memory" };
+                            let kind = PhysicalAddressKind::Reserved;
+                            match PhysicalAddressRange::from_reg(base, len, kind, description) {
+                                Ok(reserved) => {
+                                    hwinfo.add_reserved_memory(reserved);
+                                    if ramoops {
+                                        hwinfo.pstore(reserved);
+                                    }
+                                }
+                                Err(err) => {
+                                    report(DtError::property(&node_path(&range), "reg", err))
+                                }
+                            }
+                        }
+                        (Err(err), _) | (_, Err(err)) => {
+                            report(DtError::property(&node_path(&range), "reg", err))
+                        }
+                    }
                     // Only prop we need or expect to find.
                     break;
                 }
@@ -543,7 +846,7 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
         }
 
         let mut is_ram = false;
-        let mut reg = None;
+        let mut regs = Vec::new();
         for prop in node.props() {
             // let name = node.name().unwrap();
             match prop.name() {
@@ -553,39 +856,228 @@ fn walk_dtb<'a>(tree: DevTree<'a>) -> anyhow::Result<HwInfo> {
                     }
                 }
                 Ok("reg") => {
-                    if let (Ok(base), Ok(len)) = (prop.u64(0), prop.u64(1)) {
-                        reg = Some(PhysicalAddressRange::new(
-                            base..(base + len),
-                            PhysicalAddressKind::Usable,
-                            "",
-                        ));
+                    // A memory node can list more than one bank.
+                    for i in 0..prop.length() / 16 {
+                        if let (Ok(base), Ok(len)) = (prop.u64(2 * i), prop.u64(2 * i + 1)) {
+                            let kind = PhysicalAddressKind::Usable;
+                            match PhysicalAddressRange::from_reg(base, len, kind, "RAM") {
+                                Ok(reg) => regs.push(reg),
+                                Err(err) => {
+                                    report(DtError::property(&node_path(&node), "reg", err))
+                                }
+                            }
+                        }
                     }
                 }
                 Ok("timebase-frequency") => {
-                    match prop.length() {
-                        4 => hwinfo.timebase_freq(prop.u32(0).unwrap() as u64),
-                        8 => hwinfo.timebase_freq(prop.u64(0).unwrap()),
-                        _ => panic!("Unexpected timebase-frequency value: {:?}", prop.raw()),
+                    let freq = match prop.length() {
+                        4 => prop.u32(0).map(u64::from),
+                        _ => prop.u64(0),
+                    };
+                    match freq {
+                        Ok(freq) => hwinfo.timebase_freq(freq),
+                        Err(err) => {
+                            let node = node_path(&node);
+                            return Err(DtError::property(&node, "timebase-frequency", err).into());
+                        }
                     };
                 }
                 _ => {}
             }
         }
 
-        if is_ram && reg.is_some() {
-            let mut reg = reg.unwrap();
-            reg.description = "RAM";
-            hwinfo.add_memory(reg);
+        if is_ram {
+            for reg in regs {
+                hwinfo.add_memory(reg);
+            }
+        }
+    }
+
+    if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+        let kind = PhysicalAddressKind::ReadOnly;
+        match PhysicalAddressRange::from_bounds(start, end, kind, "initrd") {
+            Ok(initrd) => {
+                hwinfo.initrd(initrd);
+            }
+            Err(err) => report(DtError::property("/chosen", "linux,initrd-end", err)),
+        }
+    }
+
+    Ok(hwinfo.build().map_err(|err| DtError::new("", err))?)
+}
+
+/// Note a device left out of [`HwInfo`].
+fn report(err: DtError) {
+    boot::report("device tree", &err.into());
+}
+
+fn parse_clint(
+    node: &fdt_rs::index::DevTreeIndexNode,
+    hwinfo: &HwInfoBuilder,
+) -> Result<Clint, DtError> {
+    let path = node_path(node);
+    let mut clint = ClintBuilder::default();
+    clint.name(node.name()?.into());
+
+    for prop in node.props() {
+        match prop.name()? {
+            "reg" => {
+                let reg = |i| prop.u64(i).map_err(|err| DtError::property(&path, "reg", err));
+                let (base, len) = (reg(0)?, reg(1)?);
+                // OpenSBI protects clint0.
+                let kind = PhysicalAddressKind::Reserved;
+                let reg = PhysicalAddressRange::from_reg(base, len, kind, "clint")
+                    .map_err(|err| DtError::property(&path, "reg", err))?;
+                clint.reg(reg);
+            }
+            "interrupts-extended" => {
+                let contexts = parse_interrupt_extended(prop, hwinfo)
+                    .map_err(|err| DtError::property(&path, "interrupts-extended", err))?;
+                clint.contexts(contexts);
+            }
+
+            _ => {}
+        }
+    }
+    clint.build().map_err(|err| DtError::new(&path, err))
+}
+
+fn parse_rtc(node: &fdt_rs::index::DevTreeIndexNode) -> Result<Rtc, DtError> {
+    let path = node_path(node);
+    let mut rtc = RtcBuilder::default();
+    rtc.name(node.name()?.into());
+
+    for prop in node.props() {
+        match prop.name()? {
+            "interrupts" => {
+                let id = prop
+                    .u32(0)
+                    .map_err(|err| DtError::property(&path, "interrupts", err))?;
+                let id = InterruptId::new(id)
+                    .ok_or_else(|| DtError::new(&path, "interrupt 0 isn't a real interrupt"))?;
+                rtc.interrupt(id);
+            }
+            "interrupt-parent" => {
+                let parent = prop
+                    .phandle(0)
+                    .map_err(|err| DtError::property(&path, "interrupt-parent", err))?;
+                rtc.interrupt_parent(parent);
+            }
+            "reg" => {
+                let reg = |i| prop.u64(i).map_err(|err| DtError::property(&path, "reg", err));
+                let (base, len) = (reg(0)?, reg(1)?);
+                let kind = PhysicalAddressKind::Mmio;
+                let reg = PhysicalAddressRange::from_reg(base, len, kind, "rtc")
+                    .map_err(|err| DtError::property(&path, "reg", err))?;
+                rtc.reg(reg);
+            }
+            _ => {}
+        }
+    }
+    rtc.build().map_err(|err| DtError::new(&path, err))
+}
+
+/// Full path of `node`, like `/soc/serial@10000000`.
+fn node_path(node: &fdt_rs::index::DevTreeIndexNode) -> String {
+    let mut names = Vec::new();
+    let mut name = node.name().unwrap_or("");
+    let mut parent = node.parent();
+    // The root is the one without a parent, and has no name of its own.
+    while let Some(node) = parent {
+        names.push(name);
+        name = node.name().unwrap_or("");
+        parent = node.parent();
+    }
+    if names.is_empty() {
+        return "/".into();
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
+
+/// Copy out a node.
+fn dt_node(node: &fdt_rs::index::DevTreeIndexNode) -> DtNode {
+    let cells = |name| {
+        node.parent()?
+            .props()
+            .find(|prop| prop.name() == Ok(name))?
+            .u32(0)
+            .ok()
+    };
+    DtNode {
+        path: node_path(node),
+        name: node.name().unwrap_or("").into(),
+        address_cells: cells("#address-cells").unwrap_or(devices::DEFAULT_ADDRESS_CELLS),
+        size_cells: cells("#size-cells").unwrap_or(devices::DEFAULT_SIZE_CELLS),
+        props: node
+            .props()
+            .filter_map(|prop| Some((prop.name().ok()?.into(), prop.raw().to_vec())))
+            .collect(),
+    }
+}
+
+/// Whether `path` is the node `wanted` names. Components of `wanted` can leave off the
+/// unit address, as long as that's not ambiguous to whoever wrote it.
+fn path_matches(path: &str, wanted: &str) -> bool {
+    let mut path = path.split('/');
+    let mut wanted = wanted.split('/');
+    loop {
+        match (path.next(), wanted.next()) {
+            (None, None) => return true,
+            (Some(have), Some(want)) => {
+                let have_name = have.split('@').next().unwrap_or(have);
+                if have != want && (want.contains('@') || have_name != want) {
+                    return false;
+                }
+            }
+            _ => return false,
         }
     }
+}
+
+/// `stdout-path` from `/chosen`, or the older `linux,stdout-path`, as a full path.
+fn stdout_path(index: &DevTreeIndex) -> Option<String> {
+    let node_at = |path: &str| index.nodes().find(|node| node_path(node) == path);
+
+    let chosen = node_at("/chosen")?;
+    let value = chosen
+        .props()
+        .find(|prop| prop.name() == Ok("stdout-path"))
+        .or_else(|| {
+            chosen
+                .props()
+                .find(|prop| prop.name() == Ok("linux,stdout-path"))
+        })?
+        .str()
+        .ok()?;
+    // Anything after a ':' is options for the device, like the baud rate.
+    let path = value.split(':').next().unwrap_or(value);
+    if path.starts_with('/') {
+        return Some(path.into());
+    }
 
-    hwinfo.build().map_err(Error::msg)
+    // Otherwise it starts with an alias, maybe with more path after it.
+    let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+    let aliased = node_at("/aliases")?
+        .props()
+        .find(|prop| prop.name() == Ok(alias))?
+        .str()
+        .ok()?;
+    if rest.is_empty() {
+        Some(aliased.into())
+    } else {
+        Some(format!("{}/{}", aliased.trim_end_matches('/'), rest))
+    }
 }
 
 fn parse_interrupt_extended<'a>(
     prop: fdt_rs::index::DevTreeIndexProp,
     hwinfo: &'a HwInfoBuilder,
-) -> Vec<InterruptContext> {
+) -> Result<Vec<InterruptContext>, DevTreeError> {
     let entries = prop.length() / size_of::<Phandle>() / 2;
     let mut result = Vec::new();
 
@@ -593,15 +1085,13 @@ fn parse_interrupt_extended<'a>(
         let phandle_offset = 2 * index as usize;
         let interrupt_cause_offset = phandle_offset + 1;
 
-        let phandle = prop
-            .phandle(phandle_offset)
-            .expect("failed to read phandle");
+        let phandle = prop.phandle(phandle_offset)?;
 
-        if let Ok(cause) = InterruptCause::try_from(prop.u32(interrupt_cause_offset).unwrap()) {
+        if let Ok(cause) = InterruptCause::try_from(prop.u32(interrupt_cause_offset)?) {
             if let Some(hart) = hwinfo
                 .harts
-                .as_ref()
-                .unwrap()
+                .as_deref()
+                .unwrap_or_default()
                 .iter()
                 .find(|h| h.interrupt_handle == phandle)
             {
@@ -614,7 +1104,12 @@ fn parse_interrupt_extended<'a>(
             }
         }
     }
-    result
+    Ok(result)
+}
+
+/// Where a range of the kernel image is. It's linked in the upper half.
+fn phys(range: Range<u64>) -> Range<PhysAddr> {
+    virt_to_phys(VirtAddr::new(range.start))..virt_to_phys(VirtAddr::new(range.end))
 }
 
 pub struct MemoryLayout {
@@ -631,33 +1126,51 @@ impl HwInfo {
     pub fn memory_layout(&self) -> Vec<PhysicalAddressRange> {
         let mut layout = vec![];
         layout.push(PhysicalAddressRange::new(
-            text(),
+            phys(text()),
             PhysicalAddressKind::Executable,
             ".text",
         ));
         layout.push(PhysicalAddressRange::new(
-            rodata(),
+            phys(rodata()),
             PhysicalAddressKind::ReadOnly,
             ".rodata",
         ));
         layout.push(PhysicalAddressRange::new(
-            data(),
+            phys(data()),
             PhysicalAddressKind::Writable,
             ".data",
         ));
         layout.push(PhysicalAddressRange::new(
-            bss(),
+            phys(bss()),
             PhysicalAddressKind::Writable,
             ".bss",
         ));
-        layout.push(self.uart.reg.clone());
+        for uart in &self.uarts {
+            layout.push(uart.reg.clone());
+        }
         layout.push(self.plic.reg.clone());
-        layout.push(self.rtc.reg.clone());
+        if let Some(rtc) = &self.rtc {
+            layout.push(rtc.reg.clone());
+        }
+        for node in self.nodes.iter() {
+            if let (Some(driver), Some(reg)) = (devices::driver_for(node), node.reg(0)) {
+                layout.push(PhysicalAddressRange::new(
+                    reg,
+                    PhysicalAddressKind::Mmio,
+                    driver.name,
+                ));
+            }
+        }
         for rm in self.reserved_memory.iter() {
             layout.push(rm.clone());
         }
+        if let Some(initrd) = self.initrd {
+            if !basic_allocator::initrd_released() {
+                layout.push(initrd);
+            }
+        }
 
-        layout.push(basic_allocator::heap_range());
+        layout.extend(basic_allocator::heap_ranges());
         // layout.push(self.tree_range);
         /*
         let spare_start = if self.tree_range.end % 4096 == 0 {
@@ -672,10 +1185,41 @@ impl HwInfo {
             "spare",
         ));
 */
+        // Firmware doesn't always leave these apart. memmap::init warns about it.
         layout.sort_by_key(|range| range.start);
-        for r in layout.windows(2) {
-            assert!(r[0].end <= r[1].start, "{} does not finish before {}", r[0].description, r[1].description);
-        }
         layout
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::addr::PHYS_BITS;
+
+    #[test_case]
+    fn isa_extensions() {
+        let isa = "rv64imafdch_zicsr_zifencei_svpbmt";
+        assert!(isa_has_extension(isa, "c"));
+        assert!(isa_has_extension(isa, "H"));
+        assert!(!isa_has_extension(isa, "v"));
+        assert!(isa_has_extension(isa, "svpbmt"));
+        assert!(!isa_has_extension(isa, "svnapot"));
+        assert!(!isa_has_extension("rv64imac", "svpbmt"));
+    }
+
+    #[test_case]
+    fn out_of_range_regs() {
+        let reg = |base, len| {
+            PhysicalAddressRange::from_reg(base, len, PhysicalAddressKind::Mmio, "test")
+                .map_err(|err| DtError::property("/soc/test@0", "reg", err))
+        };
+        assert_eq!(reg(0x1000_0000, 0x100).unwrap().size(), 0x100);
+        assert!(reg(u64::MAX, 0x100).is_err());
+        assert!(reg(1 << PHYS_BITS, 0x1000).is_err());
+        let err = reg((1 << PHYS_BITS) - 0x1000, 0x2000).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "/soc/test@0: bad `reg`: not a physical address range"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! What a hart does when there's nothing to do.
+//!
+//! [`idle`] picks how deeply to sleep from how long it is until the hart's timer goes off:
+//! `wfi` for short waits, a retentive HSM suspend for longer ones, and for the longest a
+//! non-retentive suspend, which loses the hart's state and has to put it back. Any other
+//! interrupt can still end it early. A state the SBI implementation turns down isn't tried
+//! again.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    cpustat, hart_local,
+    isr::without_interrupts,
+    log,
+    sbi::{
+        hart::{hsm_extension, NonRetentiveSuspendType, RetentiveSuspendType, SuspendContext},
+        susp::{system_suspend_extension, SleepType},
+        SbiError, SbiResult,
+    },
+    smp::MAX_HARTS,
+    time::{timer, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleState {
+    Wfi,
+    Retentive,
+    NonRetentive,
+}
+
+impl IdleState {
+    pub const ALL: [IdleState; 3] = [
+        IdleState::Wfi,
+        IdleState::Retentive,
+        IdleState::NonRetentive,
+    ];
+
+    /// Shortest expected idle time it's worth going this deep for.
+    fn min_residency(self) -> Duration {
+        match self {
+            IdleState::Wfi => Duration::ZERO,
+            IdleState::Retentive => Duration::from_millis(1),
+            IdleState::NonRetentive => Duration::from_millis(50),
+        }
+    }
+}
+
+/// States that failed, by [`IdleState`] index.
+static DISABLED: [AtomicBool; 3] = [const { AtomicBool::new(false) }; 3];
+/// Times each state was entered.
+static ENTERED: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+/// Where each hart saves itself for a non-retentive suspend. In the kernel image, so
+/// [`suspend_resume`](crate::asm::suspend_resume) can find it with paging off.
+struct Contexts([UnsafeCell<SuspendContext>; MAX_HARTS]);
+
+unsafe impl Sync for Contexts {}
+
+static CONTEXTS: Contexts = Contexts([const { UnsafeCell::new(SuspendContext::new()) }; MAX_HARTS]);
+
+/// Sleep until an interrupt is pending.
+///
+/// Call with interrupts off, after checking whatever you're waiting for, so an interrupt
+/// in between still wakes us. [`isr::wait_until`](crate::isr::wait_until) does that.
+pub fn idle() {
+    let predicted = timer::next_deadline()
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .unwrap_or(Duration::MAX);
+    let state = choose(predicted);
+    ENTERED[state as usize].fetch_add(1, Ordering::Relaxed);
+    let start = Instant::now();
+    let entered = enter(state);
+    cpustat::add_idle(start.elapsed());
+    if let Err(err) = entered {
+        log::info!("idle: not using {:?} again: {}", state, err);
+        DISABLED[state as usize].store(true, Ordering::Relaxed);
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
+/// The deepest state that's still working and worth it for `predicted`.
+fn choose(predicted: Duration) -> IdleState {
+    IdleState::ALL
+        .into_iter()
+        .rev()
+        .find(|&state| {
+            !DISABLED[state as usize].load(Ordering::Relaxed) && predicted >= state.min_residency()
+        })
+        .unwrap_or(IdleState::Wfi)
+}
+
+fn enter(state: IdleState) -> SbiResult<()> {
+    match state {
+        IdleState::Wfi => {
+            unsafe { riscv::asm::wfi() };
+            Ok(())
+        }
+        IdleState::Retentive => {
+            hsm_extension().hart_retentive_suspend(RetentiveSuspendType::DEFAULT_RETENTIVE_SUSPEND)
+        }
+        IdleState::NonRetentive => unsafe {
+            hsm_extension().hart_non_retentive_suspend(
+                NonRetentiveSuspendType::DEFAULT_NON_RETENTIVE_SUSPEND,
+                this_context(),
+            )
+        },
+    }
+}
+
+fn this_context() -> *mut SuspendContext {
+    let hart = hart_local::try_current_hart().map_or(0, |hart| hart.0);
+    assert!(hart < MAX_HARTS, "no suspend context for hart {}", hart);
+    CONTEXTS.0[hart].get()
+}
+
+/// Times each state has been entered.
+pub fn stats() -> [(IdleState, usize); 3] {
+    IdleState::ALL.map(|state| (state, ENTERED[state as usize].load(Ordering::Relaxed)))
+}
+
+#[derive(Debug)]
+pub enum SuspendError {
+    NoExtension,
+    Sbi(SbiError),
+}
+
+impl Display for SuspendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SuspendError::NoExtension => f.write_str("no system suspend extension"),
+            SuspendError::Sbi(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl core::error::Error for SuspendError {}
+
+/// Suspend the machine to RAM, until something wakes it. The other harts have to be
+/// stopped first.
+pub fn suspend_system() -> Result<(), SuspendError> {
+    let susp = system_suspend_extension().ok_or(SuspendError::NoExtension)?;
+    without_interrupts(|| unsafe { susp.system_suspend(SleepType::SUSPEND_TO_RAM, this_context()) })
+        .map_err(SuspendError::Sbi)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn choose_by_residency() {
+        let enabled = |state: IdleState| !DISABLED[state as usize].load(Ordering::Relaxed);
+        assert_eq!(choose(Duration::from_micros(10)), IdleState::Wfi);
+        if enabled(IdleState::Retentive) {
+            assert_eq!(choose(Duration::from_millis(5)), IdleState::Retentive);
+        }
+        if enabled(IdleState::NonRetentive) {
+            assert_eq!(choose(Duration::MAX), IdleState::NonRetentive);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! What keys type, for a US keyboard.
+//!
+//! Keys are evdev key codes. Most type one byte; the arrows and a few others type the
+//! escape sequence a VT100 would send, and Alt puts an `ESC` in front.
+
+use super::Modifiers;
+
+/// The most [`translate`] ever writes.
+pub const MAX_TYPED: usize = 5;
+
+pub const KEY_ESC: u16 = 1;
+pub const KEY_A: u16 = 30;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_KPENTER: u16 = 96;
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_DELETE: u16 = 111;
+
+/// What each key up to [`KEY_SPACE`] types, by code. 0 types nothing.
+const PLAIN: &[u8; 58] = b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0\
+    asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 58] = b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0\
+    ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// The modifier `code` is, if it's one.
+pub fn modifier(code: u16) -> Option<Modifiers> {
+    match code {
+        KEY_LEFTSHIFT | KEY_RIGHTSHIFT => Some(Modifiers::SHIFT),
+        KEY_LEFTCTRL | KEY_RIGHTCTRL => Some(Modifiers::CTRL),
+        KEY_LEFTALT | KEY_RIGHTALT => Some(Modifiers::ALT),
+        KEY_CAPSLOCK => Some(Modifiers::CAPS_LOCK),
+        _ => None,
+    }
+}
+
+/// What pressing `code` with `modifiers` held types, written to `out`. Empty for keys
+/// that don't type anything.
+pub fn translate(code: u16, modifiers: Modifiers, out: &mut [u8; MAX_TYPED]) -> &[u8] {
+    let mut single = [0];
+    let sequence: &[u8] = match code {
+        KEY_UP => b"\x1b[A",
+        KEY_DOWN => b"\x1b[B",
+        KEY_RIGHT => b"\x1b[C",
+        KEY_LEFT => b"\x1b[D",
+        KEY_HOME => b"\x1b[H",
+        KEY_END => b"\x1b[F",
+        KEY_DELETE => b"\x1b[3~",
+        KEY_KPENTER => b"\r",
+        _ => {
+            let table = match modifiers.contains(Modifiers::SHIFT) {
+                true => SHIFTED,
+                false => PLAIN,
+            };
+            let mut byte = table.get(code as usize).copied().unwrap_or(0);
+            if byte.is_ascii_alphabetic() && modifiers.contains(Modifiers::CAPS_LOCK) {
+                byte ^= 0x20;
+            }
+            if modifiers.contains(Modifiers::CTRL)
+                && (b'@'..=b'_').contains(&byte.to_ascii_uppercase())
+            {
+                byte = byte.to_ascii_uppercase() & 0x1f;
+            }
+            single[0] = byte;
+            match byte {
+                0 => b"",
+                _ => &single,
+            }
+        }
+    };
+    let alt = modifiers.contains(Modifiers::ALT) && !sequence.is_empty();
+    let start = alt as usize;
+    out[0] = 0x1b;
+    out[start..start + sequence.len()].copy_from_slice(sequence);
+    &out[..start + sequence.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn us_keymap() {
+        let mut out = [0; MAX_TYPED];
+        assert_eq!(translate(KEY_A, Modifiers::empty(), &mut out), b"a");
+        assert_eq!(translate(KEY_A, Modifiers::SHIFT, &mut out), b"A");
+        assert_eq!(
+            translate(KEY_A, Modifiers::SHIFT | Modifiers::CAPS_LOCK, &mut out),
+            b"a"
+        );
+        assert_eq!(translate(2, Modifiers::CAPS_LOCK, &mut out), b"1");
+        assert_eq!(translate(46, Modifiers::CTRL, &mut out), b"\x03");
+        assert_eq!(translate(KEY_UP, Modifiers::ALT, &mut out), b"\x1b\x1b[A");
+        assert_eq!(translate(KEY_LEFTSHIFT, Modifiers::ALT, &mut out), b"");
+        assert_eq!(translate(KEY_SPACE, Modifiers::empty(), &mut out), b" ");
+    }
+}
@@ -0,0 +1,219 @@
+//! Keyboards and mice.
+//!
+//! Drivers [`report`] events the way evdev has them: a type, a code and a value, which is
+//! also what virtio-input sends. They come out of [`next_event`] as [`InputEvent`]s, keys
+//! with the modifiers held at the time, and mouse motion added up until the device says
+//! the report is over. Key presses that type something are also given to the console's
+//! input through [`keymap`], so the shell and user programs read the keyboard like the
+//! UART.
+//!
+//! The queue keeps [`MAX_EVENTS`]. With nobody reading, the oldest go.
+
+pub mod keymap;
+
+use alloc::collections::VecDeque;
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use crate::{console, sync::IrqSafeMutex};
+
+pub const MAX_EVENTS: usize = 256;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+static STATE: IrqSafeMutex<State> = IrqSafeMutex::new(State::new());
+
+bitflags::bitflags! {
+    pub struct Modifiers: u8 {
+        const SHIFT = 1 << 0;
+        const CTRL = 1 << 1;
+        const ALT = 1 << 2;
+        const CAPS_LOCK = 1 << 3;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    /// Held down long enough to repeat.
+    Repeated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// `code` is an evdev `KEY_*` or `BTN_*`.
+    Key {
+        code: u16,
+        state: KeyState,
+        modifiers: Modifiers,
+    },
+    /// How far a mouse moved since the last one.
+    Motion { dx: i32, dy: i32, wheel: i32 },
+    /// Where a tablet is. `axis` is an evdev `ABS_*`.
+    Absolute { axis: u16, value: i32 },
+}
+
+struct State {
+    modifiers: Modifiers,
+    /// Relative motion since the last `SYN_REPORT`, as (x, y, wheel).
+    motion: (i32, i32, i32),
+    events: VecDeque<InputEvent>,
+    waker: Option<Waker>,
+}
+
+impl State {
+    const fn new() -> State {
+        State {
+            modifiers: Modifiers::empty(),
+            motion: (0, 0, 0),
+            events: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    /// Turn an evdev event into one of ours, if it makes one yet.
+    fn translate(&mut self, kind: u16, code: u16, value: u32) -> Option<InputEvent> {
+        match kind {
+            EV_KEY => {
+                let state = match value {
+                    0 => KeyState::Released,
+                    2 => KeyState::Repeated,
+                    _ => KeyState::Pressed,
+                };
+                let pressed = state != KeyState::Released;
+                match keymap::modifier(code) {
+                    Some(Modifiers::CAPS_LOCK) if state == KeyState::Pressed => {
+                        self.modifiers.toggle(Modifiers::CAPS_LOCK)
+                    }
+                    Some(Modifiers::CAPS_LOCK) => {}
+                    Some(modifier) => self.modifiers.set(modifier, pressed),
+                    None => {}
+                }
+                Some(InputEvent::Key {
+                    code,
+                    state,
+                    modifiers: self.modifiers,
+                })
+            }
+            EV_REL => {
+                let value = value as i32;
+                match code {
+                    REL_X => self.motion.0 += value,
+                    REL_Y => self.motion.1 += value,
+                    REL_WHEEL => self.motion.2 += value,
+                    _ => {}
+                }
+                None
+            }
+            EV_ABS => Some(InputEvent::Absolute {
+                axis: code,
+                value: value as i32,
+            }),
+            EV_SYN if code == SYN_REPORT && self.motion != (0, 0, 0) => {
+                let (dx, dy, wheel) = core::mem::take(&mut self.motion);
+                Some(InputEvent::Motion { dx, dy, wheel })
+            }
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// An event from a driver. Doesn't block, so it can be called from an interrupt handler.
+pub fn report(kind: u16, code: u16, value: u32) {
+    let mut state = STATE.lock();
+    let Some(event) = state.translate(kind, code, value) else {
+        return;
+    };
+    state.push(event);
+    drop(state);
+
+    if let InputEvent::Key {
+        code,
+        state: KeyState::Pressed | KeyState::Repeated,
+        modifiers,
+    } = event
+    {
+        let mut typed = [0; keymap::MAX_TYPED];
+        console::push_input(keymap::translate(code, modifiers, &mut typed));
+    }
+}
+
+pub fn try_next_event() -> Option<InputEvent> {
+    STATE.lock().events.pop_front()
+}
+
+/// Wait for the next event.
+pub async fn next_event() -> InputEvent {
+    poll_fn(|cx| {
+        let mut state = STATE.lock();
+        match state.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn evdev_translation() {
+        let mut state = State::new();
+        assert!(state.translate(EV_KEY, keymap::KEY_LEFTSHIFT, 1).is_some());
+        assert_eq!(
+            state.translate(EV_KEY, keymap::KEY_A, 1),
+            Some(InputEvent::Key {
+                code: keymap::KEY_A,
+                state: KeyState::Pressed,
+                modifiers: Modifiers::SHIFT,
+            })
+        );
+        state.translate(EV_KEY, keymap::KEY_LEFTSHIFT, 0);
+        state.translate(EV_KEY, keymap::KEY_CAPSLOCK, 1);
+        state.translate(EV_KEY, keymap::KEY_CAPSLOCK, 0);
+        assert_eq!(state.modifiers, Modifiers::CAPS_LOCK);
+
+        assert_eq!(state.translate(EV_REL, REL_X, 3), None);
+        assert_eq!(state.translate(EV_REL, REL_Y, -2i32 as u32), None);
+        assert_eq!(
+            state.translate(EV_SYN, SYN_REPORT, 0),
+            Some(InputEvent::Motion {
+                dx: 3,
+                dy: -2,
+                wheel: 0
+            })
+        );
+        assert_eq!(state.translate(EV_SYN, SYN_REPORT, 0), None);
+    }
+}
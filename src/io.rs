@@ -1,44 +1,111 @@
+//! A cut down `std::io`: [`Read`], [`Write`] and [`Seek`], with [`BufReader`],
+//! [`BufWriter`] and [`Cursor`] to put on top of them.
+//!
+//! [`read_vectored`](Read::read_vectored) and [`write_vectored`](Write::write_vectored)
+//! move several buffers in one call, for things where each call costs, like a `writev`.
+//! The defaults only do the first non-empty buffer.
+
+use core::{
+    cmp,
+    fmt::{self, Display, Formatter},
+    mem::{self, ManuallyDrop},
+    ops::{Deref, DerefMut},
+    ptr, str,
+};
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// What [`BufReader`] and [`BufWriter`] buffer if not told otherwise.
+pub const DEFAULT_BUF_SIZE: usize = 1024;
+
 pub trait Read {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
 
-    // TODO: read_to_end requires Vec
+    /// Read into `bufs` in order, filling each before the next.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        let buf = bufs.iter_mut().find(|buf| !buf.is_empty());
+        self.read(buf.map_or(&mut [], |buf| &mut **buf))
+    }
+
+    /// Read until the end, appending to `buf`. Returns how many bytes were read.
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        default_read_to_end(self, buf)
+    }
 
-    // TODO: read_to_string requires String
+    /// Read until the end, appending to `buf`. Fails with [`ErrorKind::InvalidData`],
+    /// leaving `buf` as it was, if it isn't UTF-8.
+    fn read_to_string(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.read_to_end(&mut bytes)?;
+        let text = String::from_utf8(bytes).map_err(|_| {
+            Error::new_const(ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })?;
+        buf.push_str(&text);
+        Ok(n)
+    }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
-        default_read_exact(self, buf)        
+        default_read_exact(self, buf)
     }
 
-    fn by_ref(&mut self) -> &mut Self 
+    fn by_ref(&mut self) -> &mut Self
     where
-        Self: Sized
+        Self: Sized,
     {
         self
     }
 
     fn bytes(self) -> Bytes<Self>
     where
-        Self: Sized
+        Self: Sized,
     {
         Bytes { inner: self }
     }
 
     fn chain<R: Read>(self, next: R) -> Chain<Self, R>
     where
-        Self: Sized
+        Self: Sized,
     {
-        Chain { first: self, second: next, done_first: false }
+        Chain {
+            first: self,
+            second: next,
+            done_first: false,
+        }
     }
 
     fn take(self, limit: u64) -> Take<Self>
     where
-        Self: Sized
+        Self: Sized,
     {
-        Take { inner: self, limit, amount: 0 }
+        Take {
+            inner: self,
+            limit,
+            amount: 0,
+        }
+    }
+}
+
+fn default_read_to_end<R: Read + ?Sized>(this: &mut R, buf: &mut Vec<u8>) -> Result<usize> {
+    let start = buf.len();
+    let mut len = start;
+    loop {
+        if len == buf.len() {
+            buf.resize(cmp::max(len * 2, len + 32), 0);
+        }
+        match this.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                buf.truncate(len);
+                return Err(e);
+            }
+        }
     }
+    buf.truncate(len);
+    Ok(len - start)
 }
 
 fn default_read_exact<R: Read + ?Sized>(this: &mut R, mut buf: &mut [u8]) -> Result<()> {
@@ -54,12 +121,352 @@ fn default_read_exact<R: Read + ?Sized>(this: &mut R, mut buf: &mut [u8]) -> Res
         }
     }
     if !buf.is_empty() {
-        Err(Error::new_const(ErrorKind::UnexpectedEof, &"failed to fill whole buffer"))
+        Err(Error::new_const(
+            ErrorKind::UnexpectedEof,
+            "failed to fill whole buffer",
+        ))
     } else {
         Ok(())
     }
 }
 
+pub trait Write {
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    /// Write `bufs` in order, as if they were one.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let buf = bufs.iter().find(|buf| !buf.is_empty());
+        self.write(buf.map_or(&[], |buf| &**buf))
+    }
+
+    /// Push out anything buffered.
+    fn flush(&mut self) -> Result<()>;
+
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => {
+                    return Err(Error::new_const(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => buf = &buf[n..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_vectored(&mut self, mut bufs: &mut [IoSlice<'_>]) -> Result<()> {
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            match self.write_vectored(bufs) {
+                Ok(0) => {
+                    return Err(Error::new_const(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// For `write!`. Returns the first error from underneath.
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<()> {
+        struct Adapter<'a, W: ?Sized> {
+            inner: &'a mut W,
+            error: Result<()>,
+        }
+
+        impl<W: Write + ?Sized> fmt::Write for Adapter<'_, W> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.inner.write_all(s.as_bytes()).map_err(|e| {
+                    self.error = Err(e);
+                    fmt::Error
+                })
+            }
+        }
+
+        let mut adapter = Adapter {
+            inner: self,
+            error: Ok(()),
+        };
+        match fmt::write(&mut adapter, args) {
+            Ok(()) => Ok(()),
+            Err(_) => match adapter.error {
+                Err(e) => Err(e),
+                Ok(()) => Err(Error::new_const(
+                    ErrorKind::Uncategorized,
+                    "formatter error",
+                )),
+            },
+        }
+    }
+
+    fn by_ref(&mut self) -> &mut Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+pub trait Seek {
+    /// Returns the new position from the start.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+    fn rewind(&mut self) -> Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+/// [`Read`] with a buffer to look into, so it can be read a line at a time.
+pub trait BufRead: Read {
+    /// What's buffered, reading more first if it's empty. Empty at the end.
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+
+    /// Mark `amount` bytes of [`fill_buf`](Self::fill_buf) as read.
+    fn consume(&mut self, amount: usize);
+
+    /// Read up to and including `byte`, or to the end, appending to `buf`.
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+        let mut read = 0;
+        loop {
+            let (done, used) = {
+                let available = match self.fill_buf() {
+                    Ok(available) => available,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match available.iter().position(|&b| b == byte) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            read += used;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Read a line, with its `\n`, appending to `buf`. Returns 0 at the end.
+    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.read_until(b'\n', &mut bytes)?;
+        let line = str::from_utf8(&bytes).map_err(|_| {
+            Error::new_const(ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })?;
+        buf.push_str(line);
+        Ok(n)
+    }
+}
+
+/// Read everything from `reader` and write it to `writer`, a buffer at a time. Returns
+/// how many bytes went across.
+pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buf = [0; DEFAULT_BUF_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// One buffer for [`Write::write_vectored`].
+#[derive(Clone, Copy, Debug)]
+pub struct IoSlice<'a>(&'a [u8]);
+
+impl<'a> IoSlice<'a> {
+    pub const fn new(buf: &'a [u8]) -> Self {
+        IoSlice(buf)
+    }
+
+    /// Drop the first `n` bytes of `bufs`, and any buffers left empty at the front.
+    pub fn advance_slices(bufs: &mut &mut [IoSlice<'a>], n: usize) {
+        let mut left = n;
+        let mut done = 0;
+        for buf in bufs.iter() {
+            if buf.len() > left {
+                break;
+            }
+            left -= buf.len();
+            done += 1;
+        }
+        *bufs = &mut mem::take(bufs)[done..];
+        match bufs.first_mut() {
+            Some(first) => first.0 = &first.0[left..],
+            None => assert_eq!(left, 0, "advancing past the end of the slices"),
+        }
+    }
+}
+
+impl Deref for IoSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// One buffer for [`Read::read_vectored`].
+#[derive(Debug)]
+pub struct IoSliceMut<'a>(&'a mut [u8]);
+
+impl<'a> IoSliceMut<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        IoSliceMut(buf)
+    }
+
+    /// Drop the first `n` bytes of `bufs`, and any buffers left empty at the front.
+    pub fn advance_slices(bufs: &mut &mut [IoSliceMut<'a>], n: usize) {
+        let mut left = n;
+        let mut done = 0;
+        for buf in bufs.iter() {
+            if buf.len() > left {
+                break;
+            }
+            left -= buf.len();
+            done += 1;
+        }
+        *bufs = &mut mem::take(bufs)[done..];
+        match bufs.first_mut() {
+            Some(first) => first.0 = &mut mem::take(&mut first.0)[left..],
+            None => assert_eq!(left, 0, "advancing past the end of the slices"),
+        }
+    }
+}
+
+impl Deref for IoSliceMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl DerefMut for IoSliceMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+    }
+}
+
+impl<R: Read + ?Sized> Read for &mut R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        (**self).read_vectored(bufs)
+    }
+}
+
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        (**self).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<S: Seek + ?Sized> Seek for &mut S {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        (**self).seek(pos)
+    }
+}
+
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = cmp::min(buf.len(), self.len());
+        let (head, tail) = self.split_at(n);
+        buf[..n].copy_from_slice(head);
+        *self = tail;
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        let mut n = 0;
+        for buf in bufs {
+            if self.is_empty() {
+                break;
+            }
+            n += self.read(buf)?;
+        }
+        Ok(n)
+    }
+}
+
+impl BufRead for &[u8] {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(self)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        *self = &self[amount..];
+    }
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.reserve(len);
+        for buf in bufs {
+            self.extend_from_slice(buf);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
     kind: ErrorKind,
@@ -67,18 +474,41 @@ pub struct Error {
 }
 
 impl Error {
-    pub const fn kind(&self) -> ErrorKind { self.kind }
+    pub const fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 
     pub const fn new_const(kind: ErrorKind, message: &'static str) -> Self {
         Self { kind, message }
     }
+
+    pub const fn message(&self) -> &'static str {
+        self.message
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.kind)
+        } else {
+            f.write_str(self.message)
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new_const(kind, "")
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[non_exhaustive]
 #[allow(dead_code)]
 pub enum ErrorKind {
-
     /// An entity was not found, often a file.
     NotFound,
     /// The operation lacked the necessary privileges to complete.
@@ -256,12 +686,11 @@ pub enum ErrorKind {
     Uncategorized,
 }
 
-
-pub struct Bytes<R: Read+Sized> {
+pub struct Bytes<R: Read + Sized> {
     inner: R,
 }
 
-impl<R: Read+Sized> Iterator for Bytes<R> {
+impl<R: Read + Sized> Iterator for Bytes<R> {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -273,21 +702,29 @@ impl<R: Read+Sized> Iterator for Bytes<R> {
     }
 }
 
-pub struct Chain<A: Read+Sized, B: Read+Sized> {
+pub struct Chain<A: Read + Sized, B: Read + Sized> {
     first: A,
     second: B,
     done_first: bool,
 }
 
-impl<A,B> Read for Chain<A, B> 
-    where A: Read+Sized, B: Read+Sized
+impl<A, B> Read for Chain<A, B>
+where
+    A: Read + Sized,
+    B: Read + Sized,
 {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         if !self.done_first {
             match self.first.read(buf) {
-                Ok(0) => { self.done_first = true; }
-                Ok(n) => { return Ok(n); }
-                Err(err) => { return Err(err); }
+                Ok(0) => {
+                    self.done_first = true;
+                }
+                Ok(n) => {
+                    return Ok(n);
+                }
+                Err(err) => {
+                    return Err(err);
+                }
             }
         }
         match self.second.read(buf) {
@@ -297,40 +734,481 @@ impl<A,B> Read for Chain<A, B>
     }
 }
 
-pub struct Take<R: Read+Sized> { 
+pub struct Take<R: Read + Sized> {
     inner: R,
     limit: u64,
     amount: u64,
 }
 
-impl<R: Read+Sized> Take<R> {
+impl<R: Read + Sized> Take<R> {
     pub fn amount_remaining(&self) -> u64 {
         self.limit - self.amount
     }
 }
 
-
-impl<R: Read+Sized> Read for Take<R> {
+impl<R: Read + Sized> Read for Take<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let reaming: usize = match self.amount_remaining().try_into() {
+        let remaining: usize = match self.amount_remaining().try_into() {
             Ok(n) => n,
-            _ => usize::MAX
-        };
-        
-        
-        let buf_len = buf.len();
-        let b = if reaming > buf.len() {
-            buf
-        } else {
-            &mut buf[..buf_len]
+            _ => usize::MAX,
         };
 
-        match self.inner.read(b) {
+        let len = cmp::min(remaining, buf.len());
+        match self.inner.read(&mut buf[..len]) {
             Ok(n) => {
                 self.amount += n as u64;
                 Ok(n)
-            },
-            Err(err) => Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Reads from `R` a buffer at a time, for readers where every [`read`](Read::read) is
+/// expensive, like a file.
+pub struct BufReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    /// `buf[pos..filled]` hasn't been read yet.
+    pos: usize,
+    filled: usize,
+}
+
+impl<R: Read> BufReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        BufReader {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl<R> BufReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Reading from it directly skips whatever is buffered.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Anything buffered is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// What's been read from `R` but not from here.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn discard_buffer(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Nothing to gain from copying a big read through the buffer.
+        if self.pos == self.filled && buf.len() >= self.buf.len() {
+            self.discard_buffer();
+            return self.inner.read(buf);
+        }
+        let n = {
+            let available = self.fill_buf()?;
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if self.pos == self.filled && len >= self.buf.len() {
+            self.discard_buffer();
+            return self.inner.read_vectored(bufs);
+        }
+        let n = self.fill_buf()?.read_vectored(bufs)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.pos >= self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(self.buffer())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos = cmp::min(self.pos + amount, self.filled);
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReader<R> {
+    /// Drops the buffer. [`SeekFrom::Current`] is from where this has read to, not `R`.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let result = match pos {
+            SeekFrom::Current(n) => {
+                let buffered = (self.filled - self.pos) as i64;
+                match n.checked_sub(buffered) {
+                    Some(n) => self.inner.seek(SeekFrom::Current(n))?,
+                    None => {
+                        self.inner.seek(SeekFrom::Current(-buffered))?;
+                        self.inner.seek(SeekFrom::Current(n))?
+                    }
+                }
+            }
+            pos => self.inner.seek(pos)?,
+        };
+        self.discard_buffer();
+        Ok(result)
+    }
+}
+
+/// Collects small writes into one big one to `W`. Flushed when dropped, with any error
+/// lost, so [`flush`](Write::flush) first if it matters.
+pub struct BufWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BufWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W) -> Self {
+        BufWriter {
+            inner,
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Writing to it directly goes ahead of whatever is buffered.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Flush and hand back `W`. If the flush fails, `W` is dropped along with the rest.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush_buf()?;
+        let this = ManuallyDrop::new(self);
+        // `this` is never used or dropped again, so each field is read out once.
+        let (inner, buf) = unsafe { (ptr::read(&this.inner), ptr::read(&this.buf)) };
+        drop(buf);
+        Ok(inner)
+    }
+
+    /// Written but not passed on yet.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    fn flush_buf(&mut self) -> Result<()> {
+        let mut written = 0;
+        let mut result = Ok(());
+        while written < self.buf.len() {
+            match self.inner.write(&self.buf[written..]) {
+                Ok(0) => {
+                    result = Err(Error::new_const(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..written);
+        result
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            self.inner.write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Buffers the lot if it fits, or passes it straight on as one call if it's as big as
+    /// the buffer.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if self.buf.len() + len > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if len >= self.buf.capacity() {
+            self.inner.write_vectored(bufs)
+        } else {
+            self.buf.extend(bufs.iter().flat_map(|buf| buf.iter()));
+            Ok(len)
         }
     }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriter<W> {
+    /// Flushes first.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.flush_buf()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+/// Reads, writes and seeks in memory: a `&[u8]` or `Vec<u8>` with a position.
+#[derive(Debug, Default, Clone)]
+pub struct Cursor<T> {
+    inner: T,
+    pos: u64,
+}
+
+impl<T> Cursor<T> {
+    pub const fn new(inner: T) -> Self {
+        Cursor { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub const fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub const fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Past the end is fine. Reads there return nothing, and writes to a `Vec` fill the
+    /// gap with zeros.
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+}
+
+impl<T: AsRef<[u8]>> Cursor<T> {
+    /// From the position to the end.
+    pub fn remaining_slice(&self) -> &[u8] {
+        let data = self.inner.as_ref();
+        let start = cmp::min(self.pos, data.len() as u64) as usize;
+        &data[start..]
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for Cursor<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = Read::read(&mut self.remaining_slice(), buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> Result<usize> {
+        let n = self.remaining_slice().read_vectored(bufs)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: AsRef<[u8]>> BufRead for Cursor<T> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(self.remaining_slice())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos += amount as u64;
+    }
+}
+
+impl<T: AsRef<[u8]>> Seek for Cursor<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.inner.as_ref().len() as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(Error::new_const(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Write for Cursor<&mut [u8]> {
+    /// Short at the end of the slice.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let start = cmp::min(self.pos, self.inner.len() as u64) as usize;
+        let space = &mut self.inner[start..];
+        let n = cmp::min(space.len(), buf.len());
+        space[..n].copy_from_slice(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Cursor<Vec<u8>> {
+    /// Overwrites from the position, growing the `Vec` as needed.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let pos = usize::try_from(self.pos)
+            .map_err(|_| Error::new_const(ErrorKind::FileTooLarge, "cursor position too big"))?;
+        let vec = &mut self.inner;
+        if vec.len() < pos {
+            vec.resize(pos, 0);
+        }
+        let overlap = cmp::min(vec.len() - pos, buf.len());
+        vec[pos..pos + overlap].copy_from_slice(&buf[..overlap]);
+        vec.extend_from_slice(&buf[overlap..]);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn buffered_io() {
+        let mut cursor = Cursor::new(&b"first line\nsecond\nlast"[..]);
+        let mut byte = [0; 1];
+        cursor.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"f");
+        assert_eq!(cursor.seek(SeekFrom::End(-4)).unwrap(), 18);
+        assert!(cursor.seek(SeekFrom::Current(-100)).is_err());
+        cursor.rewind().unwrap();
+
+        // Lines that straddle the buffer boundary.
+        let mut reader = BufReader::with_capacity(4, cursor);
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 11);
+        assert_eq!(line, "first line\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "second\n");
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "last");
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+
+        let mut writer = BufWriter::with_capacity(8, Cursor::new(Vec::new()));
+        write!(writer, "{}-abc", 12).unwrap();
+        assert_eq!(writer.get_ref().get_ref().len(), 0);
+        writer.write_all(b" and more than a buffer").unwrap();
+        let mut cursor = writer.into_inner().unwrap();
+        assert_eq!(cursor.get_ref(), b"12-abc and more than a buffer");
+        cursor.set_position(3);
+        cursor.write_all(b"xyz").unwrap();
+        assert_eq!(&cursor.get_ref()[..8], b"12-xyz a");
+
+        let mut out = Vec::new();
+        let n = (&b"0123456789"[..]).take(4).read_to_end(&mut out).unwrap();
+        assert_eq!((n, &out[..]), (4, &b"0123"[..]));
+    }
+
+    #[test_case]
+    fn vectored_io() {
+        let (mut head, mut tail) = ([0; 3], [0; 8]);
+        let mut bufs = [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)];
+        let mut reader = BufReader::with_capacity(4, &b"abcdefg"[..]);
+        assert_eq!(reader.read_vectored(&mut bufs).unwrap(), 7);
+        assert_eq!((&head, &tail[..4]), (b"abc", &b"defg"[..]));
+
+        // A writer that takes at most two bytes a call.
+        struct Dribble(Vec<u8>);
+        impl Write for Dribble {
+            fn write(&mut self, buf: &[u8]) -> Result<usize> {
+                let n = cmp::min(buf.len(), 2);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                Ok(())
+            }
+        }
+        let mut out = Dribble(Vec::new());
+        let mut bufs = [
+            IoSlice::new(b"one "),
+            IoSlice::new(b""),
+            IoSlice::new(b"two"),
+        ];
+        out.write_all_vectored(&mut bufs).unwrap();
+        assert_eq!(out.0, b"one two");
+
+        let mut copied = Vec::new();
+        let data = [7; DEFAULT_BUF_SIZE + 10];
+        assert_eq!(
+            copy(&mut &data[..], &mut copied).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(copied, data);
+    }
 }
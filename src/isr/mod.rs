@@ -1,7 +1,55 @@
 use core::arch::asm;
 
+use riscv::register::sstatus;
+
 pub mod plic;
 
+/// Run `f` with supervisor interrupts disabled on this hart. Restores the previous state after.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let sie = disable_interrupts();
+    let r = f();
+    restore_interrupts(sie);
+    r
+}
+
+/// Turn off supervisor interrupts on this hart. Returns whether they were on, for
+/// [`restore_interrupts`].
+pub fn disable_interrupts() -> bool {
+    let sie = sstatus::read().sie();
+    unsafe { sstatus::clear_sie() };
+    sie
+}
+
+/// Turn interrupts back on if [`disable_interrupts`] found them on.
+pub fn restore_interrupts(sie: bool) {
+    if sie {
+        unsafe { sstatus::set_sie() };
+    }
+}
+
+/// Sleep the hart until `done` returns true.
+///
+/// `done` is checked with interrupts off, so an interrupt that lands between the check and
+/// [`idle`](crate::idle::idle) still wakes us. If interrupts were already off this degrades
+/// to polling `done`.
+pub fn wait_until(mut done: impl FnMut() -> bool) {
+    let sie = sstatus::read().sie();
+    loop {
+        unsafe { sstatus::clear_sie() };
+        if done() {
+            break;
+        }
+        crate::idle::idle();
+        if sie {
+            // Let the pending interrupt run.
+            unsafe { sstatus::set_sie() };
+        }
+    }
+    if sie {
+        unsafe { sstatus::set_sie() };
+    }
+}
+
 bitflags::bitflags! {
     pub struct Sip : usize {
         const SSIP = 1 << 1;
@@ -30,4 +78,14 @@ impl Sip {
             );
         }
     }
+
+    /// Clear just the bits in `sip`.
+    pub fn clear(sip: Sip) {
+        unsafe {
+            asm!(
+                "csrc sip, {bits}",
+                bits = in(reg) sip.bits
+            );
+        }
+    }
 }
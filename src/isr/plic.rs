@@ -1,48 +1,68 @@
-use core::{
-    mem::size_of,
-    num::NonZeroU32,
-    sync::atomic::{AtomicPtr, Ordering},
-};
+use core::{cell::Cell, num::NonZeroU32};
 
 use alloc::vec::Vec;
-use spin::{Mutex, Once};
-
-use crate::{hwinfo::HwInfo, isr::Sip, println, sbi::hart::HartId};
-
-const PLIC_SIZE: usize = 0x10000 / 4;
+use core::fmt::Write;
+use crate::sync::Once;
+
+use crate::{
+    console,
+    hart_local::current_hart,
+    hwinfo::HwInfo,
+    isr::Sip,
+    mmio::Reg,
+    pagetable::memory_map::ioremap,
+    sbi::hart::HartId,
+    sync::{IrqSafeMutex, Rcu},
+};
 
 const PRIORITY_BASE: usize = 0;
-const PRIORITY_PER_ID: usize = 4;
 
 const CONTEXT_ENABLE_BASE: usize = 0x2000;
-const CONTEXT_ENABLE_SIZE: usize = 0x2000;
+const CONTEXT_ENABLE_SIZE: usize = 0x80;
 
 const CONTEXT_BASE: usize = 0x200000;
 const CONTEXT_SIZE: usize = 0x1000;
-const CONTEXT_THRESHOLD: usize = 0x00;
-const CONTEXT_CLAIM: usize = 0x04;
 
-const PLIC_DISABLE_THRESHOLD: usize = 0x7;
-const PLIC_ENABLE_THRESHOLD: usize = 0x0;
+crate::register_block! {
+    /// A context's registers past [`CONTEXT_BASE`].
+    struct ContextRegs {
+        0x00 => threshold: u32,
+        /// Read to claim, write to complete.
+        0x04 => claim: u32,
+    }
+}
+
+/// Called from the external interrupt handler with the claimed interrupt.
+/// Runs with interrupts disabled, so keep it short.
+pub type InterruptHandler = fn(InterruptId);
 
 #[derive(Debug)]
 pub struct MmioPlic {
-    addr: AtomicPtr<u8>,
+    /// Indexed by interrupt id.
+    priorities: Reg<u32>,
     contexts: Vec<Context>,
     number_of_sources: u32,
+    /// Read from the interrupt handler, without waiting for anyone registering one.
+    handlers: Rcu<Vec<(InterruptId, InterruptHandler)>>,
 }
 
 #[derive(Debug)]
 pub struct Context {
     index: usize,
     hart_id: HartId,
-    hart_base: AtomicPtr<u32>,
-    enable_base: AtomicPtr<u32>,
-    enable_mutex: Mutex<()>,
+    regs: ContextRegs,
+    /// One bit per interrupt id, 32 to a register.
+    enables: Reg<u32>,
+    enable_mutex: IrqSafeMutex<()>,
 }
 
 pub static PLIC: Once<MmioPlic> = Once::INIT;
 
+crate::hart_local! {
+    /// Index into `MmioPlic::contexts` for this hart. Filled in on first use.
+    static CONTEXT_INDEX: Cell<Option<usize>> = Cell::new(None);
+}
+
 pub unsafe fn init(hwinfo: &HwInfo) {
     PLIC.call_once(|| (MmioPlic::init(hwinfo)));
 }
@@ -72,44 +92,36 @@ impl MmioPlic {
         // Clear pending interrupts.
         Sip::write(Sip::empty());
 
-        let base = info.plic.reg.start as *mut u8;
+        let reg = &info.plic.reg;
+        let base = ioremap(reg.start, reg.end - reg.start, "PLIC").as_u64() as usize;
         let number_of_sources = info.plic.number_of_sources;
+        let priorities = Reg::new(base + PRIORITY_BASE);
 
         let mut contexts = Vec::with_capacity(info.plic.contexts.len());
 
         for ctx in &info.plic.contexts {
             let index = ctx.index;
             let hart_id = ctx.hart_id;
-            let hart_base =
-                AtomicPtr::new(base.add(CONTEXT_BASE).add(CONTEXT_SIZE * ctx.index) as *mut u32);
-            let enable_base = AtomicPtr::new(
-                base.add(CONTEXT_ENABLE_BASE)
-                    .add(CONTEXT_ENABLE_SIZE * ctx.index) as *mut u32,
-            );
-
             let mut ctx = Context {
                 index,
                 hart_id,
-                hart_base,
-                enable_base,
-                enable_mutex: Mutex::new(()),
+                regs: ContextRegs::new(base + CONTEXT_BASE + CONTEXT_SIZE * index),
+                enables: Reg::new(base + CONTEXT_ENABLE_BASE + CONTEXT_ENABLE_SIZE * index),
+                enable_mutex: IrqSafeMutex::new(()),
             };
 
             for irq in 1..number_of_sources {
                 ctx.toggle(irq, false);
-                let priority =
-                    base.add(PRIORITY_BASE)
-                        .add((irq as usize) * PRIORITY_PER_ID) as *mut u32;
-
-                priority.write_volatile(1);
+                priorities.index(irq as usize).write(1);
             }
             contexts.push(ctx);
         }
 
         let plic = Self {
             number_of_sources,
-            addr: AtomicPtr::new(base),
+            priorities,
             contexts,
+            handlers: Rcu::new(Vec::new()),
         };
 
         // println!("{:#?}", plic);
@@ -117,76 +129,76 @@ impl MmioPlic {
         plic
     }
 
-    fn context_for(&self, current_hart: HartId) -> &Context {
-        for ctx in &self.contexts {
-            if ctx.hart_id == current_hart {
-                return ctx;
-            }
+    /// Run every handler for `interrupt`. Returns false if it has none.
+    fn run_handlers(&self, interrupt: InterruptId) -> bool {
+        let handlers = self.handlers.read();
+        let mut handled = false;
+        for (_, handler) in handlers.iter().filter(|(id, _)| *id == interrupt) {
+            handler(interrupt);
+            handled = true;
         }
-        panic!("Hart #{} has no context", current_hart.0);
+        handled
+    }
+
+    fn context_for(&self, hart: HartId) -> Option<usize> {
+        self.contexts.iter().position(|ctx| ctx.hart_id == hart)
+    }
+
+    /// The context for the hart we're running on.
+    fn current_context(&self) -> &Context {
+        let cached = CONTEXT_INDEX.get();
+        let index = match cached.get() {
+            Some(index) => index,
+            None => {
+                let hart = current_hart();
+                let index = self
+                    .context_for(hart)
+                    .unwrap_or_else(|| panic!("Hart #{} has no context", hart.0));
+                cached.set(Some(index));
+                index
+            }
+        };
+        &self.contexts[index]
     }
 }
 
 impl Context {
+    /// The enable register with `irq`'s bit in it, and the bit.
+    fn enable_bit(&self, irq: u32) -> (Reg<u32>, u32) {
+        // Within the context's enable block, which has room for every interrupt id.
+        let reg = unsafe { self.enables.index(irq as usize / 32) };
+        (reg, 1 << (irq % 32))
+    }
+
     fn toggle(&mut self, irq: u32, enable: bool) {
-        unsafe {
-            let reg = self
-                .enable_base
-                .load(Ordering::Relaxed)
-                .add((irq as usize / 32) * size_of::<u32>());
-            let mask = 1 << (irq % 32);
-
-            if enable {
-                reg.write_volatile(reg.read_volatile() | mask);
-            } else {
-                reg.write_volatile(reg.read_volatile() & !mask);
-            }
+        let (reg, mask) = self.enable_bit(irq);
+        if enable {
+            reg.modify(|bits| bits | mask);
+        } else {
+            reg.modify(|bits| bits & !mask);
         }
     }
 
     fn set_threshold(&self, arg: Threshold) {
-        unsafe {
-            let ptr = self
-                .hart_base
-                .load(Ordering::Relaxed)
-                .add(CONTEXT_THRESHOLD);
-            ptr.write(arg as u32);
-        }
+        self.regs.threshold().write(arg as u32);
     }
 
     fn toggle_interrupt(&self, interrupt: InterruptId, enable: bool) {
-        let i = interrupt.0.get();
-        self.enable_mutex.lock();
-        let enable_base = self.enable_base.load(Ordering::Relaxed);
-        unsafe {
-            let reg = enable_base.add((i as usize) / 32);
-            let mask = 1 << (i % 32);
-
-            if enable {
-                let old = reg.read_volatile();
-                let new = old | mask;
-                reg.write_volatile(new);
-            } else {
-                let old = reg.read_volatile();
-                let new = old & !mask;
-                reg.write_volatile(new);
-            }
+        let (reg, mask) = self.enable_bit(interrupt.get());
+        let _enable = self.enable_mutex.lock();
+        if enable {
+            reg.modify(|bits| bits | mask);
+        } else {
+            reg.modify(|bits| bits & !mask);
         }
     }
 
     fn claim(&self) -> Option<InterruptId> {
-        unsafe {
-            let claim_ptr = self.hart_base.load(Ordering::Relaxed).add(CONTEXT_CLAIM);
-            let res = claim_ptr.read_volatile();
-            InterruptId::new(res)
-        }
+        InterruptId::new(self.regs.claim().read())
     }
 
     pub(crate) fn complete(&self, interrupt: InterruptId) {
-        unsafe {
-            let complete_ptr = self.hart_base.load(Ordering::Relaxed).add(CONTEXT_CLAIM);
-            complete_ptr.write_volatile(interrupt.get());
-        }
+        self.regs.claim().write(interrupt.get());
     }
 }
 
@@ -220,13 +232,28 @@ pub(crate) fn enable_interrupt(interrupt: InterruptId) {
     }
 }
 
-pub(crate) fn process_interrupt(current_hart: HartId) {
+/// Add a handler for an interrupt. Devices can share one, like PCI INTx, so every handler
+/// for it is called. The same handler registered twice is only called once.
+pub(crate) fn register_handler(interrupt: InterruptId, handler: InterruptHandler) {
     let plic = load_plic();
-    let context = plic.context_for(current_hart);
 
-    if let Some(interrupt) = context.claim() {
-        println!("Claimed interrupt {:?}", interrupt);
-        // TODO
+    plic.handlers.update(|handlers| {
+        let mut handlers = handlers.clone();
+        handlers.retain(|(id, h)| !(*id == interrupt && *h as usize == handler as usize));
+        handlers.push((interrupt, handler));
+        handlers
+    });
+}
+
+/// Claim and handle every pending interrupt for this hart.
+pub(crate) fn process_interrupt() {
+    let plic = load_plic();
+    let context = plic.current_context();
+
+    while let Some(interrupt) = context.claim() {
+        if !plic.run_handlers(interrupt) {
+            writeln!(console::lock_or_dummy(), "Unhandled interrupt {:?}", interrupt).ok();
+        }
         context.complete(interrupt);
     }
 }
@@ -3,7 +3,7 @@
 use core::fmt::Write;
 use core::{ffi::c_void, ops::Range};
 
-use spin::Once;
+use crate::sync::Lazy;
 
 use crate::console;
 
@@ -68,17 +68,6 @@ macro_rules! write_address {
     };
 }
 
-pub fn print_address_ranges() {
-    let mut w = console::lock();
-    writeln!(w, "image   0x{:x}..0x{:x}", image().start, image().end).ok();
-    writeln!(w, "text    0x{:x}..0x{:x}", text().start, text().end).ok();
-    writeln!(w, "rodata  0x{:x}..0x{:x}", rodata().start, rodata().end).ok();
-    writeln!(w, "data    0x{:x}..0x{:x}", data().start, data().end).ok();
-    writeln!(w, "bss     0x{:x}..0x{:x}", bss().start, bss().end).ok();
-    writeln!(w, "tdata   0x{:x}..0x{:x}", tdata().start, tdata().end).ok();
-    writeln!(w, "tbss    0x{:x}..0x{:x}", tbss().start, tbss().end).ok();
-}
-
 pub unsafe fn print_address() {
     let mut w = console::lock();
     write_address!(w, __image_start);
@@ -110,18 +99,18 @@ pub struct LinkerInfo {
     pub tbss: Range<u64>,
 }
 
-static LINKER_INFO: Once<LinkerInfo> = Once::INIT;
+static LINKER_INFO: Lazy<LinkerInfo> = Lazy::new(|| LinkerInfo {
+    image: image(),
+    text: text(),
+    rodata: rodata(),
+    data: data(),
+    bss: bss(),
+    tdata: tdata(),
+    tbss: tbss(),
+});
 
 impl LinkerInfo {
     pub fn get() -> &'static Self {
-        LINKER_INFO.call_once(|| LinkerInfo {
-            image: image(),
-            text: text(),
-            rodata: rodata(),
-            data: data(),
-            bss: bss(),
-            tdata: tdata(),
-            tbss: tbss(),
-        })
+        &LINKER_INFO
     }
 }
@@ -0,0 +1,396 @@
+//! Kernel log.
+//!
+//! [`error!`], [`warn!`], [`info!`] and [`debug!`] format a line with the time since boot,
+//! the level and the module it came from. Lines the filters let through go into a fixed
+//! size ring buffer, which `dmesg` in the shell replays, and out the console. Before the
+//! UART is up they go out through the SBI console instead.
+//!
+//! Each module can have its own level, set with [`configure`] from the `log=` kernel
+//! argument or the `loglevel` shell command. Release builds leave out debug messages
+//! altogether.
+
+use core::{
+    fmt::{self, Arguments, Display, Formatter},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{console, isr::without_interrupts, prelude::*, time};
+
+/// Bytes of log kept. Older lines are overwritten.
+const RING_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    /// Only as a filter, to log nothing.
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    fn from_u8(n: u8) -> Option<Level> {
+        match n {
+            0 => Some(Level::Off),
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Level, FilterError> {
+        [
+            Level::Off,
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+        ]
+        .into_iter()
+        .find(|level| level.name().eq_ignore_ascii_case(s))
+        .ok_or(FilterError::UnknownLevel)
+    }
+}
+
+/// Messages more verbose than this aren't compiled in. Debug builds keep everything.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(debug_assertions) {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    UnknownLevel,
+    /// A `module=level` with no module.
+    EmptyModule,
+}
+
+impl Display for FilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::UnknownLevel => write!(f, "unknown log level"),
+            FilterError::EmptyModule => write!(f, "missing module name"),
+        }
+    }
+}
+
+impl core::error::Error for FilterError {}
+
+struct Filters {
+    default: Level,
+    /// Module path prefixes and their levels, longest first.
+    modules: Vec<(String, Level)>,
+}
+
+impl Filters {
+    fn level_for(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .find(|(prefix, _)| {
+                module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .map_or(false, |rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Level::max)
+    }
+}
+
+/// Locked with interrupts off.
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: Level::Info,
+    modules: Vec::new(),
+});
+/// The most verbose level anything logs at, so most messages that won't be logged are
+/// turned away without taking the lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Level for modules without their own.
+pub fn level() -> Level {
+    without_interrupts(|| FILTERS.lock().default)
+}
+
+pub fn set_level(level: Level) {
+    without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        filters.default = level;
+        MAX_LEVEL.store(filters.max() as u8, Ordering::Relaxed);
+    });
+}
+
+/// Apply a filter spec like `info,pagetable=warn,fs::fat=debug`. A bare level sets the
+/// default. Module paths leave out the crate name and cover the modules under them. Nothing
+/// changes if any of it is bad.
+pub fn configure(spec: &str) -> Result<(), FilterError> {
+    let mut default = None;
+    let mut modules = Vec::new();
+    for item in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+    {
+        match item.split_once('=') {
+            Some((module, level)) => {
+                let module = module.trim().trim_start_matches("kernel::");
+                if module.is_empty() {
+                    return Err(FilterError::EmptyModule);
+                }
+                modules.push((String::from(module), level.trim().parse()?));
+            }
+            None => default = Some(item.parse()?),
+        }
+    }
+
+    without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        if let Some(default) = default {
+            filters.default = default;
+        }
+        for (module, level) in modules {
+            filters.modules.retain(|(prefix, _)| *prefix != module);
+            filters.modules.push((module, level));
+        }
+        filters
+            .modules
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        MAX_LEVEL.store(filters.max() as u8, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// The current filters, in the form [`configure`] takes.
+pub fn filters() -> String {
+    without_interrupts(|| {
+        let filters = FILTERS.lock();
+        let mut spec = String::from(filters.default.name());
+        for (module, level) in filters.modules.iter().rev() {
+            write!(spec, ",{}={}", module, level.name()).ok();
+        }
+        spec.to_ascii_lowercase()
+    })
+}
+
+/// Whether a message at `level` from `module` would be logged.
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level == Level::Off || level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let module = module.strip_prefix("kernel::").unwrap_or(module);
+    level <= without_interrupts(|| FILTERS.lock().level_for(module))
+}
+
+struct Ring {
+    buf: [u8; RING_SIZE],
+    /// Bytes ever written. The next one goes at `written % RING_SIZE`.
+    written: usize,
+}
+
+/// Only locked with interrupts off, so handlers can log.
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; RING_SIZE],
+    written: 0,
+});
+
+impl Ring {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.written % RING_SIZE] = byte;
+            self.written += 1;
+        }
+    }
+}
+
+/// Writes to the ring and the console at once.
+struct Tee<'a> {
+    ring: &'a mut Ring,
+}
+
+impl fmt::Write for Tee<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.ring.push(s.as_bytes());
+        if console::is_initialized() {
+            print!("{}", s);
+        } else {
+            unsafe { console::sbi_console() }.write_str(s)?;
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let module = module.strip_prefix("kernel::").unwrap_or(module);
+    let uptime = time::uptime().unwrap_or_default();
+    without_interrupts(|| {
+        let mut ring = RING.lock();
+        let mut tee = Tee { ring: &mut ring };
+        writeln!(
+            tee,
+            "[{:5}.{:06}] {:<5} {}: {}",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            level.name(),
+            module,
+            args
+        )
+        .ok();
+    });
+}
+
+/// What's still in the ring, starting at the oldest whole line.
+pub fn contents() -> Vec<u8> {
+    let (mut bytes, wrapped) = without_interrupts(|| {
+        let ring = RING.lock();
+        let start = ring.written.saturating_sub(RING_SIZE);
+        let bytes: Vec<u8> = (start..ring.written)
+            .map(|i| ring.buf[i % RING_SIZE])
+            .collect();
+        (bytes, start > 0)
+    });
+    if wrapped {
+        // The first line has lost its start.
+        let first = bytes.iter().position(|&b| b == b'\n').map_or(0, |n| n + 1);
+        bytes.drain(..first);
+    }
+    bytes
+}
+
+/// The last `lines` lines in the ring, for when the kernel's dying. Doesn't allocate, and
+/// gives up rather than wait if the ring's locked.
+pub fn write_tail(w: &mut dyn fmt::Write, lines: usize) -> fmt::Result {
+    let Some(ring) = RING.try_lock() else {
+        return writeln!(w, "(the log is locked)");
+    };
+    let oldest = ring.written.saturating_sub(RING_SIZE);
+    let mut start = ring.written;
+    let mut found = 0;
+    while start > oldest && found < lines {
+        start -= 1;
+        // Back to the start of the line this byte's in. The last byte ends the last line.
+        while start > oldest && ring.buf[(start - 1) % RING_SIZE] != b'\n' {
+            start -= 1;
+        }
+        found += 1;
+    }
+    if start == oldest && oldest > 0 {
+        // The oldest line has lost its start.
+        while start < ring.written && ring.buf[start % RING_SIZE] != b'\n' {
+            start += 1;
+        }
+        start = (start + 1).min(ring.written);
+    }
+
+    let len = ring.written - start;
+    let first = start % RING_SIZE;
+    let first_len = len.min(RING_SIZE - first);
+    for part in [
+        &ring.buf[first..first + first_len],
+        &ring.buf[..len - first_len],
+    ] {
+        for chunk in part.utf8_chunks() {
+            w.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                w.write_char(char::REPLACEMENT_CHARACTER)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+macro_rules! __error {
+    ($($arg:tt)*) => {
+        if $crate::log::Level::Error <= $crate::log::STATIC_MAX_LEVEL {
+            $crate::log::_log($crate::log::Level::Error, module_path!(), format_args!($($arg)*))
+        }
+    };
+}
+
+macro_rules! __warn {
+    ($($arg:tt)*) => {
+        if $crate::log::Level::Warn <= $crate::log::STATIC_MAX_LEVEL {
+            $crate::log::_log($crate::log::Level::Warn, module_path!(), format_args!($($arg)*))
+        }
+    };
+}
+
+macro_rules! __info {
+    ($($arg:tt)*) => {
+        if $crate::log::Level::Info <= $crate::log::STATIC_MAX_LEVEL {
+            $crate::log::_log($crate::log::Level::Info, module_path!(), format_args!($($arg)*))
+        }
+    };
+}
+
+macro_rules! __debug {
+    ($($arg:tt)*) => {
+        if $crate::log::Level::Debug <= $crate::log::STATIC_MAX_LEVEL {
+            $crate::log::_log($crate::log::Level::Debug, module_path!(), format_args!($($arg)*))
+        }
+    };
+}
+
+// Through `use` so they're `log::info!` and so on, like functions in the module. `warn`
+// also clashes with the attribute otherwise.
+pub(crate) use {__debug as debug, __error as error, __info as info, __warn as warn};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn log_filters_and_keeps_lines() {
+        let old = level();
+        set_level(Level::Warn);
+        info!("log test: hidden");
+        warn!("log test: shown");
+        set_level(old);
+        let saved = without_interrupts(|| FILTERS.lock().modules.clone());
+        configure("log::test=off").unwrap();
+        warn!("log test: silenced");
+        // As it was, for the tests after this one.
+        without_interrupts(|| {
+            let mut filters = FILTERS.lock();
+            filters.modules = saved;
+            MAX_LEVEL.store(filters.max() as u8, Ordering::Relaxed);
+        });
+        assert!(!filters().contains("log::test"));
+
+        let contents = contents();
+        let text = String::from_utf8_lossy(&contents);
+        assert!(text.contains("WARN  log::test: log test: shown\n"));
+        assert!(!text.contains("log test: hidden"));
+        assert!(!text.contains("log test: silenced"));
+    }
+}
@@ -6,7 +6,9 @@
 #![feature(fn_align)]
 #![feature(type_alias_impl_trait)]
 #![feature(int_roundings)]
-#![test_runner(crate::test_runner)]
+#![feature(thread_local)]
+#![feature(alloc_error_handler)]
+#![test_runner(crate::testing::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![allow(dead_code)]
 #![no_std]
@@ -16,29 +18,68 @@ extern crate alloc;
 
 mod prelude;
 
+mod addr;
+mod asid;
 mod asm;
+mod backtrace;
 mod basic_allocator;
 mod basic_consts;
+mod block;
+mod boot;
+mod cmdline;
 mod console;
+mod cpu;
+mod cpustat;
+mod devices;
+mod dma;
+mod error;
+mod finisher;
+mod fs;
+mod graphics;
+mod hart_local;
 mod hwinfo;
+mod idle;
+mod input;
 mod io;
 mod isr;
 mod linker_info;
+mod log;
+mod memmap;
+mod mmio;
+mod net;
+mod oops;
 mod pagetable;
 mod panic;
+mod pci;
+mod perf;
+mod poison;
+mod process;
+mod pstore;
+mod rand;
 mod sbi;
+mod shell;
+mod slab;
+mod smp;
+mod sound;
+mod stack;
+mod sync;
+mod syscall;
 mod task;
 mod time;
+#[cfg(test)]
+mod testing;
+mod tlb;
+mod trace;
 mod trap;
 mod util;
+mod virtio;
+mod vmalloc;
+mod watchdog;
+mod workqueue;
 
 use hwinfo::DtbRef;
 use ::time::OffsetDateTime;
-use core::{
-    cell::UnsafeCell,
-    sync::atomic::AtomicBool,
-    time::Duration,
-};
+use core::sync::atomic::AtomicBool;
 
 use riscv::register::{
     mtvec,
@@ -51,53 +92,19 @@ use crate::{
     prelude::*,
     sbi::{
         hart::{hsm_extension, HartId},
-        reset::shutdown,
     },
-    time::{sleep, Instant},
+    time::Instant,
     linker_info::{__image_end},
 };
-use crate::pagetable::Entry;
-
-#[repr(align(4096))]
-pub struct StackGuardPage {
-    bytes: UnsafeCell<[u64; 512]>,
-}
-unsafe impl Sync for StackGuardPage {}
-
-impl StackGuardPage {
-    unsafe fn init(&self) {
-        let bytes = self.bytes.get();
-        for word in (*bytes).iter_mut() {
-            *word = 0x3355335533553355
-        }
-    }
-
-    pub(crate) fn check(&self) {
-        unsafe {
-            let byte = self.bytes.get();
-            assert_eq!((*byte)[511], 0x3355335533553355, "Stack guard corrupted");
-        }
-    }
-
-    pub(crate) fn address(&self) -> core::ops::Range<u64> {
-        let start = self as *const _ as usize as u64;
-        let end = start + 4096;
-        start..end
-    }
-}
-
-#[link_section = ".stack_guard"]
-#[no_mangle]
-pub static STACK_GUARD: StackGuardPage = StackGuardPage {
-    bytes: UnsafeCell::new([0; 512]),
-};
 
 static BOOTLOOP_DETECT: AtomicBool = AtomicBool::new(false);
 
 #[no_mangle]
-pub extern "C" fn kmain(hart_id: HartId, dtb: DtbRef) -> ! {
+pub extern "C" fn kmain(hart_id: HartId, dtb: DtbRef, started: u64) -> ! {
+    boot::entered(started);
     unsafe {
-        STACK_GUARD.init();
+        // `_start` left us in the upper half, with the DTB pointer moved up too.
+        pagetable::init_kernel();
     }
 
     let has_booted = BOOTLOOP_DETECT.swap(true, core::sync::atomic::Ordering::SeqCst);
@@ -105,50 +112,101 @@ pub extern "C" fn kmain(hart_id: HartId, dtb: DtbRef) -> ! {
         panic!("Boot loop detected");
     }
 
-    sbi::init();
-    unsafe {
+    boot::timed("sbi", sbi::init);
+    boot::timed("early heap", || unsafe {
         // Initialize the memory allocatior using space from the end of the kernel image the start of the DTB.
         #[allow(static_mut_ref)]
         basic_allocator::init_from_free_space(&mut __image_end as *mut u8 as *mut u8, &dtb);
-    }
+        // Needs the allocator for this hart's copy of .tdata/.tbss
+        hart_local::init_hart(hart_id);
+    });
 
     // let mut memory_regions = pagetable::memory_map::MemoryRegions::new();
 
-    let hwinfo = hwinfo::setup_dtb(dtb);
+    let hwinfo = boot::timed("device tree", || hwinfo::setup_dtb(dtb));
+    cmdline::init(&hwinfo.bootargs);
+    if let Some(level) = cmdline::get("loglevel") {
+        match level.parse() {
+            Ok(level) => log::set_level(level),
+            Err(err) => log::warn!("bad loglevel= argument {:?}: {}", level, err),
+        }
+    }
+    if let Some(spec) = cmdline::get("log") {
+        if let Err(err) = log::configure(spec) {
+            log::warn!("bad log= argument {:?}: {}", spec, err);
+        }
+    }
+    panic::init();
+    rand::init(hwinfo);
+    watchdog::init();
+    pagetable::init_mode(hwinfo);
+    asid::init();
     unsafe {
-        // Add the rest of the memory to the allocator. Wipes out the DTB, which has already been dropped by this point.
-        basic_allocator::finish_init(hwinfo);
+        // Add the rest of the memory to the allocator. Wipes out the DTB, which `setup_dtb` has copied by now.
+        boot::timed("heap", || basic_allocator::finish_init(hwinfo));
+        boot::timed("page tables", || {
+            pagetable::init_mmio(hwinfo);
+            pagetable::memory_map::init_io_window(hwinfo);
+        });
+        boot::timed("stacks and vmalloc", || {
+            stack::init(hwinfo);
+            vmalloc::init(hwinfo);
+        });
     }
-
-    // Check we didn't overflow the stack yet.
-    STACK_GUARD.check();
+    boot::timed("memmap", memmap::init);
+    // Anything the last boot's panic left.
+    pstore::init(hwinfo);
 
     // Initialize the Interrupt Controller
-    unsafe {
+    boot::timed("plic", || unsafe {
         plic::init(hwinfo);
         plic::set_threshold(plic::Threshold::Enable);
         // If there's a pending interrupt on uart let's clear it first.
-        plic::process_interrupt(hart_id);
-    }
+        plic::process_interrupt();
+    });
+
+    // Interrupt handlers hand work to it from here on.
+    workqueue::init();
 
     // Initialize UART
-    console::init(hwinfo);
+    boot::timed("console", || console::init(hwinfo));
 
     // Initialize the internal timer
-    time::init_time(hwinfo);
+    boot::timed("timer", || time::init_time(hwinfo));
     // Initialize the real time clock
-    time::rtc::init(hwinfo);
-
-    // Print the ELF image layout for debugging
-    linker_info::print_address_ranges();
-    // println!(    "fdt:      {:08x} - {:08x}", hwinfo.tree_range.start, hwinfo.tree_range.end);
+    boot::stage("rtc", || time::rtc::init(hwinfo));
+
+    // Bind drivers to everything else in the device tree, including the virtio slots.
+    boot::timed("devices", || {
+        devices::probe_all(hwinfo);
+        virtio::probe_pci();
+        virtio::blk::init();
+        virtio::rng::init();
+        virtio::console::init();
+        virtio::net::init();
+        virtio::gpu::init();
+        virtio::input::init();
+        virtio::ninep::init();
+        virtio::sound::init();
+        console::framebuffer::init();
+        block::ramdisk::init();
+    });
+    boot::timed("filesystems", || {
+        fs::tmpfs::init();
+        fs::devfs::init();
+        fs::procfs::init();
+        fs::fat::mount_all();
+        virtio::ninep::mount_all();
+    });
+    boot::stage("initramfs", || fs::cpio::init(hwinfo));
 
     // Check we can read the time.
     let now = Instant::now();
-    println!("now = {:?}", now);
+    log::debug!("now = {:?}", now);
 
-    println!("{:#?}", hwinfo);
+    log::debug!("{:#?}", hwinfo);
 
+    trap::init_hart(hart_id);
     let stvec_addr = asm::trap_entry as *const u8;
     assert_eq!((stvec_addr as usize) & 0b11, 0);
 
@@ -159,13 +217,13 @@ pub extern "C" fn kmain(hart_id: HartId, dtb: DtbRef) -> ! {
     };
 
 
-    println!(
+    log::debug!(
         "stvec address: Wrote: {:?}. Read: {:?}",
         stvec_addr,
         stvec_ret.address() as *const u8
     );
 
-    println!(
+    log::debug!(
         "stvec wrote:   Wrote: {:?}. Read: {:?}",
         mtvec::TrapMode::Direct,
         stvec_ret.trap_mode()
@@ -179,104 +237,56 @@ pub extern "C" fn kmain(hart_id: HartId, dtb: DtbRef) -> ! {
     }
 
     let time = OffsetDateTime::now_utc();
-    println!("time: {}", time);
+    log::info!("time: {}", time);
 
     let sie_val = sie::read();
-    println!("sie          = {:?}", sie_val);
-    println!("    .ssoft   = {:?}", sie_val.ssoft());
-    println!("    .stimer  = {:?}", sie_val.stimer());
-    println!("    .sext    = {:?}", sie_val.sext());
-    println!("    .usoft   = {:?}", sie_val.usoft());
-    println!("    .utimer  = {:?}", sie_val.utimer());
-    println!("    .uext    = {:?}", sie_val.uext());
-
-    println!("heart: {}", hart_id);
-    println!();
-
-    for i in 0..64 {
-        println!("{:?}", Entry(1 << i));
+    log::debug!(
+        "sie: ssoft {} stimer {} sext {} usoft {} utimer {} uext {}",
+        sie_val.ssoft(),
+        sie_val.stimer(),
+        sie_val.sext(),
+        sie_val.usoft(),
+        sie_val.utimer(),
+        sie_val.uext()
+    );
+
+    log::info!("booted on hart {}", hart_id);
+    boot::log_timings();
+    let problems = boot::problems().len();
+    if problems > 0 {
+        log::warn!("{} problems during boot, see `boot`", problems);
     }
+
+    pagetable::log_entry_flags();
     #[cfg(test)]
-    test_main();
+    {
+        testing::init(hwinfo);
+        test_main();
+    }
 
     let hsm = hsm_extension();
 
     for hart in &hwinfo.harts {
         let status = hsm.hart_get_status(hart.hart_id);
         match status {
-            Ok(status) => println!("{:?}: {:?}", hart.hart_id, status),
-            Err(err) => println!("{:?} invalid: ({:?})", hart.hart_id, err),
+            Ok(status) => log::info!("hart {}: {:?}", hart.hart_id, status),
+            Err(err) => log::warn!("hart {} invalid: ({:?})", hart.hart_id, err),
         }
     }
 
 
-    // shutdown();
-    #[allow(unused)]
-    let mut do_shutdown = false;
-    while !do_shutdown {
-        for b in console::pending_bytes() {
-            println!("Got byte: {:02x}", b);
-            if b == 0x03 {
-                do_shutdown = true;
-            }
-        }
-
-        if !do_shutdown {
-            sleep(Duration::from_millis(200));
-        }
-
-        // println!("Suspending!");
-        // let suspend = hsm.hart_retentive_suspend(RetentiveSuspendType::DEFAULT_RETENTIVE_SUSPEND);
-        // println!("Suspend result: {:?}", suspend);
-    }
-    shutdown();
-}
-
-async fn async_number() -> u32 {
-    42
-}
-
-async fn example_task() {
-    let number = async_number().await;
-    println!("async number: {}", number);
-}
-
-
-
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    fn run(&self) -> () {
-        print!("{}...\t", core::any::type_name::<T>());
-        self();
-        println!("[ok]");
-    }
-}
-
-#[cfg(test)]
-pub fn test_runner(tests: &[&dyn Testable]) {
-    println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    shutdown();
-}
+    process::run_init();
 
-#[test_case]
-fn hello_world() {
-    println!("Hello world!");
+    shell::run(hwinfo)
 }
 
+/// Busy-wait for `$cond`. For hardware that'll be ready in a moment and won't interrupt
+/// when it is.
 #[macro_export]
 macro_rules! wait_for {
     ($cond:expr) => {
         while !$cond {
-            core::hint::spin_loop()
+            $crate::cpu::relax()
         }
     };
 }
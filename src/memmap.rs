@@ -0,0 +1,149 @@
+//! The physical memory map: what's where, in one place.
+//!
+//! [`iter`] goes over RAM, reservations, device registers, the kernel's sections and the
+//! heap in address order, with RAM nothing has claimed as `unused`. Each [`Region`] has
+//! the permissions the kernel should map it with. It's worked out from the device tree and
+//! the heap as they are when it's called, so it stays right when the heap takes over the
+//! initrd's memory. Anything that overlaps, which [`regions`] works around, is in
+//! [`conflicts`].
+
+use core::fmt::{self, Display, Formatter};
+
+use crate::{
+    addr::PhysAddr,
+    hwinfo::{self, PhysicalAddressKind, PhysicalAddressRange},
+    log,
+    pagetable::{
+        regions::{self, Conflict},
+        EntryFlags, Pbmt,
+    },
+    prelude::*,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub kind: PhysicalAddressKind,
+    pub description: &'static str,
+    /// How the kernel may map it. Empty for memory that isn't the kernel's to touch.
+    pub flags: EntryFlags,
+}
+
+impl Region {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, address: PhysAddr) -> bool {
+        self.start <= address && address < self.end
+    }
+}
+
+impl From<PhysicalAddressRange> for Region {
+    fn from(range: PhysicalAddressRange) -> Self {
+        let flags = match range.kind {
+            PhysicalAddressKind::Reserved => EntryFlags::empty(),
+            PhysicalAddressKind::Executable => EntryFlags::READ | EntryFlags::EXECUTE,
+            PhysicalAddressKind::ReadOnly => EntryFlags::READ,
+            PhysicalAddressKind::Usable | PhysicalAddressKind::Writable => {
+                EntryFlags::READ | EntryFlags::WRITE
+            }
+            PhysicalAddressKind::Mmio => EntryFlags::READ | EntryFlags::WRITE | Pbmt::Io.flags(),
+        };
+        Region {
+            start: range.start,
+            end: range.end,
+            kind: range.kind,
+            description: range.description,
+            flags,
+        }
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        let io = if self.flags.contains(EntryFlags::PBMT_IO) {
+            " io"
+        } else {
+            ""
+        };
+        write!(
+            f,
+            "{:#010x}..{:#010x} {:>8} KiB {}{}{}{:<3} {:<10} {:?}",
+            self.start,
+            self.end,
+            self.len() / 1024,
+            flag(EntryFlags::READ, 'r'),
+            flag(EntryFlags::WRITE, 'w'),
+            flag(EntryFlags::EXECUTE, 'x'),
+            io,
+            self.description,
+            self.kind
+        )
+    }
+}
+
+fn layout() -> Vec<PhysicalAddressRange> {
+    let hwinfo = match hwinfo::get() {
+        Some(hwinfo) => hwinfo,
+        None => return Vec::new(),
+    };
+    let mut layout = hwinfo.memory_layout();
+    let unused = regions::unused(&hwinfo.ram, &layout);
+    layout.extend(unused);
+    layout.sort_by_key(|range| (range.start, range.end));
+    layout
+}
+
+/// Everything in physical memory, in address order. Empty before the device tree's read.
+pub fn iter() -> impl Iterator<Item = Region> {
+    layout().into_iter().map(Region::from)
+}
+
+/// The region `address` is in. The first, if it's in more than one.
+pub fn find(address: PhysAddr) -> Option<Region> {
+    iter().find(|region| region.contains(address))
+}
+
+/// Regions that overlap each other.
+pub fn conflicts() -> Vec<Conflict> {
+    let hwinfo = match hwinfo::get() {
+        Some(hwinfo) => hwinfo,
+        None => return Vec::new(),
+    };
+    regions::conflicts(&hwinfo.memory_layout())
+}
+
+/// Log the map, and warn about overlaps. Once the heap has all of RAM.
+pub fn init() {
+    for region in iter() {
+        log::debug!("{}", region);
+    }
+    for conflict in conflicts() {
+        log::warn!("memory map: {}", conflict);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{addr::VirtAddr, basic_allocator};
+
+    #[test_case]
+    fn memory_map() {
+        let map: Vec<Region> = iter().collect();
+        assert!(map.windows(2).all(|pair| pair[0].start <= pair[1].start));
+        assert!(map.iter().any(|region| region.description == ".text"
+            && region.flags == EntryFlags::READ | EntryFlags::EXECUTE));
+
+        // The heap is in it, as read-write RAM.
+        let on_heap = Box::new(0u64);
+        let address = crate::pagetable::virt_to_phys(VirtAddr::from_ptr(&*on_heap));
+        let heap = basic_allocator::heap_ranges();
+        let region = find(address).unwrap();
+        assert!(heap.iter().any(|range| range.start == region.start));
+        assert_eq!(region.flags, EntryFlags::READ | EntryFlags::WRITE);
+    }
+}
@@ -0,0 +1,156 @@
+//! Memory-mapped device registers.
+//!
+//! A [`Reg`] is a typed pointer to one register. Every access through it is volatile, so
+//! the compiler won't merge, reorder or drop them. [`register_block!`](crate::register_block)
+//! lays out a device's registers by their offsets from its base address.
+
+use core::{
+    fmt::{self, Debug, Formatter},
+    marker::PhantomData,
+    mem::size_of,
+};
+
+/// One memory-mapped register that reads and writes as a `T`.
+pub struct Reg<T> {
+    addr: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for Reg<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Reg<T> {}
+
+// Only an address. Whether sharing the register is sound is up to the driver.
+unsafe impl<T> Send for Reg<T> {}
+unsafe impl<T> Sync for Reg<T> {}
+
+impl<T: Copy> Reg<T> {
+    /// # Safety
+    /// `addr` must be a mapped register, aligned for `T`, for as long as this is used.
+    pub const unsafe fn new(addr: usize) -> Self {
+        Reg {
+            addr,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    pub fn read(self) -> T {
+        unsafe { (self.addr as *const T).read_volatile() }
+    }
+
+    pub fn write(self, value: T) {
+        unsafe { (self.addr as *mut T).write_volatile(value) }
+    }
+
+    /// Read, change and write back. Not atomic: lock if anything else writes it.
+    pub fn modify(self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
+
+    /// Register `index` of an array of them starting here.
+    ///
+    /// # Safety
+    /// That register has to exist too.
+    pub unsafe fn index(self, index: usize) -> Reg<T> {
+        Reg::new(self.addr + index * size_of::<T>())
+    }
+}
+
+impl<T> Debug for Reg<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Reg(0x{:x})", self.addr)
+    }
+}
+
+/// Declare a block of registers at fixed offsets from a base address.
+///
+/// ```ignore
+/// register_block! {
+///     /// The Goldfish RTC.
+///     struct Regs {
+///         0x00 => time_low: u32,
+///         0x04 => time_high: u32,
+///     }
+/// }
+///
+/// let regs = unsafe { Regs::new(base) };
+/// let low = regs.time_low().read();
+/// ```
+///
+/// Each register gets a method returning its [`Reg`]. An offset that isn't aligned for the
+/// register's type doesn't compile. Registers may overlap, for devices that bank them.
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$reg_attr:meta])* $offset:literal => $reg_vis:vis $reg:ident: $t:ty,)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy)]
+        $vis struct $name {
+            base: usize,
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// # Safety
+            /// `base` must be where this block of registers is mapped.
+            $vis const unsafe fn new(base: usize) -> Self {
+                $name { base }
+            }
+
+            $vis fn base(&self) -> usize {
+                self.base
+            }
+
+            $(
+                $(#[$reg_attr])*
+                $reg_vis fn $reg(&self) -> $crate::mmio::Reg<$t> {
+                    const _: () = assert!(
+                        $offset % core::mem::align_of::<$t>() == 0,
+                        "misaligned register"
+                    );
+                    unsafe { $crate::mmio::Reg::new(self.base + $offset) }
+                }
+            )*
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    crate::register_block! {
+        struct TestRegs {
+            0x0 => low: u32,
+            0x4 => high: u32,
+            0x0 => both: u64,
+        }
+    }
+
+    #[test_case]
+    fn register_block_offsets() {
+        let mut memory = [0u64; 2];
+        let regs = unsafe { TestRegs::new(memory.as_mut_ptr() as usize) };
+        regs.both().write(0x1234_5678_9abc_def0);
+        assert_eq!(regs.low().read(), 0x9abc_def0);
+        assert_eq!(regs.high().read(), 0x1234_5678);
+        regs.high().modify(|high| high + 1);
+        assert_eq!(regs.both().read(), 0x1234_5679_9abc_def0);
+        let second = unsafe { regs.both().index(1) };
+        assert_eq!(second.addr() - regs.base(), 8);
+        second.write(7);
+        assert_eq!(memory[1], 7);
+    }
+}
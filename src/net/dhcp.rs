@@ -0,0 +1,353 @@
+//! A DHCP client (RFC 2131), enough to get an address from QEMU's user networking or a
+//! home router.
+//!
+//! [`Client::poll`] says what to broadcast and when, and [`Client::receive`] takes the
+//! replies. Everything is broadcast, from port 68 to 67, even renewing: we don't have an
+//! address the server can answer until it's acked.
+
+use core::time::Duration;
+
+use super::wire::{Ipv4Addr, MacAddr};
+use crate::{prelude::*, time::Instant};
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Where the options start: the fixed fields and the cookie.
+const OPTIONS: usize = 240;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+/// How long to wait for an answer before asking again.
+const RETRY: Duration = Duration::from_secs(4);
+/// Requests without an answer before starting over.
+const MAX_REQUESTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    /// Netmask length.
+    pub prefix: u8,
+    pub router: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    pub duration: Duration,
+}
+
+/// What a reply did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Bound(Lease),
+    /// The server wouldn't renew. The address can't be used any more.
+    Lost,
+}
+
+#[derive(Debug)]
+enum State {
+    Discovering,
+    Requesting {
+        address: Ipv4Addr,
+        server: Ipv4Addr,
+        sent: u32,
+    },
+    Bound {
+        lease: Lease,
+        renew_at: Instant,
+    },
+}
+
+pub struct Client {
+    mac: MacAddr,
+    xid: u32,
+    state: State,
+    /// When the last message went, if there's one waiting for an answer.
+    last_sent: Option<Instant>,
+}
+
+impl Client {
+    pub fn new(mac: MacAddr, now: Instant) -> Client {
+        let mut client = Client {
+            mac,
+            xid: 0,
+            state: State::Discovering,
+            last_sent: None,
+        };
+        client.new_xid(now);
+        client
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        match &self.state {
+            State::Bound { lease, .. } => Some(lease),
+            _ => None,
+        }
+    }
+
+    /// Something made up for each exchange, so replies to old ones are ignored.
+    fn new_xid(&mut self, now: Instant) {
+        let [_, _, a, b, c, d] = self.mac.0;
+        let ticks = now.to_mtime().unwrap_or(0) as u32;
+        self.xid = u32::from_be_bytes([a, b, c, d]) ^ ticks ^ self.xid.rotate_left(7);
+    }
+
+    /// The next message to broadcast, if it's time.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let due = match self.last_sent {
+            Some(sent) => now.saturating_duration_since(sent) >= RETRY,
+            None => true,
+        };
+        match &mut self.state {
+            State::Bound { lease, renew_at } => {
+                if now < *renew_at {
+                    return None;
+                }
+                self.state = State::Requesting {
+                    address: lease.address,
+                    server: lease.server,
+                    sent: 0,
+                };
+                self.new_xid(now);
+            }
+            State::Requesting { sent, .. } if due && *sent >= MAX_REQUESTS => {
+                self.state = State::Discovering;
+                self.new_xid(now);
+            }
+            _ if !due => return None,
+            _ => {}
+        }
+
+        self.last_sent = Some(now);
+        let message = match &mut self.state {
+            State::Discovering => self.message(DISCOVER, &[]),
+            State::Requesting {
+                address,
+                server,
+                sent,
+            } => {
+                *sent += 1;
+                let (address, server) = (address.0, server.0);
+                self.message(
+                    REQUEST,
+                    &[
+                        (OPTION_REQUESTED_ADDRESS, &address),
+                        (OPTION_SERVER_ID, &server),
+                    ],
+                )
+            }
+            State::Bound { .. } => unreachable!(),
+        };
+        Some(message)
+    }
+
+    /// Take a message sent to [`CLIENT_PORT`].
+    pub fn receive(&mut self, payload: &[u8], now: Instant) -> Option<Event> {
+        let reply = Reply::parse(payload)?;
+        if reply.xid != self.xid || reply.chaddr != self.mac {
+            return None;
+        }
+        match (&self.state, reply.kind) {
+            (State::Discovering, OFFER) => {
+                self.state = State::Requesting {
+                    address: reply.address,
+                    server: reply.server?,
+                    sent: 0,
+                };
+                // Ask for it straight away.
+                self.last_sent = None;
+                None
+            }
+            (State::Requesting { .. }, ACK) => {
+                let lease = Lease {
+                    address: reply.address,
+                    prefix: reply.prefix.unwrap_or(24),
+                    router: reply.router,
+                    dns: reply.dns,
+                    server: reply.server?,
+                    duration: reply.lease.unwrap_or(Duration::from_secs(3600)),
+                };
+                self.state = State::Bound {
+                    lease,
+                    renew_at: now + lease.duration / 2,
+                };
+                self.last_sent = None;
+                Some(Event::Bound(lease))
+            }
+            (State::Requesting { .. }, NAK) => {
+                self.state = State::Discovering;
+                self.new_xid(now);
+                self.last_sent = None;
+                Some(Event::Lost)
+            }
+            _ => None,
+        }
+    }
+
+    fn message(&self, kind: u8, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut message = vec![0; OPTIONS];
+        message[..4].copy_from_slice(&[BOOTREQUEST, 1, 6, 0]);
+        message[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        // Broadcast the reply, we can't take unicast yet.
+        message[10] = 0x80;
+        message[28..34].copy_from_slice(&self.mac.0);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+        for (option, value) in options {
+            message.extend_from_slice(&[*option, value.len() as u8]);
+            message.extend_from_slice(value);
+        }
+        message.extend_from_slice(&[
+            OPTION_PARAMETERS,
+            4,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS,
+            OPTION_LEASE_TIME,
+            OPTION_END,
+        ]);
+        message
+    }
+}
+
+/// The parts of a server's message we care about.
+struct Reply {
+    xid: u32,
+    chaddr: MacAddr,
+    /// `yiaddr`: what the server's giving us.
+    address: Ipv4Addr,
+    kind: u8,
+    server: Option<Ipv4Addr>,
+    prefix: Option<u8>,
+    router: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease: Option<Duration>,
+}
+
+impl Reply {
+    fn parse(message: &[u8]) -> Option<Reply> {
+        if message.len() < OPTIONS || message[0] != BOOTREPLY || message[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let addr = |bytes: &[u8]| Some(Ipv4Addr(bytes.get(..4)?.try_into().ok()?));
+        let mut reply = Reply {
+            xid: u32::from_be_bytes(message[4..8].try_into().unwrap()),
+            chaddr: MacAddr(message[28..34].try_into().unwrap()),
+            address: addr(&message[16..])?,
+            kind: 0,
+            server: None,
+            prefix: None,
+            router: None,
+            dns: None,
+            lease: None,
+        };
+
+        let mut options = &message[OPTIONS..];
+        while let [option, rest @ ..] = options {
+            match *option {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first()?;
+            let value = rest.get(..len as usize)?;
+            match *option {
+                OPTION_MESSAGE_TYPE => reply.kind = *value.first()?,
+                OPTION_SERVER_ID => reply.server = addr(value),
+                OPTION_SUBNET_MASK => {
+                    reply.prefix = addr(value).map(|mask| mask.to_u32().count_ones() as u8)
+                }
+                OPTION_ROUTER => reply.router = addr(value),
+                OPTION_DNS => reply.dns = addr(value),
+                OPTION_LEASE_TIME => {
+                    let secs = u32::from_be_bytes(value.get(..4)?.try_into().ok()?);
+                    reply.lease = Some(Duration::from_secs(secs as u64));
+                }
+                _ => {}
+            }
+            options = &rest[len as usize..];
+        }
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    const SERVER: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+    const ADDRESS: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+
+    /// What a server would send back to `request`.
+    fn reply(request: &[u8], kind: u8) -> Vec<u8> {
+        let mut reply = request[..OPTIONS].to_vec();
+        reply[0] = BOOTREPLY;
+        reply[16..20].copy_from_slice(&ADDRESS.0);
+        reply.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind, OPTION_PAD]);
+        reply.extend_from_slice(&[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+        reply.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+        reply.extend_from_slice(&[OPTION_ROUTER, 4, 10, 0, 2, 2]);
+        reply.extend_from_slice(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+        reply.push(OPTION_END);
+        reply
+    }
+
+    #[test_case]
+    fn dhcp_exchange() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+        let discover = client.poll(now).unwrap();
+        assert_eq!(
+            discover[OPTIONS..OPTIONS + 3],
+            [OPTION_MESSAGE_TYPE, 1, DISCOVER]
+        );
+        assert!(client.poll(now).is_none());
+
+        assert_eq!(client.receive(&reply(&discover, OFFER), now), None);
+        let request = client.poll(now).unwrap();
+        assert_eq!(request[OPTIONS + 2], REQUEST);
+
+        // Someone else's.
+        let mut other = reply(&request, ACK);
+        other[4] ^= 1;
+        assert_eq!(client.receive(&other, now), None);
+
+        let lease = Lease {
+            address: ADDRESS,
+            prefix: 24,
+            router: Some(SERVER),
+            dns: None,
+            server: SERVER,
+            duration: Duration::from_secs(3600),
+        };
+        assert_eq!(
+            client.receive(&reply(&request, ACK), now),
+            Some(Event::Bound(lease))
+        );
+        assert_eq!(client.lease(), Some(&lease));
+        assert!(client.poll(now + Duration::from_secs(60)).is_none());
+        let renew = client.poll(now + Duration::from_secs(1800)).unwrap();
+        assert_eq!(renew[OPTIONS + 2], REQUEST);
+    }
+}
@@ -0,0 +1,535 @@
+//! Networking: one Ethernet interface, IPv4 on it, UDP sockets and [`tcp`].
+//!
+//! A network driver hands its [`NetDevice`] to [`add_device`], which makes it the
+//! [`Interface`] and starts a task to look after it, and the [`telnet`] server. The task
+//! takes frames off the device, answers ARP and pings, gets an address with [`dhcp`],
+//! queues UDP datagrams for the [`UdpSocket`] bound to their port, and gives TCP segments
+//! to [`tcp`]. Drivers call [`wake`] from their interrupt handler when frames arrive.
+//!
+//! Packets to an address we don't know the MAC of wait for an ARP reply, a few at a time.
+//! Fragmented IP packets are dropped.
+
+pub mod dhcp;
+pub mod tcp;
+pub mod telnet;
+pub mod wire;
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::{
+    fmt::{self, Display, Formatter},
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use crate::{
+    log,
+    prelude::*,
+    sync::IrqSafeMutex,
+    task,
+    time::{self, Instant},
+};
+use dhcp::Event;
+use wire::{Arp, Echo, Ethernet, Ipv4, Ipv4Addr, MacAddr, Tcp, Udp};
+
+/// How long the task sleeps at most, so DHCP gets to retry.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets held for each address we're waiting on ARP for.
+const MAX_WAITING: usize = 4;
+/// Datagrams a socket holds before dropping new ones.
+const MAX_QUEUED: usize = 64;
+/// Echo replies kept for [`ping`] to find.
+const MAX_ECHO_REPLIES: usize = 16;
+/// Where ports for [`UdpSocket::bind`] with port 0 come from.
+const EPHEMERAL_PORTS: core::ops::Range<u16> = 49152..65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No network device.
+    NoInterface,
+    /// No address yet.
+    NotConfigured,
+    AddressInUse(u16),
+    /// Bigger than fits in a frame.
+    TooBig,
+    /// The device's transmit queue is full.
+    Busy,
+    OutOfMemory,
+    ConnectionReset,
+}
+
+impl Display for NetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::NoInterface => write!(f, "no network interface"),
+            NetError::NotConfigured => write!(f, "no IP address"),
+            NetError::AddressInUse(port) => write!(f, "port {} in use", port),
+            NetError::TooBig => write!(f, "packet too big"),
+            NetError::Busy => write!(f, "transmit queue full"),
+            NetError::OutOfMemory => write!(f, "out of memory"),
+            NetError::ConnectionReset => write!(f, "connection reset"),
+        }
+    }
+}
+
+impl core::error::Error for NetError {}
+
+/// A network card, as far as the stack's concerned. Frames start at the Ethernet header.
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddr;
+    /// Largest frame it can send, header included.
+    fn max_frame(&self) -> usize;
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+    /// The next frame that came in, if there is one.
+    fn receive(&self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: Ipv4Addr,
+    /// Netmask length.
+    pub prefix: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    mac: MacAddr,
+    config: Option<Ipv4Config>,
+    dhcp: dhcp::Client,
+    arp: BTreeMap<Ipv4Addr, MacAddr>,
+    /// IP packets waiting on an ARP reply, by the address they're going to.
+    waiting: BTreeMap<Ipv4Addr, Vec<Vec<u8>>>,
+    next_id: u16,
+    /// `(from, ident, seq)` of echo replies, for [`ping`].
+    echo_replies: VecDeque<(Ipv4Addr, u16, u16)>,
+}
+
+static INTERFACE: Mutex<Option<Interface>> = Mutex::new(None);
+static SOCKETS: Mutex<BTreeMap<u16, Arc<SocketQueue>>> = Mutex::new(BTreeMap::new());
+static NEXT_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS.start);
+
+/// Set by [`wake`]. The task runs again as soon as it sees it.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+static TASK_WAKER: IrqSafeMutex<Option<Waker>> = IrqSafeMutex::new(None);
+/// Woken when an echo reply comes in.
+static ECHO_WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Use `device` as the network interface. Only the first one is used.
+pub fn add_device(device: Arc<dyn NetDevice>) {
+    let mut interface = INTERFACE.lock();
+    if interface.is_some() {
+        log::warn!("net: already have an interface, not using {}", device.mac());
+        return;
+    }
+    let mac = device.mac();
+    log::info!("net: eth0 is {}", mac);
+    *interface = Some(Interface {
+        device,
+        mac,
+        config: None,
+        dhcp: dhcp::Client::new(mac, Instant::now()),
+        arp: BTreeMap::new(),
+        waiting: BTreeMap::new(),
+        next_id: 0,
+        echo_replies: VecDeque::new(),
+    });
+    drop(interface);
+    task::spawn(run());
+    telnet::start();
+}
+
+/// Let the task know there are frames to look at. Fine from an interrupt handler.
+pub fn wake() {
+    WOKEN.store(true, Ordering::Release);
+    if let Some(waker) = TASK_WAKER.lock().take() {
+        waker.wake();
+    }
+}
+
+/// The interface's MAC address and IP configuration, if there is an interface.
+pub fn status() -> Option<(MacAddr, Option<Ipv4Config>)> {
+    INTERFACE
+        .lock()
+        .as_ref()
+        .map(|interface| (interface.mac, interface.config))
+}
+
+async fn run() {
+    loop {
+        if let Some(interface) = INTERFACE.lock().as_mut() {
+            interface.poll(Instant::now());
+        }
+        let woken = poll_fn(|cx| {
+            *TASK_WAKER.lock() = Some(cx.waker().clone());
+            if WOKEN.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        time::timeout(POLL_INTERVAL, woken).await.ok();
+    }
+}
+
+impl Interface {
+    fn poll(&mut self, now: Instant) {
+        while let Some(frame) = self.device.receive() {
+            self.receive(&frame, now);
+        }
+        if let Some(message) = self.dhcp.poll(now) {
+            self.send_dhcp(&message);
+        }
+        if let Some(address) = self.address() {
+            for (dst, segment) in tcp::poll(address, now) {
+                self.send_ipv4(dst, wire::PROTOCOL_TCP, &segment).ok();
+            }
+        }
+    }
+
+    fn receive(&mut self, frame: &[u8], now: Instant) {
+        let Some(ethernet) = Ethernet::parse(frame) else {
+            return;
+        };
+        if ethernet.dst != self.mac && ethernet.dst != MacAddr::BROADCAST {
+            return;
+        }
+        match ethernet.ethertype {
+            wire::ETHERTYPE_ARP => {
+                if let Some(arp) = Arp::parse(ethernet.payload) {
+                    self.receive_arp(arp);
+                }
+            }
+            wire::ETHERTYPE_IPV4 => {
+                if let Some(ip) = Ipv4::parse(ethernet.payload) {
+                    self.receive_ipv4(ip, now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn address(&self) -> Option<Ipv4Addr> {
+        self.config.map(|config| config.address)
+    }
+
+    fn receive_arp(&mut self, arp: Arp) {
+        let Some(address) = self.address() else {
+            return;
+        };
+        if arp.target_ip != address {
+            return;
+        }
+        self.learn(arp.sender_ip, arp.sender_mac);
+        if arp.operation == wire::ARP_REQUEST {
+            let reply = Arp {
+                operation: wire::ARP_REPLY,
+                sender_mac: self.mac,
+                sender_ip: address,
+                target_mac: arp.sender_mac,
+                target_ip: arp.sender_ip,
+            };
+            self.send_frame(arp.sender_mac, wire::ETHERTYPE_ARP, &reply.to_bytes());
+        }
+    }
+
+    /// Remember `mac` is where `ip` is, and send anything that was waiting to go there.
+    fn learn(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        self.arp.insert(ip, mac);
+        for packet in self.waiting.remove(&ip).unwrap_or_default() {
+            self.send_frame(mac, wire::ETHERTYPE_IPV4, &packet);
+        }
+    }
+
+    fn receive_ipv4(&mut self, ip: Ipv4<'_>, now: Instant) {
+        let ours = match self.config {
+            Some(config) => ip.dst == config.address || ip.dst == Ipv4Addr::BROADCAST,
+            // Only DHCP until there's an address.
+            None => ip.protocol == wire::PROTOCOL_UDP,
+        };
+        if !ours {
+            return;
+        }
+        match ip.protocol {
+            wire::PROTOCOL_ICMP => {
+                if let Some(echo) = Echo::parse(ip.payload) {
+                    self.receive_echo(ip.src, echo);
+                }
+            }
+            wire::PROTOCOL_UDP => {
+                if let Some(udp) = Udp::parse(ip.payload, ip.src, ip.dst) {
+                    self.receive_udp(ip.src, udp, now);
+                }
+            }
+            wire::PROTOCOL_TCP if ip.dst != Ipv4Addr::BROADCAST => {
+                if let Some(segment) = Tcp::parse(ip.payload, ip.src, ip.dst) {
+                    if let Some(reset) = tcp::receive(ip.dst, ip.src, &segment, now) {
+                        self.send_ipv4(ip.src, wire::PROTOCOL_TCP, &reset).ok();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_echo(&mut self, from: Ipv4Addr, echo: Echo<'_>) {
+        match echo.kind {
+            wire::ICMP_ECHO_REQUEST => {
+                let reply = Echo {
+                    kind: wire::ICMP_ECHO_REPLY,
+                    ..echo
+                };
+                self.send_ipv4(from, wire::PROTOCOL_ICMP, &reply.to_bytes())
+                    .ok();
+            }
+            _ => {
+                if self.echo_replies.len() == MAX_ECHO_REPLIES {
+                    self.echo_replies.pop_front();
+                }
+                self.echo_replies.push_back((from, echo.ident, echo.seq));
+                for waker in ECHO_WAKERS.lock().drain(..) {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    fn receive_udp(&mut self, from: Ipv4Addr, udp: Udp<'_>, now: Instant) {
+        if udp.dst_port == dhcp::CLIENT_PORT {
+            match self.dhcp.receive(udp.payload, now) {
+                Some(Event::Bound(lease)) => {
+                    if self.address() != Some(lease.address) {
+                        log::info!("net: eth0 is {}/{}", lease.address, lease.prefix);
+                    }
+                    self.config = Some(Ipv4Config {
+                        address: lease.address,
+                        prefix: lease.prefix,
+                        gateway: lease.router,
+                        dns: lease.dns,
+                    });
+                }
+                Some(Event::Lost) => {
+                    log::warn!("net: lost the lease on eth0's address");
+                    self.config = None;
+                }
+                None => {}
+            }
+            return;
+        }
+        if self.config.is_none() {
+            return;
+        }
+        let socket = SOCKETS.lock().get(&udp.dst_port).cloned();
+        if let Some(socket) = socket {
+            socket.push(from, udp.src_port, udp.payload);
+        }
+    }
+
+    fn send_frame(&self, dst: MacAddr, ethertype: u16, payload: &[u8]) {
+        let frame = wire::ethernet(dst, self.mac, ethertype, payload);
+        if let Err(err) = self.device.send(&frame) {
+            log::debug!("net: dropped a frame: {}", err);
+        }
+    }
+
+    fn send_dhcp(&mut self, message: &[u8]) {
+        let datagram = wire::udp(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+            dhcp::CLIENT_PORT,
+            dhcp::SERVER_PORT,
+            message,
+        );
+        let packet = self.ipv4_packet(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+            wire::PROTOCOL_UDP,
+            &datagram,
+        );
+        self.send_frame(MacAddr::BROADCAST, wire::ETHERTYPE_IPV4, &packet);
+    }
+
+    fn ipv4_packet(
+        &mut self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        self.next_id = self.next_id.wrapping_add(1);
+        wire::ipv4(src, dst, protocol, self.next_id, payload)
+    }
+
+    /// Send an IP packet from our address, finding the MAC to send it to first if needed.
+    fn send_ipv4(&mut self, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+        let config = self.config.ok_or(NetError::NotConfigured)?;
+        let packet = self.ipv4_packet(config.address, dst, protocol, payload);
+        if packet.len() + wire::ETHERNET_HEADER > self.device.max_frame() {
+            return Err(NetError::TooBig);
+        }
+        if dst == Ipv4Addr::BROADCAST {
+            self.send_frame(MacAddr::BROADCAST, wire::ETHERTYPE_IPV4, &packet);
+            return Ok(());
+        }
+
+        let next_hop = match config.gateway {
+            Some(gateway) if !dst.same_network(config.address, config.prefix) => gateway,
+            _ => dst,
+        };
+        if let Some(&mac) = self.arp.get(&next_hop) {
+            self.send_frame(mac, wire::ETHERTYPE_IPV4, &packet);
+            return Ok(());
+        }
+
+        let waiting = self.waiting.entry(next_hop).or_default();
+        if waiting.len() < MAX_WAITING {
+            waiting.push(packet);
+        }
+        let request = Arp {
+            operation: wire::ARP_REQUEST,
+            sender_mac: self.mac,
+            sender_ip: config.address,
+            target_mac: MacAddr::default(),
+            target_ip: next_hop,
+        };
+        self.send_frame(MacAddr::BROADCAST, wire::ETHERTYPE_ARP, &request.to_bytes());
+        Ok(())
+    }
+}
+
+fn with_interface<R>(f: impl FnOnce(&mut Interface) -> R) -> Result<R, NetError> {
+    INTERFACE
+        .lock()
+        .as_mut()
+        .map(f)
+        .ok_or(NetError::NoInterface)
+}
+
+/// Datagrams for one port.
+struct SocketQueue {
+    datagrams: Mutex<VecDeque<(Ipv4Addr, u16, Vec<u8>)>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl SocketQueue {
+    fn push(&self, from: Ipv4Addr, port: u16, payload: &[u8]) {
+        let mut datagrams = self.datagrams.lock();
+        if datagrams.len() < MAX_QUEUED {
+            datagrams.push_back((from, port, payload.to_vec()));
+        }
+        drop(datagrams);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A UDP port. Closed when dropped.
+pub struct UdpSocket {
+    port: u16,
+    queue: Arc<SocketQueue>,
+}
+
+impl UdpSocket {
+    /// Take `port`, or some free port if it's 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => (0..EPHEMERAL_PORTS.len())
+                .map(|_| {
+                    let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+                    EPHEMERAL_PORTS.start + port % EPHEMERAL_PORTS.len() as u16
+                })
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse(0))?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse(port)),
+            port => port,
+        };
+        let queue = Arc::new(SocketQueue {
+            datagrams: Mutex::new(VecDeque::new()),
+            waker: Mutex::new(None),
+        });
+        sockets.insert(port, queue.clone());
+        Ok(UdpSocket { port, queue })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, payload: &[u8], to: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        with_interface(|interface| {
+            let from = interface.address().ok_or(NetError::NotConfigured)?;
+            let datagram = wire::udp(from, to, self.port, port, payload);
+            interface.send_ipv4(to, wire::PROTOCOL_UDP, &datagram)
+        })?
+    }
+
+    /// The next datagram, and who sent it, if one's come in.
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, Ipv4Addr, u16)> {
+        let (from, port, payload) = self.queue.datagrams.lock().pop_front()?;
+        Some((payload, from, port))
+    }
+
+    /// Wait for the next datagram.
+    pub async fn recv_from(&self) -> (Vec<u8>, Ipv4Addr, u16) {
+        poll_fn(|cx| {
+            *self.queue.waker.lock() = Some(cx.waker().clone());
+            match self.try_recv_from() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+/// Send an echo request to `to` and wait up to `wait` for the reply. Returns how long it
+/// took.
+pub async fn ping(
+    to: Ipv4Addr,
+    ident: u16,
+    seq: u16,
+    wait: Duration,
+) -> Result<Option<Duration>, NetError> {
+    let start = Instant::now();
+    let request = Echo {
+        kind: wire::ICMP_ECHO_REQUEST,
+        ident,
+        seq,
+        data: b"adeline-os ping",
+    };
+    with_interface(|interface| interface.send_ipv4(to, wire::PROTOCOL_ICMP, &request.to_bytes()))??;
+
+    let reply = poll_fn(|cx| {
+        ECHO_WAKERS.lock().push(cx.waker().clone());
+        let found = with_interface(|interface| {
+            let replies = &mut interface.echo_replies;
+            let index = replies
+                .iter()
+                .position(|&reply| reply == (to, ident, seq))?;
+            replies.remove(index)
+        });
+        match found {
+            Ok(Some(_)) => Poll::Ready(()),
+            _ => Poll::Pending,
+        }
+    });
+    match time::timeout(wait, reply).await {
+        Ok(()) => Ok(Some(start.elapsed())),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,610 @@
+//! TCP, enough to serve a shell: passive opens only, in-order delivery, and going back to
+//! the first unacknowledged byte when nothing's been acked for a while.
+//!
+//! [`TcpListener::bind`] takes a port and [`TcpListener::accept`] waits for connections to
+//! it. The network task hands every segment to [`receive`] and then calls [`poll`] for
+//! what to send. Segments that arrive out of order are dropped for the sender to try
+//! again. There's no TIME-WAIT: a connection is forgotten once both sides have closed.
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::{
+    future::poll_fn,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use spin::Mutex;
+
+use super::{
+    wire::{self, Ipv4Addr, Tcp},
+    NetError,
+};
+use crate::{prelude::*, rand, time::Instant};
+
+/// Largest segment we send when the other side doesn't say.
+const DEFAULT_MSS: usize = 536;
+/// Largest segment we ask the other side to send: a whole Ethernet frame.
+const OUR_MSS: u16 = 1460;
+const RECEIVE_BUFFER: usize = 8192;
+const SEND_BUFFER: usize = 16384;
+/// How long unacknowledged data waits before it's sent again.
+const RETRANSMIT: Duration = Duration::from_secs(1);
+/// Times it's sent again before giving up on the connection.
+const MAX_RETRIES: u32 = 8;
+/// Connections waiting to be accepted, per listener.
+const BACKLOG: usize = 4;
+
+/// Segments to send, and who to.
+pub type Segments = Vec<(Ipv4Addr, Vec<u8>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    remote: Ipv4Addr,
+    remote_port: u16,
+    local_port: u16,
+}
+
+static LISTENERS: Mutex<BTreeMap<u16, Arc<Listener>>> = Mutex::new(BTreeMap::new());
+static CONNECTIONS: Mutex<BTreeMap<Key, Arc<Connection>>> = Mutex::new(BTreeMap::new());
+
+struct Listener {
+    pending: Mutex<VecDeque<Arc<Connection>>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+struct Connection {
+    key: Key,
+    tcb: Mutex<Tcb>,
+}
+
+/// Everything about one connection.
+struct Tcb {
+    /// Our SYN has been acked.
+    established: bool,
+    /// Our initial sequence number, which the SYN takes.
+    iss: u32,
+    /// Oldest sequence number not acked. `send[0]` once established.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// Highest sequence number sent, which `snd_nxt` goes back from to resend.
+    snd_max: u32,
+    /// How much past `snd_una` the other side will take.
+    snd_wnd: usize,
+    mss: usize,
+    /// Next sequence number we expect.
+    rcv_nxt: u32,
+    /// Written and not yet acked.
+    send: VecDeque<u8>,
+    /// Received and not yet read.
+    received: VecDeque<u8>,
+    /// Closed on our side: send a FIN once `send` is out.
+    closing: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    /// The other side sent a FIN.
+    peer_closed: bool,
+    reset: bool,
+    /// Something came in that needs acknowledging.
+    ack_due: bool,
+    /// When the oldest unacknowledged segment went.
+    last_sent: Option<Instant>,
+    retries: u32,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl Tcb {
+    fn new(syn: &Tcp<'_>) -> Tcb {
+        // Random, so segments from an old connection can't be mistaken for this one's.
+        let iss = rand::u64() as u32;
+        Tcb {
+            established: false,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: syn.window as usize,
+            mss: syn.mss.map_or(DEFAULT_MSS, usize::from),
+            rcv_nxt: syn.seq.wrapping_add(1),
+            send: VecDeque::new(),
+            received: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            fin_acked: false,
+            peer_closed: false,
+            reset: false,
+            ack_due: false,
+            last_sent: None,
+            retries: 0,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER - self.received.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// Both sides have closed, or it was reset.
+    fn finished(&self) -> bool {
+        self.reset || (self.fin_acked && self.peer_closed)
+    }
+
+    fn wake(&mut self) {
+        for waker in [self.reader.take(), self.writer.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+
+    fn segment<'a>(&self, key: Key, seq: u32, flags: u8, payload: &'a [u8]) -> Tcp<'a> {
+        Tcp {
+            src_port: key.local_port,
+            dst_port: key.remote_port,
+            seq,
+            ack: self.rcv_nxt,
+            flags: flags | wire::TCP_ACK,
+            window: self.window(),
+            mss: None,
+            payload,
+        }
+    }
+
+    fn sent_up_to(&mut self, seq: u32) {
+        self.snd_nxt = seq;
+        if (seq.wrapping_sub(self.snd_max) as i32) > 0 {
+            self.snd_max = seq;
+        }
+    }
+
+    /// Take in a segment for this connection. Returns true if it finished the handshake.
+    fn receive(&mut self, segment: &Tcp<'_>, now: Instant) -> bool {
+        if segment.flags & wire::TCP_RST != 0 {
+            if segment.seq == self.rcv_nxt {
+                self.reset = true;
+                self.wake();
+            }
+            return false;
+        }
+        if segment.flags & wire::TCP_SYN != 0 {
+            // Our SYN-ACK got lost, or theirs is a duplicate.
+            if !self.established {
+                self.snd_nxt = self.iss;
+            }
+            self.ack_due = true;
+            return false;
+        }
+        if segment.flags & wire::TCP_ACK == 0 {
+            return false;
+        }
+
+        let mut handshake = false;
+        let acked = segment.ack.wrapping_sub(self.snd_una);
+        if acked > 0 && acked <= self.snd_max.wrapping_sub(self.snd_una) {
+            let mut acked = acked as usize;
+            if !self.established {
+                self.established = true;
+                handshake = true;
+                acked -= 1;
+            }
+            let data = acked.min(self.send.len());
+            self.send.drain(..data);
+            if acked > data {
+                self.fin_acked = true;
+                self.fin_sent = true;
+            }
+            self.snd_una = segment.ack;
+            // It may have been acked while we were going back to resend it.
+            if (self.snd_nxt.wrapping_sub(self.snd_una) as i32) < 0 {
+                self.snd_nxt = self.snd_una;
+            }
+            self.retries = 0;
+            self.last_sent = (self.snd_nxt != self.snd_una).then_some(now);
+            if let Some(writer) = self.writer.take() {
+                writer.wake();
+            }
+        }
+        if !self.established {
+            return false;
+        }
+        self.snd_wnd = segment.window as usize;
+
+        let fin = segment.flags & wire::TCP_FIN != 0;
+        if segment.payload.is_empty() && !fin {
+            return handshake;
+        }
+        if segment.seq == self.rcv_nxt && !self.peer_closed {
+            let take = segment.payload.len().min(self.window() as usize);
+            self.received.extend(&segment.payload[..take]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(take as u32);
+            if fin && take == segment.payload.len() {
+                self.peer_closed = true;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            }
+            if let Some(reader) = self.reader.take() {
+                reader.wake();
+            }
+        }
+        self.ack_due = true;
+        handshake
+    }
+
+    /// Add whatever this connection has to send to `out`.
+    fn output(&mut self, key: Key, local: Ipv4Addr, now: Instant, out: &mut Segments) {
+        if self.reset {
+            return;
+        }
+        let mut push =
+            |segment: Tcp<'_>| out.push((key.remote, segment.to_bytes(local, key.remote)));
+
+        if let Some(sent) = self.last_sent {
+            if now.saturating_duration_since(sent) >= RETRANSMIT {
+                self.retries += 1;
+                if self.retries > MAX_RETRIES {
+                    push(self.segment(key, self.snd_nxt, wire::TCP_RST, &[]));
+                    self.reset = true;
+                    self.wake();
+                    return;
+                }
+                // Send everything unacknowledged again.
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                self.last_sent = None;
+            }
+        }
+
+        if !self.established {
+            if self.snd_nxt == self.iss {
+                let mut syn = self.segment(key, self.iss, wire::TCP_SYN, &[]);
+                syn.mss = Some(OUR_MSS);
+                push(syn);
+                self.sent_up_to(self.iss.wrapping_add(1));
+                self.last_sent.get_or_insert(now);
+                self.ack_due = false;
+            }
+            return;
+        }
+
+        // A closed window gets a byte at a time, so we hear when it opens.
+        let window = self.snd_wnd.max(1);
+        let mut sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        while !self.fin_sent && sent < self.send.len() && sent < window {
+            let len = (self.send.len() - sent).min(self.mss).min(window - sent);
+            let payload: Vec<u8> = self.send.range(sent..sent + len).copied().collect();
+            let seq = self.snd_una.wrapping_add(sent as u32);
+            push(self.segment(key, seq, wire::TCP_PSH, &payload));
+            sent += len;
+            self.sent_up_to(self.snd_una.wrapping_add(sent as u32));
+            self.last_sent.get_or_insert(now);
+            self.ack_due = false;
+        }
+        if self.closing && !self.fin_sent && sent == self.send.len() {
+            push(self.segment(key, self.snd_nxt, wire::TCP_FIN, &[]));
+            self.sent_up_to(self.snd_nxt.wrapping_add(1));
+            self.fin_sent = true;
+            self.last_sent.get_or_insert(now);
+            self.ack_due = false;
+        }
+        if self.ack_due {
+            push(self.segment(key, self.snd_nxt, 0, &[]));
+            self.ack_due = false;
+        }
+    }
+}
+
+/// The reset for a segment nobody wants (RFC 793, section 3.4).
+fn refuse(local: Ipv4Addr, remote: Ipv4Addr, segment: &Tcp<'_>) -> Vec<u8> {
+    let (seq, ack, flags) = if segment.flags & wire::TCP_ACK != 0 {
+        (segment.ack, 0, wire::TCP_RST)
+    } else {
+        let len = segment.payload.len()
+            + (segment.flags & wire::TCP_SYN != 0) as usize
+            + (segment.flags & wire::TCP_FIN != 0) as usize;
+        (
+            0,
+            segment.seq.wrapping_add(len as u32),
+            wire::TCP_RST | wire::TCP_ACK,
+        )
+    };
+    Tcp {
+        src_port: segment.dst_port,
+        dst_port: segment.src_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        payload: &[],
+    }
+    .to_bytes(local, remote)
+}
+
+/// Take a segment sent to us at `local`. Returns a reset if nothing wanted it; anything
+/// else to send comes from the next [`poll`].
+pub fn receive(
+    local: Ipv4Addr,
+    remote: Ipv4Addr,
+    segment: &Tcp<'_>,
+    now: Instant,
+) -> Option<Vec<u8>> {
+    let key = Key {
+        remote,
+        remote_port: segment.src_port,
+        local_port: segment.dst_port,
+    };
+    let connection = CONNECTIONS.lock().get(&key).cloned();
+    if let Some(connection) = connection {
+        let mut tcb = connection.tcb.lock();
+        if tcb.receive(segment, now) {
+            let listener = LISTENERS.lock().get(&key.local_port).cloned();
+            match listener {
+                Some(listener) => listener.push(connection.clone()),
+                None => {
+                    tcb.reset = true;
+                    return Some(refuse(local, remote, segment));
+                }
+            }
+        }
+        return None;
+    }
+
+    if segment.flags & wire::TCP_RST != 0 {
+        return None;
+    }
+    let listener = LISTENERS.lock().get(&segment.dst_port).cloned();
+    match listener {
+        Some(listener)
+            if segment.flags & (wire::TCP_SYN | wire::TCP_ACK) == wire::TCP_SYN
+                && listener.pending.lock().len() < BACKLOG =>
+        {
+            let connection = Arc::new(Connection {
+                key,
+                tcb: Mutex::new(Tcb::new(segment)),
+            });
+            CONNECTIONS.lock().insert(key, connection);
+            None
+        }
+        _ => Some(refuse(local, remote, segment)),
+    }
+}
+
+/// What every connection has to send, from `local`. Forgets the ones that are finished.
+pub fn poll(local: Ipv4Addr, now: Instant) -> Segments {
+    let mut out = Vec::new();
+    CONNECTIONS.lock().retain(|_, connection| {
+        let mut tcb = connection.tcb.lock();
+        tcb.output(connection.key, local, now, &mut out);
+        !tcb.finished()
+    });
+    out
+}
+
+impl Listener {
+    fn push(&self, connection: Arc<Connection>) {
+        self.pending.lock().push_back(connection);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A port taking connections. Stops when dropped, leaving the ones already accepted.
+pub struct TcpListener {
+    port: u16,
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse(port));
+        }
+        let listener = Arc::new(Listener {
+            pending: Mutex::new(VecDeque::new()),
+            waker: Mutex::new(None),
+        });
+        listeners.insert(port, listener.clone());
+        Ok(TcpListener { port, listener })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next connection.
+    pub async fn accept(&self) -> TcpStream {
+        poll_fn(|cx| {
+            *self.listener.waker.lock() = Some(cx.waker().clone());
+            match self.listener.pending.lock().pop_front() {
+                Some(connection) => Poll::Ready(TcpStream { connection }),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.port);
+    }
+}
+
+/// One connection. Closed when dropped.
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Who's on the other end.
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        let key = self.connection.key;
+        (key.remote, key.remote_port)
+    }
+
+    /// Read what's come in, or register to be woken when something does. `Ok(0)` means
+    /// the other side has closed.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, NetError>> {
+        let mut tcb = self.connection.tcb.lock();
+        if !tcb.received.is_empty() {
+            let was_closed = (tcb.window() as usize) < tcb.mss;
+            let len = buf.len().min(tcb.received.len());
+            for (to, from) in buf.iter_mut().zip(tcb.received.drain(..len)) {
+                *to = from;
+            }
+            if was_closed {
+                // Tell them there's room again.
+                tcb.ack_due = true;
+                super::wake();
+            }
+            return Poll::Ready(Ok(len));
+        }
+        if tcb.reset {
+            return Poll::Ready(Err(NetError::ConnectionReset));
+        }
+        if tcb.peer_closed {
+            return Poll::Ready(Ok(0));
+        }
+        tcb.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+
+    /// Queue as much of `data` as there's room for, or register to be woken when there's
+    /// room.
+    pub fn poll_write(&self, cx: &mut Context<'_>, data: &[u8]) -> Poll<Result<usize, NetError>> {
+        let mut tcb = self.connection.tcb.lock();
+        if tcb.reset {
+            return Poll::Ready(Err(NetError::ConnectionReset));
+        }
+        let len = data.len().min(SEND_BUFFER - tcb.send.len());
+        if len == 0 && !data.is_empty() {
+            tcb.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        tcb.send.extend(&data[..len]);
+        drop(tcb);
+        super::wake();
+        Poll::Ready(Ok(len))
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let len = poll_fn(|cx| self.poll_write(cx, data)).await?;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Send a FIN once everything written has gone. Reading still works until the other
+    /// side closes too.
+    pub fn close(&self) {
+        self.connection.tcb.lock().closing = true;
+        super::wake();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+    const REMOTE: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+    const PORT: u16 = 4023;
+
+    fn from_remote(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        Tcp {
+            src_port: 40000,
+            dst_port: PORT,
+            seq,
+            ack,
+            flags,
+            window: 4096,
+            mss: None,
+            payload,
+        }
+        .to_bytes(REMOTE, LOCAL)
+    }
+
+    /// Give the stack a segment and get back what it sends, parsed.
+    fn exchange(segment: &[u8], now: Instant) -> Vec<(u32, u32, u8, Vec<u8>)> {
+        let segment = Tcp::parse(segment, REMOTE, LOCAL).unwrap();
+        assert!(receive(LOCAL, REMOTE, &segment, now).is_none());
+        poll(LOCAL, now)
+            .iter()
+            .map(|(_, bytes)| {
+                let tcp = Tcp::parse(bytes, LOCAL, REMOTE).unwrap();
+                (tcp.seq, tcp.ack, tcp.flags, tcp.payload.to_vec())
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn tcp_connection() {
+        let now = Instant::now();
+        let syn = from_remote(100, 0, wire::TCP_SYN, b"");
+        let closed = Tcp::parse(&syn, REMOTE, LOCAL).unwrap();
+        let reset = receive(LOCAL, REMOTE, &closed, now).unwrap();
+        let reset = Tcp::parse(&reset, LOCAL, REMOTE).unwrap();
+        assert_eq!(
+            (reset.ack, reset.flags),
+            (101, wire::TCP_RST | wire::TCP_ACK)
+        );
+
+        let listener = TcpListener::bind(PORT).unwrap();
+        assert_eq!(
+            TcpListener::bind(PORT).err(),
+            Some(NetError::AddressInUse(PORT))
+        );
+        let sent = exchange(&from_remote(100, 0, wire::TCP_SYN, b""), now);
+        let [(iss, 101, flags, _)] = sent[..] else {
+            panic!("expected a SYN-ACK, got {:?}", sent);
+        };
+        assert_eq!(flags, wire::TCP_SYN | wire::TCP_ACK);
+        let seq = |offset| iss.wrapping_add(offset);
+
+        let sent = exchange(&from_remote(101, seq(1), wire::TCP_ACK, b"hi"), now);
+        assert_eq!(sent, [(seq(1), 103, wire::TCP_ACK, Vec::new())]);
+        let stream = task::block_on(listener.accept());
+        assert_eq!(stream.peer(), (REMOTE, 40000));
+        let mut buf = [0; 16];
+        assert_eq!(task::block_on(stream.read(&mut buf)), Ok(2));
+        assert_eq!(&buf[..2], b"hi");
+
+        task::block_on(stream.write_all(b"hello")).unwrap();
+        stream.close();
+        let sent = poll(LOCAL, now);
+        assert_eq!(sent.len(), 2);
+        let data = Tcp::parse(&sent[0].1, LOCAL, REMOTE).unwrap();
+        assert_eq!((data.seq, data.payload), (seq(1), &b"hello"[..]));
+        let fin = Tcp::parse(&sent[1].1, LOCAL, REMOTE).unwrap();
+        assert_eq!(fin.flags, wire::TCP_FIN | wire::TCP_ACK);
+
+        // Nothing acked: it all goes again.
+        let later = now + RETRANSMIT;
+        assert_eq!(poll(LOCAL, later).len(), 2);
+
+        let flags = wire::TCP_ACK | wire::TCP_FIN;
+        let sent = exchange(&from_remote(103, seq(7), flags, b""), later);
+        assert_eq!(sent, [(seq(7), 104, wire::TCP_ACK, Vec::new())]);
+        assert_eq!(task::block_on(stream.read(&mut buf)), Ok(0));
+        assert!(CONNECTIONS.lock().is_empty());
+    }
+}
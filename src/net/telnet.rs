@@ -0,0 +1,233 @@
+//! The console over telnet on port 23, and TCP echo on port 7.
+//!
+//! A telnet session is a [`ConsoleSink`]: it gets a copy of everything printed, the log
+//! included, and what's typed goes to the console's input, so the shell works the same as
+//! on the UART. One session at a time; the next waits until it's over. We ask the client
+//! to let us echo and to send characters as they're typed, and ignore everything else it
+//! negotiates.
+//!
+//! Nothing asks for a password, so `notelnet` on the command line turns both off.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+
+use super::tcp::{TcpListener, TcpStream};
+use crate::{
+    cmdline,
+    console::{self, ConsoleSink},
+    log,
+    prelude::*,
+    sync::IrqSafeMutex,
+    task,
+};
+
+const TELNET_PORT: u16 = 23;
+const ECHO_PORT: u16 = 7;
+/// Output kept for a client that isn't keeping up. The oldest goes first.
+const MAX_OUTPUT: usize = 16384;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+/// Subnegotiation, up to `IAC SE`.
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+
+/// Start both servers, unless `notelnet` is on the command line.
+pub fn start() {
+    if cmdline::flag("notelnet") {
+        return;
+    }
+    task::spawn(serve());
+    task::spawn(echo());
+}
+
+async fn serve() {
+    let listener = match TcpListener::bind(TELNET_PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("telnet: {}", err);
+            return;
+        }
+    };
+    loop {
+        let stream = listener.accept().await;
+        let (address, port) = stream.peer();
+        log::info!("telnet: session from {}:{}", address, port);
+        session(&stream).await;
+        log::info!("telnet: {}:{} left", address, port);
+    }
+}
+
+/// Console output waiting to go to the client.
+struct Output {
+    bytes: IrqSafeMutex<VecDeque<u8>>,
+    waker: IrqSafeMutex<Option<Waker>>,
+}
+
+impl ConsoleSink for Output {
+    fn write(&self, text: &[u8]) {
+        let mut bytes = self.bytes.lock();
+        for &byte in text {
+            let expanded: &[u8] = match byte {
+                b'\n' => b"\r\n",
+                8 | 0x7f => b"\x08 \x08",
+                IAC => &[IAC, IAC],
+                _ => core::slice::from_ref(&byte),
+            };
+            for &byte in expanded {
+                // Full up to the capacity it started with, so this never allocates.
+                if bytes.len() == MAX_OUTPUT {
+                    bytes.pop_front();
+                }
+                bytes.push_back(byte);
+            }
+        }
+        drop(bytes);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+async fn session(stream: &TcpStream) {
+    let negotiate = [IAC, WILL, OPTION_ECHO, IAC, WILL, OPTION_SUPPRESS_GO_AHEAD];
+    if stream.write_all(&negotiate).await.is_err() {
+        return;
+    }
+    let output = Arc::new(Output {
+        bytes: IrqSafeMutex::new(VecDeque::with_capacity(MAX_OUTPUT)),
+        waker: IrqSafeMutex::new(None),
+    });
+    let sink: Arc<dyn ConsoleSink> = output.clone();
+    console::add_sink(sink.clone());
+    // An empty line, so the shell prints a prompt.
+    console::push_input(b"\r");
+
+    let mut decoder = Decoder::default();
+    let mut buf = [0; 256];
+    let mut input = Vec::new();
+    loop {
+        // Input first, so a chatty log can't keep the keyboard out.
+        let read = poll_fn(|cx| {
+            *output.waker.lock() = Some(cx.waker().clone());
+            match stream.poll_read(cx, &mut buf) {
+                Poll::Ready(read) => Poll::Ready(Some(read)),
+                Poll::Pending if output.bytes.lock().is_empty() => Poll::Pending,
+                Poll::Pending => Poll::Ready(None),
+            }
+        })
+        .await;
+        match read {
+            None => {
+                let pending: Vec<u8> = output.bytes.lock().drain(..).collect();
+                if stream.write_all(&pending).await.is_err() {
+                    break;
+                }
+            }
+            Some(Ok(0)) | Some(Err(_)) => break,
+            Some(Ok(len)) => {
+                input.clear();
+                decoder.decode(&buf[..len], &mut input);
+                console::push_input(&input);
+            }
+        }
+    }
+    console::remove_sink(&sink);
+}
+
+/// Where [`Decoder`] is in the telnet protocol.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Data,
+    /// After a `\r`, which might be followed by `\n` or `\0`.
+    Return,
+    Command,
+    /// After `WILL`, `WONT`, `DO` or `DONT`, waiting for the option.
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Takes telnet commands out of what the client sends.
+#[derive(Debug, Default)]
+struct Decoder {
+    state: State,
+}
+
+impl Decoder {
+    /// Add the typed bytes in `bytes` to `out`. Line endings all become `\r`.
+    fn decode(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        for &byte in bytes {
+            self.state = match (self.state, byte) {
+                (State::Data | State::Return, IAC) => State::Command,
+                (State::Data | State::Return, b'\r') => {
+                    out.push(b'\r');
+                    State::Return
+                }
+                (State::Return, b'\n' | 0) => State::Data,
+                (State::Data | State::Return, _) => {
+                    out.push(byte);
+                    State::Data
+                }
+                (State::Command, IAC) => {
+                    out.push(IAC);
+                    State::Data
+                }
+                (State::Command, WILL | WONT | DO | DONT) => State::Option,
+                (State::Command, SB) => State::Subnegotiation,
+                (State::Command | State::Option, _) => State::Data,
+                (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationCommand, SE) => State::Data,
+                (State::SubnegotiationCommand, _) => State::Subnegotiation,
+            };
+        }
+    }
+}
+
+/// Send back whatever comes in, for as many connections as there are.
+async fn echo() {
+    let listener = match TcpListener::bind(ECHO_PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("echo: {}", err);
+            return;
+        }
+    };
+    loop {
+        let stream = listener.accept().await;
+        task::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                if stream.write_all(&buf[..len]).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn telnet_decoding() {
+        let mut decoder = Decoder::default();
+        let mut out = Vec::new();
+        decoder.decode(b"ls\r\n", &mut out);
+        decoder.decode(&[IAC, DO, OPTION_ECHO, b'p', b's', b'\r'], &mut out);
+        // The `\0` after `\r` comes in the next segment.
+        decoder.decode(&[0, IAC, SB, 24, 0, b'x', IAC, SE, IAC, IAC], &mut out);
+        assert_eq!(out, b"ls\rps\r\xff");
+    }
+}
@@ -0,0 +1,513 @@
+//! Reading and writing Ethernet, ARP, IPv4, ICMP, UDP and TCP headers.
+//!
+//! Parsers take the bytes of one layer and give back its header fields and a slice of
+//! the payload, or `None` if it's too short or otherwise not something we'd accept.
+//! Builders return the whole packet for that layer as a new `Vec`.
+
+use core::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use crate::prelude::*;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const ETHERNET_HEADER: usize = 14;
+const IPV4_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+const TCP_HEADER: usize = 20;
+const ICMP_HEADER: usize = 8;
+const ARP_PACKET: usize = 28;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+const DEFAULT_TTL: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Ipv4Addr {
+        Ipv4Addr(addr.to_be_bytes())
+    }
+
+    /// Whether `self` and `other` are on the same network with a `prefix` bit netmask.
+    pub fn same_network(self, other: Ipv4Addr, prefix: u8) -> bool {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        self.to_u32() & mask == other.to_u32() & mask
+    }
+
+    fn read(bytes: &[u8]) -> Ipv4Addr {
+        Ipv4Addr(bytes[..4].try_into().unwrap())
+    }
+}
+
+impl Display for Ipv4Addr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Ipv4Addr, ()> {
+        let mut addr = [0; 4];
+        let mut parts = s.split('.');
+        for byte in &mut addr {
+            *byte = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        match parts.next() {
+            None => Ok(Ipv4Addr(addr)),
+            Some(_) => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+    fn read(bytes: &[u8]) -> MacAddr {
+        MacAddr(bytes[..6].try_into().unwrap())
+    }
+}
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// The internet checksum of `data`, carrying on from `sum`.
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [high] => u16::from_be_bytes([high, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sum of the pseudo-header UDP's checksum covers.
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let words = [
+        u16_at(&src.0, 0),
+        u16_at(&src.0, 2),
+        u16_at(&dst.0, 0),
+        u16_at(&dst.0, 2),
+        protocol as u16,
+        len as u16,
+    ];
+    words.iter().map(|&word| word as u32).sum()
+}
+
+pub struct Ethernet<'a> {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ethernet<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Ethernet<'a>> {
+        if frame.len() < ETHERNET_HEADER {
+            return None;
+        }
+        Some(Ethernet {
+            dst: MacAddr::read(&frame[0..]),
+            src: MacAddr::read(&frame[6..]),
+            ethertype: u16_at(frame, 12),
+            payload: &frame[ETHERNET_HEADER..],
+        })
+    }
+}
+
+pub fn ethernet(dst: MacAddr, src: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&src.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arp {
+    pub operation: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Arp {
+    pub fn parse(packet: &[u8]) -> Option<Arp> {
+        // Ethernet, IPv4, and their address lengths.
+        if packet.len() < ARP_PACKET || packet[..6] != [0, 1, 8, 0, 6, 4] {
+            return None;
+        }
+        Some(Arp {
+            operation: u16_at(packet, 6),
+            sender_mac: MacAddr::read(&packet[8..]),
+            sender_ip: Ipv4Addr::read(&packet[14..]),
+            target_mac: MacAddr::read(&packet[18..]),
+            target_ip: Ipv4Addr::read(&packet[24..]),
+        })
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(ARP_PACKET);
+        packet.extend_from_slice(&[0, 1, 8, 0, 6, 4]);
+        packet.extend_from_slice(&self.operation.to_be_bytes());
+        packet.extend_from_slice(&self.sender_mac.0);
+        packet.extend_from_slice(&self.sender_ip.0);
+        packet.extend_from_slice(&self.target_mac.0);
+        packet.extend_from_slice(&self.target_ip.0);
+        packet
+    }
+}
+
+pub struct Ipv4<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4<'a> {
+    /// Doesn't accept fragments or a bad header checksum.
+    pub fn parse(packet: &'a [u8]) -> Option<Ipv4<'a>> {
+        if packet.len() < IPV4_HEADER || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header = (packet[0] & 0xf) as usize * 4;
+        let total = u16_at(packet, 2) as usize;
+        let more_fragments = packet[6] & 0x20 != 0;
+        let offset = u16_at(packet, 6) & 0x1fff;
+        if header < IPV4_HEADER || total < header || total > packet.len() {
+            return None;
+        }
+        if more_fragments || offset != 0 || checksum(&packet[..header], 0) != 0 {
+            return None;
+        }
+        Some(Ipv4 {
+            src: Ipv4Addr::read(&packet[12..]),
+            dst: Ipv4Addr::read(&packet[16..]),
+            protocol: packet[9],
+            payload: &packet[header..total],
+        })
+    }
+}
+
+pub fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, id: u16, payload: &[u8]) -> Vec<u8> {
+    let total = (IPV4_HEADER + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Don't fragment.
+    packet.extend_from_slice(&[0x40, 0, DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&src.0);
+    packet.extend_from_slice(&dst.0);
+    let sum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub struct Udp<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Udp<'a> {
+    /// `src` and `dst` are from the IP header, for the checksum.
+    pub fn parse(datagram: &'a [u8], src: Ipv4Addr, dst: Ipv4Addr) -> Option<Udp<'a>> {
+        if datagram.len() < UDP_HEADER {
+            return None;
+        }
+        let len = u16_at(datagram, 4) as usize;
+        if len < UDP_HEADER || len > datagram.len() {
+            return None;
+        }
+        // Zero means the sender didn't compute one.
+        let sum = pseudo_header(src, dst, PROTOCOL_UDP, len);
+        if u16_at(datagram, 6) != 0 && checksum(&datagram[..len], sum) != 0 {
+            return None;
+        }
+        Some(Udp {
+            src_port: u16_at(datagram, 0),
+            dst_port: u16_at(datagram, 2),
+            payload: &datagram[UDP_HEADER..len],
+        })
+    }
+}
+
+pub fn udp(src: Ipv4Addr, dst: Ipv4Addr, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let len = UDP_HEADER + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let sum = match checksum(&datagram, pseudo_header(src, dst, PROTOCOL_UDP, len)) {
+        // All ones, since zero means no checksum.
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+pub struct Tcp<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    /// `TCP_*` flags.
+    pub flags: u8,
+    pub window: u16,
+    /// The maximum segment size option. Only sent with `TCP_SYN`.
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Tcp<'a> {
+    /// `src` and `dst` are from the IP header, for the checksum. Options other than the
+    /// maximum segment size are skipped.
+    pub fn parse(segment: &'a [u8], src: Ipv4Addr, dst: Ipv4Addr) -> Option<Tcp<'a>> {
+        if segment.len() < TCP_HEADER {
+            return None;
+        }
+        let header = (segment[12] >> 4) as usize * 4;
+        if header < TCP_HEADER || header > segment.len() {
+            return None;
+        }
+        if checksum(
+            segment,
+            pseudo_header(src, dst, PROTOCOL_TCP, segment.len()),
+        ) != 0
+        {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[TCP_HEADER..header];
+        while let [option, rest @ ..] = options {
+            match *option {
+                TCP_OPTION_END => break,
+                TCP_OPTION_NOP => {
+                    options = rest;
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *rest.first()? as usize;
+            let value = options.get(2..len)?;
+            if *option == TCP_OPTION_MSS && value.len() == 2 {
+                mss = Some(u16_at(value, 0));
+            }
+            options = &options[len..];
+        }
+
+        Some(Tcp {
+            src_port: u16_at(segment, 0),
+            dst_port: u16_at(segment, 2),
+            seq: u32_at(segment, 4),
+            ack: u32_at(segment, 8),
+            flags: segment[13] & 0x3f,
+            window: u16_at(segment, 14),
+            mss,
+            payload: &segment[header..],
+        })
+    }
+
+    /// `src` and `dst` go in the IP header.
+    pub fn to_bytes(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let header = TCP_HEADER + if self.mss.is_some() { 4 } else { 0 };
+        let mut segment = Vec::with_capacity(header + self.payload.len());
+        segment.extend_from_slice(&self.src_port.to_be_bytes());
+        segment.extend_from_slice(&self.dst_port.to_be_bytes());
+        segment.extend_from_slice(&self.seq.to_be_bytes());
+        segment.extend_from_slice(&self.ack.to_be_bytes());
+        segment.extend_from_slice(&[((header / 4) as u8) << 4, self.flags]);
+        segment.extend_from_slice(&self.window.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if let Some(mss) = self.mss {
+            segment.extend_from_slice(&[TCP_OPTION_MSS, 4]);
+            segment.extend_from_slice(&mss.to_be_bytes());
+        }
+        segment.extend_from_slice(self.payload);
+        let sum = checksum(
+            &segment,
+            pseudo_header(src, dst, PROTOCOL_TCP, segment.len()),
+        );
+        segment[16..18].copy_from_slice(&sum.to_be_bytes());
+        segment
+    }
+}
+
+/// An ICMP echo request or reply.
+pub struct Echo<'a> {
+    pub kind: u8,
+    pub ident: u16,
+    pub seq: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> Echo<'a> {
+    pub fn parse(message: &'a [u8]) -> Option<Echo<'a>> {
+        if message.len() < ICMP_HEADER || checksum(message, 0) != 0 {
+            return None;
+        }
+        let kind = message[0];
+        if kind != ICMP_ECHO_REQUEST && kind != ICMP_ECHO_REPLY || message[1] != 0 {
+            return None;
+        }
+        Some(Echo {
+            kind,
+            ident: u16_at(message, 4),
+            seq: u16_at(message, 6),
+            data: &message[ICMP_HEADER..],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(ICMP_HEADER + self.data.len());
+        message.extend_from_slice(&[self.kind, 0, 0, 0]);
+        message.extend_from_slice(&self.ident.to_be_bytes());
+        message.extend_from_slice(&self.seq.to_be_bytes());
+        message.extend_from_slice(self.data);
+        let sum = checksum(&message, 0);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+        message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+
+    const A: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+    const B: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+
+    #[test_case]
+    fn addresses() {
+        assert_eq!("10.0.2.15".parse(), Ok(A));
+        assert_eq!("10.0.2".parse::<Ipv4Addr>(), Err(()));
+        assert_eq!("10.0.2.256".parse::<Ipv4Addr>(), Err(()));
+        assert_eq!(format!("{}", A), "10.0.2.15");
+        assert_eq!(
+            format!("{}", MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56])),
+            "52:54:00:12:34:56"
+        );
+        assert!(A.same_network(B, 24));
+        assert!(!A.same_network(Ipv4Addr([10, 0, 3, 1]), 24));
+        assert!(A.same_network(Ipv4Addr([1, 2, 3, 4]), 0));
+    }
+
+    #[test_case]
+    fn packets_round_trip() {
+        let datagram = udp(A, B, 1234, 53, b"hello");
+        let packet = ipv4(A, B, PROTOCOL_UDP, 7, &datagram);
+        let ip = Ipv4::parse(&packet).unwrap();
+        assert_eq!((ip.src, ip.dst, ip.protocol), (A, B, PROTOCOL_UDP));
+        let udp = Udp::parse(ip.payload, ip.src, ip.dst).unwrap();
+        assert_eq!(
+            (udp.src_port, udp.dst_port, udp.payload),
+            (1234, 53, &b"hello"[..])
+        );
+        // The pseudo-header's sum doesn't care which way round they are, but it does what
+        // they are.
+        assert!(Udp::parse(ip.payload, B, A).is_some());
+        assert!(Udp::parse(ip.payload, A, A).is_none());
+
+        let mut corrupt = packet.clone();
+        corrupt[8] ^= 1;
+        assert!(Ipv4::parse(&corrupt).is_none());
+
+        let echo = Echo {
+            kind: ICMP_ECHO_REQUEST,
+            ident: 1,
+            seq: 2,
+            data: b"ping",
+        }
+        .to_bytes();
+        let parsed = Echo::parse(&echo).unwrap();
+        assert_eq!(
+            (parsed.ident, parsed.seq, parsed.data),
+            (1, 2, &b"ping"[..])
+        );
+
+        let syn = Tcp {
+            src_port: 40000,
+            dst_port: 23,
+            seq: 0x12345678,
+            ack: 0,
+            flags: TCP_SYN,
+            window: 4096,
+            mss: Some(1460),
+            payload: b"",
+        }
+        .to_bytes(A, B);
+        let parsed = Tcp::parse(&syn, A, B).unwrap();
+        assert_eq!(
+            (parsed.seq, parsed.flags, parsed.mss),
+            (0x12345678, TCP_SYN, Some(1460))
+        );
+        assert!(Tcp::parse(&syn, A, A).is_none());
+    }
+}
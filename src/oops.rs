@@ -0,0 +1,214 @@
+//! Oops reports: everything we know when the kernel dies, in a form a script can pick out
+//! of a serial log.
+//!
+//! A report starts with a [`BEGIN`] line and ends with an [`END`] one. In between are
+//! `key: value` lines, then the `registers:`, `backtrace:` and `log:` sections. Each line
+//! in a section, or carrying on a value over more than one line, is indented two spaces.
+//! Backtraces have symbol names and offsets when `make symbols` has been run.
+//!
+//! The panic handler writes one, so nothing here allocates or waits for a lock. An
+//! exception in the kernel [`record_trap`]s before it panics, so the report has the
+//! registers from the trap and a backtrace from where it happened, rather than from
+//! inside the trap handler.
+
+use core::{
+    cell::Cell,
+    fmt::{self, Display, Write},
+};
+
+use crate::{
+    backtrace,
+    hart_local::try_current_hart,
+    log, process,
+    sbi::hart::HartId,
+    task,
+    trap::{self, TrapRegisters},
+};
+
+pub const BEGIN: &str = "-----BEGIN OOPS-----";
+pub const END: &str = "-----END OOPS-----";
+
+/// Lines of the log in a report.
+const LOG_LINES: usize = 20;
+
+/// `git describe` of the tree it was built from.
+const BUILD_ID: &str = match option_env!("KERNEL_BUILD_ID") {
+    Some(id) => id,
+    None => "unknown",
+};
+
+/// A fatal trap on this hart.
+#[derive(Debug, Clone, Copy)]
+pub struct Trap {
+    scause: usize,
+    stval: usize,
+    /// On the trap stack, which is still there while the trap handler's panicking.
+    registers: *const TrapRegisters,
+}
+
+crate::hart_local! {
+    static TRAP: Cell<Option<Trap>> = Cell::new(None);
+}
+
+/// This hart's about to panic over a trap, with these registers.
+pub fn record_trap(scause: usize, stval: usize, registers: &TrapRegisters) {
+    if let Some(trap) = TRAP.try_get() {
+        trap.set(Some(Trap {
+            scause,
+            stval,
+            registers,
+        }));
+    }
+}
+
+/// What [`record_trap`] recorded, once.
+pub fn take_trap() -> Option<Trap> {
+    TRAP.try_get()?.take()
+}
+
+/// A report, built up and then [`write`](Oops::write)n.
+pub struct Oops<'a> {
+    reason: &'a dyn Display,
+    hart: Option<HartId>,
+    trap: Option<(usize, usize, &'a TrapRegisters)>,
+    fp: usize,
+    log_lines: usize,
+}
+
+impl<'a> Oops<'a> {
+    /// With a backtrace from the caller.
+    #[inline(always)]
+    pub fn new(reason: &'a dyn Display) -> Oops<'a> {
+        Oops {
+            reason,
+            hart: try_current_hart(),
+            trap: None,
+            fp: backtrace::current_fp(),
+            log_lines: LOG_LINES,
+        }
+    }
+
+    /// It happened at `trap`. Adds its registers, and starts the backtrace there.
+    ///
+    /// # Safety
+    /// The trap handler that recorded `trap` must not have returned.
+    pub unsafe fn at_trap(self, trap: Trap) -> Oops<'a> {
+        let registers = &*trap.registers;
+        self.trap(trap.scause, trap.stval, registers)
+    }
+
+    /// It happened at a trap with these registers.
+    pub fn trap(mut self, scause: usize, stval: usize, registers: &'a TrapRegisters) -> Self {
+        self.trap = Some((scause, stval, registers));
+        self.fp = registers.s0 as usize;
+        self
+    }
+
+    pub fn log_lines(mut self, lines: usize) -> Self {
+        self.log_lines = lines;
+        self
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> fmt::Result {
+        writeln!(w, "{}", BEGIN)?;
+        writeln!(w, "version: {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(w, "build: {}", BUILD_ID)?;
+        match self.hart {
+            Some(hart) => writeln!(w, "hart: {}", hart.0)?,
+            None => writeln!(w, "hart: ?")?,
+        }
+        match process::current() {
+            Some(process) => writeln!(w, "process: {}", process.pid())?,
+            None => writeln!(w, "process: -")?,
+        }
+        match task::executor::current() {
+            Some(task) => writeln!(w, "task: {:?}", task.id())?,
+            None => writeln!(w, "task: -")?,
+        }
+        write!(w, "reason: ")?;
+        write!(Indented::new(w, false), "{}", self.reason)?;
+        writeln!(w)?;
+
+        let pc = match self.trap {
+            Some((scause, stval, registers)) => {
+                writeln!(w, "scause: 0x{:x}", scause)?;
+                writeln!(w, "stval: 0x{:x}", stval)?;
+                writeln!(w, "registers:")?;
+                trap::print_registers(w, registers)?;
+                Some(registers.pc as usize)
+            }
+            None => None,
+        };
+        // Its frames are already indented.
+        unsafe { backtrace::print(w, pc, self.fp)? };
+        writeln!(w, "log:")?;
+        log::write_tail(&mut Indented::new(w, true), self.log_lines)?;
+        writeln!(w, "{}", END)
+    }
+}
+
+/// Indents each line after a newline.
+struct Indented<'a> {
+    w: &'a mut dyn Write,
+    line_start: bool,
+}
+
+impl<'a> Indented<'a> {
+    fn new(w: &'a mut dyn Write, line_start: bool) -> Self {
+        Indented { w, line_start }
+    }
+}
+
+impl Write for Indented<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if self.line_start {
+                self.w.write_str("  ")?;
+            }
+            self.w.write_str(line)?;
+            self.line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test_case]
+    fn framed_report() {
+        let mut registers: TrapRegisters = unsafe { core::mem::zeroed() };
+        registers.pc = take_trap as fn() -> Option<Trap> as usize as u64;
+        registers.sp = 0x1234;
+        let reason = "it broke\nbadly";
+        let mut out = String::new();
+        Oops::new(&reason)
+            .trap(0xd, 0x8, &registers)
+            .log_lines(1)
+            .write(&mut out)
+            .unwrap();
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], BEGIN);
+        assert_eq!(lines[lines.len() - 1], END);
+        assert!(lines.contains(&"reason: it broke"));
+        assert!(lines.contains(&"  badly"));
+        assert!(lines.contains(&"scause: 0xd"));
+        assert!(lines.contains(&"  sp    = 0x1234"));
+        // Only the pc, since there's no frame pointer.
+        let backtrace = lines.iter().position(|&line| line == "backtrace:").unwrap();
+        assert!(lines[backtrace + 1].starts_with("   0: "));
+        assert_eq!(lines[backtrace + 2], "log:");
+        // Everything in a section is indented.
+        let log = &lines[backtrace + 3..lines.len() - 1];
+        assert!(log.len() <= 1);
+        assert!(log.iter().all(|line| line.starts_with("  ")));
+
+        assert!(take_trap().is_none());
+        record_trap(0xf, 0, &registers);
+        assert_eq!(take_trap().map(|trap| trap.scause), Some(0xf));
+        assert!(take_trap().is_none());
+    }
+}
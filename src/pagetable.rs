@@ -1,8 +1,42 @@
-//! Implementation of sv39
-
-use core::fmt::{Debug, Formatter};
+//! Page tables for Sv39, Sv48 and Sv57.
+//!
+//! They only differ in how many levels there are. [`init_mode`] picks the biggest mode every
+//! hart has at boot, and each [`PageTableRoot`] keeps the mode it was made with.
+//!
+//! The kernel lives in the upper half. All of physical memory is mapped at [`PHYS_OFFSET`]
+//! with gigapages, the kernel image included, so a physical address is reached at
+//! [`phys_to_virt`] of it. `_start` builds that mapping in [`KERNEL_ROOT`] and turns on
+//! Sv39 before anything else runs. Every process's root table shares the kernel's upper half
+//! as global entries, and the lower half is the process's own.
+//!
+//! With Svpbmt, [`init_mmio`] marks the parts of the direct map that aren't RAM as
+//! [`Pbmt::Io`], so device registers aren't cached or read speculatively. Drivers map
+//! their registers with [`memory_map::ioremap`] instead, which takes the last gigabyte of
+//! the direct map for them.
+
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    arch::asm,
+    fmt::{Debug, Display, Formatter},
+    ptr::NonNull,
+};
 use const_default::ConstDefault;
-use crate::basic_consts::{BITS_2, BITS_26, BITS_9};
+use riscv::register::satp;
+use crate::basic_consts::{BITS_2, BITS_26, BITS_44, BITS_9};
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    cmdline,
+    hwinfo::{HwInfo, PhysicalAddressRange},
+    log, poison,
+};
+
+pub mod memory_map;
+pub mod regions;
+mod verify;
+
+#[allow(unused_imports)]
+pub use verify::{verify, Problem, Violation};
 
 pub const PAGE_SIZE: u64 = 4096;
 pub const ENTRIES: usize = 512;
@@ -14,28 +48,91 @@ pub enum VirtualMemorySystem {
     Sv57,
 }
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-#[repr(transparent)]
-pub struct PhysicalAddr(pub u64);
+impl VirtualMemorySystem {
+    /// From an `mmu-type` property, like `riscv,sv48`. `None` for `riscv,none` or anything
+    /// we don't know.
+    pub fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        let name = mmu_type.strip_prefix("riscv,").unwrap_or(mmu_type);
+        [Self::Sv39, Self::Sv48, Self::Sv57]
+            .into_iter()
+            .find(|mode| mode.name() == name)
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Sv39 => "sv39",
+            Self::Sv48 => "sv48",
+            Self::Sv57 => "sv57",
+        }
+    }
 
-impl PhysicalAddr {
-    pub const fn page_offset(&self) -> u64 {
-        self.0 & ((1 << 12) - 1)
+    /// Levels of page table. The root is at `levels() - 1`.
+    pub const fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+
+    /// The satp MODE field.
+    const fn satp_mode(self) -> u64 {
+        match self {
+            Self::Sv39 => 8 << 60,
+            Self::Sv48 => 9 << 60,
+            Self::Sv57 => 10 << 60,
+        }
     }
 
-    pub const fn ppn0(&self) -> u64 {
-        self.0 & (((1 << 9) - 1) << 12) >> 12
+    /// First address past the lower half of the address space.
+    pub const fn lower_half_end(self) -> u64 {
+        1 << (12 + 9 * self.levels() - 1)
     }
+}
 
-    pub const fn ppn1(&self) -> u64 {
-        self.0 & (((1 << 9) - 1) << 21) >> 21
+/// [`VirtualMemorySystem`] as a `u8`, for [`MODE`].
+const fn mode_index(mode: VirtualMemorySystem) -> u8 {
+    match mode {
+        VirtualMemorySystem::Sv39 => 0,
+        VirtualMemorySystem::Sv48 => 1,
+        VirtualMemorySystem::Sv57 => 2,
     }
+}
+
+/// The mode new page tables use. Sv39 until [`init_mode`] says otherwise, since every
+/// hart that pages has it.
+static MODE: AtomicU8 = AtomicU8::new(mode_index(VirtualMemorySystem::Sv39));
 
-    pub const fn ppn2(&self) -> u64 {
-        self.0 & (((1 << 26) - 1) << 30) >> 30
+pub fn mode() -> VirtualMemorySystem {
+    match MODE.load(Ordering::Relaxed) {
+        1 => VirtualMemorySystem::Sv48,
+        2 => VirtualMemorySystem::Sv57,
+        _ => VirtualMemorySystem::Sv39,
     }
 }
 
+/// Pick the biggest mode every hart has, or a smaller one if `mmu=` on the command line
+/// asks for it.
+pub fn init_mode(hwinfo: &HwInfo) {
+    let harts = hwinfo.harts.iter().map(|hart| hart.mmu_type);
+    let mut mode = match harts.clone().min().flatten() {
+        Some(mode) => mode,
+        None if harts.len() == 0 => VirtualMemorySystem::Sv39,
+        None => {
+            log::warn!("a hart has no MMU. Processes won't run.");
+            VirtualMemorySystem::Sv39
+        }
+    };
+    if let Some(arg) = cmdline::get("mmu") {
+        match VirtualMemorySystem::from_mmu_type(arg) {
+            Some(wanted) if wanted <= mode => mode = wanted,
+            Some(wanted) => log::warn!("mmu={}: harts only have {}", wanted.name(), mode.name()),
+            None => log::warn!("mmu={}: unknown mode", arg),
+        }
+    }
+    MODE.store(mode_index(mode), Ordering::Relaxed);
+    log::info!("paging with {}", mode.name());
+}
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Default, ConstDefault)]
 pub struct Entry(pub u64);
@@ -51,6 +148,12 @@ impl Debug for Entry {
         if self.rsw() != 0 {
             write!(f, "|RSW:{:x}", self.rsw())?;
         }
+        match self.pbmt() {
+            Some(Pbmt::Pma) => {}
+            Some(Pbmt::Nc) => write!(f, "|NC")?,
+            Some(Pbmt::Io) => write!(f, "|IO")?,
+            None => write!(f, "|PBMT:3")?,
+        }
         if self.dirty() { write!(f, "|D")?; }
         if self.accessed() { write!(f, "|A")?; }
         if self.global() { write!(f, "|G")?; }
@@ -90,69 +193,644 @@ impl Entry {
         (self.0 >> 28) & BITS_26
     }
 
+    /// Bits 54 and up, less [`pbmt`](Self::pbmt).
     pub const fn reserved(self) -> u64 {
-        self.0 >> 54
+        (self.0 >> 54) & !(0b11 << 7)
+    }
+
+    /// `None` for the reserved encoding.
+    pub const fn pbmt(self) -> Option<Pbmt> {
+        match (self.0 >> 61) & 0b11 {
+            0 => Some(Pbmt::Pma),
+            1 => Some(Pbmt::Nc),
+            2 => Some(Pbmt::Io),
+            _ => None,
+        }
     }
 }
 
 impl Entry {
+    /// Points at a page (or a mega/giga page), rather than the next level of table.
     pub const fn leaf(self) -> bool {
-        !self.read() && !self.write() && !self.execute()
+        self.read() || self.write() || self.execute()
     }
 
     pub const fn non_leaf(self) -> bool {
         !self.leaf()
     }
+
+    pub const fn from_parts(ppn: u64, flags: EntryFlags) -> Self {
+        Entry(((ppn & BITS_44) << 10) | flags.bits())
+    }
+
+    pub const fn ppn(self) -> u64 {
+        (self.0 >> 10) & BITS_44
+    }
+
+    /// Physical address of the page or table this points at.
+    pub const fn address(self) -> PhysAddr {
+        PhysAddr::from_ppn(self.ppn())
+    }
+
+    pub const fn flags(self) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.0)
+    }
 }
 
-pub trait Level {
-    const PAGE_SIZE: usize;
+bitflags::bitflags! {
+    pub struct EntryFlags: u64 {
+        const VALID = 1 << 0;
+        const READ = 1 << 1;
+        const WRITE = 1 << 2;
+        const EXECUTE = 1 << 3;
+        const USER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const ACCESSED = 1 << 6;
+        const DIRTY = 1 << 7;
+        /// Svpbmt's non-cacheable memory type. Only in leaf entries.
+        const PBMT_NC = 1 << 61;
+        /// Svpbmt's I/O memory type. Only in leaf entries.
+        const PBMT_IO = 1 << 62;
+    }
 }
-pub trait NonLeaf: Level {
-    type Next: Level;
+
+/// Svpbmt memory types, which override the physical memory attributes for a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pbmt {
+    /// Whatever the physical memory attributes say.
+    Pma,
+    /// Non-cacheable, idempotent, weakly-ordered main memory.
+    Nc,
+    /// Non-cacheable, non-idempotent, strongly-ordered I/O memory. For device registers.
+    Io,
 }
 
-struct Level0 {}
-impl Level for Level0 {
-    const PAGE_SIZE: usize = 4096;
+impl Pbmt {
+    /// Flags for a leaf entry with this type. Empty without Svpbmt, where those bits are
+    /// reserved.
+    pub fn flags(self) -> EntryFlags {
+        match self {
+            Pbmt::Nc if has_svpbmt() => EntryFlags::PBMT_NC,
+            Pbmt::Io if has_svpbmt() => EntryFlags::PBMT_IO,
+            _ => EntryFlags::empty(),
+        }
+    }
 }
 
-struct Level1 {}
-impl Level for Level1 {
-    const PAGE_SIZE: usize = 1 << 21;
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Every hart has Svpbmt, so entries can use [`Pbmt`]. Set by [`init_mmio`].
+pub fn has_svpbmt() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
 }
-impl NonLeaf for Level1 {
-    type Next = Level0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Addresses must be page aligned.
+    Misaligned,
+    /// Not a virtual address in the lower half for the paging mode.
+    OutOfRange,
+    AlreadyMapped,
+    OutOfMemory,
 }
 
-struct Level2 {}
-impl Level for Level2 {
-    const PAGE_SIZE: usize = 1 << 30;
+impl Display for MapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MapError::Misaligned => write!(f, "address is not page aligned"),
+            MapError::OutOfRange => write!(f, "address out of range"),
+            MapError::AlreadyMapped => write!(f, "address already mapped"),
+            MapError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
 }
-impl NonLeaf for Level0 {
-    type Next = Level1;
+
+impl core::error::Error for MapError {}
+
+/// Where physical memory starts in the kernel's half. Also where `linker.ld` puts the
+/// kernel, on top of its load address.
+pub const PHYS_OFFSET: u64 = 0xffff_ffc0_0000_0000;
+/// How much physical memory the direct map covers. All of the Sv39 upper half.
+pub const PHYS_MAP_SIZE: u64 = 1 << 38;
+
+/// Root table entries in each half.
+const HALF: usize = ENTRIES / 2;
+/// Where the direct map stops. The windows [`claim_window`](memory_map::claim_window) takes
+/// come off its top, so this comes down as they're taken.
+static DIRECT_MAP_END: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// The kernel's address for physical address `pa`.
+pub const fn phys_to_virt(pa: PhysAddr) -> VirtAddr {
+    VirtAddr::new(pa.as_u64() + PHYS_OFFSET)
 }
 
+/// The physical address of kernel address `va`, which has to be in the direct map. The
+/// kernel image and the heap are. Stacks, vmalloc areas and device registers aren't: use
+/// [`kernel_translate`] for those.
+pub fn virt_to_phys(va: VirtAddr) -> PhysAddr {
+    let end = DIRECT_MAP_END.load(Ordering::Relaxed);
+    assert!(
+        (PHYS_OFFSET..end).contains(&va.as_u64()),
+        "{:#x} isn't in the direct map",
+        va
+    );
+    PhysAddr::new(va.as_u64() - PHYS_OFFSET)
+}
+
+/// The direct map doesn't reach `start` and above any more.
+pub(crate) fn shrink_direct_map(start: VirtAddr) {
+    DIRECT_MAP_END.fetch_min(start.as_u64(), Ordering::Relaxed);
+}
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub struct PageTable<L: Level> {
+#[repr(C, align(4096))]
+pub struct PageTable {
     entries: [Entry; ENTRIES],
 }
 
-impl<L:NonLeaf> PageTable<L> {
-    fn next_level(&self, index: u32) -> Option<PageTable<L::Next>> {
-        let e: Entry = self.entries[index];
-        if e.valid() & !e.leaf() {
+impl PageTable {
+    const EMPTY: PageTable = PageTable {
+        entries: [Entry(0); ENTRIES],
+    };
+}
+
+/// The kernel's Sv39 root. `_start` fills in the direct map, and [`init_kernel`] takes away
+/// the identity map it also needed to get there. Only the upper half is used after that.
+pub static mut KERNEL_ROOT: PageTable = PageTable::EMPTY;
+/// Puts [`KERNEL_ROOT`] at the top of an Sv57 address space. Sv48 doesn't need it, since
+/// its last root entry can point at [`KERNEL_ROOT`] directly.
+static mut KERNEL_SV48: PageTable = PageTable::EMPTY;
+
+/// Drop the identity map `_start` used to turn on paging, now that we're running in the
+/// upper half.
+///
+/// # Safety
+/// Once, on the boot hart, before anything uses a physical address as a pointer.
+pub unsafe fn init_kernel() {
+    let root = &mut *core::ptr::addr_of_mut!(KERNEL_ROOT);
+    for entry in &mut root.entries[..HALF] {
+        *entry = Entry(0);
+    }
+    asm!("sfence.vma");
+
+    let sv48 = &mut *core::ptr::addr_of_mut!(KERNEL_SV48);
+    sv48.entries[ENTRIES - 1] = kernel_table_entry(core::ptr::addr_of!(KERNEL_ROOT));
+}
+
+/// Map everything in the direct map that isn't RAM as [`Pbmt::Io`], if every hart has
+/// Svpbmt. `_start` maps it all as normal memory, which may be cached and read
+/// speculatively.
+///
+/// # Safety
+/// Before the other harts start, since only this hart's TLB is flushed, and before any
+/// address space is made: they copy the kernel's root entries, so they'd keep the gigapages
+/// this changes. Splitting a gigapage that's partly RAM takes frames, so after the heap.
+pub unsafe fn init_mmio(hwinfo: &HwInfo) {
+    let svpbmt = !hwinfo.harts.is_empty()
+        && hwinfo.harts.iter().all(|hart| hart.has_extension("svpbmt"));
+    SVPBMT.store(svpbmt, Ordering::Relaxed);
+    if !svpbmt {
+        log::info!("no Svpbmt: devices are mapped as normal memory");
+        return;
+    }
+    let root = &mut *core::ptr::addr_of_mut!(KERNEL_ROOT);
+    match mark_io(&mut root.entries[HALF..], 2, PhysAddr::new(0), &hwinfo.ram) {
+        Ok(()) => log::info!("Svpbmt: devices are mapped as I/O"),
+        Err(err) => log::warn!("Svpbmt: marking devices as I/O: {}", err),
+    }
+    asm!("sfence.vma");
+}
+
+/// Set [`Pbmt::Io`] on the leaves in `entries`, a table at `level` starting at physical
+/// address `pa`, that have no RAM in them. Leaves that are partly RAM are split into a
+/// table of smaller pages.
+unsafe fn mark_io(
+    entries: &mut [Entry],
+    level: usize,
+    pa: PhysAddr,
+    ram: &[PhysicalAddressRange],
+) -> Result<(), MapError> {
+    let size = 1u64 << (12 + 9 * level);
+    for (i, entry) in entries.iter_mut().enumerate() {
+        if !entry.valid() || entry.non_leaf() {
+            continue;
+        }
+        let start = pa + i as u64 * size;
+        let end = start + size;
+        let overlaps = ram.iter().any(|ram| ram.start < end && start < ram.end);
+        let inside = ram.iter().any(|ram| ram.start <= start && end <= ram.end);
+        if !overlaps {
+            *entry = Entry(entry.0 | EntryFlags::PBMT_IO.bits());
+        } else if !inside && level > 0 {
+            split(entry, level)?;
+            let smaller = &mut (*table_at(entry.address())).entries;
+            mark_io(smaller, level - 1, start, ram)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace the leaf `entry` at `level` with a table of pages one size down, mapping the
+/// same memory the same way.
+unsafe fn split(entry: &mut Entry, level: usize) -> Result<(), MapError> {
+    let size = 1u64 << (12 + 9 * (level - 1));
+    let table = alloc_frame().ok_or(MapError::OutOfMemory)?;
+    let smaller = &mut (*table_at(table)).entries;
+    for (j, small) in smaller.iter_mut().enumerate() {
+        *small = Entry::from_parts((entry.address() + j as u64 * size).ppn(), entry.flags());
+    }
+    // Maps the same as before, so it doesn't matter which one the hart sees until the
+    // sfence.
+    *entry = Entry::from_parts(table.ppn(), EntryFlags::VALID | EntryFlags::GLOBAL);
+    Ok(())
+}
+
+fn kernel_table_entry(table: *const PageTable) -> Entry {
+    Entry::from_parts(
+        virt_to_phys(VirtAddr::from_ptr(table)).ppn(),
+        EntryFlags::VALID | EntryFlags::GLOBAL,
+    )
+}
+
+/// satp for the kernel's own address space, with no process in the lower half.
+pub fn kernel_satp() -> u64 {
+    let root = virt_to_phys(VirtAddr::from_ptr(core::ptr::addr_of!(KERNEL_ROOT)));
+    VirtualMemorySystem::Sv39.satp_mode() | root.ppn()
+}
+
+/// A zeroed page from the heap. Returns its physical address, for page table entries.
+/// The kernel reaches it at [`phys_to_virt`] of that.
+pub fn alloc_frame() -> Option<PhysAddr> {
+    let layout = Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap();
+    let frame = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if frame.is_null() {
+        None
+    } else {
+        Some(virt_to_phys(VirtAddr::from_ptr(frame)))
+    }
+}
+
+/// # Safety
+/// `frame` came from [`alloc_frame`] and nothing still uses it.
+pub unsafe fn free_frame(frame: PhysAddr) {
+    // Page tables are what a stale TLB entry could still be using.
+    let frame = if poison::ENABLED {
+        match poison::quarantine(frame) {
+            Some(released) => released,
+            None => return,
+        }
+    } else {
+        frame
+    };
+    let layout = Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap();
+    alloc::alloc::dealloc(phys_to_virt(frame).as_mut_ptr(), layout);
+}
+
+/// The table at physical address `pa`.
+fn table_at(pa: PhysAddr) -> *mut PageTable {
+    phys_to_virt(pa).as_mut_ptr()
+}
+
+/// An address space. Owns its page tables, but not the pages they map.
+pub struct PageTableRoot {
+    root: NonNull<PageTable>,
+    mode: VirtualMemorySystem,
+}
+
+// Only changed through `&mut self`, like a `Box`.
+unsafe impl Send for PageTableRoot {}
+unsafe impl Sync for PageTableRoot {}
+
+impl PageTableRoot {
+    /// An empty address space in the boot-time [`mode`].
+    pub fn new() -> Result<Self, MapError> {
+        Self::with_mode(mode())
+    }
+
+    pub fn with_mode(mode: VirtualMemorySystem) -> Result<Self, MapError> {
+        let root = NonNull::new(table_at(alloc_frame().ok_or(MapError::OutOfMemory)?)).unwrap();
+        // The kernel's half, shared with every other address space.
+        let entries = unsafe { &mut (*root.as_ptr()).entries };
+        match mode {
+            VirtualMemorySystem::Sv39 => unsafe {
+                let kernel = &*core::ptr::addr_of!(KERNEL_ROOT);
+                entries[HALF..].copy_from_slice(&kernel.entries[HALF..]);
+            },
+            VirtualMemorySystem::Sv48 => {
+                entries[ENTRIES - 1] = kernel_table_entry(core::ptr::addr_of!(KERNEL_ROOT));
+            }
+            VirtualMemorySystem::Sv57 => {
+                entries[ENTRIES - 1] = kernel_table_entry(core::ptr::addr_of!(KERNEL_SV48));
+            }
+        }
+        Ok(PageTableRoot { root, mode })
+    }
+
+    pub fn mode(&self) -> VirtualMemorySystem {
+        self.mode
+    }
+
+    /// Physical address of the root table.
+    pub fn address(&self) -> PhysAddr {
+        virt_to_phys(VirtAddr::from_ptr(self.root.as_ptr()))
+    }
+
+    /// Value for the satp register to use this address space.
+    pub fn satp(&self, asid: u16) -> u64 {
+        self.mode.satp_mode() | ((asid as u64) << 44) | self.address().ppn()
+    }
+
+    /// Find the last level entry for `va`, making tables on the way if `create` is set.
+    fn walk(&mut self, va: VirtAddr, create: bool) -> Result<Option<&mut Entry>, MapError> {
+        if va.as_u64() >= self.mode.lower_half_end() {
+            return Err(MapError::OutOfRange);
+        }
+        unsafe { walk_from(self.root.as_ptr(), self.mode.levels(), va, create) }
+    }
+
+    /// Map the page at `va` to the page at `pa`. [`EntryFlags::VALID`] is implied.
+    ///
+    /// Accessed and dirty are set up front, so hardware that doesn't manage them itself
+    /// doesn't fault on the first access.
+    pub fn map(&mut self, va: VirtAddr, pa: PhysAddr, flags: EntryFlags) -> Result<(), MapError> {
+        if !va.is_page_aligned() || !pa.is_page_aligned() {
+            return Err(MapError::Misaligned);
+        }
+        let entry = self.walk(va, true)?.unwrap();
+        if entry.valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        let flags = flags | EntryFlags::VALID | EntryFlags::ACCESSED | EntryFlags::DIRTY;
+        *entry = Entry::from_parts(pa.ppn(), flags);
+        Ok(())
+    }
+
+    /// Remove the mapping for the page at `va`, returning what it was. The caller deals
+    /// with flushing the TLB.
+    pub fn unmap(&mut self, va: VirtAddr) -> Option<Entry> {
+        match self.walk(va.align_down(PAGE_SIZE), false) {
+            Ok(Some(entry)) if entry.valid() => Some(core::mem::take(entry)),
+            _ => None,
+        }
+    }
+
+    /// Physical address and flags `va` maps to.
+    pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, EntryFlags)> {
+        if va.as_u64() >= self.mode.lower_half_end() {
+            return None;
+        }
+        let found = unsafe { translate_from(self.root.as_ptr(), self.mode.levels(), va) }?;
+        Some((found.pa, found.flags))
+    }
+
+    /// Call `f` with the virtual address, physical address, flags and size of every
+    /// mapped page in the lower half, in address order.
+    pub fn for_each_mapping(&self, mut f: impl FnMut(VirtAddr, PhysAddr, EntryFlags, u64)) {
+        let root = unsafe { &self.root.as_ref().entries[..HALF] };
+        unsafe { walk_mappings(root, self.mode.levels() - 1, VirtAddr::new(0), &mut f) }
+    }
+}
+
+/// Where a virtual address goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub pa: PhysAddr,
+    pub flags: EntryFlags,
+    /// Level of the entry that maps it. 0 for a 4 KiB page, 1 for 2 MiB, and so on.
+    pub level: usize,
+}
+
+impl Translation {
+    pub const fn page_size(&self) -> u64 {
+        1 << (12 + 9 * self.level)
+    }
+}
+
+impl Display for Translation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#x} in a {} KiB page, {:?}",
+            self.pa,
+            self.page_size() / 1024,
+            self.flags
+        )
+    }
+}
+
+/// Where `va` goes in `root`'s address space, the kernel's half included. A walk in
+/// software, so it doesn't matter whose tables the hart is using.
+pub fn translate(root: &PageTableRoot, va: VirtAddr) -> Option<Translation> {
+    if !canonical(root.mode, va) {
+        return None;
+    }
+    unsafe { translate_from(root.root.as_ptr(), root.mode.levels(), va) }
+}
 
+/// [`translate`] in the address space this hart is running in. `None` without paging.
+pub fn translate_current(va: VirtAddr) -> Option<Translation> {
+    let satp = satp::read();
+    let mode = match satp.mode() {
+        satp::Mode::Sv39 => VirtualMemorySystem::Sv39,
+        satp::Mode::Sv48 => VirtualMemorySystem::Sv48,
+        satp::Mode::Sv57 => VirtualMemorySystem::Sv57,
+        _ => return None,
+    };
+    if !canonical(mode, va) {
+        return None;
+    }
+    let root = table_at(PhysAddr::from_ppn(satp.ppn() as u64));
+    unsafe { translate_from(root, mode.levels(), va) }
+}
+
+/// The bits above the top of the address space are all copies of its top bit.
+fn canonical(mode: VirtualMemorySystem, va: VirtAddr) -> bool {
+    let top = mode.lower_half_end();
+    va.as_u64() < top || va.as_u64() >= top.wrapping_neg()
+}
+
+/// [`translate`] from any root, `levels` deep.
+///
+/// # Safety
+/// `root` is a page table.
+unsafe fn translate_from(
+    root: *const PageTable,
+    levels: usize,
+    va: VirtAddr,
+) -> Option<Translation> {
+    let mut table = root;
+    for level in (0..levels).rev() {
+        let entry = (*table).entries[va.vpn(level)];
+        if !entry.valid() {
+            return None;
+        }
+        if entry.leaf() {
+            let page_size = 1 << (12 + 9 * level);
+            return Some(Translation {
+                pa: entry.address().align_down(page_size) + (va.as_u64() & (page_size - 1)),
+                flags: entry.flags(),
+                level,
+            });
+        }
+        table = table_at(entry.address());
+    }
+    None
+}
+
+/// [`PageTableRoot::walk`] from any root, `levels` deep.
+///
+/// # Safety
+/// `root` is a page table nothing else is changing, and the entry isn't used past it.
+unsafe fn walk_from<'a>(
+    root: *mut PageTable,
+    levels: usize,
+    va: VirtAddr,
+    create: bool,
+) -> Result<Option<&'a mut Entry>, MapError> {
+    let mut table = root;
+    for level in (1..levels).rev() {
+        let entry = &mut (*table).entries[va.vpn(level)];
+        if !entry.valid() {
+            if !create {
+                return Ok(None);
+            }
+            let next = alloc_frame().ok_or(MapError::OutOfMemory)?;
+            *entry = Entry::from_parts(next.ppn(), EntryFlags::VALID);
+        } else if entry.leaf() {
+            // Part of a bigger page. We only make 4K pages, so someone else made this.
+            return Err(MapError::AlreadyMapped);
         }
+        table = table_at(entry.address());
     }
+    Ok(Some(&mut (*table).entries[va.vpn(0)]))
 }
 
-impl PageTable<Level2> {
+/// Physical address and flags `va` maps to in the kernel's half. For checking an address
+/// before touching it.
+pub fn kernel_translate(va: VirtAddr) -> Option<(PhysAddr, EntryFlags)> {
+    if va.as_u64() < PHYS_OFFSET {
+        return None;
+    }
+    let root = core::ptr::addr_of!(KERNEL_ROOT);
+    let found = unsafe { translate_from(root, VirtualMemorySystem::Sv39.levels(), va) }?;
+    Some((found.pa, found.flags))
+}
 
-    pub fn print(&self, f: &mut Formatter) {
+/// Map a page in the kernel's half, outside the direct map. Shared with every address
+/// space, as long as the root entry it's under was there before they were made.
+///
+/// # Safety
+/// Nothing else is changing the kernel's tables, and nothing uses the old mapping at `va`.
+pub(crate) unsafe fn map_kernel(
+    va: VirtAddr,
+    pa: PhysAddr,
+    flags: EntryFlags,
+) -> Result<(), MapError> {
+    if va.as_u64() < PHYS_OFFSET {
+        return Err(MapError::OutOfRange);
+    }
+    let root = core::ptr::addr_of_mut!(KERNEL_ROOT);
+    let entry = walk_from(root, VirtualMemorySystem::Sv39.levels(), va, true)?.unwrap();
+    if entry.valid() {
+        return Err(MapError::AlreadyMapped);
+    }
+    let flags = flags | EntryFlags::VALID | EntryFlags::ACCESSED | EntryFlags::DIRTY;
+    *entry = Entry::from_parts(pa.ppn(), flags);
+    // `zero` for the ASID, or it'd skip global entries.
+    asm!("sfence.vma {va}, zero", va = in(reg) va.as_u64());
+    Ok(())
+}
 
+/// Unmap the page at `va` in the kernel's half and return what it mapped. A bigger page
+/// it's part of is split first, leaving the rest of it mapped. Only flushes this hart's
+/// TLB.
+///
+/// # Safety
+/// Nothing else is changing the kernel's tables, and nothing uses the page at `va` any
+/// more.
+pub(crate) unsafe fn unmap_kernel(va: VirtAddr) -> Result<Option<Entry>, MapError> {
+    if va.as_u64() < PHYS_OFFSET {
+        return Err(MapError::OutOfRange);
+    }
+    let mut table = core::ptr::addr_of_mut!(KERNEL_ROOT);
+    for level in (1..VirtualMemorySystem::Sv39.levels()).rev() {
+        let entry = &mut (*table).entries[va.vpn(level)];
+        if !entry.valid() {
+            return Ok(None);
+        }
+        if entry.leaf() {
+            split(entry, level)?;
+        }
+        table = table_at(entry.address());
     }
+    let entry = core::mem::replace(&mut (*table).entries[va.vpn(0)], Entry(0));
+    asm!("sfence.vma {va}, zero", va = in(reg) va.as_u64());
+    Ok(entry.valid().then_some(entry))
+}
+
+unsafe fn walk_mappings(
+    entries: &[Entry],
+    level: usize,
+    base: VirtAddr,
+    f: &mut impl FnMut(VirtAddr, PhysAddr, EntryFlags, u64),
+) {
+    let size = 1 << (12 + 9 * level);
+    for (i, entry) in entries.iter().enumerate() {
+        if !entry.valid() {
+            continue;
+        }
+        let va = base + i as u64 * size;
+        if entry.leaf() {
+            f(va, entry.address(), entry.flags(), size);
+        } else if level > 0 {
+            let table = &*table_at(entry.address());
+            walk_mappings(&table.entries, level - 1, va, f);
+        }
+    }
+}
+
+/// Free the tables under `entries`, which are at `level`.
+unsafe fn free_tables(entries: &[Entry], level: usize) {
+    if level == 0 {
+        return;
+    }
+    for entry in entries {
+        if entry.valid() && entry.non_leaf() {
+            let table = table_at(entry.address());
+            free_tables(&(*table).entries, level - 1);
+            free_frame(entry.address());
+        }
+    }
+}
+
+impl Drop for PageTableRoot {
+    fn drop(&mut self) {
+        // The upper half is the kernel's.
+        unsafe {
+            free_tables(&self.root.as_ref().entries[..HALF], self.mode.levels() - 1);
+            free_frame(self.address());
+        }
+    }
+}
+
+/// Log what each bit of an [`Entry`] means.
+pub fn log_entry_flags() {
+    for i in 0..64 {
+        log::debug!("{:?}", Entry(1 << i));
+    }
+}
+
+/// Switch address space and flush the TLB. [`kernel_satp`] goes back to the kernel's.
+///
+/// # Safety
+/// Whatever runs next has to be mapped in the new address space.
+pub unsafe fn set_satp(satp: u64) {
+    asm!("csrw satp, {satp}", "sfence.vma", satp = in(reg) satp);
+}
+
+/// Drop any TLB entries for the page at `va` in address space `asid`.
+pub fn flush_page(va: VirtAddr, asid: u16) {
+    let va = va.as_u64();
+    unsafe { asm!("sfence.vma {va}, {asid}", va = in(reg) va, asid = in(reg) asid as u64) };
 }
 
 
@@ -173,22 +851,88 @@ pub mod test {
     }
 
     #[test_case]
-    fn page_offset_all1s() {
-        assert_eq!(0b111111111111, PhysicalAddr(u64::MAX).page_offset())
+    fn page_table_map_translate() {
+        let mut root = PageTableRoot::new().unwrap();
+        let frame = alloc_frame().unwrap();
+        let flags = EntryFlags::READ | EntryFlags::USER;
+        let va = VirtAddr::new;
+        assert!(root.translate(va(PHYS_OFFSET)).is_none(), "kernel half is out of reach");
+        root.map(va(0x10000), frame, flags).unwrap();
+        assert_eq!(root.map(va(0x10000), frame, flags), Err(MapError::AlreadyMapped));
+
+        let (pa, got) = root.translate(va(0x10123)).unwrap();
+        assert_eq!(pa, frame + 0x123);
+        assert!(got.contains(flags | EntryFlags::VALID));
+        assert!(root.translate(va(0x11000)).is_none());
+
+        assert!(root.unmap(va(0x10000)).is_some());
+        assert!(root.translate(va(0x10000)).is_none());
+        unsafe { free_frame(frame) };
     }
 
     #[test_case]
-    fn pp0_all1s() {
-        assert_eq!(0b111111111, PhysicalAddr(u64::MAX).ppn0())
+    fn software_walk() {
+        let mut root = PageTableRoot::with_mode(VirtualMemorySystem::Sv48).unwrap();
+        let va = VirtAddr::new;
+        let flags = EntryFlags::READ | EntryFlags::USER;
+        root.map(va(0x2000), PhysAddr::new(0x8000_0000), flags).unwrap();
+        let found = translate(&root, va(0x2010)).unwrap();
+        assert_eq!((found.pa, found.level), (PhysAddr::new(0x8000_0010), 0));
+        assert!(translate(&root, va(0x3000)).is_none());
+        assert!(translate(&root, va(1 << 50)).is_none(), "not canonical for Sv48");
+
+        // The kernel's half, through the direct map.
+        let on_heap = alloc::boxed::Box::new(0u64);
+        let va = VirtAddr::from_ptr(&*on_heap);
+        let found = translate(&root, va).unwrap();
+        assert_eq!(found.pa, virt_to_phys(va));
+        assert!(found.level > 0);
+        assert_eq!(translate_current(va), Some(found));
     }
 
     #[test_case]
-    fn pp2_all1s() {
-        assert_eq!(0b111111111, PhysicalAddr(u64::MAX).ppn1())
+    fn page_table_modes() {
+        let high = VirtAddr::new(1 << 40);
+        let ram = PhysAddr::new(0x8000_0000);
+        let mut sv39 = PageTableRoot::with_mode(VirtualMemorySystem::Sv39).unwrap();
+        assert_eq!(sv39.map(high, ram, EntryFlags::READ), Err(MapError::OutOfRange));
+
+        for mode in [VirtualMemorySystem::Sv48, VirtualMemorySystem::Sv57] {
+            let mut root = PageTableRoot::with_mode(mode).unwrap();
+            root.map(high, ram, EntryFlags::READ).unwrap();
+            assert_eq!(root.translate(high + 8).map(|(pa, _)| pa), Some(ram + 8));
+            assert_eq!(root.satp(1) >> 60, mode.satp_mode() >> 60);
+        }
+        assert_eq!(
+            VirtualMemorySystem::from_mmu_type("riscv,sv48"),
+            Some(VirtualMemorySystem::Sv48)
+        );
+        assert_eq!(VirtualMemorySystem::from_mmu_type("riscv,none"), None);
     }
 
     #[test_case]
-    fn pp3_all1s() {
-        assert_eq!(0b11111111111111111111111111, PhysicalAddr(u64::MAX).ppn2())
+    fn mark_io_splits_partial_pages() {
+        const MEGA: u64 = 1 << 21;
+        let flags = EntryFlags::VALID | EntryFlags::READ | EntryFlags::WRITE;
+        let mut entries = [0, 1, 2, 3].map(|i| Entry::from_parts(i * MEGA >> 12, flags));
+        let ram = [PhysicalAddressRange::from_reg(
+            MEGA + MEGA / 2,
+            MEGA + MEGA / 2,
+            crate::hwinfo::PhysicalAddressKind::Usable,
+            "RAM",
+        )
+        .unwrap()];
+        unsafe { mark_io(&mut entries, 1, PhysAddr::new(0), &ram).unwrap() };
+
+        assert_eq!(entries[0].pbmt(), Some(Pbmt::Io));
+        assert_eq!(entries[2].pbmt(), Some(Pbmt::Pma));
+        assert_eq!(entries[3].pbmt(), Some(Pbmt::Io));
+        assert!(entries[1].non_leaf());
+        let split = unsafe { &(*table_at(entries[1].address())).entries };
+        assert_eq!(split[0].pbmt(), Some(Pbmt::Io));
+        assert_eq!(split[0].address(), PhysAddr::new(MEGA));
+        assert_eq!(split[ENTRIES / 2].pbmt(), Some(Pbmt::Pma));
+        assert_eq!(split[ENTRIES - 1].address(), PhysAddr::new(2 * MEGA - PAGE_SIZE));
+        unsafe { free_frame(entries[1].address()) };
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,347 @@
+//! Virtual memory regions and where they map to.
+//!
+//! [`MemoryRegions`] keeps regions sorted and apart. Adding one over others replaces the
+//! parts it covers, cutting the ones that stick out either side, and
+//! [`PageTableRoot::map_all`] puts the lot in a page table.
+//!
+//! The kernel uses one for device registers: [`ioremap`] maps them into [`IO_WINDOW`], the
+//! last gigabyte of the upper half, as [`Pbmt::Io`]. [`init_io_window`] takes that
+//! gigabyte from the direct map, which only has devices there if anything.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{
+    alloc_frame, map_kernel, phys_to_virt, shrink_direct_map, unmap_kernel, virt_to_phys, Entry,
+    EntryFlags, MapError, PageTableRoot, Pbmt, KERNEL_ROOT, PAGE_SIZE,
+};
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    hwinfo::HwInfo,
+    log,
+    prelude::*,
+    sync::IrqSafeMutex,
+    tlb,
+};
+
+/// Virtual addresses `[start, end)` mapping to physical addresses from `maps_to` up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub maps_to: PhysAddr,
+    pub flags: EntryFlags,
+    pub description: &'static str,
+}
+
+impl Region {
+    pub fn new(
+        virt: Range<VirtAddr>,
+        maps_to: PhysAddr,
+        flags: EntryFlags,
+        description: &'static str,
+    ) -> Self {
+        Region {
+            start: virt.start,
+            end: virt.end,
+            maps_to,
+            flags,
+            description,
+        }
+    }
+
+    /// Virtual addresses the same as the physical ones.
+    pub fn identity(range: Range<PhysAddr>, flags: EntryFlags, description: &'static str) -> Self {
+        let virt = VirtAddr::new(range.start.as_u64())..VirtAddr::new(range.end.as_u64());
+        Self::new(virt, range.start, flags, description)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, va: VirtAddr) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    pub fn translate(&self, va: VirtAddr) -> Option<PhysAddr> {
+        self.contains(va).then(|| self.maps_to + (va - self.start))
+    }
+
+    /// The part of this in `start..end`, still mapping to the same physical addresses.
+    fn slice(&self, start: VirtAddr, end: VirtAddr) -> Region {
+        Region {
+            start,
+            end,
+            maps_to: self.maps_to + (start - self.start),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryRegions {
+    /// Sorted by address, and no two overlap.
+    regions: Vec<Region>,
+}
+
+impl MemoryRegions {
+    pub const fn new() -> Self {
+        MemoryRegions {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Add `region`, replacing whatever mapped the same addresses before.
+    pub fn add(&mut self, region: Region) {
+        if region.start >= region.end {
+            return;
+        }
+        self.remove(region.start..region.end);
+        let at = self.regions.partition_point(|r| r.start < region.start);
+        self.regions.insert(at, region);
+    }
+
+    /// Stop mapping `range`. Regions partly in it lose that part, and one that covers it is
+    /// split in two.
+    pub fn remove(&mut self, range: Range<VirtAddr>) {
+        let mut kept = Vec::with_capacity(self.regions.len() + 1);
+        for region in self.regions.drain(..) {
+            if region.end <= range.start || range.end <= region.start {
+                kept.push(region);
+                continue;
+            }
+            if region.start < range.start {
+                kept.push(region.slice(region.start, range.start));
+            }
+            if range.end < region.end {
+                kept.push(region.slice(range.end, region.end));
+            }
+        }
+        self.regions = kept;
+    }
+
+    pub fn find(&self, va: VirtAddr) -> Option<&Region> {
+        let at = self.regions.partition_point(|r| r.end <= va);
+        self.regions.get(at).filter(|region| region.contains(va))
+    }
+
+    /// Physical address and flags `va` maps to.
+    pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, EntryFlags)> {
+        let region = self.find(va)?;
+        Some((region.translate(va)?, region.flags))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    /// The lowest page aligned address in `window` with `size` bytes free after it.
+    pub fn find_free(&self, window: Range<VirtAddr>, size: u64) -> Option<VirtAddr> {
+        let mut start = window.start;
+        for region in &self.regions {
+            if region.end <= start {
+                continue;
+            }
+            if region.start >= start.checked_add(size)? {
+                break;
+            }
+            start = region.end.align_up(PAGE_SIZE)?;
+        }
+        (start.checked_add(size)? <= window.end).then_some(start)
+    }
+}
+
+impl PageTableRoot {
+    /// Map every page of every region in `regions`, which have to be page aligned.
+    pub fn map_all(&mut self, regions: &MemoryRegions) -> Result<(), MapError> {
+        for region in regions.iter() {
+            let aligned = [region.start, region.end].map(VirtAddr::is_page_aligned);
+            if aligned != [true; 2] || !region.maps_to.is_page_aligned() {
+                return Err(MapError::Misaligned);
+            }
+            for offset in (0..region.size()).step_by(PAGE_SIZE as usize) {
+                self.map(region.start + offset, region.maps_to + offset, region.flags)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where [`ioremap`] puts device registers. The last root entry of the kernel's Sv39 half.
+pub const IO_WINDOW: Range<VirtAddr> =
+    VirtAddr::new(0xffff_ffff_c000_0000)..VirtAddr::new(u64::MAX);
+
+static IO_WINDOW_READY: AtomicBool = AtomicBool::new(false);
+/// What's mapped in [`IO_WINDOW`].
+static IO_REGIONS: IrqSafeMutex<MemoryRegions> = IrqSafeMutex::new(MemoryRegions::new());
+
+/// Swap the direct map's last gigabyte for a table [`ioremap`] can fill in. Leaves it be if
+/// there's RAM there.
+///
+/// # Safety
+/// Before the other harts start, since only this hart forgets the old root entry, and
+/// before any address space is made, since they copy the kernel's root entries. Nothing
+/// may be using the direct map's last gigabyte.
+pub unsafe fn init_io_window(hwinfo: &HwInfo) {
+    if claim_window(&IO_WINDOW, hwinfo, "devices") {
+        IO_WINDOW_READY.store(true, Ordering::Release);
+    }
+}
+
+/// Take the gigabyte of the direct map at `window` for mappings of the kernel's own,
+/// giving its root entry an empty table. Returns false and leaves it be if there's RAM
+/// there. `what` goes there, for the log.
+///
+/// Only this hart's TLB is flushed. That's enough because it runs before the other harts
+/// start, so none of them can have the old entry.
+///
+/// # Safety
+/// As [`init_io_window`]. `window` is one root entry's worth, in the kernel's half.
+pub(crate) unsafe fn claim_window(
+    window: &Range<VirtAddr>,
+    hwinfo: &HwInfo,
+    what: &str,
+) -> bool {
+    let window_pa = virt_to_phys(window.start);
+    if hwinfo.ram.iter().any(|ram| ram.end > window_pa) {
+        log::warn!("RAM past 0x{:x}: no room for {}", window_pa, what);
+        return false;
+    }
+    let table = match alloc_frame() {
+        Some(table) => table,
+        None => {
+            log::warn!("no memory for a table for {}", what);
+            return false;
+        }
+    };
+    let root = &mut *core::ptr::addr_of_mut!(KERNEL_ROOT);
+    root.entries[window.start.vpn(2)] =
+        Entry::from_parts(table.ppn(), EntryFlags::VALID | EntryFlags::GLOBAL);
+    core::arch::asm!("sfence.vma");
+    shrink_direct_map(window.start);
+    true
+}
+
+/// Map `size` bytes of device registers at physical address `pa` for the kernel, and
+/// return where. In [`IO_WINDOW`] if [`init_io_window`] worked, and the direct map if not.
+pub fn ioremap(pa: PhysAddr, size: u64, description: &'static str) -> VirtAddr {
+    if !IO_WINDOW_READY.load(Ordering::Acquire) {
+        return phys_to_virt(pa);
+    }
+    let offset = pa.page_offset();
+    let start = pa.align_down(PAGE_SIZE);
+    let size = (offset + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let flags = EntryFlags::READ | EntryFlags::WRITE | EntryFlags::GLOBAL | Pbmt::Io.flags();
+
+    let mut regions = IO_REGIONS.lock();
+    let va = match regions.find_free(IO_WINDOW, size) {
+        Some(va) => va,
+        None => {
+            log::warn!(
+                "ioremap {}: I/O window full, using the direct map",
+                description
+            );
+            return phys_to_virt(pa);
+        }
+    };
+    // Taken even if mapping fails part way, so pages that did get mapped aren't reused.
+    regions.add(Region::new(va..va + size, start, flags, description));
+    for page in (0..size).step_by(PAGE_SIZE as usize) {
+        if let Err(err) = unsafe { map_kernel(va + page, start + page, flags) } {
+            log::warn!("ioremap {}: {}, using the direct map", description, err);
+            return phys_to_virt(pa);
+        }
+    }
+    drop(regions);
+    // map_kernel only flushed this hart, and another may remember the pages as unmapped.
+    tlb::shootdown(va.as_u64()..(va + size).as_u64(), None);
+    va + offset
+}
+
+/// Undo the [`ioremap`] that returned `va`. Does nothing if that was in the direct map.
+pub fn iounmap(va: VirtAddr) {
+    let mut regions = IO_REGIONS.lock();
+    let Some(region) = regions.find(va) else {
+        return;
+    };
+    let range = region.start..region.end;
+    for page in (range.start.as_u64()..range.end.as_u64()).step_by(PAGE_SIZE as usize) {
+        // Only ioremap and this change the window's tables, and IO_REGIONS is held.
+        unsafe { unmap_kernel(VirtAddr::new(page)) }.ok();
+    }
+    regions.remove(range.clone());
+    drop(regions);
+    tlb::shootdown(range.start.as_u64()..range.end.as_u64(), None);
+}
+
+/// Call `f` with each [`ioremap`]ped region.
+pub fn for_each_io_region(f: impl FnMut(&Region)) {
+    IO_REGIONS.lock().iter().for_each(f);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pagetable::kernel_translate;
+
+    #[test_case]
+    fn regions_split_on_overlap() {
+        let flags = EntryFlags::READ;
+        let mut regions = MemoryRegions::new();
+        let (va, pa) = (VirtAddr::new, PhysAddr::new);
+        regions.add(Region::new(va(0x1000)..va(0x5000), pa(0x8000_0000), flags, "a"));
+        regions.add(Region::new(va(0x2000)..va(0x3000), pa(0x9000_0000), flags, "b"));
+
+        let parts: Vec<_> = regions
+            .iter()
+            .map(|r| (r.start.as_u64(), r.end.as_u64(), r.maps_to.as_u64(), r.description))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                (0x1000, 0x2000, 0x8000_0000, "a"),
+                (0x2000, 0x3000, 0x9000_0000, "b"),
+                (0x3000, 0x5000, 0x8000_2000, "a"),
+            ]
+        );
+        assert_eq!(regions.translate(va(0x3456)), Some((pa(0x8000_2456), flags)));
+        assert_eq!(regions.translate(va(0x5000)), None);
+        assert_eq!(regions.find_free(va(0)..va(0x10000), 0x1000), Some(va(0)));
+        assert_eq!(regions.find_free(va(0x1000)..va(0x10000), 0x1000), Some(va(0x5000)));
+
+        regions.remove(va(0)..va(0x2800));
+        assert_eq!(regions.find(va(0x2800)).map(|r| r.maps_to), Some(pa(0x9000_0800)));
+        assert_eq!(regions.find_free(va(0)..va(0x10000), 0x3000), Some(va(0x5000)));
+    }
+
+    #[test_case]
+    fn map_all_offsets() {
+        let mut root = PageTableRoot::new().unwrap();
+        let mut regions = MemoryRegions::new();
+        regions.add(Region::new(
+            VirtAddr::new(0x40_0000)..VirtAddr::new(0x40_2000),
+            PhysAddr::new(0x8000_0000),
+            EntryFlags::READ,
+            "x",
+        ));
+        root.map_all(&regions).unwrap();
+        assert_eq!(
+            root.translate(VirtAddr::new(0x40_1234)).map(|(pa, _)| pa),
+            Some(PhysAddr::new(0x8000_1234))
+        );
+    }
+
+    #[test_case]
+    fn ioremap_keeps_page_offset() {
+        let va = ioremap(PhysAddr::new(0x1000_0005), 8, "test");
+        assert_eq!(va.page_offset(), 5);
+        if IO_WINDOW.contains(&va) {
+            iounmap(va);
+            assert!(kernel_translate(va).is_none());
+            assert!(IO_REGIONS.lock().find(va).is_none());
+        }
+    }
+}
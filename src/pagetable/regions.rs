@@ -0,0 +1,132 @@
+//! Sorting out physical memory when the device tree doesn't add up.
+//!
+//! Firmware doesn't always agree with itself: OpenSBI versions differ in what they reserve,
+//! and a reservation can land in the middle of RAM we'd have used, or on top of a device.
+//! [`subtract`] cuts reservations and devices out of RAM, [`unused`] finds what's left
+//! of RAM after everything in a layout, and [`conflicts`] finds what overlaps in one so it
+//! can be warned about instead of panicking. [`memmap`](crate::memmap) puts them together.
+
+use core::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+};
+
+use crate::{
+    addr::PhysAddr,
+    hwinfo::{PhysicalAddressKind, PhysicalAddressRange},
+    prelude::*,
+};
+
+/// Two ranges in a memory layout that overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conflict {
+    pub first: PhysicalAddressRange,
+    pub second: PhysicalAddressRange,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:#x}..{:#x} overlaps {} {:#x}..{:#x}",
+            self.first.description,
+            self.first.start,
+            self.first.end,
+            self.second.description,
+            self.second.start,
+            self.second.end
+        )
+    }
+}
+
+/// `range` with each of `holes` taken out of it.
+pub fn subtract<T: Copy + Ord>(range: Range<T>, holes: &[Range<T>]) -> Vec<Range<T>> {
+    let mut pieces = vec![range];
+    for hole in holes {
+        pieces = pieces
+            .into_iter()
+            .flat_map(|piece| {
+                [
+                    piece.start..piece.end.min(hole.start),
+                    piece.start.max(hole.end)..piece.end,
+                ]
+            })
+            .filter(|piece| piece.start < piece.end)
+            .collect();
+    }
+    pieces
+}
+
+/// Every pair of ranges in `layout` that overlap, each once.
+pub fn conflicts(layout: &[PhysicalAddressRange]) -> Vec<Conflict> {
+    let mut sorted = layout.to_vec();
+    sorted.sort_by_key(|range| (range.start, range.end));
+    let mut conflicts = Vec::new();
+    // Ranges that haven't ended by the start of the current one.
+    let mut open: Vec<PhysicalAddressRange> = Vec::new();
+    for range in sorted.into_iter().filter(|range| range.start < range.end) {
+        open.retain(|other| other.end > range.start);
+        conflicts.extend(open.iter().map(|&first| Conflict {
+            first,
+            second: range,
+        }));
+        open.push(range);
+    }
+    conflicts
+}
+
+/// The parts of `ram` nothing in `layout` uses, sorted.
+pub fn unused(
+    ram: &[PhysicalAddressRange],
+    layout: &[PhysicalAddressRange],
+) -> Vec<PhysicalAddressRange> {
+    let used: Vec<Range<PhysAddr>> = layout.iter().map(|range| range.as_range()).collect();
+    let mut unused: Vec<PhysicalAddressRange> = ram
+        .iter()
+        .flat_map(|ram| subtract(ram.as_range(), &used))
+        .map(|piece| PhysicalAddressRange::new(piece, PhysicalAddressKind::Usable, "unused"))
+        .collect();
+    unused.sort_by_key(|range| range.start);
+    unused
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ram_around_holes() {
+        assert_eq!(
+            subtract(0x1000..0x9000, &[0x2000..0x3000, 0x8000..0xa000, 0..0x800]),
+            [0x1000..0x2000, 0x3000..0x8000]
+        );
+        assert_eq!(subtract(0x1000..0x2000, &[0..0x4000]), []);
+    }
+
+    #[test_case]
+    fn overlapping_layout() {
+        let range = |range: Range<u64>, description| {
+            let range = PhysAddr::new(range.start)..PhysAddr::new(range.end);
+            PhysicalAddressRange::new(range, PhysicalAddressKind::Reserved, description)
+        };
+        let ram = [range(0x1000..0x10000, "ram")];
+        let layout = [
+            range(0x8000..0x9000, "heap"),
+            range(0x2000..0x6000, "opensbi"),
+            range(0x3000..0x4000, "mmode_resv"),
+            range(0x5000..0x8000, "uart"),
+        ];
+
+        let found: Vec<_> = conflicts(&layout)
+            .iter()
+            .map(|conflict| (conflict.first.description, conflict.second.description))
+            .collect();
+        assert_eq!(found, [("opensbi", "mmode_resv"), ("opensbi", "uart")]);
+
+        let free: Vec<_> = unused(&ram, &layout)
+            .iter()
+            .map(|range| range.start.as_u64()..range.end.as_u64())
+            .collect();
+        assert_eq!(free, [0x1000..0x2000, 0x9000..0x10000]);
+    }
+}
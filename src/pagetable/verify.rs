@@ -0,0 +1,250 @@
+//! Checking a page table follows the rules.
+//!
+//! [`verify`] walks every table under a root and reports each entry the MMU would fault
+//! on, or that the kernel never means to make: reserved bits set, writable but not
+//! readable, a superpage that isn't aligned to its size, a table where there should be a
+//! page, or a user page in the kernel's half.
+
+use core::fmt::{self, Display, Formatter};
+
+use super::{table_at, Entry, PageTableRoot, HALF};
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// Bits the spec reserves, like PBMT's fourth encoding, or D, A and U on a table.
+    ReservedBits,
+    WriteWithoutRead,
+    /// A superpage whose physical address isn't aligned to its size.
+    MisalignedLeaf,
+    /// Points at a table at the last level.
+    NoLeaf,
+    UserInKernelHalf,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::ReservedBits => write!(f, "reserved bits set"),
+            Problem::WriteWithoutRead => write!(f, "writable but not readable"),
+            Problem::MisalignedLeaf => write!(f, "superpage not aligned to its size"),
+            Problem::NoLeaf => write!(f, "table at the last level"),
+            Problem::UserInKernelHalf => write!(f, "user page in the kernel's half"),
+        }
+    }
+}
+
+/// An entry that breaks the rules, for the `level` sized piece of address space at `va`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub va: u64,
+    pub level: usize,
+    pub entry: Entry,
+    pub problem: Problem,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x} (level {}): {}: {:?}",
+            self.va, self.level, self.problem, self.entry
+        )
+    }
+}
+
+/// Every entry under `root` that breaks the rules, in address order.
+pub fn verify(root: &PageTableRoot) -> Result<(), Vec<Violation>> {
+    let levels = root.mode.levels();
+    let entries = unsafe { &root.root.as_ref().entries };
+    let mut violations = Vec::new();
+    // Addresses in the upper half are sign extended from the top bit.
+    let upper = !(root.mode.lower_half_end() * 2 - 1);
+    let size = 1 << (12 + 9 * (levels - 1));
+    for (i, entry) in entries.iter().enumerate() {
+        let kernel = i >= HALF;
+        let va = (i as u64 * size) | if kernel { upper } else { 0 };
+        unsafe { check(*entry, levels - 1, va, kernel, &mut violations) };
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// Check `entry`, at `level` and mapping `va`, and the tables under it.
+///
+/// # Safety
+/// Any table it points at is a page table.
+unsafe fn check(
+    entry: Entry,
+    level: usize,
+    va: u64,
+    kernel: bool,
+    violations: &mut Vec<Violation>,
+) {
+    if !entry.valid() {
+        return;
+    }
+    let mut report = |problem| {
+        violations.push(Violation {
+            va,
+            level,
+            entry,
+            problem,
+        })
+    };
+    if entry.reserved() != 0 || entry.pbmt().is_none() {
+        report(Problem::ReservedBits);
+    }
+    if entry.write() && !entry.read() {
+        report(Problem::WriteWithoutRead);
+    }
+    if kernel && entry.user() {
+        report(Problem::UserInKernelHalf);
+    }
+
+    if entry.leaf() {
+        if !entry.address().is_aligned(1 << (12 + 9 * level)) {
+            report(Problem::MisalignedLeaf);
+        }
+        return;
+    }
+    // D, A and U are reserved on tables, and so is PBMT.
+    if entry.dirty() || entry.accessed() || entry.user() || entry.0 >> 61 != 0 {
+        report(Problem::ReservedBits);
+    }
+    if level == 0 {
+        report(Problem::NoLeaf);
+        return;
+    }
+
+    let table = &*table_at(entry.address());
+    let size = 1 << (12 + 9 * (level - 1));
+    for (i, entry) in table.entries.iter().enumerate() {
+        check(*entry, level - 1, va + i as u64 * size, kernel, violations);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        addr::{PhysAddr, VirtAddr},
+        pagetable::{alloc_frame, free_frame, EntryFlags, VirtualMemorySystem, PAGE_SIZE},
+        rand,
+    };
+
+    /// Deterministic from its seed, so a failure can be told apart from the next run's.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test_case]
+    fn broken_entries() {
+        let mut root = PageTableRoot::with_mode(VirtualMemorySystem::Sv48).unwrap();
+        let va = VirtAddr::new(0x1000);
+        let flags = EntryFlags::READ | EntryFlags::USER;
+        root.map(va, PhysAddr::new(0x8000_0000), flags).unwrap();
+        assert_eq!(verify(&root), Ok(()));
+
+        let entry = root.walk(va, false).unwrap().unwrap();
+        let flags = EntryFlags::VALID | EntryFlags::WRITE | EntryFlags::USER;
+        *entry = Entry::from_parts(0x8000_0000 >> 12, flags);
+        let violations = verify(&root).unwrap_err();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            (violations[0].va, violations[0].level, violations[0].problem),
+            (0x1000, 0, Problem::WriteWithoutRead)
+        );
+        root.unmap(va);
+
+        // A 2 MiB page at an address that's only 4 KiB aligned, in place of the last
+        // level's table.
+        let middle = unsafe {
+            let level2 = table_at(root.root.as_ref().entries[0].address());
+            &mut (*table_at((*level2).entries[0].address())).entries[0]
+        };
+        let table = core::mem::replace(
+            middle,
+            Entry::from_parts(0x8000_1000 >> 12, EntryFlags::VALID | EntryFlags::READ),
+        );
+        let violations = verify(&root).unwrap_err();
+        assert_eq!(
+            (violations[0].va, violations[0].level, violations[0].problem),
+            (0, 1, Problem::MisalignedLeaf)
+        );
+        *middle = table;
+        assert_eq!(verify(&root), Ok(()));
+    }
+
+    #[test_case]
+    fn random_mappings() {
+        let seed = rand::u64() | 1;
+        let mut rng = XorShift(seed);
+        let frame = alloc_frame().unwrap();
+        let flags = [
+            EntryFlags::READ | EntryFlags::USER,
+            EntryFlags::READ | EntryFlags::WRITE | EntryFlags::USER,
+            EntryFlags::READ | EntryFlags::EXECUTE | EntryFlags::USER,
+        ];
+
+        for mode in [VirtualMemorySystem::Sv39, VirtualMemorySystem::Sv48] {
+            let mut root = PageTableRoot::with_mode(mode).unwrap();
+            let mut model = BTreeMap::new();
+            let mut starts = Vec::new();
+            for _ in 0..64 {
+                // Runs of pages, half of them in the first 64 MiB so they share tables. A
+                // third of the time, unmap one that was mapped.
+                let pages = rng.next() % 8 + 1;
+                let span = match rng.next() % 2 {
+                    0 => 1 << 26,
+                    _ => mode.lower_half_end(),
+                };
+                let map = starts.is_empty() || rng.next() % 3 != 0;
+                let start = if map {
+                    rng.next() % (span / PAGE_SIZE - pages) * PAGE_SIZE
+                } else {
+                    starts[rng.next() as usize % starts.len()]
+                };
+                starts.push(start);
+                let flags = flags[rng.next() as usize % flags.len()];
+                for page in 0..pages {
+                    let va = VirtAddr::new(start + page * PAGE_SIZE);
+                    if map && !model.contains_key(&va) {
+                        root.map(va, frame, flags).unwrap();
+                        model.insert(va, flags);
+                    } else if !map {
+                        assert_eq!(root.unmap(va).is_some(), model.remove(&va).is_some());
+                    }
+                }
+            }
+
+            assert_eq!(verify(&root), Ok(()), "seed {:#x}", seed);
+            let mut mapped = Vec::new();
+            root.for_each_mapping(|va, pa, flags, size| mapped.push((va, pa, flags, size)));
+            assert_eq!(mapped.len(), model.len(), "seed {:#x}", seed);
+            for ((va, pa, got, size), (want_va, want)) in mapped.into_iter().zip(model.iter()) {
+                assert_eq!(
+                    (va, pa, size),
+                    (*want_va, frame, PAGE_SIZE),
+                    "seed {:#x}",
+                    seed
+                );
+                assert!(got.contains(*want | EntryFlags::VALID), "seed {:#x}", seed);
+                assert_eq!(root.translate(va + 8), Some((frame + 8, got)));
+            }
+        }
+        unsafe { free_frame(frame) };
+    }
+}
@@ -1,29 +1,168 @@
-use crate::console::sbi_console;
+//! Panics.
+//!
+//! The first hart to panic owns the rest of the kernel's life. It stops every other hart
+//! with an IPI, takes the console from whoever had it, prints an [oops](crate::oops) report,
+//! keeps a copy in the [`pstore`] for next boot, and does what the [`Policy`] says. Harts
+//! that get the IPI, or panic or print after that, [`park`] instead.
+
+use crate::cmdline;
+use crate::console::{self, sbi_console};
+use crate::hart_local;
+use crate::log;
+use crate::oops::{self, Oops};
+use crate::pstore;
+use crate::sbi::ipi::IPI_EXTENSION;
+use crate::sbi::reset::{ResetReason, ResetType, SYSTEM_RESET_EXTENSION};
+use crate::smp;
+use crate::sync::Once;
+use crate::time;
+use crate::trap;
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+/// One more than the id of the hart that's panicking, or `usize::MAX` if it panicked
+/// before it knew its id. 0 when no one is.
+static PANICKING: AtomicUsize = AtomicUsize::new(0);
+
+fn this_hart() -> usize {
+    hart_local::try_current_hart().map_or(usize::MAX, |hart| hart.0 + 1)
+}
+
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Acquire) != 0
+}
+
+/// Park this hart if some other hart is panicking.
+pub fn park_if_panicking() {
+    let owner = PANICKING.load(Ordering::Acquire);
+    if owner != 0 && owner != this_hart() {
+        park();
+    }
+}
+
+/// Stop this hart for good.
+pub fn park() -> ! {
+    crate::cpu::halt_forever()
+}
 
 #[panic_handler]
 #[no_mangle]
 pub fn panic(info: &PanicInfo) -> ! {
-    let mut io = unsafe { sbi_console() };
+    unsafe { riscv::register::sstatus::clear_sie() };
+    // A failing test goes back to the test runner.
+    #[cfg(test)]
+    crate::testing::panicked(info);
+    let me = this_hart();
+    match PANICKING.compare_exchange(0, me, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {}
+        Err(owner) if owner == me => {
+            // Something in the panic path panicked. Don't trust the console.
+            writeln!(unsafe { sbi_console() }, "panic while panicking: {info}").ok();
+            abort();
+        }
+        Err(_) => park(),
+    }
+
+    if let Some(ipi) = IPI_EXTENSION.get() {
+        let others = smp::other_harts();
+        if !others.is_empty() {
+            ipi.send_ipi(others).ok();
+        }
+    }
 
-    writeln!(io, "{info}").ok();
+    let mut io = unsafe { console::_panic_unlock() };
+    let mut oops = Oops::new(info);
+    if let Some(trap) = oops::take_trap() {
+        // Recorded by the trap handler we're panicking from.
+        oops = unsafe { oops.at_trap(trap) };
+    }
+    // First, in case the console's what's broken.
+    pstore::save(|w| oops.write(w));
+    oops.write(&mut io).ok();
+    match policy() {
+        Policy::Reboot(after) => writeln!(io, "rebooting in {} seconds", after.as_secs()).ok(),
+        Policy::Shell => writeln!(io, "entering the debugger").ok(),
+        _ => None,
+    };
+    // GDB gets the console.
+    drop(io);
+    crate::trap::gdbstub::panicked();
+    if policy() == Policy::Shell && trap::can_trap() {
+        trap::debugger::enter();
+    }
     abort();
 }
 
-#[cfg(not(any(features = "ndebug", test)))]
-#[no_mangle]
-extern "C" fn abort() -> ! {
-    loop {
-        core::hint::spin_loop();
+/// What to do once a panic's been reported. `panic=` on the command line: `halt`,
+/// `poweroff`, `reboot`, a number of seconds to wait before rebooting, or `shell` for the
+/// [debugger](crate::trap::debugger)'s prompt. Test runs always power off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Stop, and leave what's on the console. Without `panic=`, unless built with
+    /// `ndebug`.
+    Halt,
+    /// With a failure status, under QEMU. Without `panic=` if built with `ndebug`.
+    PowerOff,
+    /// A warm reboot after this long, so the [`pstore`](crate::pstore) is kept.
+    Reboot(Duration),
+    /// The `kdb>` prompt, then halt. Halts straight away if traps can't be taken.
+    Shell,
+}
+
+impl Policy {
+    pub fn parse(arg: &str) -> Option<Policy> {
+        Some(match arg {
+            "halt" => Policy::Halt,
+            "poweroff" => Policy::PowerOff,
+            "reboot" => Policy::Reboot(Duration::ZERO),
+            "shell" => Policy::Shell,
+            seconds => Policy::Reboot(Duration::from_secs(seconds.parse().ok()?)),
+        })
     }
 }
 
-#[cfg(any(features = "ndebug", test))]
+static POLICY: Once<Policy> = Once::new();
+
+/// Read `panic=` from the command line. Until then panics use the default.
+pub fn init() {
+    if let Some(arg) = cmdline::get("panic") {
+        match Policy::parse(arg) {
+            Some(policy) => {
+                POLICY.call_once(|| policy);
+            }
+            None => log::warn!("bad panic= argument {:?}", arg),
+        }
+    }
+}
+
+pub fn policy() -> Policy {
+    if cfg!(test) {
+        return Policy::PowerOff;
+    }
+    match POLICY.get() {
+        Some(&policy) => policy,
+        None if cfg!(feature = "ndebug") => Policy::PowerOff,
+        None => Policy::Halt,
+    }
+}
+
+/// Carry out the [`policy`]. Never the prompt: [`panic`] has had its chance at that.
 #[no_mangle]
 extern "C" fn abort() -> ! {
-    use crate::sbi::reset::{ResetReason, ResetType, SYSTEM_RESET_EXTENSION};
+    match policy() {
+        Policy::Halt | Policy::Shell => {}
+        Policy::PowerOff => power_off(),
+        Policy::Reboot(after) => reboot(after),
+    }
+    crate::cpu::halt_forever()
+}
+
+fn power_off() {
+    // SBI's shutdown exits QEMU with 0 whatever the reason.
+    crate::finisher::exit(crate::finisher::ExitCode::Failure(1));
     if let Some(srst) = SYSTEM_RESET_EXTENSION.get() {
         srst.reset(ResetType::Shutdown, ResetReason::SystemFailure)
             .ok();
@@ -31,6 +170,40 @@ extern "C" fn abort() -> ! {
 
     #[allow(deprecated)]
     crate::sbi::_legacy_shutdown().ok();
+}
+
+fn reboot(after: Duration) {
+    // Interrupts are off, so no timers. Without a clock there's no waiting either.
+    if let Some(start) = time::uptime() {
+        while time::uptime().is_some_and(|now| now - start < after) {
+            crate::cpu::relax();
+        }
+    }
+    if let Some(srst) = SYSTEM_RESET_EXTENSION.get() {
+        srst.reset(ResetType::WarmReboot, ResetReason::SystemFailure)
+            .ok();
+        srst.reset(ResetType::ColdReboot, ResetReason::SystemFailure)
+            .ok();
+    }
+}
 
-    loop {}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_policy() {
+        assert_eq!(Policy::parse("halt"), Some(Policy::Halt));
+        assert_eq!(
+            Policy::parse("reboot"),
+            Some(Policy::Reboot(Duration::ZERO))
+        );
+        assert_eq!(
+            Policy::parse("10"),
+            Some(Policy::Reboot(Duration::from_secs(10)))
+        );
+        assert_eq!(Policy::parse("shell"), Some(Policy::Shell));
+        assert_eq!(Policy::parse("explode"), None);
+        assert_eq!(policy(), Policy::PowerOff);
+    }
 }
@@ -0,0 +1,512 @@
+//! PCI, through a `pci-host-ecam-generic` host bridge.
+//!
+//! ECAM maps each function's 4 KiB of config space at `base + (bus << 20 | device << 15 |
+//! function << 12)`. Probing the bridge goes through every bus in its `bus-range` and
+//! keeps a [`PciDevice`] for each function it finds. Firmware on QEMU leaves the BARs
+//! empty, so memory BARs that don't have an address yet get one from the bridge's
+//! `ranges`, and then memory decoding and bus mastering are turned on. I/O BARs are
+//! left alone. Bridges to other buses aren't set up either, which QEMU's virt machine
+//! doesn't need.
+//!
+//! Interrupts are legacy INTx, routed by the bridge's `interrupt-map` to a PLIC
+//! interrupt. MSI needs an IMSIC, which the PLIC machines don't have, so it's only shown.
+
+use core::{
+    fmt::{self, Display, Formatter},
+    ops::Range,
+};
+
+use alloc::{format, sync::Arc};
+use spin::Mutex;
+
+use crate::{
+    addr::PhysAddr,
+    devices::{Driver, DtNode, ProbeError},
+    error::KernelError,
+    isr::plic::InterruptId,
+    log,
+    mmio::Reg,
+    pagetable::memory_map::ioremap,
+    prelude::*,
+};
+
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+pub const REVISION: usize = 0x08;
+pub const CLASS: usize = 0x09;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const SUBSYSTEM_ID: usize = 0x2e;
+pub const CAPABILITIES: usize = 0x34;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The space code in the top cell of a PCI address.
+const SPACE_MMIO32: u32 = 2;
+const SPACE_MMIO64: u32 = 3;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS: u8 = 8;
+
+static DEVICES: Mutex<Vec<Arc<PciDevice>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Where its config space is from the start of the ECAM region.
+    fn ecam_offset(self) -> u64 {
+        (self.bus as u64) << 20 | (self.device as u64) << 15 | (self.function as u64) << 12
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// `address` is physical, where the CPU sees it.
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+/// A function's config space.
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigSpace {
+    base: usize,
+}
+
+impl ConfigSpace {
+    fn reg<T: Copy>(&self, offset: usize) -> Reg<T> {
+        assert!(offset + core::mem::size_of::<T>() <= 4096);
+        // ECAM takes any naturally aligned access.
+        unsafe { Reg::new(self.base + offset) }
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        self.reg(offset).read()
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        self.reg(offset).read()
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        self.reg(offset).read()
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        self.reg(offset).write(value)
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        self.reg(offset).write(value)
+    }
+
+    /// Each capability's ID and offset, if the function has a list.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, usize)> + '_ {
+        let mut next = match self.read_u16(STATUS) & STATUS_CAPABILITIES {
+            0 => 0,
+            _ => self.read_u8(CAPABILITIES) as usize & !3,
+        };
+        // At most 48 fit after the header, which stops a list that loops.
+        (0..48).map_while(move |_| {
+            if next == 0 {
+                return None;
+            }
+            let offset = next;
+            next = self.read_u8(offset + 1) as usize & !3;
+            Some((self.read_u8(offset), offset))
+        })
+    }
+}
+
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class, subclass and programming interface.
+    pub class: (u8, u8, u8),
+    pub revision: u8,
+    pub bars: [Option<Bar>; 6],
+    /// Where INTx goes, if the function uses it.
+    pub interrupt: Option<InterruptId>,
+    pub config: ConfigSpace,
+}
+
+impl PciDevice {
+    pub fn class_name(&self) -> &'static str {
+        match (self.class.0, self.class.1) {
+            (0x01, 0x00) => "SCSI controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x09, _) => "input device",
+            (0x0c, 0x03) => "USB controller",
+            (0x0c, _) => "serial bus controller",
+            (0xff, _) | (0x00, _) => "unclassified device",
+            _ => "device",
+        }
+    }
+}
+
+/// Windows of bus addresses from `ranges` that BARs are given space from.
+struct Windows {
+    /// 32 bit memory: the next free bus address, the end, and what to add for the CPU's.
+    mmio32: Option<(u64, u64, u64)>,
+    mmio64: Option<(u64, u64, u64)>,
+}
+
+impl Windows {
+    /// Take `size` bytes, aligned to `size`. Returns the bus and CPU addresses.
+    fn alloc(&mut self, size: u64, is_64: bool) -> Option<(u64, u64)> {
+        // 64 bit BARs can go in either.
+        let windows = [&mut self.mmio64, &mut self.mmio32];
+        for (next, end, offset) in windows.into_iter().skip(!is_64 as usize).flatten() {
+            let start = (*next + size - 1) & !(size - 1);
+            if start + size <= *end {
+                *next = start + size;
+                return Some((start, start.wrapping_add(*offset)));
+            }
+        }
+        None
+    }
+}
+
+/// The memory windows in a host bridge's `ranges`.
+fn parse_ranges(node: &DtNode) -> Windows {
+    let mut windows = Windows {
+        mmio32: None,
+        mmio64: None,
+    };
+    let child_cells = 3;
+    let size_cells = node.u32("#size-cells", 0).unwrap_or(2);
+    let stride = (child_cells + node.address_cells + size_cells) as usize;
+    let count = node.prop("ranges").unwrap_or_default().len() / 4 / stride;
+    for i in 0..count {
+        let cell = i * stride;
+        let (Some(space), Some(bus), Some(cpu), Some(size)) = (
+            node.u32("ranges", cell),
+            node.cells("ranges", cell + 1, 2),
+            node.cells("ranges", cell + 3, node.address_cells),
+            node.cells("ranges", cell + 3 + node.address_cells as usize, size_cells),
+        ) else {
+            continue;
+        };
+        let window = Some((bus, bus + size, cpu.wrapping_sub(bus)));
+        match space >> 24 & 3 {
+            SPACE_MMIO32 => windows.mmio32 = window,
+            SPACE_MMIO64 => windows.mmio64 = window,
+            _ => {}
+        }
+    }
+    windows
+}
+
+/// The PLIC interrupt `interrupt-map` gives for INTx `pin` (1 to 4) of `address`. Assumes
+/// the parent is a PLIC, with no address cells and one interrupt cell.
+fn map_interrupt(node: &DtNode, address: PciAddress, pin: u8) -> Option<InterruptId> {
+    let mask = |i| node.u32("interrupt-map-mask", i).unwrap_or(!0);
+    let devfn = (address.device as u32) << 11 | (address.function as u32) << 8;
+    let key = [(address.bus as u32) << 16 | devfn, 0, 0, pin as u32];
+    let key: [u32; 4] = core::array::from_fn(|i| key[i] & mask(i));
+
+    // Child address, child interrupt, parent phandle, parent interrupt.
+    let stride = 6;
+    let count = node.prop("interrupt-map")?.len() / 4 / stride;
+    (0..count).find_map(|i| {
+        let entry: [u32; 4] = core::array::from_fn(|j| {
+            node.u32("interrupt-map", i * stride + j)
+                .unwrap_or_default()
+        });
+        match entry == key {
+            true => InterruptId::new(node.u32("interrupt-map", i * stride + 5)?),
+            false => None,
+        }
+    })
+}
+
+/// Read BAR `index`, sizing it by writing all ones. Returns it, whether it's 64 bit and
+/// so takes the next one too, and its address on the bus.
+fn read_bar(config: &ConfigSpace, index: usize) -> Option<(Bar, bool, u64)> {
+    let offset = BAR0 + index * 4;
+    let low = config.read_u32(offset);
+    config.write_u32(offset, !0);
+    let low_mask = config.read_u32(offset);
+    config.write_u32(offset, low);
+    if low_mask == 0 {
+        return None;
+    }
+
+    if low & BAR_IO != 0 {
+        let size = (!(low_mask & !3)).wrapping_add(1) & 0xffff;
+        let port = low & !3;
+        return Some((Bar::Io { port, size }, false, port as u64));
+    }
+
+    let is_64 = low & 0b110 == BAR_64 && index < 5;
+    let (address, mask) = match is_64 {
+        true => {
+            let high = config.read_u32(offset + 4);
+            config.write_u32(offset + 4, !0);
+            let high_mask = config.read_u32(offset + 4);
+            config.write_u32(offset + 4, high);
+            (
+                (high as u64) << 32 | (low & !0xf) as u64,
+                (high_mask as u64) << 32 | (low_mask & !0xf) as u64,
+            )
+        }
+        false => ((low & !0xf) as u64, (low_mask & !0xf) as u64 | !0 << 32),
+    };
+    let bar = Bar::Memory {
+        address,
+        size: (!mask).wrapping_add(1),
+        prefetchable: low & BAR_PREFETCHABLE != 0,
+    };
+    Some((bar, is_64, address))
+}
+
+/// Read a function's BARs, and give memory ones without an address some space.
+fn set_up_bars(config: &ConfigSpace, windows: &mut Windows, cpu_offset: u64) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < 6 {
+        let Some((mut bar, is_64, bus_address)) = read_bar(config, index) else {
+            index += 1;
+            continue;
+        };
+        if let Bar::Memory { address, size, .. } = &mut bar {
+            *address = match bus_address {
+                0 => match windows.alloc(*size, is_64) {
+                    Some((bus, cpu)) => {
+                        config.write_u32(BAR0 + index * 4, bus as u32);
+                        if is_64 {
+                            config.write_u32(BAR0 + index * 4 + 4, (bus >> 32) as u32);
+                        }
+                        cpu
+                    }
+                    None => {
+                        log::warn!("pci: no room for a {} byte BAR", size);
+                        0
+                    }
+                },
+                bus => bus.wrapping_add(cpu_offset),
+            };
+        }
+        bars[index] = Some(bar);
+        index += if is_64 { 2 } else { 1 };
+    }
+    bars
+}
+
+/// A probed host bridge.
+struct HostBridge {
+    base: PhysAddr,
+    buses: Range<u16>,
+    functions: usize,
+}
+
+impl Driver for HostBridge {
+    fn describe(&self) -> String {
+        format!(
+            "ECAM at 0x{:x}, buses {}-{}, {} functions",
+            self.base,
+            self.buses.start,
+            self.buses.end - 1,
+            self.functions
+        )
+    }
+}
+
+/// Probe a `pci-host-ecam-generic` node and everything on its buses.
+pub fn probe_ecam(node: &DtNode) -> Result<Box<dyn Driver>, KernelError> {
+    let reg = node.reg(0).ok_or(ProbeError::MissingProperty("reg"))?;
+    let first = node.u32("bus-range", 0).unwrap_or(0) as u16;
+    let last = node.u32("bus-range", 1).unwrap_or(255).min(255) as u16;
+    let buses = first..last + 1;
+    let len = ((buses.len() as u64) << 20).min(reg.end - reg.start);
+    let base = ioremap(reg.start, len, "pci-ecam");
+    let mut windows = parse_ranges(node);
+
+    let mut found = Vec::new();
+    for bus in buses.clone() {
+        for device in 0..DEVICES_PER_BUS {
+            for function in 0..FUNCTIONS {
+                let address = PciAddress {
+                    bus: bus as u8,
+                    device,
+                    function,
+                };
+                if address.ecam_offset() >= len {
+                    break;
+                }
+                let config = ConfigSpace {
+                    base: (base + address.ecam_offset()).as_u64() as usize,
+                };
+                let vendor_id = config.read_u16(VENDOR_ID);
+                if vendor_id == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                found.push(probe_function(node, address, config, &mut windows));
+                let multifunction = config.read_u8(HEADER_TYPE) & 0x80 != 0;
+                if function == 0 && !multifunction {
+                    break;
+                }
+            }
+        }
+    }
+
+    let functions = found.len();
+    DEVICES.lock().extend(found.into_iter().map(Arc::new));
+    Ok(Box::new(HostBridge {
+        base: reg.start,
+        buses,
+        functions,
+    }))
+}
+
+fn probe_function(
+    node: &DtNode,
+    address: PciAddress,
+    config: ConfigSpace,
+    windows: &mut Windows,
+) -> PciDevice {
+    let class = config.read_u32(REVISION);
+    // Only normal functions have six BARs. Bridges aren't set up.
+    let bars = match config.read_u8(HEADER_TYPE) & 0x7f {
+        0 => {
+            // Where BARs that were already set are for the CPU. Assumes the windows all
+            // have the same offset, as on QEMU.
+            let cpu_offset = windows.mmio32.map_or(0, |(_, _, offset)| offset);
+            set_up_bars(&config, windows, cpu_offset)
+        }
+        _ => [None; 6],
+    };
+    let interrupt = match config.read_u8(INTERRUPT_PIN) {
+        0 => None,
+        pin => map_interrupt(node, address, pin),
+    };
+    if bars
+        .iter()
+        .any(|bar| matches!(bar, Some(Bar::Memory { .. })))
+    {
+        let command = config.read_u16(COMMAND);
+        config.write_u16(COMMAND, command | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    let device = PciDevice {
+        address,
+        vendor_id: config.read_u16(VENDOR_ID),
+        device_id: config.read_u16(DEVICE_ID),
+        class: ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8),
+        revision: class as u8,
+        bars,
+        interrupt,
+        config,
+    };
+    log::debug!(
+        "pci {}: {:04x}:{:04x} {}",
+        address,
+        device.vendor_id,
+        device.device_id,
+        device.class_name()
+    );
+    device
+}
+
+/// Every function found, in bus order.
+pub fn devices() -> Vec<Arc<PciDevice>> {
+    DEVICES.lock().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cells(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    #[test_case]
+    fn pci_interrupt_map() {
+        // Like QEMU's virt machine: slot and pin, swizzled, onto PLIC 32 to 35.
+        let mut map = Vec::new();
+        for slot in 0..4 {
+            for pin in 1..=4 {
+                map.extend([slot << 11, 0, 0, pin, 3, 32 + (slot + pin - 1) % 4]);
+            }
+        }
+        let node = DtNode {
+            path: "/soc/pci@30000000".into(),
+            name: "pci@30000000".into(),
+            address_cells: 2,
+            size_cells: 2,
+            props: vec![
+                ("interrupt-map".into(), cells(&map)),
+                ("interrupt-map-mask".into(), cells(&[0x1800, 0, 0, 7])),
+                (
+                    "ranges".into(),
+                    cells(&[0x0200_0000, 0, 0x4000_0000, 0, 0x4000_0000, 0, 0x4000_0000]),
+                ),
+            ],
+        };
+        let address = |device| PciAddress {
+            bus: 0,
+            device,
+            function: 0,
+        };
+        assert_eq!(map_interrupt(&node, address(1), 1), InterruptId::new(33));
+        assert_eq!(map_interrupt(&node, address(5), 2), InterruptId::new(34));
+        assert_eq!(map_interrupt(&node, address(3), 4), InterruptId::new(34));
+
+        let mut windows = parse_ranges(&node);
+        assert!(windows.mmio64.is_none());
+        assert_eq!(
+            windows.alloc(0x1000, false),
+            Some((0x4000_0000, 0x4000_0000))
+        );
+        assert_eq!(
+            windows.alloc(0x4000, true),
+            Some((0x4000_4000, 0x4000_4000))
+        );
+        assert_eq!(windows.alloc(0x8000_0000, false), None);
+    }
+}
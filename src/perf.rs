@@ -0,0 +1,190 @@
+//! Performance counters.
+//!
+//! [`measure`] counts cycles, instructions and cache misses while a closure runs on this
+//! hart. The counters are set up through the SBI PMU extension. Without it only cycles and
+//! instructions are counted, straight from the `cycle` and `instret` CSRs.
+
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::sbi::pmu::{pmu_extension, ConfigFlags, CounterInfo, EventIdx, PmuExtension, StopFlags};
+
+const CSR_CYCLE: u16 = 0xc00;
+const CSR_INSTRET: u16 = 0xc02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    CacheMisses,
+}
+
+impl Event {
+    pub const ALL: [Event; 3] = [Event::Cycles, Event::Instructions, Event::CacheMisses];
+
+    fn event_idx(self) -> EventIdx {
+        match self {
+            Event::Cycles => EventIdx::CPU_CYCLES,
+            Event::Instructions => EventIdx::INSTRUCTIONS,
+            Event::CacheMisses => EventIdx::CACHE_MISSES,
+        }
+    }
+
+    /// The fixed CSR that counts it, if there is one.
+    fn csr(self) -> Option<u16> {
+        match self {
+            Event::Cycles => Some(CSR_CYCLE),
+            Event::Instructions => Some(CSR_INSTRET),
+            Event::CacheMisses => None,
+        }
+    }
+}
+
+/// Counts from [`measure`]. `None` for events nothing could count.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub cycles: Option<u64>,
+    pub instructions: Option<u64>,
+    pub cache_misses: Option<u64>,
+}
+
+impl Sample {
+    fn set(&mut self, event: Event, value: Option<u64>) {
+        match event {
+            Event::Cycles => self.cycles = value,
+            Event::Instructions => self.instructions = value,
+            Event::CacheMisses => self.cache_misses = value,
+        }
+    }
+}
+
+impl Display for Sample {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let counts = [
+            ("cycles", self.cycles),
+            ("instructions", self.instructions),
+            ("cache misses", self.cache_misses),
+        ];
+        for (i, (name, count)) in counts.into_iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            match count {
+                Some(count) => write!(f, "{} {}", count, name)?,
+                None => write!(f, "? {}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A PMU counter we've set up.
+struct Counter {
+    index: usize,
+    info: CounterInfo,
+}
+
+impl Counter {
+    /// Find a counter for `event` and start it from zero. Only counts S-mode and U-mode.
+    fn start(pmu: &PmuExtension, event: Event) -> Option<Counter> {
+        let counters = pmu.num_counters().ok().filter(|&n| n > 0)?;
+        let mask = usize::MAX >> (usize::BITS as usize - counters.min(64));
+        let flags = ConfigFlags::CLEAR_VALUE | ConfigFlags::AUTO_START | ConfigFlags::SET_MINH;
+        let index = pmu
+            .counter_config_matching(0, mask, flags, event.event_idx(), 0)
+            .ok()?;
+        match pmu.counter_get_info(index) {
+            Ok(info) => Some(Counter { index, info }),
+            Err(_) => {
+                pmu.counter_stop(index, 1, StopFlags::RESET).ok();
+                None
+            }
+        }
+    }
+
+    fn read(&self, pmu: &PmuExtension) -> Option<u64> {
+        read_counter(pmu, self.info, self.index)
+    }
+
+    /// Read it and give it back.
+    fn stop(self, pmu: &PmuExtension) -> Option<u64> {
+        let value = self.read(pmu);
+        pmu.counter_stop(self.index, 1, StopFlags::RESET).ok();
+        value
+    }
+}
+
+/// Run `f` and count what it did.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Sample) {
+    let mut sample = Sample::default();
+    match pmu_extension() {
+        Some(pmu) => {
+            let counters = Event::ALL.map(|event| Counter::start(pmu, event));
+            let r = f();
+            for (event, counter) in Event::ALL.into_iter().zip(counters) {
+                sample.set(event, counter.and_then(|counter| counter.stop(pmu)));
+            }
+            (r, sample)
+        }
+        None => {
+            let start = Event::ALL.map(|event| event.csr().and_then(read_csr));
+            let r = f();
+            for (event, start) in Event::ALL.into_iter().zip(start) {
+                let end = event.csr().and_then(read_csr);
+                let count = start.zip(end).map(|(start, end)| end.wrapping_sub(start));
+                sample.set(event, count);
+            }
+            (r, sample)
+        }
+    }
+}
+
+/// Current value of counter `index`, whichever kind it is.
+pub fn read_counter(pmu: &PmuExtension, info: CounterInfo, index: usize) -> Option<u64> {
+    if info.is_firmware() {
+        pmu.counter_fw_read(index).ok()
+    } else {
+        read_csr(info.csr())
+    }
+}
+
+macro_rules! read_counter_csr {
+    ($csr:expr; $($n:literal)*) => {
+        match $csr {
+            $($n => {
+                let value: u64;
+                unsafe { asm!(concat!("csrr {}, ", stringify!($n)), out(reg) value) };
+                Some(value)
+            })*
+            _ => None,
+        }
+    };
+}
+
+/// Read one of the user counter CSRs, `cycle` to `hpmcounter31`. This traps if the
+/// firmware hasn't let S-mode read it.
+fn read_csr(csr: u16) -> Option<u64> {
+    read_counter_csr!(csr;
+        0xc00 0xc01 0xc02 0xc03 0xc04 0xc05 0xc06 0xc07
+        0xc08 0xc09 0xc0a 0xc0b 0xc0c 0xc0d 0xc0e 0xc0f
+        0xc10 0xc11 0xc12 0xc13 0xc14 0xc15 0xc16 0xc17
+        0xc18 0xc19 0xc1a 0xc1b 0xc1c 0xc1d 0xc1e 0xc1f
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn measure_counts_something() {
+        let (sum, sample) = measure(|| (0..1000u64).map(core::hint::black_box).sum::<u64>());
+        assert_eq!(sum, 499500);
+        assert!(sample
+            .instructions
+            .map_or(true, |instructions| instructions > 1000));
+        assert!(sample.cycles.map_or(true, |cycles| cycles > 0));
+    }
+}
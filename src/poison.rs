@@ -0,0 +1,141 @@
+//! Poisoning freed memory, to catch use after free. Built with the `poison` feature.
+//!
+//! Freed heap memory is filled with [`FREED`]. What comes from the slab caches is checked
+//! when it's handed out again, so something that wrote to it after it was freed is a panic
+//! saying where, instead of whoever has it next finding their data changed. The first word
+//! isn't checked, since that's where the caches keep their free lists. Bigger allocations
+//! are only filled: the heap keeps its bookkeeping in whatever's free.
+//!
+//! Freed page table pages wait in a quarantine of the last [`QUARANTINED`] before they go
+//! back to the heap, and are checked on the way out. A hart still walking one through a
+//! stale TLB entry sets accessed and dirty bits in it, which shows up there rather than as
+//! a page fault in some unrelated process later.
+
+use core::mem::size_of;
+
+use crate::{
+    addr::PhysAddr,
+    pagetable::{phys_to_virt, PAGE_SIZE},
+    sync::IrqSafeMutex,
+};
+
+pub const ENABLED: bool = cfg!(feature = "poison");
+/// What freed memory is filled with.
+pub const FREED: u8 = 0x6b;
+/// Page table pages held back from reuse.
+pub const QUARANTINED: usize = 64;
+/// Bytes at the start of a cached object the slab caches write to.
+const LINK: usize = size_of::<usize>();
+
+static QUARANTINE: IrqSafeMutex<Quarantine> = IrqSafeMutex::new(Quarantine::new());
+
+/// Fill `len` bytes at `ptr` with [`FREED`].
+///
+/// # Safety
+/// They're writable, and nothing uses them.
+pub unsafe fn fill(ptr: *mut u8, len: usize) {
+    ptr.write_bytes(FREED, len);
+}
+
+/// Where the first byte of `len` at `ptr` that isn't [`FREED`] is.
+///
+/// # Safety
+/// They're readable.
+pub unsafe fn first_written(ptr: *const u8, len: usize) -> Option<usize> {
+    core::slice::from_raw_parts(ptr, len)
+        .iter()
+        .position(|&byte| byte != FREED)
+}
+
+/// Panic if any of `len` bytes at `ptr` were written since [`fill`].
+///
+/// # Safety
+/// They're readable.
+pub unsafe fn check(ptr: *const u8, len: usize, what: &str) {
+    if let Some(offset) = first_written(ptr, len) {
+        panic!(
+            "use after free: {} at {:p} written at +{:#x} ({:#04x}) after it was freed",
+            what,
+            ptr,
+            offset,
+            *ptr.add(offset)
+        );
+    }
+}
+
+/// [`check`] an object of `len` bytes from a slab cache, leaving out its free list link.
+///
+/// # Safety
+/// It was [`fill`]ed before it went back in the cache.
+pub unsafe fn check_object(ptr: *const u8, len: usize) {
+    check(ptr.add(LINK), len - LINK, "heap object");
+}
+
+/// Hold back the page table page `frame` instead of freeing it. Returns the one it pushed
+/// out of the quarantine, to free now, once it's checked nothing wrote to it.
+///
+/// # Safety
+/// `frame` came from [`alloc_frame`](crate::pagetable::alloc_frame) and nothing uses it.
+pub unsafe fn quarantine(frame: PhysAddr) -> Option<PhysAddr> {
+    fill(phys_to_virt(frame).as_mut_ptr(), PAGE_SIZE as usize);
+    let released = QUARANTINE.lock().push(frame)?;
+    check(
+        phys_to_virt(released).as_ptr(),
+        PAGE_SIZE as usize,
+        "page table",
+    );
+    Some(released)
+}
+
+/// The last [`QUARANTINED`] frames, oldest at `next` once it's full.
+struct Quarantine {
+    frames: [PhysAddr; QUARANTINED],
+    next: usize,
+    len: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Quarantine {
+        Quarantine {
+            frames: [PhysAddr::new(0); QUARANTINED],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add `frame`, and take out the oldest if it was full.
+    fn push(&mut self, frame: PhysAddr) -> Option<PhysAddr> {
+        let old = core::mem::replace(&mut self.frames[self.next], frame);
+        self.next = (self.next + 1) % QUARANTINED;
+        if self.len < QUARANTINED {
+            self.len += 1;
+            None
+        } else {
+            Some(old)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn poisoned_memory() {
+        let mut buf = [0u8; 64];
+        unsafe {
+            fill(buf.as_mut_ptr(), buf.len());
+            assert_eq!(first_written(buf.as_ptr(), buf.len()), None);
+            buf[40] = 1;
+            assert_eq!(first_written(buf.as_ptr(), buf.len()), Some(40));
+        }
+
+        let mut quarantine = Quarantine::new();
+        for frame in 0..QUARANTINED as u64 {
+            assert_eq!(quarantine.push(PhysAddr::from_ppn(frame)), None);
+        }
+        let (first, second) = (PhysAddr::from_ppn(100), PhysAddr::from_ppn(101));
+        assert_eq!(quarantine.push(first), Some(PhysAddr::from_ppn(0)));
+        assert_eq!(quarantine.push(second), Some(PhysAddr::from_ppn(1)));
+    }
+}
@@ -0,0 +1,158 @@
+//! Just enough ELF64 to load the static RISC-V executables in `user/`.
+
+use core::fmt::{self, Display, Formatter};
+
+use crate::pagetable::MapError;
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// Not a 64-bit little endian RISC-V executable.
+    Unsupported,
+    Truncated,
+    /// A segment is outside user memory, or its sizes don't make sense.
+    BadSegment,
+    Map(MapError),
+    /// The arguments and environment don't fit on the initial stack.
+    ArgsTooLong,
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported => write!(f, "not a RISC-V 64-bit executable"),
+            ElfError::Truncated => write!(f, "ELF file truncated"),
+            ElfError::BadSegment => write!(f, "bad ELF segment"),
+            ElfError::Map(err) => write!(f, "{}", err),
+            ElfError::ArgsTooLong => write!(f, "argument list too long"),
+        }
+    }
+}
+
+impl core::error::Error for ElfError {}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    /// `PF_*`
+    pub flags: u32,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    ph_offset: usize,
+    ph_count: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(if data.starts_with(MAGIC) {
+                ElfError::Truncated
+            } else {
+                ElfError::NotElf
+            });
+        }
+        if &data[..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != CLASS_64
+            || data[5] != DATA_LITTLE
+            || u16_at(data, 16) != TYPE_EXEC
+            || u16_at(data, 18) != MACHINE_RISCV
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = u64_at(data, 24);
+        let ph_offset = u64_at(data, 32) as usize;
+        let ph_size = u16_at(data, 54) as usize;
+        let ph_count = u16_at(data, 56) as usize;
+        if ph_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        match ph_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(ph_offset))
+        {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(ElfError::Truncated),
+        }
+
+        Ok(Elf {
+            data,
+            entry,
+            ph_offset,
+            ph_count,
+        })
+    }
+
+    /// The loadable segments, checked to be inside the file.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment, ElfError>> + '_ {
+        (0..self.ph_count).filter_map(move |i| {
+            let header = &self.data[self.ph_offset + i * PROGRAM_HEADER_SIZE..];
+            if u32_at(header, 0) != PT_LOAD {
+                return None;
+            }
+            let segment = Segment {
+                flags: u32_at(header, 4),
+                offset: u64_at(header, 8),
+                vaddr: u64_at(header, 16),
+                file_size: u64_at(header, 32),
+                mem_size: u64_at(header, 40),
+            };
+            if segment.file_size > segment.mem_size {
+                return Some(Err(ElfError::BadSegment));
+            }
+            match segment.offset.checked_add(segment.file_size) {
+                Some(end) if end <= self.data.len() as u64 => Some(Ok(segment)),
+                _ => Some(Err(ElfError::Truncated)),
+            }
+        })
+    }
+
+    /// The bytes of `segment` stored in the file.
+    pub fn file_data(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+}
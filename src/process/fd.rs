@@ -0,0 +1,247 @@
+//! File descriptors.
+//!
+//! Each process has an [`FdTable`] of [`OpenFile`]s. An open file is either a device, like
+//! the console, or a VFS node with its own offset.
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::{
+    console,
+    fs::{
+        devfs::{self, CharDevice},
+        FsError, Inode,
+    },
+    prelude::*,
+};
+
+/// Most descriptors a process can have open.
+pub const MAX_FDS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    Fs(FsError),
+    /// Not opened for reading or writing.
+    BadMode,
+    /// Seek on something that can't.
+    NotSeekable,
+    /// Seek to before the start.
+    InvalidOffset,
+}
+
+impl From<FsError> for FileError {
+    fn from(err: FsError) -> Self {
+        FileError::Fs(err)
+    }
+}
+
+pub enum Object {
+    Device(Arc<dyn CharDevice>),
+    Node(Arc<dyn Inode>),
+}
+
+pub struct OpenFile {
+    object: Object,
+    /// What it was opened as.
+    path: String,
+    readable: bool,
+    writable: bool,
+    /// Writes always go to the end of the file.
+    append: bool,
+    offset: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub fn console() -> Arc<OpenFile> {
+        Arc::new(OpenFile {
+            object: Object::Device(console::device()),
+            path: "/dev/console".into(),
+            readable: true,
+            writable: true,
+            append: false,
+            offset: Mutex::new(0),
+        })
+    }
+
+    /// `node`, opened as `path`. Nodes in `/dev` open as their device.
+    pub fn node(
+        path: String,
+        node: Arc<dyn Inode>,
+        readable: bool,
+        writable: bool,
+        append: bool,
+    ) -> Arc<Self> {
+        let object = match devfs::device(&node) {
+            Some(device) => Object::Device(device),
+            None => Object::Node(node),
+        };
+        Arc::new(OpenFile {
+            object,
+            path,
+            readable,
+            writable,
+            append,
+            offset: Mutex::new(0),
+        })
+    }
+
+    /// Read at the current offset and move past what was read. Devices wait for at least
+    /// one byte.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        if !self.readable {
+            return Err(FileError::BadMode);
+        }
+        match &self.object {
+            Object::Device(device) => Ok(device.read(buf)?),
+            Object::Node(node) => {
+                let mut offset = self.offset.lock();
+                let n = node.read_at(*offset, buf)?;
+                *offset += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        if !self.writable {
+            return Err(FileError::BadMode);
+        }
+        match &self.object {
+            Object::Device(device) => Ok(device.write(buf)?),
+            Object::Node(node) => {
+                let mut offset = self.offset.lock();
+                if self.append {
+                    *offset = node.metadata()?.size;
+                }
+                let n = node.write_at(*offset, buf)?;
+                *offset += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `r`, `w` or `rw`, and `a` if it appends.
+    pub fn mode(&self) -> &'static str {
+        match (self.readable, self.writable, self.append) {
+            (true, false, _) => "r",
+            (false, true, false) => "w",
+            (false, true, true) => "wa",
+            (true, true, false) => "rw",
+            (true, true, true) => "rwa",
+            (false, false, _) => "-",
+        }
+    }
+
+    /// Where the next read or write goes. 0 for devices.
+    pub fn offset(&self) -> u64 {
+        *self.offset.lock()
+    }
+
+    /// The device, if this is one.
+    pub fn device(&self) -> Option<&Arc<dyn CharDevice>> {
+        match &self.object {
+            Object::Device(device) => Some(device),
+            Object::Node(_) => None,
+        }
+    }
+
+    /// Returns the new offset.
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FileError> {
+        let node = match &self.object {
+            Object::Device(_) => return Err(FileError::NotSeekable),
+            Object::Node(node) => node,
+        };
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => add_signed(*offset, delta),
+            SeekFrom::End(delta) => add_signed(node.metadata()?.size, delta),
+        };
+        *offset = new.ok_or(FileError::InvalidOffset)?;
+        Ok(*offset)
+    }
+}
+
+fn add_signed(base: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FdTable {
+    /// 0, 1 and 2 on the console.
+    pub fn with_console() -> Self {
+        let console = OpenFile::console();
+        FdTable {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get(fd)?.clone()
+    }
+
+    /// Put `file` in the lowest free slot. `None` if the table is full.
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> Option<usize> {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Some(fd)
+            }
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(Some(file));
+                Some(self.files.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.get_mut(fd)?.take()
+    }
+
+    /// The open descriptors and their files, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Arc<OpenFile>)> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, file)| Some((fd, file.as_ref()?)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn fd_table_reuses_lowest() {
+        let mut table = FdTable::with_console();
+        assert_eq!(table.insert(OpenFile::console()), Some(3));
+        assert_eq!(table.insert(OpenFile::console()), Some(4));
+        assert!(table.remove(1).is_some());
+        assert!(table.remove(1).is_none());
+        assert_eq!(table.insert(OpenFile::console()), Some(1));
+        assert!(table.get(MAX_FDS).is_none());
+        let open: Vec<usize> = table.iter().map(|(fd, _)| fd).collect();
+        assert_eq!(open, [0, 1, 2, 3, 4]);
+        assert_eq!(table.get(0).unwrap().path(), "/dev/console");
+    }
+}
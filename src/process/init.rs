@@ -0,0 +1,103 @@
+//! PID 1.
+//!
+//! [`run_init`] starts the program `init=` names, or the first of
+//! [`DEFAULT_INITS`](cmdline::DEFAULT_INITS) there is, from the root filesystem or the
+//! built-in [`programs`]. Orphans go to it. When it exits it's started again, unless it
+//! keeps dying straight away, when it's given up on and the kernel's shell takes over.
+
+use alloc::{borrow::Cow, sync::Arc};
+use core::time::Duration;
+
+use super::{programs, run, Process, INIT};
+use crate::{cmdline, fs::FsError, log, prelude::*, time::Instant};
+
+/// Init exiting this many times in a row, each within [`QUICK_EXIT`] of starting, is
+/// given up on.
+const RESTART_LIMIT: u32 = 5;
+const QUICK_EXIT: Duration = Duration::from_secs(1);
+
+/// Run init, and keep running it, until it's given up on. Returns straight away if there
+/// isn't one.
+pub fn run_init() {
+    let (path, elf) = match find() {
+        Some(init) => init,
+        None => return,
+    };
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut restarts = Restarts::default();
+    loop {
+        let started = Instant::now();
+        let process = match Process::from_elf(name, &elf, &[path.as_bytes()]) {
+            Ok(process) => process,
+            Err(err) => return log::error!("{}: {}", path, err),
+        };
+        *INIT.lock() = Arc::downgrade(&process);
+        let code = run(process);
+        println!("{} exited with status {}", name, code);
+        if !restarts.exited(started.elapsed()) {
+            return log::error!("{} keeps exiting, not restarting it", path);
+        }
+        log::warn!("restarting {}", path);
+    }
+}
+
+/// The path and contents of init.
+fn find() -> Option<(&'static str, Cow<'static, [u8]>)> {
+    if let Some(path) = cmdline::init_path() {
+        return match programs::read(path) {
+            Ok(elf) => Some((path, elf)),
+            Err(err) => {
+                log::error!("{}: {}", path, err);
+                None
+            }
+        };
+    }
+    cmdline::DEFAULT_INITS
+        .iter()
+        .find_map(|&path| match programs::read(path) {
+            Ok(elf) => Some((path, elf)),
+            Err(FsError::NotFound) => None,
+            Err(err) => {
+                log::error!("{}: {}", path, err);
+                None
+            }
+        })
+}
+
+/// Whether init should be started again.
+#[derive(Debug, Default)]
+struct Restarts {
+    /// Quick exits in a row.
+    quick: u32,
+}
+
+impl Restarts {
+    /// Init exited after running for `ran`. Returns whether to start it again.
+    fn exited(&mut self, ran: Duration) -> bool {
+        if ran < QUICK_EXIT {
+            self.quick += 1;
+        } else {
+            self.quick = 0;
+        }
+        self.quick < RESTART_LIMIT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn restart_limit() {
+        let mut restarts = Restarts::default();
+        for _ in 1..RESTART_LIMIT {
+            assert!(restarts.exited(Duration::ZERO));
+        }
+        // A good run starts the count again.
+        assert!(restarts.exited(QUICK_EXIT * 2));
+        for _ in 1..RESTART_LIMIT {
+            assert!(restarts.exited(Duration::ZERO));
+        }
+        assert!(!restarts.exited(Duration::from_millis(10)));
+    }
+}
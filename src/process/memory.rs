@@ -0,0 +1,461 @@
+//! User address spaces.
+//!
+//! Each process gets a page table with its own memory in the lower half, below
+//! [`USER_END`], and the kernel's in the upper half. The kernel doesn't use the lower half
+//! itself. It gets at user memory through the direct map, by way of the page table.
+//!
+//! What the process may use is kept as a list of [`Vma`]s. Pages in a VMA get a frame
+//! when they're first touched, either by the process faulting on them or by the kernel
+//! copying to or from them.
+
+use core::ops::Range;
+
+use alloc::collections::BTreeMap;
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    asid::Asid,
+    pagetable::{
+        alloc_frame, flush_page, free_frame, phys_to_virt, EntryFlags, MapError, PageTableRoot,
+        PAGE_SIZE,
+    },
+    prelude::*,
+    tlb,
+};
+
+/// Lowest address a process can map. Leaves null pointers unmapped.
+pub const USER_START: u64 = 0x10000;
+/// End of user memory.
+pub const USER_END: u64 = 0x8000_0000;
+
+/// The initial stack sits right at the top of user memory.
+pub const USER_STACK_TOP: u64 = USER_END;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+/// `mmap` places mappings downwards from here, leaving an unmapped page under the stack.
+const MMAP_TOP: u64 = USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+/// An address the process doesn't have access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+/// What a faulting access was trying to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page aligned range of user memory the process may use. Empty `flags` reserves the
+/// range without allowing any access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub flags: EntryFlags,
+}
+
+pub struct AddressSpace {
+    table: PageTableRoot,
+    /// The pages the process owns, by virtual address.
+    pages: BTreeMap<u64, PhysAddr>,
+    /// By start address. Never overlap.
+    vmas: BTreeMap<u64, Vma>,
+    /// Where the heap starts, just past the program.
+    heap_start: u64,
+    /// End of the heap. Not page aligned.
+    brk: u64,
+    asid: Asid,
+}
+
+fn page_down(va: u64) -> u64 {
+    va & !(PAGE_SIZE - 1)
+}
+
+fn page_up(va: u64) -> Option<u64> {
+    Some(page_down(va.checked_add(PAGE_SIZE - 1)?))
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapError> {
+        Ok(AddressSpace {
+            table: PageTableRoot::new()?,
+            pages: BTreeMap::new(),
+            vmas: BTreeMap::new(),
+            heap_start: USER_START,
+            brk: USER_START,
+            asid: Asid::new(),
+        })
+    }
+
+    pub fn page_table(&self) -> &PageTableRoot {
+        &self.table
+    }
+
+    /// satp for running this address space on this hart. Gets it a current ASID, and
+    /// flushes the TLB if the hart needs it.
+    pub fn activate(&mut self) -> u64 {
+        let asid = self.asid.activate();
+        self.table.satp(asid)
+    }
+
+    fn asid(&self) -> u16 {
+        self.asid.get()
+    }
+
+    /// Map zeroed pages covering `range` now. Pages already mapped get `flags` added to what
+    /// they have, for segments that share a page.
+    pub fn map_zeroed(&mut self, range: Range<u64>, flags: EntryFlags) -> Result<(), MapError> {
+        if range.start < USER_START || range.end > USER_END || range.start > range.end {
+            return Err(MapError::OutOfRange);
+        }
+        let start = page_down(range.start);
+        self.add_vma(start, page_up(range.end).unwrap(), flags);
+        let flags = flags | EntryFlags::USER;
+        let mut changed = false;
+        for page in (start..range.end).step_by(PAGE_SIZE as usize) {
+            let va = VirtAddr::new(page);
+            match self.pages.get(&page) {
+                Some(&frame) => {
+                    let old = self.table.unmap(va).unwrap();
+                    self.table.map(va, frame, old.flags() | flags)?;
+                    changed = true;
+                }
+                None => {
+                    let frame = alloc_frame().ok_or(MapError::OutOfMemory)?;
+                    if let Err(err) = self.table.map(va, frame, flags) {
+                        unsafe { free_frame(frame) };
+                        return Err(err);
+                    }
+                    self.pages.insert(page, frame);
+                }
+            }
+        }
+        if changed {
+            tlb::shootdown(start..range.end, Some(self.asid()));
+        }
+        Ok(())
+    }
+
+    /// Start the heap at `at`, which should be past everything mapped so far.
+    pub fn set_heap_start(&mut self, at: u64) {
+        self.heap_start = page_up(at).unwrap_or(USER_END);
+        self.brk = self.heap_start;
+    }
+
+    /// The VMAs, lowest first.
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// The VMA `va` is in.
+    fn find_vma(&self, va: u64) -> Option<&Vma> {
+        let (_, vma) = self.vmas.range(..=va).next_back()?;
+        (va < vma.end).then_some(vma)
+    }
+
+    /// Start addresses of the VMAs overlapping `start..end`.
+    fn overlapping(&self, start: u64, end: u64) -> Vec<u64> {
+        self.vmas
+            .range(..end)
+            .rev()
+            .take_while(|(_, vma)| vma.end > start)
+            .map(|(&start, _)| start)
+            .collect()
+    }
+
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        self.overlapping(start, end).is_empty()
+    }
+
+    /// Add `start..end` to the VMAs. Where it overlaps an existing one, the flags are
+    /// combined.
+    fn add_vma(&mut self, start: u64, end: u64, flags: EntryFlags) {
+        let old: Vec<Vma> = self
+            .overlapping(start, end)
+            .into_iter()
+            .map(|start| self.vmas.remove(&start).unwrap())
+            .collect();
+        let mut bounds = vec![start, end];
+        for vma in &old {
+            bounds.extend([vma.start, vma.end]);
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        for piece in bounds.windows(2) {
+            let (a, b) = (piece[0], piece[1]);
+            let mut covered = start <= a && b <= end;
+            let mut piece_flags = if covered { flags } else { EntryFlags::empty() };
+            for vma in old.iter().filter(|vma| vma.start <= a && b <= vma.end) {
+                covered = true;
+                piece_flags |= vma.flags;
+            }
+            if covered {
+                let vma = Vma {
+                    start: a,
+                    end: b,
+                    flags: piece_flags,
+                };
+                self.vmas.insert(a, vma);
+            }
+        }
+        self.coalesce();
+    }
+
+    /// Join neighbouring VMAs with the same flags.
+    fn coalesce(&mut self) {
+        let mut merged: BTreeMap<u64, Vma> = BTreeMap::new();
+        for vma in self.vmas.values() {
+            match merged.values_mut().next_back() {
+                Some(prev) if prev.end == vma.start && prev.flags == vma.flags => {
+                    prev.end = vma.end
+                }
+                _ => {
+                    merged.insert(vma.start, *vma);
+                }
+            }
+        }
+        self.vmas = merged;
+    }
+
+    /// Free the pages in `range` and forget the VMAs covering it.
+    pub fn unmap(&mut self, range: Range<u64>) -> Result<(), MapError> {
+        if range.start % PAGE_SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+        let end = page_up(range.end).ok_or(MapError::OutOfRange)?;
+        if range.start < USER_START || end > USER_END || range.start > end {
+            return Err(MapError::OutOfRange);
+        }
+
+        for start in self.overlapping(range.start, end) {
+            let vma = self.vmas.remove(&start).unwrap();
+            if vma.start < range.start {
+                let before = Vma {
+                    end: range.start,
+                    ..vma
+                };
+                self.vmas.insert(before.start, before);
+            }
+            if vma.end > end {
+                let after = Vma { start: end, ..vma };
+                self.vmas.insert(after.start, after);
+            }
+        }
+
+        let pages: Vec<(u64, PhysAddr)> = self
+            .pages
+            .range(range.start..end)
+            .map(|(&page, &frame)| (page, frame))
+            .collect();
+        for &(page, _) in &pages {
+            self.table.unmap(VirtAddr::new(page));
+            self.pages.remove(&page);
+        }
+        // No hart may still be using the pages by the time they're freed.
+        tlb::shootdown(range.start..end, Some(self.asid()));
+        for (_, frame) in pages {
+            unsafe { free_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// Reserve `len` bytes of zeroed memory with `flags`, returning where it went. Tries
+    /// `addr` first. With `fixed`, it goes at `addr`, replacing whatever was there.
+    pub fn map_anonymous(
+        &mut self,
+        addr: u64,
+        len: u64,
+        flags: EntryFlags,
+        fixed: bool,
+    ) -> Result<u64, MapError> {
+        let len = page_up(len).ok_or(MapError::OutOfRange)?;
+        let fits = |start: u64| {
+            start >= USER_START && start.checked_add(len).map_or(false, |end| end <= USER_END)
+        };
+
+        let start = if fixed {
+            if addr % PAGE_SIZE != 0 {
+                return Err(MapError::Misaligned);
+            }
+            if !fits(addr) {
+                return Err(MapError::OutOfRange);
+            }
+            self.unmap(addr..addr + len)?;
+            addr
+        } else if addr % PAGE_SIZE == 0 && fits(addr) && self.is_free(addr, addr + len) {
+            addr
+        } else {
+            self.find_free(len).ok_or(MapError::OutOfMemory)?
+        };
+        self.add_vma(start, start + len, flags);
+        Ok(start)
+    }
+
+    /// Highest free gap of `len` bytes between the heap and [`MMAP_TOP`].
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let bottom = page_up(self.brk)?;
+        let mut end = MMAP_TOP;
+        for vma in self.vmas.range(..MMAP_TOP).rev().map(|(_, vma)| vma) {
+            if vma.end <= end && end - vma.end >= len {
+                break;
+            }
+            end = end.min(vma.start);
+        }
+        let start = end.checked_sub(len)?;
+        (start >= bottom).then_some(start)
+    }
+
+    /// Move the end of the heap to `new` and return where it ends up. Like Linux, it stays
+    /// where it was if it can't move.
+    pub fn brk(&mut self, new: u64) -> u64 {
+        let (old_end, new_end) = match (page_up(self.brk), page_up(new)) {
+            (Some(old_end), Some(new_end)) if new >= self.heap_start && new <= USER_END => {
+                (old_end, new_end)
+            }
+            _ => return self.brk,
+        };
+        if new_end > old_end {
+            if !self.is_free(old_end, new_end) {
+                return self.brk;
+            }
+            self.add_vma(old_end, new_end, EntryFlags::READ | EntryFlags::WRITE);
+        } else if new_end < old_end && self.unmap(new_end..old_end).is_err() {
+            return self.brk;
+        }
+        self.brk = new;
+        self.brk
+    }
+
+    /// Give the page at `va` a frame, if it's in a VMA the process can use.
+    fn populate(&mut self, va: u64) -> Result<(PhysAddr, EntryFlags), Fault> {
+        let page = page_down(va);
+        let vma = *self.find_vma(page).ok_or(Fault)?;
+        if vma.flags.is_empty() {
+            return Err(Fault);
+        }
+        let frame = alloc_frame().ok_or(Fault)?;
+        let flags = vma.flags | EntryFlags::USER;
+        if self.table.map(VirtAddr::new(page), frame, flags).is_err() {
+            unsafe { free_frame(frame) };
+            return Err(Fault);
+        }
+        self.pages.insert(page, frame);
+        Ok((frame + va % PAGE_SIZE, flags))
+    }
+
+    /// Handle a page fault at `va`. Fine if it was the first touch of a page the process
+    /// is allowed to do `access` to.
+    pub fn fault(&mut self, va: u64, access: Access) -> Result<(), Fault> {
+        if self.pages.contains_key(&page_down(va)) {
+            return Err(Fault);
+        }
+        let needed = match access {
+            Access::Read => EntryFlags::READ,
+            Access::Write => EntryFlags::WRITE,
+            Access::Execute => EntryFlags::EXECUTE,
+        };
+        if !self.find_vma(va).ok_or(Fault)?.flags.contains(needed) {
+            return Err(Fault);
+        }
+        self.populate(va)?;
+        // The hart may have remembered the page as invalid.
+        flush_page(VirtAddr::new(page_down(va)), self.asid());
+        Ok(())
+    }
+
+    /// Call `f` with each page sized piece of `va..va + len`: where it is in the kernel, and
+    /// which part of the range it is. Every page has to have `flags`.
+    fn for_each_page(
+        &mut self,
+        va: u64,
+        len: usize,
+        flags: EntryFlags,
+        mut f: impl FnMut(*mut u8, Range<usize>),
+    ) -> Result<(), Fault> {
+        match va.checked_add(len as u64) {
+            Some(end) if end <= USER_END => {}
+            _ => return Err(Fault),
+        }
+        let mut done = 0;
+        while done < len {
+            let addr = va + done as u64;
+            let (pa, page_flags) = match self.table.translate(VirtAddr::new(addr)) {
+                Some(found) => found,
+                None => self.populate(addr)?,
+            };
+            if !page_flags.contains(flags) {
+                return Err(Fault);
+            }
+            let n = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(len - done);
+            f(phys_to_virt(pa).as_mut_ptr(), done..done + n);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` into the process's memory at `va`, whatever the page permissions.
+    pub fn write(&mut self, va: u64, data: &[u8]) -> Result<(), Fault> {
+        self.for_each_page(va, data.len(), EntryFlags::USER, |dest, range| unsafe {
+            core::ptr::copy_nonoverlapping(data[range.clone()].as_ptr(), dest, range.len())
+        })
+    }
+
+    /// Read user memory at `va`, as the process would be allowed to.
+    pub fn copy_from_user(&mut self, va: u64, buf: &mut [u8]) -> Result<(), Fault> {
+        let flags = EntryFlags::USER | EntryFlags::READ;
+        self.for_each_page(va, buf.len(), flags, |src, range| unsafe {
+            core::ptr::copy_nonoverlapping(src, buf[range.clone()].as_mut_ptr(), range.len())
+        })
+    }
+
+    /// Write user memory at `va`, as the process would be allowed to.
+    pub fn copy_to_user(&mut self, va: u64, data: &[u8]) -> Result<(), Fault> {
+        let flags = EntryFlags::USER | EntryFlags::WRITE;
+        self.for_each_page(va, data.len(), flags, |dest, range| unsafe {
+            core::ptr::copy_nonoverlapping(data[range.clone()].as_ptr(), dest, range.len())
+        })
+    }
+
+    /// Bytes of user memory with frames behind them.
+    pub fn size(&self) -> u64 {
+        self.pages.len() as u64 * PAGE_SIZE
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for &frame in self.pages.values() {
+            unsafe { free_frame(frame) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn anonymous_memory_is_lazy() {
+        let mut memory = AddressSpace::new().unwrap();
+        let flags = EntryFlags::READ | EntryFlags::WRITE;
+        let start = memory
+            .map_anonymous(0, 3 * PAGE_SIZE, flags, false)
+            .unwrap();
+        assert_eq!(memory.size(), 0);
+
+        memory.copy_to_user(start + PAGE_SIZE, &[1, 2, 3]).unwrap();
+        assert_eq!(memory.size(), PAGE_SIZE);
+        assert_eq!(memory.fault(start, Access::Execute), Err(Fault));
+        assert_eq!(memory.fault(start, Access::Write), Ok(()));
+
+        memory
+            .unmap(start + PAGE_SIZE..start + 2 * PAGE_SIZE)
+            .unwrap();
+        assert_eq!(memory.size(), PAGE_SIZE);
+        assert_eq!(memory.vmas.len(), 2);
+        assert_eq!(memory.copy_to_user(start + PAGE_SIZE, &[1]), Err(Fault));
+    }
+}
@@ -0,0 +1,697 @@
+//! User processes.
+//!
+//! A [`Process`] owns an address space, a kernel stack and a [`TrapFrame`]. [`run`]
+//! switches from the calling kernel code onto the process's kernel stack, which drops
+//! into U-mode with [`return_to_user`]. Traps from U-mode come back in on that stack at
+//! [`trap::user_trap`](crate::trap::user_trap). When the process exits, it switches back
+//! to whoever called [`run`].
+//!
+//! There's no scheduler yet. A process [`spawn`]ed by another runs when its parent
+//! [`wait`]s for it, on top of the parent, so `run` nests. An exited process is a zombie,
+//! holding its exit code, until its parent waits for it. Then it's reaped: the last
+//! reference goes, and with it the address space, files and kernel stack. Children of a
+//! process that exits go to init, and the kernel reaps them when they exit.
+
+pub mod elf;
+pub mod fd;
+mod init;
+pub mod memory;
+pub mod programs;
+
+use core::{
+    cell::{RefCell, UnsafeCell},
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, AtomicI8, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    sync::{Arc, Weak},
+};
+use riscv::register::sstatus::{self, SPP};
+use spin::{Mutex, MutexGuard};
+
+pub use init::run_init;
+pub use memory::{Access, AddressSpace, Fault, USER_STACK_SIZE, USER_STACK_TOP};
+
+use crate::{
+    asm::{switch_context, user_return},
+    cpustat, hart_local,
+    hart_local::current_hart,
+    log,
+    pagetable::{self, EntryFlags, PAGE_SIZE},
+    prelude::*,
+    sbi::hart::HartId,
+    stack::KernelStack,
+    sync::{nice_priority, WaitQueue},
+    trace::trace,
+    task,
+    time::Instant,
+    trap,
+};
+
+use self::{
+    elf::{Elf, ElfError, PF_R, PF_W, PF_X},
+    fd::FdTable,
+};
+
+const KERNEL_STACK_SIZE: u64 = 16 * 1024;
+
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+/// Every process that's still around.
+static PROCESSES: Mutex<BTreeMap<Pid, Weak<Process>>> = Mutex::new(BTreeMap::new());
+/// Where orphans go. Set by [`run_init`] each time it starts init.
+static INIT: Mutex<Weak<Process>> = Mutex::new(Weak::new());
+
+hart_local! {
+    static CURRENT: RefCell<Option<Arc<Process>>> = RefCell::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u32);
+
+impl Display for Pid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Signals, by their Linux numbers. There are no handlers: every one kills the process,
+/// which exits with 128 plus the number, like a shell reports it.
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Created, but not started yet.
+    Ready,
+    Running,
+    /// A zombie until it's been waited for.
+    Exited(i32),
+}
+
+/// Where a process has run, and for how long.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    /// The hart it's running on, or last ran on.
+    pub hart: Option<HartId>,
+    /// Time it's been running, in U-mode or in the kernel for it. Not counting time its
+    /// children ran on top of it.
+    pub cpu_time: Duration,
+    /// Times it's been switched to.
+    pub switches: u64,
+    /// When it was last switched to, if it's running.
+    resumed: Option<Instant>,
+}
+
+/// User registers, saved on every trap from U-mode.
+#[repr(C)]
+pub struct TrapFrame {
+    /// `x0`-`x31`, indexed by register number. `regs[0]` is unused.
+    pub regs: [u64; 32],
+    /// Where the process will resume.
+    pub pc: u64,
+    /// Set by [`return_to_user`] for [`trap_entry`](crate::asm::trap_entry) and
+    /// [`user_return`].
+    kernel_sp: u64,
+    kernel_tp: u64,
+    satp: u64,
+    kernel_satp: u64,
+}
+
+impl TrapFrame {
+    pub(crate) const PC: usize = 32 * 8;
+    pub(crate) const KERNEL_SP: usize = 33 * 8;
+    pub(crate) const KERNEL_TP: usize = 34 * 8;
+    pub(crate) const SATP: usize = 35 * 8;
+    pub(crate) const KERNEL_SATP: usize = 36 * 8;
+
+    pub const SP: usize = 2;
+    pub const A0: usize = 10;
+    pub const A7: usize = 17;
+
+    fn new(pc: u64, sp: u64) -> Self {
+        let mut regs = [0; 32];
+        regs[Self::SP] = sp;
+        TrapFrame {
+            regs,
+            pc,
+            kernel_sp: 0,
+            kernel_tp: 0,
+            satp: 0,
+            kernel_satp: 0,
+        }
+    }
+}
+
+/// Registers a kernel context keeps across [`switch_context`](crate::asm::switch_context).
+#[repr(C)]
+pub struct Context {
+    ra: u64,
+    sp: u64,
+    /// `s0`-`s11`
+    s: [u64; 12],
+}
+
+impl Context {
+    pub(crate) const ZERO: Context = Context {
+        ra: 0,
+        sp: 0,
+        s: [0; 12],
+    };
+
+    /// A fresh context that starts running `entry` at the top of a stack.
+    pub(crate) fn starting_at(entry: extern "C" fn() -> !, stack_top: u64) -> Context {
+        Context {
+            ra: entry as *const () as u64,
+            sp: stack_top,
+            ..Context::ZERO
+        }
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: Mutex<String>,
+    memory: Mutex<AddressSpace>,
+    files: Mutex<FdTable>,
+    kernel_stack: KernelStack,
+    // Only touched by the hart running the process.
+    trap_frame: Box<UnsafeCell<TrapFrame>>,
+    context: UnsafeCell<Context>,
+    /// Where [`run`] was called from. The process switches back here when it exits.
+    caller: UnsafeCell<Context>,
+    state: Mutex<State>,
+    parent: Mutex<Weak<Process>>,
+    /// Until they're reaped.
+    children: Mutex<Vec<Arc<Process>>>,
+    /// Woken when a child exits.
+    child_exited: WaitQueue,
+    /// Signals sent and not yet acted on, one bit each.
+    pending_signals: AtomicU64,
+    usage: Mutex<Usage>,
+    /// -20 to 19, lower going first. Children start with their parent's.
+    nice: AtomicI8,
+    /// Handed to init when its parent exited. Init didn't start it and won't wait for it,
+    /// so it's reaped as soon as it exits.
+    adopted: AtomicBool,
+}
+
+unsafe impl Sync for Process {}
+
+impl Process {
+    /// Load a static ELF executable into a new address space, ready to [`run`].
+    pub fn from_elf(name: &str, data: &[u8], argv: &[&[u8]]) -> Result<Arc<Process>, ElfError> {
+        let image = load(data, argv, &[])?;
+        let trap_frame = Box::new(UnsafeCell::new(TrapFrame::new(image.entry, image.sp)));
+        let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+        let kernel_stack = KernelStack::new(KERNEL_STACK_SIZE, format!("{}[{}]", name, pid))?;
+
+        let mut process = Process {
+            pid,
+            name: Mutex::new(name.into()),
+            memory: Mutex::new(image.memory),
+            files: Mutex::new(FdTable::with_console()),
+            kernel_stack,
+            trap_frame,
+            context: UnsafeCell::new(Context::ZERO),
+            caller: UnsafeCell::new(Context::ZERO),
+            state: Mutex::new(State::Ready),
+            parent: Mutex::new(Weak::new()),
+            children: Mutex::new(Vec::new()),
+            child_exited: WaitQueue::new(),
+            pending_signals: AtomicU64::new(0),
+            usage: Mutex::new(Usage::default()),
+            nice: AtomicI8::new(0),
+            adopted: AtomicBool::new(false),
+        };
+        let stack_top = process.kernel_stack_top();
+        *process.context.get_mut() = Context::starting_at(process_start, stack_top);
+
+        let process = Arc::new(process);
+        PROCESSES
+            .lock()
+            .insert(process.pid, Arc::downgrade(&process));
+        Ok(process)
+    }
+
+    /// Load a static ELF executable as a child of `self`, with a copy of its file table.
+    /// It runs when `self` [`wait`]s for it.
+    pub fn spawn(
+        self: &Arc<Self>,
+        name: &str,
+        data: &[u8],
+        argv: &[&[u8]],
+    ) -> Result<Arc<Process>, ElfError> {
+        let child = Process::from_elf(name, data, argv)?;
+        *child.files.lock() = self.files.lock().clone();
+        child.set_nice(self.nice());
+        *child.parent.lock() = Arc::downgrade(self);
+        self.children.lock().push(child.clone());
+        Ok(child)
+    }
+
+    /// Replace the program the current process is running. On success the old address
+    /// space is gone and `frame` starts the new program at its entry point. On failure
+    /// nothing has changed.
+    pub fn exec(
+        &self,
+        frame: &mut TrapFrame,
+        name: &str,
+        data: &[u8],
+        argv: &[&[u8]],
+        envp: &[&[u8]],
+    ) -> Result<(), ElfError> {
+        let image = load(data, argv, envp)?;
+        *self.memory.lock() = image.memory;
+        *frame = TrapFrame::new(image.entry, image.sp);
+        *self.name.lock() = name.into();
+        Ok(())
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.lock()
+    }
+
+    pub fn memory(&self) -> MutexGuard<'_, AddressSpace> {
+        self.memory.lock()
+    }
+
+    pub fn files(&self) -> MutexGuard<'_, FdTable> {
+        self.files.lock()
+    }
+
+    /// Kill the process with `signal` the next time it would go back to U-mode.
+    pub fn signal(&self, signal: u32) {
+        self.pending_signals
+            .fetch_or(1 << (signal % 64), Ordering::Relaxed);
+    }
+
+    pub fn signal_pending(&self) -> bool {
+        self.pending_signals.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice.load(Ordering::Relaxed)
+    }
+
+    /// Clamped to -20 to 19.
+    pub fn set_nice(&self, nice: i8) {
+        self.nice.store(nice.clamp(-20, 19), Ordering::Relaxed);
+    }
+
+    /// Its priority on wait queues, from its nice value.
+    pub fn priority(&self) -> u8 {
+        nice_priority(self.nice())
+    }
+
+    /// Where it's run, with its CPU time up to now.
+    pub fn usage(&self) -> Usage {
+        let mut usage = *self.usage.lock();
+        if let Some(resumed) = usage.resumed {
+            usage.cpu_time += Instant::now().saturating_duration_since(resumed);
+        }
+        usage
+    }
+
+    /// It's running on this hart from now.
+    fn resume(&self) {
+        let mut usage = self.usage.lock();
+        usage.hart = Some(current_hart());
+        usage.switches += 1;
+        usage.resumed = Some(Instant::now());
+    }
+
+    /// It's stopped running for now. Add the time since [`resume`](Process::resume).
+    fn pause(&self) {
+        let mut usage = self.usage.lock();
+        if let Some(resumed) = usage.resumed.take() {
+            usage.cpu_time += Instant::now().saturating_duration_since(resumed);
+        }
+    }
+
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.parent.lock().upgrade()
+    }
+
+    /// Take `orphans` on as children.
+    fn adopt(self: &Arc<Self>, orphans: Vec<Arc<Process>>) {
+        for orphan in &orphans {
+            *orphan.parent.lock() = Arc::downgrade(self);
+            orphan.adopted.store(true, Ordering::Relaxed);
+        }
+        self.children.lock().extend(orphans);
+        // Some may be zombies already.
+        self.reap_orphans();
+    }
+
+    /// Reap the children [`adopt`](Process::adopt)ed that have exited.
+    fn reap_orphans(&self) {
+        let zombies: Vec<Arc<Process>> = {
+            let mut children = self.children.lock();
+            let reapable = |child: &Arc<Process>| {
+                child.adopted.load(Ordering::Relaxed) && matches!(child.state(), State::Exited(_))
+            };
+            let zombies = children
+                .iter()
+                .filter(|child| reapable(child))
+                .cloned()
+                .collect();
+            children.retain(|child| !reapable(child));
+            zombies
+        };
+        for zombie in zombies {
+            let (pid, code) = reap(zombie);
+            log::debug!("reaped orphan {} with status {}", pid, code);
+        }
+    }
+
+    fn kernel_stack_top(&self) -> u64 {
+        self.kernel_stack.top()
+    }
+}
+
+/// A program loaded into an address space, ready to start.
+struct Image {
+    memory: AddressSpace,
+    entry: u64,
+    sp: u64,
+}
+
+/// Load the ELF executable in `data` into a new address space with its initial stack.
+fn load(data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Image, ElfError> {
+    let elf = Elf::parse(data)?;
+    let mut memory = AddressSpace::new()?;
+    let mut program_end = 0;
+
+    for segment in elf.segments() {
+        let segment = segment?;
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let end = segment
+            .vaddr
+            .checked_add(segment.mem_size)
+            .ok_or(ElfError::BadSegment)?;
+
+        let mut flags = EntryFlags::empty();
+        // Write without read is reserved.
+        if segment.flags & (PF_R | PF_W) != 0 {
+            flags |= EntryFlags::READ;
+        }
+        if segment.flags & PF_W != 0 {
+            flags |= EntryFlags::WRITE;
+        }
+        if segment.flags & PF_X != 0 {
+            flags |= EntryFlags::EXECUTE;
+        }
+        memory.map_zeroed(segment.vaddr..end, flags)?;
+        program_end = program_end.max(end);
+        memory
+            .write(segment.vaddr, elf.file_data(&segment))
+            .map_err(|_| ElfError::BadSegment)?;
+    }
+    memory.set_heap_start(program_end);
+
+    memory.map_zeroed(
+        USER_STACK_TOP - USER_STACK_SIZE..USER_STACK_TOP,
+        EntryFlags::READ | EntryFlags::WRITE,
+    )?;
+    let sp = push_args(&mut memory, argv, envp)?;
+
+    Ok(Image {
+        memory,
+        entry: elf.entry,
+        sp,
+    })
+}
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+
+/// Most of the stack the arguments and environment can take.
+const MAX_ARGS_SIZE: u64 = USER_STACK_SIZE / 2;
+
+/// Lay out the initial stack as the RISC-V ELF psABI has it. From `sp` up: `argc`, the
+/// `argv` pointers and a null, the `envp` pointers and a null, then the auxiliary vector.
+/// The strings go at the very top. Returns `sp`.
+fn push_args(memory: &mut AddressSpace, argv: &[&[u8]], envp: &[&[u8]]) -> Result<u64, ElfError> {
+    let strings: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 4;
+    if strings + words as u64 * 8 + 16 > MAX_ARGS_SIZE {
+        return Err(ElfError::ArgsTooLong);
+    }
+
+    let mut pointers = Vec::with_capacity(words);
+    pointers.push(argv.len() as u64);
+    let mut top = USER_STACK_TOP;
+    let mut push_strings = |list: &[&[u8]], pointers: &mut Vec<u64>| -> Result<(), Fault> {
+        for s in list {
+            top -= s.len() as u64 + 1;
+            memory.write(top, s)?;
+            memory.write(top + s.len() as u64, &[0])?;
+            pointers.push(top);
+        }
+        pointers.push(0);
+        Ok(())
+    };
+    push_strings(argv, &mut pointers).map_err(|_| ElfError::ArgsTooLong)?;
+    push_strings(envp, &mut pointers).map_err(|_| ElfError::ArgsTooLong)?;
+    pointers.extend([AT_PAGESZ, PAGE_SIZE, AT_NULL, 0]);
+
+    let sp = (top - pointers.len() as u64 * 8) & !0xf;
+    for (i, pointer) in pointers.iter().enumerate() {
+        memory
+            .write(sp + i as u64 * 8, &pointer.to_le_bytes())
+            .map_err(|_| ElfError::ArgsTooLong)?;
+    }
+    Ok(sp)
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESSES.lock().remove(&self.pid);
+    }
+}
+
+impl Display for Process {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.name.lock(), self.pid)
+    }
+}
+
+/// All the processes, by pid.
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+pub fn find(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid)?.upgrade()
+}
+
+/// The process running on this hart, if any. Safe from interrupt handlers: one that lands
+/// while [`run`] is switching answers `None`.
+pub fn current() -> Option<Arc<Process>> {
+    CURRENT.try_get()?.try_borrow().ok()?.clone()
+}
+
+/// Run `process` on this hart until it exits. Returns its exit code. From inside a
+/// process, the caller is suspended meanwhile and comes back as current afterwards.
+pub fn run(process: Arc<Process>) -> i32 {
+    assert_eq!(process.state(), State::Ready, "{} run twice", process);
+    let interrupts = sstatus::read().sie();
+
+    *process.state.lock() = State::Running;
+    let caller = CURRENT.get().replace(Some(process.clone()));
+    if let Some(caller) = &caller {
+        caller.pause();
+    }
+    process.resume();
+    cpustat::count_switch();
+    trace!(Switch, process.pid.0, 1);
+    unsafe { switch_context(process.caller.get(), process.context.get()) };
+    trace!(Switch, process.pid.0, 0);
+    cpustat::count_switch();
+    process.pause();
+    if let Some(caller) = &caller {
+        caller.resume();
+    }
+    *CURRENT.get().borrow_mut() = caller;
+
+    if interrupts {
+        unsafe { sstatus::set_sie() };
+    }
+    let code = match process.state() {
+        State::Exited(code) => code,
+        state => panic!("{} switched back to the kernel while {:?}", process, state),
+    };
+    let init = process
+        .parent()
+        .filter(|_| process.adopted.load(Ordering::Relaxed));
+    drop(process);
+    if let Some(init) = init {
+        init.reap_orphans();
+    }
+    code
+}
+
+extern "C" fn process_start() -> ! {
+    return_to_user()
+}
+
+/// Resume the current process in U-mode.
+///
+/// The kernel stack is thrown away when the process next traps, so nothing on it gets
+/// dropped. Don't hold anything that needs to be.
+pub fn return_to_user() -> ! {
+    let frame = {
+        let process = current().expect("return_to_user without a process");
+        let frame = process.trap_frame.get();
+        unsafe {
+            (*frame).kernel_sp = process.kernel_stack_top();
+            (*frame).kernel_tp = crate::hart_local::read_tp() as u64;
+            (*frame).satp = process.memory.lock().activate();
+            (*frame).kernel_satp = pagetable::kernel_satp();
+            trap::set_user_frame(frame);
+        }
+        frame
+    };
+
+    unsafe {
+        // A trap before sret would set SPP back to S-mode.
+        sstatus::clear_sie();
+        sstatus::set_spp(SPP::User);
+        sstatus::set_spie();
+        user_return(frame)
+    }
+}
+
+/// Exit if the current process has been sent a signal. Called on the way back to U-mode.
+pub fn check_signals() {
+    let pending = match current() {
+        Some(process) => process.pending_signals.swap(0, Ordering::Relaxed),
+        None => return,
+    };
+    if pending != 0 {
+        exit(128 + pending.trailing_zeros() as i32);
+    }
+}
+
+/// Stop the current process and go back to [`run`]. Its files are closed straight away.
+/// The rest goes when it's reaped.
+pub fn exit(code: i32) -> ! {
+    let (context, caller) = {
+        let process = current().expect("exit without a process");
+        let files = core::mem::take(&mut *process.files.lock());
+        drop(files);
+
+        let orphans = core::mem::take(&mut *process.children.lock());
+        let init = INIT.lock().upgrade();
+        match init {
+            Some(init) if !Arc::ptr_eq(&init, &process) => init.adopt(orphans),
+            // Nobody to wait for them, so they go now.
+            _ => drop(orphans),
+        }
+
+        *process.state.lock() = State::Exited(code);
+        if let Some(parent) = process.parent() {
+            parent.child_exited.wake_all();
+        }
+        (process.context.get(), process.caller.get())
+    };
+    unsafe { switch_context(context, caller) };
+    unreachable!("exited process was switched back to")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// No child matches.
+    NoChild,
+}
+
+impl Display for WaitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitError::NoChild => write!(f, "no such child"),
+        }
+    }
+}
+
+impl core::error::Error for WaitError {}
+
+/// Wait for a child of the current process to exit, and reap it: `pid`, or any child if
+/// `None`. Returns its pid and exit code, or `None` if `hang` is false and none have
+/// exited yet.
+///
+/// Children that haven't started are run first, here, since nothing else will.
+pub fn wait(pid: Option<Pid>, hang: bool) -> Result<Option<(Pid, i32)>, WaitError> {
+    let process = current().expect("wait without a process");
+    let matches = |child: &Arc<Process>| pid.map_or(true, |pid| child.pid == pid);
+    let zombie = |children: &mut Vec<Arc<Process>>| {
+        let index = children
+            .iter()
+            .position(|child| matches(child) && matches!(child.state(), State::Exited(_)))?;
+        Some(children.remove(index))
+    };
+
+    loop {
+        let mut children = process.children.lock();
+        if !children.iter().any(matches) {
+            return Err(WaitError::NoChild);
+        }
+        if let Some(child) = zombie(&mut children) {
+            drop(children);
+            return Ok(Some(reap(child)));
+        }
+        if !hang {
+            return Ok(None);
+        }
+        let ready = children
+            .iter()
+            .find(|child| matches(child) && child.state() == State::Ready)
+            .cloned();
+        drop(children);
+
+        match ready {
+            Some(child) => {
+                run(child);
+            }
+            // Running somewhere else.
+            None => {
+                let child = task::block_on(
+                    process
+                        .child_exited
+                        .wait_until(|| zombie(&mut process.children.lock())),
+                );
+                return Ok(Some(reap(child)));
+            }
+        }
+    }
+}
+
+/// Tear down a zombie. It's gone once the last reference is, which should be this one.
+fn reap(child: Arc<Process>) -> (Pid, i32) {
+    let State::Exited(code) = child.state() else {
+        unreachable!("reaped {} before it exited", child);
+    };
+    let pid = child.pid;
+    PROCESSES.lock().remove(&pid);
+    if Arc::strong_count(&child) > 1 {
+        log::debug!("{} reaped while still referenced", child);
+    }
+    (pid, code)
+}
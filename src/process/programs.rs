@@ -0,0 +1,67 @@
+//! User programs built into the kernel, for running before there's a filesystem.
+//!
+//! Set `USER_PROGRAMS` to a space separated list of static ELF files when building, each
+//! optionally `name=path`, and build.rs embeds them in `.rodata`. `make embedded` does it
+//! with `user/`'s `init` and `sh`. A path under `/bin` that the root filesystem doesn't
+//! have is looked up here by [`read`], so init starts without an initramfs and can still
+//! spawn the shell.
+
+use alloc::borrow::Cow;
+
+use crate::fs::{self, FsError};
+
+/// Where [`read`] finds programs.
+pub const DIR: &str = "/bin/";
+
+pub struct Program {
+    pub name: &'static str,
+    pub elf: &'static [u8],
+}
+
+/// What build.rs made of `USER_PROGRAMS`. Empty if it wasn't set.
+static PROGRAMS: &[Program] = include!(concat!(env!("OUT_DIR"), "/programs.rs"));
+
+pub fn all() -> &'static [Program] {
+    PROGRAMS
+}
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
+
+/// The ELF at `path`: the file, or if there isn't one, the built-in program it names.
+pub fn read(path: &str) -> fs::Result<Cow<'static, [u8]>> {
+    match fs::read_to_vec(path) {
+        Err(FsError::NotFound) => path
+            .strip_prefix(DIR)
+            .and_then(find)
+            .map(|program| Cow::Borrowed(program.elf))
+            .ok_or(FsError::NotFound),
+        result => result.map(Cow::Owned),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+
+    use super::*;
+    use crate::process::elf::Elf;
+
+    #[test_case]
+    fn built_in_programs() {
+        assert!(find("no such program").is_none());
+        assert_eq!(read("/bin/no such program"), Err(FsError::NotFound));
+        for program in all() {
+            assert!(
+                Elf::parse(program.elf).is_ok(),
+                "{} isn't an ELF",
+                program.name
+            );
+            let path = format!("{}{}", DIR, program.name);
+            if fs::read_to_vec(&path).is_err() {
+                assert_eq!(read(&path).as_deref(), Ok(program.elf));
+            }
+        }
+    }
+}
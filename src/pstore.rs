@@ -0,0 +1,195 @@
+//! A panic report that survives a warm reboot.
+//!
+//! [`SIZE`] bytes of RAM nothing else uses: a `reserved-memory` node that's
+//! `compatible = "ramoops"`, like Linux's, if the device tree has one. Otherwise the top of
+//! the highest RAM bank, below the device tree, initrd and early heap. That has to land in
+//! the same place next boot, which it does as long as the bootloader puts things in the
+//! same places. It's reserved, so the heap never gets it.
+//!
+//! The panic handler [`save`]s its oops report there, behind a header with a CRC. A warm
+//! reboot leaves RAM alone, so next boot [`init`] finds it, checks it, replays it into the
+//! log and clears it. A cold reboot or power off loses it, as does firmware that clears
+//! RAM.
+
+use core::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use crate::{
+    addr::PhysAddr,
+    hwinfo::{HwInfo, PhysicalAddressKind, PhysicalAddressRange},
+    log,
+    pagetable::{phys_to_virt, PAGE_SIZE},
+    prelude::*,
+    sync::Once,
+};
+
+/// Size of the region it picks when the device tree doesn't give one.
+pub const SIZE: u64 = 64 * 1024;
+/// `ADLNOOPS`.
+const MAGIC: u64 = u64::from_le_bytes(*b"ADLNOOPS");
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    magic: u64,
+    /// Bytes of report after the header.
+    len: u32,
+    /// CRC-32 of them.
+    crc: u32,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+/// The region, by physical address, once [`init`] has run.
+static REGION: Once<Range<PhysAddr>> = Once::INIT;
+
+/// Where to put it when the device tree doesn't say: the top [`SIZE`] of the highest RAM
+/// bank that doesn't overlap anything in `avoid`.
+pub fn place(
+    ram: &[PhysicalAddressRange],
+    avoid: &[Range<PhysAddr>],
+) -> Option<PhysicalAddressRange> {
+    let bank = ram.iter().max_by_key(|bank| bank.end)?;
+    let mut end = bank.end.align_down(PAGE_SIZE);
+    loop {
+        let start = end.checked_sub(SIZE).filter(|&start| start >= bank.start)?;
+        let below = avoid
+            .iter()
+            .filter(|range| range.start < end && range.end > start)
+            .map(|range| range.start)
+            .min();
+        match below {
+            Some(below) => end = below.align_down(PAGE_SIZE),
+            None => {
+                return Some(PhysicalAddressRange::new(
+                    start..end,
+                    PhysicalAddressKind::Reserved,
+                    "pstore",
+                ))
+            }
+        }
+    }
+}
+
+/// The region: its header, and the space for the report after it.
+fn region() -> Option<(*mut Header, &'static mut [u8])> {
+    let region = REGION.get()?;
+    let base: *mut u8 = phys_to_virt(region.start).as_mut_ptr();
+    let capacity = (region.end - region.start) as usize - HEADER_SIZE;
+    // Reserved for this, and the only one writing is the panicking hart.
+    let data = unsafe { core::slice::from_raw_parts_mut(base.add(HEADER_SIZE), capacity) };
+    Some((base as *mut Header, data))
+}
+
+/// Keep what `write` writes for next boot, in place of whatever was kept. What doesn't fit
+/// is left out. For the panic handler: doesn't allocate or lock.
+pub fn save(write: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+    let Some((header, data)) = region() else {
+        return;
+    };
+    let mut writer = Writer { data, len: 0 };
+    // A partial report is better than none.
+    let _ = write(&mut writer);
+    let len = writer.len;
+    let header_value = Header {
+        magic: MAGIC,
+        len: len as u32,
+        crc: crc32(&writer.data[..len]),
+    };
+    unsafe { header.write_volatile(header_value) };
+}
+
+/// What last boot [`save`]d, if anything. `Err` if it's there but damaged. Cleared, so
+/// it's only found once.
+fn take() -> Option<Result<&'static [u8], ()>> {
+    let (header, data) = region()?;
+    let found = unsafe { header.read_volatile() };
+    if found.magic != MAGIC {
+        return None;
+    }
+    unsafe { (*header).magic = 0 };
+    let report = data.get(..found.len as usize).ok_or(());
+    Some(report.and_then(|report| {
+        if crc32(report) == found.crc {
+            Ok(report)
+        } else {
+            Err(())
+        }
+    }))
+}
+
+/// Fills a buffer and then drops the rest.
+struct Writer {
+    data: &'static mut [u8],
+    len: usize,
+}
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = &mut self.data[self.len..];
+        let len = s.len().min(room.len());
+        room[..len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// CRC-32, as in zlib and Ethernet.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Use the region the device tree set up, and replay anything last boot left in it.
+pub fn init(hwinfo: &HwInfo) {
+    let Some(pstore) = hwinfo.pstore else {
+        return log::info!("no room for a pstore");
+    };
+    if pstore.size() <= HEADER_SIZE as u64 {
+        return log::warn!("pstore at {:#x} is too small", pstore.start);
+    }
+    REGION.call_once(|| pstore.start..pstore.end);
+    match take() {
+        None => {}
+        Some(Err(())) => log::warn!("last boot left a damaged report in the pstore"),
+        Some(Ok(report)) => {
+            log::warn!("last boot panicked. It said:");
+            for line in String::from_utf8_lossy(report).lines() {
+                log::warn!("{}", line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn placement_and_crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let range = |range: Range<u64>| PhysAddr::new(range.start)..PhysAddr::new(range.end);
+        let bank = |bank: Range<u64>| {
+            PhysicalAddressRange::new(range(bank), PhysicalAddressKind::Usable, "memory")
+        };
+        let ram = [bank(0x8000_0000..0x8800_0000), bank(0x1000..0x2000)];
+        let top = place(&ram, &[]).unwrap();
+        assert_eq!(top.as_range(), range(0x8800_0000 - SIZE..0x8800_0000));
+        // Below the device tree at the top, then below the initrd under that.
+        let dtb = range(0x87ff_e000..0x8800_0000);
+        let initrd = range(0x87fe_0000..0x87ff_e000);
+        let under = place(&ram, &[dtb, initrd.clone()]).unwrap();
+        assert_eq!(under.end, initrd.start);
+        assert_eq!(under.size(), SIZE);
+        assert!(place(&[bank(0x1000..0x2000)], &[]).is_none());
+    }
+}
@@ -0,0 +1,170 @@
+//! Random numbers for the kernel.
+//!
+//! One entropy pool, a ChaCha20 key. Entropy is stirred in with [`add_entropy`]: the
+//! device tree's `rng-seed` and timing jitter at boot, then virtio-rng if there is one.
+//! Output is ChaCha20 keystream, and the key is replaced after every [`fill`] so earlier
+//! output can't be worked out from the pool.
+//!
+//! Jitter alone isn't worth much, least of all on QEMU, so [`is_seeded`] is only true once
+//! one of the other sources has been added.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use riscv::register::time;
+
+use crate::{hwinfo::HwInfo, log, sync::IrqSafeMutex};
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+/// Timing samples taken at boot.
+const JITTER_SAMPLES: usize = 64;
+
+static POOL: IrqSafeMutex<Pool> = IrqSafeMutex::new(Pool {
+    key: [0; 8],
+    counter: 0,
+});
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+struct Pool {
+    key: [u32; 8],
+    counter: u64,
+}
+
+impl Pool {
+    fn block(&mut self) -> [u32; 16] {
+        self.counter = self.counter.wrapping_add(1);
+        chacha20(&self.key, self.counter, 0)
+    }
+
+    /// XOR `bytes` into the key a block at a time, making a new key after each.
+    fn mix(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(32) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= (*byte as u32) << (8 * (i % 4));
+            }
+            self.rekey();
+        }
+    }
+
+    fn rekey(&mut self) {
+        let block = self.block();
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = self.block();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> (8 * (i % 4))) as u8;
+            }
+        }
+        self.rekey();
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One ChaCha20 block, with the original 64 bit counter and 64 bit nonce.
+fn chacha20(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+/// How long some busy work takes, by the `time` CSR, many times over.
+fn jitter() -> [u8; JITTER_SAMPLES] {
+    let mut samples = [0; JITTER_SAMPLES];
+    for sample in samples.iter_mut() {
+        let start = time::read();
+        let mut x = start;
+        for _ in 0..(start & 0xff) {
+            x = core::hint::black_box(x.rotate_left(5) ^ 0x9e37_79b9);
+        }
+        *sample = (time::read().wrapping_sub(start) ^ x) as u8;
+    }
+    samples
+}
+
+/// Stir `bytes` into the pool. `seeds` says they're from a real source of randomness,
+/// not just timing.
+pub fn add_entropy(bytes: &[u8], seeds: bool) {
+    POOL.lock().mix(bytes);
+    if seeds {
+        SEEDED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether anything better than timing jitter has gone into the pool.
+pub fn is_seeded() -> bool {
+    SEEDED.load(Ordering::Relaxed)
+}
+
+pub fn fill(buf: &mut [u8]) {
+    POOL.lock().fill(buf);
+}
+
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Seed the pool from the device tree and jitter.
+pub fn init(info: &HwInfo) {
+    add_entropy(&jitter(), false);
+    if !info.rng_seed.is_empty() {
+        add_entropy(&info.rng_seed, true);
+        log::debug!("rand: {} bytes of rng-seed", info.rng_seed.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn chacha20_block() {
+        // RFC 8439 section 2.3.2, whose 32 bit counter and 96 bit nonce are our counter
+        // and nonce.
+        let key =
+            core::array::from_fn(|i| u32::from_le_bytes([0, 1, 2, 3].map(|b| (4 * i + b) as u8)));
+        let block = chacha20(&key, 1 | 0x0900_0000 << 32, 0x4a00_0000);
+        assert_eq!(block[..4], [0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3]);
+        assert_eq!(block[15], 0x4e3c50a2);
+
+        let mut pool = Pool { key, counter: 0 };
+        let (mut first, mut second) = ([0; 40], [0; 40]);
+        pool.fill(&mut first);
+        pool.fill(&mut second);
+        assert_ne!(first, second);
+    }
+}
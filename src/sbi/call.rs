@@ -1,18 +1,18 @@
 use super::*;
 use core::arch::asm;
+use riscv::register::time;
 
-pub unsafe fn sbi_call0(ext: ExtensionId, func: FunctionId) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
+/// Run `ecall`, counting it and the time it takes in [`stats`], and turn the error and value
+/// it gets back into a result.
+#[inline(always)]
+fn timed(
+    ext: ExtensionId,
+    func: FunctionId,
+    ecall: impl FnOnce() -> (isize, isize),
+) -> SbiResult<isize> {
+    let start = time::read();
+    let (error, value) = ecall();
+    stats::record(ext, func, time::read().wrapping_sub(start) as u64);
     SbiRet {
         error: error.into(),
         value,
@@ -20,24 +20,33 @@ pub unsafe fn sbi_call0(ext: ExtensionId, func: FunctionId) -> SbiResult<isize>
     .into_result(ext, func)
 }
 
-pub unsafe fn sbi_call1(a0: usize, ext: ExtensionId, func: FunctionId) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
+pub unsafe fn sbi_call0(ext: ExtensionId, func: FunctionId) -> SbiResult<isize> {
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
+}
 
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+pub unsafe fn sbi_call1(a0: usize, ext: ExtensionId, func: FunctionId) -> SbiResult<isize> {
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
 
 pub unsafe fn sbi_call2(
@@ -46,24 +55,19 @@ pub unsafe fn sbi_call2(
     ext: ExtensionId,
     func: FunctionId,
 ) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        in("a1") a1,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            in("a1") a1,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
 
 pub unsafe fn sbi_call3(
@@ -73,25 +77,20 @@ pub unsafe fn sbi_call3(
     ext: ExtensionId,
     func: FunctionId,
 ) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        in("a1") a1,
-        in("a2") a2,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            in("a1") a1,
+            in("a2") a2,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
 
 pub unsafe fn sbi_call4(
@@ -102,26 +101,21 @@ pub unsafe fn sbi_call4(
     ext: ExtensionId,
     func: FunctionId,
 ) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        in("a1") a1,
-        in("a2") a2,
-        in("a3") a3,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            in("a1") a1,
+            in("a2") a2,
+            in("a3") a3,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
 
 pub unsafe fn sbi_call5(
@@ -133,27 +127,22 @@ pub unsafe fn sbi_call5(
     ext: ExtensionId,
     func: FunctionId,
 ) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        in("a1") a1,
-        in("a2") a2,
-        in("a3") a3,
-        in("a4") a4,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            in("a1") a1,
+            in("a2") a2,
+            in("a3") a3,
+            in("a4") a4,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
 
 pub unsafe fn sbi_call6(
@@ -166,26 +155,21 @@ pub unsafe fn sbi_call6(
     ext: ExtensionId,
     func: FunctionId,
 ) -> SbiResult<isize> {
-    let mut error: isize;
-    let mut value: isize;
-
-    asm!(
-        "ecall",
-        in("a6") func.0,
-        in("a7") ext.0,
-        in("a0") a0,
-        in("a1") a1,
-        in("a2") a2,
-        in("a3") a3,
-        in("a4") a4,
-        in("a5") a5,
-        lateout("a0") error,
-        lateout("a1") value,
-    );
-
-    SbiRet {
-        error: error.into(),
-        value,
-    }
-    .into_result(ext, func)
+    timed(ext, func, || {
+        let (error, value);
+        asm!(
+            "ecall",
+            in("a6") func.0,
+            in("a7") ext.0,
+            in("a0") a0,
+            in("a1") a1,
+            in("a2") a2,
+            in("a3") a3,
+            in("a4") a4,
+            in("a5") a5,
+            lateout("a0") error,
+            lateout("a1") value,
+        );
+        (error, value)
+    })
 }
@@ -0,0 +1,72 @@
+use crate::sync::Once;
+
+use super::{
+    base::SbiExtension,
+    call::{sbi_call1, sbi_call3},
+    ExtensionId, FunctionId, SbiResult,
+};
+
+/// The firmware's debug console, which takes whole buffers instead of a character a call.
+pub static DEBUG_CONSOLE_EXTENSION: Once<DebugConsoleExtension> = Once::INIT;
+
+/// `None` if the firmware has no debug console, which is new in SBI 2.0. The console gets
+/// no SBI sink then.
+pub fn debug_console_extension() -> Option<&'static DebugConsoleExtension> {
+    DEBUG_CONSOLE_EXTENSION.get()
+}
+
+pub struct DebugConsoleExtension {
+    _probe_result: isize,
+}
+
+const DBCN_CONSOLE_WRITE: FunctionId = FunctionId(0);
+const DBCN_CONSOLE_READ: FunctionId = FunctionId(1);
+const DBCN_CONSOLE_WRITE_BYTE: FunctionId = FunctionId(2);
+
+impl SbiExtension for DebugConsoleExtension {
+    fn id() -> ExtensionId {
+        ExtensionId::DBCN
+    }
+
+    unsafe fn from_probe(probe_result: isize) -> Self {
+        DebugConsoleExtension {
+            _probe_result: probe_result,
+        }
+    }
+}
+
+impl DebugConsoleExtension {
+    /// Write up to `len` bytes at physical address `phys`. Returns how many were written,
+    /// which can be fewer.
+    ///
+    /// # Safety
+    /// `phys` has to be `len` bytes of memory the firmware can read.
+    pub unsafe fn write(&self, phys: u64, len: usize) -> SbiResult<usize> {
+        let (low, high) = split(phys);
+        sbi_call3(len, low, high, Self::id(), DBCN_CONSOLE_WRITE).map(|n| n as usize)
+    }
+
+    /// Read up to `len` bytes to physical address `phys`, without waiting. Returns how many
+    /// were read, which is 0 if nothing's been typed.
+    ///
+    /// # Safety
+    /// `phys` has to be `len` bytes of memory the firmware can write.
+    pub unsafe fn read(&self, phys: u64, len: usize) -> SbiResult<usize> {
+        let (low, high) = split(phys);
+        sbi_call3(len, low, high, Self::id(), DBCN_CONSOLE_READ).map(|n| n as usize)
+    }
+
+    /// Write one byte, waiting until it's gone.
+    pub fn write_byte(&self, byte: u8) -> SbiResult<()> {
+        unsafe { sbi_call1(byte as usize, Self::id(), DBCN_CONSOLE_WRITE_BYTE) }.map(|_| ())
+    }
+}
+
+/// The low and high halves of an address, as the calls take them. On RV64 it all fits in
+/// the low one.
+fn split(phys: u64) -> (usize, usize) {
+    #[cfg(target_pointer_width = "64")]
+    return (phys as usize, 0);
+    #[cfg(target_pointer_width = "32")]
+    return (phys as usize, (phys >> 32) as usize);
+}
@@ -1,11 +1,11 @@
 use core::fmt::Display;
 
-use spin::Once;
+use crate::sync::Once;
 
 use super::{
     base::SbiExtension,
     call::{sbi_call0, sbi_call1, sbi_call3},
-    FunctionId, SbiResult,
+    ExtensionId, FunctionId, SbiError, SbiErrorCode, SbiResult,
 };
 
 pub static HSM_EXTENSION: Once<Hsm> = Once::INIT;
@@ -82,23 +82,29 @@ impl HartMask {
     }
 
     pub fn set_id(&mut self, id: HartId) {
-        if self.hart_mask_base + id.0 >= (usize::BITS as usize) {
-            panic!(
-                "Hart ID #{} will not fit in mask with base: {}",
-                id.0, self.hart_mask_base
-            );
-        }
-        self.hart_mask_base |= 1 << (id.0 - self.hart_mask_base);
+        self.hart_mask |= self.bit(id);
     }
 
     pub fn clear_id(&mut self, id: HartId) {
-        if self.hart_mask_base + id.0 >= (usize::BITS as usize) {
-            panic!(
+        self.hart_mask &= !self.bit(id);
+    }
+
+    pub fn contains(&self, id: HartId) -> bool {
+        self.hart_mask & self.bit(id) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hart_mask == 0
+    }
+
+    fn bit(&self, id: HartId) -> usize {
+        match id.0.checked_sub(self.hart_mask_base) {
+            Some(bit) if bit < usize::BITS as usize => 1 << bit,
+            _ => panic!(
                 "Hart ID #{} will not fit in mask with base: {}",
                 id.0, self.hart_mask_base
-            );
+            ),
         }
-        self.hart_mask_base &= !(1 << (id.0 - self.hart_mask_base));
     }
 }
 
@@ -229,13 +235,18 @@ impl Hsm {
         unsafe { self.hart_suspend(suspend_type.0, 0, 0) }
     }
 
-    unsafe fn hart_non_retentive_suspend(
+    /// Suspend, losing the hart's state. It's saved in `ctx` first, and this returns from
+    /// there once the hart resumes.
+    ///
+    /// # Safety
+    /// `ctx` must be in the kernel image, so its physical address is known, and not used by
+    /// anything else until this returns.
+    pub unsafe fn hart_non_retentive_suspend(
         &self,
         suspend_type: NonRetentiveSuspendType,
-        resume_addr: usize,
-        opaque: usize,
+        ctx: *mut SuspendContext,
     ) -> SbiResult<()> {
-        self.hart_suspend(suspend_type.0, resume_addr, opaque)
+        call_with_resume(Self::id(), HSM_HART_SUSPEND, suspend_type.0 as usize, ctx)
     }
 
     unsafe fn hart_suspend(
@@ -254,3 +265,59 @@ impl Hsm {
         Ok(())
     }
 }
+
+/// What a non-retentive suspend loses. Saved by
+/// [`suspend_and_save`](crate::asm::suspend_and_save) and put back by
+/// [`suspend_resume`](crate::asm::suspend_resume), which runs with paging off.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct SuspendContext {
+    /// `ra`, `sp`, `gp`, `tp`, then `s0`-`s11`.
+    regs: [u64; 16],
+    satp: u64,
+    stvec: u64,
+    sie: u64,
+    sscratch: u64,
+    sstatus: u64,
+}
+
+impl SuspendContext {
+    pub(crate) const SATP: usize = 16 * 8;
+    pub(crate) const STVEC: usize = 17 * 8;
+    pub(crate) const SIE: usize = 18 * 8;
+    pub(crate) const SSCRATCH: usize = 19 * 8;
+    pub(crate) const SSTATUS: usize = 20 * 8;
+
+    pub const fn new() -> SuspendContext {
+        SuspendContext {
+            regs: [0; 16],
+            satp: 0,
+            stvec: 0,
+            sie: 0,
+            sscratch: 0,
+            sstatus: 0,
+        }
+    }
+}
+
+/// Make an SBI call that takes `(arg, resume_addr, opaque)` and resumes the hart at
+/// `resume_addr` instead of returning. Returns normally either way.
+///
+/// # Safety
+/// As for [`Hsm::hart_non_retentive_suspend`].
+pub(crate) unsafe fn call_with_resume(
+    extension: ExtensionId,
+    function: FunctionId,
+    arg: usize,
+    ctx: *mut SuspendContext,
+) -> SbiResult<()> {
+    let error = crate::asm::suspend_and_save(ctx, arg, extension.0 as usize, function.0 as usize);
+    match SbiErrorCode::from(error) {
+        SbiErrorCode::SbiSuccess => Ok(()),
+        code => Err(SbiError {
+            code,
+            extension,
+            function,
+        }),
+    }
+}
@@ -1,4 +1,4 @@
-use spin::Once;
+use crate::sync::Once;
 
 use super::{base::SbiExtension, call::sbi_call2, hart::HartMask, SbiResult};
 
@@ -10,18 +10,26 @@ use call::*;
 
 use self::{
     base::{base_extension, SbiExtension},
+    dbcn::DEBUG_CONSOLE_EXTENSION,
     hart::HSM_EXTENSION,
     ipi::IPI_EXTENSION,
+    pmu::PMU_EXTENSION,
     reset::SYSTEM_RESET_EXTENSION,
     rfence::RFENCE_EXTENSION,
+    susp::SYSTEM_SUSPEND_EXTENSION,
     timer::TIMER_EXTENSION,
 };
+use crate::trace::trace;
 
 pub mod base;
+pub mod dbcn;
 pub mod hart;
 pub mod ipi;
+pub mod pmu;
 pub mod reset;
 pub mod rfence;
+pub mod stats;
+pub mod susp;
 pub mod timer;
 
 pub(crate) fn init() {
@@ -32,6 +40,16 @@ pub(crate) fn init() {
     RFENCE_EXTENSION.call_once(|| base.get_extension().unwrap());
     HSM_EXTENSION.call_once(|| base.get_extension().unwrap());
     SYSTEM_RESET_EXTENSION.call_once(|| base.get_extension().unwrap());
+    // Optional. QEMU only has it with a new enough OpenSBI.
+    if let Ok(pmu) = base.get_extension() {
+        PMU_EXTENSION.call_once(|| pmu);
+    }
+    if let Ok(susp) = base.get_extension() {
+        SYSTEM_SUSPEND_EXTENSION.call_once(|| susp);
+    }
+    if let Ok(dbcn) = base.get_extension() {
+        DEBUG_CONSOLE_EXTENSION.call_once(|| dbcn);
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -59,6 +77,12 @@ impl ExtensionId {
     const HSM: ExtensionId = ExtensionId(0x48534D);
     const SRST: ExtensionId = ExtensionId(0x53525354);
     const PMU: ExtensionId = ExtensionId(0x504D55);
+    const SUSP: ExtensionId = ExtensionId(0x53555350);
+    const DBCN: ExtensionId = ExtensionId(0x4442434E);
+
+    pub const fn id(self) -> isize {
+        self.0
+    }
 
     pub const fn is_legacy(self) -> bool {
         self.0 >= Self::LEGACY_SET_TIMER.0 && self.0 <= Self::LEGACY_SYSTEM_SHUTDOWN.0
@@ -78,9 +102,12 @@ impl ExtensionId {
             Self::LEGACY_SYSTEM_SHUTDOWN => "Legacy System Shutdown",
             Self::TIMER => "Timer Extension",
             Self::IPI => "IPI Extension",
-            Self::RFENCE => "Hart State Management Extension",
+            Self::RFENCE => "Remote Fence Extension",
+            Self::HSM => "Hart State Management Extension",
             Self::SRST => "System Reset Extension",
             Self::PMU => "Performance Moniotoring Unit Extension",
+            Self::SUSP => "System Suspend Extension",
+            Self::DBCN => "Debug Console Extension",
             _ if self.0 >= 0x08000000 && self.0 <= 0x08FFFFFF => "Experimental SBI Extension",
             _ if self.0 >= 0x09000000 && self.0 <= 0x09FFFFFF => "Vendor-Specific SBI Extension",
             _ if self.0 >= 0x0A000000 && self.0 <= 0x0AFFFFFF => "Firmware Specific SBI Extension",
@@ -103,6 +130,10 @@ impl Display for ExtensionId {
 pub struct FunctionId(isize);
 
 impl FunctionId {
+    pub const fn id(self) -> isize {
+        self.0
+    }
+
     fn desc(self, ext: ExtensionId) -> Option<&'static str> {
         match ext {
             ExtensionId::BASE => match self.0 {
@@ -144,6 +175,16 @@ impl FunctionId {
                 0 => Some("System reset"),
                 _ => None,
             },
+            ExtensionId::SUSP => match self.0 {
+                0 => Some("System suspend"),
+                _ => None,
+            },
+            ExtensionId::DBCN => match self.0 {
+                0 => Some("Console write"),
+                1 => Some("Console read"),
+                2 => Some("Console write byte"),
+                _ => None,
+            },
             ExtensionId::PMU => match self.0 {
                 0 => Some("Get number of counters"),
                 1 => Some("Get details of a counter"),
@@ -166,6 +207,7 @@ pub struct SbiRet {
 
 impl SbiRet {
     pub fn into_result(self, extension: ExtensionId, function: FunctionId) -> SbiResult<isize> {
+        trace!(SbiCall, extension.0, function.0);
         let res: Result<isize, SbiErrorCode> = self.into();
 
         res.map_err(|code| SbiError {
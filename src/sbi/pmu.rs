@@ -0,0 +1,172 @@
+use bitflags::bitflags;
+
+use crate::sync::Once;
+
+use super::{
+    base::SbiExtension,
+    call::{sbi_call0, sbi_call1, sbi_call3, sbi_call4, sbi_call5},
+    FunctionId, SbiResult,
+};
+
+/// The firmware's performance counters. QEMU only has them with a new enough OpenSBI.
+pub static PMU_EXTENSION: Once<PmuExtension> = Once::INIT;
+
+/// `None` if the firmware has no PMU extension. [`perf`](crate::perf) falls back to reading
+/// the cycle and instret CSRs directly then, and can't count cache misses.
+pub fn pmu_extension() -> Option<&'static PmuExtension> {
+    PMU_EXTENSION.get()
+}
+
+pub struct PmuExtension {
+    _probe_result: isize,
+}
+
+const PMU_NUM_COUNTERS: FunctionId = FunctionId(0);
+const PMU_COUNTER_GET_INFO: FunctionId = FunctionId(1);
+const PMU_COUNTER_CONFIG_MATCHING: FunctionId = FunctionId(2);
+const PMU_COUNTER_START: FunctionId = FunctionId(3);
+const PMU_COUNTER_STOP: FunctionId = FunctionId(4);
+const PMU_COUNTER_FW_READ: FunctionId = FunctionId(5);
+
+/// An event to count. The type is in bits 16..20 and the code below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventIdx(pub usize);
+
+impl EventIdx {
+    pub const CPU_CYCLES: EventIdx = EventIdx(1);
+    pub const INSTRUCTIONS: EventIdx = EventIdx(2);
+    pub const CACHE_REFERENCES: EventIdx = EventIdx(3);
+    pub const CACHE_MISSES: EventIdx = EventIdx(4);
+    pub const BRANCH_INSTRUCTIONS: EventIdx = EventIdx(5);
+    pub const BRANCH_MISSES: EventIdx = EventIdx(6);
+}
+
+bitflags! {
+    pub struct ConfigFlags: usize {
+        /// Reuse the counters given without checking they can count the event.
+        const SKIP_MATCH = 1 << 0;
+        const CLEAR_VALUE = 1 << 1;
+        const AUTO_START = 1 << 2;
+        const SET_VUINH = 1 << 3;
+        const SET_VSINH = 1 << 4;
+        /// Don't count in U-mode.
+        const SET_UINH = 1 << 5;
+        /// Don't count in S-mode.
+        const SET_SINH = 1 << 6;
+        /// Don't count in M-mode.
+        const SET_MINH = 1 << 7;
+    }
+}
+
+bitflags! {
+    pub struct StartFlags: usize {
+        /// Start from the initial value given, rather than where it left off.
+        const SET_INIT_VALUE = 1 << 0;
+    }
+}
+
+bitflags! {
+    pub struct StopFlags: usize {
+        /// Release the counter, so it can be configured for something else.
+        const RESET = 1 << 0;
+    }
+}
+
+/// What [`PmuExtension::counter_get_info`] says about a counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterInfo(pub usize);
+
+impl CounterInfo {
+    /// The CSR to read a hardware counter with.
+    pub fn csr(self) -> u16 {
+        (self.0 & 0xfff) as u16
+    }
+
+    /// Bits in a hardware counter, less one.
+    pub fn width(self) -> u8 {
+        ((self.0 >> 12) & 0x3f) as u8
+    }
+
+    /// Kept by the SBI implementation. Read with [`PmuExtension::counter_fw_read`].
+    pub fn is_firmware(self) -> bool {
+        self.0 >> (usize::BITS - 1) != 0
+    }
+}
+
+impl SbiExtension for PmuExtension {
+    fn id() -> super::ExtensionId {
+        super::ExtensionId::PMU
+    }
+
+    unsafe fn from_probe(probe_result: isize) -> Self {
+        PmuExtension {
+            _probe_result: probe_result,
+        }
+    }
+}
+
+impl PmuExtension {
+    /// Hardware and firmware counters together.
+    pub fn num_counters(&self) -> SbiResult<usize> {
+        unsafe { sbi_call0(Self::id(), PMU_NUM_COUNTERS).map(|n| n as usize) }
+    }
+
+    pub fn counter_get_info(&self, counter: usize) -> SbiResult<CounterInfo> {
+        unsafe {
+            sbi_call1(counter, Self::id(), PMU_COUNTER_GET_INFO)
+                .map(|info| CounterInfo(info as usize))
+        }
+    }
+
+    /// Pick one of the counters in `base + mask` that can count `event` on this hart and
+    /// set it up. Returns its index.
+    pub fn counter_config_matching(
+        &self,
+        base: usize,
+        mask: usize,
+        flags: ConfigFlags,
+        event: EventIdx,
+        event_data: u64,
+    ) -> SbiResult<usize> {
+        unsafe {
+            sbi_call5(
+                base,
+                mask,
+                flags.bits(),
+                event.0,
+                event_data as usize,
+                Self::id(),
+                PMU_COUNTER_CONFIG_MATCHING,
+            )
+            .map(|counter| counter as usize)
+        }
+    }
+
+    pub fn counter_start(
+        &self,
+        base: usize,
+        mask: usize,
+        flags: StartFlags,
+        initial_value: u64,
+    ) -> SbiResult<()> {
+        unsafe {
+            sbi_call4(
+                base,
+                mask,
+                flags.bits(),
+                initial_value as usize,
+                Self::id(),
+                PMU_COUNTER_START,
+            )
+            .and(Ok(()))
+        }
+    }
+
+    pub fn counter_stop(&self, base: usize, mask: usize, flags: StopFlags) -> SbiResult<()> {
+        unsafe { sbi_call3(base, mask, flags.bits(), Self::id(), PMU_COUNTER_STOP).and(Ok(())) }
+    }
+
+    pub fn counter_fw_read(&self, counter: usize) -> SbiResult<u64> {
+        unsafe { sbi_call1(counter, Self::id(), PMU_COUNTER_FW_READ).map(|value| value as u64) }
+    }
+}
@@ -1,4 +1,4 @@
-use spin::Once;
+use crate::sync::Once;
 
 use crate::{console::_panic_unlock, prelude::*};
 
@@ -74,5 +74,5 @@ pub fn shutdown() -> ! {
     }
 
     writeln!(w, "Shutdown not avalible").ok();
-    loop {}
+    crate::cpu::halt_forever()
 }
@@ -1,6 +1,11 @@
-use spin::Once;
+use crate::sync::Once;
 
-use super::base::SbiExtension;
+use super::{
+    base::SbiExtension,
+    call::{sbi_call2, sbi_call4, sbi_call5},
+    hart::HartMask,
+    FunctionId, SbiResult,
+};
 
 pub static RFENCE_EXTENSION: Once<RfenceExtension> = Once::INIT;
 
@@ -12,6 +17,13 @@ pub struct RfenceExtension {
     _probe_result: isize,
 }
 
+const RFENCE_REMOTE_FENCE_I: FunctionId = FunctionId(0);
+const RFENCE_REMOTE_SFENCE_VMA: FunctionId = FunctionId(1);
+const RFENCE_REMOTE_SFENCE_VMA_ASID: FunctionId = FunctionId(2);
+
+/// A `size` that covers the whole address space.
+pub const FLUSH_ALL: usize = usize::MAX;
+
 impl SbiExtension for RfenceExtension {
     fn id() -> super::ExtensionId {
         super::ExtensionId::RFENCE
@@ -23,3 +35,69 @@ impl SbiExtension for RfenceExtension {
         }
     }
 }
+
+impl RfenceExtension {
+    /// Run `fence.i` on each hart in `harts`.
+    pub fn remote_fence_i<H>(&self, harts: H) -> SbiResult<()>
+    where
+        HartMask: From<H>,
+    {
+        let hart_mask = HartMask::from(harts);
+        unsafe {
+            sbi_call2(
+                hart_mask.hart_mask,
+                hart_mask.hart_mask_base,
+                Self::id(),
+                RFENCE_REMOTE_FENCE_I,
+            )
+            .and(Ok(()))
+        }
+    }
+
+    /// Run `sfence.vma` over `start..start + size` on each hart in `harts`, for every
+    /// address space. A `size` of [`FLUSH_ALL`] flushes everything.
+    pub fn remote_sfence_vma<H>(&self, harts: H, start: usize, size: usize) -> SbiResult<()>
+    where
+        HartMask: From<H>,
+    {
+        let hart_mask = HartMask::from(harts);
+        unsafe {
+            sbi_call4(
+                hart_mask.hart_mask,
+                hart_mask.hart_mask_base,
+                start,
+                size,
+                Self::id(),
+                RFENCE_REMOTE_SFENCE_VMA,
+            )
+            .and(Ok(()))
+        }
+    }
+
+    /// Like [`remote_sfence_vma`](Self::remote_sfence_vma), but only for address space
+    /// `asid`.
+    pub fn remote_sfence_vma_asid<H>(
+        &self,
+        harts: H,
+        start: usize,
+        size: usize,
+        asid: usize,
+    ) -> SbiResult<()>
+    where
+        HartMask: From<H>,
+    {
+        let hart_mask = HartMask::from(harts);
+        unsafe {
+            sbi_call5(
+                hart_mask.hart_mask,
+                hart_mask.hart_mask_base,
+                start,
+                size,
+                asid,
+                Self::id(),
+                RFENCE_REMOTE_SFENCE_VMA_ASID,
+            )
+            .and(Ok(()))
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! How often each SBI function is called, and how long it takes.
+//!
+//! Every `sbi_call*` counts itself against the hart it's on, in a table per hart, with the
+//! mtime ticks from just before the `ecall` to just after. Each table has room for
+//! [`FUNCTIONS`] different functions, which is more than there are; anything past that is
+//! only counted as [`dropped`]. Recording doesn't lock or allocate, so it's fine
+//! from interrupt handlers. `sbi` in the shell shows them, and `perf` shows the calls a
+//! command made.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{ExtensionId, FunctionId};
+use crate::{
+    hart_local::{online_harts, try_current_hart},
+    prelude::*,
+    sbi::hart::HartId,
+    smp::MAX_HARTS,
+    time,
+};
+
+/// Different functions each hart keeps count of.
+const FUNCTIONS: usize = 32;
+
+struct Entry {
+    /// [`key`] of the function, or 0 while it's free.
+    key: AtomicU64,
+    calls: AtomicU64,
+    ticks: AtomicU64,
+}
+
+struct Table {
+    entries: [Entry; FUNCTIONS],
+    /// Calls there wasn't room for.
+    dropped: AtomicU64,
+}
+
+static TABLES: [Table; MAX_HARTS] = [const {
+    Table {
+        entries: [const {
+            Entry {
+                key: AtomicU64::new(0),
+                calls: AtomicU64::new(0),
+                ticks: AtomicU64::new(0),
+            }
+        }; FUNCTIONS],
+        dropped: AtomicU64::new(0),
+    }
+}; MAX_HARTS];
+
+/// Never 0. Extension ids fit in 32 bits, and function ids are small.
+fn key(extension: ExtensionId, function: FunctionId) -> u64 {
+    ((extension.0 as u64) << 32 | function.0 as u32 as u64) + 1
+}
+
+fn unkey(key: u64) -> (ExtensionId, FunctionId) {
+    let key = key - 1;
+    (
+        ExtensionId((key >> 32) as isize),
+        FunctionId(key as u32 as isize),
+    )
+}
+
+/// Count a call that took `ticks` of mtime.
+pub(super) fn record(extension: ExtensionId, function: FunctionId, ticks: u64) {
+    let Some(table) = try_current_hart().and_then(|hart| TABLES.get(hart.0)) else {
+        return;
+    };
+    let key = key(extension, function);
+    // Only this hart adds entries, but an interrupt can add one in the middle of it.
+    let entry = table.entries.iter().find(|entry| {
+        match entry
+            .key
+            .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => true,
+            Err(found) => found == key,
+        }
+    });
+    match entry {
+        Some(entry) => {
+            entry.calls.fetch_add(1, Ordering::Relaxed);
+            entry.ticks.fetch_add(ticks, Ordering::Relaxed);
+        }
+        None => {
+            table.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallStats {
+    pub extension: ExtensionId,
+    pub function: FunctionId,
+    pub calls: u64,
+    /// mtime ticks spent in them.
+    pub ticks: u64,
+}
+
+impl CallStats {
+    /// What the function is, if it's one we know.
+    pub fn name(&self) -> Option<&'static str> {
+        self.function.desc(self.extension)
+    }
+
+    pub fn time(&self) -> Duration {
+        time::convert_mtime_to_duration(self.ticks)
+    }
+
+    /// Average time for a call.
+    pub fn mean(&self) -> Duration {
+        time::convert_mtime_to_duration(self.ticks / self.calls.max(1))
+    }
+}
+
+/// What `hart` has called, most time first.
+pub fn hart(hart: HartId) -> Vec<CallStats> {
+    let mut stats = Vec::new();
+    if let Some(table) = TABLES.get(hart.0) {
+        add(&mut stats, table);
+    }
+    sort(&mut stats);
+    stats
+}
+
+/// What every online hart has called, added up, most time first.
+pub fn total() -> Vec<CallStats> {
+    let online = online_harts();
+    let mut stats = Vec::new();
+    for hart in (0..MAX_HARTS).filter(|&hart| online.contains(HartId(hart))) {
+        add(&mut stats, &TABLES[hart]);
+    }
+    sort(&mut stats);
+    stats
+}
+
+/// Calls `hart` had no room to count.
+pub fn dropped(hart: HartId) -> u64 {
+    TABLES
+        .get(hart.0)
+        .map_or(0, |table| table.dropped.load(Ordering::Relaxed))
+}
+
+/// The calls in `now` that weren't in `earlier`, like [`total`] before and after something.
+pub fn since(now: &[CallStats], earlier: &[CallStats]) -> Vec<CallStats> {
+    let mut stats: Vec<CallStats> = now
+        .iter()
+        .filter_map(|now| {
+            let then = earlier
+                .iter()
+                .find(|then| (then.extension, then.function) == (now.extension, now.function));
+            let (calls, ticks) = then.map_or((0, 0), |then| (then.calls, then.ticks));
+            (now.calls > calls).then(|| CallStats {
+                calls: now.calls - calls,
+                ticks: now.ticks.saturating_sub(ticks),
+                ..*now
+            })
+        })
+        .collect();
+    sort(&mut stats);
+    stats
+}
+
+/// Start counting again, on every hart. Calls on other harts at the same time can be lost.
+pub fn reset() {
+    for table in TABLES.iter() {
+        for entry in table.entries.iter() {
+            entry.calls.store(0, Ordering::Relaxed);
+            entry.ticks.store(0, Ordering::Relaxed);
+        }
+        table.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Add `table`'s counts to `stats`.
+fn add(stats: &mut Vec<CallStats>, table: &Table) {
+    for entry in table.entries.iter() {
+        let key = entry.key.load(Ordering::Acquire);
+        let calls = entry.calls.load(Ordering::Relaxed);
+        if key == 0 || calls == 0 {
+            continue;
+        }
+        let (extension, function) = unkey(key);
+        let ticks = entry.ticks.load(Ordering::Relaxed);
+        match stats
+            .iter_mut()
+            .find(|stat| (stat.extension, stat.function) == (extension, function))
+        {
+            Some(stat) => {
+                stat.calls += calls;
+                stat.ticks += ticks;
+            }
+            None => stats.push(CallStats {
+                extension,
+                function,
+                calls,
+                ticks,
+            }),
+        }
+    }
+}
+
+fn sort(stats: &mut [CallStats]) {
+    stats.sort_by_key(|stat| {
+        (
+            core::cmp::Reverse(stat.ticks),
+            stat.extension,
+            stat.function,
+        )
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{isr::without_interrupts, sbi::base_extension};
+
+    #[test_case]
+    fn counts_calls() {
+        let (extension, function) = (ExtensionId::TIMER, FunctionId(0));
+        assert_eq!(unkey(key(extension, function)), (extension, function));
+        assert_eq!(
+            unkey(key(ExtensionId::LEGACY_SET_TIMER, FunctionId(0)))
+                .0
+                 .0,
+            0
+        );
+
+        let this = try_current_hart().unwrap();
+        let calls = |stats: &[CallStats]| {
+            stats
+                .iter()
+                .find(|stat| stat.extension == ExtensionId::BASE && stat.function == FunctionId(0))
+                .map_or(0, |stat| stat.calls)
+        };
+        // Or the timer could make a call in between.
+        let (before, after) = without_interrupts(|| {
+            let before = hart(this);
+            base_extension().get_spec_version().unwrap();
+            (before, hart(this))
+        });
+        assert_eq!(calls(&after), calls(&before) + 1);
+        let made = since(&after, &before);
+        assert_eq!(made.len(), 1);
+        assert_eq!(made[0].name(), Some("Get SBI specification version"));
+    }
+}
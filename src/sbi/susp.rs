@@ -0,0 +1,56 @@
+use crate::sync::Once;
+
+use super::{
+    base::SbiExtension,
+    hart::{call_with_resume, SuspendContext},
+    FunctionId, SbiResult,
+};
+
+/// Suspending the whole machine to RAM. Firmware only has it where the platform can.
+pub static SYSTEM_SUSPEND_EXTENSION: Once<SystemSuspendExtension> = Once::INIT;
+
+/// `None` if the firmware can't suspend the machine, and then
+/// [`suspend_system`](crate::idle::suspend_system) fails with `NoExtension`.
+pub fn system_suspend_extension() -> Option<&'static SystemSuspendExtension> {
+    SYSTEM_SUSPEND_EXTENSION.get()
+}
+
+pub struct SystemSuspendExtension {
+    _probe_result: isize,
+}
+
+const SUSP_SYSTEM_SUSPEND: FunctionId = FunctionId(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SleepType(pub u32);
+
+impl SleepType {
+    pub const SUSPEND_TO_RAM: SleepType = SleepType(0);
+}
+
+impl SbiExtension for SystemSuspendExtension {
+    fn id() -> super::ExtensionId {
+        super::ExtensionId::SUSP
+    }
+
+    unsafe fn from_probe(probe_result: isize) -> Self {
+        SystemSuspendExtension {
+            _probe_result: probe_result,
+        }
+    }
+}
+
+impl SystemSuspendExtension {
+    /// Suspend the whole machine. Every other hart has to be stopped first. The calling
+    /// hart resumes like from a non-retentive suspend, and this returns once it has.
+    ///
+    /// # Safety
+    /// As for [`Hsm::hart_non_retentive_suspend`](super::hart::Hsm::hart_non_retentive_suspend).
+    pub unsafe fn system_suspend(
+        &self,
+        sleep_type: SleepType,
+        ctx: *mut SuspendContext,
+    ) -> SbiResult<()> {
+        call_with_resume(Self::id(), SUSP_SYSTEM_SUSPEND, sleep_type.0 as usize, ctx)
+    }
+}
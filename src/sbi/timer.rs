@@ -1,5 +1,5 @@
 use cfg_if::cfg_if;
-use spin::Once;
+use crate::sync::Once;
 
 use super::{
     call::{sbi_call1, sbi_call2},
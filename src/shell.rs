@@ -0,0 +1,1094 @@
+//! The kernel shell. Where `kmain` ends up once everything is set up.
+//!
+//! Reads commands from the console with a little line editing: backspace, `^U` and `^W`
+//! to erase, `^C` to give up on a line, and up and down for history.
+
+use ::time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+use alloc::{collections::VecDeque, format, sync::Arc};
+use core::time::Duration;
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    basic_allocator, boot, console, cpustat, devices,
+    finisher::{self, ExitCode},
+    hart_local::current_hart,
+    hwinfo::{self, HwInfo},
+    idle, log, memmap,
+    net::{self, wire::Ipv4Addr},
+    pagetable::{self, memory_map, EntryFlags},
+    pci::{self, Bar},
+    perf,
+    prelude::*,
+    process::{self, programs, Pid, Process, State},
+    sbi::{
+        self,
+        hart::{hsm_extension, HartId},
+        pmu::pmu_extension,
+        reset::{shutdown, ResetReason, ResetType, SYSTEM_RESET_EXTENSION},
+    },
+    task,
+    time::{self, SystemTime},
+    trace,
+    trap::{debugger, gdbstub},
+    vmalloc, workqueue,
+};
+
+const PROMPT: &str = "> ";
+/// Lines of history kept.
+const HISTORY: usize = 16;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&HwInfo, &[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        help: "heap usage, mapped devices and vmalloc areas",
+        run: mem,
+    },
+    Command {
+        name: "memmap",
+        usage: "[address]",
+        help: "physical memory map, or the region an address is in",
+        run: memmap,
+    },
+    Command {
+        name: "heap",
+        usage: "",
+        help: "heap usage, allocation counts by size and the largest free block",
+        run: heap,
+    },
+    Command {
+        name: "pt",
+        usage: "<pid | elf>",
+        help: "dump a process's page table, or the one an ELF file would get",
+        run: pt,
+    },
+    Command {
+        name: "v2p",
+        usage: "<address> [pid]",
+        help: "where a virtual address goes, in the kernel or a process",
+        run: v2p,
+    },
+    Command {
+        name: "harts",
+        usage: "",
+        help: "list harts and their state",
+        run: harts,
+    },
+    Command {
+        name: "dtb",
+        usage: "[dump [path]]",
+        help: "what was read from the device tree, or the tree itself",
+        run: dtb,
+    },
+    Command {
+        name: "devices",
+        usage: "",
+        help: "list devices and the drivers bound to them",
+        run: devices,
+    },
+    Command {
+        name: "lspci",
+        usage: "[-v]",
+        help: "list PCI functions, with -v their BARs, interrupt and capabilities",
+        run: lspci,
+    },
+    Command {
+        name: "net",
+        usage: "",
+        help: "the network interface and its address",
+        run: net_status,
+    },
+    Command {
+        name: "ping",
+        usage: "<addr> [count]",
+        help: "send ICMP echo requests and wait for the replies",
+        run: ping,
+    },
+    Command {
+        name: "date",
+        usage: "[YYYY-MM-DD HH:MM:SS | @seconds]",
+        help: "show the time, or set the clock, in UTC",
+        run: date,
+    },
+    Command {
+        name: "serial",
+        usage: "[baud[parity[bits[stop]]]]",
+        help: "console UART line settings and receive errors",
+        run: serial,
+    },
+    Command {
+        name: "dmesg",
+        usage: "",
+        help: "replay the kernel log",
+        run: dmesg,
+    },
+    Command {
+        name: "boot",
+        usage: "[times]",
+        help: "problems boot carried on past, or how long each stage took",
+        run: boot_problems,
+    },
+    Command {
+        name: "loglevel",
+        usage: "[spec]",
+        help: "show or change log levels, eg. `debug` or `info,pagetable=debug`",
+        run: loglevel,
+    },
+    Command {
+        name: "perf",
+        usage: "[command [args...]]",
+        help: "dump the performance counters, or count what a command does",
+        run: perf,
+    },
+    Command {
+        name: "sbi",
+        usage: "[hart | reset]",
+        help: "SBI calls made, on all harts or one, and the time spent in them",
+        run: sbi_stats,
+    },
+    Command {
+        name: "top",
+        usage: "[seconds]",
+        help: "how busy each hart and process is, every few seconds until a key's pressed",
+        run: top,
+    },
+    Command {
+        name: "work",
+        usage: "",
+        help: "list deferred work from interrupt handlers, and how often each has run",
+        run: work,
+    },
+    Command {
+        name: "trace",
+        usage: "[on | off | clear | json]",
+        help: "start or stop tracing, or print what's been traced, as text or Chrome trace JSON",
+        run: trace_command,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        help: "list processes. /proc has more on each",
+        run: ps,
+    },
+    Command {
+        name: "renice",
+        usage: "<nice> <pid>",
+        help: "set a process's nice value, -20 (first) to 19 (last)",
+        run: renice,
+    },
+    Command {
+        name: "run",
+        usage: "<elf> [args...]",
+        help: "run a program and wait for it to exit",
+        run: run_program,
+    },
+    Command {
+        name: "usertest",
+        usage: "[name [args...]]",
+        help: "list the built-in user programs, or run one",
+        run: usertest,
+    },
+    Command {
+        name: "break",
+        usage: "[-d] [addr | symbol]",
+        help: "list kernel breakpoints, set one, or with -d remove one",
+        run: break_at,
+    },
+    Command {
+        name: "kdb",
+        usage: "",
+        help: "stop in the kernel debugger",
+        run: |_, _| debugger::enter(),
+    },
+    Command {
+        name: "gdb",
+        usage: "",
+        help: "stop and wait for GDB on the console",
+        run: gdb,
+    },
+    Command {
+        name: "suspend",
+        usage: "",
+        help: "suspend to RAM until an interrupt wakes the machine",
+        run: suspend,
+    },
+    Command {
+        name: "reboot",
+        usage: "[warm]",
+        help: "reset the machine. A warm reset keeps RAM, and the pstore with it",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "[status]",
+        help: "power off. Under QEMU, exit with a status",
+        run: power_off,
+    },
+];
+
+/// Read and run commands forever.
+pub fn run(hwinfo: &HwInfo) -> ! {
+    println!("Kernel shell. Type `help` for a list of commands.");
+    let mut history = VecDeque::with_capacity(HISTORY);
+    loop {
+        print!("{}", PROMPT);
+        let line = match read_line(&history, console::read_byte) {
+            Some(line) => line,
+            None => continue,
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        match COMMANDS.iter().find(|command| command.name == *name) {
+            Some(command) => (command.run)(hwinfo, args),
+            None => println!("{}: no such command. Try `help`.", name),
+        }
+
+        if history.front() != Some(&line) {
+            if history.len() == HISTORY {
+                history.pop_back();
+            }
+            history.push_front(line);
+        }
+    }
+}
+
+/// Erase `n` characters before the cursor.
+fn erase(n: usize) {
+    for _ in 0..n {
+        // The console turns this into "\x08 \x08".
+        print!("\x7f");
+    }
+}
+
+/// Read a line from `read_byte`, with editing. `None` if it was cancelled with `^C`.
+pub(crate) fn read_line(history: &VecDeque<String>, read_byte: fn() -> u8) -> Option<String> {
+    let mut line = String::new();
+    // How far back in `history` we are. 0 is the line being typed.
+    let mut back = 0;
+    let mut typed = String::new();
+    loop {
+        match read_byte() {
+            b'\r' | b'\n' => {
+                println!();
+                return Some(line);
+            }
+            CTRL_C => {
+                println!("^C");
+                return None;
+            }
+            8 | 0x7f => {
+                if line.pop().is_some() {
+                    erase(1);
+                }
+            }
+            CTRL_U => {
+                erase(line.len());
+                line.clear();
+            }
+            CTRL_W => {
+                let trimmed = line.trim_end_matches(' ');
+                let keep = trimmed.rfind(' ').map_or(0, |space| space + 1);
+                erase(line.len() - keep);
+                line.truncate(keep);
+            }
+            ESC => {
+                // Only the arrow keys: ESC [ A and ESC [ B.
+                if read_byte() != b'[' {
+                    continue;
+                }
+                let new_back = match read_byte() {
+                    b'A' if back < history.len() => back + 1,
+                    b'B' if back > 0 => back - 1,
+                    _ => continue,
+                };
+                if back == 0 {
+                    typed = line.clone();
+                }
+                back = new_back;
+                erase(line.len());
+                line = match back {
+                    0 => typed.clone(),
+                    n => history[n - 1].clone(),
+                };
+                print!("{}", line);
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => {
+                line.push(byte as char);
+                print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn help(_: &HwInfo, _: &[&str]) {
+    for command in COMMANDS {
+        let usage = format!("{} {}", command.name, command.usage);
+        println!("  {:<24} {}", usage, command.help);
+    }
+}
+
+fn mem(_: &HwInfo, _: &[&str]) {
+    let (used, free) = basic_allocator::heap_usage();
+    println!("heap: {} KiB used, {} KiB free", used / 1024, free / 1024);
+    memory_map::for_each_io_region(|region| {
+        println!(
+            "  {:#x}..{:#x} -> {:#010x} {}",
+            region.start, region.end, region.maps_to, region.description
+        );
+    });
+    vmalloc::for_each_area(|range, description| {
+        println!(
+            "  {:#x}..{:#x} {:>8} KiB {}",
+            range.start,
+            range.end,
+            (range.end - range.start) / 1024,
+            description
+        );
+    });
+}
+
+fn memmap(_: &HwInfo, args: &[&str]) {
+    match args {
+        [] => {
+            for region in memmap::iter() {
+                println!("  {}", region);
+            }
+            for conflict in memmap::conflicts() {
+                println!("  conflict: {}", conflict);
+            }
+        }
+        [address] => match parse_address(address).and_then(PhysAddr::try_new) {
+            Some(address) => match memmap::find(address) {
+                Some(region) => println!("  {}", region),
+                None => println!("{:#x} isn't in the memory map", address),
+            },
+            None => println!("bad address {:?}", address),
+        },
+        _ => println!("usage: memmap [address]"),
+    }
+}
+
+/// Hex with `0x`, decimal without.
+fn parse_address(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn heap(_: &HwInfo, _: &[&str]) {
+    println!("{}", basic_allocator::stats());
+}
+
+fn pt(_: &HwInfo, args: &[&str]) {
+    let process = match args {
+        [arg] => match arg.parse() {
+            Ok(pid) => match process::find(Pid(pid)) {
+                Some(process) => process,
+                None => return println!("pt: no process {}", pid),
+            },
+            Err(_) => match load(arg, args) {
+                Some(process) => process,
+                None => return,
+            },
+        },
+        _ => return println!("usage: pt <pid | elf>"),
+    };
+
+    // Runs of pages that carry on where the last one left off are printed together.
+    let mut run: Option<(VirtAddr, PhysAddr, EntryFlags, u64)> = None;
+    let print_run = |(va, pa, flags, len): (VirtAddr, PhysAddr, EntryFlags, u64)| {
+        println!(
+            "  {:#011x}..{:#011x} -> {:#011x} {:?}",
+            va,
+            va.as_u64().wrapping_add(len),
+            pa,
+            flags
+        );
+    };
+    process
+        .memory()
+        .page_table()
+        .for_each_mapping(|va, pa, flags, size| match &mut run {
+            Some((start, phys, run_flags, len))
+                if start.checked_add(*len) == Some(va)
+                    && phys.checked_add(*len) == Some(pa)
+                    && *run_flags == flags =>
+            {
+                *len += size
+            }
+            _ => {
+                if let Some(done) = run.replace((va, pa, flags, size)) {
+                    print_run(done);
+                }
+            }
+        });
+    if let Some(done) = run {
+        print_run(done);
+    }
+    let verified = pagetable::verify(process.memory().page_table());
+    for violation in verified.err().unwrap_or_default() {
+        println!("  bad entry at {}", violation);
+    }
+}
+
+fn v2p(_: &HwInfo, args: &[&str]) {
+    let (address, pid) = match args {
+        [address] => (address, None),
+        [address, pid] => match pid.parse() {
+            Ok(pid) => (address, Some(Pid(pid))),
+            Err(_) => return println!("v2p: bad pid {:?}", pid),
+        },
+        _ => return println!("usage: v2p <address> [pid]"),
+    };
+    let va = match parse_address(address).and_then(VirtAddr::try_new) {
+        Some(va) => va,
+        None => return println!("v2p: bad address {:?}", address),
+    };
+    let found = match pid {
+        None => pagetable::translate_current(va),
+        Some(pid) => match process::find(pid) {
+            Some(process) => pagetable::translate(process.memory().page_table(), va),
+            None => return println!("v2p: no process {}", pid),
+        },
+    };
+    match found {
+        Some(found) => println!("  {:#x} -> {}", va, found),
+        None => println!("  {:#x} isn't mapped", va),
+    }
+}
+
+fn harts(hwinfo: &HwInfo, _: &[&str]) {
+    let hsm = hsm_extension();
+    for hart in &hwinfo.harts {
+        let current = if hart.hart_id == current_hart() {
+            " (this one)"
+        } else {
+            ""
+        };
+        match hsm.hart_get_status(hart.hart_id) {
+            Ok(state) => println!("  {} {}: {:?}{}", hart.hart_id.0, hart.name, state, current),
+            Err(err) => println!("  {} {}: {:?}{}", hart.hart_id.0, hart.name, err, current),
+        }
+    }
+    let stats: Vec<String> = idle::stats()
+        .iter()
+        .map(|(state, count)| format!("{:?} {}", state, count))
+        .collect();
+    println!("idle: {}", stats.join(", "));
+}
+
+fn perf(hwinfo: &HwInfo, args: &[&str]) {
+    if let Some((name, args)) = args.split_first() {
+        let command = match COMMANDS.iter().find(|command| command.name == *name) {
+            Some(command) => command,
+            None => return println!("{}: no such command. Try `help`.", name),
+        };
+        let calls = sbi::stats::total();
+        let ((), sample) = perf::measure(|| (command.run)(hwinfo, args));
+        println!("{}", sample);
+        return print_sbi_calls(&sbi::stats::since(&sbi::stats::total(), &calls));
+    }
+
+    let pmu = match pmu_extension() {
+        Some(pmu) => pmu,
+        None => return println!("perf: no PMU extension"),
+    };
+    let counters = match pmu.num_counters() {
+        Ok(counters) => counters,
+        Err(err) => return println!("perf: {}", err),
+    };
+    for index in 0..counters {
+        let info = match pmu.counter_get_info(index) {
+            Ok(info) => info,
+            Err(err) => {
+                println!("  {}: {}", index, err);
+                continue;
+            }
+        };
+        let value = perf::read_counter(pmu, info, index);
+        let value = value.map_or_else(|| "?".to_owned(), |value| format!("{}", value));
+        if info.is_firmware() {
+            println!("  {}: firmware       {}", index, value);
+        } else {
+            println!(
+                "  {}: csr {:#x} {}-bit {}",
+                index,
+                info.csr(),
+                info.width() as u32 + 1,
+                value
+            );
+        }
+    }
+}
+
+fn dtb(hwinfo: &HwInfo, args: &[&str]) {
+    let prefix = match args {
+        [] => return println!("{:#?}", hwinfo),
+        ["dump"] => "/",
+        ["dump", path] => path,
+        _ => return println!("usage: dtb [dump [path]]"),
+    };
+    let dtb = match hwinfo::dtb() {
+        Some(dtb) => dtb,
+        None => return println!("dtb: no device tree"),
+    };
+    let prefix = prefix.trim_end_matches('/');
+    let mut found = false;
+    // Nodes come parents first, so one is closed when the next isn't inside it.
+    let mut open: Vec<usize> = Vec::new();
+    let close = |depth: usize| println!("{}}};", "  ".repeat(depth));
+    for node in dtb.nodes() {
+        let inside = node
+            .path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'));
+        if !inside {
+            continue;
+        }
+        found = true;
+        let depth = node.path.matches('/').count() - usize::from(node.path == "/");
+        while open.last().map_or(false, |&parent| parent >= depth) {
+            close(open.pop().unwrap());
+        }
+        let indent = "  ".repeat(depth);
+        let name = if node.path == "/" { "/" } else { &node.name };
+        println!("{}{} {{", indent, name);
+        for (prop, value) in &node.props {
+            println!("{}  {} = {};", indent, prop, format_prop(value));
+        }
+        open.push(depth);
+    }
+    while let Some(depth) = open.pop() {
+        close(depth);
+    }
+    if !found {
+        println!("dtb: no node {}", prefix);
+    }
+}
+
+/// A property value the way `dtc` would write it: strings if it looks like strings,
+/// otherwise 32 bit cells, otherwise bytes.
+fn format_prop(value: &[u8]) -> String {
+    let strings = value.last() == Some(&0)
+        && value[..value.len() - 1]
+            .split(|&b| b == 0)
+            .all(|s| !s.is_empty() && s.iter().all(|&b| b.is_ascii_graphic() || b == b' '));
+    if value.is_empty() {
+        String::from("<>")
+    } else if strings {
+        value[..value.len() - 1]
+            .split(|&b| b == 0)
+            .map(|s| format!("\"{}\"", String::from_utf8_lossy(s)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else if value.len() % 4 == 0 {
+        let cells: Vec<String> = value
+            .chunks(4)
+            .map(|cell| {
+                format!(
+                    "{:#x}",
+                    u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]])
+                )
+            })
+            .collect();
+        format!("<{}>", cells.join(" "))
+    } else {
+        let bytes: Vec<String> = value.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("[{}]", bytes.join(" "))
+    }
+}
+
+fn devices(_: &HwInfo, _: &[&str]) {
+    devices::for_each(|device| {
+        println!(
+            "  {:<32} {:<12} {}",
+            device.path,
+            device.driver.name,
+            device.instance.describe()
+        );
+    });
+}
+
+fn lspci(_: &HwInfo, args: &[&str]) {
+    let verbose = args.first() == Some(&"-v");
+    for device in pci::devices() {
+        println!(
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            device.address,
+            device.class_name(),
+            device.class.0,
+            device.class.1,
+            device.vendor_id,
+            device.device_id,
+            device.revision
+        );
+        if !verbose {
+            continue;
+        }
+        if let Some(interrupt) = device.interrupt {
+            println!("  interrupt: INTx, PLIC {}", interrupt.get());
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                }) => println!(
+                    "  BAR {}: memory at 0x{:x}, {} bytes{}",
+                    index,
+                    address,
+                    size,
+                    if *prefetchable { ", prefetchable" } else { "" }
+                ),
+                Some(Bar::Io { port, size }) => {
+                    println!("  BAR {}: I/O at 0x{:x}, {} bytes", index, port, size)
+                }
+                None => {}
+            }
+        }
+        for (id, offset) in device.config.capabilities() {
+            let name = match id {
+                pci::CAP_MSI => "MSI",
+                pci::CAP_MSIX => "MSI-X",
+                pci::CAP_VENDOR => "vendor specific",
+                _ => "other",
+            };
+            println!("  capability 0x{:02x} at 0x{:02x}: {}", id, offset, name);
+        }
+    }
+}
+
+fn net_status(_: &HwInfo, _: &[&str]) {
+    let Some((mac, config)) = net::status() else {
+        println!("no network interface");
+        return;
+    };
+    println!("eth0: {}", mac);
+    match config {
+        Some(config) => {
+            println!("  inet {}/{}", config.address, config.prefix);
+            if let Some(gateway) = config.gateway {
+                println!("  gateway {}", gateway);
+            }
+            if let Some(dns) = config.dns {
+                println!("  dns {}", dns);
+            }
+        }
+        None => println!("  no address yet"),
+    }
+}
+
+fn ping(_: &HwInfo, args: &[&str]) {
+    let Some(Ok(to)) = args.first().map(|arg| arg.parse::<Ipv4Addr>()) else {
+        println!("usage: ping <addr> [count]");
+        return;
+    };
+    let count = args.get(1).and_then(|arg| arg.parse().ok()).unwrap_or(4u16);
+    for seq in 0..count {
+        match task::block_on(net::ping(to, 0xade1, seq, Duration::from_secs(1))) {
+            Ok(Some(rtt)) => println!("reply from {}: seq={} time={:?}", to, seq, rtt),
+            Ok(None) => println!("no reply from {}: seq={}", to, seq),
+            Err(err) => {
+                println!("ping: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+fn date(_: &HwInfo, args: &[&str]) {
+    if !args.is_empty() {
+        let time = match args {
+            [seconds] if seconds.starts_with('@') => seconds[1..]
+                .parse::<u64>()
+                .ok()
+                .map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)),
+            [date, time] => {
+                let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+                PrimitiveDateTime::parse(&format!("{} {}", date, time), format)
+                    .ok()
+                    .and_then(|time| u64::try_from(time.assume_utc().unix_timestamp_nanos()).ok())
+                    .map(SystemTime::from_unix_nanos)
+            }
+            _ => None,
+        };
+        match time {
+            Some(time) => time::set_time_of_day(time),
+            None => return println!("date: expected YYYY-MM-DD HH:MM:SS or @seconds"),
+        }
+    }
+    let now = OffsetDateTime::now_utc();
+    println!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+}
+
+fn serial(_: &HwInfo, args: &[&str]) {
+    if let [line] = args {
+        if let Err(err) = line.parse().and_then(console::set_uart_line) {
+            return println!("serial: {}: {}", line, err);
+        }
+    }
+    let Some((config, errors)) = console::uart_status() else {
+        return println!("serial: no UART");
+    };
+    match config {
+        Some(config) => println!("{}", config),
+        None => println!("set by firmware"),
+    }
+    println!(
+        "  overrun {}  parity {}  framing {}  break {}",
+        errors.overrun, errors.parity, errors.framing, errors.breaks
+    );
+}
+
+fn dmesg(_: &HwInfo, _: &[&str]) {
+    print!("{}", String::from_utf8_lossy(&log::contents()));
+}
+
+fn boot_problems(_: &HwInfo, args: &[&str]) {
+    match args {
+        [] => {}
+        ["times"] => {
+            println!("     start       time      stage");
+            for timing in boot::timings() {
+                println!("{}", timing);
+            }
+            return;
+        }
+        _ => return println!("usage: boot [times]"),
+    }
+    let problems = boot::problems();
+    if problems.is_empty() {
+        return println!("no problems");
+    }
+    for problem in problems {
+        println!("{}: {}", problem.stage, problem.message);
+    }
+}
+
+fn loglevel(_: &HwInfo, args: &[&str]) {
+    for spec in args {
+        if let Err(err) = log::configure(spec) {
+            return println!("loglevel: {}: {}", spec, err);
+        }
+    }
+    println!("{}", log::filters());
+}
+
+fn sbi_stats(_: &HwInfo, args: &[&str]) {
+    let calls = match args {
+        [] => sbi::stats::total(),
+        ["reset"] => return sbi::stats::reset(),
+        [hart] => match hart.parse() {
+            Ok(hart) => {
+                let dropped = sbi::stats::dropped(HartId(hart));
+                if dropped > 0 {
+                    println!("{} calls not counted", dropped);
+                }
+                sbi::stats::hart(HartId(hart))
+            }
+            Err(_) => return println!("usage: sbi [hart | reset]"),
+        },
+        _ => return println!("usage: sbi [hart | reset]"),
+    };
+    print_sbi_calls(&calls);
+}
+
+fn print_sbi_calls(calls: &[sbi::stats::CallStats]) {
+    println!(
+        "  {:>10} {:>4} {:>10} {:>12} {:>10}  CALL",
+        "EID", "FID", "CALLS", "TOTAL", "MEAN"
+    );
+    for call in calls {
+        let mean = call.mean();
+        println!(
+            "  {:>#10x} {:>4} {:>10} {:>10}us {:>5}.{:02}us  {}",
+            call.extension.id(),
+            call.function.id(),
+            call.calls,
+            call.time().as_micros(),
+            mean.as_micros(),
+            mean.subsec_nanos() % 1000 / 10,
+            call.name().unwrap_or("?")
+        );
+    }
+}
+
+fn top(_: &HwInfo, args: &[&str]) {
+    let interval = match args.first().map(|arg| arg.parse::<u64>()) {
+        None => Duration::from_secs(2),
+        Some(Ok(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+        _ => return println!("usage: top [seconds]"),
+    };
+    let mut earlier = cpustat::Snapshot::take();
+    loop {
+        let key = task::block_on(time::timeout(interval, console::read_byte_async()));
+        if key.is_ok() {
+            return;
+        }
+        let now = cpustat::Snapshot::take();
+        println!();
+        for load in now.harts_since(&earlier) {
+            println!(
+                "hart {}: {:>3}% busy, {} switches",
+                load.hart.0, load.busy, load.switches
+            );
+        }
+        println!(
+            "  {:>5} {:>4} {:>10} {:>8}  NAME",
+            "PID", "CPU", "TIME", "SWITCHES"
+        );
+        for load in now.processes_since(&earlier) {
+            println!(
+                "  {:>5} {:>3}% {:>6}.{:03} {:>8}  {}",
+                load.pid,
+                load.cpu,
+                load.cpu_time.as_secs(),
+                load.cpu_time.subsec_millis(),
+                load.switches,
+                load.name
+            );
+        }
+        earlier = now;
+    }
+}
+
+fn work(_: &HwInfo, _: &[&str]) {
+    for work in workqueue::list() {
+        let pending = if work.is_pending() { " (pending)" } else { "" };
+        println!("  {:<16} {:>8} runs{}", work.name(), work.runs(), pending);
+    }
+}
+
+fn trace_command(_: &HwInfo, args: &[&str]) {
+    let json = match args {
+        [] => false,
+        ["json"] => true,
+        ["on"] => return trace::enable(),
+        ["off"] => return trace::disable(),
+        ["clear"] => return trace::clear(),
+        _ => return println!("usage: trace [on | off | clear | json]"),
+    };
+    let records = trace::snapshot();
+    if records.is_empty() && !json {
+        let state = if trace::enabled() { "on" } else { "off" };
+        return println!("nothing traced. Tracing is {}", state);
+    }
+    let mut out = String::new();
+    let _ = if json {
+        trace::write_json(&mut out, &records)
+    } else {
+        trace::write_text(&mut out, &records)
+    };
+    // A line at a time, so the console isn't held for all of it.
+    for line in out.lines() {
+        println!("{}", line);
+    }
+}
+
+fn ps(_: &HwInfo, _: &[&str]) {
+    println!(
+        "  {:>5} {:>5} {:<10} {:>3} {:>4} {:>10} {:>8} {:>3}  NAME",
+        "PID", "PPID", "STATE", "NI", "HART", "TIME", "MEM", "FDS"
+    );
+    for process in process::list() {
+        let state = match process.state() {
+            State::Ready => "ready".into(),
+            State::Running => "running".into(),
+            State::Exited(code) => format!("exit {}", code),
+        };
+        let usage = process.usage();
+        let hart = usage.hart.map_or("-".into(), |hart| format!("{}", hart.0));
+        let parent = process.parent().map_or(0, |parent| parent.pid().0);
+        let time = usage.cpu_time;
+        println!(
+            "  {:>5} {:>5} {:<10} {:>3} {:>4} {:>6}.{:03} {:>5}KiB {:>3}  {}",
+            process.pid(),
+            parent,
+            state,
+            process.nice(),
+            hart,
+            time.as_secs(),
+            time.subsec_millis(),
+            process.memory().size() / 1024,
+            process.files().iter().count(),
+            process.name()
+        );
+    }
+}
+
+fn renice(_: &HwInfo, args: &[&str]) {
+    let (nice, pid) = match args {
+        [nice, pid] => match (nice.parse::<i8>(), pid.parse()) {
+            (Ok(nice), Ok(pid)) => (nice, Pid(pid)),
+            _ => return println!("usage: renice <nice> <pid>"),
+        },
+        _ => return println!("usage: renice <nice> <pid>"),
+    };
+    match process::find(pid) {
+        Some(process) => process.set_nice(nice),
+        None => println!("renice: no process {}", pid),
+    }
+}
+
+/// Load `path` as a process, with `args` as its `argv`.
+fn load(path: &str, args: &[&str]) -> Option<Arc<Process>> {
+    let elf = match programs::read(path) {
+        Ok(elf) => elf,
+        Err(err) => {
+            println!("{}: {}", path, err);
+            return None;
+        }
+    };
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let argv: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+    match Process::from_elf(name, &elf, &argv) {
+        Ok(process) => Some(process),
+        Err(err) => {
+            println!("{}: {}", path, err);
+            None
+        }
+    }
+}
+
+fn run_program(_: &HwInfo, args: &[&str]) {
+    let path = match args.first() {
+        Some(path) => path,
+        None => return println!("usage: run <elf> [args...]"),
+    };
+    if let Some(process) = load(path, args) {
+        let code = process::run(process);
+        println!("{} exited with status {}", path, code);
+    }
+}
+
+fn usertest(_: &HwInfo, args: &[&str]) {
+    let name = match args.first() {
+        Some(name) => name,
+        None => {
+            if programs::all().is_empty() {
+                println!("no built-in programs: build with USER_PROGRAMS set");
+            }
+            for program in programs::all() {
+                println!("{:<16} {:>8} bytes", program.name, program.elf.len());
+            }
+            return;
+        }
+    };
+    let program = match programs::find(name) {
+        Some(program) => program,
+        None => return println!("usertest: no built-in program {}", name),
+    };
+    let path = format!("{}{}", programs::DIR, program.name);
+    let argv: Vec<&[u8]> = core::iter::once(path.as_bytes())
+        .chain(args[1..].iter().map(|arg| arg.as_bytes()))
+        .collect();
+    match Process::from_elf(program.name, program.elf, &argv) {
+        Ok(process) => {
+            let code = process::run(process);
+            println!("{} exited with status {}", program.name, code);
+        }
+        Err(err) => println!("usertest: {}: {}", program.name, err),
+    }
+}
+
+fn break_at(_: &HwInfo, args: &[&str]) {
+    let (delete, arg) = match args {
+        [] => {
+            for addr in debugger::breakpoints() {
+                println!("  0x{:x}", addr);
+            }
+            return;
+        }
+        ["-d", arg] => (true, arg),
+        [arg] => (false, arg),
+        _ => return println!("usage: break [-d] [addr | symbol]"),
+    };
+    let addr = match debugger::parse_address(arg) {
+        Some(addr) => addr,
+        None => return println!("break: no symbol {}", arg),
+    };
+    if delete {
+        if !debugger::remove(addr) {
+            println!("break: no breakpoint at 0x{:x}", addr);
+        }
+    } else if let Err(err) = debugger::set(addr) {
+        println!("break: {}", err);
+    }
+}
+
+fn gdb(_: &HwInfo, _: &[&str]) {
+    println!("Waiting for GDB. `target remote` the console.");
+    gdbstub::wait();
+}
+
+fn suspend(_: &HwInfo, _: &[&str]) {
+    match idle::suspend_system() {
+        Ok(()) => println!("resumed"),
+        Err(err) => println!("suspend: {}", err),
+    }
+}
+
+fn reboot(_: &HwInfo, args: &[&str]) {
+    let reset_type = match args {
+        [] => ResetType::ColdReboot,
+        ["warm"] => ResetType::WarmReboot,
+        _ => return println!("usage: reboot [warm]"),
+    };
+    match SYSTEM_RESET_EXTENSION.get() {
+        Some(reset) => {
+            if let Err(err) = reset.reset(reset_type, ResetReason::NoReason) {
+                println!("reboot failed: {:?}", err);
+            }
+        }
+        None => println!("reboot: no SBI system reset extension"),
+    }
+}
+
+fn power_off(_: &HwInfo, args: &[&str]) {
+    let code = match args {
+        [] => None,
+        [status] => match status.parse() {
+            Ok(0) => Some(ExitCode::Success),
+            Ok(code) => Some(ExitCode::Failure(code)),
+            Err(_) => return println!("shutdown: bad status {}", status),
+        },
+        _ => return println!("usage: shutdown [status]"),
+    };
+    if let Some(code) = code {
+        finisher::exit(code);
+        println!("shutdown: no test finisher to exit with, powering off");
+    }
+    shutdown()
+}
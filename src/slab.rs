@@ -0,0 +1,220 @@
+//! Slab caches in front of the heap.
+//!
+//! Most allocations are small, and page tables come and go a page at a time. Mixed in with
+//! everything else on the linked-list heap they leave it in pieces. So anything up to
+//! [`MAX_OBJECT`] bytes is rounded up to a power of two and comes from a cache of that
+//! size, which takes a page from the heap and cuts it up when it runs out, and anything
+//! page sized comes from a cache of whole pages. Freed memory goes back on its cache's
+//! list. The page cache hands pages past [`MAX_CACHED_PAGES`] back to the heap, the others
+//! keep what they've got.
+
+use core::{alloc::Layout, ptr::NonNull};
+
+use spin::Mutex;
+
+use crate::pagetable::PAGE_SIZE;
+
+pub const MIN_OBJECT: usize = 16;
+pub const MAX_OBJECT: usize = 2048;
+const OBJECT_CACHES: usize = (MAX_OBJECT / MIN_OBJECT).trailing_zeros() as usize + 1;
+/// Free pages the page cache keeps before giving them back.
+pub const MAX_CACHED_PAGES: usize = 256;
+const PAGE: usize = PAGE_SIZE as usize;
+
+/// Where an allocation of some [`Layout`] comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// The object cache with objects of `MIN_OBJECT << n` bytes.
+    Object(usize),
+    Page,
+    /// The heap.
+    Large,
+}
+
+impl Size {
+    pub fn of(layout: Layout) -> Size {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(MIN_OBJECT)
+            .next_power_of_two();
+        if size <= MAX_OBJECT {
+            Size::Object((size / MIN_OBJECT).trailing_zeros() as usize)
+        } else if size == PAGE {
+            Size::Page
+        } else {
+            Size::Large
+        }
+    }
+
+    /// Bytes in each of the cache's objects. `None` for [`Size::Large`].
+    pub fn bytes(self) -> Option<usize> {
+        match self {
+            Size::Object(cache) => Some(MIN_OBJECT << cache),
+            Size::Page => Some(PAGE),
+            Size::Large => None,
+        }
+    }
+}
+
+/// Layout of the pages the caches take from the heap.
+pub fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE, PAGE).unwrap()
+}
+
+/// Free memory, each piece holding a pointer to the next.
+struct FreeList {
+    head: Option<NonNull<Free>>,
+    len: usize,
+}
+
+struct Free {
+    next: Option<NonNull<Free>>,
+}
+
+// Only the pointers to free memory, which nothing else has.
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn new() -> FreeList {
+        FreeList { head: None, len: 0 }
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let head = self.head?;
+        self.head = unsafe { head.as_ref().next };
+        self.len -= 1;
+        Some(head.cast())
+    }
+
+    /// # Safety
+    /// `ptr` is free, aligned for a pointer and big enough for one.
+    unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        let ptr = ptr.cast::<Free>();
+        ptr.as_ptr().write(Free { next: self.head });
+        self.head = Some(ptr);
+        self.len += 1;
+    }
+}
+
+pub struct Slabs {
+    objects: [Mutex<FreeList>; OBJECT_CACHES],
+    pages: Mutex<FreeList>,
+}
+
+impl Slabs {
+    pub const fn new() -> Slabs {
+        Slabs {
+            objects: [const { Mutex::new(FreeList::new()) }; OBJECT_CACHES],
+            pages: Mutex::new(FreeList::new()),
+        }
+    }
+
+    /// Something of `size`, which mustn't be [`Size::Large`]. `page` gets a page of
+    /// [`page_layout`] from the heap when the cache is empty.
+    pub fn alloc(
+        &self,
+        size: Size,
+        page: impl FnOnce() -> Option<NonNull<u8>>,
+    ) -> Option<NonNull<u8>> {
+        match size {
+            Size::Object(cache) => {
+                let mut objects = self.objects[cache].lock();
+                if let Some(object) = objects.pop() {
+                    return Some(object);
+                }
+                // Cut up a new page. The page cache's lock is taken inside this one, never
+                // the other way round.
+                let page = self.pages.lock().pop().or_else(page)?;
+                let object_size = MIN_OBJECT << cache;
+                for offset in (object_size..PAGE).step_by(object_size) {
+                    unsafe { objects.push(NonNull::new_unchecked(page.as_ptr().add(offset))) };
+                }
+                Some(page)
+            }
+            Size::Page => self.pages.lock().pop().or_else(page),
+            Size::Large => panic!("large allocation from a slab cache"),
+        }
+    }
+
+    /// Put `ptr` back in its cache. Returns it if it's a page the heap should have back.
+    ///
+    /// # Safety
+    /// `ptr` came from [`alloc`](Slabs::alloc) with the same `size`.
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>, size: Size) -> Option<NonNull<u8>> {
+        match size {
+            Size::Object(cache) => self.objects[cache].lock().push(ptr),
+            Size::Page => {
+                let mut pages = self.pages.lock();
+                if pages.len >= MAX_CACHED_PAGES {
+                    return Some(ptr);
+                }
+                pages.push(ptr)
+            }
+            Size::Large => panic!("large allocation freed to a slab cache"),
+        }
+        None
+    }
+
+    /// Bytes sitting free in the caches.
+    pub fn cached(&self) -> usize {
+        let objects: usize = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(cache, objects)| objects.lock().len * (MIN_OBJECT << cache))
+            .sum();
+        objects + self.pages.lock().len * PAGE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Pages([[u8; PAGE]; 2]);
+
+    #[test_case]
+    fn sizes() {
+        let size = |size, align| Size::of(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(size(1, 1), Size::Object(0));
+        assert_eq!(size(16, 8), Size::Object(0));
+        assert_eq!(size(24, 8), Size::Object(1));
+        assert_eq!(size(8, 64), Size::Object(2));
+        assert_eq!(size(2048, 8), Size::Object(OBJECT_CACHES - 1));
+        assert_eq!(size(2049, 8), Size::Page);
+        assert_eq!(size(4096, 4096), Size::Page);
+        assert_eq!(size(16, 4096), Size::Page);
+        assert_eq!(size(4097, 8), Size::Large);
+        assert_eq!(size(16, 8192), Size::Large);
+    }
+
+    #[test_case]
+    fn objects_come_from_pages() {
+        let mut pages = Pages([[0; PAGE]; 2]);
+        let [first, second] = &mut pages.0;
+        let (first, second) = (
+            NonNull::new(first.as_mut_ptr()),
+            NonNull::new(second.as_mut_ptr()),
+        );
+        let slabs = Slabs::new();
+        let size = Size::Object(7);
+
+        let a = slabs.alloc(size, || first).unwrap();
+        let b = slabs
+            .alloc(size, || panic!("should have had room"))
+            .unwrap();
+        assert_eq!(a, first.unwrap());
+        assert_eq!(b.as_ptr() as usize, a.as_ptr() as usize + 2048);
+        assert_eq!(slabs.alloc(size, || second), second);
+        assert_eq!(slabs.cached(), 2048);
+
+        unsafe {
+            assert_eq!(slabs.dealloc(a, size), None);
+            assert_eq!(slabs.dealloc(b, size), None);
+        }
+        assert_eq!(slabs.cached(), 3 * 2048);
+        assert_eq!(slabs.alloc(size, || None), Some(b));
+    }
+}
@@ -0,0 +1,144 @@
+//! Running code on other harts.
+//!
+//! Each hart has a mailbox of calls. [`call_on`] puts one in each target's mailbox and
+//! sends them an IPI with the SBI IPI extension. The supervisor software interrupt lands in
+//! [`handle_ipi`], which runs whatever's queued.
+//!
+//! Only harts that are [online](hart_local::online_harts) get calls. Hart ids past the
+//! first [`MAX_HARTS`] have no mailbox.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    hart_local,
+    isr::{without_interrupts, Sip},
+    log, panic,
+    prelude::*,
+    sbi::{
+        hart::{HartId, HartMask},
+        ipi::ipi_extension,
+    },
+};
+
+/// Harts with a mailbox.
+pub const MAX_HARTS: usize = usize::BITS as usize;
+
+struct Call {
+    f: Arc<dyn Fn() + Send + Sync>,
+    /// Harts that haven't finished yet, shared with the [`Pending`].
+    left: Arc<AtomicUsize>,
+}
+
+/// Only locked with interrupts off, since [`handle_ipi`] takes the hart's own one.
+static MAILBOXES: [Mutex<VecDeque<Call>>; MAX_HARTS] =
+    [const { Mutex::new(VecDeque::new()) }; MAX_HARTS];
+
+/// A call from [`call_on`] that may still be running.
+pub struct Pending {
+    left: Arc<AtomicUsize>,
+}
+
+impl Pending {
+    pub fn is_done(&self) -> bool {
+        self.left.load(Ordering::Acquire) == 0
+    }
+
+    /// Spin until every target has run the call. Runs calls for this hart meanwhile, so
+    /// two harts waiting on each other don't deadlock.
+    pub fn wait(self) {
+        while !self.is_done() {
+            run_queued();
+            crate::cpu::relax();
+        }
+    }
+}
+
+/// Every online hart but this one.
+pub fn other_harts() -> HartMask {
+    let mut harts = hart_local::online_harts();
+    if let Some(hart) = hart_local::try_current_hart() {
+        if hart.0 < MAX_HARTS {
+            harts.clear_id(hart);
+        }
+    }
+    harts
+}
+
+/// Run `f` on each online hart in `targets`, with interrupts off. If this hart is one of
+/// them it runs `f` itself before returning. The others run it when the IPI arrives.
+pub fn call_on(targets: HartMask, f: impl Fn() + Send + Sync + 'static) -> Pending {
+    let online = hart_local::online_harts();
+    let harts: Vec<HartId> = targets
+        .into_iter()
+        .filter(|hart| hart.0 < MAX_HARTS && online.contains(*hart))
+        .collect();
+    let left = Arc::new(AtomicUsize::new(harts.len()));
+    let f: Arc<dyn Fn() + Send + Sync> = Arc::new(f);
+
+    let here = hart_local::try_current_hart();
+    let mut remote = HartMask::new();
+    for &hart in &harts {
+        if Some(hart) == here {
+            continue;
+        }
+        let call = Call {
+            f: f.clone(),
+            left: left.clone(),
+        };
+        without_interrupts(|| MAILBOXES[hart.0].lock().push_back(call));
+        remote.set_id(hart);
+    }
+    if !remote.is_empty() {
+        if let Err(err) = ipi_extension().send_ipi(remote) {
+            log::error!("sending IPI to {:?}: {}", remote, err);
+        }
+    }
+
+    if here.map_or(false, |here| harts.contains(&here)) {
+        without_interrupts(|| f());
+        left.fetch_sub(1, Ordering::Release);
+    }
+    Pending { left }
+}
+
+/// The supervisor software interrupt handler. Parks the hart instead if another one is
+/// panicking.
+pub fn handle_ipi() {
+    Sip::clear(Sip::SSIP);
+    panic::park_if_panicking();
+    run_queued();
+}
+
+/// Run the calls in this hart's mailbox.
+fn run_queued() {
+    let hart = match hart_local::try_current_hart() {
+        Some(hart) if hart.0 < MAX_HARTS => hart,
+        _ => return,
+    };
+    while let Some(call) = without_interrupts(|| MAILBOXES[hart.0].lock().pop_front()) {
+        without_interrupts(|| (call.f)());
+        call.left.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn call_on_this_hart() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut targets = HartMask::new();
+        targets.set_id(hart_local::current_hart());
+        let counted = count.clone();
+        let pending = call_on(targets, move || {
+            counted.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(pending.is_done());
+        pending.wait();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+}
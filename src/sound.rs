@@ -0,0 +1,237 @@
+//! Sound output.
+//!
+//! Samples are signed 16 bit, with a frame's channels interleaved. [`open`] finds a device
+//! that plays the [`PcmConfig`] asked for and returns a [`Playback`]: writes go into its
+//! ring of frames, and the device's driver takes a period at a time from the other end as
+//! it finishes the last. If the ring runs dry the driver gets silence, and it counts as an
+//! underrun. Dropping the `Playback` stops the device.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt::{self, Display, Formatter},
+    future::poll_fn,
+    ops::Deref,
+    task::{Poll, Waker},
+};
+use spin::Mutex;
+
+use crate::{log, sync::IrqSafeMutex};
+
+/// How much a [`Playback`] holds, in milliseconds.
+const RING_MS: u32 = 250;
+
+static DEVICES: Mutex<Vec<Arc<dyn SoundDevice>>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    NoDevice,
+    /// No device plays that rate and number of channels.
+    Unsupported,
+    /// The device is already playing something.
+    Busy,
+    /// The device reported an error.
+    Device,
+}
+
+impl Display for SoundError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundError::NoDevice => write!(f, "no sound device"),
+            SoundError::Unsupported => write!(f, "format not supported"),
+            SoundError::Busy => write!(f, "device busy"),
+            SoundError::Device => write!(f, "device error"),
+        }
+    }
+}
+
+impl core::error::Error for SoundError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmConfig {
+    /// Frames a second.
+    pub rate: u32,
+    pub channels: u8,
+}
+
+impl PcmConfig {
+    pub fn frame_bytes(&self) -> usize {
+        2 * self.channels as usize
+    }
+}
+
+pub trait SoundDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn supports(&self, config: PcmConfig) -> bool;
+
+    /// Start playing `stream`, taking periods from it with [`PcmStream::take`].
+    fn play(&self, stream: Arc<PcmStream>) -> Result<(), SoundError>;
+
+    fn stop(&self);
+}
+
+struct Ring {
+    samples: VecDeque<i16>,
+    /// In samples, not frames.
+    capacity: usize,
+    underruns: u64,
+    /// Someone waiting for room.
+    waker: Option<Waker>,
+}
+
+/// A ring of frames between whoever's playing and the driver.
+pub struct PcmStream {
+    config: PcmConfig,
+    /// The driver takes it from its interrupt handler.
+    ring: IrqSafeMutex<Ring>,
+}
+
+impl PcmStream {
+    pub fn new(config: PcmConfig, frames: usize) -> PcmStream {
+        let capacity = frames * config.channels as usize;
+        PcmStream {
+            config,
+            ring: IrqSafeMutex::new(Ring {
+                samples: VecDeque::with_capacity(capacity),
+                capacity,
+                underruns: 0,
+                waker: None,
+            }),
+        }
+    }
+
+    pub fn config(&self) -> PcmConfig {
+        self.config
+    }
+
+    /// Queue as many whole frames from `samples` as fit. Returns how many samples that was.
+    pub fn write(&self, samples: &[i16]) -> usize {
+        let mut ring = self.ring.lock();
+        let room = ring.capacity - ring.samples.len();
+        let channels = self.config.channels as usize;
+        let len = room.min(samples.len()) / channels * channels;
+        ring.samples.extend(&samples[..len]);
+        len
+    }
+
+    /// Queue all of `samples`, waiting for room as the device plays.
+    pub async fn write_all(&self, mut samples: &[i16]) {
+        let channels = self.config.channels as usize;
+        while samples.len() >= channels {
+            let written = poll_fn(|cx| match self.write(samples) {
+                0 => {
+                    let mut ring = self.ring.lock();
+                    ring.waker = Some(cx.waker().clone());
+                    // Check again in case the driver took some in between.
+                    match ring.samples.len() < ring.capacity {
+                        true => Poll::Ready(0),
+                        false => Poll::Pending,
+                    }
+                }
+                written => Poll::Ready(written),
+            })
+            .await;
+            samples = &samples[written..];
+        }
+    }
+
+    /// Frames waiting to be played.
+    pub fn queued(&self) -> usize {
+        self.ring.lock().samples.len() / self.config.channels as usize
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.ring.lock().underruns
+    }
+
+    /// For drivers: fill `out` with the next samples, and silence for any there aren't.
+    pub fn take(&self, out: &mut [i16]) {
+        let mut ring = self.ring.lock();
+        let len = ring.samples.len().min(out.len());
+        for (out, sample) in out.iter_mut().zip(ring.samples.drain(..len)) {
+            *out = sample;
+        }
+        if len < out.len() {
+            out[len..].fill(0);
+            ring.underruns += 1;
+        }
+        if len > 0 {
+            if let Some(waker) = ring.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A stream being played. Stops the device when dropped.
+pub struct Playback {
+    stream: Arc<PcmStream>,
+    device: Arc<dyn SoundDevice>,
+}
+
+impl Deref for Playback {
+    type Target = PcmStream;
+
+    fn deref(&self) -> &PcmStream {
+        &self.stream
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.device.stop();
+    }
+}
+
+pub fn add_device(device: Arc<dyn SoundDevice>) {
+    log::info!("sound: {}", device.name());
+    DEVICES.lock().push(device);
+}
+
+/// Start playing on the first device that can play `config`.
+pub fn open(config: PcmConfig) -> Result<Playback, SoundError> {
+    if config.channels == 0 || config.rate == 0 {
+        return Err(SoundError::Unsupported);
+    }
+    let devices = DEVICES.lock().clone();
+    if devices.is_empty() {
+        return Err(SoundError::NoDevice);
+    }
+    let device = devices
+        .into_iter()
+        .find(|device| device.supports(config))
+        .ok_or(SoundError::Unsupported)?;
+    let frames = (config.rate * RING_MS / 1000) as usize;
+    let stream = Arc::new(PcmStream::new(config, frames));
+    device.play(stream.clone())?;
+    Ok(Playback { stream, device })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn pcm_ring() {
+        let stream = PcmStream::new(
+            PcmConfig {
+                rate: 8000,
+                channels: 2,
+            },
+            4,
+        );
+        // Only whole frames go in.
+        assert_eq!(stream.write(&[1, 2, 3]), 2);
+        assert_eq!(stream.write(&[3, 4, 5, 6, 7, 8, 9, 10]), 6);
+        assert_eq!(stream.queued(), 4);
+        assert_eq!(stream.write(&[11, 12]), 0);
+
+        let mut period = [0; 6];
+        stream.take(&mut period);
+        assert_eq!(period, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(stream.underruns(), 0);
+        stream.take(&mut period);
+        assert_eq!(period, [7, 8, 0, 0, 0, 0]);
+        assert_eq!(stream.underruns(), 1);
+    }
+}
@@ -0,0 +1,233 @@
+//! Kernel stacks with a guard under them.
+//!
+//! Each [`KernelStack`] gets a [`SLOT_SIZE`] slot in [`STACK_WINDOW`], with its pages at the
+//! top and nothing mapped below them, so running off the bottom faults instead of writing
+//! over whatever's next. [`overflowed`] says whose stack a fault address was under. The
+//! boot hart's stack is in the kernel image, and [`init`] unmaps the page below that one.
+//!
+//! If the window can't be had, stacks come from the heap and have no guard.
+
+use alloc::{collections::BTreeMap, format};
+use core::{
+    alloc::Layout,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    hwinfo::HwInfo,
+    log,
+    pagetable::{
+        alloc_frame, free_frame, map_kernel, memory_map, unmap_kernel, EntryFlags, MapError,
+        PAGE_SIZE,
+    },
+    prelude::*,
+    sync::IrqSafeMutex,
+    tlb,
+};
+
+/// Where kernel stacks go. The gigabyte under [`IO_WINDOW`](memory_map::IO_WINDOW).
+pub const STACK_WINDOW: Range<VirtAddr> =
+    VirtAddr::new(0xffff_ffff_8000_0000)..VirtAddr::new(0xffff_ffff_c000_0000);
+/// Address space each stack gets. Whatever the stack doesn't use is its guard.
+pub const SLOT_SIZE: u64 = 64 * 1024;
+/// Biggest stack that still leaves a guard page in its slot.
+pub const MAX_STACK_SIZE: u64 = SLOT_SIZE - PAGE_SIZE;
+const SLOTS: usize =
+    ((STACK_WINDOW.end.as_u64() - STACK_WINDOW.start.as_u64()) / SLOT_SIZE) as usize;
+
+#[repr(C, align(4096))]
+struct GuardPage([u8; PAGE_SIZE as usize]);
+
+/// Under the boot hart's stack: `linker.ld` puts it right before `__stack_limit`.
+#[link_section = ".stack_guard"]
+static BOOT_GUARD: GuardPage = GuardPage([0; PAGE_SIZE as usize]);
+
+static WINDOW_READY: AtomicBool = AtomicBool::new(false);
+/// Who each slot in use belongs to. Held while mapping, so only one stack's tables change
+/// at once.
+static OWNERS: IrqSafeMutex<BTreeMap<usize, String>> = IrqSafeMutex::new(BTreeMap::new());
+
+/// Unmap the boot stack's guard page and set up [`STACK_WINDOW`].
+///
+/// # Safety
+/// Once: claiming [`STACK_WINDOW`] again would throw away every stack mapped in it. Before
+/// the other harts start or any address space is made, since only this hart's TLB and the
+/// kernel's own root table see the window replace the direct map there. No device may be
+/// in use through that gigabyte of the direct map.
+pub unsafe fn init(hwinfo: &HwInfo) {
+    if let Err(err) = unmap_kernel(VirtAddr::new(boot_guard().start)) {
+        log::warn!("unmapping the boot stack's guard page: {}", err);
+    }
+    if memory_map::claim_window(&STACK_WINDOW, hwinfo, "kernel stacks") {
+        WINDOW_READY.store(true, Ordering::Release);
+    }
+}
+
+fn boot_guard() -> Range<u64> {
+    let start = &BOOT_GUARD as *const GuardPage as u64;
+    start..start + PAGE_SIZE
+}
+
+/// A kernel stack. Dropping it unmaps and frees it, so nothing can still be running on it.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: u64,
+    top: u64,
+    /// `None` for one from the heap.
+    slot: Option<usize>,
+}
+
+impl KernelStack {
+    /// `size` bytes of stack, rounded up to pages. `owner` is who gets blamed if it
+    /// overflows.
+    ///
+    /// # Panics
+    /// If `size` is over [`MAX_STACK_SIZE`].
+    pub fn new(size: u64, owner: String) -> Result<KernelStack, MapError> {
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        assert!(size <= MAX_STACK_SIZE, "{} byte kernel stack", size);
+        if !WINDOW_READY.load(Ordering::Acquire) {
+            let bottom = unsafe { alloc::alloc::alloc(heap_layout(size)) } as u64;
+            if bottom == 0 {
+                return Err(MapError::OutOfMemory);
+            }
+            return Ok(KernelStack {
+                bottom,
+                top: bottom + size,
+                slot: None,
+            });
+        }
+
+        let mut owners = OWNERS.lock();
+        let slot = (0..SLOTS)
+            .find(|slot| !owners.contains_key(slot))
+            .ok_or(MapError::OutOfMemory)?;
+        let top = STACK_WINDOW.start.as_u64() + (slot as u64 + 1) * SLOT_SIZE;
+        let bottom = top - size;
+        owners.insert(slot, owner);
+        for va in (bottom..top).step_by(PAGE_SIZE as usize) {
+            if let Err(err) = unsafe { map_page(va) } {
+                let frames = unsafe { unmap(bottom..va) };
+                drop(owners);
+                unsafe { release(bottom..va, frames) };
+                OWNERS.lock().remove(&slot);
+                return Err(err);
+            }
+        }
+        Ok(KernelStack {
+            bottom,
+            top,
+            slot: Some(slot),
+        })
+    }
+
+    /// Where `sp` starts. 16 byte aligned.
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        match self.slot {
+            None => unsafe {
+                alloc::alloc::dealloc(self.bottom as *mut u8, heap_layout(self.top - self.bottom))
+            },
+            Some(slot) => {
+                let frames = {
+                    let _owners = OWNERS.lock();
+                    unsafe { unmap(self.bottom..self.top) }
+                };
+                // The slot's still taken, so it isn't mapped again until every hart has
+                // forgotten the old pages.
+                unsafe { release(self.bottom..self.top, frames) };
+                OWNERS.lock().remove(&slot);
+            }
+        }
+    }
+}
+
+/// Map a new page at `va`.
+///
+/// # Safety
+/// `OWNERS` is held, and `va` is in a slot being made.
+unsafe fn map_page(va: u64) -> Result<(), MapError> {
+    let frame = alloc_frame().ok_or(MapError::OutOfMemory)?;
+    let flags = EntryFlags::READ | EntryFlags::WRITE | EntryFlags::GLOBAL;
+    if let Err(err) = map_kernel(VirtAddr::new(va), frame, flags) {
+        free_frame(frame);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn heap_layout(size: u64) -> Layout {
+    Layout::from_size_align(size as usize, PAGE_SIZE as usize).unwrap()
+}
+
+/// Unmap the pages in `range`, returning their frames for [`release`].
+///
+/// # Safety
+/// `OWNERS` is held, and nothing uses them any more.
+unsafe fn unmap(range: Range<u64>) -> Vec<PhysAddr> {
+    let mut frames = Vec::new();
+    for va in range.step_by(PAGE_SIZE as usize) {
+        if let Ok(Some(entry)) = unmap_kernel(VirtAddr::new(va)) {
+            frames.push(entry.address());
+        }
+    }
+    frames
+}
+
+/// Have every hart forget `range`, then free the `frames` that were there.
+///
+/// # Safety
+/// `OWNERS` isn't held: a hart spinning on it with interrupts off can't answer the
+/// shootdown. The slot `range` is in stays taken until this returns.
+unsafe fn release(range: Range<u64>, frames: Vec<PhysAddr>) {
+    tlb::shootdown(range, None);
+    for frame in frames {
+        free_frame(frame);
+    }
+}
+
+/// Whose stack `addr` is in the guard of, if any. For page faults.
+pub fn overflowed(addr: u64) -> Option<String> {
+    if boot_guard().contains(&addr) {
+        return Some(String::from("the boot stack"));
+    }
+    let slot = match VirtAddr::try_new(addr) {
+        Some(va) if STACK_WINDOW.contains(&va) => ((va - STACK_WINDOW.start) / SLOT_SIZE) as usize,
+        _ => return None,
+    };
+    // The fault may have been with it held.
+    let owners = match OWNERS.try_lock() {
+        Some(owners) => owners,
+        None => return Some(format!("stack slot {}", slot)),
+    };
+    owners.get(&slot).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn guard_under_stack() {
+        let stack = KernelStack::new(8 * 1024, String::from("test")).unwrap();
+        assert_eq!(stack.top() - stack.bottom(), 8 * 1024);
+        assert_eq!(stack.top() % 16, 0);
+        unsafe { ((stack.top() - 8) as *mut u64).write_volatile(1) };
+        if stack.slot.is_some() {
+            assert_eq!(overflowed(stack.bottom() - 8).as_deref(), Some("test"));
+        }
+        assert_eq!(overflowed(0), None);
+        assert!(overflowed(boot_guard().start).is_some());
+    }
+}
@@ -0,0 +1,226 @@
+//! [`Rcu`]: epoch based reclamation, for things read much more often than they change.
+//!
+//! An `Rcu` points at the current version of its value. [`read`](Rcu::read) takes it
+//! without a lock, so it never waits for a writer and is fine in interrupt handlers.
+//! [`update`](Rcu::update) makes a new version from the old one and swaps it in. Readers
+//! already looking at the old one carry on with it, and it's freed once every online hart
+//! has been seen with no readers since: at the timer tick, or on the way back to U-mode.
+//! That's a second or so. The freeing is done from the [`workqueue`](crate::workqueue).
+//!
+//! A reader counts against the hart it started on until its guard is dropped, so don't hold
+//! one across anything that might switch to a process. Guards aren't `Send`, so a task
+//! can't hold one across an `await`. Holding one a long time keeps old versions around.
+
+use alloc::boxed::Box;
+use core::{
+    fmt::{self, Debug, Formatter},
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    hart_local::{online_harts, try_current_hart},
+    prelude::*,
+    sbi::hart::HartId,
+    smp::MAX_HARTS,
+    workqueue::Work,
+};
+
+/// Bumped by every old version retired.
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+/// Guards each hart has out.
+static READERS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+/// The epoch each hart last saw with no readers.
+static SEEN: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+/// Old versions, and the epoch they were retired in.
+static GARBAGE: Mutex<Vec<(u64, Box<dyn Send>)>> = Mutex::new(Vec::new());
+/// How many are in [`GARBAGE`].
+static RETIRED: AtomicUsize = AtomicUsize::new(0);
+static RECLAIM: Work = Work::new("epoch reclaim", reclaim);
+
+fn this_hart() -> usize {
+    let hart = try_current_hart().map_or(0, |hart| hart.0);
+    assert!(hart < MAX_HARTS, "no epoch for hart {}", hart);
+    hart
+}
+
+/// A value that's read without locking. See the [module docs](self).
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    /// Writers take turns.
+    writer: Mutex<()>,
+}
+
+// Like an `RwLock<Box<T>>`.
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+/// A version of an [`Rcu`]'s value. Later updates don't change it.
+pub struct RcuGuard<'a, T> {
+    value: &'a T,
+    hart: usize,
+    /// Counted against one hart, so it stays there.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    pub fn new(value: T) -> Self {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current version. Never waits.
+    pub fn read(&self) -> RcuGuard<'_, T> {
+        let hart = this_hart();
+        READERS[hart].fetch_add(1, Ordering::SeqCst);
+        // Not freed until this hart's seen with no readers, which is after the guard's gone.
+        let value = unsafe { &*self.current.load(Ordering::SeqCst) };
+        RcuGuard {
+            value,
+            hart,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Replace the value with what `f` makes of it. Not from interrupt handlers.
+    pub fn update(&self, f: impl FnOnce(&T) -> T) {
+        let _ = self.try_update(|value| Ok::<_, ()>(f(value)));
+    }
+
+    /// [`update`](Self::update), unless `f` fails, when the value's left alone.
+    pub fn try_update<E>(&self, f: impl FnOnce(&T) -> Result<T, E>) -> Result<(), E> {
+        let _writer = self.writer.lock();
+        let old = self.current.load(Ordering::SeqCst);
+        // Only writers free it, and it's our turn.
+        let new = f(unsafe { &*old })?;
+        self.current
+            .store(Box::into_raw(Box::new(new)), Ordering::SeqCst);
+        retire(unsafe { Box::from_raw(old) });
+        Ok(())
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // Guards borrow it, so there aren't any.
+        drop(unsafe { Box::from_raw(*self.current.get_mut()) });
+    }
+}
+
+impl<T: Debug + Send + Sync + 'static> Debug for Rcu<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.read(), f)
+    }
+}
+
+impl<T> Deref for RcuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuGuard<'_, T> {
+    fn drop(&mut self) {
+        READERS[self.hart].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Free `garbage` once no reader can still have it.
+fn retire(garbage: Box<dyn Send>) {
+    RECLAIM.register();
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    GARBAGE.lock().push((epoch, garbage));
+    RETIRED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note the epoch if this hart has no readers.
+fn see() {
+    let hart = this_hart();
+    if READERS[hart].load(Ordering::SeqCst) == 0 {
+        SEEN[hart].store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+}
+
+/// This hart isn't in the middle of reading anything, unless it has a guard out. From the
+/// timer tick and on the way back to U-mode.
+pub fn quiescent() {
+    see();
+    if RETIRED.load(Ordering::Relaxed) > 0 {
+        RECLAIM.schedule();
+    }
+}
+
+/// Free the old versions every online hart has finished with.
+fn reclaim() {
+    see();
+    let online = online_harts();
+    let safe = (0..MAX_HARTS)
+        .filter(|&hart| online.contains(HartId(hart)))
+        .map(|hart| SEEN[hart].load(Ordering::SeqCst))
+        .min()
+        .unwrap_or(0);
+    let freed: Vec<_> = {
+        let mut garbage = GARBAGE.lock();
+        let (freed, kept) = core::mem::take(&mut *garbage)
+            .into_iter()
+            .partition(|(epoch, _)| *epoch <= safe);
+        *garbage = kept;
+        freed
+    };
+    RETIRED.fetch_sub(freed.len(), Ordering::Relaxed);
+    // Outside the lock, in case dropping them retires more.
+    drop(freed);
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::{sync::atomic::AtomicBool, time::Duration};
+
+    use super::*;
+    use crate::{
+        task,
+        time::{self, Instant},
+    };
+
+    /// Sets its flag when it's dropped.
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test_case]
+    fn old_versions_outlive_readers() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let rcu = Rcu::new((1, Dropped(dropped.clone())));
+        let old = rcu.read();
+        rcu.update(|_| (2, Dropped(Arc::new(AtomicBool::new(false)))));
+        assert_eq!(old.0, 1);
+        assert_eq!(rcu.read().0, 2);
+        assert_eq!(rcu.try_update(|_| Err("no")), Err("no"));
+        assert_eq!(rcu.read().0, 2);
+
+        quiescent();
+        reclaim();
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        // Every hart has to be seen first, which takes up to a tick.
+        drop(old);
+        let start = Instant::now();
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(5), "never freed");
+            reclaim();
+            task::block_on(time::sleep_async(Duration::from_millis(10)));
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Locks and one-time initialization for the kernel.
+//!
+//! - [`IrqSafeMutex`]: a spinlock that's safe to share with interrupt handlers.
+//! - [`RwLock`]: many readers or one writer. Writers go first. [`IrqSafeRwLock`] keeps
+//!   interrupts off while it's held.
+//! - [`Once`] and [`Lazy`]: values set up once, either by whoever gets there first or on
+//!   first use.
+//! - [`Rcu`]: read without locking, for things that rarely change. Old versions are freed
+//!   once no hart can still be reading them.
+//! - [`WaitQueue`]: for waiting on a condition without holding a lock.
+//! - [`Mutex`], [`Semaphore`] and [`CondVar`]: sleep on a wait queue instead of spinning,
+//!   for anything held across a wait. Not for interrupt handlers.
+
+pub mod epoch;
+mod mutex;
+mod once;
+mod rwlock;
+mod sleeping;
+mod wait_queue;
+
+pub use epoch::Rcu;
+pub use mutex::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use once::{Lazy, Once};
+#[allow(unused_imports)]
+pub use rwlock::{IrqSafeRwLock, RwLock};
+#[allow(unused_imports)]
+pub use rwlock::{RwLockReadGuard, RwLockWriteGuard};
+#[allow(unused_imports)]
+pub use sleeping::CondVar;
+pub use sleeping::{Mutex, MutexGuard, Semaphore};
+#[allow(unused_imports)]
+pub use wait_queue::{
+    nice_priority, priority, WaitQueue, HIGH_PRIORITY, KERNEL_PRIORITY, PROCESS_PRIORITY,
+};
@@ -0,0 +1,222 @@
+//! [`IrqSafeMutex`], a spinlock that keeps interrupts off on its hart while it's held, so
+//! an interrupt handler that takes the same lock can't deadlock against the code it
+//! interrupted. Debug builds also remember which hart took the lock and where, and panic
+//! on a hart trying to take a lock it already holds, instead of spinning forever.
+
+use core::{
+    fmt::{self, Debug, Formatter},
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    panic::Location,
+};
+#[cfg(debug_assertions)]
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::isr::{disable_interrupts, restore_interrupts};
+
+pub struct IrqSafeMutex<T: ?Sized> {
+    /// One more than the id of the hart holding the lock. 0 if it's free, or the holder
+    /// didn't know its id.
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    /// Where the lock was taken.
+    #[cfg(debug_assertions)]
+    locked_at: AtomicPtr<Location<'static>>,
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    #[cfg(debug_assertions)]
+    mutex: &'a IrqSafeMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// Whether interrupts were on before the lock was taken.
+    interrupts: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            locked_at: AtomicPtr::new(ptr::null_mut()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Turn off interrupts and take the lock, spinning until it's free.
+    ///
+    /// In debug builds, panics if this hart already holds it.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        #[cfg(debug_assertions)]
+        self.check_recursion();
+        let guard = self.inner.lock();
+        self.acquired(guard, interrupts)
+    }
+
+    /// Take the lock if it's free.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts = disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.acquired(guard, interrupts)),
+            None => {
+                restore_interrupts(interrupts);
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Release the lock, whoever holds it. Their interrupt state isn't restored.
+    ///
+    /// # Safety
+    /// For the panic path, once the holder will never run again.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        self.inner.force_unlock();
+    }
+
+    /// Which hart holds the lock, and where it took it. Only known in debug builds.
+    pub fn holder(&self) -> Option<(usize, &'static Location<'static>)> {
+        #[cfg(debug_assertions)]
+        {
+            let owner = self.owner.load(Ordering::Relaxed);
+            let at = self.locked_at.load(Ordering::Relaxed);
+            if owner != 0 && !at.is_null() && self.inner.is_locked() {
+                return Some((owner - 1, unsafe { &*at }));
+            }
+        }
+        None
+    }
+
+    #[track_caller]
+    fn acquired<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        interrupts: bool,
+    ) -> IrqSafeMutexGuard<'a, T> {
+        #[cfg(debug_assertions)]
+        {
+            self.owner.store(this_hart(), Ordering::Relaxed);
+            let at: &'static Location<'static> = Location::caller();
+            self.locked_at
+                .store(at as *const _ as *mut _, Ordering::Relaxed);
+        }
+        IrqSafeMutexGuard {
+            #[cfg(debug_assertions)]
+            mutex: self,
+            guard: ManuallyDrop::new(guard),
+            interrupts,
+        }
+    }
+
+    /// With interrupts off, the only way to find our own hart holding the lock is by
+    /// taking it again further down the stack.
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_recursion(&self) {
+        let me = this_hart();
+        if me != 0 && self.owner.load(Ordering::Relaxed) == me {
+            if let Some((_, at)) = self.holder() {
+                panic!("lock taken again on the same hart. It was taken at {}", at);
+            }
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+fn this_hart() -> usize {
+    crate::hart_local::try_current_hart().map_or(0, |hart| hart.0 + 1)
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSafeMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeMutex<T> {}
+
+impl<T: ?Sized + Debug> Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.inner.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqSafeMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("IrqSafeMutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: Default> Default for IrqSafeMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized + Debug> Debug for IrqSafeMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.mutex.owner.store(0, Ordering::Relaxed);
+        // Unlock before interrupts can come back on.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore_interrupts(self.interrupts);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hart_local;
+
+    #[test_case]
+    fn irq_safe_mutex() {
+        let mutex = IrqSafeMutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(!riscv::register::sstatus::read().sie());
+            assert!(mutex.try_lock().is_none());
+            #[cfg(debug_assertions)]
+            assert_eq!(
+                mutex.holder().map(|(hart, _)| hart),
+                Some(hart_local::current_hart().0)
+            );
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(mutex.holder(), None);
+        assert_eq!(mutex.into_inner(), 2);
+    }
+}
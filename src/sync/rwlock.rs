@@ -0,0 +1,235 @@
+//! [`RwLock`] and [`IrqSafeRwLock`].
+//!
+//! Writers go first: once one is waiting, new readers wait behind it, so a steady stream of
+//! readers can't starve it.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Debug, Formatter},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::isr::{disable_interrupts, restore_interrupts};
+
+/// A writer holds the lock.
+const WRITER: usize = 1;
+/// A writer is waiting for the readers to finish.
+const WAITING: usize = 2;
+/// One reader. The rest of the state counts them.
+const READER: usize = 4;
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    /// Whether to turn interrupts back on after, for [`IrqSafeRwLock`].
+    interrupts: bool,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    interrupts: bool,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Share the lock with other readers, once no writer has it or wants it.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockReadGuard {
+                lock: self,
+                interrupts: false,
+            })
+    }
+
+    /// Take the lock for ourselves, once the readers have finished. New readers wait
+    /// meanwhile.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WAITING == 0 {
+                self.state.fetch_or(WAITING, Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WAITING != 0 {
+            return None;
+        }
+        // Taking it clears WAITING. Any other writer still waiting sets it again.
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard {
+                lock: self,
+                interrupts: false,
+            })
+    }
+
+    pub fn readers(&self) -> usize {
+        self.state.load(Ordering::Relaxed) / READER
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for RwLock<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.write_str("RwLock { <locked> }"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        restore_interrupts(self.interrupts);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        // Leaves WAITING alone, for the next writer.
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        restore_interrupts(self.interrupts);
+    }
+}
+
+/// An [`RwLock`] that keeps interrupts off on its hart while it's held, for data that
+/// interrupt handlers use too.
+#[derive(Default)]
+pub struct IrqSafeRwLock<T: ?Sized> {
+    inner: RwLock<T>,
+}
+
+impl<T> IrqSafeRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeRwLock {
+            inner: RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeRwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        let mut guard = self.inner.read();
+        guard.interrupts = interrupts;
+        guard
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        let mut guard = self.inner.write();
+        guard.interrupts = interrupts;
+        guard
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for IrqSafeRwLock<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn rwlock_readers_and_writers() {
+        let lock = RwLock::new(1);
+        {
+            let a = lock.read();
+            let b = lock.read();
+            assert_eq!(*a + *b, 2);
+            assert_eq!(lock.readers(), 2);
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let mut writer = lock.write();
+            *writer = 2;
+            assert!(lock.is_write_locked());
+            assert!(lock.try_read().is_none());
+        }
+        // A waiting writer keeps new readers out.
+        let reader = lock.read();
+        lock.state.fetch_or(WAITING, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        assert_eq!(*lock.try_write().unwrap(), 2);
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,309 @@
+//! [`Mutex`], [`Semaphore`] and [`CondVar`], which sleep on a [`WaitQueue`] instead of
+//! spinning.
+//!
+//! Each has a blocking method, which waits in [`task::block_on`] so the hart runs other
+//! tasks or idles meanwhile, and an `async` one for tasks. Neither is for interrupt
+//! handlers, which can't wait. Wakeups go to the highest [`priority`](super::priority)
+//! waiter first. A task holding a [`Mutex`] inherits the priority of whoever waits for it,
+//! until it lets go of that one.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Debug, Formatter},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::task::{self, executor::TaskRef};
+
+use super::{priority, IrqSafeMutex, WaitQueue};
+
+/// A lock that sleeps while someone else has it. For things held a long time, like a
+/// device in the middle of a transfer.
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    /// The task that has it, if it was taken by one.
+    holder: IrqSafeMutex<Option<TaskRef>>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            holder: IrqSafeMutex::new(None),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Take the lock, sleeping until it's free.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.try_lock() {
+            Some(guard) => guard,
+            None => task::block_on(self.lock_async()),
+        }
+    }
+
+    pub async fn lock_async(&self) -> MutexGuard<'_, T> {
+        self.waiters
+            .wait_until(|| {
+                let guard = self.try_lock();
+                if guard.is_none() {
+                    self.lend_priority();
+                }
+                guard
+            })
+            .await
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        *self.holder.lock() = task::executor::current();
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Boost the task holding it to our priority, so it isn't stuck behind tasks that
+    /// are less important than us.
+    fn lend_priority(&self) {
+        if let Some(holder) = &*self.holder.lock() {
+            holder.boost(self.id(), priority());
+        }
+    }
+
+    /// Which lock this is, for what it lent its holder.
+    fn id(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn unlock(&self) {
+        if let Some(holder) = self.holder.lock().take() {
+            holder.unboost(self.id());
+        }
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for Mutex<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_tuple("Mutex").field(&&*guard).finish(),
+            None => f.write_str("Mutex(<locked>)"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A count of permits. [`acquire`](Self::acquire) takes one, sleeping until there is one,
+/// and [`release`](Self::release) gives one back.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            task::block_on(self.acquire_async());
+        }
+    }
+
+    pub async fn acquire_async(&self) {
+        self.waiters
+            .wait_until(|| self.try_acquire().then_some(()))
+            .await
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Fine from interrupt handlers.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+/// Waits for a condition on data behind a [`Mutex`]. Wakeups may be spurious, so check
+/// the condition again, or use [`wait_while`](Self::wait_while).
+pub struct CondVar {
+    /// Bumped by every notify, so a waiter can tell it's been notified since it let go of
+    /// the lock.
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        CondVar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Let go of `guard`, sleep until notified, and take the lock again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        task::block_on(self.wait_async(guard))
+    }
+
+    pub async fn wait_async<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| (self.generation.load(Ordering::Acquire) != generation).then_some(()))
+            .await;
+        mutex.lock_async().await
+    }
+
+    /// Wait until `condition` is false, checking it with the lock held.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::task::Poll;
+
+    use super::*;
+
+    #[test_case]
+    fn sleeping_locks() {
+        let mutex = Mutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.try_lock().is_none());
+        }
+        assert_eq!(*mutex.lock(), 2);
+
+        let semaphore = Semaphore::new(1);
+        semaphore.acquire();
+        assert!(!semaphore.try_acquire());
+        semaphore.release();
+        assert_eq!(semaphore.available(), 1);
+
+        // A task sets the flag while the test sleeps on the condvar.
+        let shared = Arc::new((Mutex::new(false), CondVar::new()));
+        let other = shared.clone();
+        task::spawn(async move {
+            *other.0.lock_async().await = true;
+            other.1.notify_all();
+        });
+        let (flag, ready) = &*shared;
+        let guard = ready.wait_while(flag.lock(), |set| !*set);
+        assert!(*guard);
+        drop(guard);
+
+        // A low priority task holding a lock we want is boosted to our priority, and
+        // lets go once it sees it has been.
+        let mutex = Arc::new(Mutex::new(()));
+        let boosted = Arc::new(AtomicUsize::new(0));
+        {
+            let (mutex, boosted) = (mutex.clone(), boosted.clone());
+            task::spawn_with_priority(0, async move {
+                let _guard = mutex.lock_async().await;
+                core::future::poll_fn(|cx| {
+                    let priority = task::executor::current().unwrap().priority();
+                    boosted.store(priority as usize, Ordering::Relaxed);
+                    if priority > 0 {
+                        return Poll::Ready(());
+                    }
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+            });
+        }
+        task::block_on(core::future::poll_fn(|cx| {
+            if mutex.is_locked() {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }));
+        drop(mutex.lock());
+        assert_eq!(boosted.load(Ordering::Relaxed), priority() as usize);
+    }
+}
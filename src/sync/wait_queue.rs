@@ -0,0 +1,160 @@
+//! [`WaitQueue`].
+
+use core::{future::poll_fn, future::Future, task::Poll, task::Waker};
+
+use alloc::vec::Vec;
+
+use super::IrqSafeMutex;
+
+/// Interrupt driven work others wait on, like reading the console.
+pub const HIGH_PRIORITY: u8 = 60;
+/// Kernel code that isn't running for a process: drivers, the console, the kernel shell.
+pub const KERNEL_PRIORITY: u8 = 50;
+/// A process at nice 0. See [`nice_priority`].
+pub const PROCESS_PRIORITY: u8 = 19;
+
+/// A process's priority for its nice value: 39 at -20 down to 0 at 19, all below the
+/// kernel's.
+pub fn nice_priority(nice: i8) -> u8 {
+    (PROCESS_PRIORITY as i8 - nice.clamp(-20, 19)) as u8
+}
+
+/// Who goes first when [`WaitQueue::wake_one`] has a choice. Higher first. A task's own
+/// priority, or else the current process's.
+pub fn priority() -> u8 {
+    if let Some(task) = crate::task::executor::current() {
+        return task.priority();
+    }
+    match crate::process::current() {
+        Some(process) => process.priority(),
+        None => KERNEL_PRIORITY,
+    }
+}
+
+/// Somewhere to wait for a condition that someone else will make true. Whoever changes
+/// it calls [`wake_all`](Self::wake_all), or [`wake_one`](Self::wake_one) if only one
+/// waiter can have it. Waking is fine from interrupt handlers.
+pub struct WaitQueue {
+    /// In the order they started waiting.
+    waiters: IrqSafeMutex<Vec<Waiter>>,
+}
+
+struct Waiter {
+    waker: Waker,
+    priority: u8,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSafeMutex::new(Vec::new()),
+        }
+    }
+
+    /// Wait until `ready` returns something. It's checked again after every wake, which
+    /// may be spurious.
+    pub fn wait_until<'a, T>(
+        &'a self,
+        mut ready: impl FnMut() -> Option<T> + 'a,
+    ) -> impl Future<Output = T> + 'a {
+        poll_fn(move |cx| {
+            if let Some(value) = ready() {
+                return Poll::Ready(value);
+            }
+            self.register(cx.waker());
+            // It may have become ready while registering.
+            match ready() {
+                Some(value) => Poll::Ready(value),
+                None => Poll::Pending,
+            }
+        })
+    }
+
+    /// Wake `waker` on the next wake that gets to it, at the current [`priority`]. A waker
+    /// already waiting keeps its place.
+    pub fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.iter().any(|waiter| waiter.waker.will_wake(waker)) {
+            waiters.push(Waiter {
+                waker: waker.clone(),
+                priority: priority(),
+            });
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for waiter in waiters {
+            waiter.waker.wake();
+        }
+    }
+
+    /// Wake the highest priority waiter, the longest waiting of those. Returns false if
+    /// there wasn't one.
+    ///
+    /// A waiter that gives up without checking its condition loses the wake, so whatever
+    /// waits with this has to be polled to completion.
+    pub fn wake_one(&self) -> bool {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            let best = waiters
+                .iter()
+                .enumerate()
+                .max_by_key(|(index, waiter)| (waiter.priority, core::cmp::Reverse(*index)))
+                .map(|(index, _)| index);
+            best.map(|index| waiters.remove(index))
+        };
+        match waiter {
+            Some(waiter) => {
+                waiter.waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many are waiting.
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use alloc::sync::Arc;
+
+    use super::*;
+    use crate::task;
+
+    #[test_case]
+    fn wait_queue_wakes() {
+        let queue = Arc::new(WaitQueue::new());
+        let done = Arc::new(AtomicBool::new(false));
+        {
+            let (queue, done) = (queue.clone(), done.clone());
+            task::spawn(async move {
+                done.store(true, Ordering::Release);
+                queue.wake_all();
+            });
+        }
+        task::block_on(queue.wait_until(|| done.load(Ordering::Acquire).then_some(())));
+        assert!(queue.is_empty());
+
+        assert_eq!(nice_priority(0), PROCESS_PRIORITY);
+        assert_eq!(nice_priority(-100), 39);
+        assert_eq!(nice_priority(19), 0);
+        assert!(nice_priority(-20) < KERNEL_PRIORITY);
+    }
+}
@@ -0,0 +1,691 @@
+//! System calls from U-mode.
+//!
+//! Same convention and numbers as `user/src/syscall.rs`: number in `a7`, arguments in
+//! `a0`-`a5`, result in `a0`. Errors are returned as `-errno`.
+
+use core::{future::poll_fn, task::Poll, time::Duration};
+
+use alloc::{format, sync::Arc};
+
+use crate::{
+    devices::ProbeError,
+    error::{self, KernelError},
+    fs::{self, devfs::PollFlags, FsError, NodeKind},
+    io,
+    pagetable::{EntryFlags, MapError, PAGE_SIZE},
+    prelude::*,
+    process::{
+        self,
+        elf::ElfError,
+        fd::{FileError, OpenFile, SeekFrom, MAX_FDS},
+        programs, AddressSpace, Fault, Pid, Process, TrapFrame, WaitError,
+    },
+    task, time,
+};
+
+pub const SYS_IOCTL: u64 = 29;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_READV: u64 = 65;
+pub const SYS_WRITEV: u64 = 66;
+pub const SYS_PPOLL: u64 = 73;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_NANOSLEEP: u64 = 101;
+pub const SYS_SETPRIORITY: u64 = 140;
+pub const SYS_GETPRIORITY: u64 = 141;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_EXECVE: u64 = 221;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_WAIT4: u64 = 260;
+/// Not Linux's: start a program as a new child process, without `fork`.
+pub const SYS_SPAWN: u64 = 1000;
+
+/// Most bytes one `read` or `write` moves. Anything more is a short read or write.
+const MAX_IO: usize = 4096;
+/// Most `struct iovec`s to one `readv` or `writev`.
+const IOV_MAX: u64 = 1024;
+/// Longest path, including the nul.
+const MAX_PATH: usize = 4096;
+/// Longest single argument or environment string to `execve`, including the nul.
+const MAX_ARG_LEN: usize = 4096;
+/// Most arguments, or environment strings, to `execve`.
+const MAX_ARGS: usize = 256;
+
+/// `openat` relative to the working directory, which is always `/`.
+const AT_FDCWD: i64 = -100;
+
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_DIRECTORY: u64 = 0o200000;
+
+/// `revents` for a descriptor that isn't open.
+const POLLNVAL: u16 = 0x20;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+const PROT_READ: u64 = 0x1;
+const PROT_WRITE: u64 = 0x2;
+const PROT_EXEC: u64 = 0x4;
+
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// `wait4` returns straight away if no child has exited.
+const WNOHANG: u64 = 1;
+
+/// `setpriority` and `getpriority` of one process. There are no groups or users.
+const PRIO_PROCESS: u64 = 0;
+
+/// Error numbers. Same values as Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    EOPNOTSUPP = 95,
+}
+
+impl From<Fault> for Errno {
+    fn from(_: Fault) -> Self {
+        Errno::EFAULT
+    }
+}
+
+impl From<MapError> for Errno {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::Misaligned => Errno::EINVAL,
+            MapError::OutOfRange | MapError::OutOfMemory => Errno::ENOMEM,
+            MapError::AlreadyMapped => Errno::EEXIST,
+        }
+    }
+}
+
+impl From<ElfError> for Errno {
+    fn from(err: ElfError) -> Self {
+        match err {
+            ElfError::NotElf
+            | ElfError::Unsupported
+            | ElfError::Truncated
+            | ElfError::BadSegment => Errno::ENOEXEC,
+            ElfError::Map(err) => err.into(),
+            ElfError::ArgsTooLong => Errno::E2BIG,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::NotEmpty => Errno::ENOTEMPTY,
+            FsError::InvalidPath => Errno::EINVAL,
+            FsError::NameTooLong => Errno::ENAMETOOLONG,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::FileTooLarge => Errno::EFBIG,
+            FsError::CrossDevice => Errno::EXDEV,
+            FsError::Busy => Errno::EBUSY,
+            FsError::Corrupt | FsError::Io(_) => Errno::EIO,
+            FsError::Unsupported => Errno::EOPNOTSUPP,
+            FsError::Interrupted => Errno::EINTR,
+            FsError::BadAddress => Errno::EFAULT,
+        }
+    }
+}
+
+impl From<KernelError> for Errno {
+    fn from(err: KernelError) -> Self {
+        match err {
+            KernelError::OutOfMemory => Errno::ENOMEM,
+            KernelError::Io(err) => match err.kind() {
+                io::ErrorKind::InvalidInput => Errno::EINVAL,
+                io::ErrorKind::Interrupted => Errno::EINTR,
+                io::ErrorKind::NotFound => Errno::ENOENT,
+                _ => Errno::EIO,
+            },
+            KernelError::Fs(err) => err.into(),
+            KernelError::Map(err) => err.into(),
+            KernelError::Probe(ProbeError::NoDevice) => Errno::ENODEV,
+            KernelError::Sbi(_) | KernelError::Dtb(_) | KernelError::Probe(_) => Errno::EIO,
+        }
+    }
+}
+
+impl From<WaitError> for Errno {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::NoChild => Errno::ECHILD,
+        }
+    }
+}
+
+impl From<FileError> for Errno {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::Fs(err) => err.into(),
+            FileError::BadMode => Errno::EBADF,
+            FileError::NotSeekable => Errno::ESPIPE,
+            FileError::InvalidOffset => Errno::EINVAL,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Handle the `ecall` that trapped with `frame`. `frame.pc` should already be past it.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let number = frame.regs[TrapFrame::A7];
+    let mut args = [0; 6];
+    args.copy_from_slice(&frame.regs[TrapFrame::A0..TrapFrame::A0 + 6]);
+
+    let result = match number {
+        SYS_OPENAT => openat(args[0] as i64, args[1], args[2]),
+        SYS_CLOSE => close(args[0]),
+        SYS_LSEEK => lseek(args[0], args[1] as i64, args[2]),
+        SYS_READ => read(args[0], args[1], args[2] as usize),
+        SYS_WRITE => write(args[0], args[1], args[2] as usize),
+        SYS_READV => readv(args[0], args[1], args[2]),
+        SYS_WRITEV => writev(args[0], args[1], args[2]),
+        SYS_IOCTL => ioctl(args[0], args[1], args[2]),
+        SYS_PPOLL => ppoll(args[0], args[1], args[2]),
+        SYS_EXIT => process::exit(args[0] as i32),
+        SYS_NANOSLEEP => nanosleep(args[0]),
+        SYS_SETPRIORITY => setpriority(args[0], args[1], args[2] as i64),
+        SYS_GETPRIORITY => getpriority(args[0], args[1]),
+        SYS_GETPID => getpid(),
+        SYS_BRK => brk(args[0]),
+        SYS_MUNMAP => munmap(args[0], args[1]),
+        SYS_EXECVE => execve(frame, args[0], args[1], args[2]),
+        SYS_MMAP => mmap(args[0], args[1], args[2], args[3]),
+        SYS_WAIT4 => wait4(args[0] as i64, args[1], args[2]),
+        SYS_SPAWN => spawn(args[0], args[1], args[2]),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    frame.regs[TrapFrame::A0] = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+}
+
+fn file(fd: u64) -> Result<Arc<OpenFile>, Errno> {
+    process::current()
+        .unwrap()
+        .files()
+        .get(fd as usize)
+        .ok_or(Errno::EBADF)
+}
+
+/// Copy a nul terminated string out of user memory. `too_long` if it's `max` bytes or more.
+fn read_c_string(
+    memory: &mut AddressSpace,
+    va: u64,
+    max: usize,
+    too_long: Errno,
+) -> Result<Vec<u8>, Errno> {
+    let mut string = Vec::new();
+    let mut chunk = [0; 64];
+    loop {
+        // Don't read past the end of the page, the next one might not be mapped.
+        let addr = va.checked_add(string.len() as u64).ok_or(Errno::EFAULT)?;
+        let len = (chunk.len() as u64).min(PAGE_SIZE - addr % PAGE_SIZE);
+        let chunk = &mut chunk[..len as usize];
+        memory.copy_from_user(addr, chunk)?;
+        match chunk.iter().position(|&b| b == 0) {
+            Some(end) => {
+                string.extend_from_slice(&chunk[..end]);
+                return Ok(string);
+            }
+            None => string.extend_from_slice(chunk),
+        }
+        if string.len() >= max {
+            return Err(too_long);
+        }
+    }
+}
+
+fn read_path(va: u64) -> Result<String, Errno> {
+    let process = process::current().unwrap();
+    let path = read_c_string(&mut process.memory(), va, MAX_PATH, Errno::ENAMETOOLONG)?;
+    String::from_utf8(path).map_err(|_| Errno::EINVAL)
+}
+
+/// The working directory is always `/`.
+fn absolute(path: String) -> String {
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Read a null terminated array of string pointers, like `argv`. A null array is empty.
+fn read_strings(va: u64) -> Result<Vec<Vec<u8>>, Errno> {
+    read_string_array(va, None)
+}
+
+/// Read an array of string pointers that ends at a null, or after `count` of them.
+fn read_string_array(va: u64, count: Option<u64>) -> Result<Vec<Vec<u8>>, Errno> {
+    let process = process::current().unwrap();
+    let mut memory = process.memory();
+    let mut strings = Vec::new();
+    if va == 0 {
+        return Ok(strings);
+    }
+    loop {
+        if count == Some(strings.len() as u64) {
+            return Ok(strings);
+        }
+        let mut pointer = [0; 8];
+        let addr = va
+            .checked_add(strings.len() as u64 * 8)
+            .ok_or(Errno::EFAULT)?;
+        memory.copy_from_user(addr, &mut pointer)?;
+        match u64::from_le_bytes(pointer) {
+            0 => return Ok(strings),
+            _ if strings.len() == MAX_ARGS => return Err(Errno::E2BIG),
+            string => strings.push(read_c_string(
+                &mut memory,
+                string,
+                MAX_ARG_LEN,
+                Errno::E2BIG,
+            )?),
+        }
+    }
+}
+
+fn openat(dirfd: i64, path: u64, flags: u64) -> SyscallResult {
+    let path = read_path(path)?;
+    if !path.starts_with('/') && dirfd != AT_FDCWD {
+        return Err(Errno::EOPNOTSUPP);
+    }
+    let path = absolute(path);
+
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let node = match fs::lookup(&path) {
+        Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => return Err(Errno::EEXIST),
+        Ok(node) => node,
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => fs::create(&path, NodeKind::File)?,
+        Err(err) => return Err(err.into()),
+    };
+
+    let kind = node.metadata()?.kind;
+    if kind == NodeKind::Directory && writable {
+        return Err(Errno::EISDIR);
+    }
+    if kind != NodeKind::Directory && flags & O_DIRECTORY != 0 {
+        return Err(Errno::ENOTDIR);
+    }
+    if flags & O_TRUNC != 0 && writable {
+        node.truncate(0)?;
+    }
+
+    let file = OpenFile::node(path, node, readable, writable, flags & O_APPEND != 0);
+    let process = process::current().unwrap();
+    let fd = process.files().insert(file).ok_or(Errno::EMFILE)?;
+    Ok(fd as u64)
+}
+
+fn close(fd: u64) -> SyscallResult {
+    let process = process::current().unwrap();
+    let file = process.files().remove(fd as usize).ok_or(Errno::EBADF)?;
+    drop(file);
+    Ok(0)
+}
+
+fn lseek(fd: u64, offset: i64, whence: u64) -> SyscallResult {
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(file(fd)?.seek(pos)?)
+}
+
+fn read(fd: u64, buf: u64, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut data = error::try_zeroed(len.min(MAX_IO))?;
+    let n = file.read(&mut data)?;
+    process::current()
+        .unwrap()
+        .memory()
+        .copy_to_user(buf, &data[..n])?;
+    Ok(n as u64)
+}
+
+fn write(fd: u64, buf: u64, len: usize) -> SyscallResult {
+    let file = file(fd)?;
+    let mut data = error::try_zeroed(len.min(MAX_IO))?;
+    process::current()
+        .unwrap()
+        .memory()
+        .copy_from_user(buf, &mut data)?;
+    Ok(file.write(&data)? as u64)
+}
+
+/// Read an array of `struct iovec`, as `(base, len)` pairs.
+fn read_iovecs(va: u64, count: u64) -> Result<Vec<(u64, usize)>, Errno> {
+    if count > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut raw = error::try_zeroed(count as usize * 16)?;
+    process::current()
+        .unwrap()
+        .memory()
+        .copy_from_user(va, &mut raw)?;
+    Ok(raw
+        .chunks_exact(16)
+        .map(|iovec| {
+            let base = u64::from_le_bytes(iovec[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(iovec[8..].try_into().unwrap());
+            (base, len as usize)
+        })
+        .collect())
+}
+
+/// One `read` into the buffers in order. Short the same way `read` is.
+fn readv(fd: u64, iov: u64, count: u64) -> SyscallResult {
+    let file = file(fd)?;
+    let iovecs = read_iovecs(iov, count)?;
+    let len = iovecs
+        .iter()
+        .fold(0, |len: usize, &(_, n)| len.saturating_add(n));
+    let mut data = error::try_zeroed(len.min(MAX_IO))?;
+    let n = file.read(&mut data)?;
+
+    let process = process::current().unwrap();
+    let mut memory = process.memory();
+    let mut done = 0;
+    for (base, len) in iovecs {
+        if done == n {
+            break;
+        }
+        let chunk = len.min(n - done);
+        memory.copy_to_user(base, &data[done..done + chunk])?;
+        done += chunk;
+    }
+    Ok(n as u64)
+}
+
+/// The buffers gathered into one `write`, so they aren't split up by other writers.
+fn writev(fd: u64, iov: u64, count: u64) -> SyscallResult {
+    let file = file(fd)?;
+    let iovecs = read_iovecs(iov, count)?;
+    let mut data = Vec::new();
+    {
+        let process = process::current().unwrap();
+        let mut memory = process.memory();
+        for (base, len) in iovecs {
+            let start = data.len();
+            let len = len.min(MAX_IO - start);
+            data.resize(start + len, 0);
+            memory.copy_from_user(base, &mut data[start..])?;
+            if data.len() == MAX_IO {
+                break;
+            }
+        }
+    }
+    Ok(file.write(&data)? as u64)
+}
+
+/// Device specific requests. Anything that isn't a device is `ENOTTY`, like Linux.
+fn ioctl(fd: u64, request: u64, arg: u64) -> SyscallResult {
+    let file = file(fd)?;
+    let device = file.device().ok_or(Errno::ENOTTY)?;
+    match device.ioctl(request as u32, arg) {
+        Err(FsError::Unsupported) => Err(Errno::ENOTTY),
+        result => Ok(result?),
+    }
+}
+
+/// Wait for any of an array of `struct pollfd` to be ready, or for `timeout` if it isn't
+/// null. The signal mask is ignored, there being no signals. Files that aren't devices
+/// are always ready.
+fn ppoll(fds: u64, nfds: u64, timeout: u64) -> SyscallResult {
+    if nfds > MAX_FDS as u64 {
+        return Err(Errno::EINVAL);
+    }
+    let timeout = match timeout {
+        0 => None,
+        timeout => Some(read_timespec(timeout)?),
+    };
+    let process = process::current().unwrap();
+    let mut pollfds = vec![0; nfds as usize * 8];
+    process.memory().copy_from_user(fds, &mut pollfds)?;
+
+    // Negative descriptors are skipped.
+    let files: Vec<_> = pollfds
+        .chunks(8)
+        .map(|pollfd| {
+            let fd = i32::from_le_bytes(pollfd[..4].try_into().unwrap());
+            let events = u16::from_le_bytes(pollfd[4..6].try_into().unwrap());
+            let interest = PollFlags::from_bits_truncate(events);
+            (fd >= 0).then(|| (process.files().get(fd as usize), interest))
+        })
+        .collect();
+    let mut revents = vec![0; files.len()];
+    let ready = poll_fn(|cx| {
+        for (revents, entry) in revents.iter_mut().zip(&files) {
+            *revents = match entry {
+                None => 0,
+                Some((None, _)) => POLLNVAL,
+                Some((Some(file), interest)) => match file.device() {
+                    Some(device) => device.poll(*interest, cx.waker()).bits(),
+                    None => interest.bits(),
+                },
+            };
+        }
+        match revents.iter().filter(|&&revents| revents != 0).count() {
+            0 => Poll::Pending,
+            count => Poll::Ready(count),
+        }
+    });
+    let count = match timeout {
+        Some(timeout) => task::block_on(time::timeout(timeout, ready)).unwrap_or(0),
+        None => task::block_on(ready),
+    };
+
+    for (pollfd, revents) in pollfds.chunks_mut(8).zip(revents) {
+        pollfd[6..].copy_from_slice(&revents.to_le_bytes());
+    }
+    process.memory().copy_to_user(fds, &pollfds)?;
+    Ok(count as u64)
+}
+
+fn read_timespec(va: u64) -> Result<Duration, Errno> {
+    let mut timespec = [0; 16];
+    process::current()
+        .unwrap()
+        .memory()
+        .copy_from_user(va, &mut timespec)?;
+    let seconds = i64::from_le_bytes(timespec[..8].try_into().unwrap());
+    let nanos = i64::from_le_bytes(timespec[8..].try_into().unwrap());
+    if seconds < 0 || !(0..1_000_000_000).contains(&nanos) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(Duration::new(seconds as u64, nanos as u32))
+}
+
+fn nanosleep(request: u64) -> SyscallResult {
+    time::sleep(read_timespec(request)?);
+    Ok(0)
+}
+
+/// Set a process's nice value, clamped to -20..=19. Anyone can renice anyone.
+fn setpriority(which: u64, who: u64, nice: i64) -> SyscallResult {
+    priority_target(which, who)?.set_nice(nice.clamp(-20, 19) as i8);
+    Ok(0)
+}
+
+/// Like Linux's raw syscall, returns 20 minus the nice value, so it's never negative.
+fn getpriority(which: u64, who: u64) -> SyscallResult {
+    Ok((20 - priority_target(which, who)?.nice() as i64) as u64)
+}
+
+/// The process `who` names, or the caller for 0.
+fn priority_target(which: u64, who: u64) -> Result<Arc<Process>, Errno> {
+    if which != PRIO_PROCESS {
+        return Err(Errno::EINVAL);
+    }
+    match who {
+        0 => Ok(process::current().unwrap()),
+        who => u32::try_from(who)
+            .ok()
+            .and_then(|pid| process::find(Pid(pid)))
+            .ok_or(Errno::ESRCH),
+    }
+}
+
+fn getpid() -> SyscallResult {
+    Ok(process::current().unwrap().pid().0 as u64)
+}
+
+/// On success the process is running the new program, whose `a0` starts at 0.
+fn execve(frame: &mut TrapFrame, path: u64, argv: u64, envp: u64) -> SyscallResult {
+    let path = absolute(read_path(path)?);
+    let argv = read_strings(argv)?;
+    let envp = read_strings(envp)?;
+    let data = programs::read(&path)?;
+
+    let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(Vec::as_slice).collect();
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    process::current()
+        .unwrap()
+        .exec(frame, name, &data, &argv, &envp)?;
+    Ok(0)
+}
+
+/// `argv` is `argc` string pointers, or fewer ending in a null. The child gets a copy of
+/// the caller's file table. Returns its pid.
+fn spawn(path: u64, argv: u64, argc: u64) -> SyscallResult {
+    let path = absolute(read_path(path)?);
+    let argv = read_string_array(argv, Some(argc))?;
+    let data = programs::read(&path)?;
+
+    let argv: Vec<&[u8]> = argv.iter().map(Vec::as_slice).collect();
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    let child = process::current().unwrap().spawn(name, &data, &argv)?;
+    Ok(child.pid().0 as u64)
+}
+
+/// `pid` -1 waits for any child. Other negative pids are process groups, which don't
+/// exist. The status is encoded as for a normal exit, with the code in bits 8-15.
+fn wait4(pid: i64, status: u64, options: u64) -> SyscallResult {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 && pid <= u32::MAX as i64 => Some(Pid(pid as u32)),
+        _ => return Err(Errno::ECHILD),
+    };
+    let Some((pid, code)) = process::wait(pid, options & WNOHANG == 0)? else {
+        return Ok(0);
+    };
+    if status != 0 {
+        let wstatus = (code & 0xff) << 8;
+        process::current()
+            .unwrap()
+            .memory()
+            .copy_to_user(status, &wstatus.to_le_bytes())?;
+    }
+    Ok(pid.0 as u64)
+}
+
+/// Returns the new end of the heap, or the old one if it couldn't move. `0` just asks.
+fn brk(addr: u64) -> SyscallResult {
+    Ok(process::current().unwrap().memory().brk(addr))
+}
+
+/// Only anonymous mappings. The file arguments are ignored.
+fn mmap(addr: u64, len: u64, prot: u64, flags: u64) -> SyscallResult {
+    if len == 0 || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+        return Err(Errno::EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::ENODEV);
+    }
+
+    let mut entry_flags = EntryFlags::empty();
+    // Write without read is reserved.
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        entry_flags |= EntryFlags::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        entry_flags |= EntryFlags::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        entry_flags |= EntryFlags::EXECUTE;
+    }
+
+    let process = process::current().unwrap();
+    let start = process
+        .memory()
+        .map_anonymous(addr, len, entry_flags, flags & MAP_FIXED != 0)?;
+    Ok(start)
+}
+
+fn munmap(addr: u64, len: u64) -> SyscallResult {
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let end = addr.checked_add(len).ok_or(Errno::EINVAL)?;
+    process::current()
+        .unwrap()
+        .memory()
+        .unmap(addr..end)
+        .map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
@@ -0,0 +1,148 @@
+//! Byte queues for the console.
+//!
+//! Received bytes end up in [`UART_QUEUE`], pushed by the console's deferred work once the
+//! interrupt handler has taken them from the UART. Readers pop from it, either by polling
+//! or by parking a [`Waker`] that the pusher wakes.
+//! The transmit side uses the same [`ByteQueue`] the other way around.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Waker,
+};
+
+use spin::Mutex;
+
+const UART_QUEUE_SIZE: usize = 256;
+
+pub static UART_QUEUE: ByteQueue<UART_QUEUE_SIZE> = ByteQueue::new();
+
+/// Fixed size ring of bytes.
+///
+/// A single producer (for received bytes, the interrupt handler, serialized by the PLIC
+/// claim) and any number of consumers. When full new bytes are dropped and counted.
+pub struct ByteQueue<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    /// Next slot to read. Only ever increases, wraps with `% N` on access.
+    head: AtomicUsize,
+    /// Next slot to write.
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+    waker: Mutex<Option<Waker>>,
+}
+
+unsafe impl<const N: usize> Sync for ByteQueue<N> {}
+
+impl<const N: usize> ByteQueue<N> {
+    pub const fn new() -> Self {
+        ByteQueue {
+            buffer: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Add a byte. Returns false if the queue was full and the byte was dropped.
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe {
+            (*self.buffer.get())[tail % N] = byte;
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        loop {
+            let head = self.head.load(Ordering::Relaxed);
+            let tail = self.tail.load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+            // The producer won't touch this slot until head moves past it.
+            let byte = unsafe { (*self.buffer.get())[head % N] };
+            if self
+                .head
+                .compare_exchange(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(byte);
+            }
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Relaxed).wrapping_sub(head) >= N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+
+    /// Number of bytes lost because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wake whoever registered last. Called by the producer after pushing.
+    ///
+    /// Uses `try_lock` since this can run in an interrupt handler. If a reader is halfway
+    /// through registering it checks the queue again afterwards, so nothing is lost.
+    pub fn wake(&self) {
+        if let Some(mut waker) = self.waker.try_lock() {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn register_waker(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        match &*slot {
+            Some(existing) if existing.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn byte_queue_fifo() {
+        let queue = ByteQueue::<4>::new();
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(3));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case]
+    fn byte_queue_drops_when_full() {
+        let queue = ByteQueue::<2>::new();
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+//! The kernel's async executor.
+//!
+//! [`spawn`] hands a future to the executor. Whoever is in [`block_on`] or [`run`] polls
+//! the tasks that are ready, on whichever hart they're on. Each task has a priority, the
+//! same as [`WaitQueue`](crate::sync::WaitQueue)s use: the highest that's ready goes
+//! first, and ones with the same priority take turns. A task holding a sleeping
+//! [`Mutex`](crate::sync::Mutex) is [`boost`](TaskRef::boost)ed to the priority of whoever's
+//! waiting for it, so it isn't held up by tasks in between.
+//!
+//! A task's [`Waker`] puts it back on the run queue and is fine to call from interrupt
+//! handlers: the queue is an [`IrqSafeMutex`] with room reserved for every live task, so
+//! waking never allocates. With nothing ready a hart sleeps in `wfi`. Harts asleep here get
+//! an IPI when something on another hart is woken.
+
+use alloc::{collections::VecDeque, sync::Arc, task::Wake};
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+use crate::{
+    hart_local, isr, log,
+    prelude::*,
+    sbi::{hart::HartMask, ipi::IPI_EXTENSION},
+    smp::MAX_HARTS,
+    sync::{IrqSafeMutex, KERNEL_PRIORITY},
+    trace::trace,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct Task {
+    id: TaskId,
+    /// `None` once it's finished.
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// On the run queue already. A task is never on it twice.
+    queued: AtomicBool,
+    priority: u8,
+    /// Inherited from waiters, while it holds what they're waiting for: the most each lock
+    /// it holds has lent it, by lock.
+    lent: IrqSafeMutex<Vec<(usize, u8)>>,
+    /// The most of `lent`, so the run queue needn't lock it. 0 if not boosted.
+    boost: AtomicU8,
+}
+
+impl Task {
+    fn priority(&self) -> u8 {
+        self.priority.max(self.boost.load(Ordering::Relaxed))
+    }
+}
+
+/// A running task, to [`boost`](TaskRef::boost).
+#[derive(Clone)]
+pub struct TaskRef(Arc<Task>);
+
+impl TaskRef {
+    pub fn id(&self) -> TaskId {
+        self.0.id
+    }
+
+    /// Its priority now, boosted or not.
+    pub fn priority(&self) -> u8 {
+        self.0.priority()
+    }
+
+    /// Run it at `priority` at least, until `lock` [`unboost`](TaskRef::unboost)s it.
+    /// `lock` tells apart the locks it holds, like by their address.
+    pub fn boost(&self, lock: usize, priority: u8) {
+        let mut lent = self.0.lent.lock();
+        match lent.iter_mut().find(|(by, _)| *by == lock) {
+            Some((_, most)) => *most = (*most).max(priority),
+            None => lent.push((lock, priority)),
+        }
+        self.0.boost.fetch_max(priority, Ordering::Relaxed);
+    }
+
+    /// Take back what `lock` lent it. It keeps what the other locks it holds lent it.
+    pub fn unboost(&self, lock: usize) {
+        let mut lent = self.0.lent.lock();
+        lent.retain(|(by, _)| *by != lock);
+        let boost = lent.iter().map(|(_, priority)| *priority).max().unwrap_or(0);
+        self.0.boost.store(boost, Ordering::Relaxed);
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            RUN_QUEUE.lock().push_back(self.clone());
+            notify_idle();
+        }
+    }
+}
+
+static RUN_QUEUE: IrqSafeMutex<VecDeque<Arc<Task>>> = IrqSafeMutex::new(VecDeque::new());
+/// Tasks spawned that haven't finished. The run queue always has room for all of them.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+/// Harts sleeping in [`idle_until`].
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+
+hart_local! {
+    /// The task being polled on this hart.
+    static CURRENT: RefCell<Option<Arc<Task>>> = RefCell::new(None);
+}
+
+/// Run `future` in the background at [`KERNEL_PRIORITY`]. It's first polled by the next
+/// hart to look for work.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    spawn_with_priority(KERNEL_PRIORITY, future)
+}
+
+/// [`spawn`] at `priority`. Higher goes first.
+pub fn spawn_with_priority(
+    priority: u8,
+    future: impl Future<Output = ()> + Send + 'static,
+) -> TaskId {
+    let task = Arc::new(Task {
+        id: TaskId::new(),
+        future: Mutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(true),
+        priority,
+        lent: IrqSafeMutex::new(Vec::new()),
+        boost: AtomicU8::new(0),
+    });
+    let id = task.id;
+    let live = LIVE.fetch_add(1, Ordering::Relaxed) + 1;
+    {
+        let mut queue = RUN_QUEUE.lock();
+        let len = queue.len();
+        queue.reserve(live.saturating_sub(len));
+        queue.push_back(task);
+    }
+    notify_idle();
+    id
+}
+
+/// Run `future` to completion on this hart. Spawned tasks run while it's pending, and the
+/// hart sleeps when there's nothing to do.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let signal = Arc::new(Signal::new());
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if signal.woken.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        if !poll_next() {
+            idle_until(|| signal.woken.load(Ordering::Acquire) || has_ready());
+        }
+    }
+}
+
+/// Run spawned tasks on this hart forever.
+pub fn run() -> ! {
+    loop {
+        if !poll_next() {
+            idle_until(has_ready);
+        }
+    }
+}
+
+fn has_ready() -> bool {
+    !RUN_QUEUE.lock().is_empty()
+}
+
+/// The task being polled on this hart, if any.
+pub fn current() -> Option<TaskRef> {
+    let current = CURRENT.try_get()?.try_borrow().ok()?;
+    current.clone().map(TaskRef)
+}
+
+/// The highest priority task on the run queue, the one that's waited longest of those.
+fn next(queue: &mut VecDeque<Arc<Task>>) -> Option<Arc<Task>> {
+    let (index, _) = queue
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, task)| (task.priority(), core::cmp::Reverse(*index)))?;
+    queue.remove(index)
+}
+
+/// Poll the next task on the run queue. Returns false if there wasn't one.
+fn poll_next() -> bool {
+    let task = match next(&mut RUN_QUEUE.lock()) {
+        Some(task) => task,
+        None => return false,
+    };
+    // Cleared first so a wake during the poll queues it again.
+    task.queued.store(false, Ordering::Release);
+    let waker = Waker::from(task.clone());
+    let mut cx = Context::from_waker(&waker);
+    // A task can block_on, which polls others, so put back whoever was there after.
+    let outer = CURRENT
+        .try_get()
+        .map(|current| current.replace(Some(task.clone())));
+    let mut slot = task.future.lock();
+    trace!(Poll, task.id.0);
+    if let Some(future) = slot.as_mut() {
+        if future.as_mut().poll(&mut cx).is_ready() {
+            *slot = None;
+            LIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    if let (Some(current), Some(outer)) = (CURRENT.try_get(), outer) {
+        *current.borrow_mut() = outer;
+    }
+    true
+}
+
+/// Sleep until `ready`, marking the hart idle so wakers on other harts send it an IPI.
+fn idle_until(ready: impl Fn() -> bool) {
+    let bit = match hart_local::try_current_hart() {
+        Some(hart) if hart.0 < MAX_HARTS => 1 << hart.0,
+        _ => 0,
+    };
+    IDLE.fetch_or(bit, Ordering::SeqCst);
+    isr::wait_until(ready);
+    IDLE.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/// Send an IPI to the other idle harts so they look at the run queue again.
+fn notify_idle() {
+    let mut idle = IDLE.load(Ordering::SeqCst);
+    if let Some(hart) = hart_local::try_current_hart() {
+        if hart.0 < MAX_HARTS {
+            idle &= !(1 << hart.0);
+        }
+    }
+    if idle == 0 {
+        return;
+    }
+    let harts = HartMask {
+        hart_mask: idle,
+        hart_mask_base: 0,
+    };
+    if let Some(ipi) = IPI_EXTENSION.get() {
+        if let Err(err) = ipi.send_ipi(harts) {
+            log::warn!("waking idle harts {:?}: {}", harts, err);
+        }
+    }
+}
+
+/// Waker for the future in [`block_on`].
+struct Signal {
+    woken: AtomicBool,
+}
+
+impl Signal {
+    fn new() -> Signal {
+        // Starts woken so the future is polled once.
+        Signal {
+            woken: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        notify_idle();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn spawn_and_block_on() {
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        spawn(async move { flag.store(true, Ordering::Relaxed) });
+
+        // Yields until the spawned task has run, which block_on does meanwhile.
+        let mut count = 0;
+        let polls = block_on(core::future::poll_fn(|cx| {
+            count += 1;
+            if done.load(Ordering::Relaxed) {
+                Poll::Ready(count)
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }));
+        assert!(polls >= 2);
+    }
+
+    #[test_case]
+    fn higher_priority_first() {
+        let task = |priority| {
+            Arc::new(Task {
+                id: TaskId::new(),
+                future: Mutex::new(None),
+                queued: AtomicBool::new(true),
+                priority,
+                lent: IrqSafeMutex::new(Vec::new()),
+                boost: AtomicU8::new(0),
+            })
+        };
+        let (low, first, second) = (task(1), task(KERNEL_PRIORITY), task(KERNEL_PRIORITY));
+        let mut queue: VecDeque<_> = [low.clone(), first.clone(), second.clone()].into();
+        let next_id = |queue: &mut VecDeque<_>| next(queue).map(|task| task.id);
+        assert_eq!(next_id(&mut queue), Some(first.id));
+        // Boosted past the one that's waited longer.
+        queue.push_back(first.clone());
+        TaskRef(low.clone()).boost(1, KERNEL_PRIORITY + 1);
+        assert_eq!(next_id(&mut queue), Some(low.id));
+        TaskRef(low.clone()).unboost(1);
+        queue.push_back(low.clone());
+        assert_eq!(next_id(&mut queue), Some(second.id));
+        assert_eq!(next_id(&mut queue), Some(first.id));
+        assert_eq!(next_id(&mut queue), Some(low.id));
+        assert_eq!(next_id(&mut queue), None);
+        assert!(current().is_none());
+    }
+
+    #[test_case]
+    fn boosts_are_per_lock() {
+        let low = TaskRef(Arc::new(Task {
+            id: TaskId::new(),
+            future: Mutex::new(None),
+            queued: AtomicBool::new(false),
+            priority: 1,
+            lent: IrqSafeMutex::new(Vec::new()),
+            boost: AtomicU8::new(0),
+        }));
+        low.boost(1, 5);
+        low.boost(2, 3);
+        low.boost(1, 4);
+        assert_eq!(low.priority(), 5);
+        // Still holding lock 2, so it keeps what that lent it.
+        low.unboost(1);
+        assert_eq!(low.priority(), 3);
+        low.unboost(2);
+        assert_eq!(low.priority(), 1);
+    }
+}
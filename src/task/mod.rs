@@ -1,27 +1,7 @@
-use core::{
-    future::Future,
-    pin::Pin,
-    task::{Context, Poll},
-};
+//! Async tasks, and the queues interrupt handlers use to wake them.
 
-use alloc::boxed::Box;
+pub mod console;
+pub mod executor;
 
-pub mod simple_executor;
-
-pub struct Task {
-    future: Pin<Box<dyn Future<Output = ()>>>,
-}
-
-impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task {
-            future: Box::pin(future),
-        }
-    }
-}
-
-impl Task {
-    fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
-    }
-}
+#[allow(unused_imports)]
+pub use executor::{block_on, spawn, spawn_with_priority};
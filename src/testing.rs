@@ -0,0 +1,291 @@
+//! The in-kernel test runner.
+//!
+//! Each test runs on its own stack, in a context of its own, like a process does. That way
+//! a test that panics or runs past its timeout can be switched away from for good, and the
+//! runner goes on to the next one. The panic handler calls [`panicked`] first, and the
+//! timeout is a [`Timer`] that does the same from the timer interrupt. A test spinning
+//! with interrupts off can't be timed out, and anything it had locked stays locked.
+//!
+//! `#[test_case]` works on a plain `fn()`, or on a static [`Test`] for a test that should
+//! panic or needs a different timeout:
+//!
+//! ```ignore
+//! #[test_case]
+//! static OVERFLOW: Test = Test::new("overflow", || overflow()).should_panic();
+//! ```
+//!
+//! Results go to a second ns16550a UART if the device tree has one, so they can be read
+//! without the console's noise, one line per test. Otherwise they're on the console.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt::{self, Write},
+    time::Duration,
+};
+
+use riscv::register::sstatus;
+
+use crate::{
+    asm::switch_context,
+    console::{
+        self,
+        uart_ns16550a::{LineConfig, MmioSerialPort},
+    },
+    finisher::{self, ExitCode},
+    hart_local,
+    hwinfo::HwInfo,
+    pagetable::memory_map::ioremap,
+    process::Context,
+    sbi,
+    stack::KernelStack,
+    sync::{IrqSafeMutex, Once},
+    time::{timer::Timer, Instant},
+    trap,
+};
+
+/// How long a test gets unless it says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const TEST_STACK_SIZE: u64 = 32 * 1024;
+
+pub trait Testable: Sync {
+    fn name(&self) -> &str;
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+
+    fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
+}
+
+impl<T> Testable for T
+where
+    T: Fn() + Sync,
+{
+    fn name(&self) -> &str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+/// A test with options a plain `fn()` can't have.
+pub struct Test {
+    name: &'static str,
+    test: fn(),
+    should_panic: bool,
+    timeout: Duration,
+}
+
+impl Test {
+    pub const fn new(name: &'static str, test: fn()) -> Test {
+        Test {
+            name,
+            test,
+            should_panic: false,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Passes only if it panics.
+    pub const fn should_panic(self) -> Test {
+        Test {
+            should_panic: true,
+            ..self
+        }
+    }
+
+    pub const fn timeout(self, timeout: Duration) -> Test {
+        Test { timeout, ..self }
+    }
+}
+
+impl Testable for Test {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.test)()
+    }
+
+    fn should_panic(&self) -> bool {
+        self.should_panic
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// How a test stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Returned,
+    Panicked,
+    TimedOut,
+}
+
+hart_local! {
+    /// Where [`test_runner`] switched to the test from.
+    static RUNNER: UnsafeCell<Context> = UnsafeCell::new(Context::ZERO);
+    static TEST_CONTEXT: UnsafeCell<Context> = UnsafeCell::new(Context::ZERO);
+    /// The test running on this hart, if any.
+    static RUNNING: Cell<Option<&'static dyn Testable>> = Cell::new(None);
+    static OUTCOME: Cell<Outcome> = Cell::new(Outcome::Returned);
+}
+
+/// The UART results go to, if there's one that isn't the console.
+static REPORT: Once<IrqSafeMutex<MmioSerialPort>> = Once::INIT;
+
+/// Find a UART for the results. Called before `test_main`.
+pub fn init(hwinfo: &HwInfo) {
+    let console = console::uart_index();
+    let mut uarts = hwinfo.uarts.iter().enumerate();
+    let Some((_, uart)) = uarts.find(|&(index, _)| Some(index) != console) else {
+        return;
+    };
+    let base = ioremap(uart.reg.start, uart.reg.size(), "test results uart");
+    let base = base.as_u64() as usize;
+    let mut report = unsafe { MmioSerialPort::new(base, uart.interrupt, uart.clock_freq) };
+    if report.init(LineConfig::default()).is_ok() {
+        REPORT.call_once(|| IrqSafeMutex::new(report));
+    }
+}
+
+struct Report;
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match REPORT.get() {
+            Some(uart) => uart.lock().write_str(s),
+            None => {
+                crate::print!("{}", s);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn test_runner(tests: &[&'static dyn Testable]) {
+    let stack = KernelStack::new(TEST_STACK_SIZE, "tests".into()).expect("no stack for tests");
+    let mut report = Report;
+    writeln!(report, "running {} tests", tests.len()).ok();
+
+    let mut failed = 0u16;
+    for &test in tests {
+        let start = Instant::now();
+        let outcome = run(test, &stack);
+        let elapsed = start.elapsed();
+        let result = match (outcome, test.should_panic()) {
+            (Outcome::Returned, false) | (Outcome::Panicked, true) => "ok",
+            (Outcome::Returned, true) => "FAILED (didn't panic)",
+            (Outcome::Panicked, false) => "FAILED (panicked)",
+            (Outcome::TimedOut, _) => "FAILED (timed out)",
+        };
+        if result != "ok" {
+            failed = failed.saturating_add(1);
+        }
+        writeln!(report, "test {} ... {} {:?}", test.name(), result, elapsed).ok();
+    }
+
+    writeln!(
+        report,
+        "test result: {}. {} passed; {} failed",
+        if failed == 0 { "ok" } else { "FAILED" },
+        tests.len() - failed as usize,
+        failed
+    )
+    .ok();
+    console::flush();
+    finisher::exit(match failed {
+        0 => ExitCode::Success,
+        failed => ExitCode::Failure(failed),
+    });
+    sbi::reset::shutdown();
+}
+
+/// Run `test` on `stack` until it returns, panics or times out.
+fn run(test: &'static dyn Testable, stack: &KernelStack) -> Outcome {
+    let interrupts = sstatus::read().sie();
+    let depth = trap::depth();
+
+    RUNNING.get().set(Some(test));
+    let context = TEST_CONTEXT.get().get();
+    unsafe { *context = Context::starting_at(test_start, stack.top()) };
+    let timeout = Timer::after_in_interrupt(test.timeout(), || {
+        // It might have finished since.
+        if RUNNING.get().get().is_some() {
+            unsafe { finish(Outcome::TimedOut) }
+        }
+    });
+    unsafe { switch_context(RUNNER.get().get(), context) };
+    timeout.cancel();
+
+    // Whatever the test was in the middle of is gone.
+    unsafe {
+        trap::abandon_traps(depth);
+        if interrupts {
+            sstatus::set_sie();
+        } else {
+            sstatus::clear_sie();
+        }
+    }
+    OUTCOME.get().get()
+}
+
+extern "C" fn test_start() -> ! {
+    let test = RUNNING.get().get().expect("test_start without a test");
+    test.run();
+    unsafe { finish(Outcome::Returned) }
+}
+
+/// Switch back to the runner for good.
+///
+/// # Safety
+/// A test is running on this hart, and nothing on its stack needs dropping.
+unsafe fn finish(outcome: Outcome) -> ! {
+    OUTCOME.get().set(outcome);
+    RUNNING.get().set(None);
+    switch_context(TEST_CONTEXT.get().get(), RUNNER.get().get());
+    unreachable!("finished test was switched back to")
+}
+
+/// Called first thing by the panic handler. If a test is running on this hart, that test
+/// failed, so go back to the runner instead of panicking the kernel.
+pub fn panicked(info: &core::panic::PanicInfo) {
+    let running = RUNNING.try_get().and_then(|running| running.get());
+    if let Some(test) = running {
+        if !test.should_panic() {
+            writeln!(console::lock_or_dummy(), "{}: {}", test.name(), info).ok();
+        }
+        unsafe { finish(Outcome::Panicked) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn hello_world() {
+        crate::println!("Hello world!");
+    }
+
+    #[test_case]
+    static PANICS: Test =
+        Test::new("testing::test::panics", || panic!("on purpose")).should_panic();
+
+    #[test_case]
+    static WITHIN_TIMEOUT: Test = Test::new("testing::test::within_timeout", || {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {
+            core::hint::spin_loop();
+        }
+    })
+    .timeout(Duration::from_secs(1));
+}
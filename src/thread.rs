@@ -103,7 +103,7 @@ pub unsafe extern "C" fn global_hart_entry2(hart_id: usize, opaque: usize) -> !
     let thread_state = opaque as *mut ThreadState;
     (*thread_state).magic.validate();
     run_thread(HartId(hart_id), &mut *thread_state);
-    loop {}
+    crate::cpu::halt_forever()
 }
 
 pub fn run_thread(hart_id: HartId, thread: &'static mut ThreadState) {
@@ -1,42 +1,84 @@
 use core::{
     fmt::{self, Write},
-    num::NonZeroU64,
     ops::{Add, AddAssign, Sub, SubAssign},
     sync::atomic::{AtomicU64, Ordering},
     time::Duration,
 };
-use riscv::register::{self, sstatus};
+use riscv::register;
 
 use crate::{
-    sbi::{hart::hsm_extension, timer::TIMER_EXTENSION},
-    trap::TrapRegisters,
+    isr::{wait_until, without_interrupts},
+    sync::Once,
 };
 
 pub mod rtc;
+mod sleep;
+pub mod timer;
+
+#[allow(unused_imports)]
+pub use sleep::{sleep_async, sleep_until, timeout, Elapsed, Sleep, Timeout};
+#[allow(unused_imports)]
+pub use timer::{wake_hart_at, Timer};
 
 const NANOS_PER_SECOND: u64 = 1_000_000_000;
 
-static MTIME_PER_SECOND: AtomicU64 = AtomicU64::new(0);
+static CLOCK: Once<Clock> = Once::INIT;
+
+/// Conversions between mtime ticks and time, for a given `timebase-frequency`.
+#[derive(Debug)]
+struct Clock {
+    ticks_per_second: u64,
+    /// Nanoseconds per tick, in 64.64 fixed point.
+    nanos_per_tick: u128,
+}
+
+impl Clock {
+    fn new(ticks_per_second: u64) -> Clock {
+        assert!(ticks_per_second != 0, "timebase frequency is zero");
+        Clock {
+            ticks_per_second,
+            nanos_per_tick: ((NANOS_PER_SECOND as u128) << 64) / ticks_per_second as u128,
+        }
+    }
+
+    /// Off by less than a nanosecond, for any frequency. The multiply only ever sees the
+    /// part of a second, so it can't overflow.
+    fn to_duration(&self, ticks: u64) -> Duration {
+        let secs = ticks / self.ticks_per_second;
+        let subsec_ticks = ticks % self.ticks_per_second;
+        let subsec_nanos = (subsec_ticks as u128 * self.nanos_per_tick) >> 64;
+        Duration::new(secs, subsec_nanos as u32)
+    }
+
+    /// Rounds up, so waiting until the tick means the time has passed.
+    fn to_ticks(&self, duration: Duration) -> Option<u64> {
+        let ticks = duration.as_secs().checked_mul(self.ticks_per_second)?;
+        let subsec_ticks = (duration.subsec_nanos() as u128 * self.ticks_per_second as u128)
+            .div_ceil(NANOS_PER_SECOND as u128);
+        ticks.checked_add(subsec_ticks as u64)
+    }
+}
 
 pub(crate) fn init_time(hwinfo: &crate::hwinfo::HwInfo) {
-    MTIME_PER_SECOND.store(hwinfo.timebase_freq, Ordering::Relaxed);
+    CLOCK.call_once(|| Clock::new(hwinfo.timebase_freq));
 
     // Fail early if something is wrong
     let _time = Instant::now();
+    timer::init();
 
-    LAST_SET_TIMER.store(0, Ordering::Relaxed);
-    TIMER_EXTENSION
-        .get()
-        .unwrap()
-        .set_timer(0)
-        .expect("failed to set timer")
+    // Goes off straight away. The interrupt sets it again for the next tick.
+    timer::arm(Instant::time_started()).expect("failed to set timer")
 }
 
-fn get_mtime_per_second() -> u64 {
-    let hz = MTIME_PER_SECOND.load(Ordering::Relaxed);
-    NonZeroU64::new(hz)
-        .unwrap_or_else(|| panic!("{} has not been initialized", module_path!()))
+/// Time since the clock started. `None` until [`init_time`] knows how fast it runs.
+pub fn uptime() -> Option<Duration> {
+    CLOCK.get().map(|clock| clock.to_duration(get_mtime()))
+}
+
+fn clock() -> &'static Clock {
+    CLOCK
         .get()
+        .unwrap_or_else(|| panic!("{} has not been initialized", module_path!()))
 }
 
 // Haven't decided how I'm dealing with 32-bit
@@ -45,21 +87,9 @@ fn get_mtime() -> u64 {
     register::time::read() as u64
 }
 
-fn convert_mtime_to_duration(mtime: u64) -> Duration {
-    let mtime_per_second = get_mtime_per_second();
-    let secs = mtime / mtime_per_second;
-    let subsec_t = mtime % mtime_per_second;
-
-    if mtime_per_second == NANOS_PER_SECOND {
-        Duration::new(secs, subsec_t as u32)
-    } else if mtime_per_second < NANOS_PER_SECOND {
-        let nanos_per_t = NANOS_PER_SECOND / mtime_per_second;
-        let subsec_nanos = subsec_t * nanos_per_t;
-        assert!(subsec_nanos < (u32::MAX as u64));
-        Duration::new(secs, subsec_nanos as u32)
-    } else {
-        todo!("when freq is greater than 1GHz")
-    }
+/// How long `mtime` ticks is. Panics before [`init_time`].
+pub fn convert_mtime_to_duration(mtime: u64) -> Duration {
+    clock().to_duration(mtime)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -82,22 +112,7 @@ impl Instant {
     }
 
     pub fn to_mtime(&self) -> Option<u64> {
-        let secs = self.since_zero.as_secs();
-        let subsec_nanos = self.since_zero.subsec_nanos() as u64;
-
-        let mtime_per_second = MTIME_PER_SECOND.load(Ordering::Relaxed);
-
-        let ticks = secs.checked_mul(mtime_per_second)?;
-
-        if mtime_per_second == NANOS_PER_SECOND {
-            Some(ticks + subsec_nanos)
-        } else if mtime_per_second < NANOS_PER_SECOND {
-            let nanos_per_t = NANOS_PER_SECOND / mtime_per_second;
-            let subsec_t = subsec_nanos / nanos_per_t;
-            Some(ticks + subsec_t)
-        } else {
-            todo!("when freq is greater than 1GHz")
-        }
+        clock().to_ticks(self.since_zero)
     }
 
     pub fn now() -> Instant {
@@ -177,94 +192,83 @@ impl Sub<Instant> for Instant {
     }
 }
 
-/// Set the interrupt timer and suspend. Returning on the next interrupt.
+/// Idle until `duration` has passed or an interrupt comes, whichever is first.
 pub fn park_for(duration: Duration) {
-    let start = Instant::now();
-    let until = start + duration;
-
-    let hsm = hsm_extension();
-
-    set_timer(until).expect("failed to to set timer");
-    hsm.hart_retentive_suspend(crate::sbi::hart::RetentiveSuspendType::DEFAULT_RETENTIVE_SUSPEND)
-        .expect("failed to suspend");
+    let timer = Timer::interrupt_at(Instant::now() + duration);
+    without_interrupts(crate::idle::idle);
+    timer.cancel();
 }
 
+/// Idle the hart until `duration` has passed. Interrupts are still handled meanwhile.
+///
+/// The deadline goes on the timer queue like any other, so timers going off sooner don't
+/// make it late.
 pub fn sleep(duration: Duration) {
-    let start = Instant::now();
-    let until = start + duration;
-
-    let hsm = hsm_extension();
-
-    loop {
-        set_timer(until).expect("failed to to set timer");
-        hsm.hart_retentive_suspend(
-            crate::sbi::hart::RetentiveSuspendType::DEFAULT_RETENTIVE_SUSPEND,
-        )
-        .expect("failed to suspend");
-
-        let now = Instant::now();
-        // println!("until = {:?}, now = {:?}", until, now);
-        if until < now {
-            return;
-        }
-    }
+    let until = Instant::now() + duration;
+    let timer = Timer::interrupt_at(until);
+    wait_until(|| Instant::now() >= until);
+    timer.cancel();
 }
 
-pub static LAST_SET_TIMER: AtomicU64 = AtomicU64::new(u64::MAX);
-
-pub fn set_timer(instant: Instant) -> Result<(), crate::sbi::SbiError> {
-    let new_time = instant.to_mtime().expect("instant overflows mtime");
-    let time = TIMER_EXTENSION.get().expect("no timer extension");
+pub(crate) fn interrupt_handler(mut w: impl Write) {
+    let time = get_mtime();
+    crate::watchdog::pet();
+    crate::sync::epoch::quiescent();
+    timer::fire();
 
-    unsafe {
-        sstatus::clear_sie();
-    }
-    let old_timer = LAST_SET_TIMER.load(Ordering::SeqCst);
-    let r;
-    if old_timer > new_time {
-        r = time.set_timer(new_time);
-        if r.is_ok() {
-            LAST_SET_TIMER.store(new_time, Ordering::SeqCst);
-        }
-    } else {
-        r = Ok(())
-    }
-    unsafe {
-        sstatus::set_sie();
-    }
-    r
+    writeln!(w, "TIMER: {:?}", time).ok();
 }
 
-pub(crate) fn interrupt_handler(mut w: impl Write, _registers: &mut TrapRegisters) {
-    let time = get_mtime();
-    let last_set = LAST_SET_TIMER.load(Ordering::SeqCst);
-    let timer = TIMER_EXTENSION.get().expect("no timer extension");
-
-    if last_set < time {
-        let mtime_per_second = MTIME_PER_SECOND.load(Ordering::Relaxed);
+/// Nanoseconds since the unix epoch when [`Instant`] was zero. `u64::MAX` until the RTC
+/// has been read.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(u64::MAX);
 
-        // This implies that eventually the kernel crashes onces mtime runs out.
-        // From the hardware i'm using now that'll take: 58455 average Gregorian years
-        let new_time = last_set
-            .checked_add(mtime_per_second)
-            .expect("mtime overflow");
+/// Tie the wall clock to the monotonic one: `now` is the wall clock time at the moment
+/// this is called. [`SystemTime::now`] counts from here using mtime.
+pub(crate) fn set_wall_clock(now: SystemTime) {
+    let since_boot = Instant::now().since_zero;
+    let boot = now.0.saturating_sub(since_boot);
+    BOOT_TIME.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
 
-        if let Ok(_) = timer.set_timer(new_time) {
-            LAST_SET_TIMER.store(new_time, Ordering::SeqCst);
-        }
+/// Set the wall clock to `now`, in the RTC and for [`SystemTime::now`]. [`Instant`]s and
+/// timers on them aren't affected.
+pub fn set_time_of_day(now: SystemTime) {
+    if let Some(rtc) = rtc::Goldfish::try_get() {
+        rtc.set_time(now);
     }
-
-    writeln!(w, "TIMER: {:?}", time).ok();
+    set_wall_clock(now);
 }
 
+/// Time since the unix epoch. Read from the RTC at boot or when it's set, then kept by
+/// mtime.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SystemTime(Duration);
 
 impl SystemTime {
     pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::new(0, 0));
 
+    /// Panics if the RTC hasn't been read yet.
     pub fn now() -> SystemTime {
-        todo!()
+        Self::try_now().expect("wall clock not set")
+    }
+
+    /// `None` before the RTC has been read.
+    pub fn try_now() -> Option<SystemTime> {
+        match BOOT_TIME.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            boot => Some(SystemTime(
+                Duration::from_nanos(boot) + Instant::now().since_zero,
+            )),
+        }
+    }
+
+    pub fn from_unix_nanos(nanos: u64) -> SystemTime {
+        SystemTime(Duration::from_nanos(nanos))
+    }
+
+    pub fn unix_nanos(&self) -> u128 {
+        self.0.as_nanos()
     }
 
     pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
@@ -330,3 +334,23 @@ impl fmt::Display for SystemTimeError {
         write!(f, "second time provided was later than self")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn clock_conversions() {
+        // QEMU's 10 MHz, a 32 kHz crystal, and faster than a nanosecond.
+        for hz in [10_000_000, 32_768, 3_000_000_000] {
+            let clock = Clock::new(hz);
+            assert_eq!(clock.to_duration(hz), Duration::from_secs(1));
+            let ticks = hz * 1000 + hz / 3;
+            let duration = clock.to_duration(ticks);
+            assert_eq!(duration.as_secs(), 1000);
+            // Past 1 GHz a tick is less than the nanosecond `Duration` keeps.
+            assert!(clock.to_ticks(duration).unwrap().abs_diff(ticks) <= 1);
+        }
+        assert_eq!(Clock::new(32_768).to_duration(1).as_nanos(), 30_517);
+    }
+}
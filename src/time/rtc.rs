@@ -1,37 +1,79 @@
+//! The Goldfish RTC: the wall clock, and alarms on it.
+//!
+//! Only one alarm can be set in the hardware. [`wake_at`] keeps a queue of them and sets
+//! the alarm for the earliest.
+//!
+//! Without an RTC the wall clock starts at the epoch, and [`wake_at`] uses a timer on
+//! [`SystemTime`] instead.
+
 use ::time::OffsetDateTime;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
 use fdt_rs::spec::Phandle;
 
-use spin::Once;
+use crate::sync::{IrqSafeMutex, Once};
+
+use crate::{
+    devices::ProbeError,
+    error::KernelResult,
+    hwinfo::{HwInfo, Rtc},
+    isr::plic::{self, InterruptId},
+    pagetable::memory_map::ioremap,
+    prelude::*,
+};
 
-use crate::{hwinfo::HwInfo, isr::plic::InterruptId};
+use super::{timer::Timer, Instant, SystemTime};
 
-const TIME_LOW: u64 = 0x00;
-const TIME_HIGH: u64 = 0x04;
-const ALARM_LOW: u64 = 0x08;
-const ALARM_HIGH: u64 = 0x0c;
-const IRQ_ENABLED: u64 = 0x10;
-const CLEAR_ALARM: u64 = 0x14;
-const ALARM_STATUS: u64 = 0x18;
-const CLEAR_INTERRUPT: u64 = 0x1c;
+crate::register_block! {
+    struct Regs {
+        0x00 => time_low: u32,
+        0x04 => time_high: u32,
+        0x08 => alarm_low: u32,
+        0x0c => alarm_high: u32,
+        0x10 => irq_enabled: u32,
+        0x14 => clear_alarm: u32,
+        0x18 => alarm_status: u32,
+        0x1c => clear_interrupt: u32,
+    }
+}
 
 pub static RTC: Once<Goldfish> = Once::INIT;
 
-pub fn init(hwinfo: &'static HwInfo) {
-    Goldfish::init(hwinfo);
+pub fn init(hwinfo: &'static HwInfo) -> KernelResult<()> {
+    let rtc = match &hwinfo.rtc {
+        Some(info) => Goldfish::init(info),
+        None => {
+            super::set_wall_clock(SystemTime::UNIX_EPOCH);
+            return Err(ProbeError::NoDevice.into());
+        }
+    };
+    super::set_wall_clock(rtc.read_system_time());
+
+    rtc.clear_alarm();
+    rtc.regs.clear_interrupt().write(1);
+    rtc.regs.irq_enabled().write(1);
+    plic::register_handler(rtc.interrupt, rtc_interrupt);
+    plic::enable_interrupt(rtc.interrupt);
+    Ok(())
 }
 
 pub struct Goldfish {
-    base: u64,
+    regs: Regs,
     interrupt: InterruptId,
     interrupt_parent: Phandle,
 }
 
 impl Goldfish {
-    pub fn init(hwinfo: &HwInfo) -> &'static Goldfish {
+    pub fn init(info: &Rtc) -> &'static Goldfish {
+        let reg = &info.reg;
         RTC.call_once(|| Goldfish {
-            base: hwinfo.rtc.reg.start,
-            interrupt: hwinfo.rtc.interrupt,
-            interrupt_parent: hwinfo.rtc.interrupt_parent,
+            regs: unsafe { Regs::new(ioremap(reg.start, reg.size(), "RTC").as_u64() as usize) },
+            interrupt: info.interrupt,
+            interrupt_parent: info.interrupt_parent,
         })
     }
 
@@ -39,15 +81,168 @@ impl Goldfish {
         RTC.get().expect("rtc not initialized")
     }
 
+    /// `None` if there isn't one.
+    pub fn try_get() -> Option<&'static Goldfish> {
+        RTC.get()
+    }
+
     pub fn read_time(&self) -> i64 {
-        let time_lo;
-        let time_hi;
-        unsafe {
-            time_lo = ((self.base + TIME_LOW) as *const u32).read_volatile() as u64;
-            time_hi = ((self.base + TIME_HIGH) as *const u32).read_volatile() as u64;
+        // Reading the low half latches the high half.
+        let time_lo = self.regs.time_low().read() as u64;
+        let time_hi = self.regs.time_high().read() as u64;
+        (time_hi << 32 | time_lo) as i64
+    }
+
+    /// Read the clock. Slow next to [`SystemTime::now`], which only reads it at boot.
+    pub fn read_system_time(&self) -> SystemTime {
+        SystemTime::from_unix_nanos(self.read_time().max(0) as u64)
+    }
+
+    /// Set the clock. Most callers want [`super::set_time_of_day`], which moves
+    /// [`SystemTime::now`] too.
+    pub fn set_time(&self, time: SystemTime) {
+        let nanos = time.unix_nanos().min(i64::MAX as u128) as u64;
+        // Writing the low half is what sets it, like the alarm.
+        self.regs.time_high().write((nanos >> 32) as u32);
+        self.regs.time_low().write(nanos as u32);
+        // Alarms are on the clock, so any that are now due go off.
+        let alarms = ALARMS.lock();
+        match alarms.last() {
+            Some(next) => self.set_alarm(next.at),
+            None => self.clear_alarm(),
         }
-        let time = (time_hi << 32 | time_lo) as i64;
-        time
+    }
+
+    /// Raise the interrupt once the clock reaches `at`. Straight away if it's already past.
+    /// Replaces any alarm that was set.
+    pub fn set_alarm(&self, at: SystemTime) {
+        let nanos = at.unix_nanos().min(i64::MAX as u128) as u64;
+        // Writing the low half is what sets it.
+        self.regs.alarm_high().write((nanos >> 32) as u32);
+        self.regs.alarm_low().write(nanos as u32);
+    }
+
+    pub fn clear_alarm(&self) {
+        self.regs.clear_alarm().write(1);
+    }
+
+    pub fn alarm_pending(&self) -> bool {
+        self.regs.alarm_status().read() != 0
+    }
+}
+
+struct Alarm {
+    at: SystemTime,
+    id: u64,
+    waker: Waker,
+}
+
+/// Latest first, so the next one due is at the end.
+static ALARMS: IrqSafeMutex<Vec<Alarm>> = IrqSafeMutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Future from [`wake_at`].
+#[derive(Debug)]
+pub struct WakeAt {
+    at: SystemTime,
+    /// Our entry in [`ALARMS`], if we've made one.
+    id: Option<u64>,
+    /// Without an RTC, a timer for when [`SystemTime`] gets there.
+    timer: Option<Timer>,
+}
+
+/// Finish once the wall clock reaches `at`. The RTC's alarm interrupt wakes the task, so
+/// this works for times far off, and follows the RTC if it's set.
+pub fn wake_at(at: SystemTime) -> WakeAt {
+    WakeAt {
+        at,
+        id: None,
+        timer: None,
+    }
+}
+
+impl Future for WakeAt {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let rtc = match Goldfish::try_get() {
+            Some(rtc) => rtc,
+            None => return self.poll_timer(cx),
+        };
+        if let Some(id) = self.id.take() {
+            remove_alarm(id);
+        }
+        if rtc.read_system_time() >= self.at {
+            return Poll::Ready(());
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut alarms = ALARMS.lock();
+        let index = alarms.partition_point(|alarm| alarm.at > self.at);
+        alarms.insert(
+            index,
+            Alarm {
+                at: self.at,
+                id,
+                waker: cx.waker().clone(),
+            },
+        );
+        if index == alarms.len() - 1 {
+            rtc.set_alarm(self.at);
+        }
+        self.id = Some(id);
+        Poll::Pending
+    }
+}
+
+impl WakeAt {
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(timer) = self.timer.take() {
+            timer.cancel();
+        }
+        let left = match self.at.duration_since(SystemTime::now()) {
+            Ok(left) if !left.is_zero() => left,
+            _ => return Poll::Ready(()),
+        };
+        let waker = cx.waker().clone();
+        self.timer = Some(Timer::at(Instant::now() + left, move || waker.wake()));
+        Poll::Pending
+    }
+}
+
+impl Drop for WakeAt {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            remove_alarm(id);
+        }
+        if let Some(timer) = self.timer.take() {
+            timer.cancel();
+        }
+    }
+}
+
+/// Drop an alarm. The hardware one stays set, and finds nothing due when it goes off.
+fn remove_alarm(id: u64) {
+    let mut alarms = ALARMS.lock();
+    if let Some(index) = alarms.iter().position(|alarm| alarm.id == id) {
+        alarms.remove(index);
+    }
+}
+
+fn rtc_interrupt(_interrupt: InterruptId) {
+    let rtc = match RTC.get() {
+        Some(rtc) => rtc,
+        None => return,
+    };
+    rtc.regs.clear_interrupt().write(1);
+
+    let now = rtc.read_system_time();
+    let mut alarms = ALARMS.lock();
+    while alarms.last().map_or(false, |alarm| alarm.at <= now) {
+        alarms.pop().unwrap().waker.wake();
+    }
+    match alarms.last() {
+        Some(next) => rtc.set_alarm(next.at),
+        None => rtc.clear_alarm(),
     }
 }
 
@@ -55,8 +250,11 @@ pub trait TimeValue: Sized {
     fn from_unix_nanos(i: i128) -> Self;
 
     fn now_utc() -> Self {
-        let time = Goldfish::get().read_time();
-        Self::from_unix_nanos(time as i128)
+        Self::from_system_time(SystemTime::now())
+    }
+
+    fn from_system_time(time: SystemTime) -> Self {
+        Self::from_unix_nanos(time.unix_nanos() as i128)
     }
 }
 
@@ -65,3 +263,30 @@ impl TimeValue for OffsetDateTime {
         OffsetDateTime::from_unix_timestamp_nanos(i).expect("unix timestamp overflowed")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use core::time::Duration;
+
+    use super::*;
+
+    #[test_case]
+    fn system_time_follows_rtc() {
+        let rtc = Goldfish::get().read_system_time();
+        let now = SystemTime::now();
+        let skew = match now.duration_since(rtc) {
+            Ok(ahead) => ahead,
+            Err(behind) => behind.duration(),
+        };
+        assert!(skew < Duration::from_secs(1), "skew {:?}", skew);
+    }
+
+    #[test_case]
+    fn wake_at_alarm() {
+        let rtc = Goldfish::get();
+        let at = rtc.read_system_time() + Duration::from_millis(20);
+        crate::task::block_on(wake_at(at));
+        assert!(rtc.read_system_time() >= at);
+        assert!(ALARMS.lock().is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Futures for waiting on the clock: [`sleep_async`] and [`timeout`].
+//!
+//! Unlike [`time::sleep`](super::sleep) these don't stop the hart. The task is woken from
+//! the timer interrupt and the executor runs something else meanwhile.
+
+use core::{
+    fmt::{self, Display, Formatter},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use super::{timer::Timer, Instant};
+
+/// Future from [`sleep_async`] and [`sleep_until`].
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    /// Wakes whoever polled last, which is the waker kept with it.
+    timer: Option<(Timer, Waker)>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // Polled early. The timer is still waiting, so keep it unless the waker changed.
+        match &self.timer {
+            Some((_, waker)) if waker.will_wake(cx.waker()) => {}
+            _ => {
+                if let Some((timer, _)) = self.timer.take() {
+                    timer.cancel();
+                }
+                let timer = Timer::wake_at(self.deadline, cx.waker());
+                self.timer = Some((timer, cx.waker().clone()));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((timer, _)) = self.timer.take() {
+            timer.cancel();
+        }
+    }
+}
+
+/// Finish after `duration`.
+pub fn sleep_async(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Future from [`timeout`].
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// The future in a [`Timeout`] didn't finish in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl core::error::Error for Elapsed {}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `future` is never moved out of the pinned `Timeout`. `sleep` is Unpin.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Run `future`, giving up if it takes longer than `duration`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep_async(duration),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::block_on;
+
+    #[test_case]
+    fn sleep_and_timeout() {
+        let start = Instant::now();
+        block_on(sleep_async(Duration::from_millis(10)));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        let slow = timeout(
+            Duration::from_millis(1),
+            sleep_async(Duration::from_secs(10)),
+        );
+        assert_eq!(block_on(slow), Err(Elapsed));
+        let fast = timeout(Duration::from_secs(10), async { 7 });
+        assert_eq!(block_on(fast), Ok(7));
+    }
+}
@@ -0,0 +1,333 @@
+//! Timers, multiplexed onto each hart's one SBI timer.
+//!
+//! Every hart has a queue of deadlines and its timer is kept set for the earliest. When it
+//! goes off, [`fire`] runs everything that's due and sets it for the next one. With nothing
+//! sooner it's set [`TICK`] ahead, so the interrupt keeps coming.
+//!
+//! A [`Timer`] goes on the queue of the hart that made it. Its callback is run later, from
+//! the [`workqueue`](crate::workqueue) with interrupts on, unless it has to be run in the
+//! interrupt handler on that hart. Before the hart-local area is up everything uses hart
+//! 0's queue.
+//!
+//! A hart can only set its own SBI timer, so [`wake_hart_at`] puts a deadline on another
+//! hart's queue and sends it an IPI to set its timer. Every deadline goes through a queue,
+//! even ones that only want the interrupt, so that setting the timer for one never loses
+//! another.
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    task::Waker,
+    time::Duration,
+};
+
+use crate::{
+    hart_local,
+    prelude::*,
+    sbi::{
+        hart::{HartId, HartMask},
+        timer::TIMER_EXTENSION,
+        SbiResult,
+    },
+    smp::{self, MAX_HARTS},
+    sync::IrqSafeMutex,
+    workqueue::Work,
+};
+
+use super::Instant;
+
+/// Longest the timer is left unset for.
+pub const TICK: Duration = Duration::from_secs(1);
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+enum Action {
+    Wake(Waker),
+    /// Handed to [`CALLBACKS`] when it's due.
+    Defer(Callback),
+    /// Run in the interrupt handler.
+    Call(Callback),
+    /// Nothing but the interrupt, for a hart waiting in `wfi`.
+    Interrupt,
+}
+
+struct Entry {
+    deadline: Instant,
+    id: u64,
+    action: Action,
+}
+
+struct Queue {
+    /// Latest deadline first, so the next one due is popped off the end.
+    entries: Vec<Entry>,
+    /// What the SBI timer is set to, in mtime. `u64::MAX` for not set.
+    armed: u64,
+    /// Due [`Action::Defer`] callbacks, for [`CALLBACKS`]. Has room for every one still on
+    /// `entries` as well, so the interrupt handler never allocates.
+    expired: Vec<Callback>,
+    /// [`Action::Defer`]s on `entries`.
+    deferred: usize,
+}
+
+static QUEUES: [IrqSafeMutex<Queue>; MAX_HARTS] = [const {
+    IrqSafeMutex::new(Queue {
+        entries: Vec::new(),
+        armed: u64::MAX,
+        expired: Vec::new(),
+        deferred: 0,
+    })
+}; MAX_HARTS];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static CALLBACKS: Work = Work::new("timers", run_expired);
+
+fn this_hart() -> usize {
+    let hart = hart_local::try_current_hart().map_or(0, |hart| hart.0);
+    assert!(hart < MAX_HARTS, "no timer queue for hart {}", hart);
+    hart
+}
+
+impl Queue {
+    /// Set the timer for `deadline`, if it isn't set sooner already.
+    fn arm(&mut self, deadline: Instant) -> SbiResult<()> {
+        let mtime = deadline.to_mtime().expect("instant overflows mtime");
+        if mtime >= self.armed {
+            return Ok(());
+        }
+        TIMER_EXTENSION
+            .get()
+            .expect("no timer extension")
+            .set_timer(mtime)?;
+        self.armed = mtime;
+        Ok(())
+    }
+}
+
+/// A callback or waker waiting for its deadline. Dropping this leaves it waiting. Use
+/// [`cancel`](Self::cancel) to stop it.
+#[derive(Debug)]
+pub struct Timer {
+    id: u64,
+    hart: usize,
+    deadline: Instant,
+}
+
+impl Timer {
+    /// Call `callback` after `duration`, from the [`workqueue`](crate::workqueue).
+    pub fn after(duration: Duration, callback: impl FnOnce() + Send + 'static) -> Timer {
+        Self::at(Instant::now() + duration, callback)
+    }
+
+    pub fn at(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> Timer {
+        add(deadline, Action::Defer(Box::new(callback)))
+    }
+
+    /// Call `callback` after `duration`, from this hart's timer interrupt. For when it has
+    /// to be this hart, or can't wait.
+    pub fn after_in_interrupt(
+        duration: Duration,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> Timer {
+        add(Instant::now() + duration, Action::Call(Box::new(callback)))
+    }
+
+    /// Wake `waker` once `deadline` has passed.
+    pub(crate) fn wake_at(deadline: Instant, waker: &Waker) -> Timer {
+        add(deadline, Action::Wake(waker.clone()))
+    }
+
+    /// Have this hart's timer interrupt come at `deadline`, and do nothing else.
+    pub(crate) fn interrupt_at(deadline: Instant) -> Timer {
+        add(deadline, Action::Interrupt)
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Take it off the queue. Returns false if it had already gone off.
+    pub fn cancel(self) -> bool {
+        let mut queue = QUEUES[self.hart].lock();
+        match queue.entries.iter().position(|entry| entry.id == self.id) {
+            Some(at) => {
+                // The timer may still go off for it. `fire` just finds nothing due.
+                if let Action::Defer(_) = queue.entries.remove(at).action {
+                    queue.deferred -= 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn add(deadline: Instant, action: Action) -> Timer {
+    add_on(this_hart(), deadline, action)
+}
+
+fn add_on(hart: usize, deadline: Instant, action: Action) -> Timer {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut queue = QUEUES[hart].lock();
+    if let Action::Defer(_) = action {
+        queue.deferred += 1;
+        let room = queue.deferred;
+        queue.expired.reserve(room);
+    }
+    let at = queue
+        .entries
+        .partition_point(|entry| entry.deadline > deadline);
+    queue.entries.insert(
+        at,
+        Entry {
+            deadline,
+            id,
+            action,
+        },
+    );
+    let timer = Timer { id, hart, deadline };
+    if hart == this_hart() {
+        queue.arm(deadline).expect("failed to set timer");
+    } else if deadline
+        .to_mtime()
+        .map_or(false, |mtime| mtime < queue.armed)
+    {
+        drop(queue);
+        let mut target = HartMask::new();
+        target.set_id(HartId(hart));
+        // Not waited for. It's set when the IPI gets there.
+        smp::call_on(target, move || {
+            QUEUES[hart]
+                .lock()
+                .arm(deadline)
+                .expect("failed to set timer");
+        });
+    }
+    timer
+}
+
+/// Have `hart`'s timer interrupt come by `deadline`, waking it if it's idle. Any online
+/// hart, this one included.
+pub fn wake_hart_at(hart: HartId, deadline: Instant) -> Timer {
+    assert!(hart.0 < MAX_HARTS, "no timer queue for hart {}", hart.0);
+    add_on(hart.0, deadline, Action::Interrupt)
+}
+
+/// Make sure this hart's timer goes off by `deadline`. Not kept once it has, so only for
+/// starting the tick. Anything else wants a [`Timer`].
+pub(crate) fn arm(deadline: Instant) -> SbiResult<()> {
+    QUEUES[this_hart()].lock().arm(deadline)
+}
+
+/// Run everything that's due on this hart and set the timer for what's next. Called from
+/// the timer interrupt.
+pub(crate) fn fire() {
+    let queue = &QUEUES[this_hart()];
+    let now = Instant::now();
+    let mut deferred = false;
+    loop {
+        // Not held while running them, they might add timers.
+        let entry = {
+            let mut queue = queue.lock();
+            if !queue
+                .entries
+                .last()
+                .map_or(false, |entry| entry.deadline <= now)
+            {
+                break;
+            }
+            queue.entries.pop().unwrap()
+        };
+        match entry.action {
+            Action::Wake(waker) => waker.wake(),
+            Action::Defer(callback) => {
+                let mut queue = queue.lock();
+                queue.deferred -= 1;
+                queue.expired.push(callback);
+                deferred = true;
+            }
+            Action::Call(callback) => callback(),
+            Action::Interrupt => {}
+        }
+    }
+    if deferred {
+        CALLBACKS.schedule();
+    }
+
+    let mut queue = queue.lock();
+    // It's gone off, so whatever it was set to is past.
+    queue.armed = u64::MAX;
+    let tick = now + TICK;
+    let next = match queue.entries.last() {
+        Some(entry) if entry.deadline < tick => entry.deadline,
+        _ => tick,
+    };
+    queue.arm(next).expect("failed to set timer");
+}
+
+/// Run the callbacks that are due, from every hart's queue.
+fn run_expired() {
+    for queue in &QUEUES {
+        let expired: Vec<Callback> = queue.lock().expired.drain(..).collect();
+        for callback in expired {
+            callback();
+        }
+    }
+}
+
+/// Get [`CALLBACKS`] ready. Before any [`Timer`] is made.
+pub(crate) fn init() {
+    CALLBACKS.register();
+}
+
+/// When this hart's timer is next set to go off.
+pub(crate) fn next_deadline() -> Option<Instant> {
+    let armed = QUEUES[this_hart()].lock().armed;
+    (armed != u64::MAX).then(|| Instant::from_mtime(armed))
+}
+
+/// Number of timers waiting on this hart.
+pub fn pending() -> usize {
+    QUEUES[this_hart()].lock().entries.len()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    use super::*;
+    use crate::{task::block_on, time::sleep_async};
+
+    #[test_case]
+    fn timer_after_and_cancel() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let flag = fired.clone();
+        let timer = Timer::after(Duration::from_millis(1), move || {
+            flag.store(true, Ordering::Relaxed)
+        });
+        let cancelled = Timer::after(Duration::from_millis(1), || panic!("cancelled timer ran"));
+        assert!(cancelled.cancel());
+
+        block_on(sleep_async(Duration::from_millis(5)));
+        assert!(fired.load(Ordering::Relaxed));
+        assert!(!timer.cancel());
+    }
+
+    #[test_case]
+    fn wake_this_hart() {
+        let me = crate::hart_local::current_hart();
+        let timer = wake_hart_at(me, Instant::now() + Duration::from_millis(1));
+        block_on(sleep_async(Duration::from_millis(5)));
+        assert!(!timer.cancel());
+    }
+
+    #[test_case]
+    fn sleep_alongside_timers() {
+        // The timer going off first used to leave the sleep waiting for the next tick.
+        let start = Instant::now();
+        let _sooner = Timer::after(Duration::from_millis(1), || {});
+        crate::time::sleep(Duration::from_millis(5));
+        let slept = start.elapsed();
+        assert!(slept >= Duration::from_millis(5));
+        assert!(slept < TICK / 2, "slept for {:?}", slept);
+    }
+}
@@ -0,0 +1,76 @@
+//! Keeping TLBs in step with the page tables.
+//!
+//! `sfence.vma` only reaches the hart that runs it. When a mapping goes away or loses
+//! permissions, [`shootdown`] flushes it here and has SBI's RFENCE extension flush it on
+//! every other online hart too.
+
+use core::{arch::asm, ops::Range};
+
+use crate::{
+    log,
+    pagetable::PAGE_SIZE,
+    sbi::rfence::{rfence_extension, FLUSH_ALL},
+    smp,
+};
+
+/// Past this many pages it's cheaper to flush the whole address space.
+const MAX_PAGE_FLUSHES: u64 = 64;
+
+/// Flush the pages covering `range` from every hart's TLB. With an `asid`, only that
+/// address space's entries go. Without one, every address space's do, global mappings
+/// included.
+pub fn shootdown(range: Range<u64>, asid: Option<u16>) {
+    let start = range.start & !(PAGE_SIZE - 1);
+    let pages = range.end.saturating_sub(start).div_ceil(PAGE_SIZE);
+    if pages == 0 {
+        return;
+    }
+    let whole = pages > MAX_PAGE_FLUSHES;
+
+    if whole {
+        flush_local_all(asid);
+    } else {
+        for page in 0..pages {
+            flush_local(start + page * PAGE_SIZE, asid);
+        }
+    }
+
+    let others = smp::other_harts();
+    if others.is_empty() {
+        return;
+    }
+    let (start, size) = if whole {
+        (0, FLUSH_ALL)
+    } else {
+        (start as usize, (pages * PAGE_SIZE) as usize)
+    };
+    let rfence = rfence_extension();
+    let result = match asid {
+        Some(asid) => rfence.remote_sfence_vma_asid(others, start, size, asid as usize),
+        None => rfence.remote_sfence_vma(others, start, size),
+    };
+    if let Err(err) = result {
+        log::error!("remote sfence.vma failed: {}", err);
+    }
+}
+
+fn flush_local(va: u64, asid: Option<u16>) {
+    unsafe {
+        match asid {
+            Some(asid) => {
+                asm!("sfence.vma {va}, {asid}", va = in(reg) va, asid = in(reg) asid as u64)
+            }
+            None => asm!("sfence.vma {va}, zero", va = in(reg) va),
+        }
+    }
+}
+
+/// Flush this hart's TLB, for address space `asid` or all of them.
+pub fn flush_local_all(asid: Option<u16>) {
+    unsafe {
+        match asid {
+            Some(asid) => asm!("sfence.vma zero, {asid}", asid = in(reg) asid as u64),
+            None => asm!("sfence.vma"),
+        }
+    }
+}
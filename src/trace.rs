@@ -0,0 +1,327 @@
+//! Event tracing.
+//!
+//! [`trace!`] records an [`Event`], with up to two numbers, in a ring buffer for the hart
+//! it's on. Each ring keeps the last [`RING_SIZE`] events. It's off until
+//! [`enable`]d, and costs a load and a branch until then. Enabling gives each online hart a
+//! ring if it doesn't have one, and they're kept after, so a hart that comes online later
+//! needs it enabling again.
+//!
+//! Recording never locks or allocates, so it's fine in trap handlers and the allocator. An
+//! event can be overwritten while it's being written, or read half written, if tracing's on
+//! when it's read, so [`snapshot`] turns it off while it copies the rings. `trace` in the
+//! shell prints them, as text or as JSON for Chrome's trace viewer (or Perfetto).
+
+use alloc::boxed::Box;
+use core::{
+    fmt::{self, Write},
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    hart_local::{online_harts, try_current_hart},
+    prelude::*,
+    sbi::hart::HartId,
+    smp::MAX_HARTS,
+    time::Instant,
+};
+
+/// Events each hart keeps.
+pub const RING_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Event {
+    /// A trap from S-mode: scause, sepc.
+    TrapEnter = 1,
+    /// Back from one: scause, sepc.
+    TrapExit,
+    /// A trap from U-mode: scause, pc.
+    UserTrapEnter,
+    /// Going back to U-mode: pid.
+    UserTrapExit,
+    /// Switching to a process (1) or back from it (0): pid, direction.
+    Switch,
+    /// Polling a task: task id.
+    Poll,
+    /// An SBI call returned: extension, function.
+    SbiCall,
+    /// Size, address.
+    Alloc,
+    /// Size, address.
+    Free,
+}
+
+impl Event {
+    pub const ALL: [Event; 9] = [
+        Event::TrapEnter,
+        Event::TrapExit,
+        Event::UserTrapEnter,
+        Event::UserTrapExit,
+        Event::Switch,
+        Event::Poll,
+        Event::SbiCall,
+        Event::Alloc,
+        Event::Free,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::TrapEnter => "trap_enter",
+            Event::TrapExit => "trap_exit",
+            Event::UserTrapEnter => "user_trap_enter",
+            Event::UserTrapExit => "user_trap_exit",
+            Event::Switch => "switch",
+            Event::Poll => "poll",
+            Event::SbiCall => "sbi_call",
+            Event::Alloc => "alloc",
+            Event::Free => "free",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Event> {
+        Event::ALL.into_iter().find(|&event| event as u8 == value)
+    }
+}
+
+/// One recorded event. `time` is zero until it's first written.
+#[derive(Default)]
+struct Slot {
+    time: AtomicU64,
+    event: AtomicU8,
+    args: [AtomicU64; 2],
+}
+
+struct Ring {
+    /// Where the next event goes, mod [`RING_SIZE`].
+    next: AtomicUsize,
+    slots: Box<[Slot]>,
+}
+
+impl Ring {
+    fn new() -> Ring {
+        Ring {
+            next: AtomicUsize::new(0),
+            // Built in place. As an array it'd be on the stack first.
+            slots: (0..RING_SIZE).map(|_| Slot::default()).collect(),
+        }
+    }
+}
+
+/// Each hart's ring, once tracing's been enabled. Never freed.
+static RINGS: [AtomicPtr<Ring>; MAX_HARTS] = [const { AtomicPtr::new(null_mut()) }; MAX_HARTS];
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether [`trace!`] records anything.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Start recording, on every online hart.
+pub fn enable() {
+    let online = online_harts();
+    for hart in (0..MAX_HARTS).filter(|&hart| online.contains(HartId(hart))) {
+        if RINGS[hart].load(Ordering::Acquire).is_null() {
+            let ring = Box::into_raw(Box::new(Ring::new()));
+            if RINGS[hart]
+                .compare_exchange(null_mut(), ring, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                drop(unsafe { Box::from_raw(ring) });
+            }
+        }
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+fn ring(hart: usize) -> Option<&'static Ring> {
+    // Never freed once it's there.
+    unsafe { RINGS.get(hart)?.load(Ordering::Acquire).as_ref() }
+}
+
+/// Record `event` for this hart. Use [`trace!`], which checks [`enabled`] first.
+pub fn record(event: Event, a: u64, b: u64) {
+    let Some(ring) = try_current_hart().and_then(|hart| ring(hart.0)) else {
+        return;
+    };
+    let slot = &ring.slots[ring.next.fetch_add(1, Ordering::Relaxed) % RING_SIZE];
+    slot.event.store(event as u8, Ordering::Relaxed);
+    slot.args[0].store(a, Ordering::Relaxed);
+    slot.args[1].store(b, Ordering::Relaxed);
+    slot.time
+        .store(riscv::register::time::read() as u64, Ordering::Release);
+}
+
+/// Forget everything recorded.
+pub fn clear() {
+    let was = ENABLED.swap(false, Ordering::AcqRel);
+    for ring in (0..MAX_HARTS).filter_map(ring) {
+        for slot in ring.slots.iter() {
+            slot.time.store(0, Ordering::Relaxed);
+        }
+        ring.next.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(was, Ordering::Release);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub hart: HartId,
+    /// Since the clock started.
+    pub at: Duration,
+    pub event: Event,
+    pub args: [u64; 2],
+}
+
+/// Every hart's events, oldest first. Tracing's off while they're copied.
+pub fn snapshot() -> Vec<Record> {
+    let was = ENABLED.swap(false, Ordering::AcqRel);
+    let mut records = Vec::new();
+    for hart in 0..MAX_HARTS {
+        let Some(ring) = ring(hart) else {
+            continue;
+        };
+        for slot in ring.slots.iter() {
+            let time = slot.time.load(Ordering::Acquire);
+            let event = Event::from_u8(slot.event.load(Ordering::Relaxed));
+            if let (true, Some(event)) = (time != 0, event) {
+                records.push((
+                    time,
+                    hart,
+                    event,
+                    slot.args.each_ref().map(|arg| arg.load(Ordering::Relaxed)),
+                ));
+            }
+        }
+    }
+    ENABLED.store(was, Ordering::Release);
+
+    records.sort_unstable_by_key(|&(time, hart, _, _)| (time, hart));
+    let start = Instant::time_started();
+    records
+        .into_iter()
+        .map(|(time, hart, event, args)| Record {
+            hart: HartId(hart),
+            at: Instant::from_mtime(time).saturating_duration_since(start),
+            event,
+            args,
+        })
+        .collect()
+}
+
+/// A line per record: `seconds hart event args`.
+pub fn write_text(out: &mut impl Write, records: &[Record]) -> fmt::Result {
+    for record in records {
+        writeln!(
+            out,
+            "{:>5}.{:06} {:>2} {:<15} {:#x} {:#x}",
+            record.at.as_secs(),
+            record.at.subsec_micros(),
+            record.hart.0,
+            record.event.name(),
+            record.args[0],
+            record.args[1]
+        )?;
+    }
+    Ok(())
+}
+
+/// Chrome's trace event format. Traps are spans, the rest are instants, and each hart is a
+/// thread.
+pub fn write_json(out: &mut impl Write, records: &[Record]) -> fmt::Result {
+    writeln!(out, "{{\"traceEvents\":[")?;
+    for (i, record) in records.iter().enumerate() {
+        let phase = match record.event {
+            Event::TrapEnter | Event::UserTrapEnter => "B",
+            Event::TrapExit | Event::UserTrapExit => "E",
+            _ => "i",
+        };
+        let name = match record.event {
+            Event::TrapEnter | Event::TrapExit => "trap",
+            Event::UserTrapEnter | Event::UserTrapExit => "user_trap",
+            event => event.name(),
+        };
+        writeln!(
+            out,
+            "{{\"name\":\"{}\",\"ph\":\"{}\",\"ts\":{}.{:03},\"pid\":0,\"tid\":{},\
+             \"s\":\"t\",\"args\":{{\"a\":{},\"b\":{}}}}}{}",
+            name,
+            phase,
+            record.at.as_micros(),
+            record.at.subsec_nanos() % 1000,
+            record.hart.0,
+            record.args[0],
+            record.args[1],
+            if i + 1 < records.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "]}}")
+}
+
+/// Record an event, if tracing's on: `trace!(Poll, id)`. Missing args are 0.
+macro_rules! __trace {
+    ($event:ident) => {
+        $crate::trace::trace!($event, 0, 0)
+    };
+    ($event:ident, $a:expr) => {
+        $crate::trace::trace!($event, $a, 0)
+    };
+    ($event:ident, $a:expr, $b:expr) => {
+        if $crate::trace::enabled() {
+            $crate::trace::record($crate::trace::Event::$event, $a as u64, $b as u64)
+        }
+    };
+}
+
+pub(crate) use __trace as trace;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::isr::without_interrupts;
+
+    #[test_case]
+    fn record_and_dump() {
+        let was = enabled();
+        enable();
+        // Or the timer could get an SBI call in between.
+        without_interrupts(|| {
+            trace!(SbiCall, 0x10, 3);
+            trace!(Poll, 42);
+        });
+        let records = snapshot();
+        if !was {
+            disable();
+        }
+        assert_eq!(enabled(), was);
+
+        let hart = try_current_hart().unwrap();
+        let mine: Vec<Record> = records
+            .into_iter()
+            .filter(|record| {
+                record.hart == hart && matches!(record.event, Event::SbiCall | Event::Poll)
+            })
+            .collect();
+        let last = &mine[mine.len() - 2..];
+        assert_eq!((last[0].event, last[0].args), (Event::SbiCall, [0x10, 3]));
+        assert_eq!((last[1].event, last[1].args), (Event::Poll, [42, 0]));
+        assert!(last[0].at <= last[1].at);
+
+        let mut text = String::new();
+        write_text(&mut text, last).unwrap();
+        assert!(text
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with(" sbi_call        0x10 0x3"));
+        let mut json = String::new();
+        write_json(&mut json, last).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":[\n{\"name\":\"sbi_call\",\"ph\":\"i\""));
+        assert!(json.contains("\"args\":{\"a\":42,\"b\":0}}\n]}"));
+    }
+}
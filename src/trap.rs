@@ -1,20 +1,46 @@
-use core::fmt::{Debug, Write};
+use alloc::format;
+use core::{
+    cell::{RefCell, UnsafeCell},
+    fmt::{self, Debug, Write},
+};
 
 use riscv::register::{
     scause::{self, Trap},
-    sepc, sie, sstatus, stval,
+    sepc, sie, sscratch,
+    sstatus::{self, SPP},
+    stval,
 };
 
+use crate::addr::VirtAddr;
 use crate::console::{self, LockOrDummy};
+use crate::log;
+use crate::oops;
+use crate::pagetable;
+use crate::panic;
+use crate::sbi::hart::HartId;
+use crate::smp::MAX_HARTS;
 use crate::isr::Sip;
+use crate::prelude::*;
+use crate::process::{self, Access, TrapFrame};
+use crate::stack::{self, KernelStack};
+use crate::sync::epoch;
+use crate::syscall;
+use crate::trace::trace;
+use crate::watchdog;
+use crate::workqueue;
+
+pub mod debugger;
+pub mod gdbstub;
+pub mod misaligned;
 
-/// Registers saved to stack on
+/// Registers saved by [`trap_entry`](crate::asm::trap_entry), in register number order
+/// with `pc` in place of `x0`.
 #[repr(C)]
 pub struct TrapRegisters {
-    /// Informative. Won't be restored on trap return. Use sepc
+    /// `sepc`. Where the trap returns to, so change this to skip the instruction.
     pub pc: u64,
     pub ra: u64,
-    /// Informative. Won't be restored on trap return.
+    /// Where the trap came in, not the trap stack.
     pub sp: u64,
     pub gp: u64,
     pub tp: u64,
@@ -45,6 +71,190 @@ pub struct TrapRegisters {
     pub t4: u64,
     pub t5: u64,
     pub t6: u64,
+    /// Put back on return, in case a nested trap changed it.
+    sstatus: u64,
+    _align: u64,
+}
+
+impl TrapRegisters {
+    pub(crate) const SSTATUS: usize = 32 * 8;
+    /// A multiple of 16, so `sp` stays aligned.
+    pub(crate) const SIZE: usize = 34 * 8;
+
+    /// `pc` then `x1`-`x31`.
+    fn as_array(&self) -> &[u64; 32] {
+        unsafe { &*(self as *const Self as *const [u64; 32]) }
+    }
+
+    fn as_array_mut(&mut self) -> &mut [u64; 32] {
+        unsafe { &mut *(self as *mut Self as *mut [u64; 32]) }
+    }
+}
+
+impl misaligned::Registers for TrapRegisters {
+    fn x(&self, n: usize) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.as_array()[n]
+        }
+    }
+
+    fn set_x(&mut self, n: usize, value: u64) {
+        if n != 0 {
+            self.as_array_mut()[n] = value;
+        }
+    }
+
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+}
+
+/// ABI register names, by number. `pc` stands in for `x0`, as in [`TrapRegisters`].
+pub(crate) const REGISTER_NAMES: [&str; 32] = [
+    "pc", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The number of the register called `name`, with 0 for `pc`. `fp` and `xN` work too.
+pub(crate) fn register_number(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(n) = name.strip_prefix('x').and_then(|n| n.parse().ok()) {
+        return (1..32).contains(&n).then_some(n);
+    }
+    REGISTER_NAMES.iter().position(|&register| register == name)
+}
+
+/// Print `pc` and `x1`-`x31`, one a line.
+pub(crate) fn print_registers(w: &mut dyn Write, regs: &impl misaligned::Registers) -> fmt::Result {
+    for (n, name) in REGISTER_NAMES.iter().enumerate() {
+        let value = if n == 0 { regs.pc() } else { regs.x(n) };
+        writeln!(w, "  {:<5} = 0x{:x}", name, value)?;
+    }
+    Ok(())
+}
+
+/// What [`trap_entry`](crate::asm::trap_entry) finds through `sscratch`. One per hart.
+#[repr(C)]
+pub(crate) struct TrapContext {
+    /// Where `t1` is parked while working out where the registers go.
+    scratch: u64,
+    /// Top of the trap stack. 0 until there is one, and kernel traps carry on down the
+    /// stack they land on.
+    trap_sp: u64,
+    /// Kernel traps being handled.
+    depth: u64,
+    /// The running process's trap frame, while it's in U-mode.
+    user_frame: u64,
+}
+
+impl TrapContext {
+    pub(crate) const SCRATCH: usize = 0;
+    pub(crate) const TRAP_SP: usize = 8;
+    pub(crate) const DEPTH: usize = 2 * 8;
+    pub(crate) const USER_FRAME: usize = 3 * 8;
+}
+
+struct Contexts([UnsafeCell<TrapContext>; MAX_HARTS]);
+
+unsafe impl Sync for Contexts {}
+
+static CONTEXTS: Contexts = Contexts(
+    [const {
+        UnsafeCell::new(TrapContext {
+            scratch: 0,
+            trap_sp: 0,
+            depth: 0,
+            user_frame: 0,
+        })
+    }; MAX_HARTS],
+);
+
+/// How many kernel traps deep [`trap`] can be. One trap, and one more while handling it.
+pub(crate) const MAX_DEPTH: usize = 2;
+
+/// Size of each hart's trap stack.
+const TRAP_STACK_SIZE: u64 = 16 * 1024;
+
+crate::hart_local! {
+    /// Where [`trap`] runs, whatever state the stack it came from is in.
+    static TRAP_STACK: RefCell<Option<KernelStack>> = RefCell::new(None);
+}
+
+/// Point `sscratch` at this hart's [`TrapContext`] and give it a trap stack. Has to be
+/// done before `stvec` points at [`trap_entry`](crate::asm::trap_entry).
+pub(crate) fn init_hart(hart: HartId) {
+    assert!(hart.0 < MAX_HARTS, "no trap context for hart {}", hart);
+    let context = CONTEXTS.0[hart.0].get();
+    sscratch::write(context as usize);
+    match KernelStack::new(TRAP_STACK_SIZE, format!("hart {}'s trap stack", hart)) {
+        Ok(stack) => {
+            unsafe { (*context).trap_sp = stack.top() };
+            *TRAP_STACK.get().borrow_mut() = Some(stack);
+        }
+        Err(err) => log::warn!("no trap stack for hart {}: {}", hart, err),
+    }
+}
+
+/// This hart's context. Only touched by this hart, with interrupts off or from a trap.
+fn context() -> *mut TrapContext {
+    sscratch::read() as *mut TrapContext
+}
+
+/// Where the next trap from U-mode saves the registers.
+///
+/// # Safety
+/// `frame` stays put until then, and [`init_hart`] has been run.
+pub(crate) unsafe fn set_user_frame(frame: *mut TrapFrame) {
+    (*context()).user_frame = frame as u64;
+}
+
+/// How many kernel traps this hart is in.
+pub(crate) fn depth() -> usize {
+    match context() {
+        context if context.is_null() => 0,
+        context => unsafe { (*context).depth as usize },
+    }
+}
+
+/// Forget the traps this hart was in past `depth`, when whatever was running in them has
+/// been abandoned for good.
+///
+/// # Safety
+/// Nothing will return to those traps.
+pub(crate) unsafe fn abandon_traps(depth: usize) {
+    let context = context();
+    if !context.is_null() {
+        (*context).depth = depth as u64;
+    }
+}
+
+/// Whether a trap here would come back: `sscratch` is set up, and there's room for one
+/// more.
+pub(crate) fn can_trap() -> bool {
+    !context().is_null() && depth() < MAX_DEPTH
+}
+
+/// Where [`trap_entry`](crate::asm::trap_entry) goes for a trap past [`MAX_DEPTH`], at the
+/// top of the trap stack.
+pub(crate) extern "C" fn trap_too_deep() -> ! {
+    if panic::panicking() {
+        panic::park();
+    }
+    panic!(
+        "{:?} at 0x{:x} inside a nested trap, stval 0x{:x}",
+        scause::read().cause(),
+        sepc::read(),
+        stval::read()
+    );
 }
 
 impl Debug for TrapRegisters {
@@ -86,6 +296,113 @@ impl Debug for TrapRegisters {
     }
 }
 
+#[allow(unused_must_use)]
+fn interrupt(int: scause::Interrupt, stval: usize, mut w: LockOrDummy) {
+    match int {
+        scause::Interrupt::UserSoft => {
+            writeln!(w, "USER SOFTWARE INTERRUPT: {:x}", stval);
+        }
+        scause::Interrupt::SupervisorSoft => {
+            crate::smp::handle_ipi();
+        }
+        scause::Interrupt::UserTimer => {
+            writeln!(w, "USER TIMER: {:x}", stval);
+        }
+        scause::Interrupt::SupervisorTimer => {
+            crate::time::interrupt_handler(w);
+        }
+        scause::Interrupt::UserExternal => {
+            writeln!(w, "USER EXTERNAL INTERRUPT: {:x}", stval);
+        }
+        scause::Interrupt::SupervisorExternal => {
+            crate::isr::plic::process_interrupt();
+        }
+        scause::Interrupt::Unknown => {
+            writeln!(w, "Unknown interrupt: {:x}", stval);
+        }
+    }
+}
+
+/// Traps from U-mode. Entered from [`trap_entry`](crate::asm::trap_entry) on the process's
+/// kernel stack, with the user registers in `frame`.
+pub(crate) extern "C" fn user_trap(frame: &mut TrapFrame) -> ! {
+    watchdog::record_user_trap(frame);
+    let scause = scause::read();
+    trace!(UserTrapEnter, scause.bits(), frame.pc);
+    let stval = stval::read();
+    match scause.cause() {
+        Trap::Interrupt(int) => {
+            interrupt(int, stval, LockOrDummy::Dummy);
+            // Whatever the handler left for later. return_to_user turns interrupts off again.
+            unsafe { sstatus::set_sie() };
+            workqueue::run_pending();
+        }
+        Trap::Exception(scause::Exception::UserEnvCall) => {
+            // Resume after the ecall.
+            frame.pc += 4;
+            // Syscalls can block waiting on interrupts. return_to_user turns them off again.
+            unsafe { sstatus::set_sie() };
+            syscall::dispatch(frame);
+        }
+        Trap::Exception(_) if misaligned::is_misaligned(&scause) && emulate_user(frame) => {}
+        Trap::Exception(ex) if page_fault(ex, stval) => {}
+        Trap::Exception(ex) => {
+            if let Some(process) = process::current() {
+                println!(
+                    "{}: {:?} at 0x{:x}, stval 0x{:x}. Killed",
+                    process, ex, frame.pc, stval
+                );
+                if page_fault_access(ex).is_some() {
+                    match VirtAddr::try_new(stval as u64).and_then(pagetable::translate_current) {
+                        Some(found) => println!("  stval maps to {}", found),
+                        None => println!("  stval isn't mapped"),
+                    }
+                }
+                if ex == scause::Exception::Breakpoint {
+                    print_registers(&mut console::lock(), frame).ok();
+                }
+            }
+            process::exit(-1);
+        }
+    }
+
+    process::check_signals();
+    // Nothing's reading from an `Rcu` on this hart between here and U-mode.
+    epoch::quiescent();
+    trace!(UserTrapExit, process::current().map_or(0, |process| process.pid().0));
+    process::return_to_user()
+}
+
+/// Do a misaligned load or store for the current process. `true` if it worked.
+fn emulate_user(frame: &mut TrapFrame) -> bool {
+    let process = process::current().unwrap();
+    let mut memory = process.memory();
+    misaligned::emulate(frame, &mut *memory).is_ok()
+}
+
+/// Let the current process's memory deal with a page fault. `true` if it did.
+fn page_fault(ex: scause::Exception, stval: usize) -> bool {
+    let access = match page_fault_access(ex) {
+        Some(access) => access,
+        None => return false,
+    };
+    process::current()
+        .unwrap()
+        .memory()
+        .fault(stval as u64, access)
+        .is_ok()
+}
+
+/// What a page fault was trying to do. `None` if `ex` isn't one.
+fn page_fault_access(ex: scause::Exception) -> Option<Access> {
+    match ex {
+        scause::Exception::LoadPageFault => Some(Access::Read),
+        scause::Exception::StorePageFault => Some(Access::Write),
+        scause::Exception::InstructionPageFault => Some(Access::Execute),
+        _ => None,
+    }
+}
+
 #[allow(unused_must_use)]
 pub(crate) extern "C" fn trap(registers: &mut TrapRegisters) {
     let sepc = sepc::read();
@@ -94,6 +411,8 @@ pub(crate) extern "C" fn trap(registers: &mut TrapRegisters) {
     let sip = Sip::read();
     let scause = scause::read();
     let stval = stval::read();
+    watchdog::record_trap(sepc, registers);
+    trace!(TrapEnter, scause.bits(), sepc);
 
     let mut w = LockOrDummy::Dummy;
 
@@ -104,31 +423,30 @@ pub(crate) extern "C" fn trap(registers: &mut TrapRegisters) {
     writeln!(w, "scause: {:?}", scause.cause());
     writeln!(w, "stval: {:?}", stval);
 
+    if sstatus.spp() == SPP::User {
+        panic!("U-mode trap in the kernel's trap handler");
+    }
+
     match scause.cause() {
-        Trap::Interrupt(int) => match int {
-            scause::Interrupt::UserSoft => {
-                writeln!(w, "USER SOFTWARE INTERRUPT: {:x}", stval);
-            }
-            scause::Interrupt::SupervisorSoft => {
-                writeln!(w, "SUPERVISOR SOFTWARE INTERRUPT: {:x}", stval);
+        Trap::Interrupt(int) => {
+            interrupt(int, stval, w);
+            if gdbstub::take_attach() {
+                debugger::interrupted(registers);
             }
-            scause::Interrupt::UserTimer => {
-                writeln!(w, "USER TIMER: {:x}", stval);
-            }
-            scause::Interrupt::SupervisorTimer => {
-                crate::time::interrupt_handler(w, registers);
-            }
-            scause::Interrupt::UserExternal => {
-                writeln!(w, "USER EXTERNAL INTERRUPT: {:x}", stval);
-            }
-            scause::Interrupt::SupervisorExternal => {
-                writeln!(w, "SUPERVISOR EXTERNAL INTERRUPT: {:x}", stval);
-            }
-            scause::Interrupt::Unknown => {
-                writeln!(w, "Unknown interrupt: {:x}", stval);
+        }
+        Trap::Exception(scause::Exception::Breakpoint) => debugger::breakpoint(registers),
+        Trap::Exception(_) if misaligned::is_misaligned(&scause) => {
+            if let Err(err) = misaligned::emulate(registers, &mut misaligned::KernelMemory) {
+                panic!("Misaligned access at 0x{:x}: {}", sepc, err);
             }
-        },
+        }
         Trap::Exception(ex) => {
+            let overflow = match ex {
+                scause::Exception::LoadPageFault | scause::Exception::StorePageFault => {
+                    stack::overflowed(stval as u64)
+                }
+                _ => None,
+            };
             let mut console = unsafe { console::force_unlock() };
             writeln!(console, "*** EXCEPTION ***").ok();
             writeln!(console, "sepc    = 0x{:x}", sepc).ok();
@@ -151,45 +469,41 @@ pub(crate) extern "C" fn trap(registers: &mut TrapRegisters) {
             writeln!(console, " .code  = {:?}", scause.code()).ok();
             writeln!(console, " .cause = {:?}", scause.cause()).ok();
             writeln!(console, "stval   = 0x{:x}", stval).ok();
-            writeln!(console, "registers:").ok();
-            writeln!(console, "  pc    = 0x{:x}", registers.pc);
-            writeln!(console, "  ra    = 0x{:x}", registers.ra);
-            writeln!(console, "  sp    = 0x{:x}", registers.sp);
-            writeln!(console, "  gp    = 0x{:x}", registers.gp);
-            writeln!(console, "  tp    = 0x{:x}", registers.tp);
-            writeln!(console, "  t0    = 0x{:x}", registers.t0);
-            writeln!(console, "  t1    = 0x{:x}", registers.t1);
-            writeln!(console, "  t2    = 0x{:x}", registers.t2);
-            writeln!(console, "  s0    = 0x{:x}", registers.s0);
-            writeln!(console, "  s1    = 0x{:x}", registers.s1);
-            writeln!(console, "  a0    = 0x{:x}", registers.a0);
-            writeln!(console, "  a1    = 0x{:x}", registers.a1);
-            writeln!(console, "  a2    = 0x{:x}", registers.a2);
-            writeln!(console, "  a3    = 0x{:x}", registers.a3);
-            writeln!(console, "  a4    = 0x{:x}", registers.a4);
-            writeln!(console, "  a5    = 0x{:x}", registers.a5);
-            writeln!(console, "  a6    = 0x{:x}", registers.a6);
-            writeln!(console, "  a7    = 0x{:x}", registers.a7);
-            writeln!(console, "  s2    = 0x{:x}", registers.s2);
-            writeln!(console, "  s3    = 0x{:x}", registers.s3);
-            writeln!(console, "  s4    = 0x{:x}", registers.s4);
-            writeln!(console, "  s5    = 0x{:x}", registers.s5);
-            writeln!(console, "  s6    = 0x{:x}", registers.s6);
-            writeln!(console, "  s7    = 0x{:x}", registers.s7);
-            writeln!(console, "  s8    = 0x{:x}", registers.s8);
-            writeln!(console, "  s9    = 0x{:x}", registers.s9);
-            writeln!(console, "  s10   = 0x{:x}", registers.s10);
-            writeln!(console, "  s11   = 0x{:x}", registers.s11);
-            writeln!(console, "  t3    = 0x{:x}", registers.t3);
-            writeln!(console, "  t4    = 0x{:x}", registers.t4);
-            writeln!(console, "  t5    = 0x{:x}", registers.t5);
-            writeln!(console, "  t6    = 0x{:x}", registers.t6);
-
+            if page_fault_access(ex).is_some() {
+                match VirtAddr::try_new(stval as u64).and_then(pagetable::translate_current) {
+                    Some(found) => writeln!(console, " maps to {}", found).ok(),
+                    None => writeln!(console, " isn't mapped").ok(),
+                };
+            }
+            writeln!(console, "depth   = {}", depth()).ok();
             let instruction = unsafe { *(sepc as *const u32) };
-            writeln!(console, "pc      = 0x{:x}", sepc).ok();
             writeln!(console, "ins     = 0x{:08x}", instruction).ok();
+            // The panic's oops has the registers, and a backtrace from here.
+            oops::record_trap(scause.bits(), stval, registers);
 
+            if let Some(owner) = overflow {
+                panic!("Kernel stack overflow in {}: {:?} at 0x{:x}", owner, ex, stval);
+            }
             panic!("Supervisor exception {:?}", ex);
         }
     }
+    trace!(TrapExit, scause.bits(), sepc);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn trap_registers_layout() {
+        let registers: TrapRegisters = unsafe { core::mem::zeroed() };
+        let offset = |field: &u64| field as *const u64 as usize - &registers as *const _ as usize;
+        assert_eq!(offset(&registers.sp), 2 * 8);
+        assert_eq!(offset(&registers.t0), 5 * 8);
+        assert_eq!(offset(&registers.t6), 31 * 8);
+        assert_eq!(offset(&registers.sstatus), TrapRegisters::SSTATUS);
+        assert_eq!(core::mem::size_of::<TrapRegisters>(), TrapRegisters::SIZE);
+        assert_eq!(TrapRegisters::SIZE % 16, 0);
+        assert_eq!(depth(), 0);
+    }
 }
@@ -0,0 +1,682 @@
+//! A small kernel debugger.
+//!
+//! An `ebreak` in the kernel comes to [`breakpoint`], which prints the registers. One built
+//! into the code is stepped over unless `kdb` is on the command line, or the shell's `kdb`
+//! put it there. Breakpoints from [`set`] patch an `ebreak` over an instruction in `.text`.
+//! Either way the hart then stops at a `kdb>` prompt with commands to look at memory,
+//! change registers, set breakpoints, step and carry on.
+//!
+//! There's no hardware single step in S-mode, so stepping works out where the instruction
+//! goes from the registers and puts a breakpoint there. A breakpoint being stepped over is
+//! lifted until then, and other harts don't stop at it meanwhile. The prompt uses the
+//! console, so don't break inside it. With GDB attached, [`gdbstub`] stands in for the
+//! prompt.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+};
+use core::{
+    arch::asm,
+    fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{gdbstub, misaligned::Registers, print_registers, register_number, TrapRegisters};
+use crate::{
+    addr::VirtAddr,
+    backtrace, cmdline, console,
+    linker_info::text,
+    log,
+    pagetable::{kernel_translate, EntryFlags},
+    prelude::*,
+    sbi::rfence::rfence_extension,
+    shell, smp,
+    sync::IrqSafeMutex,
+};
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+/// Words `x` shows if it isn't told.
+const DEFAULT_WORDS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Patch {
+    /// What the `ebreak` went over.
+    original: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    /// Where the step stops.
+    at: u64,
+    /// A breakpoint lifted for the step, to put back after.
+    rearm: Option<u64>,
+    /// Stop at the prompt after, rather than carry on.
+    stop: bool,
+}
+
+pub(super) struct Debugger {
+    /// Every `ebreak` patched in, breakpoints and steps.
+    patches: BTreeMap<u64, Patch>,
+    breakpoints: BTreeSet<u64>,
+    step: Option<Step>,
+}
+
+/// Held while a hart is stopped, so the others queue up behind it.
+static DEBUGGER: IrqSafeMutex<Debugger> = IrqSafeMutex::new(Debugger::new());
+
+/// Stop at the next built in `ebreak`, as if `kdb` was on the command line.
+static STOP_NEXT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    /// Breakpoints go at instructions in `.text`.
+    NotText(u64),
+    Exists(u64),
+}
+
+impl Display for BreakpointError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakpointError::NotText(addr) => write!(f, "0x{:x} isn't kernel code", addr),
+            BreakpointError::Exists(addr) => write!(f, "already a breakpoint at 0x{:x}", addr),
+        }
+    }
+}
+
+impl core::error::Error for BreakpointError {}
+
+/// Break at `addr`.
+pub fn set(addr: u64) -> Result<(), BreakpointError> {
+    DEBUGGER.lock().add_breakpoint(addr)
+}
+
+/// Stop breaking at `addr`. `false` if there was no breakpoint there.
+pub fn remove(addr: u64) -> bool {
+    DEBUGGER.lock().remove_breakpoint(addr, None)
+}
+
+pub fn breakpoints() -> Vec<u64> {
+    DEBUGGER.lock().breakpoints.iter().copied().collect()
+}
+
+/// Stop at the prompt, here.
+pub fn enter() {
+    STOP_NEXT.store(true, Ordering::Relaxed);
+    unsafe { asm!("ebreak") };
+}
+
+/// An address, symbol, or number: `0x` for hex.
+pub fn parse_address(arg: &str) -> Option<u64> {
+    if let Some(hex) = arg.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    arg.parse()
+        .ok()
+        .or_else(|| backtrace::lookup(arg).map(|addr| addr as u64))
+}
+
+/// An instruction's length from its first halfword.
+fn instruction_len(low: u16) -> u64 {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// The instruction at `addr`, which is at least 2 byte aligned.
+unsafe fn read_instruction(addr: u64) -> u32 {
+    let low = (addr as *const u16).read_volatile();
+    match instruction_len(low) {
+        4 => low as u32 | ((addr as *const u16).add(1).read_volatile() as u32) << 16,
+        _ => low as u32,
+    }
+}
+
+/// Write the `len` byte instruction `raw` at `addr`, a halfword at a time.
+unsafe fn write_instruction(addr: u64, raw: u32, len: u64) {
+    (addr as *mut u16).write_volatile(raw as u16);
+    if len == 4 {
+        (addr as *mut u16).add(1).write_volatile((raw >> 16) as u16);
+    }
+}
+
+/// Make every hart fetch the instructions we just wrote.
+pub(super) fn sync_icache() {
+    unsafe { asm!("fence.i") };
+    let others = smp::other_harts();
+    if others.is_empty() {
+        return;
+    }
+    if let Err(err) = rfence_extension().remote_fence_i(others) {
+        log::error!("remote fence.i failed: {}", err);
+    }
+}
+
+impl Debugger {
+    pub(super) const fn new() -> Self {
+        Debugger {
+            patches: BTreeMap::new(),
+            breakpoints: BTreeSet::new(),
+            step: None,
+        }
+    }
+
+    pub(super) fn add_breakpoint(&mut self, addr: u64) -> Result<(), BreakpointError> {
+        if !text().contains(&addr) || addr % 2 != 0 {
+            return Err(BreakpointError::NotText(addr));
+        }
+        if !self.breakpoints.insert(addr) {
+            return Err(BreakpointError::Exists(addr));
+        }
+        unsafe { self.patch(addr) };
+        Ok(())
+    }
+
+    /// `false` if there was no breakpoint at `addr`. One at `stopped_at`, where the hart is
+    /// stopped, stays patched until it goes, so it's stepped over.
+    pub(super) fn remove_breakpoint(&mut self, addr: u64, stopped_at: Option<u64>) -> bool {
+        if !self.breakpoints.remove(&addr) {
+            return false;
+        }
+        let stepping = self.step.map_or(false, |step| step.at == addr);
+        if !stepping && stopped_at != Some(addr) {
+            unsafe { self.unpatch(addr) };
+        }
+        true
+    }
+
+    /// Put back what our patches replaced in `buf`, read from `addr`.
+    pub(super) fn unpatched(&self, addr: u64, buf: &mut [u8]) {
+        let end = addr + buf.len() as u64;
+        for (&at, patch) in self.patches.range(addr.saturating_sub(3)..end) {
+            let len = instruction_len(patch.original as u16);
+            for (i, byte) in patch.original.to_le_bytes()[..len as usize]
+                .iter()
+                .enumerate()
+            {
+                let at = at + i as u64;
+                if (addr..end).contains(&at) {
+                    buf[(at - addr) as usize] = *byte;
+                }
+            }
+        }
+    }
+
+    /// Put an `ebreak` the same size as the instruction at `addr` over it. Does nothing if
+    /// there's one of ours there already.
+    unsafe fn patch(&mut self, addr: u64) {
+        if self.patches.contains_key(&addr) {
+            return;
+        }
+        let original = read_instruction(addr);
+        match instruction_len(original as u16) {
+            4 => write_instruction(addr, EBREAK, 4),
+            _ => write_instruction(addr, C_EBREAK as u32, 2),
+        }
+        self.patches.insert(addr, Patch { original });
+        sync_icache();
+    }
+
+    unsafe fn unpatch(&mut self, addr: u64) {
+        if let Some(patch) = self.patches.remove(&addr) {
+            let len = instruction_len(patch.original as u16);
+            write_instruction(addr, patch.original, len);
+            sync_icache();
+        }
+    }
+
+    /// The instruction at `addr` as it is without our patches.
+    fn instruction(&self, addr: u64) -> u32 {
+        match self.patches.get(&addr) {
+            Some(patch) => patch.original,
+            None => unsafe { read_instruction(addr) },
+        }
+    }
+
+    /// Take out the step at `step.at`, and put back what it lifted.
+    unsafe fn finish_step(&mut self, step: Step) {
+        if !self.breakpoints.contains(&step.at) {
+            self.unpatch(step.at);
+        }
+        if let Some(addr) = step.rearm.filter(|addr| self.breakpoints.contains(addr)) {
+            self.patch(addr);
+        }
+    }
+
+    /// Get ready to go back to `registers.pc`. If there's an `ebreak` of ours there, or
+    /// `stop` is set, the instruction is stepped over first.
+    pub(super) unsafe fn resume(&mut self, registers: &TrapRegisters, stop: bool) {
+        let pc = registers.pc;
+        if !stop && !self.patches.contains_key(&pc) {
+            return;
+        }
+        if let Some(step) = self.step.take() {
+            self.finish_step(step);
+        }
+        let patched = self.patches.contains_key(&pc);
+        let next = next_pc(registers, self.instruction(pc));
+        self.unpatch(pc);
+        self.patch(next);
+        self.step = Some(Step {
+            at: next,
+            rearm: patched.then_some(pc),
+            stop,
+        });
+    }
+}
+
+/// Where the instruction `raw` at `regs.pc()` goes next, given the registers.
+pub(crate) fn next_pc(regs: &impl Registers, raw: u32) -> u64 {
+    let pc = regs.pc();
+    let bits = |hi: u32, lo: u32| (raw >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // Sign extend the low `width` bits of `imm`.
+    let extend = |imm: u32, width: u32| ((imm << (32 - width)) as i32 >> (32 - width)) as u64;
+    let x = |n: u32| regs.x(n as usize);
+
+    if instruction_len(raw as u16) == 2 {
+        let short = |hi, lo| x(8 + bits(hi, lo));
+        let branch = || {
+            let imm = bits(12, 12) << 8
+                | bits(11, 10) << 3
+                | bits(6, 5) << 6
+                | bits(4, 3) << 1
+                | bits(2, 2) << 5;
+            pc.wrapping_add(extend(imm, 9))
+        };
+        return match (bits(1, 0), bits(15, 13)) {
+            // c.j
+            (0b01, 0b101) => {
+                let imm = bits(12, 12) << 11
+                    | bits(11, 11) << 4
+                    | bits(10, 9) << 8
+                    | bits(8, 8) << 10
+                    | bits(7, 7) << 6
+                    | bits(6, 6) << 7
+                    | bits(5, 3) << 1
+                    | bits(2, 2) << 5;
+                pc.wrapping_add(extend(imm, 12))
+            }
+            // c.beqz, c.bnez
+            (0b01, 0b110) if short(9, 7) == 0 => branch(),
+            (0b01, 0b111) if short(9, 7) != 0 => branch(),
+            // c.jr, c.jalr
+            (0b10, 0b100) if bits(6, 2) == 0 && bits(11, 7) != 0 => x(bits(11, 7)) & !1,
+            _ => pc + 2,
+        };
+    }
+
+    let rs1 = x(bits(19, 15));
+    let rs2 = x(bits(24, 20));
+    match bits(6, 0) {
+        // jal
+        0x6f => {
+            let imm =
+                bits(31, 31) << 20 | bits(19, 12) << 12 | bits(20, 20) << 11 | bits(30, 21) << 1;
+            pc.wrapping_add(extend(imm, 21))
+        }
+        // jalr
+        0x67 => rs1.wrapping_add(extend(bits(31, 20), 12)) & !1,
+        // beq, bne, blt, bge, bltu, bgeu
+        0x63 => {
+            let taken = match bits(14, 12) {
+                0 => rs1 == rs2,
+                1 => rs1 != rs2,
+                4 => (rs1 as i64) < rs2 as i64,
+                5 => rs1 as i64 >= rs2 as i64,
+                6 => rs1 < rs2,
+                7 => rs1 >= rs2,
+                _ => false,
+            };
+            if taken {
+                let imm =
+                    bits(31, 31) << 12 | bits(7, 7) << 11 | bits(30, 25) << 5 | bits(11, 8) << 1;
+                pc.wrapping_add(extend(imm, 13))
+            } else {
+                pc + 4
+            }
+        }
+        _ => pc + 4,
+    }
+}
+
+/// `addr` and the function it's in.
+struct Location(u64);
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}", self.0)?;
+        match backtrace::symbolize(self.0 as usize) {
+            Some((name, offset)) => write!(f, " ({}+0x{:x})", name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What to do with the stopped hart.
+pub(super) enum Resume {
+    Continue,
+    Step,
+}
+
+/// The `Breakpoint` exception from the kernel.
+pub(crate) fn breakpoint(registers: &mut TrapRegisters) {
+    let pc = registers.pc;
+    let mut debugger = DEBUGGER.lock();
+    let hit = debugger.breakpoints.contains(&pc);
+    // GDB has the console, so nothing else gets printed.
+    let gdb = gdbstub::active();
+
+    let stepped = match debugger.step {
+        Some(step) if step.at == pc => debugger.step.take(),
+        _ => None,
+    };
+    if let Some(step) = stepped {
+        unsafe { debugger.finish_step(step) };
+        if !hit && !step.stop {
+            return;
+        }
+        if !hit && !gdb {
+            println!("kdb: stepped to {}", Location(pc));
+        }
+    } else if !hit && debugger.patches.contains_key(&pc) {
+        // Left behind somehow. Put the instruction back and run it.
+        unsafe { debugger.unpatch(pc) };
+        return;
+    } else if !hit {
+        let raw = unsafe { (pc as *const u16).read_volatile() };
+        registers.pc += instruction_len(raw);
+        let stop = STOP_NEXT.swap(false, Ordering::Relaxed) || gdb || cmdline::flag("kdb");
+        if !gdb {
+            println!("kdb: ebreak at {}", Location(pc));
+            print_registers(&mut console::lock(), registers).ok();
+        }
+        if !stop {
+            return;
+        }
+    }
+    if hit && !gdb {
+        println!("kdb: breakpoint at {}", Location(pc));
+        print_registers(&mut console::lock(), registers).ok();
+    }
+
+    let resume = if gdb {
+        gdbstub::stop(&mut debugger, registers, gdbstub::SIGTRAP)
+    } else {
+        prompt(&mut debugger, registers)
+    };
+    unsafe { debugger.resume(registers, matches!(resume, Resume::Step)) };
+}
+
+/// Stop for GDB, which asked to with `^C` or by connecting.
+pub(crate) fn interrupted(registers: &mut TrapRegisters) {
+    let mut debugger = DEBUGGER.lock();
+    let resume = gdbstub::stop(&mut debugger, registers, gdbstub::SIGINT);
+    unsafe { debugger.resume(registers, matches!(resume, Resume::Step)) };
+}
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Debugger, &mut TrapRegisters, &[&str]) -> Option<Resume>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "regs",
+        usage: "",
+        help: "dump the registers",
+        run: regs,
+    },
+    Command {
+        name: "set",
+        usage: "<register> <value>",
+        help: "change a register, pc included",
+        run: set_register,
+    },
+    Command {
+        name: "x",
+        usage: "<addr> [words]",
+        help: "dump memory, 8 bytes at a time",
+        run: examine,
+    },
+    Command {
+        name: "bt",
+        usage: "",
+        help: "backtrace from where the hart stopped",
+        run: bt,
+    },
+    Command {
+        name: "break",
+        usage: "[addr | symbol]",
+        help: "list breakpoints, or set one",
+        run: break_at,
+    },
+    Command {
+        name: "delete",
+        usage: "<addr | symbol>",
+        help: "remove a breakpoint",
+        run: delete,
+    },
+    Command {
+        name: "step",
+        usage: "",
+        help: "run one instruction",
+        run: |_, _, _| Some(Resume::Step),
+    },
+    Command {
+        name: "continue",
+        usage: "",
+        help: "carry on",
+        run: |_, _, _| Some(Resume::Continue),
+    },
+];
+
+/// Read commands until one says to go.
+fn prompt(debugger: &mut Debugger, registers: &mut TrapRegisters) -> Resume {
+    let mut history = VecDeque::new();
+    loop {
+        print!("kdb> ");
+        let line = match shell::read_line(&history, console::read_byte_polled) {
+            Some(line) => line,
+            None => continue,
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        // Unique prefixes do, so `s` and `c` step and continue.
+        let mut matches = COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(name));
+        let resume = match (matches.next(), matches.next()) {
+            (Some(command), None) => (command.run)(debugger, registers, args),
+            (Some(_), Some(_)) => {
+                println!("{}: which one? Try `help`.", name);
+                None
+            }
+            (None, _) => {
+                println!("{}: no such command. Try `help`.", name);
+                None
+            }
+        };
+        if let Some(resume) = resume {
+            return resume;
+        }
+        history.push_front(line);
+    }
+}
+
+fn help(_: &mut Debugger, _: &mut TrapRegisters, _: &[&str]) -> Option<Resume> {
+    for command in COMMANDS {
+        let usage = format!("{} {}", command.name, command.usage);
+        println!("  {:<24} {}", usage, command.help);
+    }
+    None
+}
+
+fn regs(_: &mut Debugger, registers: &mut TrapRegisters, _: &[&str]) -> Option<Resume> {
+    print_registers(&mut console::lock(), registers).ok();
+    None
+}
+
+fn set_register(_: &mut Debugger, registers: &mut TrapRegisters, args: &[&str]) -> Option<Resume> {
+    let (name, value) = match args {
+        [name, value] => (name, value),
+        _ => {
+            println!("usage: set <register> <value>");
+            return None;
+        }
+    };
+    let (n, value) = match (register_number(name), parse_address(value)) {
+        (Some(n), Some(value)) => (n, value),
+        (None, _) => {
+            println!("set: no register {}", name);
+            return None;
+        }
+        (_, None) => {
+            println!("set: bad value {}", value);
+            return None;
+        }
+    };
+    match n {
+        0 => registers.set_pc(value),
+        n => registers.set_x(n, value),
+    }
+    None
+}
+
+fn examine(_: &mut Debugger, _: &mut TrapRegisters, args: &[&str]) -> Option<Resume> {
+    let (addr, words) = match args {
+        [addr] => (parse_address(addr), Some(DEFAULT_WORDS)),
+        [addr, words] => (parse_address(addr), words.parse().ok()),
+        _ => (None, None),
+    };
+    let (addr, words) = match (addr, words) {
+        (Some(addr), Some(words)) => (addr & !7, words),
+        _ => {
+            println!("usage: x <addr> [words]");
+            return None;
+        }
+    };
+    for i in 0..words as u64 {
+        let addr = addr + i * 8;
+        // A fault here would be a nested trap, so look first.
+        match VirtAddr::try_new(addr).and_then(kernel_translate) {
+            Some((_, flags)) if flags.contains(EntryFlags::READ) => {
+                let value = unsafe { (addr as *const u64).read_volatile() };
+                println!("  0x{:016x}: 0x{:016x}", addr, value);
+            }
+            _ => {
+                println!("  0x{:016x}: not mapped", addr);
+                break;
+            }
+        }
+    }
+    None
+}
+
+fn bt(_: &mut Debugger, registers: &mut TrapRegisters, _: &[&str]) -> Option<Resume> {
+    let mut console = console::lock();
+    unsafe {
+        backtrace::print(
+            &mut console,
+            Some(registers.pc as usize),
+            registers.s0 as usize,
+        )
+        .ok()
+    };
+    None
+}
+
+fn break_at(debugger: &mut Debugger, _: &mut TrapRegisters, args: &[&str]) -> Option<Resume> {
+    let addr = match args {
+        [] => {
+            for addr in &debugger.breakpoints {
+                println!("  {}", Location(*addr));
+            }
+            return None;
+        }
+        [arg] => match parse_address(arg) {
+            Some(addr) => addr,
+            None => {
+                println!("break: no symbol {}", arg);
+                return None;
+            }
+        },
+        _ => {
+            println!("usage: break [addr | symbol]");
+            return None;
+        }
+    };
+    if let Err(err) = debugger.add_breakpoint(addr) {
+        println!("break: {}", err);
+    }
+    None
+}
+
+fn delete(debugger: &mut Debugger, registers: &mut TrapRegisters, args: &[&str]) -> Option<Resume> {
+    let addr = match args {
+        [arg] => parse_address(arg),
+        _ => None,
+    };
+    match addr {
+        Some(addr) if debugger.remove_breakpoint(addr, Some(registers.pc)) => {}
+        Some(addr) => println!("delete: no breakpoint at 0x{:x}", addr),
+        None => println!("usage: delete <addr | symbol>"),
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn next_pc_follows_jumps_and_branches() {
+        let mut regs: TrapRegisters = unsafe { core::mem::zeroed() };
+        regs.pc = 0x1000;
+        regs.ra = 0x2000;
+        regs.a0 = 5;
+        regs.a1 = 5;
+
+        // addi a0, a0, 1
+        assert_eq!(next_pc(&regs, 0x00150513), 0x1004);
+        // jal x0, -8
+        assert_eq!(next_pc(&regs, 0xff9ff06f), 0xff8);
+        // jalr x0, 4(ra)
+        assert_eq!(next_pc(&regs, 0x00408067), 0x2004);
+        // beq a0, a1, 16
+        assert_eq!(next_pc(&regs, 0x00b50863), 0x1010);
+        regs.a1 = 6;
+        assert_eq!(next_pc(&regs, 0x00b50863), 0x1004);
+
+        // c.j 8, c.j -2048
+        assert_eq!(next_pc(&regs, 0xa021), 0x1008);
+        assert_eq!(next_pc(&regs, 0xb001), 0x800);
+        // c.bnez a0, -4
+        assert_eq!(next_pc(&regs, 0xfd75), 0xffc);
+        regs.a0 = 0;
+        assert_eq!(next_pc(&regs, 0xfd75), 0x1002);
+        // ret
+        assert_eq!(next_pc(&regs, 0x8082), 0x2000);
+    }
+
+    #[test_case]
+    fn parse_addresses() {
+        assert_eq!(parse_address("0x80200000"), Some(0x8020_0000));
+        assert_eq!(parse_address("16"), Some(16));
+        assert_eq!(parse_address("0xzz"), None);
+    }
+}
@@ -0,0 +1,439 @@
+//! A GDB remote serial protocol stub, on the console UART.
+//!
+//! Point GDB at the console, say with QEMU's `-serial tcp::1234,server,nowait` and
+//! `target remote :1234`. Its first packet, `$qSupported`, is the magic that attaches:
+//! [`watch`] spots it in what the UART receives, and the kernel stops for GDB at its next
+//! trap. The shell's `gdb` stops and waits for it right away, and with `gdb` on the
+//! command line a panic waits for it before giving up.
+//!
+//! While attached, breakpoints stop here instead of at the `kdb>` prompt and `^C` from GDB
+//! stops the kernel at its next trap. It has the registers GDB calls `x1`-`x31` and `pc`,
+//! memory, software breakpoints and single steps, and no threads. Kernel output ends up
+//! between packets, where GDB ignores it.
+
+use alloc::{format, vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use super::{
+    debugger::{self, Debugger, Resume},
+    misaligned::Registers,
+    TrapRegisters,
+};
+use crate::{
+    addr::VirtAddr,
+    cmdline, console,
+    linker_info::text,
+    pagetable::{kernel_translate, EntryFlags, PAGE_SIZE},
+    prelude::*,
+    task::console::ByteQueue,
+};
+
+pub(crate) const SIGINT: u8 = 2;
+pub(crate) const SIGTRAP: u8 = 5;
+
+const CTRL_C: u8 = 0x03;
+/// How GDB's first packet starts.
+const MAGIC: &[u8] = b"$qSupported";
+/// Biggest packet we take, which is what we tell GDB. Memory goes as hex, so half this.
+const PACKET_SIZE: usize = 0x1000;
+const GDB_QUEUE_SIZE: usize = 256;
+
+/// GDB is attached.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Stop for GDB at the next kernel trap.
+static ATTACH: AtomicBool = AtomicBool::new(false);
+/// [`watch`] took the start of GDB's first packet. The rest is still to come.
+static MID_PACKET: AtomicBool = AtomicBool::new(false);
+/// GDB sent `c` or `s`, and is waiting to hear where we stopped.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// How much of [`MAGIC`] the UART has seen.
+static MATCHED: AtomicUsize = AtomicUsize::new(0);
+/// What GDB sends while attached, kept from the shell.
+static GDB_QUEUE: ByteQueue<GDB_QUEUE_SIZE> = ByteQueue::new();
+
+pub(crate) fn active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Look at a byte from the UART, from its interrupt handler. `true` if it's for GDB, and
+/// the console shouldn't see it.
+pub(crate) fn watch(byte: u8) -> bool {
+    if active() {
+        if byte == CTRL_C {
+            ATTACH.store(true, Ordering::Release);
+        }
+        GDB_QUEUE.push(byte);
+        return true;
+    }
+    let matched = MATCHED.load(Ordering::Relaxed);
+    let matched = match byte {
+        byte if byte == MAGIC[matched] => matched + 1,
+        byte => (byte == MAGIC[0]) as usize,
+    };
+    if matched < MAGIC.len() {
+        MATCHED.store(matched, Ordering::Relaxed);
+        return false;
+    }
+    MATCHED.store(0, Ordering::Relaxed);
+    MID_PACKET.store(true, Ordering::Relaxed);
+    ACTIVE.store(true, Ordering::Release);
+    ATTACH.store(true, Ordering::Release);
+    true
+}
+
+/// Whether GDB asked to stop. Checked after each interrupt.
+pub(crate) fn take_attach() -> bool {
+    ATTACH.swap(false, Ordering::AcqRel)
+}
+
+/// Stop here and wait for GDB.
+pub fn wait() {
+    ACTIVE.store(true, Ordering::Release);
+    debugger::enter();
+}
+
+/// Let GDB look at a panic before the kernel gives up, if it's attached or `gdb` is on the
+/// command line. Returns when it continues.
+pub fn panicked() {
+    if !(active() || cmdline::flag("gdb")) || !super::can_trap() {
+        return;
+    }
+    wait();
+}
+
+fn read_byte() -> u8 {
+    loop {
+        console::flush();
+        if let Some(byte) = GDB_QUEUE.pop().or_else(console::try_receive) {
+            return byte;
+        }
+        crate::cpu::relax();
+    }
+}
+
+fn write(s: &str) {
+    console::lock().write_str(s).ok();
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn encode_hex(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).ok();
+    }
+}
+
+/// A register as GDB sends it: target byte order, so little endian.
+fn decode_register(hex: &str) -> Option<u64> {
+    let bytes: [u8; 8] = decode_hex(hex)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn parse_u64(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Read packets until one's checksum is right, acking them, and put its body in `packet`.
+fn read_packet(packet: &mut Vec<u8>) {
+    let mut mid = MID_PACKET.swap(false, Ordering::Relaxed);
+    loop {
+        packet.clear();
+        if mid {
+            packet.extend_from_slice(&MAGIC[1..]);
+            mid = false;
+        } else {
+            while read_byte() != b'$' {}
+        }
+        loop {
+            match read_byte() {
+                b'#' => break,
+                b'$' => packet.clear(),
+                byte if packet.len() < PACKET_SIZE => packet.push(byte),
+                _ => {}
+            }
+        }
+        let sum = packet.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let check = match (hex_digit(read_byte()), hex_digit(read_byte())) {
+            (Some(hi), Some(lo)) => Some(hi << 4 | lo),
+            _ => None,
+        };
+        if check == Some(sum) {
+            write("+");
+            return;
+        }
+        write("-");
+    }
+}
+
+/// Send a packet, again until GDB acks it.
+fn send(body: &str) {
+    let sum = body.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    let packet = format!("${}#{:02x}", body, sum);
+    loop {
+        write(&packet);
+        loop {
+            match read_byte() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Whether every page under `addr..addr + len` is mapped with `flag`.
+fn mapped(addr: u64, len: usize, flag: EntryFlags) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+    (addr & !(PAGE_SIZE - 1)..end)
+        .step_by(PAGE_SIZE as usize)
+        .all(|page| {
+            VirtAddr::try_new(page)
+                .and_then(kernel_translate)
+                .map_or(false, |(_, flags)| flags.contains(flag))
+        })
+}
+
+/// What's at `addr..addr + len`, as it would be without our breakpoints.
+fn read_memory(debugger: &Debugger, addr: u64, len: usize) -> Option<Vec<u8>> {
+    if len > PACKET_SIZE / 2 || !mapped(addr, len, EntryFlags::READ) {
+        return None;
+    }
+    let mut buf = vec![0; len];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { ((addr + i as u64) as *const u8).read_volatile() };
+    }
+    debugger.unpatched(addr, &mut buf);
+    Some(buf)
+}
+
+fn write_memory(addr: u64, data: &[u8]) -> Option<()> {
+    if !mapped(addr, data.len(), EntryFlags::WRITE) {
+        return None;
+    }
+    for (i, byte) in data.iter().enumerate() {
+        unsafe { ((addr + i as u64) as *mut u8).write_volatile(*byte) };
+    }
+    if text().contains(&addr) {
+        debugger::sync_icache();
+    }
+    Some(())
+}
+
+/// `value` of register `n`, in GDB's numbering: `x0`-`x31`, then `pc`.
+fn register(registers: &TrapRegisters, n: usize) -> Option<u64> {
+    match n {
+        0..=31 => Some(registers.x(n)),
+        32 => Some(registers.pc()),
+        _ => None,
+    }
+}
+
+fn set_register(registers: &mut TrapRegisters, n: usize, value: u64) -> Option<()> {
+    match n {
+        0..=31 => registers.set_x(n, value),
+        32 => registers.set_pc(value),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// What a packet wants done.
+enum Action {
+    Reply(String),
+    Resume(Resume),
+    /// GDB's going, and the kernel carries on without it.
+    Detach,
+}
+
+fn ok_or_error(result: Option<()>) -> Action {
+    match result {
+        Some(()) => Action::Reply(String::from("OK")),
+        None => Action::Reply(String::from("E01")),
+    }
+}
+
+fn handle(
+    debugger: &mut Debugger,
+    registers: &mut TrapRegisters,
+    signal: u8,
+    packet: &str,
+) -> Action {
+    let (command, args) = match packet.chars().next() {
+        Some(command) => (command, &packet[command.len_utf8()..]),
+        None => return Action::Reply(String::new()),
+    };
+    let mut reply = String::new();
+    match command {
+        '?' => reply = format!("S{:02x}", signal),
+        'g' => {
+            for n in 0..=32 {
+                encode_hex(&mut reply, &register(registers, n).unwrap().to_le_bytes());
+            }
+        }
+        'G' => {
+            let values: Option<Vec<u64>> = (0..=32)
+                .map(|n| decode_register(args.get(n * 16..(n + 1) * 16)?))
+                .collect();
+            let result = values.map(|values| {
+                for (n, value) in values.into_iter().enumerate() {
+                    set_register(registers, n, value);
+                }
+            });
+            return ok_or_error(result);
+        }
+        'p' => match parse_u64(args).and_then(|n| register(registers, n as usize)) {
+            Some(value) => encode_hex(&mut reply, &value.to_le_bytes()),
+            None => reply.push_str("E01"),
+        },
+        'P' => {
+            let result = args.split_once('=').and_then(|(n, value)| {
+                set_register(registers, parse_u64(n)? as usize, decode_register(value)?)
+            });
+            return ok_or_error(result);
+        }
+        'm' => {
+            let memory = args.split_once(',').and_then(|(addr, len)| {
+                read_memory(debugger, parse_u64(addr)?, parse_u64(len)? as usize)
+            });
+            match memory {
+                Some(memory) => encode_hex(&mut reply, &memory),
+                None => reply.push_str("E14"),
+            }
+        }
+        'M' => {
+            let result = args.split_once(':').and_then(|(range, data)| {
+                let (addr, len) = range.split_once(',')?;
+                let data = decode_hex(data)?;
+                if data.len() as u64 != parse_u64(len)? {
+                    return None;
+                }
+                write_memory(parse_u64(addr)?, &data)
+            });
+            return ok_or_error(result);
+        }
+        'c' | 's' => {
+            if let Some(addr) = parse_u64(args) {
+                registers.pc = addr;
+            }
+            return Action::Resume(match command {
+                'c' => Resume::Continue,
+                _ => Resume::Step,
+            });
+        }
+        'Z' | 'z' => {
+            let mut parts = args.split(',');
+            let addr = match (parts.next(), parts.next().and_then(parse_u64)) {
+                // Software breakpoints only.
+                (Some("0"), Some(addr)) => addr,
+                _ => return Action::Reply(reply),
+            };
+            let result = match command {
+                'Z' => debugger.add_breakpoint(addr).ok(),
+                _ => debugger
+                    .remove_breakpoint(addr, Some(registers.pc))
+                    .then_some(()),
+            };
+            return ok_or_error(result);
+        }
+        'D' => {
+            send("OK");
+            return Action::Detach;
+        }
+        'k' => return Action::Detach,
+        'H' => reply.push_str("OK"),
+        'q' if args.starts_with("Supported") => {
+            reply = format!("PacketSize={:x}", PACKET_SIZE);
+        }
+        'q' if args == "Attached" => reply.push('1'),
+        'q' if args == "fThreadInfo" || args == "sThreadInfo" => reply.push('l'),
+        // Anything else isn't supported, which GDB takes from an empty reply.
+        _ => {}
+    }
+    Action::Reply(reply)
+}
+
+/// Talk to GDB until it says to go, with the hart stopped at `registers` for `signal`.
+pub(super) fn stop(debugger: &mut Debugger, registers: &mut TrapRegisters, signal: u8) -> Resume {
+    if RUNNING.swap(false, Ordering::AcqRel) {
+        send(&format!("S{:02x}", signal));
+    }
+    let mut packet = Vec::new();
+    loop {
+        read_packet(&mut packet);
+        let body = core::str::from_utf8(&packet).unwrap_or("");
+        match handle(debugger, registers, signal, body) {
+            Action::Reply(reply) => send(&reply),
+            Action::Resume(resume) => {
+                RUNNING.store(true, Ordering::Release);
+                return resume;
+            }
+            Action::Detach => {
+                ACTIVE.store(false, Ordering::Release);
+                return Resume::Continue;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn registers_and_hex() {
+        let mut registers: TrapRegisters = unsafe { core::mem::zeroed() };
+        let mut debugger = Debugger::new();
+        registers.pc = 0x8020_0000;
+        registers.a0 = 0x1234;
+
+        let reply = match handle(&mut debugger, &mut registers, SIGTRAP, "p20") {
+            Action::Reply(reply) => reply,
+            _ => panic!("p didn't reply"),
+        };
+        assert_eq!(reply, "0000208000000000");
+        let reply = match handle(
+            &mut debugger,
+            &mut registers,
+            SIGTRAP,
+            "Pa=ffffffffffffffff",
+        ) {
+            Action::Reply(reply) => reply,
+            _ => panic!("P didn't reply"),
+        };
+        assert_eq!(reply, "OK");
+        assert_eq!(registers.a0, u64::MAX);
+        assert_eq!(decode_hex("0aff"), Some(vec![0x0a, 0xff]));
+        assert_eq!(decode_hex("0g"), None);
+    }
+
+    #[test_case]
+    fn magic_attaches() {
+        for byte in b"$$qSupported" {
+            if watch(*byte) {
+                break;
+            }
+        }
+        assert!(active());
+        assert!(take_attach());
+        ACTIVE.store(false, Ordering::Release);
+        MID_PACKET.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,380 @@
+//! Emulating misaligned loads and stores.
+//!
+//! A hart can trap on a load or store that isn't aligned to its size instead of doing it,
+//! leaving it to us. [`emulate`] decodes the instruction at `pc`, does the access a byte at
+//! a time, and steps over it. Only integer loads and stores, compressed ones included: the
+//! kernel doesn't use floating point, and a process that does still gets killed.
+
+use core::fmt::{self, Display, Formatter};
+
+use riscv::register::scause::Scause;
+
+use crate::process::{AddressSpace, Fault, TrapFrame};
+
+/// `scause` exception codes.
+const LOAD_MISALIGNED: usize = 4;
+const STORE_MISALIGNED: usize = 6;
+
+/// Whether `scause` is a misaligned load or store. Not every version of the `riscv` crate
+/// knows the load one.
+pub(crate) fn is_misaligned(scause: &Scause) -> bool {
+    scause.is_exception() && matches!(scause.code(), LOAD_MISALIGNED | STORE_MISALIGNED)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Load { rd: usize, signed: bool },
+    Store { rs2: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Instruction {
+    op: Op,
+    /// Bytes accessed.
+    width: usize,
+    rs1: usize,
+    offset: i64,
+    /// Of the instruction. 2 if it's compressed.
+    len: u64,
+}
+
+/// Bits `hi..=lo` of `raw`, shifted down.
+fn bits(raw: u32, hi: u32, lo: u32) -> u32 {
+    (raw >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Decode an integer load or store. `raw` has a compressed instruction in its low half.
+pub(crate) fn decode(raw: u32) -> Option<Instruction> {
+    if raw & 0b11 == 0b11 {
+        decode_full(raw)
+    } else {
+        decode_compressed(raw as u16)
+    }
+}
+
+fn decode_full(raw: u32) -> Option<Instruction> {
+    let funct3 = bits(raw, 14, 12);
+    let rs1 = bits(raw, 19, 15) as usize;
+    let (op, offset) = match bits(raw, 6, 0) {
+        0x03 => {
+            let op = Op::Load {
+                rd: bits(raw, 11, 7) as usize,
+                signed: funct3 < 4,
+            };
+            (op, (raw as i32 >> 20) as i64)
+        }
+        0x23 if funct3 < 4 => {
+            let op = Op::Store {
+                rs2: bits(raw, 24, 20) as usize,
+            };
+            let offset = ((raw as i32 >> 25) << 5) as i64 | bits(raw, 11, 7) as i64;
+            (op, offset)
+        }
+        _ => return None,
+    };
+    let width = match funct3 & 0b11 {
+        // There's no 64-bit load that zero extends.
+        3 if funct3 == 7 => return None,
+        n => 1 << n,
+    };
+    Some(Instruction {
+        op,
+        width,
+        rs1,
+        offset,
+        len: 4,
+    })
+}
+
+fn decode_compressed(raw: u16) -> Option<Instruction> {
+    let raw = raw as u32;
+    // x8-x15, for the three bit register fields.
+    let short = |hi, lo| 8 + bits(raw, hi, lo) as usize;
+    let long = |hi, lo| bits(raw, hi, lo) as usize;
+    let (op, width, rs1, offset) = match (bits(raw, 1, 0), bits(raw, 15, 13)) {
+        // c.lw, c.sw
+        (0b00, 0b010 | 0b110) => {
+            let offset = bits(raw, 12, 10) << 3 | bits(raw, 6, 6) << 2 | bits(raw, 5, 5) << 6;
+            (short(4, 2), 4, short(9, 7), offset)
+        }
+        // c.ld, c.sd
+        (0b00, 0b011 | 0b111) => {
+            let offset = bits(raw, 12, 10) << 3 | bits(raw, 6, 5) << 6;
+            (short(4, 2), 8, short(9, 7), offset)
+        }
+        // c.lwsp
+        (0b10, 0b010) => {
+            let offset = bits(raw, 12, 12) << 5 | bits(raw, 6, 4) << 2 | bits(raw, 3, 2) << 6;
+            (long(11, 7), 4, 2, offset)
+        }
+        // c.ldsp
+        (0b10, 0b011) => {
+            let offset = bits(raw, 12, 12) << 5 | bits(raw, 6, 5) << 3 | bits(raw, 4, 2) << 6;
+            (long(11, 7), 8, 2, offset)
+        }
+        // c.swsp
+        (0b10, 0b110) => {
+            let offset = bits(raw, 12, 9) << 2 | bits(raw, 8, 7) << 6;
+            (long(6, 2), 4, 2, offset)
+        }
+        // c.sdsp
+        (0b10, 0b111) => {
+            let offset = bits(raw, 12, 10) << 3 | bits(raw, 9, 7) << 6;
+            (long(6, 2), 8, 2, offset)
+        }
+        _ => return None,
+    };
+    let op = if bits(raw, 15, 15) == 0 {
+        Op::Load {
+            rd: op,
+            signed: true,
+        }
+    } else {
+        Op::Store { rs2: op }
+    };
+    Some(Instruction {
+        op,
+        width,
+        rs1,
+        offset: offset as i64,
+        len: 2,
+    })
+}
+
+/// The registers of whatever trapped.
+pub(crate) trait Registers {
+    /// `x0` reads as 0.
+    fn x(&self, n: usize) -> u64;
+    /// Writes to `x0` go nowhere.
+    fn set_x(&mut self, n: usize, value: u64);
+    fn pc(&self) -> u64;
+    fn set_pc(&mut self, pc: u64);
+}
+
+/// The memory of whatever trapped.
+pub(crate) trait Memory {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Fault>;
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Fault>;
+}
+
+impl Registers for TrapFrame {
+    fn x(&self, n: usize) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.regs[n]
+        }
+    }
+
+    fn set_x(&mut self, n: usize, value: u64) {
+        if n != 0 {
+            self.regs[n] = value;
+        }
+    }
+
+    fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+}
+
+impl Memory for AddressSpace {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Fault> {
+        self.copy_from_user(addr, buf)
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Fault> {
+        self.copy_to_user(addr, data)
+    }
+}
+
+/// The kernel's own memory. A bad address faults again, from inside the trap.
+pub(crate) struct KernelMemory;
+
+impl Memory for KernelMemory {
+    fn read(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Fault> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { ((addr + i as u64) as *const u8).read_volatile() };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Fault> {
+        for (i, &byte) in data.iter().enumerate() {
+            unsafe { ((addr + i as u64) as *mut u8).write_volatile(byte) };
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmulateError {
+    /// The instruction or the memory it accesses couldn't be reached.
+    Fault,
+    /// Not an integer load or store.
+    Unsupported(u32),
+}
+
+impl Display for EmulateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulateError::Fault => f.write_str("bad address"),
+            EmulateError::Unsupported(raw) => write!(f, "can't emulate instruction 0x{:08x}", raw),
+        }
+    }
+}
+
+impl core::error::Error for EmulateError {}
+
+impl From<Fault> for EmulateError {
+    fn from(_: Fault) -> Self {
+        EmulateError::Fault
+    }
+}
+
+/// Do the load or store at `regs.pc()` a byte at a time and step past it.
+pub(crate) fn emulate(
+    regs: &mut impl Registers,
+    memory: &mut impl Memory,
+) -> Result<(), EmulateError> {
+    let pc = regs.pc();
+    let mut half = [0u8; 2];
+    memory.read(pc, &mut half)?;
+    let mut raw = u16::from_le_bytes(half) as u32;
+    if raw & 0b11 == 0b11 {
+        memory.read(pc + 2, &mut half)?;
+        raw |= (u16::from_le_bytes(half) as u32) << 16;
+    }
+    let instruction = decode(raw).ok_or(EmulateError::Unsupported(raw))?;
+
+    let addr = regs
+        .x(instruction.rs1)
+        .wrapping_add(instruction.offset as u64);
+    let mut bytes = [0u8; 8];
+    let bytes = &mut bytes[..instruction.width];
+    match instruction.op {
+        Op::Load { rd, signed } => {
+            memory.read(addr, bytes)?;
+            let mut value = [0u8; 8];
+            value[..bytes.len()].copy_from_slice(bytes);
+            let value = u64::from_le_bytes(value);
+            let shift = 64 - 8 * instruction.width as u32;
+            let value = if signed {
+                ((value << shift) as i64 >> shift) as u64
+            } else {
+                value
+            };
+            regs.set_x(rd, value);
+        }
+        Op::Store { rs2 } => {
+            bytes.copy_from_slice(&regs.x(rs2).to_le_bytes()[..instruction.width]);
+            memory.write(addr, bytes)?;
+        }
+    }
+    regs.set_pc(pc + instruction.len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn decode_loads_and_stores() {
+        let load = |rd, signed| Op::Load { rd, signed };
+        let cases = [
+            // lw a0, 1(a1)
+            (0x0015a503, load(10, true), 4, 11, 1, 4),
+            // lhu a0, -1(a1)
+            (0xfff5d503, load(10, false), 2, 11, -1, 4),
+            // sd a2, 3(sp)
+            (0x00c131a3, Op::Store { rs2: 12 }, 8, 2, 3, 4),
+            // c.lw a0, 4(a1)
+            (0x41c8, load(10, true), 4, 11, 4, 2),
+            // c.sdsp a0, 8(sp)
+            (0xe42a, Op::Store { rs2: 10 }, 8, 2, 8, 2),
+        ];
+        for (raw, op, width, rs1, offset, len) in cases {
+            let expected = Instruction {
+                op,
+                width,
+                rs1,
+                offset,
+                len,
+            };
+            assert_eq!(decode(raw), Some(expected), "0x{:08x}", raw);
+        }
+        // addi a0, a0, 1
+        assert_eq!(decode(0x00150513), None);
+    }
+
+    struct Regs {
+        x: [u64; 32],
+        pc: u64,
+    }
+
+    impl Registers for Regs {
+        fn x(&self, n: usize) -> u64 {
+            self.x[n]
+        }
+
+        fn set_x(&mut self, n: usize, value: u64) {
+            if n != 0 {
+                self.x[n] = value;
+            }
+        }
+
+        fn pc(&self) -> u64 {
+            self.pc
+        }
+
+        fn set_pc(&mut self, pc: u64) {
+            self.pc = pc;
+        }
+    }
+
+    struct Bytes([u8; 32]);
+
+    impl Memory for Bytes {
+        fn read(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Fault> {
+            let addr = addr as usize;
+            buf.copy_from_slice(self.0.get(addr..addr + buf.len()).ok_or(Fault)?);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Fault> {
+            let addr = addr as usize;
+            self.0
+                .get_mut(addr..addr + data.len())
+                .ok_or(Fault)?
+                .copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn emulate_misaligned() {
+        let mut memory = Bytes([0; 32]);
+        // lw a0, 1(a1), then c.sdsp a0, 8(sp)
+        memory.0[..4].copy_from_slice(&0x0015a503u32.to_le_bytes());
+        memory.0[4..6].copy_from_slice(&0xe42au16.to_le_bytes());
+        memory.0[17..21].copy_from_slice(&0x8765_4321u32.to_le_bytes());
+        let mut regs = Regs { x: [0; 32], pc: 0 };
+        regs.x[11] = 16;
+        regs.x[2] = 1;
+
+        emulate(&mut regs, &mut memory).unwrap();
+        assert_eq!(regs.x[10], 0xffff_ffff_8765_4321);
+        assert_eq!(regs.pc, 4);
+        emulate(&mut regs, &mut memory).unwrap();
+        assert_eq!(&memory.0[9..17], &0xffff_ffff_8765_4321u64.to_le_bytes());
+        assert_eq!(regs.pc, 6);
+
+        regs.x[11] = 100;
+        regs.pc = 0;
+        assert_eq!(emulate(&mut regs, &mut memory), Err(EmulateError::Fault));
+    }
+}
@@ -0,0 +1,233 @@
+//! virtio-blk driver. Section 5.2 of the virtio spec.
+//!
+//! Each request is a three descriptor chain: header, data, status byte. The caller sleeps
+//! on a [`CompletionQueue`] until the chain comes back, which the interrupt handler leaves
+//! to [`COLLECT_WORK`] to notice. When the queue is full, callers sleep until a request
+//! finishes. All three are in one
+//! [`DmaBuffer`](crate::dma::DmaBuffer), with the data copied in or out of the caller's
+//! buffer, since that may be on a stack or somewhere else the device can't get at.
+
+use core::mem::size_of;
+
+use alloc::{format, sync::Arc, vec::Vec};
+
+use crate::{
+    block::{self, BlockDevice, BlockError, BLOCK_SIZE},
+    dma,
+    isr::plic::{self, InterruptId},
+    sync::{Once, Semaphore},
+    workqueue::Work,
+};
+
+use super::{queue::CompletionQueue, DeviceType, Transport, VirtioError};
+
+static COLLECT_WORK: Work = Work::new("virtio-blk", collect);
+
+const QUEUE_SIZE: u16 = 16;
+/// Requests that fit in the queue at once.
+const MAX_REQUESTS: usize = QUEUE_SIZE as usize / 3;
+
+/// Device is read only.
+const BLK_F_RO: u64 = 1 << 5;
+/// Device supports the flush command.
+const BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Offset of `capacity` in the device config. In 512 byte sectors.
+const CONFIG_CAPACITY: usize = 0;
+
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+/// Not a status the device sends. Marks the status byte as not written yet.
+const STATUS_PENDING: u8 = 0xff;
+
+static DEVICES: Once<Vec<Arc<VirtioBlk>>> = Once::INIT;
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+impl RequestHeader {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    requests: CompletionQueue,
+    /// One permit per request the queue has room for.
+    slots: Semaphore,
+    interrupt: InterruptId,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+}
+
+impl VirtioBlk {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        let features = transport.begin_init(BLK_F_RO | BLK_F_FLUSH)?;
+        let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+        let capacity = transport.read_config::<u64>(CONFIG_CAPACITY);
+        let interrupt = transport.interrupt();
+        transport.finish_init();
+
+        Ok(VirtioBlk {
+            transport,
+            requests: CompletionQueue::new(queue),
+            slots: Semaphore::new(MAX_REQUESTS),
+            interrupt,
+            capacity,
+            read_only: features & BLK_F_RO != 0,
+            can_flush: features & BLK_F_FLUSH != 0,
+        })
+    }
+
+    /// Send a request and sleep until the device completes it.
+    fn request(&self, kind: u32, sector: u64, data: Option<Data>) -> Result<(), BlockError> {
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        let len = match &data {
+            Some(Data::Read(buf)) => buf.len(),
+            Some(Data::Write(buf)) => buf.len(),
+            None => 0,
+        };
+        let data_start = size_of::<RequestHeader>();
+        let mut request = dma::alloc(data_start + len + 1, 8).map_err(|err| {
+            crate::log::warn!("virtio-blk: {}", err);
+            BlockError::Io
+        })?;
+        let (header_bytes, rest) = unsafe { request.as_mut_slice() }.split_at_mut(data_start);
+        let (data_bytes, status) = rest.split_at_mut(len);
+        header_bytes.copy_from_slice(header.as_bytes());
+        if let Some(Data::Write(buf)) = &data {
+            data_bytes.copy_from_slice(buf);
+        }
+        status[0] = STATUS_PENDING;
+        let header = &*header_bytes;
+
+        self.slots.acquire();
+        let (requests, transport) = (&self.requests, &self.transport);
+        let added = unsafe {
+            match data {
+                Some(Data::Read(_)) => {
+                    requests.submit(transport, &[header], &mut [data_bytes, status])
+                }
+                Some(Data::Write(_)) => {
+                    requests.submit(transport, &[header, data_bytes], &mut [status])
+                }
+                None => requests.submit(transport, &[header], &mut [status]),
+            }
+        };
+        let head = match added {
+            Ok(head) => head,
+            Err(_) => {
+                self.slots.release();
+                return Err(BlockError::Io);
+            }
+        };
+
+        requests.wait(head);
+        self.slots.release();
+
+        request.sync_for_cpu();
+        let done = unsafe { request.as_slice() };
+        match done[data_start + len] {
+            STATUS_OK => {
+                if let Some(Data::Read(buf)) = data {
+                    buf.copy_from_slice(&done[data_start..data_start + len]);
+                }
+                Ok(())
+            }
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+enum Data<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl BlockDevice for VirtioBlk {
+    fn num_blocks(&self) -> u64 {
+        // virtio sectors are always 512 bytes.
+        self.capacity * 512 / BLOCK_SIZE as u64
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self.num_blocks(), lba, buf.len())?;
+        self.request(REQ_IN, lba, Some(Data::Read(buf)))
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self.num_blocks(), lba, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.request(REQ_OUT, lba, Some(Data::Write(buf)))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        self.request(REQ_FLUSH, 0, None)
+    }
+}
+
+/// PLIC handler. Leaves finding the finished requests to [`collect`].
+fn blk_interrupt(interrupt: InterruptId) {
+    let devices = match DEVICES.get() {
+        Some(devices) => devices,
+        None => return,
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.transport.ack_interrupt();
+    }
+    COLLECT_WORK.schedule();
+}
+
+/// Mark finished requests on every device so their callers wake up.
+fn collect() {
+    for device in DEVICES.get().into_iter().flatten() {
+        device.requests.collect();
+    }
+}
+
+/// Set up every virtio-blk device and register them as `vda`, `vdb`, ...
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Block) {
+            match VirtioBlk::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => crate::log::error!("{}", err),
+            }
+        }
+        devices
+    });
+
+    COLLECT_WORK.register();
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, blk_interrupt);
+        plic::enable_interrupt(device.interrupt);
+
+        let letter = (b'a' + block::count_with_prefix("vd") as u8) as char;
+        block::register(&format!("vd{letter}"), device.clone());
+    }
+}
@@ -0,0 +1,212 @@
+//! virtio-console driver. Section 5.3 of the virtio spec.
+//!
+//! Only port 0, without `VIRTIO_CONSOLE_F_MULTIPORT`: queue 0 receives and queue 1
+//! transmits. Each device is a console backend like the UART: a [`ConsoleSink`] for
+//! output, and what arrives goes to [`console::push_input`]. The receive queue is kept full
+//! like virtio-net's. Output is copied into a ring of transmit buffers, and dropped if the
+//! host isn't taking it rather than holding up the console.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    console::{self, ConsoleSink},
+    dma::{self, DmaBuffer},
+    isr::plic::{self, InterruptId},
+    log,
+    prelude::*,
+    sync::{IrqSafeMutex, Once},
+};
+
+use super::{queue::VirtQueue, DeviceType, Transport, VirtioError};
+
+const QUEUE_SIZE: u16 = 8;
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const BUFFER: usize = 256;
+
+static DEVICES: Once<Vec<Arc<VirtioConsole>>> = Once::INIT;
+
+/// `count` buffers of [`BUFFER`] bytes in one [`DmaBuffer`], and which descriptor chain
+/// each one is.
+struct Buffers {
+    memory: DmaBuffer,
+    /// Which buffer each descriptor chain is, by head.
+    slots: Vec<usize>,
+}
+
+impl Buffers {
+    fn new(count: usize) -> Result<Buffers, VirtioError> {
+        Ok(Buffers {
+            memory: dma::alloc(count * BUFFER, 16).map_err(|_| VirtioError::OutOfMemory)?,
+            slots: vec![0; count],
+        })
+    }
+
+    /// # Safety
+    /// Buffer `slot` mustn't be the device's, or borrowed already.
+    unsafe fn get(&mut self, slot: usize) -> &mut [u8] {
+        let start = self.memory.as_ptr().add(slot * BUFFER);
+        core::slice::from_raw_parts_mut(start, BUFFER)
+    }
+}
+
+struct Inner {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    rx_buffers: Buffers,
+    tx_buffers: Buffers,
+    /// Transmit buffers the device isn't using.
+    tx_free: Vec<usize>,
+}
+
+impl Inner {
+    /// Give receive buffer `slot` to the device.
+    fn post_rx(&mut self, slot: usize) {
+        let buffer = unsafe { self.rx_buffers.get(slot) };
+        // The queue has a descriptor for every buffer, so there's always room.
+        let head = unsafe { self.rx.add(&[], &mut [buffer]) }.expect("receive queue full");
+        self.rx_buffers.slots[head as usize] = slot;
+    }
+
+    /// Give the first `len` bytes of transmit buffer `slot` to the device.
+    fn send(&mut self, slot: usize, len: usize) {
+        self.tx_buffers.memory.sync_for_device();
+        let buffer = unsafe { &self.tx_buffers.get(slot)[..len] };
+        let head = unsafe { self.tx.add(&[buffer], &mut []) }.expect("transmit queue full");
+        self.tx_buffers.slots[head as usize] = slot;
+    }
+
+    fn reclaim_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            let slot = self.tx_buffers.slots[head as usize];
+            self.tx_free.push(slot);
+        }
+    }
+
+    /// Give everything that's arrived to the console.
+    fn collect_rx(&mut self) {
+        let mut posted = false;
+        while let Some((head, len)) = self.rx.pop_used() {
+            let slot = self.rx_buffers.slots[head as usize];
+            self.rx_buffers.memory.sync_for_cpu();
+            let received = unsafe { &self.rx_buffers.get(slot)[..(len as usize).min(BUFFER)] };
+            console::push_input(received);
+            self.post_rx(slot);
+            posted = true;
+        }
+        if posted && self.rx.should_notify() {
+            self.transport.notify(RX_QUEUE);
+        }
+    }
+}
+
+pub struct VirtioConsole {
+    /// Taken with interrupts off, since the interrupt handler takes it too.
+    inner: IrqSafeMutex<Inner>,
+    interrupt: InterruptId,
+}
+
+impl VirtioConsole {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?;
+        let rx = transport.setup_queue(RX_QUEUE, QUEUE_SIZE)?;
+        let tx = transport.setup_queue(TX_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+
+        let mut inner = Inner {
+            rx_buffers: Buffers::new(rx.size() as usize)?,
+            tx_buffers: Buffers::new(tx.size() as usize)?,
+            tx_free: (0..tx.size() as usize).collect(),
+            transport,
+            rx,
+            tx,
+        };
+        for slot in 0..inner.rx.size() as usize {
+            inner.post_rx(slot);
+        }
+        inner.transport.finish_init();
+        inner.transport.notify(RX_QUEUE);
+
+        Ok(VirtioConsole {
+            inner: IrqSafeMutex::new(inner),
+            interrupt,
+        })
+    }
+}
+
+impl ConsoleSink for VirtioConsole {
+    fn write(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.reclaim_tx();
+        let mut current = None;
+        let mut len = 0;
+        for &byte in bytes {
+            // Backspace the same way as the UART.
+            let expanded: &[u8] = match byte {
+                8 | 0x7f => b"\x08 \x08",
+                _ => core::slice::from_ref(&byte),
+            };
+            if len + expanded.len() > BUFFER {
+                if let Some(slot) = current.take() {
+                    inner.send(slot, len);
+                }
+            }
+            if current.is_none() {
+                current = inner.tx_free.pop();
+                len = 0;
+            }
+            let Some(slot) = current else {
+                break;
+            };
+            let buffer = unsafe { inner.tx_buffers.get(slot) };
+            buffer[len..len + expanded.len()].copy_from_slice(expanded);
+            len += expanded.len();
+        }
+        if let Some(slot) = current {
+            inner.send(slot, len);
+        }
+        if inner.tx.should_notify() {
+            inner.transport.notify(TX_QUEUE);
+        }
+    }
+}
+
+/// PLIC handler. Frees sent buffers and passes on what's arrived.
+fn console_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        let mut inner = device.inner.lock();
+        inner.transport.ack_interrupt();
+        inner.reclaim_tx();
+        inner.collect_rx();
+    }
+}
+
+/// Set up every virtio-console device and add them as consoles, unless `console=` leaves
+/// out `hvc`.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Console) {
+            match VirtioConsole::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-console: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, console_interrupt);
+        plic::enable_interrupt(device.interrupt);
+        if console::wanted("hvc") {
+            console::add_sink(device.clone());
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! virtio-gpu driver, 2D only. Section 5.7 of the virtio spec.
+//!
+//! At init we ask the device for its displays and give the first enabled one a resource
+//! the size of its screen, backed by a [`DmaBuffer`] that becomes the [`Framebuffer`].
+//! Flushing a rect copies it to the host's copy of the resource and then to the screen.
+//! Commands go on the control queue, and the caller sleeps on a [`CompletionQueue`] until
+//! the response comes back. The cursor queue isn't used.
+
+use core::{mem::size_of, ptr::NonNull};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    dma::{self, DmaBuffer},
+    graphics::{self, Framebuffer, GraphicsError, PixelFormat, Rect, Scanout},
+    isr::plic::{self, InterruptId},
+    log,
+    sync::Once,
+};
+
+use super::{queue::CompletionQueue, DeviceType, Transport, VirtioError};
+
+const QUEUE_SIZE: u16 = 16;
+const CONTROL_QUEUE: u16 = 0;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const MAX_SCANOUTS: usize = 16;
+/// The only resource we make.
+const RESOURCE_ID: u32 = 1;
+/// What we use if the device says no display is enabled.
+const DEFAULT_SIZE: (u32, u32) = (1024, 768);
+
+static DEVICES: Once<Vec<Arc<VirtioGpu>>> = Once::INIT;
+
+/// `virtio_gpu_ctrl_hdr`, which starts every command and response.
+#[repr(C)]
+#[derive(Default)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl Header {
+    fn new(kind: u32) -> Header {
+        Header {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+/// `virtio_gpu_rect`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for GpuRect {
+    fn from(rect: Rect) -> GpuRect {
+        GpuRect {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct DisplayInfo {
+    header: Header,
+    displays: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+/// `virtio_gpu_resource_attach_backing` with one `virtio_gpu_mem_entry`.
+#[repr(C)]
+struct AttachBacking {
+    header: Header,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: Header,
+    rect: GpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: Header,
+    rect: GpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: Header,
+    rect: GpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// The bytes of a command. They're all `repr(C)` and have no padding the compiler added.
+fn as_bytes<T>(command: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(command as *const T as *const u8, size_of::<T>()) }
+}
+
+pub struct VirtioGpu {
+    transport: Transport,
+    control: CompletionQueue,
+    interrupt: InterruptId,
+    /// Set once there's a framebuffer.
+    screen: Once<Screen>,
+}
+
+struct Screen {
+    width: u32,
+    height: u32,
+    stride: usize,
+    pixels: DmaBuffer,
+}
+
+impl VirtioGpu {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?;
+        let queue = transport.setup_queue(CONTROL_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+        transport.finish_init();
+
+        Ok(VirtioGpu {
+            transport,
+            control: CompletionQueue::new(queue),
+            interrupt,
+            screen: Once::INIT,
+        })
+    }
+
+    /// Send `command` and sleep until the device answers. Returns the response, which is
+    /// `response_len` bytes.
+    fn command<T>(&self, command: &T, response_len: usize) -> Result<DmaBuffer, GraphicsError> {
+        let command = as_bytes(command);
+        let mut buffer =
+            dma::alloc(command.len() + response_len, 8).map_err(|_| GraphicsError::OutOfMemory)?;
+        unsafe { buffer.as_mut_slice()[..command.len()].copy_from_slice(command) };
+        buffer.sync_for_device();
+        let (request, response) = unsafe {
+            let base = buffer.as_ptr();
+            (
+                core::slice::from_raw_parts(base, command.len()),
+                core::slice::from_raw_parts_mut(base.add(command.len()), response_len),
+            )
+        };
+
+        let head = unsafe {
+            self.control
+                .submit(&self.transport, &[request], &mut [response])
+        }
+        .map_err(|_| GraphicsError::Io)?;
+        self.control.wait(head);
+        buffer.sync_for_cpu();
+        Ok(buffer)
+    }
+
+    /// Send `command` and check the device says OK. Doesn't log, since the framebuffer
+    /// console flushes with the console locked.
+    fn command_ok<T>(&self, command: &T) -> Result<(), GraphicsError> {
+        let response = self.command(command, size_of::<Header>())?;
+        let kind = unsafe {
+            let header = response.as_ptr().add(size_of::<T>()) as *const Header;
+            header.read_unaligned().kind
+        };
+        match kind {
+            RESP_OK_NODATA => Ok(()),
+            _ => Err(GraphicsError::Io),
+        }
+    }
+
+    /// The first enabled display, and its size.
+    fn display(&self) -> Result<(u32, u32, u32), GraphicsError> {
+        let get = Header::new(CMD_GET_DISPLAY_INFO);
+        let response = self.command(&get, size_of::<DisplayInfo>())?;
+        let info = unsafe {
+            let info = response.as_ptr().add(size_of::<Header>()) as *const DisplayInfo;
+            info.read_unaligned()
+        };
+        if info.header.kind != RESP_OK_DISPLAY_INFO {
+            return Err(GraphicsError::Io);
+        }
+        let found = info
+            .displays
+            .iter()
+            .enumerate()
+            .find(|(_, display)| display.enabled != 0 && display.rect.width != 0);
+        Ok(match found {
+            Some((id, display)) => (id as u32, display.rect.width, display.rect.height),
+            None => (0, DEFAULT_SIZE.0, DEFAULT_SIZE.1),
+        })
+    }
+
+    /// Make a resource for the first display and show it there.
+    fn set_up_screen(self: &Arc<Self>) -> Result<Arc<Framebuffer>, GraphicsError> {
+        let (scanout_id, width, height) = self.display()?;
+        let format = PixelFormat::Bgrx8888;
+        let stride = width as usize * format.bytes_per_pixel();
+        let pixels =
+            dma::alloc(stride * height as usize, 4096).map_err(|_| GraphicsError::OutOfMemory)?;
+
+        self.command_ok(&ResourceCreate2d {
+            header: Header::new(CMD_RESOURCE_CREATE_2D),
+            resource_id: RESOURCE_ID,
+            format: FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        self.command_ok(&AttachBacking {
+            header: Header::new(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: RESOURCE_ID,
+            nr_entries: 1,
+            addr: pixels.phys(),
+            length: pixels.len() as u32,
+            padding: 0,
+        })?;
+        self.command_ok(&SetScanout {
+            header: Header::new(CMD_SET_SCANOUT),
+            rect: Rect::new(0, 0, width, height).into(),
+            scanout_id,
+            resource_id: RESOURCE_ID,
+        })?;
+
+        let base = NonNull::new(pixels.as_ptr()).unwrap();
+        self.screen.call_once(|| Screen {
+            width,
+            height,
+            stride,
+            pixels,
+        });
+        let framebuffer =
+            unsafe { Framebuffer::new(width, height, stride, format, base, self.clone()) };
+        Ok(Arc::new(framebuffer))
+    }
+}
+
+impl Scanout for VirtioGpu {
+    fn flush(&self, rect: Rect) -> Result<(), GraphicsError> {
+        let screen = self.screen.get().ok_or(GraphicsError::Unsupported)?;
+        let rect = rect.clip(screen.width, screen.height);
+        screen.pixels.sync_for_device();
+        self.command_ok(&TransferToHost2d {
+            header: Header::new(CMD_TRANSFER_TO_HOST_2D),
+            rect: rect.into(),
+            offset: (rect.y as usize * screen.stride + rect.x as usize * 4) as u64,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })?;
+        self.command_ok(&ResourceFlush {
+            header: Header::new(CMD_RESOURCE_FLUSH),
+            rect: rect.into(),
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })
+    }
+}
+
+/// PLIC handler. Marks finished commands so their callers wake up.
+fn gpu_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.transport.ack_interrupt();
+        device.control.collect();
+    }
+}
+
+/// Set up every virtio-gpu device and register a framebuffer for each.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Gpu) {
+            match VirtioGpu::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-gpu: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, gpu_interrupt);
+        plic::enable_interrupt(device.interrupt);
+
+        match device.set_up_screen() {
+            Ok(framebuffer) => graphics::register(framebuffer),
+            Err(err) => log::error!("virtio-gpu: {}", err),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! virtio-input driver, for keyboards, mice and tablets. Section 5.8 of the virtio spec.
+//!
+//! The event queue is kept full of buffers for one `virtio_input_event` each, carved out
+//! of one [`DmaBuffer`]. After an interrupt, [`EVENT_WORK`] gives the events to
+//! [`input::report`] and the buffers back to the device. The status queue, which would set the keyboard's
+//! LEDs, isn't used.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    dma::{self, DmaBuffer},
+    input,
+    isr::plic::{self, InterruptId},
+    log,
+    prelude::*,
+    sync::{IrqSafeMutex, Once},
+    workqueue::Work,
+};
+
+use super::{queue::VirtQueue, DeviceType, Transport, VirtioError};
+
+static EVENT_WORK: Work = Work::new("virtio-input", collect);
+
+const QUEUE_SIZE: u16 = 64;
+const EVENT_QUEUE: u16 = 0;
+
+/// `virtio_input_event`: type, code and value, little endian.
+const EVENT_SIZE: usize = 8;
+
+/// Offsets in the device config. Writing `select` and `subsel` picks what's in `data`.
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+const CFG_ID_NAME: u8 = 0x01;
+
+static DEVICES: Once<Vec<Arc<VirtioInput>>> = Once::INIT;
+
+struct Inner {
+    transport: Transport,
+    queue: VirtQueue,
+    /// Every event buffer, [`EVENT_SIZE`] bytes each.
+    buffers: DmaBuffer,
+    /// Which buffer each descriptor chain is, by head.
+    slots: Vec<usize>,
+}
+
+impl Inner {
+    /// Give event buffer `slot` to the device.
+    fn post(&mut self, slot: usize) {
+        let buffer = unsafe {
+            let start = self.buffers.as_ptr().add(slot * EVENT_SIZE);
+            core::slice::from_raw_parts_mut(start, EVENT_SIZE)
+        };
+        // The queue has a descriptor for every buffer, so there's always room.
+        let head = unsafe { self.queue.add(&[], &mut [buffer]) }.expect("event queue full");
+        self.slots[head as usize] = slot;
+    }
+
+    /// Report every event the device has written, and give the buffers back.
+    fn collect(&mut self) {
+        let mut posted = false;
+        while let Some((head, _len)) = self.queue.pop_used() {
+            let slot = self.slots[head as usize];
+            self.buffers.sync_for_cpu();
+            let event = unsafe {
+                let start = self.buffers.as_ptr().add(slot * EVENT_SIZE);
+                core::slice::from_raw_parts(start, EVENT_SIZE)
+            };
+            let kind = u16::from_le_bytes([event[0], event[1]]);
+            let code = u16::from_le_bytes([event[2], event[3]]);
+            let value = u32::from_le_bytes([event[4], event[5], event[6], event[7]]);
+            input::report(kind, code, value);
+            self.post(slot);
+            posted = true;
+        }
+        if posted && self.queue.should_notify() {
+            self.transport.notify(EVENT_QUEUE);
+        }
+    }
+}
+
+pub struct VirtioInput {
+    /// Taken with interrupts off, since the interrupt handler takes it too.
+    inner: IrqSafeMutex<Inner>,
+    interrupt: InterruptId,
+    name: String,
+}
+
+impl VirtioInput {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?;
+        let queue = transport.setup_queue(EVENT_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+        let name = read_name(&transport);
+
+        let slots = queue.size() as usize;
+        let buffers = dma::alloc(slots * EVENT_SIZE, 8).map_err(|_| VirtioError::OutOfMemory)?;
+        let mut inner = Inner {
+            slots: vec![0; slots],
+            transport,
+            queue,
+            buffers,
+        };
+        for slot in 0..slots {
+            inner.post(slot);
+        }
+        inner.transport.finish_init();
+        inner.transport.notify(EVENT_QUEUE);
+
+        Ok(VirtioInput {
+            inner: IrqSafeMutex::new(inner),
+            interrupt,
+            name,
+        })
+    }
+}
+
+/// What the device calls itself, like "QEMU Virtio Keyboard".
+fn read_name(transport: &Transport) -> String {
+    transport.write_config::<u8>(CONFIG_SELECT, CFG_ID_NAME);
+    transport.write_config::<u8>(CONFIG_SUBSEL, 0);
+    let size = transport.read_config::<u8>(CONFIG_SIZE) as usize;
+    (0..size)
+        .map(|i| transport.read_config::<u8>(CONFIG_DATA + i) as char)
+        .collect()
+}
+
+/// PLIC handler. Leaves the events to [`collect`].
+fn input_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.inner.lock().transport.ack_interrupt();
+    }
+    EVENT_WORK.schedule();
+}
+
+/// Report what every device has sent.
+fn collect() {
+    for device in DEVICES.get().into_iter().flatten() {
+        device.inner.lock().collect();
+    }
+}
+
+/// Set up every virtio-input device.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Input) {
+            match VirtioInput::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-input: {}", err),
+            }
+        }
+        devices
+    });
+
+    EVENT_WORK.register();
+    for device in DEVICES.get().unwrap() {
+        log::info!("virtio-input: {}", device.name);
+        plic::register_handler(device.interrupt, input_interrupt);
+        plic::enable_interrupt(device.interrupt);
+    }
+}
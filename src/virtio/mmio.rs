@@ -0,0 +1,266 @@
+//! virtio-mmio transport. Section 4.2 of the virtio spec.
+//!
+//! Handles both the legacy (version 1) register layout, which is what QEMU gives you by
+//! default, and the modern (version 2) layout.
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{isr::plic::InterruptId, mmio::Reg, pagetable::PAGE_SIZE};
+
+use super::{features, queue::VirtQueue, DeviceStatus, DeviceType, VirtioError};
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // legacy
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // legacy
+const QUEUE_PFN: usize = 0x040; // legacy
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// Interrupt status bits.
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+#[derive(Debug)]
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+    device_type: DeviceType,
+    vendor_id: u32,
+    interrupt: InterruptId,
+}
+
+impl MmioTransport {
+    /// Probe a virtio-mmio slot. Returns `None` if nothing is plugged into it.
+    ///
+    /// # Safety
+    /// `base` must be a virtio-mmio register block that nothing else is using.
+    pub unsafe fn new(base: usize, interrupt: InterruptId) -> Result<Option<Self>, VirtioError> {
+        let mut transport = MmioTransport {
+            base,
+            version: 0,
+            device_type: DeviceType::Unknown(0),
+            vendor_id: 0,
+            interrupt,
+        };
+
+        let magic = transport.read(MAGIC_VALUE);
+        if magic != MAGIC {
+            return Err(VirtioError::BadMagic(magic));
+        }
+        transport.version = transport.read(VERSION);
+        if transport.version != 1 && transport.version != 2 {
+            return Err(VirtioError::UnsupportedVersion(transport.version));
+        }
+        let device_id = transport.read(DEVICE_ID);
+        if device_id == 0 {
+            return Ok(None);
+        }
+        transport.device_type = DeviceType::from(device_id);
+        transport.vendor_id = transport.read(VENDOR_ID);
+
+        Ok(Some(transport))
+    }
+
+    /// The register at `offset`, or the config field at `CONFIG + offset`.
+    fn reg<T: Copy>(&self, offset: usize) -> Reg<T> {
+        unsafe { Reg::new(self.base + offset) }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        self.reg(offset).read()
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.reg(offset).write(value)
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.vendor_id
+    }
+
+    pub fn interrupt(&self) -> InterruptId {
+        self.interrupt
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.read(STATUS))
+    }
+
+    fn set_status(&self, status: DeviceStatus) {
+        self.write(STATUS, status.bits());
+    }
+
+    fn add_status(&self, status: DeviceStatus) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Reset the device. Any queues it had are no longer in use after this returns.
+    pub fn reset(&mut self) {
+        self.write(STATUS, 0);
+        while self.read(STATUS) != 0 {
+            crate::cpu::relax();
+        }
+    }
+
+    /// Tell the device we've given up on it.
+    pub fn fail(&mut self) {
+        self.add_status(DeviceStatus::FAILED);
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        high << 32 | low
+    }
+
+    fn write_driver_features(&self, features: u64) {
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    /// Reset the device and negotiate features. Steps 1-6 of the initialization sequence
+    /// in section 3.1.1. Returns the features both sides support.
+    ///
+    /// Set up queues and device config after this, then call [`finish_init`](Self::finish_init).
+    pub fn begin_init(&mut self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+
+        let mut supported = supported;
+        if !self.is_legacy() {
+            supported |= features::VERSION_1;
+        }
+        let negotiated = self.device_features() & supported;
+        self.write_driver_features(negotiated);
+
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.add_status(DeviceStatus::FEATURES_OK);
+            if !self.status().contains(DeviceStatus::FEATURES_OK) {
+                self.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+
+        Ok(negotiated)
+    }
+
+    /// Let the device start using its queues.
+    pub fn finish_init(&mut self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+    }
+
+    /// Allocate and install queue `index` with up to `size` entries. The device may
+    /// support fewer.
+    pub fn setup_queue(&mut self, index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        self.write(QUEUE_SEL, index as u32);
+
+        let in_use = if self.is_legacy() {
+            self.read(QUEUE_PFN) != 0
+        } else {
+            self.read(QUEUE_READY) != 0
+        };
+        if in_use {
+            return Err(VirtioError::QueueInUse(index));
+        }
+
+        let max = self.read(QUEUE_NUM_MAX);
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+        // Queues are a power of two long, but the most a device takes needn't be.
+        let max = max.min(1 << 15);
+        let size = size.min(1 << (31 - max.leading_zeros()));
+        let queue = VirtQueue::new(index, size)?;
+        self.write(QUEUE_NUM, size as u32);
+
+        if self.is_legacy() {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (queue.desc_addr() / PAGE_SIZE) as u32);
+        } else {
+            let (desc, driver, device) = (queue.desc_addr(), queue.avail_addr(), queue.used_addr());
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, driver as u32);
+            self.write(QUEUE_DRIVER_HIGH, (driver >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, device as u32);
+            self.write(QUEUE_DEVICE_HIGH, (device >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+
+        Ok(queue)
+    }
+
+    /// Tell the device there's new buffers in `queue`.
+    pub fn notify(&self, queue: u16) {
+        // Descriptors and the avail ring must be visible before the device looks.
+        fence(Ordering::SeqCst);
+        self.write(QUEUE_NOTIFY, queue as u32);
+    }
+
+    /// Read and acknowledge the interrupt status. Call from the device's interrupt handler.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+
+    /// Read a device specific config field at `offset`.
+    ///
+    /// Fields wider than 32 bits may change while being read, so this retries until the
+    /// config generation is stable. Legacy devices don't have a generation counter.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        loop {
+            let before = self.read(CONFIG_GENERATION);
+            let value = self.reg::<T>(CONFIG + offset).read();
+            if self.is_legacy() || self.read(CONFIG_GENERATION) == before {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        self.reg(CONFIG + offset).write(value)
+    }
+}
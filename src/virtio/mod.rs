@@ -0,0 +1,272 @@
+//! Virtio devices.
+//!
+//! QEMU's _virt_ machine has a row of `virtio,mmio` slots in the device tree. The driver
+//! model calls [`probe_mmio`] for each one, which keeps a [`MmioTransport`] for every slot
+//! that has a device. Devices can also be on PCI, and [`probe_pci`] keeps a
+//! [`PciTransport`] for each of those.
+//! Drivers [`take`] the [`Transport`] for the device type they handle, negotiate features,
+//! and set up their [`VirtQueue`](queue::VirtQueue)s. Which kind it is doesn't matter to
+//! them.
+
+pub mod blk;
+pub mod console;
+pub mod gpu;
+pub mod input;
+mod mmio;
+pub mod net;
+pub mod ninep;
+mod pci;
+pub mod queue;
+pub mod rng;
+pub mod sound;
+
+use core::fmt::{self, Display, Formatter};
+
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    addr::PhysAddr,
+    devices::{Driver, DtNode, ProbeError},
+    error::KernelError,
+    isr::plic::InterruptId,
+    log,
+    pagetable::memory_map::ioremap,
+};
+
+use queue::VirtQueue;
+
+pub use mmio::MmioTransport;
+pub use pci::PciTransport;
+
+/// Transports no driver has taken yet.
+static DEVICES: Mutex<Vec<Transport>> = Mutex::new(Vec::new());
+
+/// A device on either transport.
+#[derive(Debug)]
+pub enum Transport {
+    Mmio(MmioTransport),
+    Pci(PciTransport),
+}
+
+/// Call the same method on whichever transport it is.
+macro_rules! dispatch {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self {
+            Transport::Mmio(transport) => transport.$method($($arg),*),
+            Transport::Pci(transport) => transport.$method($($arg),*),
+        }
+    };
+}
+
+impl Transport {
+    pub fn device_type(&self) -> DeviceType {
+        dispatch!(self.device_type())
+    }
+
+    pub fn interrupt(&self) -> InterruptId {
+        dispatch!(self.interrupt())
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        dispatch!(self.status())
+    }
+
+    pub fn reset(&mut self) {
+        dispatch!(self.reset())
+    }
+
+    pub fn fail(&mut self) {
+        dispatch!(self.fail())
+    }
+
+    /// See [`MmioTransport::begin_init`].
+    pub fn begin_init(&mut self, supported: u64) -> Result<u64, VirtioError> {
+        dispatch!(self.begin_init(supported))
+    }
+
+    pub fn finish_init(&mut self) {
+        dispatch!(self.finish_init())
+    }
+
+    pub fn setup_queue(&mut self, index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        dispatch!(self.setup_queue(index, size))
+    }
+
+    pub fn notify(&self, queue: u16) {
+        dispatch!(self.notify(queue))
+    }
+
+    pub fn ack_interrupt(&self) -> u32 {
+        dispatch!(self.ack_interrupt())
+    }
+
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        dispatch!(self.read_config(offset))
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        dispatch!(self.write_config(offset, value))
+    }
+}
+
+/// Device IDs from section 5 of the virtio spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    NineP,
+    Gpu,
+    Input,
+    Socket,
+    Sound,
+    Unknown(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        use DeviceType::*;
+        match id {
+            1 => Network,
+            2 => Block,
+            3 => Console,
+            4 => Entropy,
+            5 => Balloon,
+            8 => Scsi,
+            9 => NineP,
+            16 => Gpu,
+            18 => Input,
+            19 => Socket,
+            25 => Sound,
+            id => Unknown(id),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Device status register. The driver sets these in order during initialization.
+    pub struct DeviceStatus: u32 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 2;
+        const DRIVER_OK = 4;
+        const FEATURES_OK = 8;
+        const DEVICE_NEEDS_RESET = 64;
+        const FAILED = 128;
+    }
+}
+
+/// Feature bits shared by every device type. Device specific bits are below 24.
+pub mod features {
+    pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+    pub const RING_EVENT_IDX: u64 = 1 << 29;
+    /// Set by devices that follow virtio 1.0 or later. Required by the modern transport.
+    pub const VERSION_1: u64 = 1 << 32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Not a virtio-mmio register block.
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    /// The device didn't accept the features we asked for.
+    FeaturesRejected,
+    /// The queue doesn't exist on this device.
+    QueueUnavailable(u16),
+    /// The queue has already been set up.
+    QueueInUse(u16),
+    /// Not enough free descriptors for the buffers.
+    QueueFull,
+    /// Tried to add a chain with no buffers.
+    EmptyChain,
+    /// The device reported an error.
+    DeviceFailed,
+    /// No memory for a queue.
+    OutOfMemory,
+}
+
+impl Display for VirtioError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtioError::BadMagic(magic) => write!(f, "bad virtio-mmio magic: 0x{magic:08x}"),
+            VirtioError::UnsupportedVersion(version) => {
+                write!(f, "unsupported virtio-mmio version: {version}")
+            }
+            VirtioError::FeaturesRejected => write!(f, "device rejected features"),
+            VirtioError::QueueUnavailable(queue) => write!(f, "queue {queue} not available"),
+            VirtioError::QueueInUse(queue) => write!(f, "queue {queue} already in use"),
+            VirtioError::QueueFull => write!(f, "virtqueue full"),
+            VirtioError::EmptyChain => write!(f, "no buffers to add"),
+            VirtioError::DeviceFailed => write!(f, "device failed"),
+            VirtioError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+impl core::error::Error for VirtioError {}
+
+/// A virtio-mmio slot with a device in it. The device itself goes to whichever driver
+/// [`take`]s it.
+struct MmioSlot {
+    base: PhysAddr,
+    device_type: DeviceType,
+    version: u32,
+}
+
+impl Driver for MmioSlot {
+    fn describe(&self) -> String {
+        format!(
+            "{:?} at 0x{:x} (version {})",
+            self.device_type, self.base, self.version
+        )
+    }
+}
+
+/// Probe a `virtio,mmio` node.
+pub fn probe_mmio(node: &DtNode) -> Result<Box<dyn Driver>, KernelError> {
+    let reg = node.reg(0).ok_or(ProbeError::MissingProperty("reg"))?;
+    let interrupt = node
+        .interrupt()
+        .ok_or(ProbeError::MissingProperty("interrupts"))?;
+    let base = ioremap(reg.start, reg.end - reg.start, "virtio-mmio").as_u64() as usize;
+    let transport = unsafe { MmioTransport::new(base, InterruptId::from(interrupt)) }?
+        .ok_or(ProbeError::NoDevice)?;
+    let slot = MmioSlot {
+        base: reg.start,
+        device_type: transport.device_type(),
+        version: transport.version(),
+    };
+    DEVICES.lock().push(Transport::Mmio(transport));
+    Ok(Box::new(slot))
+}
+
+/// Keep a transport for every virtio device found on PCI. Call after probing the host
+/// bridge.
+pub fn probe_pci() {
+    for device in crate::pci::devices() {
+        match PciTransport::new(&device) {
+            Ok(Some(transport)) => {
+                log::info!(
+                    "pci {}: virtio {:?}",
+                    device.address,
+                    transport.device_type()
+                );
+                DEVICES.lock().push(Transport::Pci(transport));
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("pci {}: virtio: {}", device.address, err),
+        }
+    }
+}
+
+/// Take the first unclaimed device of `device_type`. Each transport is handed out once.
+pub fn take(device_type: DeviceType) -> Option<Transport> {
+    let mut devices = DEVICES.lock();
+    let index = devices
+        .iter()
+        .position(|device| device.device_type() == device_type)?;
+    Some(devices.remove(index))
+}
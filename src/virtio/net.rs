@@ -0,0 +1,208 @@
+//! virtio-net driver. Section 5.1 of the virtio spec.
+//!
+//! Queue 0 receives and queue 1 transmits. Every buffer starts with a `virtio_net_hdr`,
+//! which we leave zeroed: no checksum offload or segmentation. The receive queue is kept
+//! full of [`RX_BUFFER`] sized buffers, each big enough for a whole frame, carved out of
+//! one [`DmaBuffer`]. Frames to send are copied into a [`DmaBuffer`] of their own, freed
+//! once the device hands it back.
+//!
+//! The interrupt handler only acknowledges the interrupt and [`wake`](net::wake)s the
+//! network task, which collects the frames.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    dma::{self, DmaBuffer},
+    isr::plic::{self, InterruptId},
+    log,
+    net::{self, wire::MacAddr, NetDevice, NetError},
+    prelude::*,
+    sync::{IrqSafeMutex, Once},
+};
+
+use super::{features, queue::VirtQueue, DeviceType, Transport, VirtioError};
+
+const QUEUE_SIZE: u16 = 16;
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// The device has given us its MAC address in the config space.
+const NET_F_MAC: u64 = 1 << 5;
+
+/// Offset of `mac` in the device config.
+const CONFIG_MAC: usize = 0;
+
+/// Without `VERSION_1` or mergeable receive buffers the header stops before `num_buffers`.
+const LEGACY_HEADER: usize = 10;
+const HEADER: usize = 12;
+/// Ethernet header and the largest payload, without a VLAN tag.
+const MAX_FRAME: usize = 1514;
+const RX_BUFFER: usize = 2048;
+
+static DEVICES: Once<Vec<Arc<VirtioNet>>> = Once::INIT;
+
+struct Inner {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// Every receive buffer, [`RX_BUFFER`] bytes each.
+    rx_buffers: DmaBuffer,
+    /// Which receive buffer each descriptor chain is, by head.
+    rx_slots: Vec<usize>,
+    /// Frames the device is still sending, by head.
+    tx_buffers: Vec<Option<DmaBuffer>>,
+}
+
+impl Inner {
+    /// Give receive buffer `slot` to the device.
+    fn post_rx(&mut self, slot: usize) {
+        let buffer = unsafe {
+            let start = self.rx_buffers.as_ptr().add(slot * RX_BUFFER);
+            core::slice::from_raw_parts_mut(start, RX_BUFFER)
+        };
+        // The queue has a descriptor for every buffer, so there's always room.
+        let head = unsafe { self.rx.add(&[], &mut [buffer]) }.expect("receive queue full");
+        self.rx_slots[head as usize] = slot;
+    }
+
+    /// Free the frames the device has finished sending.
+    fn reclaim_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            self.tx_buffers[head as usize] = None;
+        }
+    }
+}
+
+pub struct VirtioNet {
+    /// Taken with interrupts off, since the interrupt handler takes it too.
+    inner: IrqSafeMutex<Inner>,
+    interrupt: InterruptId,
+    mac: MacAddr,
+    header: usize,
+}
+
+impl VirtioNet {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        let features = transport.begin_init(NET_F_MAC)?;
+        let rx = transport.setup_queue(RX_QUEUE, QUEUE_SIZE)?;
+        let tx = transport.setup_queue(TX_QUEUE, QUEUE_SIZE)?;
+        let mac = if features & NET_F_MAC != 0 {
+            let mut mac = [0; 6];
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.read_config::<u8>(CONFIG_MAC + i);
+            }
+            MacAddr(mac)
+        } else {
+            // Locally administered, and what QEMU would have picked.
+            MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+        };
+        let header = if features & features::VERSION_1 != 0 {
+            HEADER
+        } else {
+            LEGACY_HEADER
+        };
+        let interrupt = transport.interrupt();
+
+        let slots = rx.size() as usize;
+        let rx_buffers = dma::alloc(slots * RX_BUFFER, 16).map_err(|_| VirtioError::OutOfMemory)?;
+        let mut inner = Inner {
+            rx_slots: vec![0; slots],
+            tx_buffers: (0..tx.size()).map(|_| None).collect(),
+            transport,
+            rx,
+            tx,
+            rx_buffers,
+        };
+        for slot in 0..slots {
+            inner.post_rx(slot);
+        }
+        inner.transport.finish_init();
+        inner.transport.notify(RX_QUEUE);
+
+        Ok(VirtioNet {
+            inner: IrqSafeMutex::new(inner),
+            interrupt,
+            mac,
+            header,
+        })
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn max_frame(&self) -> usize {
+        MAX_FRAME
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::TooBig);
+        }
+        let mut buffer =
+            dma::alloc(self.header + frame.len(), 16).map_err(|_| NetError::OutOfMemory)?;
+        unsafe { buffer.as_mut_slice()[self.header..].copy_from_slice(frame) };
+        buffer.sync_for_device();
+
+        let mut inner = self.inner.lock();
+        inner.reclaim_tx();
+        let bytes = unsafe { buffer.as_slice() };
+        let head = unsafe { inner.tx.add(&[bytes], &mut []) }.map_err(|_| NetError::Busy)?;
+        inner.tx_buffers[head as usize] = Some(buffer);
+        if inner.tx.should_notify() {
+            inner.transport.notify(TX_QUEUE);
+        }
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock();
+        let (head, len) = inner.rx.pop_used()?;
+        let slot = inner.rx_slots[head as usize];
+        inner.rx_buffers.sync_for_cpu();
+        let frame = unsafe {
+            let start = inner.rx_buffers.as_ptr().add(slot * RX_BUFFER);
+            let len = (len as usize).min(RX_BUFFER);
+            let buffer = core::slice::from_raw_parts(start, len);
+            buffer.get(self.header..).unwrap_or_default().to_vec()
+        };
+        inner.post_rx(slot);
+        if inner.rx.should_notify() {
+            inner.transport.notify(RX_QUEUE);
+        }
+        Some(frame)
+    }
+}
+
+/// PLIC handler. The network task does the rest.
+fn net_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.inner.lock().transport.ack_interrupt();
+        net::wake();
+    }
+}
+
+/// Set up every virtio-net device and give them to the network stack.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Network) {
+            match VirtioNet::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-net: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, net_interrupt);
+        plic::enable_interrupt(device.interrupt);
+        net::add_device(device.clone());
+    }
+}
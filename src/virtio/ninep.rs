@@ -0,0 +1,158 @@
+//! virtio-9p driver, the transport for [`fs::ninep`](crate::fs::ninep).
+//!
+//! The virtio spec only gives it a device ID; QEMU's device is the reference. One queue:
+//! each request is a chain of the T-message for the device to read and room for the
+//! R-message it writes back. The config space has the mount tag, which names the share on
+//! the host and where it's mounted here. Requests go one at a time and the caller sleeps
+//! on a [`CompletionQueue`] until they're back.
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    dma,
+    fs::{self, ninep::NinePFs, FsError},
+    isr::plic::{self, InterruptId},
+    log,
+    sync::Once,
+};
+
+use super::{queue::CompletionQueue, DeviceType, Transport, VirtioError};
+
+const QUEUE_SIZE: u16 = 4;
+const REQUEST_QUEUE: u16 = 0;
+/// The biggest message we'll send or take.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+const FEATURE_MOUNT_TAG: u64 = 1 << 0;
+
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+
+static DEVICES: Once<Vec<Arc<VirtioNineP>>> = Once::INIT;
+
+pub struct VirtioNineP {
+    transport: Transport,
+    requests: CompletionQueue,
+    /// Held for a whole request, since every message has the same tag.
+    request: Mutex<()>,
+    interrupt: InterruptId,
+    tag: String,
+}
+
+impl VirtioNineP {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        let features = transport.begin_init(FEATURE_MOUNT_TAG)?;
+        let tag = match features & FEATURE_MOUNT_TAG {
+            0 => String::from("9p"),
+            _ => {
+                let len = transport.read_config::<u16>(CONFIG_TAG_LEN) as usize;
+                (0..len)
+                    .map(|i| transport.read_config::<u8>(CONFIG_TAG + i) as char)
+                    .collect()
+            }
+        };
+        let queue = transport.setup_queue(REQUEST_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+        transport.finish_init();
+
+        Ok(VirtioNineP {
+            transport,
+            requests: CompletionQueue::new(queue),
+            request: Mutex::new(()),
+            interrupt,
+            tag,
+        })
+    }
+
+    fn request(&self, message: &[u8]) -> Result<Vec<u8>, VirtioError> {
+        let _request = self.request.lock();
+        let mut buffer =
+            dma::alloc(message.len() + MAX_MESSAGE, 8).map_err(|_| VirtioError::OutOfMemory)?;
+        unsafe { buffer.as_mut_slice()[..message.len()].copy_from_slice(message) };
+        buffer.sync_for_device();
+
+        let (out, response) = unsafe { buffer.as_mut_slice() }.split_at_mut(message.len());
+        let head = unsafe {
+            self.requests
+                .submit(&self.transport, &[out], &mut [response])
+        }?;
+        let len = (self.requests.wait(head) as usize).min(MAX_MESSAGE);
+        buffer.sync_for_cpu();
+        let response = unsafe { &buffer.as_slice()[message.len()..] };
+        // Believe the size in the message over the length from the device.
+        let size = match response.get(..4) {
+            Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+            None => return Err(VirtioError::DeviceFailed),
+        };
+        if size > len {
+            return Err(VirtioError::DeviceFailed);
+        }
+        Ok(response[..size].to_vec())
+    }
+}
+
+impl fs::ninep::Transport for VirtioNineP {
+    fn max_message(&self) -> usize {
+        MAX_MESSAGE
+    }
+
+    fn rpc(&self, request: &[u8]) -> fs::Result<Vec<u8>> {
+        self.request(request).map_err(|err| {
+            log::warn!("virtio-9p {}: {}", self.tag, err);
+            FsError::Io(crate::block::BlockError::Io)
+        })
+    }
+}
+
+/// PLIC handler. Marks finished requests so their callers wake up.
+fn ninep_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.transport.ack_interrupt();
+        device.requests.collect();
+    }
+}
+
+/// Set up every virtio-9p device.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::NineP) {
+            match VirtioNineP::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-9p: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, ninep_interrupt);
+        plic::enable_interrupt(device.interrupt);
+    }
+}
+
+/// Mount every share at `/mnt/<tag>`.
+pub fn mount_all() {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices {
+        let fs = match NinePFs::new(device.clone(), "") {
+            Ok(fs) => fs,
+            Err(err) => {
+                log::warn!("9p {}: {}", device.tag, err);
+                continue;
+            }
+        };
+        let path = format!("/mnt/{}", device.tag);
+        // So it shows up when listing /mnt. Mounting works without it.
+        let _ = fs::mkdir_all(&path);
+        if let Err(err) = fs::mount(&path, fs) {
+            log::error!("failed to mount {}: {}", device.tag, err);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! virtio-pci transport, modern interface only. Section 4.1 of the virtio spec.
+//!
+//! The device's vendor capabilities say which BAR, and where in it, each register block
+//! is: the common config, the notification area, the ISR status byte and the device
+//! config. Each is mapped on its own. Interrupts are INTx, read and cleared through the
+//! ISR byte, since there's nothing to send MSI-X to.
+
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    addr::PhysAddr,
+    isr::plic::InterruptId,
+    mmio::Reg,
+    pagetable::memory_map::ioremap,
+    pci::{self, Bar, PciDevice},
+};
+
+use super::{features, queue::VirtQueue, DeviceStatus, DeviceType, VirtioError};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+/// Modern devices are this plus the device type.
+const MODERN_DEVICE_ID: u16 = 0x1040;
+/// Transitional devices are in this range, with the type in the subsystem ID.
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// `virtio_pci_cap`, after the standard capability header.
+const CAP_CFG_TYPE: usize = 3;
+const CAP_BAR: usize = 4;
+const CAP_OFFSET: usize = 8;
+const CAP_LENGTH: usize = 12;
+const CAP_NOTIFY_MULTIPLIER: usize = 16;
+
+// `virtio_pci_common_cfg`.
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+#[derive(Debug)]
+pub struct PciTransport {
+    device_type: DeviceType,
+    interrupt: InterruptId,
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    isr: usize,
+    /// Not every device has config.
+    device_config: Option<usize>,
+    /// Each queue's offset into the notification area, by index.
+    notify_offsets: Vec<usize>,
+}
+
+impl PciTransport {
+    /// The transport for a virtio PCI function. Returns `None` if it isn't one.
+    pub fn new(device: &PciDevice) -> Result<Option<Self>, VirtioError> {
+        if device.vendor_id != VENDOR_ID {
+            return Ok(None);
+        }
+        let device_type = match device.device_id {
+            id if id >= MODERN_DEVICE_ID => DeviceType::from((id - MODERN_DEVICE_ID) as u32),
+            id if TRANSITIONAL_DEVICE_IDS.contains(&id) => {
+                DeviceType::from(device.config.read_u16(pci::SUBSYSTEM_ID) as u32)
+            }
+            _ => return Ok(None),
+        };
+
+        let (mut common, mut notify, mut isr, mut device_config) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        let config = &device.config;
+        for (id, cap) in config.capabilities() {
+            if id != pci::CAP_VENDOR {
+                continue;
+            }
+            let Some(Bar::Memory { address, size, .. }) = device
+                .bars
+                .get(config.read_u8(cap + CAP_BAR) as usize)
+                .copied()
+                .flatten()
+            else {
+                continue;
+            };
+            let offset = config.read_u32(cap + CAP_OFFSET) as u64;
+            let length = config.read_u32(cap + CAP_LENGTH) as u64;
+            if address == 0 || offset + length > size {
+                continue;
+            }
+            let map = || {
+                let pa = PhysAddr::try_new(address + offset)?;
+                Some(ioremap(pa, length, "virtio-pci").as_u64() as usize)
+            };
+            // The first of each type is the one to use.
+            match config.read_u8(cap + CAP_CFG_TYPE) {
+                CAP_COMMON_CFG if common.is_none() => common = map(),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = map();
+                    notify_multiplier = config.read_u32(cap + CAP_NOTIFY_MULTIPLIER);
+                }
+                CAP_ISR_CFG if isr.is_none() => isr = map(),
+                CAP_DEVICE_CFG if device_config.is_none() => device_config = map(),
+                _ => {}
+            }
+        }
+
+        // Without these it's a legacy-only device.
+        let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) else {
+            return Err(VirtioError::UnsupportedVersion(0));
+        };
+        let interrupt = device.interrupt.ok_or(VirtioError::DeviceFailed)?;
+        Ok(Some(PciTransport {
+            device_type,
+            interrupt,
+            common,
+            notify,
+            notify_multiplier,
+            isr,
+            device_config,
+            notify_offsets: Vec::new(),
+        }))
+    }
+
+    fn common<T: Copy>(&self, offset: usize) -> Reg<T> {
+        unsafe { Reg::new(self.common + offset) }
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn interrupt(&self) -> InterruptId {
+        self.interrupt
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.common::<u8>(DEVICE_STATUS).read() as u32)
+    }
+
+    fn add_status(&self, status: DeviceStatus) {
+        let status = self.status() | status;
+        self.common::<u8>(DEVICE_STATUS).write(status.bits() as u8);
+    }
+
+    /// Reset the device. Any queues it had are no longer in use after this returns.
+    pub fn reset(&mut self) {
+        self.common::<u8>(DEVICE_STATUS).write(0);
+        while self.common::<u8>(DEVICE_STATUS).read() != 0 {
+            crate::cpu::relax();
+        }
+    }
+
+    /// Tell the device we've given up on it.
+    pub fn fail(&mut self) {
+        self.add_status(DeviceStatus::FAILED);
+    }
+
+    fn device_features(&self) -> u64 {
+        self.common(DEVICE_FEATURE_SELECT).write(0u32);
+        let low = self.common::<u32>(DEVICE_FEATURE).read() as u64;
+        self.common(DEVICE_FEATURE_SELECT).write(1u32);
+        let high = self.common::<u32>(DEVICE_FEATURE).read() as u64;
+        high << 32 | low
+    }
+
+    /// Reset the device and negotiate features, the same as
+    /// [`MmioTransport::begin_init`](super::MmioTransport::begin_init).
+    pub fn begin_init(&mut self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(DeviceStatus::ACKNOWLEDGE);
+        self.add_status(DeviceStatus::DRIVER);
+
+        let negotiated = self.device_features() & (supported | features::VERSION_1);
+        self.common(DRIVER_FEATURE_SELECT).write(0u32);
+        self.common(DRIVER_FEATURE).write(negotiated as u32);
+        self.common(DRIVER_FEATURE_SELECT).write(1u32);
+        self.common(DRIVER_FEATURE).write((negotiated >> 32) as u32);
+
+        self.add_status(DeviceStatus::FEATURES_OK);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(negotiated)
+    }
+
+    /// Let the device start using its queues.
+    pub fn finish_init(&mut self) {
+        self.add_status(DeviceStatus::DRIVER_OK);
+    }
+
+    /// Allocate and install queue `index` with up to `size` entries. The device may
+    /// support fewer.
+    pub fn setup_queue(&mut self, index: u16, size: u16) -> Result<VirtQueue, VirtioError> {
+        self.common(QUEUE_SELECT).write(index);
+        if self.common::<u16>(QUEUE_ENABLE).read() != 0 {
+            return Err(VirtioError::QueueInUse(index));
+        }
+        let max = self.common::<u16>(QUEUE_SIZE).read();
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+        let size = size.min(1 << (15 - max.leading_zeros()));
+        let queue = VirtQueue::new(index, size)?;
+        self.common(QUEUE_SIZE).write(size);
+        // 64 bit fields can be written in halves, which every device takes.
+        for (field, addr) in [
+            (QUEUE_DESC, queue.desc_addr()),
+            (QUEUE_DRIVER, queue.avail_addr()),
+            (QUEUE_DEVICE, queue.used_addr()),
+        ] {
+            self.common(field).write(addr as u32);
+            self.common(field + 4).write((addr >> 32) as u32);
+        }
+        let notify_offset = self.common::<u16>(QUEUE_NOTIFY_OFF).read() as usize;
+        let index = index as usize;
+        if self.notify_offsets.len() <= index {
+            self.notify_offsets.resize(index + 1, 0);
+        }
+        self.notify_offsets[index] = notify_offset * self.notify_multiplier as usize;
+        self.common(QUEUE_ENABLE).write(1u16);
+        Ok(queue)
+    }
+
+    /// Tell the device there's new buffers in `queue`.
+    pub fn notify(&self, queue: u16) {
+        // Descriptors and the avail ring must be visible before the device looks.
+        fence(Ordering::SeqCst);
+        let offset = self
+            .notify_offsets
+            .get(queue as usize)
+            .copied()
+            .unwrap_or(0);
+        unsafe { Reg::<u16>::new(self.notify + offset) }.write(queue);
+    }
+
+    /// Read the interrupt status, which clears it. The bits are the same as virtio-mmio's.
+    pub fn ack_interrupt(&self) -> u32 {
+        unsafe { Reg::<u8>::new(self.isr) }.read() as u32
+    }
+
+    /// Read a device specific config field at `offset`, retrying until the config
+    /// generation is stable.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        let Some(config) = self.device_config else {
+            panic!("virtio-pci device has no config");
+        };
+        loop {
+            let before = self.common::<u8>(CONFIG_GENERATION).read();
+            let value = unsafe { Reg::<T>::new(config + offset) }.read();
+            if self.common::<u8>(CONFIG_GENERATION).read() == before {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        let Some(config) = self.device_config else {
+            panic!("virtio-pci device has no config");
+        };
+        unsafe { Reg::new(config + offset) }.write(value)
+    }
+}
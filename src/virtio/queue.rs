@@ -0,0 +1,406 @@
+//! Split virtqueues. Section 2.7 of the virtio spec.
+//!
+//! The descriptor table, available ring and used ring live in one allocation, laid out
+//! the way the legacy transport expects: the used ring starts on the next page after the
+//! available ring. The modern transport takes the three addresses separately, so the same
+//! layout works for both.
+//!
+//! The rings are a [`DmaBuffer`]. Buffers added to the queue go to the device as pointers
+//! less [`PHYS_OFFSET`](crate::pagetable::PHYS_OFFSET), so they have to be in the direct
+//! map too: heap memory or another [`DmaBuffer`], not the stack.
+//!
+//! Most drivers send a request and sleep until it's back. A [`CompletionQueue`] does that
+//! for them: their interrupt handler [`collect`](CompletionQueue::collect)s finished chains
+//! and each caller [`wait`](CompletionQueue::wait)s for its own.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{fence, Ordering},
+};
+
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    addr::VirtAddr,
+    dma::{self, DmaBuffer},
+    isr::{wait_until, without_interrupts},
+    pagetable::{virt_to_phys, PAGE_SIZE},
+};
+
+use super::{Transport, VirtioError};
+
+/// More descriptors follow in this chain.
+const DESC_F_NEXT: u16 = 1;
+/// The device writes to this buffer, rather than reading from it.
+const DESC_F_WRITE: u16 = 2;
+
+/// Set by the device in the used ring's flags when it doesn't need notifying.
+const USED_F_NO_NOTIFY: u16 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Byte offsets of each part of the queue in its allocation.
+struct QueueLayout {
+    avail: usize,
+    used: usize,
+    size: usize,
+}
+
+impl QueueLayout {
+    fn new(size: u16) -> Self {
+        let size = size as usize;
+        let page = PAGE_SIZE as usize;
+        let desc_bytes = size_of::<Descriptor>() * size;
+        // flags, idx, ring[size], used_event
+        let avail_bytes = 2 * (3 + size);
+        // flags, idx, ring[size], avail_event
+        let used_bytes = 2 * 3 + size_of::<UsedElem>() * size;
+
+        let used = (desc_bytes + avail_bytes).next_multiple_of(page);
+        let total = used + used_bytes.next_multiple_of(page);
+        QueueLayout {
+            avail: desc_bytes,
+            used,
+            size: total,
+        }
+    }
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    /// Freed with the queue. The device must have been reset first, or it may still write
+    /// to it.
+    memory: DmaBuffer,
+    desc: *mut Descriptor,
+    /// `flags`, `idx` then the ring.
+    avail: *mut u16,
+    used: *mut u8,
+    /// Head of the free descriptor list. Linked through `next`.
+    free_head: u16,
+    num_free: u16,
+    /// Our copy of `avail.idx`.
+    avail_idx: u16,
+    /// Next entry in the used ring we haven't looked at.
+    last_used: u16,
+}
+
+// The raw pointers are into memory this queue owns.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocate a queue. The transport installs it on the device.
+    pub(super) fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        assert!(
+            size.is_power_of_two(),
+            "virtqueue size must be a power of two"
+        );
+        let QueueLayout {
+            avail,
+            used,
+            size: bytes,
+        } = QueueLayout::new(size);
+
+        let memory =
+            dma::alloc(bytes, PAGE_SIZE as usize).map_err(|_| VirtioError::OutOfMemory)?;
+        let desc = memory.as_ptr() as *mut Descriptor;
+        for i in 0..size {
+            unsafe {
+                (*desc.add(i as usize)).next = i.wrapping_add(1);
+            }
+        }
+
+        Ok(VirtQueue {
+            index,
+            size,
+            avail: unsafe { memory.as_ptr().add(avail) } as *mut u16,
+            used: unsafe { memory.as_ptr().add(used) },
+            memory,
+            desc,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    pub(super) fn desc_addr(&self) -> u64 {
+        self.memory.phys()
+    }
+
+    pub(super) fn avail_addr(&self) -> u64 {
+        self.memory.phys() + (self.avail as u64 - self.desc as u64)
+    }
+
+    pub(super) fn used_addr(&self) -> u64 {
+        self.memory.phys() + (self.used as u64 - self.desc as u64)
+    }
+
+    /// Add a chain of buffers for the device. `inputs` are read by the device, `outputs`
+    /// are written by it. Returns the id of the chain, which [`pop_used`](Self::pop_used)
+    /// gives back once the device is done.
+    ///
+    /// Doesn't notify the device. Call [`Transport::notify`](super::Transport::notify)
+    /// if [`should_notify`](Self::should_notify) says to.
+    ///
+    /// # Safety
+    /// The buffers must stay alive, and not be touched, until the chain comes back from
+    /// [`pop_used`](Self::pop_used).
+    pub unsafe fn add(
+        &mut self,
+        inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+    ) -> Result<u16, VirtioError> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 {
+            return Err(VirtioError::EmptyChain);
+        }
+        if count > self.num_free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut last = head;
+        let mut next = head;
+        let buffers = inputs.iter().map(|buf| (buf.as_ptr(), buf.len(), 0)).chain(
+            outputs
+                .iter_mut()
+                .map(|buf| (buf.as_mut_ptr() as *const u8, buf.len(), DESC_F_WRITE)),
+        );
+        for (addr, len, flags) in buffers {
+            let desc = &mut *self.desc.add(next as usize);
+            desc.addr = virt_to_phys(VirtAddr::from_ptr(addr)).as_u64();
+            desc.len = len as u32;
+            desc.flags = flags | DESC_F_NEXT;
+            last = next;
+            next = desc.next;
+        }
+        (*self.desc.add(last as usize)).flags &= !DESC_F_NEXT;
+        self.free_head = next;
+        self.num_free -= count as u16;
+
+        // Publish the chain. The device may read the ring as soon as idx moves.
+        let slot = self.avail_idx % self.size;
+        self.avail.add(2 + slot as usize).write_volatile(head);
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.avail.add(1).write_volatile(self.avail_idx);
+
+        Ok(head)
+    }
+
+    /// Whether the device wants to hear about new buffers.
+    pub fn should_notify(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let flags = unsafe { (self.used as *const u16).read_volatile() };
+        flags & USED_F_NO_NOTIFY == 0
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { (self.used as *const u16).add(1).read_volatile() }
+    }
+
+    /// True if the device has finished with some buffers we haven't collected.
+    pub fn can_pop(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.used_idx() != self.last_used
+    }
+
+    /// Collect a chain the device is finished with. Returns its id and how many bytes
+    /// the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None;
+        }
+
+        let slot = self.last_used % self.size;
+        let elem = unsafe {
+            let ring = self.used.add(4) as *const UsedElem;
+            ring.add(slot as usize).read_volatile()
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = elem.id as u16;
+        self.free_chain(head);
+        Some((head, elem.len))
+    }
+
+    /// Put the chain starting at `head` back on the free list.
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let desc = unsafe { &mut *self.desc.add(index as usize) };
+            desc.addr = 0;
+            desc.len = 0;
+            self.num_free += 1;
+            if desc.flags & DESC_F_NEXT == 0 {
+                desc.next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+    }
+}
+
+/// A [`VirtQueue`] whose callers sleep until the device is done with what they added.
+pub struct CompletionQueue {
+    /// Only locked with interrupts disabled. The interrupt handler takes it too.
+    inner: Mutex<Completions>,
+}
+
+struct Completions {
+    queue: VirtQueue,
+    /// Bytes written by the device for each finished chain, indexed by head descriptor.
+    done: Vec<Option<u32>>,
+}
+
+impl Completions {
+    /// Move everything on the used ring into `done`.
+    fn collect(&mut self) {
+        while let Some((head, len)) = self.queue.pop_used() {
+            self.done[head as usize] = Some(len);
+        }
+    }
+}
+
+impl CompletionQueue {
+    pub fn new(queue: VirtQueue) -> Self {
+        CompletionQueue {
+            inner: Mutex::new(Completions {
+                done: vec![None; queue.size() as usize],
+                queue,
+            }),
+        }
+    }
+
+    /// Add a chain, the same as [`VirtQueue::add`], and tell the device through `transport`
+    /// if it wants to know. Returns the head to [`wait`](Self::wait) on.
+    ///
+    /// # Safety
+    /// The buffers must stay alive, and not be touched, until `wait` returns.
+    pub unsafe fn submit(
+        &self,
+        transport: &Transport,
+        inputs: &[&[u8]],
+        outputs: &mut [&mut [u8]],
+    ) -> Result<u16, VirtioError> {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let head = inner.queue.add(inputs, outputs)?;
+            if inner.queue.should_notify() {
+                transport.notify(inner.queue.index());
+            }
+            Ok(head)
+        })
+    }
+
+    /// Note every chain the device has finished with, so whoever's waiting wakes up. For
+    /// the interrupt handler.
+    pub fn collect(&self) {
+        without_interrupts(|| self.inner.lock().collect());
+    }
+
+    /// Sleep until the chain at `head` comes back. Returns how many bytes the device wrote.
+    pub fn wait(&self, head: u16) -> u32 {
+        let mut written = 0;
+        wait_until(|| {
+            let mut inner = self.inner.lock();
+            // Picks up completions if interrupts are off.
+            inner.collect();
+            match inner.done[head as usize].take() {
+                Some(len) => {
+                    written = len;
+                    true
+                }
+                None => false,
+            }
+        });
+        written
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Pretend to be the device, giving back the chain at `head`.
+    fn complete(queue: &VirtQueue, head: u16, len: u32) {
+        unsafe {
+            let used = queue.used;
+            let idx = (used as *mut u16).add(1);
+            let slot = idx.read_volatile() % queue.size;
+            let ring = used.add(4) as *mut UsedElem;
+            ring.add(slot as usize).write_volatile(UsedElem {
+                id: head as u32,
+                len,
+            });
+            idx.write_volatile(idx.read_volatile().wrapping_add(1));
+        }
+    }
+
+    #[test_case]
+    fn virtqueue_layout() {
+        let layout = QueueLayout::new(16);
+        assert_eq!(layout.avail, 16 * 16);
+        assert_eq!(layout.used, 4096);
+        assert_eq!(layout.size, 8192);
+    }
+
+    #[test_case]
+    fn virtqueue_descriptors_recycled() {
+        let mut queue = VirtQueue::new(0, 4).unwrap();
+        // Not on the stack, which isn't in the direct map.
+        let data = vec![0u8; 8];
+        let mut out = vec![0u8; 8];
+        let head = unsafe { queue.add(&[&data], &mut [&mut out]) }.unwrap();
+        assert_eq!(queue.num_free(), 2);
+
+        complete(&queue, head, 8);
+        assert_eq!(queue.pop_used(), Some((head, 8)));
+        assert_eq!(queue.num_free(), 4);
+        assert!(queue.pop_used().is_none());
+    }
+
+    #[test_case]
+    fn completions_wait_for_their_own_chain() {
+        let queue = CompletionQueue::new(VirtQueue::new(0, 4).unwrap());
+        let mut out = [vec![0u8; 8], vec![0u8; 8]];
+        let [first, second] = &mut out;
+        let mut inner = queue.inner.lock();
+        let first = unsafe { inner.queue.add(&[], &mut [first]) }.unwrap();
+        let second = unsafe { inner.queue.add(&[], &mut [second]) }.unwrap();
+        complete(&inner.queue, second, 2);
+        complete(&inner.queue, first, 1);
+        drop(inner);
+        assert_eq!(queue.wait(first), 1);
+        assert_eq!(queue.wait(second), 2);
+    }
+}
@@ -0,0 +1,94 @@
+//! virtio-rng driver. Section 5.4 of the virtio spec.
+//!
+//! One queue: we give the device a buffer and it fills some of it with random bytes. At
+//! init each device seeds [`rand`]'s pool. The caller sleeps on a [`CompletionQueue`] until
+//! the buffer's back.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    dma,
+    isr::plic::{self, InterruptId},
+    log, rand,
+    sync::Once,
+};
+
+use super::{queue::CompletionQueue, DeviceType, Transport, VirtioError};
+
+const QUEUE_SIZE: u16 = 4;
+const REQUEST_QUEUE: u16 = 0;
+/// What each device puts in the pool at init.
+const SEED_BYTES: usize = 64;
+
+static DEVICES: Once<Vec<Arc<VirtioRng>>> = Once::INIT;
+
+pub struct VirtioRng {
+    transport: Transport,
+    requests: CompletionQueue,
+    interrupt: InterruptId,
+}
+
+impl VirtioRng {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?;
+        let queue = transport.setup_queue(REQUEST_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+        transport.finish_init();
+
+        Ok(VirtioRng {
+            transport,
+            requests: CompletionQueue::new(queue),
+            interrupt,
+        })
+    }
+
+    /// Fill the start of `buf` with random bytes. Returns how many, which can be fewer
+    /// than asked for.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, VirtioError> {
+        let mut buffer = dma::alloc(buf.len(), 1).map_err(|_| VirtioError::OutOfMemory)?;
+        let head = unsafe {
+            self.requests
+                .submit(&self.transport, &[], &mut [buffer.as_mut_slice()])
+        }?;
+        let len = (self.requests.wait(head) as usize).min(buf.len());
+        buffer.sync_for_cpu();
+        buf[..len].copy_from_slice(unsafe { &buffer.as_slice()[..len] });
+        Ok(len)
+    }
+}
+
+/// PLIC handler. Marks finished requests so their callers wake up.
+fn rng_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.transport.ack_interrupt();
+        device.requests.collect();
+    }
+}
+
+/// Set up every virtio-rng device and seed the entropy pool from each.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Entropy) {
+            match VirtioRng::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-rng: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, rng_interrupt);
+        plic::enable_interrupt(device.interrupt);
+
+        let mut seed = [0; SEED_BYTES];
+        match device.read(&mut seed) {
+            Ok(len) => rand::add_entropy(&seed[..len], len > 0),
+            Err(err) => log::error!("virtio-rng: {}", err),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! virtio-sound driver, playback only. Section 5.14 of the virtio spec.
+//!
+//! At init we ask for the device's PCM streams and use the first output stream that
+//! takes signed 16 bit samples. Control requests go on the control queue and the caller
+//! sleeps on a [`CompletionQueue`] until they're answered. Playing keeps
+//! [`PERIODS`] buffers on the transmit queue, each a period of frames from the
+//! [`PcmStream`]; the interrupt handler refills each one as the device finishes with it.
+//! The event and receive queues aren't used.
+
+use core::mem::size_of;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    dma::{self, DmaBuffer},
+    isr::{
+        plic::{self, InterruptId},
+        without_interrupts,
+    },
+    log,
+    sound::{self, PcmConfig, PcmStream, SoundDevice, SoundError},
+    sync::Once,
+};
+
+use super::{
+    queue::{CompletionQueue, VirtQueue},
+    DeviceType, Transport, VirtioError,
+};
+
+const QUEUE_SIZE: u16 = 16;
+const CONTROL_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 2;
+
+const CONFIG_STREAMS: usize = 4;
+
+const R_PCM_INFO: u32 = 0x0100;
+const R_PCM_SET_PARAMS: u32 = 0x0101;
+const R_PCM_PREPARE: u32 = 0x0102;
+const R_PCM_RELEASE: u32 = 0x0103;
+const R_PCM_START: u32 = 0x0104;
+const R_PCM_STOP: u32 = 0x0105;
+
+const S_OK: u32 = 0x8000;
+
+const DIRECTION_OUTPUT: u8 = 0;
+const FORMAT_S16: u8 = 5;
+/// `VIRTIO_SND_PCM_RATE_*` is the index.
+const RATES: [u32; 14] = [
+    5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000,
+    384000,
+];
+
+/// Buffers on the transmit queue while playing.
+const PERIODS: usize = 4;
+const PERIOD_MS: u32 = 20;
+/// The most streams we ask about.
+const MAX_STREAMS: u32 = 16;
+/// Where a period's samples start in its buffer, after the `virtio_snd_pcm_xfer`.
+const DATA_OFFSET: usize = 8;
+
+static DEVICES: Once<Vec<Arc<VirtioSound>>> = Once::INIT;
+
+/// `virtio_snd_query_info`.
+#[repr(C)]
+struct QueryInfo {
+    code: u32,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+/// `virtio_snd_pcm_info`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PcmInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+/// `virtio_snd_pcm_hdr`, which is all PREPARE, RELEASE, START and STOP have.
+#[repr(C)]
+struct PcmHeader {
+    code: u32,
+    stream_id: u32,
+}
+
+/// `virtio_snd_pcm_set_params`.
+#[repr(C)]
+struct SetParams {
+    header: PcmHeader,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+/// `virtio_snd_pcm_status`, which the device writes after each period.
+#[repr(C)]
+struct PcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+/// The bytes of a request. They're all `repr(C)` and have no padding the compiler added.
+fn as_bytes<T>(request: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(request as *const T as *const u8, size_of::<T>()) }
+}
+
+/// The stream being played and its buffers.
+struct Playing {
+    stream: Arc<PcmStream>,
+    /// Each has the stream ID, a period of samples, then room for the status.
+    buffers: Vec<DmaBuffer>,
+    period_bytes: usize,
+    /// Set before stopping, so finished buffers aren't sent again.
+    stopping: bool,
+}
+
+struct Inner {
+    tx: VirtQueue,
+    /// Which buffer each transmit chain is, by head.
+    tx_buffers: Vec<usize>,
+    /// Buffers the device has.
+    tx_pending: usize,
+    playing: Option<Playing>,
+}
+
+impl Inner {
+    /// Fill buffer `index` with the next period and give it to the device.
+    fn send_period(&mut self, stream_id: u32, index: usize) {
+        let Some(playing) = &mut self.playing else {
+            return;
+        };
+        let buffer = &mut playing.buffers[index];
+        let period_bytes = playing.period_bytes;
+        // The samples are aligned, since DATA_OFFSET and the buffer are.
+        let samples = unsafe {
+            buffer.as_mut_slice()[..4].copy_from_slice(&stream_id.to_le_bytes());
+            let data = buffer.as_ptr().add(DATA_OFFSET) as *mut i16;
+            core::slice::from_raw_parts_mut(data, period_bytes / 2)
+        };
+        playing.stream.take(samples);
+        buffer.sync_for_device();
+
+        let (xfer, rest) = unsafe { buffer.as_mut_slice() }.split_at_mut(DATA_OFFSET);
+        let (data, status) = rest.split_at_mut(period_bytes);
+        // There's a descriptor for every buffer, so there's always room.
+        let head = unsafe { self.tx.add(&[&xfer[..4], data], &mut [status]) }
+            .expect("transmit queue full");
+        self.tx_buffers[head as usize] = index;
+        self.tx_pending += 1;
+    }
+
+    /// Send each period the device has finished with again, unless we're stopping.
+    fn refill(&mut self, transport: &Transport, stream_id: u32) {
+        let mut sent = false;
+        while let Some((head, _len)) = self.tx.pop_used() {
+            self.tx_pending -= 1;
+            let index = self.tx_buffers[head as usize];
+            if matches!(&self.playing, Some(playing) if !playing.stopping) {
+                self.send_period(stream_id, index);
+                sent = true;
+            }
+        }
+        if sent && self.tx.should_notify() {
+            transport.notify(TX_QUEUE);
+        }
+    }
+}
+
+pub struct VirtioSound {
+    transport: Transport,
+    control: CompletionQueue,
+    /// The transmit queue. Only locked with interrupts disabled: the interrupt handler
+    /// takes it too.
+    inner: Mutex<Inner>,
+    interrupt: InterruptId,
+    streams: u32,
+    /// The stream we play on and what it takes, once found.
+    output: Once<(u32, PcmInfo)>,
+}
+
+impl VirtioSound {
+    fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?;
+        let control = transport.setup_queue(CONTROL_QUEUE, QUEUE_SIZE)?;
+        let tx = transport.setup_queue(TX_QUEUE, QUEUE_SIZE)?;
+        let interrupt = transport.interrupt();
+        let streams = transport
+            .read_config::<u32>(CONFIG_STREAMS)
+            .min(MAX_STREAMS);
+        transport.finish_init();
+
+        Ok(VirtioSound {
+            transport,
+            control: CompletionQueue::new(control),
+            inner: Mutex::new(Inner {
+                tx_buffers: vec![0; tx.size() as usize],
+                tx_pending: 0,
+                playing: None,
+                tx,
+            }),
+            interrupt,
+            streams,
+            output: Once::INIT,
+        })
+    }
+
+    fn stream_id(&self) -> u32 {
+        self.output.get().map_or(0, |(id, _)| *id)
+    }
+
+    /// Send `request` and sleep until the device answers. Returns the response, which is
+    /// `response_len` bytes.
+    fn request<T>(&self, request: &T, response_len: usize) -> Result<DmaBuffer, SoundError> {
+        let request = as_bytes(request);
+        let mut buffer =
+            dma::alloc(request.len() + response_len, 8).map_err(|_| SoundError::Device)?;
+        unsafe { buffer.as_mut_slice()[..request.len()].copy_from_slice(request) };
+        buffer.sync_for_device();
+        let (out, response) = unsafe {
+            let base = buffer.as_ptr();
+            (
+                core::slice::from_raw_parts(base, request.len()),
+                core::slice::from_raw_parts_mut(base.add(request.len()), response_len),
+            )
+        };
+
+        let head = unsafe {
+            self.control
+                .submit(&self.transport, &[out], &mut [response])
+        }
+        .map_err(|_| SoundError::Device)?;
+        self.control.wait(head);
+        buffer.sync_for_cpu();
+        Ok(buffer)
+    }
+
+    /// Send `request` and check the device says OK.
+    fn request_ok<T>(&self, request: &T) -> Result<(), SoundError> {
+        let response = self.request(request, size_of::<u32>())?;
+        let status = unsafe { (response.as_ptr().add(size_of::<T>()) as *const u32).read() };
+        match status {
+            S_OK => Ok(()),
+            _ => Err(SoundError::Device),
+        }
+    }
+
+    fn pcm_request(&self, code: u32) -> Result<(), SoundError> {
+        self.request_ok(&PcmHeader {
+            code,
+            stream_id: self.stream_id(),
+        })
+    }
+
+    /// Find the first output stream that takes signed 16 bit samples.
+    fn find_output(&self) -> Result<bool, SoundError> {
+        let infos = self.stream_info(self.streams)?;
+        let found = infos.into_iter().enumerate().find(|(_, info)| {
+            info.direction == DIRECTION_OUTPUT && info.formats & (1 << FORMAT_S16) != 0
+        });
+        if let Some((id, info)) = found {
+            self.output.call_once(|| (id as u32, info));
+        }
+        Ok(found.is_some())
+    }
+
+    fn stream_info(&self, count: u32) -> Result<Vec<PcmInfo>, SoundError> {
+        let query = QueryInfo {
+            code: R_PCM_INFO,
+            start_id: 0,
+            count,
+            size: size_of::<PcmInfo>() as u32,
+        };
+        let info_len = count as usize * size_of::<PcmInfo>();
+        let response = self.request(&query, size_of::<u32>() + info_len)?;
+        let response = unsafe { &response.as_slice()[size_of::<QueryInfo>()..] };
+        if response[..4] != S_OK.to_le_bytes() {
+            return Err(SoundError::Device);
+        }
+        Ok(response[4..]
+            .chunks_exact(size_of::<PcmInfo>())
+            .map(|info| unsafe { (info.as_ptr() as *const PcmInfo).read_unaligned() })
+            .collect())
+    }
+}
+
+impl SoundDevice for VirtioSound {
+    fn name(&self) -> &str {
+        "virtio-sound"
+    }
+
+    fn supports(&self, config: PcmConfig) -> bool {
+        let Some((_, info)) = self.output.get() else {
+            return false;
+        };
+        let rate = RATES.iter().position(|&rate| rate == config.rate);
+        matches!(rate, Some(rate) if info.rates & (1 << rate) != 0)
+            && (info.channels_min..=info.channels_max).contains(&config.channels)
+    }
+
+    fn play(&self, stream: Arc<PcmStream>) -> Result<(), SoundError> {
+        let config = stream.config();
+        if !self.supports(config) {
+            return Err(SoundError::Unsupported);
+        }
+        if without_interrupts(|| self.inner.lock().playing.is_some()) {
+            return Err(SoundError::Busy);
+        }
+        let rate = RATES.iter().position(|&rate| rate == config.rate).unwrap();
+        let period_bytes = (config.rate * PERIOD_MS / 1000) as usize * config.frame_bytes();
+        self.request_ok(&SetParams {
+            header: PcmHeader {
+                code: R_PCM_SET_PARAMS,
+                stream_id: self.stream_id(),
+            },
+            buffer_bytes: (PERIODS * period_bytes) as u32,
+            period_bytes: period_bytes as u32,
+            features: 0,
+            channels: config.channels,
+            format: FORMAT_S16,
+            rate: rate as u8,
+            padding: 0,
+        })?;
+        self.pcm_request(R_PCM_PREPARE)?;
+
+        let len = DATA_OFFSET + period_bytes + size_of::<PcmStatus>();
+        let buffers = (0..PERIODS)
+            .map(|_| dma::alloc(len, 8))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SoundError::Device)?;
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            inner.playing = Some(Playing {
+                stream,
+                buffers,
+                period_bytes,
+                stopping: false,
+            });
+            for index in 0..PERIODS {
+                inner.send_period(self.stream_id(), index);
+            }
+            self.transport.notify(TX_QUEUE);
+        });
+        self.pcm_request(R_PCM_START)
+    }
+
+    fn stop(&self) {
+        let playing = without_interrupts(|| match &mut self.inner.lock().playing {
+            Some(playing) => {
+                playing.stopping = true;
+                true
+            }
+            None => false,
+        });
+        if !playing {
+            return;
+        }
+        let stopped = self.pcm_request(R_PCM_STOP);
+        // Releasing makes the device give back every buffer before it answers.
+        let released = self.pcm_request(R_PCM_RELEASE);
+        if let Err(err) = stopped.and(released) {
+            log::warn!("virtio-sound: stopping: {}", err);
+        }
+        let kept = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            inner.refill(&self.transport, self.stream_id());
+            let playing = inner.playing.take();
+            if inner.tx_pending != 0 {
+                // The device may still write to them.
+                core::mem::forget(playing);
+            }
+            inner.tx_pending
+        });
+        if kept != 0 {
+            log::warn!("virtio-sound: the device kept {} buffers", kept);
+        }
+    }
+}
+
+/// PLIC handler. Wakes control requests and refills finished periods.
+fn sound_interrupt(interrupt: InterruptId) {
+    let Some(devices) = DEVICES.get() else {
+        return;
+    };
+    for device in devices.iter().filter(|d| d.interrupt == interrupt) {
+        device.transport.ack_interrupt();
+        device.control.collect();
+        device
+            .inner
+            .lock()
+            .refill(&device.transport, device.stream_id());
+    }
+}
+
+/// Set up every virtio-sound device and add the ones we can play on.
+pub fn init() {
+    DEVICES.call_once(|| {
+        let mut devices = Vec::new();
+        while let Some(transport) = super::take(DeviceType::Sound) {
+            match VirtioSound::new(transport) {
+                Ok(device) => devices.push(Arc::new(device)),
+                Err(err) => log::error!("virtio-sound: {}", err),
+            }
+        }
+        devices
+    });
+
+    for device in DEVICES.get().unwrap() {
+        plic::register_handler(device.interrupt, sound_interrupt);
+        plic::enable_interrupt(device.interrupt);
+        match device.find_output() {
+            Ok(true) => sound::add_device(device.clone()),
+            Ok(false) => log::warn!("virtio-sound: no output stream"),
+            Err(err) => log::error!("virtio-sound: {}", err),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Kernel memory that's contiguous in virtual addresses but not physical ones.
+//!
+//! [`vmalloc`] takes pages from wherever the heap has them and maps them one after another
+//! in [`VMALLOC_WINDOW`], for big buffers that would otherwise need that much contiguous
+//! RAM. [`vmap`] does the same with frames the caller already has. Each area has an
+//! unmapped guard page either side, so running off either end faults.
+//!
+//! If the window can't be had, [`vmalloc`] falls back to the heap, like kernel stacks do,
+//! and [`vmap`] fails.
+
+use alloc::collections::BTreeMap;
+use core::{
+    alloc::Layout,
+    ops::{Deref, DerefMut, Range},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    addr::{PhysAddr, VirtAddr},
+    hwinfo::HwInfo,
+    pagetable::{
+        alloc_frame, free_frame, map_kernel, memory_map, unmap_kernel, EntryFlags, MapError,
+        PAGE_SIZE,
+    },
+    prelude::*,
+    sync::IrqSafeMutex,
+    tlb,
+};
+
+/// Where areas go. The gigabyte under [`STACK_WINDOW`](crate::stack::STACK_WINDOW).
+pub const VMALLOC_WINDOW: Range<VirtAddr> =
+    VirtAddr::new(0xffff_ffff_4000_0000)..VirtAddr::new(0xffff_ffff_8000_0000);
+
+static WINDOW_READY: AtomicBool = AtomicBool::new(false);
+/// Held while mapping, so only one area's tables change at once.
+static AREAS: IrqSafeMutex<Areas> = IrqSafeMutex::new(BTreeMap::new());
+
+/// Where each area starts, and where it ends and what it's for.
+type Areas = BTreeMap<u64, (u64, &'static str)>;
+
+/// Set up [`VMALLOC_WINDOW`].
+///
+/// # Safety
+/// Once, or the areas already in [`VMALLOC_WINDOW`] lose their mappings. Only this hart
+/// forgets the direct map's entry for the window, and address spaces copy the kernel's
+/// root entries, so it has to be before the other harts start or any address space is
+/// made. No device can be in use through that part of the direct map.
+pub unsafe fn init(hwinfo: &HwInfo) {
+    if memory_map::claim_window(&VMALLOC_WINDOW, hwinfo, "vmalloc") {
+        WINDOW_READY.store(true, Ordering::Release);
+    }
+}
+
+/// Where an area's memory came from.
+#[derive(Debug)]
+enum Backing {
+    /// Pages of its own, freed with it.
+    Pages,
+    /// Frames someone else owns.
+    Mapped,
+    /// One piece of heap, when there's no window.
+    Heap(Layout),
+}
+
+/// Memory from [`vmalloc`] or [`vmap`]. Unmapped when dropped. Derefs to its bytes.
+#[derive(Debug)]
+pub struct VmArea {
+    start: u64,
+    len: usize,
+    backing: Backing,
+}
+
+// Owns its memory, like a `Box<[u8]>`.
+unsafe impl Send for VmArea {}
+unsafe impl Sync for VmArea {}
+
+/// `len` zeroed bytes, mapped from pages that needn't be next to each other. `description`
+/// is what `mem` in the shell lists it as.
+pub fn vmalloc(len: usize, description: &'static str) -> Result<VmArea, MapError> {
+    if !WINDOW_READY.load(Ordering::Acquire) {
+        let layout = Layout::from_size_align(len.max(1), PAGE_SIZE as usize).unwrap();
+        let start = unsafe { alloc::alloc::alloc_zeroed(layout) } as u64;
+        if start == 0 {
+            return Err(MapError::OutOfMemory);
+        }
+        return Ok(VmArea {
+            start,
+            len,
+            backing: Backing::Heap(layout),
+        });
+    }
+
+    let mut areas = AREAS.lock();
+    let start = find_free(&areas, pages(len))?;
+    areas.insert(start, (start + pages(len), description));
+    for va in (start..start + pages(len)).step_by(PAGE_SIZE as usize) {
+        if let Err(err) = unsafe { map_new_page(va) } {
+            let frames = unsafe { unmap(start..va) };
+            drop(areas);
+            unsafe { release(start, start..va, Some(frames)) };
+            return Err(err);
+        }
+    }
+    Ok(VmArea {
+        start,
+        len,
+        backing: Backing::Pages,
+    })
+}
+
+/// Map `frames`, in order, into one area with `flags`. They stay the caller's: dropping the
+/// area only unmaps them. [`MapError::OutOfMemory`] if there's no room, or no window.
+///
+/// # Safety
+/// The frames are RAM, and stay allocated for as long as the area's around.
+pub unsafe fn vmap(
+    frames: &[PhysAddr],
+    flags: EntryFlags,
+    description: &'static str,
+) -> Result<VmArea, MapError> {
+    if !WINDOW_READY.load(Ordering::Acquire) {
+        return Err(MapError::OutOfMemory);
+    }
+    if frames.iter().any(|frame| !frame.is_page_aligned()) {
+        return Err(MapError::Misaligned);
+    }
+    let len = frames.len() * PAGE_SIZE as usize;
+    let mut areas = AREAS.lock();
+    let start = find_free(&areas, len as u64)?;
+    areas.insert(start, (start + len as u64, description));
+    for (i, &frame) in frames.iter().enumerate() {
+        let va = start + i as u64 * PAGE_SIZE;
+        if let Err(err) = map_kernel(VirtAddr::new(va), frame, flags | EntryFlags::GLOBAL) {
+            unmap(start..va);
+            drop(areas);
+            release(start, start..va, None);
+            return Err(err);
+        }
+    }
+    Ok(VmArea {
+        start,
+        len,
+        backing: Backing::Mapped,
+    })
+}
+
+impl VmArea {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Deref for VmArea {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for VmArea {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for VmArea {
+    fn drop(&mut self) {
+        let range = self.start..self.start + pages(self.len);
+        match self.backing {
+            Backing::Heap(layout) => unsafe { alloc::alloc::dealloc(self.as_ptr(), layout) },
+            Backing::Pages | Backing::Mapped => {
+                let frames = {
+                    let _areas = AREAS.lock();
+                    unsafe { unmap(range.clone()) }
+                };
+                let frames = matches!(self.backing, Backing::Pages).then_some(frames);
+                unsafe { release(self.start, range, frames) };
+            }
+        }
+    }
+}
+
+/// Call `f` with the address range and description of each area in the window.
+pub fn for_each_area(mut f: impl FnMut(Range<u64>, &'static str)) {
+    for (&start, &(end, description)) in AREAS.lock().iter() {
+        f(start..end, description);
+    }
+}
+
+/// `len` rounded up to pages.
+fn pages(len: usize) -> u64 {
+    (len as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// The lowest address with `size` bytes free and a free page either side.
+fn find_free(areas: &Areas, size: u64) -> Result<u64, MapError> {
+    let mut start = VMALLOC_WINDOW.start.as_u64() + PAGE_SIZE;
+    for (&area, &(end, _)) in areas.iter() {
+        if start + size + PAGE_SIZE <= area {
+            break;
+        }
+        start = end + PAGE_SIZE;
+    }
+    match start.checked_add(size + PAGE_SIZE) {
+        Some(end) if end <= VMALLOC_WINDOW.end.as_u64() => Ok(start),
+        _ => Err(MapError::OutOfMemory),
+    }
+}
+
+/// Map a new zeroed page at `va`.
+///
+/// # Safety
+/// `AREAS` is held, and `va` is in an area being made.
+unsafe fn map_new_page(va: u64) -> Result<(), MapError> {
+    let frame = alloc_frame().ok_or(MapError::OutOfMemory)?;
+    let flags = EntryFlags::READ | EntryFlags::WRITE | EntryFlags::GLOBAL;
+    if let Err(err) = map_kernel(VirtAddr::new(va), frame, flags) {
+        free_frame(frame);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Unmap the pages in `range`, returning their frames for [`release`].
+///
+/// # Safety
+/// `AREAS` is held, and nothing uses the pages any more.
+unsafe fn unmap(range: Range<u64>) -> Vec<PhysAddr> {
+    let mut frames = Vec::new();
+    for va in range.step_by(PAGE_SIZE as usize) {
+        if let Ok(Some(entry)) = unmap_kernel(VirtAddr::new(va)) {
+            frames.push(entry.address());
+        }
+    }
+    frames
+}
+
+/// Have every hart forget `range`, free `frames` if there are any, then give up the area
+/// at `start`, so it isn't mapped again before then.
+///
+/// # Safety
+/// `AREAS` isn't held: a hart spinning on it with interrupts off can't answer the
+/// shootdown.
+unsafe fn release(start: u64, range: Range<u64>, frames: Option<Vec<PhysAddr>>) {
+    tlb::shootdown(range, None);
+    for frame in frames.into_iter().flatten() {
+        free_frame(frame);
+    }
+    AREAS.lock().remove(&start);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pagetable::kernel_translate;
+
+    #[test_case]
+    fn areas_have_guards() {
+        let mut area = vmalloc(3 * PAGE_SIZE as usize + 1, "test").unwrap();
+        assert_eq!(area.len(), 3 * PAGE_SIZE as usize + 1);
+        assert!(area.iter().all(|&byte| byte == 0));
+        area[3 * PAGE_SIZE as usize] = 7;
+        assert_eq!(area[3 * PAGE_SIZE as usize], 7);
+        if !matches!(area.backing, Backing::Pages) {
+            return;
+        }
+
+        let start = VirtAddr::from_ptr(area.as_ptr());
+        let end = start + 4 * PAGE_SIZE;
+        assert!(kernel_translate(start - PAGE_SIZE).is_none());
+        assert!(kernel_translate(end).is_none());
+        drop(area);
+        assert!(kernel_translate(start).is_none());
+
+        // Frames of our own, backwards.
+        let frames = [alloc_frame().unwrap(), alloc_frame().unwrap()];
+        let area = unsafe { vmap(&[frames[1], frames[0]], EntryFlags::READ, "test") }.unwrap();
+        let start = VirtAddr::from_ptr(area.as_ptr());
+        assert_eq!(kernel_translate(start).map(|(pa, _)| pa), Some(frames[1]));
+        assert_eq!(
+            kernel_translate(start + PAGE_SIZE).map(|(pa, _)| pa),
+            Some(frames[0])
+        );
+        drop(area);
+        for frame in frames {
+            unsafe { free_frame(frame) };
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Spotting harts that have stopped taking interrupts.
+//!
+//! Every hart's timer goes off at least every [`TICK`](crate::time::timer::TICK), and each
+//! time it [`pet`]s its heartbeat and looks at the other harts'. One that hasn't beaten for
+//! [`TIMEOUT`] is stuck, most likely spinning with interrupts off. Whoever notices logs the
+//! last trap the stuck hart took, once. With `watchdog=reset` on the command line it resets
+//! the machine too, and `watchdog=off` turns the checks off.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    cmdline, hart_local, log, panic,
+    process::TrapFrame,
+    sbi::{
+        hart::{HartId, HartMask},
+        reset::{ResetReason, ResetType, SYSTEM_RESET_EXTENSION},
+    },
+    smp::MAX_HARTS,
+    time::Instant,
+    trap::TrapRegisters,
+};
+
+/// How long a hart can go without a heartbeat before it counts as stuck.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do about a stuck hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    Off,
+    Log,
+    Reset,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Log as u8);
+
+struct Heartbeat {
+    /// mtime of the last beat. 0 until the hart's first.
+    beat: AtomicU64,
+    /// Stuck, and already logged.
+    reported: AtomicBool,
+    /// `pc`, `ra`, `sp` and `s0` of the last trap.
+    last_trap: [AtomicU64; 4],
+    last_trap_user: AtomicBool,
+}
+
+static HEARTBEATS: [Heartbeat; MAX_HARTS] = [const {
+    Heartbeat {
+        beat: AtomicU64::new(0),
+        reported: AtomicBool::new(false),
+        last_trap: [const { AtomicU64::new(0) }; 4],
+        last_trap_user: AtomicBool::new(false),
+    }
+}; MAX_HARTS];
+
+fn this_heartbeat() -> Option<(HartId, &'static Heartbeat)> {
+    match hart_local::try_current_hart() {
+        Some(hart) if hart.0 < MAX_HARTS => Some((hart, &HEARTBEATS[hart.0])),
+        _ => None,
+    }
+}
+
+/// Read `watchdog=` from the command line.
+pub fn init() {
+    let policy = match cmdline::get("watchdog") {
+        None | Some("log") => Policy::Log,
+        Some("off") => Policy::Off,
+        Some("reset") => Policy::Reset,
+        Some(other) => {
+            log::warn!("watchdog: unknown policy {:?}, using log", other);
+            Policy::Log
+        }
+    };
+    set_policy(policy);
+}
+
+pub fn policy() -> Policy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => Policy::Off,
+        1 => Policy::Log,
+        _ => Policy::Reset,
+    }
+}
+
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Note a trap from S-mode, so there's something to show if the hart gets stuck.
+pub(crate) fn record_trap(sepc: usize, registers: &TrapRegisters) {
+    if let Some((_, heartbeat)) = this_heartbeat() {
+        heartbeat.record(
+            [sepc as u64, registers.ra, registers.sp, registers.s0],
+            false,
+        );
+    }
+}
+
+/// Note a trap from U-mode.
+pub(crate) fn record_user_trap(frame: &TrapFrame) {
+    if let Some((_, heartbeat)) = this_heartbeat() {
+        heartbeat.record(
+            [frame.pc, frame.regs[1], frame.regs[2], frame.regs[8]],
+            true,
+        );
+    }
+}
+
+impl Heartbeat {
+    fn record(&self, registers: [u64; 4], user: bool) {
+        for (slot, value) in self.last_trap.iter().zip(registers) {
+            slot.store(value, Ordering::Relaxed);
+        }
+        self.last_trap_user.store(user, Ordering::Relaxed);
+    }
+}
+
+/// Beat this hart's heart and check on the others. Called from the timer interrupt.
+pub(crate) fn pet() {
+    let (hart, heartbeat) = match this_heartbeat() {
+        Some(this) => this,
+        None => return,
+    };
+    let now = Instant::now();
+    let previous = heartbeat
+        .beat
+        .swap(now.to_mtime().unwrap_or(u64::MAX), Ordering::Relaxed);
+    if heartbeat.reported.swap(false, Ordering::Relaxed) {
+        log::warn!("watchdog: hart {} is running again", hart);
+    }
+    // If we went without a beat as well, the whole machine was stopped, by a suspend or a
+    // debugger. Everyone will have caught up by the next one.
+    let late =
+        previous != 0 && now.saturating_duration_since(Instant::from_mtime(previous)) >= TIMEOUT;
+    if policy() == Policy::Off || panic::panicking() || late {
+        return;
+    }
+    let mut others = hart_local::online_harts();
+    others.clear_id(hart);
+    if check(others, now) > 0 && policy() == Policy::Reset {
+        reset();
+    }
+}
+
+/// Log the harts in `harts` that are newly stuck. Returns how many there were.
+fn check(harts: HartMask, now: Instant) -> usize {
+    let mut stuck = 0;
+    for hart in harts {
+        if hart.0 >= MAX_HARTS {
+            continue;
+        }
+        let heartbeat = &HEARTBEATS[hart.0];
+        let beat = heartbeat.beat.load(Ordering::Relaxed);
+        if beat == 0 {
+            continue;
+        }
+        let silent = now.saturating_duration_since(Instant::from_mtime(beat));
+        if silent < TIMEOUT || heartbeat.reported.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        let [pc, ra, sp, s0] = [0, 1, 2, 3].map(|i| heartbeat.last_trap[i].load(Ordering::Relaxed));
+        let mode = if heartbeat.last_trap_user.load(Ordering::Relaxed) {
+            "U"
+        } else {
+            "S"
+        };
+        log::error!(
+            "watchdog: hart {} stuck for {:?}. Last trap from {}-mode: pc 0x{:x} ra 0x{:x} sp 0x{:x} s0 0x{:x}",
+            hart,
+            silent,
+            mode,
+            pc,
+            ra,
+            sp,
+            s0
+        );
+        stuck += 1;
+    }
+    stuck
+}
+
+fn reset() {
+    match SYSTEM_RESET_EXTENSION.get() {
+        Some(reset) => {
+            log::error!("watchdog: resetting");
+            if let Err(err) = reset.reset(ResetType::ColdReboot, ResetReason::SystemFailure) {
+                log::error!("watchdog: reset failed: {:?}", err);
+            }
+        }
+        None => log::error!("watchdog: no SBI system reset extension to reset with"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn stuck_hart_reported_once() {
+        let hart = HartId(MAX_HARTS - 1);
+        let heartbeat = &HEARTBEATS[hart.0];
+        let mut harts = HartMask::new();
+        harts.set_id(hart);
+
+        let now = Instant::now() + TIMEOUT * 2;
+        heartbeat.beat.store(0, Ordering::Relaxed);
+        assert_eq!(check(harts, now), 0, "never beat, so not watched");
+
+        heartbeat.beat.store(1, Ordering::Relaxed);
+        assert_eq!(check(harts, now), 1);
+        assert_eq!(check(harts, now), 0);
+
+        heartbeat
+            .beat
+            .store(now.to_mtime().unwrap(), Ordering::Relaxed);
+        heartbeat.reported.store(false, Ordering::Relaxed);
+        assert_eq!(check(harts, now), 0);
+        heartbeat.beat.store(0, Ordering::Relaxed);
+    }
+}
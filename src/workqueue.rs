@@ -0,0 +1,167 @@
+//! Deferred work, for interrupt handlers.
+//!
+//! PLIC handlers run with interrupts off, so they should do as little as they can: ack the
+//! device, take what it has, and [`schedule`](Work::schedule) a [`Work`] for the rest.
+//! Pending work is run with interrupts on, by a task at [`HIGH_PRIORITY`] and on the way
+//! back to U-mode after an interrupt, so a process that never makes a syscall doesn't hold
+//! it up. Whichever gets there first runs it, on whatever hart that is.
+//!
+//! A `Work` is a `static`, and is on the queue at most once: scheduling it again before it
+//! runs does nothing, so it should deal with everything that's built up, not one event.
+//! Scheduled again while it's running, it can run on another hart at the same time.
+//! [`register`](Work::register) reserves its place on the queue, so scheduling never
+//! allocates.
+
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    prelude::*,
+    sync::{IrqSafeMutex, WaitQueue, HIGH_PRIORITY},
+    task,
+};
+
+/// Something for later.
+pub struct Work {
+    name: &'static str,
+    run: fn(),
+    registered: AtomicBool,
+    pending: AtomicBool,
+    runs: AtomicU64,
+}
+
+/// Pending work, oldest first. Has room for everything registered.
+static QUEUE: IrqSafeMutex<VecDeque<&'static Work>> = IrqSafeMutex::new(VecDeque::new());
+/// Everything registered, for listing.
+static WORKS: Mutex<Vec<&'static Work>> = Mutex::new(Vec::new());
+static READY: WaitQueue = WaitQueue::new();
+
+impl Work {
+    pub const fn new(name: &'static str, run: fn()) -> Work {
+        Work {
+            name,
+            run,
+            registered: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            runs: AtomicU64::new(0),
+        }
+    }
+
+    /// Make room for it on the queue. Before it's first scheduled, and not from an
+    /// interrupt handler. Again does nothing.
+    pub fn register(&'static self) {
+        if self.registered.load(Ordering::Acquire) {
+            return;
+        }
+        let mut works = WORKS.lock();
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        works.push(self);
+        let mut queue = QUEUE.lock();
+        let room = works.len().saturating_sub(queue.len());
+        queue.reserve(room);
+    }
+
+    /// Have it run soon. Fine from interrupt handlers. Returns false if it was already
+    /// pending.
+    pub fn schedule(&'static self) -> bool {
+        assert!(
+            self.registered.load(Ordering::Acquire),
+            "{} scheduled before it was registered",
+            self.name
+        );
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        QUEUE.lock().push_back(self);
+        READY.wake_one();
+        true
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Times it's run.
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Relaxed)
+    }
+}
+
+/// Run what's pending, and whatever's scheduled meanwhile, until there's nothing left.
+/// Returns how many ran.
+pub fn run_pending() -> usize {
+    let mut ran = 0;
+    loop {
+        let Some(work) = QUEUE.lock().pop_front() else {
+            return ran;
+        };
+        // Cleared first, so scheduling it while it runs runs it again.
+        work.pending.store(false, Ordering::Release);
+        (work.run)();
+        work.runs.fetch_add(1, Ordering::Relaxed);
+        ran += 1;
+    }
+}
+
+/// Everything registered.
+pub fn list() -> Vec<&'static Work> {
+    WORKS.lock().clone()
+}
+
+async fn worker() {
+    loop {
+        READY
+            .wait_until(|| (!QUEUE.lock().is_empty()).then_some(()))
+            .await;
+        run_pending();
+    }
+}
+
+/// Start the task that runs pending work. Work scheduled before this waits for it, or
+/// for a process's next interrupt.
+pub fn init() {
+    task::spawn_with_priority(HIGH_PRIORITY, worker());
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::isr::without_interrupts;
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    static TEST_WORK: Work = Work::new("test", || {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+
+    #[test_case]
+    fn deferred_work() {
+        TEST_WORK.register();
+        TEST_WORK.register();
+        assert_eq!(
+            list().iter().filter(|work| work.name() == "test").count(),
+            1
+        );
+
+        let (runs, count) = (TEST_WORK.runs(), COUNT.load(Ordering::Relaxed));
+        // Like an interrupt handler would, twice before it gets to run.
+        let scheduled = without_interrupts(|| (TEST_WORK.schedule(), TEST_WORK.schedule()));
+        assert_eq!(scheduled, (true, false));
+        run_pending();
+        // The worker may have it on another hart.
+        while TEST_WORK.runs() == runs {
+            crate::cpu::relax();
+        }
+        assert_eq!(COUNT.load(Ordering::Relaxed), count + 1);
+        assert!(!TEST_WORK.is_pending());
+    }
+}
@@ -0,0 +1,32 @@
+//! PID 1. Starts the shell on the console and restarts it whenever it exits.
+#![no_std]
+#![no_main]
+
+use core::ffi::CStr;
+
+use ulib::{println, spawn, waitpid, Args};
+
+const SHELL: &[u8] = b"/bin/sh\0";
+
+#[no_mangle]
+fn main(_args: Args) -> i32 {
+    println!("init: started as pid {}", ulib::getpid());
+    let shell = CStr::from_bytes_with_nul(SHELL).unwrap();
+
+    loop {
+        let argv = [shell.as_ptr(), core::ptr::null()];
+        let pid = spawn(shell, &argv[..1]);
+        if pid < 0 {
+            println!("init: failed to start {:?}: error {}", shell, pid);
+            return 1;
+        }
+
+        match waitpid(pid) {
+            Ok((_, status)) => println!("init: shell exited with status {}, restarting", status),
+            Err(err) => {
+                println!("init: waitpid failed: {}", err);
+                return 1;
+            }
+        }
+    }
+}
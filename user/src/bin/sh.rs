@@ -0,0 +1,122 @@
+//! Minimal shell. Builtins plus running `/bin/<name>` for anything else.
+//!
+//! Line editing and echo are the console's job, this just reads whole lines from fd 0.
+#![no_std]
+#![no_main]
+
+use core::{ffi::{c_char, CStr}, ptr};
+
+use ulib::{print, println, read, spawn, waitpid, Args, STDIN};
+
+const LINE_MAX: usize = 256;
+const ARGS_MAX: usize = 16;
+const BIN_PREFIX: &[u8] = b"/bin/";
+
+#[no_mangle]
+fn main(_args: Args) -> i32 {
+    let mut line = [0u8; LINE_MAX];
+
+    loop {
+        print!("$ ");
+        let len = match read_line(&mut line) {
+            Some(len) => len,
+            // EOF
+            None => return 0,
+        };
+
+        let input = match core::str::from_utf8(&line[..len]) {
+            Ok(s) => s.trim(),
+            Err(_) => {
+                println!("sh: input is not utf-8");
+                continue;
+            }
+        };
+
+        let mut words = input.split_ascii_whitespace();
+        let cmd = match words.next() {
+            Some(cmd) => cmd,
+            None => continue,
+        };
+
+        match cmd {
+            "help" => {
+                println!("builtins: help echo pid exit");
+                println!("anything else runs /bin/<name>");
+            }
+            "echo" => {
+                let mut first = true;
+                for w in words {
+                    if !first {
+                        print!(" ");
+                    }
+                    print!("{}", w);
+                    first = false;
+                }
+                println!();
+            }
+            "pid" => println!("{}", ulib::getpid()),
+            "exit" => {
+                let code = words.next().and_then(|c| c.parse().ok()).unwrap_or(0);
+                return code;
+            }
+            _ => run(cmd, input),
+        }
+    }
+}
+
+/// Read up to a newline. Returns `None` on EOF with nothing read.
+fn read_line(buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        let n = read(STDIN, &mut buf[len..len + 1]);
+        if n <= 0 {
+            return if len == 0 { None } else { Some(len) };
+        }
+        if buf[len] == b'\n' || buf[len] == b'\r' {
+            return Some(len);
+        }
+        len += 1;
+    }
+    Some(len)
+}
+
+fn run(cmd: &str, input: &str) {
+    // Nul terminated copies of the path and every argument live in here.
+    let mut strings = [0u8; BIN_PREFIX.len() + 2 * LINE_MAX];
+    let mut argv: [*const c_char; ARGS_MAX + 1] = [ptr::null(); ARGS_MAX + 1];
+
+    let path_len = BIN_PREFIX.len() + cmd.len();
+    if path_len + 1 > LINE_MAX {
+        println!("sh: {}: name too long", cmd);
+        return;
+    }
+    strings[..BIN_PREFIX.len()].copy_from_slice(BIN_PREFIX);
+    strings[BIN_PREFIX.len()..path_len].copy_from_slice(cmd.as_bytes());
+
+    let mut offset = path_len + 1;
+    let mut argc = 0;
+    for word in input.split_ascii_whitespace() {
+        if argc == ARGS_MAX {
+            println!("sh: too many arguments");
+            return;
+        }
+        let end = offset + word.len();
+        strings[offset..end].copy_from_slice(word.as_bytes());
+        argv[argc] = strings[offset..].as_ptr() as *const c_char;
+        argc += 1;
+        offset = end + 1;
+    }
+
+    let path = CStr::from_bytes_until_nul(&strings).expect("path is nul terminated");
+    let pid = spawn(path, &argv[..argc]);
+    if pid < 0 {
+        println!("sh: {}: command not found", cmd);
+        return;
+    }
+
+    match waitpid(pid) {
+        Ok((_, 0)) => {}
+        Ok((_, status)) => println!("sh: {} exited with status {}", cmd, status),
+        Err(err) => println!("sh: waitpid failed: {}", err),
+    }
+}
@@ -0,0 +1,366 @@
+//! Tiny runtime shared by the in-tree user programs.
+//!
+//! Provides `_start`, a panic handler, `print!`/`println!` on fd 1 and thin wrappers
+//! around the system calls in [`syscall`]. No allocator: programs use fixed buffers.
+#![feature(naked_functions)]
+#![no_std]
+
+pub mod syscall;
+
+use core::{
+    arch::asm,
+    ffi::{c_char, CStr},
+    fmt,
+    panic::PanicInfo,
+};
+
+use syscall::*;
+
+pub type Fd = usize;
+
+pub const STDIN: Fd = 0;
+pub const STDOUT: Fd = 1;
+pub const STDERR: Fd = 2;
+
+pub const O_RDONLY: usize = 0o0;
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+pub const O_DIRECTORY: usize = 0o200000;
+
+const AT_FDCWD: isize = -100;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+pub const POLLIN: i16 = 0x001;
+pub const POLLOUT: i16 = 0x004;
+pub const POLLNVAL: i16 = 0x020;
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSF: usize = 0x5404;
+
+pub const ICRNL: u32 = 0o400;
+pub const OPOST: u32 = 0o1;
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+extern "Rust" {
+    /// Defined by each program with `#[no_mangle]`.
+    fn main(args: Args) -> i32;
+}
+
+#[naked]
+#[no_mangle]
+#[link_section = ".text.init"]
+pub unsafe extern "C" fn _start() -> ! {
+    asm!(
+        ".option push",
+        ".option norelax",
+        "la   gp, __global_pointer",
+        ".option pop",
+        // sp points at argc, followed by argv. Per the RISC-V ELF psABI.
+        "mv   a0, sp",
+        "mv   s0, zero",
+        "tail {start}",
+        start = sym start_rust,
+        options(noreturn)
+    )
+}
+
+unsafe extern "C" fn start_rust(stack: *const usize) -> ! {
+    let argc = *stack;
+    let argv = stack.add(1) as *const *const c_char;
+    let code = main(Args { argc, argv, index: 0 });
+    exit(code)
+}
+
+/// Program arguments, read straight off the initial stack.
+#[derive(Clone)]
+pub struct Args {
+    argc: usize,
+    argv: *const *const c_char,
+    index: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.index >= self.argc {
+            return None;
+        }
+        let arg = unsafe { CStr::from_ptr(*self.argv.add(self.index)) };
+        self.index += 1;
+        Some(arg.to_str().unwrap_or("<invalid utf-8>"))
+    }
+}
+
+pub fn read(fd: Fd, buf: &mut [u8]) -> isize {
+    unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
+pub fn write(fd: Fd, buf: &[u8]) -> isize {
+    unsafe { syscall3(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len()) }
+}
+
+/// `struct iovec`: one buffer for [`readv`] or [`writev`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoVec {
+    pub base: usize,
+    pub len: usize,
+}
+
+impl IoVec {
+    pub fn new(buf: &[u8]) -> Self {
+        IoVec {
+            base: buf.as_ptr() as usize,
+            len: buf.len(),
+        }
+    }
+
+    pub fn new_mut(buf: &mut [u8]) -> Self {
+        IoVec {
+            base: buf.as_mut_ptr() as usize,
+            len: buf.len(),
+        }
+    }
+}
+
+/// Read into each of `bufs` in turn. They must be from [`IoVec::new_mut`].
+pub fn readv(fd: Fd, bufs: &[IoVec]) -> isize {
+    unsafe { syscall3(SYS_READV, fd, bufs.as_ptr() as usize, bufs.len()) }
+}
+
+/// Write `bufs` one after another, in one go.
+pub fn writev(fd: Fd, bufs: &[IoVec]) -> isize {
+    unsafe { syscall3(SYS_WRITEV, fd, bufs.as_ptr() as usize, bufs.len()) }
+}
+
+/// Open `path` with `O_*` `flags`. Returns the new fd.
+pub fn open(path: &CStr, flags: usize) -> isize {
+    unsafe { syscall3(SYS_OPENAT, AT_FDCWD as usize, path.as_ptr() as usize, flags) }
+}
+
+pub fn close(fd: Fd) -> isize {
+    unsafe { syscall1(SYS_CLOSE, fd) }
+}
+
+/// Move the offset of `fd`. `whence` is one of `SEEK_*`. Returns the new offset.
+pub fn lseek(fd: Fd, offset: isize, whence: usize) -> isize {
+    unsafe { syscall3(SYS_LSEEK, fd, offset as usize, whence) }
+}
+
+/// A device specific request on `fd`.
+pub fn ioctl(fd: Fd, request: usize, arg: usize) -> isize {
+    unsafe { syscall3(SYS_IOCTL, fd, request, arg) }
+}
+
+/// `struct pollfd`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PollFd {
+    /// Negative to skip this one.
+    pub fd: i32,
+    /// `POLL*` bits to wait for.
+    pub events: i16,
+    /// Set to what's ready.
+    pub revents: i16,
+}
+
+/// Wait until one of `fds` is ready, or `timeout_ms` passes. Returns how many are ready.
+pub fn poll(fds: &mut [PollFd], timeout_ms: Option<u64>) -> isize {
+    let timeout = timeout_ms.map(|ms| [ms / 1000, ms % 1000 * 1_000_000]);
+    let timeout = match &timeout {
+        Some(timespec) => timespec.as_ptr() as usize,
+        None => 0,
+    };
+    unsafe { syscall4(SYS_PPOLL, fds.as_mut_ptr() as usize, fds.len(), timeout, 0) }
+}
+
+/// `struct termios`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; 19],
+}
+
+impl Termios {
+    /// No line editing, echo or signals, and reads that don't wait.
+    pub fn make_raw(&mut self) {
+        self.iflag &= !ICRNL;
+        self.oflag &= !OPOST;
+        self.lflag &= !(ISIG | ICANON | ECHO);
+        self.cc[VMIN] = 0;
+        self.cc[VTIME] = 0;
+    }
+}
+
+pub fn tcgetattr(fd: Fd) -> Result<Termios, isize> {
+    let mut termios = Termios::default();
+    match ioctl(fd, TCGETS, &mut termios as *mut Termios as usize) {
+        0 => Ok(termios),
+        err => Err(err),
+    }
+}
+
+pub fn tcsetattr(fd: Fd, termios: &Termios) -> isize {
+    ioctl(fd, TCSETS, termios as *const Termios as usize)
+}
+
+/// Set the end of the heap. Returns the new end, which is the old one on failure.
+pub fn brk(addr: usize) -> usize {
+    unsafe { syscall1(SYS_BRK, addr) as usize }
+}
+
+/// Grow the heap by `increment` bytes. Returns the old end, or `None` if there's no room.
+pub fn sbrk(increment: isize) -> Option<*mut u8> {
+    let old = brk(0);
+    let new = if increment >= 0 {
+        old.checked_add(increment as usize)?
+    } else {
+        old.checked_sub(increment.unsigned_abs())?
+    };
+    if brk(new) != new {
+        return None;
+    }
+    Some(old as *mut u8)
+}
+
+/// Anonymous memory only. Returns the address, or `-errno`.
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize) -> isize {
+    unsafe { syscall4(SYS_MMAP, addr, len, prot, flags | MAP_ANONYMOUS) }
+}
+
+pub fn munmap(addr: *mut u8, len: usize) -> isize {
+    unsafe { syscall2(SYS_MUNMAP, addr as usize, len) }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        syscall1(SYS_EXIT, code as usize);
+    }
+    unreachable!("exit returned")
+}
+
+pub fn getpid() -> isize {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+/// Set the nice value of process `pid`, or this one for 0.
+pub fn setpriority(pid: usize, nice: i32) -> isize {
+    unsafe { syscall3(SYS_SETPRIORITY, 0, pid, nice as usize) }
+}
+
+/// 20 minus the nice value of process `pid`, or this one for 0.
+pub fn getpriority(pid: usize) -> isize {
+    unsafe { syscall2(SYS_GETPRIORITY, 0, pid) }
+}
+
+/// Start `path` as a new process. `path` and each of `argv` must be nul terminated.
+pub fn spawn(path: &CStr, argv: &[*const c_char]) -> isize {
+    unsafe {
+        syscall3(
+            SYS_SPAWN,
+            path.as_ptr() as usize,
+            argv.as_ptr() as usize,
+            argv.len(),
+        )
+    }
+}
+
+/// Replace the current program. `argv` must end with a null pointer. Only returns on error.
+pub fn execve(path: &CStr, argv: &[*const c_char]) -> isize {
+    unsafe {
+        syscall3(
+            SYS_EXECVE,
+            path.as_ptr() as usize,
+            argv.as_ptr() as usize,
+            0,
+        )
+    }
+}
+
+/// Wait for `pid` to exit, or any child if it's -1. Returns its pid and exit code.
+pub fn waitpid(pid: isize) -> Result<(isize, i32), isize> {
+    let mut status: i32 = 0;
+    let ret = unsafe {
+        syscall4(
+            SYS_WAIT4,
+            pid as usize,
+            &mut status as *mut i32 as usize,
+            0,
+            0,
+        )
+    };
+    if ret < 0 {
+        Err(ret)
+    } else {
+        Ok((ret, (status >> 8) & 0xff))
+    }
+}
+
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let n = write(STDOUT, bytes);
+            if n <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[n as usize..];
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    fmt::Write::write_fmt(&mut Stdout, args).ok();
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::_print(format_args!("\n")) };
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    exit(101)
+}
@@ -0,0 +1,84 @@
+//! Raw system calls.
+//!
+//! Numbers follow the Linux generic (asm-generic/unistd.h) table used on RISC-V, so
+//! existing tooling has a chance of making sense of them. Calls Linux doesn't have live
+//! above 1000.
+//!
+//! Calling convention: number in `a7`, arguments in `a0`-`a5`, result in `a0`.
+//! Negative results are `-errno`.
+
+use core::arch::asm;
+
+pub const SYS_IOCTL: usize = 29;
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_LSEEK: usize = 62;
+pub const SYS_READ: usize = 63;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_READV: usize = 65;
+pub const SYS_WRITEV: usize = 66;
+pub const SYS_PPOLL: usize = 73;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_NANOSLEEP: usize = 101;
+pub const SYS_SETPRIORITY: usize = 140;
+pub const SYS_GETPRIORITY: usize = 141;
+pub const SYS_GETPID: usize = 172;
+pub const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_EXECVE: usize = 221;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_WAIT4: usize = 260;
+
+/// Start a new process running `path`. Returns the pid.
+pub const SYS_SPAWN: usize = 1000;
+
+pub unsafe fn syscall0(n: usize) -> isize {
+    let ret: isize;
+    asm!("ecall", in("a7") n, lateout("a0") ret, options(nostack));
+    ret
+}
+
+pub unsafe fn syscall1(n: usize, a0: usize) -> isize {
+    let ret: isize;
+    asm!("ecall", in("a7") n, inlateout("a0") a0 as isize => ret, options(nostack));
+    ret
+}
+
+pub unsafe fn syscall2(n: usize, a0: usize, a1: usize) -> isize {
+    let ret: isize;
+    asm!(
+        "ecall",
+        in("a7") n,
+        inlateout("a0") a0 as isize => ret,
+        in("a1") a1,
+        options(nostack)
+    );
+    ret
+}
+
+pub unsafe fn syscall3(n: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    asm!(
+        "ecall",
+        in("a7") n,
+        inlateout("a0") a0 as isize => ret,
+        in("a1") a1,
+        in("a2") a2,
+        options(nostack)
+    );
+    ret
+}
+
+pub unsafe fn syscall4(n: usize, a0: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    asm!(
+        "ecall",
+        in("a7") n,
+        inlateout("a0") a0 as isize => ret,
+        in("a1") a1,
+        in("a2") a2,
+        in("a3") a3,
+        options(nostack)
+    );
+    ret
+}